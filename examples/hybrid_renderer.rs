@@ -0,0 +1,9 @@
+use renderlib::config::{LaunchConfig, RendererKind};
+
+// Same options as the main binary, always with the hybrid renderer
+fn main() {
+    let mut config = LaunchConfig::from_args();
+    config.renderer = RendererKind::Hybrid;
+
+    cubulous_client::run(config);
+}
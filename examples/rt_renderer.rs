@@ -1,11 +1,9 @@
-use winit::event_loop::EventLoop;
-use rt_renderer::rt_renderer::RtRenderer;
+use renderlib::config::{LaunchConfig, RendererKind};
 
+// Same options as the main binary, always with the ray traced renderer
 fn main() {
-    // Generic window setup
-    let event_loop = EventLoop::new();
+    let mut config = LaunchConfig::from_args();
+    config.renderer = RendererKind::Rt;
 
-    let renderer = RtRenderer::new(&event_loop);
-
-    renderer.run_blocking(event_loop);
-}
\ No newline at end of file
+    cubulous_client::run(config);
+}
@@ -0,0 +1,135 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::vkcore::VkCore;
+
+// Position of an address in the table, the shader reads addresses[index.0]. Stays the same until unregistered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AddressIndex(pub u32);
+
+// Buffer device addresses of GpuBuffers packed into a storage buffer, so that shaders can reach any number of
+// buffers through a single descriptor. In GLSL:
+//     layout(binding = N, set = 0, scalar) readonly buffer Addresses { uint64_t addresses[]; } table;
+// followed by a GL_EXT_buffer_reference cast of table.addresses[i]. Registered buffers need SHADER_DEVICE_ADDRESS
+// usage. There is one copy per frame in flight, changes are written to a frame's copy in update, once its previous
+// submission has finished.
+pub struct ShaderAddressTable {
+    capacity: usize,
+    entries: Vec<vk::DeviceAddress>, // 0 for free slots
+    free: Vec<u32>,
+    buffers: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>,
+    dirty: Vec<bool>
+}
+
+impl ShaderAddressTable {
+    // capacity is fixed, the descriptors written for buffer() would have to be rewritten otherwise
+    pub fn new(core: &VkCore, max_frames: usize, capacity: usize) -> ShaderAddressTable {
+        let size = (mem::size_of::<vk::DeviceAddress>() * capacity) as vk::DeviceSize;
+        let mut buffers: Vec<GpuBuffer> = Vec::with_capacity(max_frames);
+        let mut mapped: Vec<*mut c_void> = Vec::with_capacity(max_frames);
+        for _ in 0..max_frames {
+            let buffer = GpuBuffer::new_mapped(core, size, vk::BufferUsageFlags::STORAGE_BUFFER);
+            let dev_memory = unsafe {
+                core.logical_device.map_memory(buffer.mem, 0, size, vk::MemoryMapFlags::empty()).unwrap()
+            };
+            unsafe { (dev_memory as *mut u8).write_bytes(0, size as usize) };
+            buffers.push(buffer);
+            mapped.push(dev_memory);
+        }
+
+        ShaderAddressTable {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+            free: Vec::new(),
+            buffers,
+            mapped,
+            dirty: vec![false; max_frames]
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn register(&mut self, core: &VkCore, buffer: &GpuBuffer) -> AddressIndex {
+        self.register_address(buffer.get_device_address(core))
+    }
+
+    // For addresses of buffers that aren't GpuBuffers, such as IndexBuffer::get_device_address
+    pub fn register_address(&mut self, address: vk::DeviceAddress) -> AddressIndex {
+        let index = match self.free.pop() {
+            Some(i) => {
+                self.entries[i as usize] = address;
+                i
+            },
+            None => {
+                assert!(self.entries.len() < self.capacity, "Shader address table is full ({} entries)",
+                        self.capacity);
+                self.entries.push(address);
+                (self.entries.len() - 1) as u32
+            }
+        };
+        self.mark_dirty();
+
+        AddressIndex(index)
+    }
+
+    // Points an index at a new buffer, e.g. after the old one was reallocated to grow
+    pub fn replace(&mut self, core: &VkCore, index: AddressIndex, buffer: &GpuBuffer) {
+        self.replace_address(index, buffer.get_device_address(core));
+    }
+
+    pub fn replace_address(&mut self, index: AddressIndex, address: vk::DeviceAddress) {
+        assert!(!self.free.contains(&index.0), "{:?} is not registered", index);
+        self.entries[index.0 as usize] = address;
+        self.mark_dirty();
+    }
+
+    // The slot is reused by a later register. Frames in flight may still read the old address, so the buffer must
+    // outlive them.
+    pub fn unregister(&mut self, index: AddressIndex) {
+        assert!(!self.free.contains(&index.0), "{:?} is not registered", index);
+        self.entries[index.0 as usize] = 0;
+        self.free.push(index.0);
+        self.mark_dirty();
+    }
+
+    pub fn address(&self, index: AddressIndex) -> vk::DeviceAddress {
+        self.entries[index.0 as usize]
+    }
+
+    fn mark_dirty(&mut self) {
+        self.dirty.iter_mut().for_each(|d| *d = true);
+    }
+
+    // Call once the frame's fence has been waited on, before recording commands that read the table
+    pub fn update(&mut self, frame: usize) {
+        if !self.dirty[frame] {
+            return;
+        }
+        unsafe {
+            (self.mapped[frame] as *mut vk::DeviceAddress)
+                .copy_from_nonoverlapping(self.entries.as_ptr(), self.entries.len());
+        }
+        self.dirty[frame] = false;
+    }
+
+    // Bind as a STORAGE_BUFFER descriptor
+    pub fn buffer(&self, frame: usize) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo::default()
+            .buffer(self.buffers[frame].buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for b in self.buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use cgmath::{InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3, VectorSpace};
+use gltf::animation::util::ReadOutputs;
+use gltf::animation::{Interpolation, Property};
+
+use crate::vertex::SkinnedVertex;
+
+// Local transform of a joint relative to its parent
+#[derive(Clone, Copy, Debug)]
+pub struct JointTransform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>
+}
+
+impl JointTransform {
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation) * Matrix4::from(self.rotation) *
+            Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+pub struct Joint {
+    pub name: String,
+    pub parent: Option<usize>, // Always a lower index than the joint itself
+    pub inverse_bind: Matrix4<f32>,
+    pub rest: JointTransform
+}
+
+pub struct Skeleton {
+    pub joints: Vec<Joint>,
+    pub root_transform: Matrix4<f32> // Global transform of the nodes above the root joints
+}
+
+enum ChannelValues {
+    Translations(Vec<Vector3<f32>>),
+    Rotations(Vec<Quaternion<f32>>),
+    Scales(Vec<Vector3<f32>>)
+}
+
+struct Channel {
+    joint: usize,
+    step: bool, // STEP interpolation, LINEAR otherwise
+    times: Vec<f32>,
+    values: ChannelValues
+}
+
+pub struct AnimationClip {
+    pub name: String,
+    pub duration: f32, // Seconds
+    channels: Vec<Channel>
+}
+
+// Index of the keyframe before t and the blend factor towards the next one
+fn find_keyframe(times: &[f32], t: f32) -> (usize, usize, f32) {
+    let last = times.len() - 1;
+    if t <= times[0] {
+        return (0, 0, 0.0);
+    }
+    if t >= times[last] {
+        return (last, last, 0.0);
+    }
+
+    let next = times.partition_point(|&k| k <= t);
+    let prev = next - 1;
+    let span = times[next] - times[prev];
+    let blend = match span > 0.0 {
+        true => (t - times[prev]) / span,
+        false => 0.0
+    };
+
+    (prev, next, blend)
+}
+
+impl AnimationClip {
+    // Local transforms of every joint at time t, wrapped to the clip duration when looping
+    pub fn sample(&self, skeleton: &Skeleton, t: f32, looping: bool) -> Vec<JointTransform> {
+        let t = match looping && self.duration > 0.0 {
+            true => t.rem_euclid(self.duration),
+            false => t.clamp(0.0, self.duration)
+        };
+        let mut pose: Vec<JointTransform> = skeleton.joints.iter().map(|j| j.rest).collect();
+        for c in self.channels.iter() {
+            let (prev, next, blend) = find_keyframe(&c.times, t);
+            let blend = match c.step {
+                true => 0.0,
+                false => blend
+            };
+            match &c.values {
+                ChannelValues::Translations(v) => pose[c.joint].translation = v[prev].lerp(v[next], blend),
+                ChannelValues::Rotations(v) => {
+                    // q and -q are the same rotation, blend towards whichever is closer
+                    let target = match v[prev].dot(v[next]) < 0.0 {
+                        true => -v[next],
+                        false => v[next]
+                    };
+                    pose[c.joint].rotation = v[prev].nlerp(target, blend);
+                },
+                ChannelValues::Scales(v) => pose[c.joint].scale = v[prev].lerp(v[next], blend)
+            }
+        }
+
+        pose
+    }
+}
+
+impl Skeleton {
+    // Skinning matrices (global joint transform * inverse bind matrix) for a pose from AnimationClip::sample
+    pub fn skin_matrices(&self, pose: &[JointTransform]) -> Vec<Matrix4<f32>> {
+        let mut globals: Vec<Matrix4<f32>> = Vec::with_capacity(self.joints.len());
+        for (joint, local) in self.joints.iter().zip(pose.iter()) {
+            let parent = match joint.parent {
+                Some(p) => globals[p],
+                None => self.root_transform
+            };
+            globals.push(parent * local.to_matrix());
+        }
+
+        globals.iter().zip(self.joints.iter()).map(|(g, j)| g * j.inverse_bind).collect()
+    }
+
+    pub fn rest_matrices(&self) -> Vec<Matrix4<f32>> {
+        self.skin_matrices(&self.joints.iter().map(|j| j.rest).collect::<Vec<JointTransform>>())
+    }
+}
+
+fn node_transform(node: &gltf::Node) -> JointTransform {
+    let (translation, rotation, scale) = node.transform().decomposed();
+    JointTransform {
+        translation: Vector3::from(translation),
+        rotation: Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]), // glTF stores xyzw
+        scale: Vector3::from(scale)
+    }
+}
+
+// Vertices, indices, skeleton and animations of a skinned glTF file
+pub type SkinnedGltf = (Vec<SkinnedVertex>, Vec<u32>, Skeleton, Vec<AnimationClip>);
+
+// Loads the first skin of a glTF file with every skinned primitive using it, plus all of its animations. Cubic spline
+// channels are sampled at their keyframe values with linear blending in between.
+pub fn load_gltf_skinned(path: &str) -> Result<SkinnedGltf, String> {
+    let (document, buffers, _) = gltf::import(path).map_err(|e| format!("Failed to load {}: {}", path, e))?;
+    let skin = document.skins().next().ok_or(format!("{}: no skins", path))?;
+
+    // Parents of every node, glTF only stores children
+    let node_count = document.nodes().len();
+    let mut node_parents: Vec<Option<usize>> = vec![None; node_count];
+    for n in document.nodes() {
+        for c in n.children() {
+            node_parents[c.index()] = Some(n.index());
+        }
+    }
+    let node_globals = {
+        let nodes: Vec<gltf::Node> = document.nodes().collect();
+        let mut globals: Vec<Option<Matrix4<f32>>> = vec![None; node_count];
+        fn resolve(idx: usize, nodes: &Vec<gltf::Node>, parents: &Vec<Option<usize>>,
+                   globals: &mut Vec<Option<Matrix4<f32>>>) -> Matrix4<f32> {
+            if let Some(m) = globals[idx] {
+                return m;
+            }
+            let local = Matrix4::from(nodes[idx].transform().matrix());
+            let m = match parents[idx] {
+                Some(p) => resolve(p, nodes, parents, globals) * local,
+                None => local
+            };
+            globals[idx] = Some(m);
+            m
+        }
+        (0..node_count).map(|n| resolve(n, &nodes, &node_parents, &mut globals)).collect::<Vec<Matrix4<f32>>>()
+    };
+
+    // Order the joints so that parents always come first
+    let skin_nodes: Vec<gltf::Node> = skin.joints().collect();
+    let reader = skin.reader(|b| Some(&buffers[b.index()]));
+    let inverse_binds: Vec<Matrix4<f32>> = match reader.read_inverse_bind_matrices() {
+        Some(m) => m.map(Matrix4::from).collect(),
+        None => vec![Matrix4::identity(); skin_nodes.len()]
+    };
+    let is_joint = |node: usize| skin_nodes.iter().position(|n| n.index() == node);
+    let mut order: Vec<usize> = Vec::with_capacity(skin_nodes.len()); // Skin joint indices in sorted order
+    while order.len() < skin_nodes.len() {
+        let before = order.len();
+        for (j, n) in skin_nodes.iter().enumerate() {
+            if order.contains(&j) {
+                continue;
+            }
+            let parent_joint = node_parents[n.index()].and_then(is_joint);
+            if parent_joint.is_none_or(|p| order.contains(&p)) {
+                order.push(j);
+            }
+        }
+        if order.len() == before {
+            return Err(format!("{}: joint hierarchy has a cycle", path));
+        }
+    }
+    let sorted_index = |skin_joint: usize| order.iter().position(|&j| j == skin_joint).unwrap();
+
+    let mut root_transform = Matrix4::identity();
+    let joints: Vec<Joint> = order.iter().map(|&j| {
+        let node = &skin_nodes[j];
+        let parent = node_parents[node.index()].and_then(is_joint).map(sorted_index);
+        if parent.is_none() {
+            if let Some(p) = node_parents[node.index()] {
+                root_transform = node_globals[p];
+            }
+        }
+        Joint {
+            name: node.name().unwrap_or("").to_string(),
+            parent,
+            inverse_bind: inverse_binds[j],
+            rest: node_transform(node)
+        }
+    }).collect();
+    let skeleton = Skeleton {
+        joints,
+        root_transform
+    };
+
+    let mut vertices: Vec<SkinnedVertex> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    for mesh in document.meshes() {
+        for primitive in mesh.primitives() {
+            let reader = primitive.reader(|b| Some(&buffers[b.index()]));
+            let (positions, joints, weights) = match (reader.read_positions(), reader.read_joints(0),
+                                                      reader.read_weights(0)) {
+                (Some(p), Some(j), Some(w)) => (p, j.into_u16(), w.into_f32()),
+                _ => continue // Not skinned
+            };
+            let tex_coords: Vec<[f32; 2]> = match reader.read_tex_coords(0) {
+                Some(t) => t.into_f32().collect(),
+                None => Vec::new()
+            };
+            let base = vertices.len() as u32;
+            for (n, ((pos, j), w)) in positions.zip(joints).zip(weights).enumerate() {
+                vertices.push(SkinnedVertex {
+                    pos,
+                    color: [1.0, 1.0, 1.0],
+                    tex_coord: *tex_coords.get(n).unwrap_or(&[0.0, 0.0]),
+                    joints: j.map(|idx| sorted_index(idx as usize) as u32),
+                    weights: w
+                });
+            }
+            match reader.read_indices() {
+                Some(i) => indices.extend(i.into_u32().map(|i| i + base)),
+                None => indices.extend(base..vertices.len() as u32)
+            }
+        }
+    }
+    if vertices.is_empty() {
+        return Err(format!("{}: no skinned primitives", path));
+    }
+
+    let mut clips: Vec<AnimationClip> = Vec::new();
+    for animation in document.animations() {
+        let mut channels: Vec<Channel> = Vec::new();
+        let mut duration: f32 = 0.0;
+        for channel in animation.channels() {
+            let joint = match is_joint(channel.target().node().index()) {
+                Some(j) => sorted_index(j),
+                None => continue // Animates a node outside of the skeleton
+            };
+            let reader = channel.reader(|b| Some(&buffers[b.index()]));
+            let times: Vec<f32> = match reader.read_inputs() {
+                Some(t) => t.collect(),
+                None => continue
+            };
+            if times.is_empty() {
+                continue;
+            }
+            let interpolation = channel.sampler().interpolation();
+            // Cubic spline outputs are (in tangent, value, out tangent) triplets
+            let keep_values = |n: usize| match interpolation {
+                Interpolation::CubicSpline => n % 3 == 1,
+                _ => true
+            };
+            let values = match (channel.target().property(), reader.read_outputs()) {
+                (Property::Translation, Some(ReadOutputs::Translations(t))) => ChannelValues::Translations(
+                    t.enumerate().filter(|(n, _)| keep_values(*n)).map(|(_, v)| Vector3::from(v)).collect()),
+                (Property::Rotation, Some(ReadOutputs::Rotations(r))) => ChannelValues::Rotations(
+                    r.into_f32().enumerate().filter(|(n, _)| keep_values(*n))
+                        .map(|(_, v)| Quaternion::new(v[3], v[0], v[1], v[2]).normalize()).collect()),
+                (Property::Scale, Some(ReadOutputs::Scales(s))) => ChannelValues::Scales(
+                    s.enumerate().filter(|(n, _)| keep_values(*n)).map(|(_, v)| Vector3::from(v)).collect()),
+                _ => continue // Morph targets aren't supported
+            };
+            duration = duration.max(*times.last().unwrap());
+            channels.push(Channel {
+                joint,
+                step: interpolation == Interpolation::Step,
+                times,
+                values
+            });
+        }
+
+        clips.push(AnimationClip {
+            name: animation.name().unwrap_or("").to_string(),
+            duration,
+            channels
+        });
+    }
+
+    Ok((vertices, indices, skeleton, clips))
+}
@@ -0,0 +1,106 @@
+use ash::vk;
+
+use crate::renderutils::setup_sync_objects;
+use crate::vkcore::VkCore;
+
+// Compute work submitted to the compute queue so that it overlaps with the next frame's graphics work. Devices
+// without a dedicated compute family use the graphics family, the work then just runs between the frames. Per
+// frame:
+// 1. The graphics submission also signals graphics_finished(frame)
+// 2. submit records the compute work, which waits on that semaphore
+// 3. The next graphics submission waits on take_wait(), the compute work of the previous frame
+// Resources used by both sides must be shared with the compute family, see ImageInfo::queue_families.
+pub struct AsyncCompute {
+    pub family_index: u32,
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    graphics_finished_sems: Vec<vk::Semaphore>,
+    compute_finished_sems: Vec<vk::Semaphore>,
+    fences: Vec<vk::Fence>, // Command buffers can't be reused before these signal
+    pending: Option<vk::Semaphore> // Signaled by the last submit and not waited on yet
+}
+
+impl AsyncCompute {
+    pub fn new(core: &VkCore, max_frames: usize) -> AsyncCompute {
+        let pool_create_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(core.compute_family_index);
+        let command_pool = unsafe { core.logical_device.create_command_pool(&pool_create_info, None).unwrap() };
+        let buf_create_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(max_frames as u32);
+        let command_buffers = unsafe { core.logical_device.allocate_command_buffers(&buf_create_info).unwrap() };
+        let (graphics_finished_sems, compute_finished_sems, fences) = setup_sync_objects(core, max_frames);
+
+        AsyncCompute {
+            family_index: core.compute_family_index,
+            queue: core.compute_queue,
+            command_pool,
+            command_buffers,
+            graphics_finished_sems,
+            compute_finished_sems,
+            fences,
+            pending: None
+        }
+    }
+
+    // Whether the work actually runs on a separate queue family
+    pub fn is_dedicated(&self, core: &VkCore) -> bool {
+        self.family_index != core.graphics_family_index
+    }
+
+    // Add to the signal semaphores of the frame's graphics submission. Every frame that signals it must call submit.
+    pub fn graphics_finished(&self, frame: usize) -> vk::Semaphore {
+        self.graphics_finished_sems[frame]
+    }
+
+    // Semaphore the next graphics submission has to wait on at COMPUTE_SHADER, None before the first submit. Take it
+    // only once the submission is certain to happen, a binary semaphore must be waited on exactly once.
+    pub fn take_wait(&mut self) -> Option<vk::Semaphore> {
+        self.pending.take()
+    }
+
+    // Records with record and submits after the frame's graphics submission
+    pub fn submit(&mut self, core: &VkCore, frame: usize, record: impl FnOnce(vk::CommandBuffer)) {
+        let command_buffer = self.command_buffers[frame];
+        let fences = [self.fences[frame]];
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            core.logical_device.wait_for_fences(&fences, true, u64::MAX).unwrap();
+            core.logical_device.reset_fences(&fences).unwrap();
+            core.logical_device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty()).unwrap();
+            core.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+        }
+        record(command_buffer);
+
+        let wait_sems = [self.graphics_finished_sems[frame]];
+        let wait_stages = [vk::PipelineStageFlags::COMPUTE_SHADER];
+        let command_buffers = [command_buffer];
+        let sig_sems = [self.compute_finished_sems[frame]];
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&wait_sems)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&sig_sems);
+        unsafe {
+            core.logical_device.end_command_buffer(command_buffer).unwrap();
+            core.logical_device.queue_submit(self.queue, &[submit_info], fences[0]).unwrap();
+        }
+        self.pending = Some(sig_sems[0]);
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            for s in self.graphics_finished_sems.iter().chain(self.compute_finished_sems.iter()) {
+                core.logical_device.destroy_semaphore(*s, None);
+            }
+            for f in self.fences.iter() {
+                core.logical_device.destroy_fence(*f, None);
+            }
+            core.logical_device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
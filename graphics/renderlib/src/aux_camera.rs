@@ -0,0 +1,307 @@
+use ash::vk;
+use cgmath::{Matrix4, Point3};
+
+use crate::depth::find_depth_format;
+use crate::frame_buffers::destroy_frame_buffers;
+use crate::offscreen::{create_offscreen_render_pass, OffscreenTarget};
+use crate::raster_pipeline::RasterPipeline;
+use crate::render_target::RenderTarget;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_linear_sampler, destroy_sampler};
+use crate::scene::{CameraProjection, SceneCamera};
+use crate::ssao::{create_apply_frame_buffers, create_apply_render_pass, create_set_layout};
+use crate::viewport::ViewportRect;
+use crate::vkcore::VkCore;
+
+pub const MAX_AUX_CAMERAS: usize = 4; // Each one has its own transforms in every frame in flight, like the viewports
+const MINIMAP_ALTITUDE: f32 = 500.0; // Above the center, the minimap sees as far below it
+
+// Matches the push constants in hud.frag
+#[repr(C)]
+pub(crate) struct HudConstants {
+    rect: [f32; 4], // Offset and size in pixels
+    params: [f32; 4] // The opacity
+}
+
+// Stays the same until the camera is removed, a later camera may get it again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AuxCameraId(usize);
+
+impl AuxCameraId {
+    // Below MAX_AUX_CAMERAS, for the camera's transforms
+    pub fn slot(&self) -> usize {
+        self.0
+    }
+}
+
+// A camera that draws the scene into its own texture, which is blended over the finished frame at rect like a HUD
+// element. resolution_scale is the texture's size in pixels of the rect, below 1 for a cheaper and blurrier view.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuxCamera {
+    pub camera: SceneCamera,
+    pub rect: ViewportRect,
+    pub resolution_scale: f32,
+    pub opacity: f32
+}
+
+impl AuxCamera {
+    // Looks straight down at center with north (+Y) up, height is the world units the rect spans vertically
+    pub fn minimap(center: Point3<f32>, height: f32, rect: ViewportRect) -> AuxCamera {
+        AuxCamera {
+            camera: SceneCamera {
+                eye: [center.x, center.y, center.z + MINIMAP_ALTITUDE],
+                target: center.into(),
+                up: [0.0, 1.0, 0.0],
+                projection: CameraProjection::Orthographic { height, near: 0.01, far: MINIMAP_ALTITUDE * 2.0 },
+                focus_distance: None,
+                aperture: 0.0
+            },
+            rect,
+            resolution_scale: 1.0,
+            opacity: 1.0
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.rect.validate()?;
+        self.camera.projection.validate()?;
+        if !(self.resolution_scale > 0.0 && self.resolution_scale <= 1.0) {
+            return Err(format!("The resolution scale must be above 0 and at most 1, not {}", self.resolution_scale));
+        }
+        match (0.0..=1.0).contains(&self.opacity) {
+            true => Ok(()),
+            false => Err(format!("The opacity must be between 0 and 1, not {}", self.opacity))
+        }
+    }
+
+    // Of the camera's texture, the camera's aspect ratio follows it
+    pub fn extent(&self, window_extent: vk::Extent2D) -> vk::Extent2D {
+        let extent = self.rect.extent(window_extent);
+        vk::Extent2D {
+            width: ((extent.width as f32 * self.resolution_scale) as u32).max(1),
+            height: ((extent.height as f32 * self.resolution_scale) as u32).max(1)
+        }
+    }
+}
+
+// Auxiliary cameras for the raster renderer, like a minimap. cmd_draw_views draws each camera's view of the scene into
+// its offscreen target before the main render pass, with pipelines made for the main render pass, and cmd_composite
+// blends the targets over the swap chain image once it is otherwise finished. Cameras can only be added, changed and
+// removed between frames.
+pub struct AuxCameras {
+    render_pass: vk::RenderPass, // Of the views
+    surface_format: vk::Format,
+    window_extent: vk::Extent2D,
+    slots: Vec<Option<(AuxCamera, OffscreenTarget)>>,
+    sampler: vk::Sampler,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>, // One per slot
+    hud_render_pass: vk::RenderPass,
+    hud_frame_buffers: Vec<vk::Framebuffer>,
+    hud: RasterPipeline
+}
+
+impl AuxCameras {
+    pub fn new(core: &VkCore, render_target: &RenderTarget) -> AuxCameras {
+        let set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+        ]);
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(MAX_AUX_CAMERAS as u32)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(MAX_AUX_CAMERAS as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = [set_layout; MAX_AUX_CAMERAS];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        let hud_render_pass = create_apply_render_pass(core, render_target);
+
+        AuxCameras {
+            render_pass: create_offscreen_render_pass(core, render_target.surface_format, find_depth_format(core),
+                                                      core.max_msaa_samples),
+            surface_format: render_target.surface_format,
+            window_extent: render_target.extent,
+            slots: (0..MAX_AUX_CAMERAS).map(|_| None).collect(),
+            sampler: create_linear_sampler(core),
+            set_layout,
+            descriptor_pool,
+            sets,
+            hud_render_pass,
+            hud_frame_buffers: create_apply_frame_buffers(core, hud_render_pass, render_target),
+            hud: RasterPipeline::new_hud(core, hud_render_pass, set_layout)
+        }
+    }
+
+    // The camera's target in the first free slot
+    pub fn add(&mut self, core: &VkCore, camera: AuxCamera) -> Result<AuxCameraId, String> {
+        camera.validate()?;
+        let slot = self.slots.iter().position(|s| s.is_none())
+            .ok_or_else(|| format!("There can be at most {} auxiliary cameras", MAX_AUX_CAMERAS))?;
+        self.fill_slot(core, slot, camera);
+
+        Ok(AuxCameraId(slot))
+    }
+
+    // The target is only created again when its size changes, which waits for the device to become idle
+    pub fn set(&mut self, core: &VkCore, id: AuxCameraId, camera: AuxCamera) -> Result<(), String> {
+        camera.validate()?;
+        match self.slots[id.0].as_mut() {
+            Some((current, target)) if target.extent == camera.extent(self.window_extent) => {
+                *current = camera;
+                Ok(())
+            },
+            Some(_) => {
+                unsafe { core.logical_device.device_wait_idle().unwrap() };
+                self.clear_slot(core, id.0);
+                self.fill_slot(core, id.0, camera);
+                Ok(())
+            },
+            None => Err(format!("{:?} was removed", id))
+        }
+    }
+
+    // Waits for the device to become idle, frames in flight may still draw into the target
+    pub fn remove(&mut self, core: &VkCore, id: AuxCameraId) -> Result<(), String> {
+        match self.slots[id.0].is_some() {
+            true => {
+                unsafe { core.logical_device.device_wait_idle().unwrap() };
+                self.clear_slot(core, id.0);
+                Ok(())
+            },
+            false => Err(format!("{:?} was removed", id))
+        }
+    }
+
+    pub fn get(&self, id: AuxCameraId) -> Option<&AuxCamera> {
+        self.slots[id.0].as_ref().map(|(camera, _)| camera)
+    }
+
+    // In slot order, with the projection of each camera for its target
+    pub fn iter(&self) -> impl Iterator<Item = (AuxCameraId, &AuxCamera, Matrix4<f32>)> {
+        self.slots.iter().enumerate()
+            .filter_map(|(slot, s)| s.as_ref().map(|(camera, target)| (AuxCameraId(slot), camera,
+                                                                       camera.camera.projection(target.extent))))
+    }
+
+    // In slot order, for adding them again to new device resources with the same ids
+    pub fn cameras(&self) -> Vec<(AuxCameraId, AuxCamera)> {
+        self.slots.iter().enumerate()
+            .filter_map(|(slot, s)| s.as_ref().map(|(camera, _)| (AuxCameraId(slot), camera.clone())))
+            .collect()
+    }
+
+    // Like add, into the slot of id, which must be free
+    pub fn insert(&mut self, core: &VkCore, id: AuxCameraId, camera: AuxCamera) {
+        assert!(self.slots[id.0].is_none(), "{:?} is taken", id);
+        self.fill_slot(core, id.0, camera);
+    }
+
+    fn fill_slot(&mut self, core: &VkCore, slot: usize, camera: AuxCamera) {
+        let target = OffscreenTarget::new(core, self.render_pass, self.surface_format,
+                                          camera.extent(self.window_extent));
+        let view_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(target.resolved.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let write = [vk::WriteDescriptorSet::default()
+            .dst_set(self.sets[slot])
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&view_info)];
+        unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+        self.slots[slot] = Some((camera, target));
+    }
+
+    fn clear_slot(&mut self, core: &VkCore, slot: usize) {
+        if let Some((_, target)) = self.slots[slot].take() {
+            target.destroy(core);
+        }
+    }
+
+    // Records draw for every camera inside its target's render pass, with the viewport and scissor set to all of the
+    // target. draw gets the camera's id, for picking its transforms.
+    pub fn cmd_draw_views<F: Fn(AuxCameraId, &AuxCamera)>(&self, core: &VkCore, command_buffer: vk::CommandBuffer,
+                                                           draw: F) {
+        for (slot, (camera, target)) in self.slots.iter().enumerate().filter_map(|(i, s)| Some((i, s.as_ref()?))) {
+            target.cmd_draw(core, command_buffer, self.render_pass, || draw(AuxCameraId(slot), camera));
+        }
+    }
+
+    // Blends every camera's target over its rect of the swap chain image, which is left in PRESENT_SRC_KHR. Nothing
+    // is recorded without cameras.
+    pub fn cmd_composite(&self, core: &VkCore, command_buffer: vk::CommandBuffer, image_index: u32) {
+        if self.slots.iter().all(|s| s.is_none()) {
+            return;
+        }
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.window_extent);
+        let hud_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.hud_render_pass)
+            .framebuffer(self.hud_frame_buffers[image_index as usize])
+            .render_area(render_area);
+        // The post-process chain may have copied into the swap chain image last
+        let image_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        let logical_device = &core.logical_device;
+
+        unsafe {
+            logical_device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::TRANSFER |
+                                                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::DependencyFlags::empty(), &[image_barrier], &[], &[]);
+            logical_device.cmd_begin_render_pass(command_buffer, &hud_pass_info, vk::SubpassContents::INLINE);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.hud.pipelines[0]);
+            for (slot, (camera, _)) in self.slots.iter().enumerate().filter_map(|(i, s)| Some((i, s.as_ref()?))) {
+                let pixels = camera.rect.pixels(self.window_extent);
+                let constants = [HudConstants {
+                    rect: [pixels.offset.x as f32, pixels.offset.y as f32, pixels.extent.width as f32,
+                        pixels.extent.height as f32],
+                    params: [camera.opacity, 0.0, 0.0, 0.0]
+                }];
+                logical_device.cmd_set_viewport(command_buffer, 0, &[camera.rect.viewport(self.window_extent)]);
+                logical_device.cmd_set_scissor(command_buffer, 0, &[pixels]);
+                logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                        self.hud.pipeline_layout, 0, &[self.sets[slot]], &[]);
+                logical_device.cmd_push_constants(command_buffer, self.hud.pipeline_layout,
+                                                  vk::ShaderStageFlags::FRAGMENT, 0, cast_to_u8_slice(&constants));
+                logical_device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            }
+            logical_device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    // After the swap chain was created again, nothing may still be using the old targets. The targets follow the new
+    // size of their rects.
+    pub fn resize(&mut self, core: &VkCore, render_target: &RenderTarget) {
+        destroy_frame_buffers(core, &self.hud_frame_buffers);
+        self.hud_frame_buffers = create_apply_frame_buffers(core, self.hud_render_pass, render_target);
+        self.window_extent = render_target.extent;
+        for (id, camera) in self.cameras() {
+            self.clear_slot(core, id.0);
+            self.fill_slot(core, id.0, camera);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for (_, target) in self.slots.iter().flatten() {
+            target.destroy(core);
+        }
+        destroy_frame_buffers(core, &self.hud_frame_buffers);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            core.logical_device.destroy_render_pass(self.render_pass, None);
+            core.logical_device.destroy_render_pass(self.hud_render_pass, None);
+        }
+        destroy_sampler(core, self.sampler);
+        self.hud.destroy(core);
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::{HashMap, HashSet};
+
+use ash::vk;
+
+use crate::color_pipeline::{SRGB_SWAPCHAIN_COLOR_SPACE, SRGB_SWAPCHAIN_FORMAT};
+use crate::display_mode::DisplayMode;
+use crate::gpu_buffer::GpuBuffer;
+use crate::image::create_image;
+use crate::render_target::RenderTarget;
+use crate::vkcore::VkCore;
+
+// Opaque resource ids, each backend maps them to its own objects
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BufferHandle(pub u64);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImageHandle(pub u64);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SwapchainHandle(pub u64);
+
+// One high level operation, as logged by NullBackend
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendOp {
+    CreateBuffer { buffer: BufferHandle, size: vk::DeviceSize, usage: vk::BufferUsageFlags },
+    DestroyBuffer(BufferHandle),
+    CreateImage { image: ImageHandle, extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags },
+    DestroyImage(ImageHandle),
+    CreateSwapchain { swapchain: SwapchainHandle, extent: vk::Extent2D },
+    DestroySwapchain(SwapchainHandle),
+    WaitIdle,
+    Barrier { src_stage: vk::PipelineStageFlags, dst_stage: vk::PipelineStageFlags },
+    DrawIndexed { index_count: u32, instance_count: u32 }
+}
+
+// The operations renderer logic needs, so that logic can be written once and tested against NullBackend on machines
+// without Vulkan
+pub trait Backend {
+    fn create_buffer(&mut self, size: vk::DeviceSize, usage: vk::BufferUsageFlags) -> BufferHandle;
+    fn destroy_buffer(&mut self, buffer: BufferHandle);
+    // Device local, single mip and sample
+    fn create_image(&mut self, extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags) -> ImageHandle;
+    fn destroy_image(&mut self, image: ImageHandle);
+    // Sized to the window like RenderTarget, returns the extent that was picked
+    fn create_swapchain(&mut self) -> (SwapchainHandle, vk::Extent2D);
+    fn destroy_swapchain(&mut self, swapchain: SwapchainHandle);
+    fn wait_idle(&mut self);
+    fn cmd_barrier(&mut self, src_stage: vk::PipelineStageFlags, dst_stage: vk::PipelineStageFlags);
+    fn cmd_draw_indexed(&mut self, index_count: u32, instance_count: u32);
+}
+
+// Creates nothing and records every call. Destroying a handle that isn't alive panics, which catches double frees
+// and use after destroy in the logic under test.
+pub struct NullBackend {
+    pub window_extent: vk::Extent2D, // Change to simulate a resize before the next create_swapchain
+    log: Vec<BackendOp>,
+    live: HashSet<u64>,
+    next_handle: u64
+}
+
+impl NullBackend {
+    pub fn new(window_extent: vk::Extent2D) -> NullBackend {
+        NullBackend {
+            window_extent,
+            log: Vec::new(),
+            live: HashSet::new(),
+            next_handle: 1 // 0 stays free to mean "no resource" in logs
+        }
+    }
+
+    pub fn ops(&self) -> &[BackendOp] {
+        self.log.as_slice()
+    }
+
+    pub fn clear_ops(&mut self) {
+        self.log.clear();
+    }
+
+    // Buffers, images and swap chains that were created and not destroyed yet
+    pub fn live_count(&self) -> usize {
+        self.live.len()
+    }
+
+    fn allocate(&mut self) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.live.insert(handle);
+        handle
+    }
+
+    fn release(&mut self, handle: u64) {
+        if !self.live.remove(&handle) {
+            panic!("Handle {} destroyed twice or never created", handle);
+        }
+    }
+}
+
+impl Backend for NullBackend {
+    fn create_buffer(&mut self, size: vk::DeviceSize, usage: vk::BufferUsageFlags) -> BufferHandle {
+        let buffer = BufferHandle(self.allocate());
+        self.log.push(BackendOp::CreateBuffer { buffer, size, usage });
+        buffer
+    }
+
+    fn destroy_buffer(&mut self, buffer: BufferHandle) {
+        self.release(buffer.0);
+        self.log.push(BackendOp::DestroyBuffer(buffer));
+    }
+
+    fn create_image(&mut self, extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags) -> ImageHandle {
+        let image = ImageHandle(self.allocate());
+        self.log.push(BackendOp::CreateImage { image, extent, format, usage });
+        image
+    }
+
+    fn destroy_image(&mut self, image: ImageHandle) {
+        self.release(image.0);
+        self.log.push(BackendOp::DestroyImage(image));
+    }
+
+    fn create_swapchain(&mut self) -> (SwapchainHandle, vk::Extent2D) {
+        let swapchain = SwapchainHandle(self.allocate());
+        let extent = self.window_extent;
+        self.log.push(BackendOp::CreateSwapchain { swapchain, extent });
+        (swapchain, extent)
+    }
+
+    fn destroy_swapchain(&mut self, swapchain: SwapchainHandle) {
+        self.release(swapchain.0);
+        self.log.push(BackendOp::DestroySwapchain(swapchain));
+    }
+
+    fn wait_idle(&mut self) {
+        self.log.push(BackendOp::WaitIdle);
+    }
+
+    fn cmd_barrier(&mut self, src_stage: vk::PipelineStageFlags, dst_stage: vk::PipelineStageFlags) {
+        self.log.push(BackendOp::Barrier { src_stage, dst_stage });
+    }
+
+    fn cmd_draw_indexed(&mut self, index_count: u32, instance_count: u32) {
+        self.log.push(BackendOp::DrawIndexed { index_count, instance_count });
+    }
+}
+
+// The objects behind a VkBackend's handles, kept by the renderer that owns the VkCore between VkBackend::new calls.
+// Swap chains are created with the present and display modes set here.
+pub struct VkResources {
+    pub swapchain_usage: vk::ImageUsageFlags,
+    pub present_mode: vk::PresentModeKHR,
+    pub display_mode: DisplayMode,
+    buffers: HashMap<u64, GpuBuffer>,
+    images: HashMap<u64, (vk::Image, vk::DeviceMemory)>,
+    swapchains: HashMap<u64, RenderTarget>,
+    next_handle: u64
+}
+
+impl VkResources {
+    pub fn new(swapchain_usage: vk::ImageUsageFlags, present_mode: vk::PresentModeKHR,
+               display_mode: DisplayMode) -> VkResources {
+        VkResources {
+            swapchain_usage,
+            present_mode,
+            display_mode,
+            buffers: HashMap::new(),
+            images: HashMap::new(),
+            swapchains: HashMap::new(),
+            next_handle: 1
+        }
+    }
+
+    pub fn buffer(&self, buffer: BufferHandle) -> &GpuBuffer {
+        &self.buffers[&buffer.0]
+    }
+
+    pub fn image(&self, image: ImageHandle) -> vk::Image {
+        self.images[&image.0].0
+    }
+
+    pub fn render_target(&self, swapchain: SwapchainHandle) -> &RenderTarget {
+        &self.swapchains[&swapchain.0]
+    }
+
+    fn allocate(&mut self) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        handle
+    }
+}
+
+// Backend over a real device. Commands are recorded into command_buffer, which the caller begins, ends and submits.
+pub struct VkBackend<'a> {
+    core: &'a VkCore,
+    resources: &'a mut VkResources,
+    pub command_buffer: vk::CommandBuffer
+}
+
+impl<'a> VkBackend<'a> {
+    pub fn new(core: &'a VkCore, resources: &'a mut VkResources) -> VkBackend<'a> {
+        VkBackend {
+            core,
+            resources,
+            command_buffer: vk::CommandBuffer::null()
+        }
+    }
+}
+
+impl<'a> Backend for VkBackend<'a> {
+    fn create_buffer(&mut self, size: vk::DeviceSize, usage: vk::BufferUsageFlags) -> BufferHandle {
+        let handle = self.resources.allocate();
+        self.resources.buffers.insert(handle, GpuBuffer::new(self.core, size, usage,
+                                                             vk::MemoryPropertyFlags::DEVICE_LOCAL));
+        BufferHandle(handle)
+    }
+
+    fn destroy_buffer(&mut self, buffer: BufferHandle) {
+        self.resources.buffers.remove(&buffer.0).unwrap().destroy(self.core);
+    }
+
+    fn create_image(&mut self, extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags) -> ImageHandle {
+        let handle = self.resources.allocate();
+        self.resources.images.insert(handle, create_image(self.core, extent.width, extent.height, 1, format,
+                                                          vk::ImageTiling::OPTIMAL, usage,
+                                                          vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                          vk::SampleCountFlags::TYPE_1));
+        ImageHandle(handle)
+    }
+
+    fn destroy_image(&mut self, image: ImageHandle) {
+        let (image, memory) = self.resources.images.remove(&image.0).unwrap();
+        unsafe {
+            self.core.logical_device.destroy_image(image, None);
+            self.core.logical_device.free_memory(memory, None);
+        }
+    }
+
+    fn create_swapchain(&mut self) -> (SwapchainHandle, vk::Extent2D) {
+        let handle = self.resources.allocate();
+        let render_target = RenderTarget::new_with_display_mode(self.core, self.resources.swapchain_usage,
+                                                                SRGB_SWAPCHAIN_FORMAT,
+                                                                Some(SRGB_SWAPCHAIN_COLOR_SPACE),
+                                                                self.resources.present_mode,
+                                                                self.resources.display_mode, false);
+        let extent = render_target.extent;
+        self.resources.swapchains.insert(handle, render_target);
+        (SwapchainHandle(handle), extent)
+    }
+
+    fn destroy_swapchain(&mut self, swapchain: SwapchainHandle) {
+        self.resources.swapchains.remove(&swapchain.0).unwrap().destroy(self.core);
+    }
+
+    fn wait_idle(&mut self) {
+        unsafe { self.core.logical_device.device_wait_idle().unwrap() };
+    }
+
+    fn cmd_barrier(&mut self, src_stage: vk::PipelineStageFlags, dst_stage: vk::PipelineStageFlags) {
+        let barrier = [vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+            .dst_access_mask(vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE)];
+        unsafe {
+            self.core.logical_device.cmd_pipeline_barrier(self.command_buffer, src_stage, dst_stage,
+                                                          vk::DependencyFlags::empty(), &barrier, &[], &[]);
+        }
+    }
+
+    fn cmd_draw_indexed(&mut self, index_count: u32, instance_count: u32) {
+        unsafe {
+            self.core.logical_device.cmd_draw_indexed(self.command_buffer, index_count, instance_count, 0, 0, 0);
+        }
+    }
+}
+
+// Swap chain and the window sized images that have to follow it. Renderers with their own depth, like the hybrid
+// renderer's GBuffer, leave depth_format out.
+pub struct SwapchainResources {
+    pub swapchain: SwapchainHandle,
+    pub extent: vk::Extent2D,
+    pub depth: Option<ImageHandle>,
+    depth_format: Option<vk::Format>
+}
+
+impl SwapchainResources {
+    pub fn new<B: Backend>(backend: &mut B, depth_format: Option<vk::Format>) -> SwapchainResources {
+        let (swapchain, extent) = backend.create_swapchain();
+        let depth = depth_format
+            .map(|format| backend.create_image(extent, format, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT));
+
+        SwapchainResources {
+            swapchain,
+            extent,
+            depth,
+            depth_format
+        }
+    }
+
+    // After an out of date or suboptimal present. Nothing may be destroyed while frames in flight still use it, and
+    // the images that depend on the swap chain go before it.
+    pub fn recreate<B: Backend>(&mut self, backend: &mut B) {
+        backend.wait_idle();
+        self.destroy(backend);
+        *self = SwapchainResources::new(backend, self.depth_format);
+    }
+
+    pub fn destroy<B: Backend>(&self, backend: &mut B) {
+        if let Some(depth) = self.depth {
+            backend.destroy_image(depth);
+        }
+        backend.destroy_swapchain(self.swapchain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXTENT: vk::Extent2D = vk::Extent2D { width: 800, height: 600 };
+
+    #[test]
+    fn null_backend_logs_in_order() {
+        let mut backend = NullBackend::new(EXTENT);
+        let buffer = backend.create_buffer(64, vk::BufferUsageFlags::VERTEX_BUFFER);
+        backend.cmd_draw_indexed(36, 1);
+        backend.destroy_buffer(buffer);
+
+        assert_eq!(backend.ops(), &[
+            BackendOp::CreateBuffer { buffer, size: 64, usage: vk::BufferUsageFlags::VERTEX_BUFFER },
+            BackendOp::DrawIndexed { index_count: 36, instance_count: 1 },
+            BackendOp::DestroyBuffer(buffer)
+        ]);
+        assert_eq!(backend.live_count(), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_destroy_panics() {
+        let mut backend = NullBackend::new(EXTENT);
+        let buffer = backend.create_buffer(64, vk::BufferUsageFlags::VERTEX_BUFFER);
+        backend.destroy_buffer(buffer);
+        backend.destroy_buffer(buffer);
+    }
+
+    #[test]
+    fn recreate_waits_then_destroys_dependents_first() {
+        let mut backend = NullBackend::new(EXTENT);
+        let mut resources = SwapchainResources::new(&mut backend, Some(vk::Format::D32_SFLOAT));
+        let (old_swapchain, old_depth) = (resources.swapchain, resources.depth.unwrap());
+        backend.clear_ops();
+
+        backend.window_extent = vk::Extent2D { width: 1024, height: 768 };
+        resources.recreate(&mut backend);
+
+        let ops = backend.ops();
+        assert_eq!(ops[0], BackendOp::WaitIdle);
+        assert_eq!(ops[1], BackendOp::DestroyImage(old_depth));
+        assert_eq!(ops[2], BackendOp::DestroySwapchain(old_swapchain));
+        assert_eq!(ops[3], BackendOp::CreateSwapchain { swapchain: resources.swapchain, extent: backend.window_extent });
+        assert_eq!(resources.extent, backend.window_extent);
+        assert!(matches!(ops[4], BackendOp::CreateImage { extent, .. } if extent == backend.window_extent));
+    }
+
+    #[test]
+    fn destroy_releases_everything() {
+        let mut backend = NullBackend::new(EXTENT);
+        let mut resources = SwapchainResources::new(&mut backend, Some(vk::Format::D32_SFLOAT));
+        resources.recreate(&mut backend);
+        resources.destroy(&mut backend);
+        assert_eq!(backend.live_count(), 0);
+    }
+
+    #[test]
+    fn recreate_without_depth_only_replaces_the_swapchain() {
+        let mut backend = NullBackend::new(EXTENT);
+        let mut resources = SwapchainResources::new(&mut backend, None);
+        let old_swapchain = resources.swapchain;
+        backend.clear_ops();
+
+        resources.recreate(&mut backend);
+
+        assert_eq!(backend.ops(), &[
+            BackendOp::WaitIdle,
+            BackendOp::DestroySwapchain(old_swapchain),
+            BackendOp::CreateSwapchain { swapchain: resources.swapchain, extent: EXTENT }
+        ]);
+        assert!(resources.depth.is_none());
+        assert_eq!(backend.live_count(), 1);
+    }
+}
@@ -0,0 +1,201 @@
+use std::fs;
+use std::path::PathBuf;
+
+use cgmath::Point3;
+use tracing::info;
+
+use crate::profiler::{PipelineStatistics, PresentTiming};
+
+// Camera path for benchmark runs. Each non empty line of the file is "eye_x eye_y eye_z target_x target_y target_z",
+// lines starting with # are comments. The eye and target are interpolated separately with Catmull-Rom splines.
+pub struct CameraSpline {
+    eyes: Vec<Point3<f32>>,
+    targets: Vec<Point3<f32>>
+}
+
+fn catmull_rom(p0: Point3<f32>, p1: Point3<f32>, p2: Point3<f32>, p3: Point3<f32>, t: f32) -> Point3<f32> {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    let blend = |a: f32, b: f32, c: f32, d: f32| {
+        0.5 * ((2.0 * b) + (-a + c) * t + (2.0 * a - 5.0 * b + 4.0 * c - d) * t2 + (-a + 3.0 * b - 3.0 * c + d) * t3)
+    };
+
+    Point3::new(blend(p0.x, p1.x, p2.x, p3.x), blend(p0.y, p1.y, p2.y, p3.y), blend(p0.z, p1.z, p2.z, p3.z))
+}
+
+fn sample_points(points: &[Point3<f32>], t: f32) -> Point3<f32> {
+    if points.len() == 1 {
+        return points[0];
+    }
+
+    let segments = points.len() - 1;
+    let scaled = t.clamp(0.0, 1.0) * segments as f32;
+    let segment = (scaled.floor() as usize).min(segments - 1);
+    let local_t = scaled - segment as f32;
+    // The end points are repeated so that the curve passes through every control point
+    let p0 = points[segment.saturating_sub(1)];
+    let p1 = points[segment];
+    let p2 = points[segment + 1];
+    let p3 = points[(segment + 2).min(points.len() - 1)];
+
+    catmull_rom(p0, p1, p2, p3, local_t)
+}
+
+impl CameraSpline {
+    pub fn load(path: &str) -> Result<CameraSpline, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let mut eyes: Vec<Point3<f32>> = Vec::new();
+        let mut targets: Vec<Point3<f32>> = Vec::new();
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let values: Vec<f32> = match line.split_whitespace().map(|v| v.parse::<f32>()).collect() {
+                Ok(v) => v,
+                Err(_) => return Err(format!("{}:{}: expected numbers", path, line_idx + 1))
+            };
+            if values.len() != 6 {
+                return Err(format!("{}:{}: expected 6 values, found {}", path, line_idx + 1, values.len()));
+            }
+            eyes.push(Point3::new(values[0], values[1], values[2]));
+            targets.push(Point3::new(values[3], values[4], values[5]));
+        }
+
+        if eyes.is_empty() {
+            return Err(format!("{}: no control points", path));
+        }
+
+        Ok(CameraSpline {
+            eyes,
+            targets
+        })
+    }
+
+    // t runs from 0 at the first control point to 1 at the last. Returns (eye, target).
+    pub fn sample(&self, t: f32) -> (Point3<f32>, Point3<f32>) {
+        (sample_points(&self.eyes, t), sample_points(&self.targets, t))
+    }
+}
+
+pub struct FrameStats {
+    pub frame_ms: f64, // Wall time since the previous frame
+    pub cpu_ms: f64, // Time spent recording and submitting, excluding fence waits
+    pub gpu_ms: Option<f64>, // From the GPU timer, lags behind by the number of frames in flight
+    // By pass, from PipelineStatsQueries with the same lag. Empty where the renderer doesn't collect them.
+    pub passes: Vec<(&'static str, PipelineStatistics)>,
+    // Reported by DisplayTiming during the frame, for frames presented some time before. Empty without it.
+    pub presents: Vec<PresentTiming>
+}
+
+pub struct Benchmark {
+    pub spline: CameraSpline,
+    pub frame_count: usize,
+    pub report_path: PathBuf, // Written as <report_path>.csv and <report_path>.json
+    stats: Vec<FrameStats>
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (p / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
+
+struct Summary {
+    mean: f64,
+    min: f64,
+    p50: f64,
+    p95: f64,
+    p99: f64,
+    max: f64
+}
+
+fn summarize(values: Vec<f64>) -> Summary {
+    let mut sorted = values;
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mean = match sorted.len() {
+        0 => 0.0,
+        n => sorted.iter().sum::<f64>() / n as f64
+    };
+
+    Summary {
+        mean,
+        min: *sorted.first().unwrap_or(&0.0),
+        p50: percentile(&sorted, 50.0),
+        p95: percentile(&sorted, 95.0),
+        p99: percentile(&sorted, 99.0),
+        max: *sorted.last().unwrap_or(&0.0)
+    }
+}
+
+impl Summary {
+    fn to_json(&self) -> String {
+        format!("{{\"mean\": {:.4}, \"min\": {:.4}, \"p50\": {:.4}, \"p95\": {:.4}, \"p99\": {:.4}, \"max\": {:.4}}}",
+                self.mean, self.min, self.p50, self.p95, self.p99, self.max)
+    }
+}
+
+impl Benchmark {
+    pub fn new(spline: CameraSpline, frame_count: usize, report_path: PathBuf) -> Benchmark {
+        Benchmark {
+            spline,
+            frame_count,
+            report_path,
+            stats: Vec::with_capacity(frame_count)
+        }
+    }
+
+    // Camera (eye, target) for the next frame to be rendered
+    pub fn camera(&self) -> (Point3<f32>, Point3<f32>) {
+        let t = match self.frame_count {
+            0 | 1 => 0.0,
+            n => self.stats.len() as f32 / (n - 1) as f32
+        };
+
+        self.spline.sample(t)
+    }
+
+    pub fn record(&mut self, stats: FrameStats) {
+        self.stats.push(stats);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.stats.len() >= self.frame_count
+    }
+
+    // Writes the report's .csv and .json next to each other and logs the summary
+    pub fn write_report(&self) -> Result<(), String> {
+        let mut csv = String::from("frame,frame_ms,cpu_ms,gpu_ms\n");
+        for (n, s) in self.stats.iter().enumerate() {
+            let gpu = match s.gpu_ms {
+                Some(g) => format!("{:.4}", g),
+                None => String::new()
+            };
+            csv += format!("{},{:.4},{:.4},{}\n", n, s.frame_ms, s.cpu_ms, gpu).as_str();
+        }
+        let csv_path = self.report_path.with_extension("csv");
+        fs::write(&csv_path, csv).map_err(|e| format!("Couldn't write {}: {}", csv_path.display(), e))?;
+
+        let frame = summarize(self.stats.iter().map(|s| s.frame_ms).collect());
+        let cpu = summarize(self.stats.iter().map(|s| s.cpu_ms).collect());
+        // null without a single GPU time, e.g. when the renderer has no GPU timer
+        let gpu_samples: Vec<f64> = self.stats.iter().filter_map(|s| s.gpu_ms).collect();
+        let gpu = (!gpu_samples.is_empty()).then(|| summarize(gpu_samples));
+        let json = format!("{{\n  \"frames\": {},\n  \"frame_ms\": {},\n  \"cpu_ms\": {},\n  \"gpu_ms\": {}\n}}\n",
+                           self.stats.len(), frame.to_json(), cpu.to_json(),
+                           gpu.as_ref().map_or(String::from("null"), Summary::to_json));
+        let json_path = self.report_path.with_extension("json");
+        fs::write(&json_path, json).map_err(|e| format!("Couldn't write {}: {}", json_path.display(), e))?;
+
+        info!("Benchmark: {} frames", self.stats.len());
+        for (name, s) in [("frame", &frame), ("cpu", &cpu)].into_iter().chain(gpu.as_ref().map(|g| ("gpu", g))) {
+            info!("{:>6} ms  mean {:8.3}  p50 {:8.3}  p95 {:8.3}  p99 {:8.3}  max {:8.3}", name, s.mean, s.p50,
+                  s.p95, s.p99, s.max);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::renderutils::cast_to_u8_slice;
+use crate::vkcore::VkCore;
+
+// Where pipelines bind the heap, BINDLESS_SET in bindless.glsl
+pub const RESOURCE_HEAP_SET: u32 = 1;
+
+const IMAGE_BINDING: u32 = 0;
+const SAMPLER_BINDING: u32 = 1;
+const BUFFER_BINDING: u32 = 2; // Last, only the last binding of a set can have a variable count
+
+// Positions in the heap's arrays, the shader reads heapImages[handle.0] and so on. They stay the same until released.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImageHandle(pub u32);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SamplerHandle(pub u32);
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BufferHandle(pub u32);
+
+// Matches HeapIndices in bindless.glsl, what a draw or dispatch pushes to say which resources it uses
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapIndices {
+    pub image: u32,
+    pub sampler: u32,
+    pub buffer: u32,
+    pub user: u32
+}
+
+impl HeapIndices {
+    pub fn new(image: ImageHandle, sampler: SamplerHandle, buffer: BufferHandle) -> HeapIndices {
+        HeapIndices {
+            image: image.0,
+            sampler: sampler.0,
+            buffer: buffer.0,
+            user: 0
+        }
+    }
+
+    // For pipeline layouts of shaders that use the heap's push constant block
+    pub fn push_constant_range(stages: vk::ShaderStageFlags) -> vk::PushConstantRange {
+        vk::PushConstantRange::default()
+            .stage_flags(stages)
+            .offset(0)
+            .size(mem::size_of::<HeapIndices>() as u32)
+    }
+
+    pub fn cmd_push(&self, core: &VkCore, command_buffer: vk::CommandBuffer, layout: vk::PipelineLayout,
+                    stages: vk::ShaderStageFlags) {
+        unsafe {
+            core.logical_device.cmd_push_constants(command_buffer, layout, stages, 0, cast_to_u8_slice(self));
+        }
+    }
+}
+
+// Slots of one of the heap's arrays. Released slots are reused once the frames in flight, which may still read them,
+// are done.
+struct HeapArray {
+    capacity: u32,
+    next: u32, // Slots from here on were never used
+    free: Vec<u32>,
+    retired: Vec<(u32, usize)> // And the number of frames left until they can be reused
+}
+
+impl HeapArray {
+    fn new(capacity: u32) -> HeapArray {
+        HeapArray {
+            capacity,
+            next: 0,
+            free: Vec::new(),
+            retired: Vec::new()
+        }
+    }
+
+    fn allocate(&mut self, kind: &str) -> Result<u32, String> {
+        if let Some(index) = self.free.pop() {
+            return Ok(index);
+        }
+        if self.next == self.capacity {
+            return Err(format!("All {} {} slots of the resource heap are used", self.capacity, kind));
+        }
+        self.next += 1;
+
+        Ok(self.next - 1)
+    }
+
+    fn release(&mut self, index: u32, max_frames: usize) {
+        assert!(index < self.next && !self.free.contains(&index) && !self.retired.iter().any(|r| r.0 == index),
+                "Slot {} is not in use", index);
+        self.retired.push((index, max_frames));
+    }
+
+    fn end_frame(&mut self) {
+        for (index, frames_left) in self.retired.iter_mut() {
+            *frames_left = frames_left.saturating_sub(1);
+            if *frames_left == 0 {
+                self.free.push(*index);
+            }
+        }
+        self.retired.retain(|r| r.1 > 0);
+    }
+}
+
+// One descriptor set with large arrays of sampled images, samplers and storage buffers that every pipeline can bind,
+// so that resources are picked by index in the shader instead of by binding a set per draw. The layout is visible to
+// all stages, raster, compute and ray tracing pipelines share the same set. The bindings are UPDATE_AFTER_BIND and
+// partially bound: registering writes the descriptor right away, also while command buffers using the set are
+// pending, and slots nothing registered are never read. Needs VkCore::descriptor_indexing_supported.
+pub struct ResourceHeap {
+    images: HeapArray,
+    samplers: HeapArray,
+    buffers: HeapArray,
+    max_frames: usize,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    set: vk::DescriptorSet
+}
+
+impl ResourceHeap {
+    // The capacities are clamped to the device's limits. The buffer array's size is only fixed when the set is
+    // allocated, the layout allows as many as the device does.
+    pub fn new(core: &VkCore, image_capacity: u32, sampler_capacity: u32, buffer_capacity: u32, max_frames: usize)
+        -> Result<ResourceHeap, String> {
+        if !core.descriptor_indexing_supported {
+            return Err("The device doesn't support descriptor indexing".to_string());
+        }
+        let mut indexing_properties = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut dev_properties2 = vk::PhysicalDeviceProperties2::default()
+            .push_next(&mut indexing_properties);
+        unsafe { core.instance.get_physical_device_properties2(core.physical_device, &mut dev_properties2) };
+        let image_capacity = image_capacity
+            .min(indexing_properties.max_per_stage_descriptor_update_after_bind_sampled_images)
+            .min(indexing_properties.max_descriptor_set_update_after_bind_sampled_images);
+        let sampler_capacity = sampler_capacity
+            .min(indexing_properties.max_per_stage_descriptor_update_after_bind_samplers)
+            .min(indexing_properties.max_descriptor_set_update_after_bind_samplers);
+        // The three arrays count against the same per stage limit
+        let max_buffers = indexing_properties.max_per_stage_descriptor_update_after_bind_storage_buffers
+            .min(indexing_properties.max_descriptor_set_update_after_bind_storage_buffers)
+            .min(indexing_properties.max_per_stage_update_after_bind_resources
+                .saturating_sub(image_capacity + sampler_capacity));
+        let buffer_capacity = buffer_capacity.min(max_buffers);
+
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(IMAGE_BINDING)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(image_capacity)
+                .stage_flags(vk::ShaderStageFlags::ALL),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(SAMPLER_BINDING)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(sampler_capacity)
+                .stage_flags(vk::ShaderStageFlags::ALL),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(BUFFER_BINDING)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(max_buffers)
+                .stage_flags(vk::ShaderStageFlags::ALL)
+        ];
+        let flags = vk::DescriptorBindingFlags::PARTIALLY_BOUND | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND |
+            vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING;
+        let binding_flags = [flags, flags, flags | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT];
+        let mut binding_flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::default()
+            .binding_flags(&binding_flags);
+        let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .bindings(&bindings)
+            .push_next(&mut binding_flags_info);
+        let set_layout = unsafe {
+            core.logical_device.create_descriptor_set_layout(&layout_create_info, None)
+                .map_err(|e| format!("Can't create the resource heap's layout: {}", e))?
+        };
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(image_capacity),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLER)
+                .descriptor_count(sampler_capacity),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(buffer_capacity.max(1))
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let counts = [buffer_capacity];
+        let mut count_info = vk::DescriptorSetVariableDescriptorCountAllocateInfo::default()
+            .descriptor_counts(&counts);
+        let layouts = [set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts)
+            .push_next(&mut count_info);
+        let set = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap()[0] };
+
+        Ok(ResourceHeap {
+            images: HeapArray::new(image_capacity),
+            samplers: HeapArray::new(sampler_capacity),
+            buffers: HeapArray::new(buffer_capacity),
+            max_frames,
+            set_layout,
+            descriptor_pool,
+            set
+        })
+    }
+
+    // For pipeline layouts, at RESOURCE_HEAP_SET or the set the shaders define as BINDLESS_SET
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    // view must be in SHADER_READ_ONLY_OPTIMAL whenever a shader reads it
+    pub fn register_image(&mut self, core: &VkCore, view: vk::ImageView) -> Result<ImageHandle, String> {
+        let handle = ImageHandle(self.images.allocate("image")?);
+        self.replace_image(core, handle, view);
+
+        Ok(handle)
+    }
+
+    pub fn register_sampler(&mut self, core: &VkCore, sampler: vk::Sampler) -> Result<SamplerHandle, String> {
+        let handle = SamplerHandle(self.samplers.allocate("sampler")?);
+        let image_info = [vk::DescriptorImageInfo::default()
+            .sampler(sampler)];
+        let write = [vk::WriteDescriptorSet::default()
+            .dst_set(self.set)
+            .dst_binding(SAMPLER_BINDING)
+            .dst_array_element(handle.0)
+            .descriptor_type(vk::DescriptorType::SAMPLER)
+            .image_info(&image_info)];
+        unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+
+        Ok(handle)
+    }
+
+    // The whole buffer, which needs STORAGE_BUFFER usage
+    pub fn register_buffer(&mut self, core: &VkCore, buffer: &GpuBuffer) -> Result<BufferHandle, String> {
+        let handle = BufferHandle(self.buffers.allocate("buffer")?);
+        self.replace_buffer(core, handle, buffer);
+
+        Ok(handle)
+    }
+
+    // Points a handle at a new image, e.g. after a texture was reloaded. Frames in flight may still read the old one,
+    // so it must outlive them.
+    pub fn replace_image(&self, core: &VkCore, handle: ImageHandle, view: vk::ImageView) {
+        let image_info = [vk::DescriptorImageInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let write = [vk::WriteDescriptorSet::default()
+            .dst_set(self.set)
+            .dst_binding(IMAGE_BINDING)
+            .dst_array_element(handle.0)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&image_info)];
+        unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+    }
+
+    pub fn replace_buffer(&self, core: &VkCore, handle: BufferHandle, buffer: &GpuBuffer) {
+        let buffer_info = [vk::DescriptorBufferInfo::default()
+            .buffer(buffer.buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)];
+        let write = [vk::WriteDescriptorSet::default()
+            .dst_set(self.set)
+            .dst_binding(BUFFER_BINDING)
+            .dst_array_element(handle.0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&buffer_info)];
+        unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+    }
+
+    // The slot is handed out again once the frames in flight are done. Draws recorded after this must not use it.
+    pub fn release_image(&mut self, handle: ImageHandle) {
+        self.images.release(handle.0, self.max_frames);
+    }
+
+    pub fn release_sampler(&mut self, handle: SamplerHandle) {
+        self.samplers.release(handle.0, self.max_frames);
+    }
+
+    pub fn release_buffer(&mut self, handle: BufferHandle) {
+        self.buffers.release(handle.0, self.max_frames);
+    }
+
+    // Once a frame, after it was submitted
+    pub fn end_frame(&mut self) {
+        self.images.end_frame();
+        self.samplers.end_frame();
+        self.buffers.end_frame();
+    }
+
+    pub fn cmd_bind(&self, core: &VkCore, command_buffer: vk::CommandBuffer, bind_point: vk::PipelineBindPoint,
+                    layout: vk::PipelineLayout, set: u32) {
+        unsafe {
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, bind_point, layout, set, &[self.set], &[]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
@@ -0,0 +1,134 @@
+use std::ffi::CStr;
+
+use ash::extensions::khr;
+use ash::{Entry, Instance, vk};
+use tracing::info;
+
+use crate::vkcore::VkCore;
+
+// The instance asks for this version when the loader has it, features that are core in it are used as such
+pub const TARGET_API_VERSION: u32 = vk::API_VERSION_1_3;
+// Devices below this are skipped, the renderers rely on 1.1 core features like multiview and device groups
+pub const MIN_API_VERSION: u32 = vk::API_VERSION_1_1;
+
+// The highest version up to TARGET_API_VERSION that the loader supports, for the instance
+pub(crate) fn instance_api_version(entry: &Entry) -> u32 {
+    // A 1.0 loader doesn't have vkEnumerateInstanceVersion
+    let loader_version = match unsafe { entry.try_enumerate_instance_version() } {
+        Ok(Some(version)) => version,
+        _ => vk::API_VERSION_1_0
+    };
+    loader_version.min(TARGET_API_VERSION)
+}
+
+fn version_string(version: u32) -> String {
+    format!("{}.{}.{}", vk::api_version_major(version), vk::api_version_minor(version),
+            vk::api_version_patch(version))
+}
+
+// Where a device gets a feature from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeatureSource {
+    Core, // The negotiated version has it
+    Extension, // The KHR extension it was promoted from, enabled along with the feature
+    Unsupported
+}
+
+impl FeatureSource {
+    pub fn is_available(self) -> bool {
+        self != FeatureSource::Unsupported
+    }
+}
+
+// What the device supports of the features that became core in Vulkan 1.2, and through what. VkCore enables
+// everything available. Code that uses one checks it here and keeps a path for devices without it.
+#[derive(Clone, Debug)]
+pub struct Capabilities {
+    pub instance_version: u32,
+    pub device_version: u32,
+    pub api_version: u32, // The lower of both, what the device is used as
+    pub buffer_device_address: FeatureSource,
+    pub timeline_semaphore: FeatureSource
+}
+
+impl Capabilities {
+    pub(crate) fn query(instance: &Instance, physical_device: vk::PhysicalDevice, instance_version: u32)
+        -> Capabilities {
+        let device_version = unsafe { instance.get_physical_device_properties(physical_device) }.api_version;
+        let api_version = instance_version.min(device_version);
+        let available_extensions = unsafe {
+            instance.enumerate_device_extension_properties(physical_device).unwrap()
+        };
+        let source = |core_since: u32, extension: &CStr| {
+            let extension_available = available_extensions.iter()
+                .any(|e| unsafe { CStr::from_ptr(e.extension_name.as_ptr()) } == extension);
+            match (api_version >= core_since, extension_available) {
+                (true, _) => FeatureSource::Core,
+                (false, true) => FeatureSource::Extension,
+                (false, false) => FeatureSource::Unsupported
+            }
+        };
+        let buffer_device_address = source(vk::API_VERSION_1_2, vk::KhrBufferDeviceAddressFn::NAME);
+        let timeline_semaphore = source(vk::API_VERSION_1_2, vk::KhrTimelineSemaphoreFn::NAME);
+
+        // Only structures the device knows may be chained, whether from the version or an extension
+        let mut address_features = vk::PhysicalDeviceBufferDeviceAddressFeatures::default();
+        let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
+        let mut features2 = vk::PhysicalDeviceFeatures2::default();
+        if buffer_device_address.is_available() {
+            features2 = features2.push_next(&mut address_features);
+        }
+        if timeline_semaphore.is_available() {
+            features2 = features2.push_next(&mut timeline_features);
+        }
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+
+        let supported = |source: FeatureSource, feature: vk::Bool32| match feature {
+            vk::TRUE => source,
+            _ => FeatureSource::Unsupported
+        };
+
+        Capabilities {
+            instance_version,
+            device_version,
+            api_version,
+            buffer_device_address: supported(buffer_device_address, address_features.buffer_device_address),
+            timeline_semaphore: supported(timeline_semaphore, timeline_features.timeline_semaphore)
+        }
+    }
+
+    // The extensions the features that aren't core come from, to enable with them
+    pub(crate) fn extensions(&self) -> Vec<&'static CStr> {
+        [(self.buffer_device_address, vk::KhrBufferDeviceAddressFn::NAME),
+            (self.timeline_semaphore, vk::KhrTimelineSemaphoreFn::NAME)].into_iter()
+            .filter(|(source, _)| *source == FeatureSource::Extension)
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    pub fn log(&self) {
+        info!(api_version = version_string(self.api_version), instance = version_string(self.instance_version),
+              device = version_string(self.device_version), buffer_device_address = ?self.buffer_device_address,
+              timeline_semaphore = ?self.timeline_semaphore, "Vulkan capabilities");
+    }
+}
+
+// The address of a buffer created with SHADER_DEVICE_ADDRESS usage, through the core command or
+// VK_KHR_buffer_device_address
+pub fn buffer_device_address(core: &VkCore, buffer: vk::Buffer) -> vk::DeviceAddress {
+    let addr_info = vk::BufferDeviceAddressInfo::default()
+        .buffer(buffer);
+    match core.capabilities.buffer_device_address {
+        FeatureSource::Core => unsafe { core.logical_device.get_buffer_device_address(&addr_info) },
+        FeatureSource::Extension => unsafe {
+            khr::BufferDeviceAddress::new(&core.instance, &core.logical_device).get_buffer_device_address(&addr_info)
+        },
+        FeatureSource::Unsupported => panic!("Buffer device addresses are not supported")
+    }
+}
+
+// Chained to vk::MemoryAllocateInfo for the memory of buffers with SHADER_DEVICE_ADDRESS usage
+pub(crate) fn device_address_allocate_flags_info() -> vk::MemoryAllocateFlagsInfo<'static> {
+    vk::MemoryAllocateFlagsInfo::default()
+        .flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS)
+}
@@ -0,0 +1,440 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use ash::vk;
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::render_target::RenderTarget;
+use crate::vkcore::VkCore;
+
+// Number of frames that can be in the readback pipeline before the oldest one has to be written out
+const CAPTURE_RING_SIZE: usize = 3;
+
+pub enum CaptureOutput {
+    PngSequence(PathBuf), // Directory that receives frame_000000.png, frame_000001.png, ...
+    Png(PathBuf), // Single file for screenshots, every captured frame overwrites it
+    EncoderPipe(String), // Shell command that receives tightly packed RGBA8 frames on stdin, for example
+    // "ffmpeg -f rawvideo -pix_fmt rgba -s 800x600 -r 60 -i - out.mp4"
+    Hashes(PathBuf) // File that receives a "frame hash" line per frame, see hash_pixels and compare_hash_files
+}
+
+// 64 bit FNV-1a over the tightly packed RGBA8 pixels. Any changed pixel changes the hash, so comparisons only hold
+// for the same driver and GPU.
+pub fn hash_pixels(pixels: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in pixels {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+fn read_hash_file(path: &Path) -> Result<Vec<(u64, String)>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    contents.lines().filter(|l| !l.trim().is_empty()).map(|l| {
+        let mut fields = l.split_whitespace();
+        match (fields.next().map(|f| f.parse::<u64>()), fields.next()) {
+            (Some(Ok(frame)), Some(hash)) => Ok((frame, hash.to_string())),
+            _ => Err(format!("{}: invalid line \"{}\"", path.display(), l))
+        }
+    }).collect()
+}
+
+// Returns the frames whose hashes differ, frames missing from either file count as differing
+pub fn compare_hash_files(expected: &Path, actual: &Path) -> Result<Vec<u64>, String> {
+    let expected = read_hash_file(expected)?;
+    let actual = read_hash_file(actual)?;
+    let mut mismatches: Vec<u64> = Vec::new();
+    for n in 0..expected.len().max(actual.len()) {
+        match (expected.get(n), actual.get(n)) {
+            (Some(e), Some(a)) if e == a => (),
+            (Some((frame, _)), _) | (None, Some((frame, _))) => mismatches.push(*frame),
+            (None, None) => ()
+        }
+    }
+
+    Ok(mismatches)
+}
+
+struct CaptureSlot {
+    buffer: GpuBuffer,
+    mapped: *const u8,
+    command_buffer: vk::CommandBuffer,
+    // On the graphics queue, they move EXCLUSIVE images to the transfer queue and back
+    release_command_buffer: vk::CommandBuffer,
+    acquire_command_buffer: vk::CommandBuffer,
+    released_sem: vk::Semaphore,
+    copied_sem: vk::Semaphore,
+    fence: vk::Fence,
+    done_sem: vk::Semaphore,
+    frame: Option<u64> // Frame number waiting to be written out of this slot
+}
+
+// Copies presented swap chain images into a ring of host visible buffers on the transfer queue. Frames are written
+// out when their slot comes around again, so the CPU only ever waits on copies that are CAPTURE_RING_SIZE frames
+// old.
+pub struct FrameCapture {
+    output: CaptureOutput,
+    encoder: Option<Child>,
+    command_pool: vk::CommandPool,
+    graphics_pool: vk::CommandPool, // For the ownership transfers
+    slots: Vec<CaptureSlot>,
+    next_slot: usize,
+    extent: vk::Extent2D,
+    swizzle_bgra: bool,
+    pub frame_count: u64,
+    pub timestep: Duration // Simulated time between frames, independent of how long capture actually takes
+}
+
+fn bytes_per_frame(extent: vk::Extent2D) -> vk::DeviceSize {
+    (extent.width * extent.height * 4) as vk::DeviceSize
+}
+
+fn allocate_command_buffers(core: &VkCore, command_pool: vk::CommandPool) -> Vec<vk::CommandBuffer> {
+    let alloc_info = vk::CommandBufferAllocateInfo::default()
+        .command_pool(command_pool)
+        .level(vk::CommandBufferLevel::PRIMARY)
+        .command_buffer_count(CAPTURE_RING_SIZE as u32);
+    unsafe { core.logical_device.allocate_command_buffers(&alloc_info).unwrap() }
+}
+
+fn create_slots(core: &VkCore, command_pool: vk::CommandPool, graphics_pool: vk::CommandPool,
+                extent: vk::Extent2D) -> Vec<CaptureSlot> {
+    let command_buffers = allocate_command_buffers(core, command_pool);
+    let release_command_buffers = allocate_command_buffers(core, graphics_pool);
+    let acquire_command_buffers = allocate_command_buffers(core, graphics_pool);
+    let sem_create_info = vk::SemaphoreCreateInfo::default();
+    let fence_create_info = vk::FenceCreateInfo::default()
+        .flags(vk::FenceCreateFlags::SIGNALED);
+
+    let mut slots: Vec<CaptureSlot> = Vec::with_capacity(CAPTURE_RING_SIZE);
+    for (n, command_buffer) in command_buffers.into_iter().enumerate() {
+        let size = bytes_per_frame(extent);
+        let buffer = GpuBuffer::new(core, size, vk::BufferUsageFlags::TRANSFER_DST,
+                                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT);
+        // Readback buffers stay mapped for their whole lifetime
+        let mapped = unsafe {
+            core.logical_device.map_memory(buffer.mem, 0, size, vk::MemoryMapFlags::empty()).unwrap() as *const u8
+        };
+        let (fence, done_sem, released_sem, copied_sem) = unsafe {
+            (core.logical_device.create_fence(&fence_create_info, None).unwrap(),
+             core.logical_device.create_semaphore(&sem_create_info, None).unwrap(),
+             core.logical_device.create_semaphore(&sem_create_info, None).unwrap(),
+             core.logical_device.create_semaphore(&sem_create_info, None).unwrap())
+        };
+
+        slots.push(CaptureSlot {
+            buffer,
+            mapped,
+            command_buffer,
+            release_command_buffer: release_command_buffers[n],
+            acquire_command_buffer: acquire_command_buffers[n],
+            released_sem,
+            copied_sem,
+            fence,
+            done_sem,
+            frame: None
+        });
+    }
+
+    slots
+}
+
+fn create_command_pool(core: &VkCore, family_index: u32) -> vk::CommandPool {
+    let pool_create_info = vk::CommandPoolCreateInfo::default()
+        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+        .queue_family_index(family_index);
+    unsafe { core.logical_device.create_command_pool(&pool_create_info, None).unwrap() }
+}
+
+// Submits a queue family ownership transfer's half on the graphics queue, between the copy and the render or present
+fn submit_barrier(core: &VkCore, command_buffer: vk::CommandBuffer, barrier: vk::ImageMemoryBarrier,
+                  wait: vk::Semaphore, signal: vk::Semaphore, fence: vk::Fence) {
+    let begin_info = vk::CommandBufferBeginInfo::default()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    let wait_sems = [wait];
+    let wait_stages = [vk::PipelineStageFlags::ALL_COMMANDS];
+    let command_buffers = [command_buffer];
+    let sig_sems = [signal];
+    let submit_info = [vk::SubmitInfo::default()
+        .wait_semaphores(&wait_sems)
+        .wait_dst_stage_mask(&wait_stages)
+        .command_buffers(&command_buffers)
+        .signal_semaphores(&sig_sems)];
+    unsafe {
+        core.logical_device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty()).unwrap();
+        core.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+        core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
+                                                 vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::DependencyFlags::empty(),
+                                                 &[], &[], &[barrier]);
+        core.logical_device.end_command_buffer(command_buffer).unwrap();
+        core.logical_device.queue_submit(core.graphics_queue, &submit_info, fence).unwrap();
+    }
+}
+
+fn destroy_slots(core: &VkCore, command_pool: vk::CommandPool, graphics_pool: vk::CommandPool,
+                 slots: &[CaptureSlot]) {
+    for s in slots.iter() {
+        unsafe {
+            core.logical_device.unmap_memory(s.buffer.mem);
+            core.logical_device.destroy_fence(s.fence, None);
+            core.logical_device.destroy_semaphore(s.done_sem, None);
+            core.logical_device.destroy_semaphore(s.released_sem, None);
+            core.logical_device.destroy_semaphore(s.copied_sem, None);
+            core.logical_device.free_command_buffers(command_pool, &[s.command_buffer]);
+            core.logical_device.free_command_buffers(graphics_pool,
+                                                     &[s.release_command_buffer, s.acquire_command_buffer]);
+        }
+        s.buffer.destroy(core);
+    }
+}
+
+impl FrameCapture {
+    // format is the swap chain format, only 8 bit RGBA/BGRA formats can be captured
+    pub fn new(core: &VkCore, extent: vk::Extent2D, format: vk::Format, output: CaptureOutput,
+               timestep: Duration) -> FrameCapture {
+        let swizzle_bgra = match format {
+            vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => true,
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => false,
+            _ => panic!("Unsupported capture format {:?}", format)
+        };
+
+        let encoder = match &output {
+            CaptureOutput::PngSequence(dir) => {
+                fs::create_dir_all(dir).unwrap();
+                None
+            },
+            CaptureOutput::Png(_) => None,
+            CaptureOutput::Hashes(path) => {
+                fs::write(path, "").unwrap(); // Truncate, frames are appended as they are written out
+                None
+            },
+            CaptureOutput::EncoderPipe(command) => Some(Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap())
+        };
+
+        let command_pool = create_command_pool(core, core.transfer_family_index);
+        let graphics_pool = create_command_pool(core, core.graphics_family_index);
+        let slots = create_slots(core, command_pool, graphics_pool, extent);
+
+        FrameCapture {
+            output,
+            encoder,
+            command_pool,
+            graphics_pool,
+            slots,
+            next_slot: 0,
+            extent,
+            swizzle_bgra,
+            frame_count: 0,
+            timestep
+        }
+    }
+
+    // Time to feed to animation instead of the wall clock so that recorded output does not depend on frame rate
+    pub fn simulation_time(&self) -> Duration {
+        self.timestep * self.frame_count as u32
+    }
+
+    // Queues a copy of image, one of render_target's, which must be in PRESENT_SRC_KHR once render_finished is
+    // signaled. The returned semaphore replaces render_finished as the present wait semaphore. Images that aren't
+    // shared with the transfer queue are released to it on the graphics queue and acquired back after the copy.
+    pub fn submit(&mut self, core: &VkCore, render_target: &RenderTarget, image: vk::Image,
+                  render_finished: vk::Semaphore) -> vk::Semaphore {
+        let slot_idx = self.next_slot;
+        unsafe {
+            core.logical_device.wait_for_fences(&[self.slots[slot_idx].fence], true, u64::MAX).unwrap();
+        }
+        self.write_slot(slot_idx);
+
+        let ownership_transfer = core.transfer_family_index != core.graphics_family_index &&
+            !render_target.shared_families.contains(&core.transfer_family_index);
+        let (graphics_family, transfer_family) = match ownership_transfer {
+            true => (core.graphics_family_index, core.transfer_family_index),
+            false => (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+        };
+        let slot = &mut self.slots[slot_idx];
+        let command_buffer = slot.command_buffer;
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let to_src_barrier = vk::ImageMemoryBarrier::default()
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .src_queue_family_index(graphics_family)
+            .dst_queue_family_index(transfer_family);
+        let to_present_barrier = vk::ImageMemoryBarrier::default()
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+            .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .src_queue_family_index(transfer_family)
+            .dst_queue_family_index(graphics_family);
+        let copy_region = [vk::BufferImageCopy::default()
+            .buffer_offset(0)
+            .buffer_row_length(0) // Tightly packed
+            .buffer_image_height(0)
+            .image_subresource(vk::ImageSubresourceLayers::default()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(0)
+                .base_array_layer(0)
+                .layer_count(1))
+            .image_offset(vk::Offset3D::default())
+            .image_extent(vk::Extent3D::default()
+                .width(self.extent.width)
+                .height(self.extent.height)
+                .depth(1))];
+
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let wait_sems = match ownership_transfer {
+            true => [slot.released_sem],
+            false => [render_finished]
+        };
+        let wait_stages = [vk::PipelineStageFlags::TRANSFER];
+        let command_buffers = [command_buffer];
+        let (sig_sems, copy_fence) = match ownership_transfer {
+            true => ([slot.copied_sem], vk::Fence::null()),
+            false => ([slot.done_sem], slot.fence)
+        };
+        let submit_info = [vk::SubmitInfo::default()
+            .wait_semaphores(&wait_sems)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&sig_sems)];
+
+        unsafe {
+            core.logical_device.reset_fences(&[slot.fence]).unwrap();
+            if ownership_transfer {
+                submit_barrier(core, slot.release_command_buffer, to_src_barrier, render_finished, slot.released_sem,
+                               vk::Fence::null());
+            }
+            core.logical_device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty()).unwrap();
+            core.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                     &[], &[], &[to_src_barrier]);
+            core.logical_device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                                         slot.buffer.buf, &copy_region);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                     vk::DependencyFlags::empty(), &[], &[], &[to_present_barrier]);
+            core.logical_device.end_command_buffer(command_buffer).unwrap();
+            core.logical_device.queue_submit(core.transfer_queue, &submit_info, copy_fence).unwrap();
+            if ownership_transfer {
+                submit_barrier(core, slot.acquire_command_buffer, to_present_barrier, slot.copied_sem, slot.done_sem,
+                               slot.fence);
+            }
+        }
+
+        slot.frame = Some(self.frame_count);
+        let done_sem = slot.done_sem;
+        self.frame_count += 1;
+        self.next_slot = (slot_idx + 1) % self.slots.len();
+
+        done_sem
+    }
+
+    fn write_slot(&mut self, slot_idx: usize) {
+        let slot = &mut self.slots[slot_idx];
+        let frame = match slot.frame.take() {
+            Some(f) => f,
+            None => return
+        };
+
+        let size = bytes_per_frame(self.extent) as usize;
+        let mut pixels = unsafe { std::slice::from_raw_parts(slot.mapped, size) }.to_vec();
+        if self.swizzle_bgra {
+            for p in pixels.chunks_exact_mut(4) {
+                p.swap(0, 2);
+            }
+        }
+
+        match &self.output {
+            CaptureOutput::PngSequence(dir) => {
+                let path = dir.join(format!("frame_{:06}.png", frame));
+                image::save_buffer(path, pixels.as_slice(), self.extent.width, self.extent.height,
+                                   image::ColorType::Rgba8).unwrap();
+            },
+            CaptureOutput::Png(path) => {
+                image::save_buffer(path, pixels.as_slice(), self.extent.width, self.extent.height,
+                                   image::ColorType::Rgba8).unwrap();
+            },
+            CaptureOutput::EncoderPipe(_) => {
+                let encoder = self.encoder.as_mut().unwrap();
+                encoder.stdin.as_mut().unwrap().write_all(pixels.as_slice()).unwrap();
+            },
+            CaptureOutput::Hashes(path) => {
+                let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+                writeln!(file, "{} {:016x}", frame, hash_pixels(pixels.as_slice())).unwrap();
+            }
+        }
+    }
+
+    // Waits for every outstanding copy and writes the frames out in submission order
+    pub fn flush(&mut self, core: &VkCore) {
+        let fences: Vec<vk::Fence> = self.slots.iter().map(|s| s.fence).collect();
+        unsafe { core.logical_device.wait_for_fences(fences.as_slice(), true, u64::MAX).unwrap() };
+        for n in 0..self.slots.len() {
+            self.write_slot((self.next_slot + n) % self.slots.len());
+        }
+    }
+
+    // Call after the swap chain is recreated
+    pub fn resize(&mut self, core: &VkCore, extent: vk::Extent2D) {
+        self.flush(core);
+        destroy_slots(core, self.command_pool, self.graphics_pool, &self.slots);
+        self.extent = extent;
+        self.slots = create_slots(core, self.command_pool, self.graphics_pool, extent);
+        self.next_slot = 0;
+    }
+
+    // Before a lost device is destroyed, without waiting for the copies. The frames still in the slots are lost.
+    pub fn release(&mut self, core: &VkCore) {
+        destroy_slots(core, self.command_pool, self.graphics_pool, &self.slots);
+        unsafe {
+            core.logical_device.destroy_command_pool(self.command_pool, None);
+            core.logical_device.destroy_command_pool(self.graphics_pool, None);
+        }
+        self.slots.clear();
+    }
+
+    // On the new device, after release. Frame numbers carry on where they stopped.
+    pub fn recreate(&mut self, core: &VkCore, extent: vk::Extent2D) {
+        self.command_pool = create_command_pool(core, core.transfer_family_index);
+        self.graphics_pool = create_command_pool(core, core.graphics_family_index);
+        self.extent = extent;
+        self.slots = create_slots(core, self.command_pool, self.graphics_pool, extent);
+        self.next_slot = 0;
+    }
+
+    pub fn destroy(&mut self, core: &VkCore) {
+        self.flush(core);
+        destroy_slots(core, self.command_pool, self.graphics_pool, &self.slots);
+        unsafe {
+            core.logical_device.destroy_command_pool(self.command_pool, None);
+            core.logical_device.destroy_command_pool(self.graphics_pool, None);
+        }
+        if let Some(mut encoder) = self.encoder.take() {
+            drop(encoder.stdin.take()); // Closing stdin lets the encoder finish the file
+            encoder.wait().unwrap();
+        }
+    }
+}
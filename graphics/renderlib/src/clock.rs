@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+pub const PAUSE_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::Pause;
+pub const FRAME_STEP_KEY: VirtualKeyCode = VirtualKeyCode::Period; // While paused
+const DEFAULT_FRAME_STEP: Duration = Duration::from_nanos(16_666_667);
+
+// Animation time shared by everything a renderer moves over time: per frame uniforms, water waves and physics. It
+// advances once per frame by the real time since the frame before, scaled by time_scale. While paused it only advances
+// by frame_step for every frame queued with step, so that a frame can be looked at and advanced one at a time.
+pub struct Clock {
+    startup: Instant,
+    last_tick: Instant,
+    time: Duration, // Animation time since startup
+    delta: Duration, // Added by the last tick
+    time_scale: f32,
+    paused: bool,
+    queued_steps: u32,
+    frame_step: Duration
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        Clock::new()
+    }
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        let now = Instant::now();
+        Clock {
+            startup: now,
+            last_tick: now,
+            time: Duration::ZERO,
+            delta: Duration::ZERO,
+            time_scale: 1.0,
+            paused: false,
+            queued_steps: 0,
+            frame_step: DEFAULT_FRAME_STEP
+        }
+    }
+
+    // At the start of every frame, returns the animation time it added
+    pub fn tick(&mut self) -> Duration {
+        let now = Instant::now();
+        let real = now.duration_since(self.last_tick);
+        self.last_tick = now;
+        self.advance(real)
+    }
+
+    // Like tick with a fixed real time instead of the wall clock, for example a capture's timestep
+    pub fn tick_by(&mut self, real: Duration) -> Duration {
+        self.last_tick = Instant::now();
+        self.advance(real)
+    }
+
+    fn advance(&mut self, real: Duration) -> Duration {
+        self.delta = match (self.paused, self.queued_steps) {
+            (false, _) => real.mul_f64(self.time_scale as f64),
+            (true, 0) => Duration::ZERO,
+            (true, _) => {
+                self.queued_steps -= 1;
+                self.frame_step
+            }
+        };
+        self.time += self.delta;
+
+        self.delta
+    }
+
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    // For shaders and animation clips
+    pub fn seconds(&self) -> f32 {
+        self.time.as_secs_f32()
+    }
+
+    // Of the current frame, zero while paused
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    // Real time, regardless of pauses and time scale
+    pub fn since_startup(&self) -> Duration {
+        self.startup.elapsed()
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.queued_steps = 0;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Advances the next frame by frame_step while paused, does nothing otherwise
+    pub fn step(&mut self) {
+        if self.paused {
+            self.queued_steps += 1;
+        }
+    }
+
+    // 0.5 is slow motion at half speed, negative scales are clamped to 0
+    pub fn set_time_scale(&mut self, time_scale: f32) {
+        self.time_scale = time_scale.max(0.0);
+    }
+
+    pub fn time_scale(&self) -> f32 {
+        self.time_scale
+    }
+
+    // Animation time a step advances, 1/60 s by default
+    pub fn set_frame_step(&mut self, frame_step: Duration) {
+        self.frame_step = frame_step;
+    }
+
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(key), .. },
+            ..
+        } = event {
+            match *key {
+                PAUSE_TOGGLE_KEY => self.set_paused(!self.paused),
+                FRAME_STEP_KEY => self.step(),
+                _ => ()
+            }
+        }
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> String {
+        match (self.paused, self.time_scale == 1.0) {
+            (true, _) => format!("paused at {:.3} s", self.seconds()),
+            (false, true) => format!("time {:.1} s", self.seconds()),
+            (false, false) => format!("time {:.1} s at {}x", self.seconds(), self.time_scale)
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3, Vector4};
+
+use crate::color_pipeline::ColorConstants;
+use crate::compute::ComputePipeline;
+use crate::gpu_buffer::GpuBuffer;
+use crate::scene::Light;
+use crate::shadow::PointShadows;
+use crate::vkcore::VkCore;
+
+const CULL_SHADER_PATH: &str = "graphics/shaders/spv/light_cull.spv";
+// Screen tiles across, down and depth slices, matches clustered.glsl. light_cull.comp has a work group per slice.
+pub const CLUSTER_GRID: [u32; 3] = [16, 9, 24];
+pub const MAX_POINT_LIGHTS: usize = 1024;
+pub const MAX_DIRECTIONAL_LIGHTS: usize = 4;
+pub const MAX_LIGHTS_PER_CLUSTER: usize = 128; // The closest ones aren't preferred, later lights are dropped
+const CLUSTER_COUNT: usize = (CLUSTER_GRID[0] * CLUSTER_GRID[1] * CLUSTER_GRID[2]) as usize;
+
+// Matches PointLight and DirectionalLight in clustered.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct GpuLight {
+    position: Vector4<f32>, // The direction for directional lights, w is the range of point lights
+    color: Vector4<f32> // w is the shadow slot of point lights, see PointShadows, or -1
+}
+
+// Matches the start of the Lights block in clustered.glsl, the point lights follow it
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct LightsHeader {
+    view: Matrix4<f32>,
+    inverse_projection: Matrix4<f32>,
+    counts: [u32; 4], // Point lights and directional lights
+    depth_range: [f32; 4], // Near plane, far plane, viewport width and height
+    directional: [GpuLight; MAX_DIRECTIONAL_LIGHTS]
+}
+
+impl GpuLight {
+    fn from_light(light: &Light) -> GpuLight {
+        match *light {
+            Light::Directional { direction, color, intensity } => GpuLight {
+                position: Vector3::from(direction).normalize().extend(0.0),
+                color: (Vector3::from(color) * intensity).extend(0.0)
+            },
+            Light::Point { position, color, intensity, range, .. } => GpuLight {
+                position: Vector3::from(position).extend(range),
+                color: (Vector3::from(color) * intensity).extend(0.0)
+            }
+        }
+    }
+}
+
+// The camera that ClusteredLights::update divides into clusters
+#[derive(Clone, Copy)]
+pub struct ClusterCamera {
+    pub view: Matrix4<f32>,
+    pub projection: Matrix4<f32>, // Y flipped like SceneCamera::projection
+    pub near: f32,
+    pub far: f32,
+    pub extent: vk::Extent2D // Of the clustered area in pixels
+}
+
+// The forward render pass that meshes shaded by ClusteredLights are drawn in, see TerrainMesh::new
+#[derive(Clone, Copy)]
+pub struct ForwardPass<'a> {
+    pub render_pass: vk::RenderPass,
+    pub lights: &'a ClusteredLights,
+    pub msaa_samples: vk::SampleCountFlags,
+    pub max_frames: usize,
+    pub views: usize // Each view has its own transforms in every frame
+}
+
+// What a mesh is drawn with in one view of the ForwardPass
+#[derive(Clone, Copy)]
+pub struct ForwardDraw<'a> {
+    pub lights: &'a ClusteredLights,
+    pub color_constants: &'a ColorConstants,
+    pub current_frame: usize,
+    pub view_index: usize
+}
+
+// Clustered forward lighting for hundreds of point lights. Every frame cmd_cull sorts the point lights into a froxel
+// grid, screen tiles split into exponential depth slices, with a list of light indices per cluster. The forward
+// fragment shader (RasterPipeline::new_clustered) then only shades with its cluster's lights. Directional lights
+// reach everything and skip the grid. Point lights with a slot in shadows are shadowed by it.
+// Like the path tracer's LightList the lights are written to a host visible buffer per frame in flight. The cluster
+// lists are only used within a frame and are shared.
+pub struct ClusteredLights {
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    cull: ComputePipeline,
+    light_buffers: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>,
+    cluster_counts: GpuBuffer,
+    cluster_indices: GpuBuffer,
+    points: Vec<GpuLight>,
+    directional: Vec<GpuLight>
+}
+
+impl ClusteredLights {
+    pub fn new(core: &VkCore, max_frames: usize, shadows: &PointShadows) -> ClusteredLights {
+        let mut bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..3)
+            .map(|b| vk::DescriptorSetLayoutBinding::default()
+                .binding(b)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE | vk::ShaderStageFlags::FRAGMENT))
+            .collect();
+        // The shadow atlas and its slots, see point_shadows.glsl
+        bindings.push(vk::DescriptorSetLayoutBinding::default()
+            .binding(3)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT));
+        bindings.push(vk::DescriptorSetLayoutBinding::default()
+            .binding(4)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT));
+        let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .bindings(bindings.as_slice());
+        let set_layout = unsafe {
+            core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap()
+        };
+
+        let light_size = (mem::size_of::<LightsHeader>() + mem::size_of::<GpuLight>() * MAX_POINT_LIGHTS)
+            as vk::DeviceSize;
+        let mut light_buffers: Vec<GpuBuffer> = Vec::with_capacity(max_frames);
+        let mut mapped: Vec<*mut c_void> = Vec::with_capacity(max_frames);
+        for _ in 0..max_frames {
+            let buffer = GpuBuffer::new(core, light_size, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                        vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                            vk::MemoryPropertyFlags::HOST_COHERENT);
+            mapped.push(unsafe {
+                core.logical_device.map_memory(buffer.mem, 0, light_size, vk::MemoryMapFlags::empty()).unwrap()
+            });
+            light_buffers.push(buffer);
+        }
+        let cluster_counts = GpuBuffer::new(core, (mem::size_of::<u32>() * CLUSTER_COUNT) as vk::DeviceSize,
+                                            vk::BufferUsageFlags::STORAGE_BUFFER,
+                                            vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let cluster_indices = GpuBuffer::new(core, (mem::size_of::<u32>() * CLUSTER_COUNT * MAX_LIGHTS_PER_CLUSTER)
+                                                 as vk::DeviceSize, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                             vk::MemoryPropertyFlags::DEVICE_LOCAL);
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(4 * max_frames as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(max_frames as u32)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe {
+            core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap()
+        };
+        let layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        let atlas_info = [shadows.atlas_info()];
+        for (frame, (set, light_buffer)) in sets.iter().zip(light_buffers.iter()).enumerate() {
+            let infos = [light_buffer, &cluster_counts, &cluster_indices].map(|b| [vk::DescriptorBufferInfo::default()
+                .buffer(b.buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)]);
+            let mut writes: Vec<vk::WriteDescriptorSet> = Vec::with_capacity(infos.len());
+            for (binding, info) in infos.iter().enumerate() {
+                writes.push(vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(info));
+            }
+            let slot_info = [vk::DescriptorBufferInfo::default()
+                .buffer(shadows.slot_buffer(frame))
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(3)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&atlas_info));
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(4)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&slot_info));
+            unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+        }
+
+        ClusteredLights {
+            set_layout,
+            descriptor_pool,
+            sets,
+            cull: ComputePipeline::new(core, CULL_SHADER_PATH, &[set_layout], 0),
+            light_buffers,
+            mapped,
+            cluster_counts,
+            cluster_indices,
+            points: Vec::new(),
+            directional: Vec::new()
+        }
+    }
+
+    // Bound as set 1 by RasterPipeline::new_clustered
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    // Replaces all lights, from the next update on. Nothing changes when there are too many.
+    pub fn set_lights(&mut self, lights: &[Light]) -> Result<(), String> {
+        let (directional, points): (Vec<&Light>, Vec<&Light>) = lights.iter()
+            .partition(|l| matches!(l, Light::Directional { .. }));
+        if directional.len() > MAX_DIRECTIONAL_LIGHTS {
+            return Err(format!("At most {} directional lights are supported", MAX_DIRECTIONAL_LIGHTS));
+        }
+        if points.len() > MAX_POINT_LIGHTS {
+            return Err(format!("At most {} point lights are supported", MAX_POINT_LIGHTS));
+        }
+        self.directional = directional.into_iter().map(GpuLight::from_light).collect();
+        self.points = points.into_iter().map(GpuLight::from_light).collect();
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len() + self.directional.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Call once the frame's fence has been waited on, with the camera the frame is drawn with. shadow_slots is
+    // PointShadows::shadow_slots after its update.
+    pub fn update(&self, frame: usize, camera: &ClusterCamera, shadow_slots: &[Option<u32>]) {
+        let ClusterCamera { view, projection, near, far, extent } = *camera;
+        let unused = GpuLight { position: Vector4::new(0.0, 0.0, 0.0, 0.0), color: Vector4::new(0.0, 0.0, 0.0, 0.0) };
+        let mut directional = [unused; MAX_DIRECTIONAL_LIGHTS];
+        directional[..self.directional.len()].copy_from_slice(self.directional.as_slice());
+        let header = LightsHeader {
+            view,
+            inverse_projection: projection.invert().unwrap(),
+            counts: [self.points.len() as u32, self.directional.len() as u32, 0, 0],
+            depth_range: [near, far, extent.width as f32, extent.height as f32],
+            directional
+        };
+        let points: Vec<GpuLight> = self.points.iter().enumerate()
+            .map(|(i, p)| {
+                let slot = shadow_slots.get(i).copied().flatten().map_or(-1.0, |s| s as f32);
+                GpuLight { position: p.position, color: p.color.truncate().extend(slot) }
+            })
+            .collect();
+        unsafe {
+            (self.mapped[frame] as *mut LightsHeader).write(header);
+            (self.mapped[frame].add(mem::size_of::<LightsHeader>()) as *mut GpuLight)
+                .copy_from_nonoverlapping(points.as_ptr(), points.len());
+        }
+    }
+
+    // Before the render pass that draws with the lights
+    pub fn cmd_cull(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize) {
+        // The previous frame's fragment shaders read the lists this overwrites
+        let before = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::SHADER_WRITE);
+        let after = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[before], &[], &[]);
+            self.cull.cmd_bind(core, command_buffer, self.sets[frame]);
+            core.logical_device.cmd_dispatch(command_buffer, 1, 1, CLUSTER_GRID[2]);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                     vk::DependencyFlags::empty(), &[after], &[], &[]);
+        }
+    }
+
+    // pipeline_layout is the one of a RasterPipeline::new_clustered pipeline
+    pub fn cmd_bind(&self, core: &VkCore, command_buffer: vk::CommandBuffer, pipeline_layout: vk::PipelineLayout,
+                    frame: usize) {
+        unsafe {
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                         pipeline_layout, 1, &[self.sets[frame]], &[]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.cull.destroy(core);
+        for b in self.light_buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+        self.cluster_counts.destroy(core);
+        self.cluster_indices.destroy(core);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
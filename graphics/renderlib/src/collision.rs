@@ -0,0 +1,582 @@
+use ash::vk;
+use cgmath::{Angle, Deg, EuclideanSpace, InnerSpace, Matrix, Matrix4, Point3, SquareMatrix, Transform, Vector3,
+             Vector4};
+
+use crate::objects::Mesh;
+use crate::scene::{CameraProjection, SceneCamera};
+use crate::vertex::Vertex;
+
+const MAX_LEAF_TRIANGLES: usize = 4;
+
+// Hits are at origin + t * direction. The direction doesn't have to be normalized, t is in multiples of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>
+}
+
+impl Ray {
+    pub fn new(origin: Point3<f32>, direction: Vector3<f32>) -> Ray {
+        Ray { origin, direction }
+    }
+
+    // Through a point of the camera's image, in pixels from the top left corner. The direction is normalized.
+    pub fn from_camera(camera: &SceneCamera, extent: vk::Extent2D, x: f32, y: f32) -> Ray {
+        let inverse_view_proj = (camera.projection(extent) * camera.view()).invert().unwrap();
+        // The projection flips y, so pixel rows go down like in clip space
+        let ndc = [x / extent.width as f32 * 2.0 - 1.0, y / extent.height as f32 * 2.0 - 1.0];
+        let far = inverse_view_proj * Vector4::new(ndc[0], ndc[1], 1.0, 1.0);
+        let far = Point3::from_homogeneous(far);
+
+        Ray::new(camera.eye(), (far - camera.eye()).normalize())
+    }
+
+    pub fn at(&self, t: f32) -> Point3<f32> {
+        self.origin + self.direction * t
+    }
+
+    // Into the space of a transform, such as from the world into an object's. The direction is transformed without
+    // being normalized again, so t stays the same along both rays.
+    pub fn transformed(&self, matrix: Matrix4<f32>) -> Ray {
+        Ray::new(matrix.transform_point(self.origin), matrix.transform_vector(self.direction))
+    }
+}
+
+// Axis aligned bounding box, empty while min is above max on any axis
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY)
+        }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for p in points {
+            aabb.grow(p);
+        }
+
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, p: Point3<f32>) {
+        self.min = Point3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z));
+        self.max = Point3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z));
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut aabb = *self;
+        aabb.grow(other.min);
+        aabb.grow(other.max);
+
+        aabb
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn size(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (a, b) = (self.min, self.max);
+        [Point3::new(a.x, a.y, a.z), Point3::new(b.x, a.y, a.z), Point3::new(a.x, b.y, a.z),
+            Point3::new(b.x, b.y, a.z), Point3::new(a.x, a.y, b.z), Point3::new(b.x, a.y, b.z),
+            Point3::new(a.x, b.y, b.z), Point3::new(b.x, b.y, b.z)]
+    }
+
+    // Bounds of the transformed box, larger than those of the transformed contents when it rotates
+    pub fn transformed(&self, matrix: Matrix4<f32>) -> Aabb {
+        match self.is_empty() {
+            true => *self,
+            false => Aabb::from_points(self.corners().iter().map(|c| matrix.transform_point(*c)))
+        }
+    }
+
+    // Where the ray enters the box within [0, t_max], 0 when it starts inside. Slab test, zero direction components
+    // divide into infinities that keep the test correct.
+    pub fn intersect_ray(&self, ray: &Ray, t_max: f32) -> Option<f32> {
+        let (mut near, mut far) = (0.0f32, t_max);
+        for axis in 0..3 {
+            let inverse = 1.0 / ray.direction[axis];
+            let t0 = (self.min[axis] - ray.origin[axis]) * inverse;
+            let t1 = (self.max[axis] - ray.origin[axis]) * inverse;
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+
+        match near <= far {
+            true => Some(near),
+            false => None
+        }
+    }
+}
+
+// Empty while the radius is negative
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32
+}
+
+impl BoundingSphere {
+    pub fn empty() -> BoundingSphere {
+        BoundingSphere {
+            center: Point3::new(0.0, 0.0, 0.0),
+            radius: -1.0
+        }
+    }
+
+    // Around the box' corners
+    pub fn from_aabb(aabb: &Aabb) -> BoundingSphere {
+        match aabb.is_empty() {
+            true => BoundingSphere::empty(),
+            false => BoundingSphere {
+                center: aabb.center(),
+                radius: aabb.size().magnitude() / 2.0
+            }
+        }
+    }
+
+    // Ritter's approximation, grown over the points again so that it holds all of them. Usually tighter than the
+    // sphere around the points' Aabb, which is taken when it isn't.
+    pub fn from_points(points: &[Point3<f32>]) -> BoundingSphere {
+        let farthest = |from: Point3<f32>| *points.iter()
+            .max_by(|a, b| (**a - from).magnitude2().total_cmp(&(**b - from).magnitude2()))
+            .unwrap();
+        let first = match points.first() {
+            Some(&p) => farthest(p),
+            None => return BoundingSphere::empty()
+        };
+        let second = farthest(first);
+        let mut sphere = BoundingSphere {
+            center: first.midpoint(second),
+            radius: (second - first).magnitude() / 2.0
+        };
+        for &p in points.iter() {
+            let distance = (p - sphere.center).magnitude();
+            if distance > sphere.radius {
+                // Moves the center towards p so that the far side of the old sphere stays inside
+                let radius = (sphere.radius + distance) / 2.0;
+                sphere.center += (p - sphere.center) * ((radius - sphere.radius) / distance);
+                sphere.radius = radius;
+            }
+        }
+        let around_aabb = BoundingSphere::from_aabb(&Aabb::from_points(points.iter().copied()));
+
+        match around_aabb.radius < sphere.radius {
+            true => around_aabb,
+            false => sphere
+        }
+    }
+
+    // Around all the spheres that aren't empty, not the tightest but cheap
+    pub fn enclosing(spheres: &[BoundingSphere]) -> BoundingSphere {
+        let spheres: Vec<&BoundingSphere> = spheres.iter().filter(|s| !s.is_empty()).collect();
+        let centers: Vec<Point3<f32>> = spheres.iter().map(|s| s.center).collect();
+        let center = BoundingSphere::from_points(&centers).center;
+
+        BoundingSphere {
+            center,
+            radius: spheres.iter().map(|s| (s.center - center).magnitude() + s.radius).fold(-1.0, f32::max)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.radius < 0.0
+    }
+
+    pub fn contains(&self, p: Point3<f32>) -> bool {
+        (p - self.center).magnitude2() <= self.radius * self.radius
+    }
+
+    // Scaled by the transform's largest axis scale, so it holds the transformed contents under any rotation and
+    // non-uniform scale
+    pub fn transformed(&self, matrix: Matrix4<f32>) -> BoundingSphere {
+        if self.is_empty() {
+            return *self;
+        }
+        let scale = matrix.x.truncate().magnitude().max(matrix.y.truncate().magnitude())
+            .max(matrix.z.truncate().magnitude());
+
+        BoundingSphere {
+            center: matrix.transform_point(self.center),
+            radius: self.radius * scale
+        }
+    }
+
+    // The part of the camera's image height the sphere spans, for picking a level of detail. Can be above 1 for
+    // spheres that fill the view, and is infinite when the camera is inside a perspective view's sphere.
+    pub fn screen_coverage(&self, camera: &SceneCamera) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        match camera.projection {
+            CameraProjection::Perspective { fov_y_degrees, .. } => {
+                let distance = (self.center - camera.eye()).magnitude();
+                match distance > self.radius {
+                    // Tangent of the sphere's angular radius over that of half the field of view
+                    true => self.radius / (distance * distance - self.radius * self.radius).sqrt() /
+                        Deg(fov_y_degrees / 2.0).tan(),
+                    false => f32::INFINITY
+                }
+            },
+            CameraProjection::Orthographic { height, .. } => 2.0 * self.radius / height
+        }
+    }
+}
+
+// The clip volume of a view projection as six planes with their normals pointing inwards
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+    planes: [Vector4<f32>; 6] // Normal in xyz and the distance from the origin in w, unnormalized
+}
+
+impl Frustum {
+    // Takes clip space depth from -1 to 1 like cgmath's perspective, which also holds Vulkan's 0 to 1
+    pub fn from_view_proj(view_proj: Matrix4<f32>) -> Frustum {
+        let row = |i: usize| view_proj.row(i);
+        Frustum {
+            planes: [row(3) + row(0), row(3) - row(0), row(3) + row(1), row(3) - row(1), row(3) + row(2),
+                row(3) - row(2)]
+        }
+    }
+
+    // Conservative, boxes near the frustum's edges may pass without being inside
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|p| {
+            // The corner furthest along the plane's normal
+            let corner = Vector3::new(if p.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                                      if p.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                                      if p.z >= 0.0 { aabb.max.z } else { aabb.min.z });
+            p.truncate().dot(corner) + p.w >= 0.0
+        })
+    }
+
+    // Like intersects, spheres near the frustum's corners may pass without being inside
+    pub fn intersects_sphere(&self, sphere: &BoundingSphere) -> bool {
+        !sphere.is_empty() && self.planes.iter().all(|p| {
+            p.truncate().dot(sphere.center.to_vec()) + p.w >= -sphere.radius * p.truncate().magnitude()
+        })
+    }
+}
+
+// Möller-Trumbore, both sides of the triangle are hit. Returns t and the barycentric coordinates of the second and
+// third vertex.
+pub fn ray_triangle(ray: &Ray, triangle: [Point3<f32>; 3], t_max: f32) -> Option<(f32, [f32; 2])> {
+    let edge1 = triangle[1] - triangle[0];
+    let edge2 = triangle[2] - triangle[0];
+    let p = ray.direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < f32::EPSILON {
+        return None; // Parallel to the triangle, or a degenerate one
+    }
+    let inverse = 1.0 / determinant;
+    let s = ray.origin - triangle[0];
+    let u = s.dot(p) * inverse;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = s.cross(edge1);
+    let v = ray.direction.dot(q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(q) * inverse;
+
+    match t >= 0.0 && t <= t_max {
+        true => Some((t, [u, v])),
+        false => None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayHit {
+    pub t: f32,
+    pub triangle: usize, // Index of its first index divided by three, as in the mesh's index list
+    pub barycentrics: [f32; 2]
+}
+
+// Leaves have items, inner nodes have their first child right after them and their second at first
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BvhNode {
+    pub(crate) bounds: Aabb,
+    pub(crate) first: usize,
+    pub(crate) count: usize // 0 for inner nodes
+}
+
+impl BvhNode {
+    pub(crate) fn children(&self, index: usize) -> [usize; 2] {
+        [index + 1, self.first]
+    }
+}
+
+// Sorts items into leaf order and returns the nodes over them, the root first. Splits at the median of the item
+// centers along their longest axis, which builds quickly and traces well enough for picking and gameplay.
+pub(crate) fn build_bvh<T, B: Fn(&T) -> Aabb>(items: &mut [T], bounds: B, max_leaf_items: usize) -> Vec<BvhNode> {
+    let mut nodes = Vec::new();
+    if !items.is_empty() {
+        build_node(items, &bounds, max_leaf_items, &mut nodes, 0);
+    }
+
+    nodes
+}
+
+// Appends the node for items, which start at first in leaf order, and its subtree
+fn build_node<T, B: Fn(&T) -> Aabb>(items: &mut [T], bounds: &B, max_leaf_items: usize, nodes: &mut Vec<BvhNode>,
+                                    first: usize) {
+    let index = nodes.len();
+    let node_bounds = items.iter().fold(Aabb::empty(), |b, i| b.union(&bounds(i)));
+    nodes.push(BvhNode { bounds: node_bounds, first, count: items.len() });
+    if items.len() <= max_leaf_items {
+        return;
+    }
+
+    let size = Aabb::from_points(items.iter().map(|i| bounds(i).center())).size();
+    let axis = match (size.x >= size.y, size.x >= size.z, size.y >= size.z) {
+        (true, true, _) => 0,
+        (false, _, true) => 1,
+        _ => 2
+    };
+    let half = items.len() / 2;
+    items.select_nth_unstable_by(half, |a, b| bounds(a).center()[axis].total_cmp(&bounds(b).center()[axis]));
+    let (left, right) = items.split_at_mut(half);
+
+    nodes[index].count = 0;
+    build_node(left, bounds, max_leaf_items, nodes, first);
+    nodes[index].first = nodes.len();
+    build_node(right, bounds, max_leaf_items, nodes, first + half);
+}
+
+// The closest of the hits hit_item reports for the items in leaf order. hit_item gets an item's index and the t of
+// the closest hit so far, and returns the hit's t and whatever the caller needs from it.
+pub(crate) fn closest_hit<H, F: FnMut(usize, f32) -> Option<(f32, H)>>(nodes: &[BvhNode], ray: &Ray, t_max: f32,
+                                                                      mut hit_item: F) -> Option<(f32, H)> {
+    let mut closest: Option<(f32, H)> = None;
+    let mut stack = Vec::with_capacity(64);
+    if !nodes.is_empty() {
+        stack.push(0);
+    }
+    while let Some(n) = stack.pop() {
+        let node = &nodes[n];
+        let t_limit = closest.as_ref().map_or(t_max, |h| h.0);
+        if node.bounds.intersect_ray(ray, t_limit).is_none() {
+            continue;
+        }
+        if node.count > 0 {
+            for i in node.first..node.first + node.count {
+                let t_limit = closest.as_ref().map_or(t_max, |h| h.0);
+                if let Some(hit) = hit_item(i, t_limit) {
+                    closest = Some(hit);
+                }
+            }
+            continue;
+        }
+        // The nearer child is popped first, so that hits in it cull the other
+        let [left, right] = node.children(n);
+        let entry = |c: usize| nodes[c].bounds.intersect_ray(ray, t_limit).unwrap_or(f32::INFINITY);
+        match entry(left) <= entry(right) {
+            true => stack.extend([right, left]),
+            false => stack.extend([left, right])
+        }
+    }
+
+    closest
+}
+
+// Bounding volume hierarchy over a mesh's triangles, for closest hit queries on the CPU
+pub struct MeshBvh {
+    positions: Vec<Point3<f32>>,
+    triangles: Vec<(usize, [u32; 3])>, // Leaf order, with the index of the triangle in the mesh
+    nodes: Vec<BvhNode>
+}
+
+impl MeshBvh {
+    // indices are in triangle list order, like load_model returns them
+    pub fn new(vertices: &[Vertex], indices: &[u32]) -> MeshBvh {
+        let positions: Vec<Point3<f32>> = vertices.iter().map(|v| Point3::from(v.pos)).collect();
+        let mut triangles: Vec<(usize, [u32; 3])> = indices.chunks_exact(3)
+            .enumerate()
+            .map(|(i, t)| (i, [t[0], t[1], t[2]]))
+            .collect();
+        let nodes = build_bvh(triangles.as_mut_slice(),
+                              |(_, t)| Aabb::from_points(t.map(|i| positions[i as usize])), MAX_LEAF_TRIANGLES);
+
+        MeshBvh {
+            positions,
+            triangles,
+            nodes
+        }
+    }
+
+    pub fn from_mesh(mesh: &Mesh) -> MeshBvh {
+        MeshBvh::new(mesh.vertices.as_slice(), mesh.indices.as_slice())
+    }
+
+    // Empty for meshes without triangles
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map(|n| n.bounds).unwrap_or_else(Aabb::empty)
+    }
+
+    // The closest hit within [0, t_max]
+    pub fn intersect(&self, ray: &Ray, t_max: f32) -> Option<RayHit> {
+        closest_hit(self.nodes.as_slice(), ray, t_max, |i, t_limit| {
+            let (triangle, indices) = self.triangles[i];
+            ray_triangle(ray, indices.map(|i| self.positions[i as usize]), t_limit)
+                .map(|(t, barycentrics)| (t, RayHit { t, triangle, barycentrics }))
+        }).map(|(_, hit)| hit)
+    }
+
+    // With the mesh placed by transform, such as a SceneObject's. t is along the world space ray.
+    pub fn intersect_transformed(&self, ray: &Ray, transform: Matrix4<f32>, t_max: f32) -> Option<RayHit> {
+        let inverse = transform.invert()?;
+        self.intersect(&ray.transformed(inverse), t_max)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelHit {
+    pub block: Vector3<i32>, // The block at block..block + 1 on every axis
+    pub normal: Vector3<i32>, // Of the face the ray entered through, zero when it started inside the block
+    pub t: f32
+}
+
+// Walks the unit blocks along the ray in order (Amanatides and Woo) until solid returns true for one, for targeting
+// blocks in a voxel world. Gives up past max_distance, in multiples of the direction.
+pub fn cast_voxels<F: Fn(Vector3<i32>) -> bool>(ray: &Ray, max_distance: f32, solid: F) -> Option<VoxelHit> {
+    let floor = |v: f32| v.floor() as i32;
+    let mut block = Vector3::new(floor(ray.origin.x), floor(ray.origin.y), floor(ray.origin.z));
+    let mut step = Vector3::new(0, 0, 0);
+    let mut t_next = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY); // To the next boundary per axis
+    let mut t_delta = Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY); // Between boundaries per axis
+    for axis in 0..3 {
+        let d = ray.direction[axis];
+        if d == 0.0 {
+            continue;
+        }
+        step[axis] = d.signum() as i32;
+        t_delta[axis] = 1.0 / d.abs();
+        let boundary = match d > 0.0 {
+            true => block[axis] as f32 + 1.0,
+            false => block[axis] as f32
+        };
+        t_next[axis] = (boundary - ray.origin[axis]) / d;
+    }
+
+    let mut normal = Vector3::new(0, 0, 0);
+    let mut t = 0.0;
+    while t <= max_distance {
+        if solid(block) {
+            return Some(VoxelHit { block, normal, t });
+        }
+        let axis = match (t_next.x <= t_next.y, t_next.x <= t_next.z, t_next.y <= t_next.z) {
+            (true, true, _) => 0,
+            (false, _, true) => 1,
+            _ => 2
+        };
+        if t_next[axis].is_infinite() {
+            return None; // Zero direction
+        }
+        t = t_next[axis];
+        t_next[axis] += t_delta[axis];
+        block[axis] += step[axis];
+        normal = Vector3::new(0, 0, 0);
+        normal[axis] = -step[axis];
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quad() -> MeshBvh {
+        // Two triangles covering [0, 1] x [0, 1] at z = 0
+        let vertex = |x: f32, y: f32| Vertex { pos: [x, y, 0.0], color: [1.0; 3], tex_coord: [x, y] };
+        MeshBvh::new(&[vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(1.0, 1.0), vertex(0.0, 1.0)], &[0, 1, 2, 0, 2, 3])
+    }
+
+    #[test]
+    fn aabb_entry_and_miss() {
+        let aabb = Aabb { min: Point3::new(-1.0, -1.0, -1.0), max: Point3::new(1.0, 1.0, 1.0) };
+        let ray = Ray::new(Point3::new(-3.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(aabb.intersect_ray(&ray, f32::INFINITY), Some(2.0));
+        assert_eq!(aabb.intersect_ray(&ray, 1.0), None);
+        assert_eq!(aabb.intersect_ray(&Ray::new(Point3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0)), 10.0),
+                   Some(0.0));
+        assert_eq!(aabb.intersect_ray(&Ray::new(Point3::new(-3.0, 2.0, 0.0), Vector3::new(1.0, 0.0, 0.0)), 10.0),
+                   None);
+    }
+
+    #[test]
+    fn mesh_closest_hit() {
+        let bvh = quad();
+        let down = Vector3::new(0.0, 0.0, -1.0);
+        let hit = bvh.intersect(&Ray::new(Point3::new(0.25, 0.75, 2.0), down), f32::INFINITY).unwrap();
+        assert_eq!((hit.t, hit.triangle), (2.0, 1));
+        assert!(bvh.intersect(&Ray::new(Point3::new(1.5, 0.5, 2.0), down), f32::INFINITY).is_none());
+        let moved = Matrix4::from_translation(Vector3::new(0.0, 0.0, -1.0));
+        let hit = bvh.intersect_transformed(&Ray::new(Point3::new(0.75, 0.25, 2.0), down), moved, f32::INFINITY);
+        assert_eq!(hit.map(|h| (h.t, h.triangle)), Some((3.0, 0)));
+    }
+
+    #[test]
+    fn frustum_keeps_what_is_in_front() {
+        let view = Matrix4::look_at_rh(Point3::new(0.0, -10.0, 0.0), Point3::new(0.0, 0.0, 0.0), Vector3::unit_z());
+        let frustum = Frustum::from_view_proj(cgmath::perspective(cgmath::Deg(60.0), 1.0, 0.1, 100.0) * view);
+        let unit_box = |y: f32| Aabb { min: Point3::new(-1.0, y - 1.0, -1.0), max: Point3::new(1.0, y + 1.0, 1.0) };
+        assert!(frustum.intersects(&unit_box(0.0)));
+        assert!(!frustum.intersects(&unit_box(-20.0))); // Behind the camera
+        assert!(!frustum.intersects(&unit_box(200.0))); // Past the far plane
+    }
+
+    #[test]
+    fn sphere_holds_points() {
+        let points = [Point3::new(0.0, 0.0, 0.0), Point3::new(4.0, 0.0, 0.0), Point3::new(2.0, 1.0, 0.0),
+            Point3::new(2.0, -1.0, 3.0), Point3::new(1.0, 1.0, -1.0)];
+        let sphere = BoundingSphere::from_points(&points);
+        assert!(points.iter().all(|&p| (p - sphere.center).magnitude() <= sphere.radius + 1e-5));
+        assert!(sphere.radius <= BoundingSphere::from_aabb(&Aabb::from_points(points)).radius);
+        assert!(BoundingSphere::from_points(&[]).is_empty());
+        let scaled = sphere.transformed(Matrix4::from_nonuniform_scale(1.0, 3.0, 2.0));
+        assert_eq!(scaled.radius, sphere.radius * 3.0);
+        let apart = [BoundingSphere { center: Point3::new(-2.0, 0.0, 0.0), radius: 1.0 }, BoundingSphere::empty(),
+            BoundingSphere { center: Point3::new(3.0, 0.0, 0.0), radius: 2.0 }];
+        let enclosing = BoundingSphere::enclosing(&apart);
+        assert_eq!((enclosing.center, enclosing.radius), (Point3::new(0.5, 0.0, 0.0), 4.5));
+        assert!(BoundingSphere::enclosing(&[BoundingSphere::empty()]).is_empty());
+    }
+
+    #[test]
+    fn frustum_keeps_spheres_in_front() {
+        let view = Matrix4::look_at_rh(Point3::new(0.0, -10.0, 0.0), Point3::new(0.0, 0.0, 0.0), Vector3::unit_z());
+        let frustum = Frustum::from_view_proj(cgmath::perspective(cgmath::Deg(60.0), 1.0, 0.1, 100.0) * view);
+        let sphere = |y: f32| BoundingSphere { center: Point3::new(0.0, y, 0.0), radius: 1.0 };
+        assert!(frustum.intersects_sphere(&sphere(0.0)));
+        assert!(frustum.intersects_sphere(&sphere(-10.5))); // Around the camera
+        assert!(!frustum.intersects_sphere(&sphere(-20.0)));
+        assert!(!frustum.intersects_sphere(&sphere(200.0)));
+    }
+
+    #[test]
+    fn voxels_hit_face() {
+        let ray = Ray::new(Point3::new(0.5, 0.5, 0.5), Vector3::new(1.0, 0.0, 0.0));
+        let hit = cast_voxels(&ray, 10.0, |b| b == Vector3::new(3, 0, 0)).unwrap();
+        assert_eq!((hit.block, hit.normal, hit.t), (Vector3::new(3, 0, 0), Vector3::new(-1, 0, 0), 2.5));
+        assert!(cast_voxels(&ray, 2.0, |b| b == Vector3::new(3, 0, 0)).is_none());
+    }
+}
@@ -1,5 +1,6 @@
 use ash::vk;
 use crate::image::{create_image, create_image_view};
+use crate::memory::transient_memory_flags;
 use crate::render_target::RenderTarget;
 use crate::vkcore::VkCore;
 
@@ -17,7 +18,7 @@ impl Color {
                                           vk::ImageTiling::OPTIMAL,
                                           vk::ImageUsageFlags::TRANSIENT_ATTACHMENT |
                                               vk::ImageUsageFlags::COLOR_ATTACHMENT,
-                                          vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                          transient_memory_flags(core),
                                           core.max_msaa_samples);
         let view = create_image_view(core, img, render_target.surface_format,
                                      vk::ImageAspectFlags::COLOR, 1);
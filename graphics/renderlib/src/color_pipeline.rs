@@ -0,0 +1,55 @@
+use ash::vk;
+
+// Both renderers shade in linear space. Intermediate targets that are written by shaders and later copied to the swap
+// chain use this format so that nothing is quantized or encoded before the final write.
+pub const LINEAR_INTERMEDIATE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// Preferred swap chain format, the presentation engine expects sRGB encoded values and the hardware encodes on write
+pub const SRGB_SWAPCHAIN_FORMAT: vk::Format = vk::Format::B8G8R8A8_SRGB;
+pub const SRGB_SWAPCHAIN_COLOR_SPACE: vk::ColorSpaceKHR = vk::ColorSpaceKHR::SRGB_NONLINEAR;
+
+pub fn is_srgb_format(format: vk::Format) -> bool {
+    matches!(format, vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32 |
+        vk::Format::R8G8B8_SRGB | vk::Format::B8G8R8_SRGB)
+}
+
+// Mirrors the ColorConstants push constant block in colorcommon.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct ColorConstants {
+    pub exposure: f32,
+    pub inverse_gamma: f32,
+    pub encode_srgb: u32, // Set when the final target is UNORM and the shader has to apply the sRGB curve itself
+    pub audit: u32
+}
+
+// Final color transform shared by the raster and ray traced paths: linear value * exposure, an optional display gamma
+// adjustment, then sRGB encoding by either the swap chain format or the shader.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorPipeline {
+    pub exposure: f32, // Linear multiplier, 1.0 leaves values untouched. Scales the adapted one with auto exposure.
+    pub gamma: f32, // Extra display gamma on top of the sRGB curve, 1.0 is neutral
+    pub audit: bool // Highlights clipped pixels in magenta and draws a reference ramp along the top of the image
+}
+
+impl Default for ColorPipeline {
+    fn default() -> ColorPipeline {
+        ColorPipeline {
+            exposure: 1.0,
+            gamma: 1.0,
+            audit: false
+        }
+    }
+}
+
+impl ColorPipeline {
+    // output_format is the format of the image the shader output finally lands in, the swap chain format for the
+    // raster path
+    pub fn constants(&self, output_format: vk::Format) -> ColorConstants {
+        ColorConstants {
+            exposure: self.exposure,
+            inverse_gamma: 1.0 / self.gamma.max(0.01),
+            encode_srgb: !is_srgb_format(output_format) as u32,
+            audit: self.audit as u32
+        }
+    }
+}
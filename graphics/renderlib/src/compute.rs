@@ -0,0 +1,104 @@
+use ash::vk;
+use tracing::{info_span, warn};
+
+use crate::raster_pipeline::load_all_shaders;
+use crate::reflection::PipelineReflection;
+use crate::specialization::SpecConstants;
+use crate::vkcore::VkCore;
+
+// Single shader compute pipeline with its own layout
+pub struct ComputePipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    pub set_layouts: Vec<vk::DescriptorSetLayout> // Owned by the pipeline, only for new_reflected
+}
+
+impl ComputePipeline {
+    // push_constant_size of 0 creates a layout without push constants
+    pub fn new(core: &VkCore, shader_path: &str, set_layouts: &[vk::DescriptorSetLayout],
+               push_constant_size: u32) -> ComputePipeline {
+        ComputePipeline::new_specialized(core, shader_path, set_layouts, push_constant_size, &SpecConstants::new())
+    }
+
+    // With the set layouts and push constants derived from the shader, see PipelineReflection. Descriptor sets are
+    // allocated with set_layouts.
+    pub fn new_reflected(core: &VkCore, shader_path: &str) -> ComputePipeline {
+        let reflection = PipelineReflection::load(&[shader_path]).unwrap();
+        let set_layouts = reflection.create_set_layouts(core);
+        let mut pipeline = ComputePipeline::new_specialized(core, shader_path, set_layouts.as_slice(),
+                                                            reflection.push_constant_size, &SpecConstants::new());
+        pipeline.set_layouts = set_layouts;
+
+        pipeline
+    }
+
+    // Like new, with values for the shader's specialization constants
+    pub fn new_specialized(core: &VkCore, shader_path: &str, set_layouts: &[vk::DescriptorSetLayout],
+                           push_constant_size: u32, spec_constants: &SpecConstants) -> ComputePipeline {
+        let _span = info_span!("compute_pipeline_create", shader = shader_path).entered();
+        let push_constant_ranges = [
+            vk::PushConstantRange::default()
+                .offset(0)
+                .size(push_constant_size)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        ];
+        let mut layout_create_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(set_layouts);
+        if push_constant_size > 0 {
+            layout_create_info = layout_create_info.push_constant_ranges(&push_constant_ranges);
+        }
+        let layout = unsafe { core.logical_device.create_pipeline_layout(&layout_create_info, None).unwrap() };
+
+        let shader_module = load_all_shaders(core, &[shader_path])[0];
+        if let Err(e) = PipelineReflection::load(&[shader_path])
+            .and_then(|r| r.check_push_constants(vk::ShaderStageFlags::COMPUTE, push_constant_size)) {
+            warn!(shader = shader_path, "The pipeline doesn't fit its shader: {}", e);
+        }
+        let spec_data = spec_constants.data();
+        let spec_info = spec_data.info();
+        let mut stage = vk::PipelineShaderStageCreateInfo::default()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(shader_module)
+            .name(c"main");
+        if let Some(info) = spec_info.as_ref() {
+            stage = stage.specialization_info(info);
+        }
+        let pipeline_create_info = vk::ComputePipelineCreateInfo::default()
+            .stage(stage)
+            .layout(layout);
+        let pipeline = unsafe {
+            core.logical_device.create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_create_info], None)
+                .unwrap()[0]
+        };
+        unsafe { core.logical_device.destroy_shader_module(shader_module, None) };
+
+        ComputePipeline {
+            pipeline,
+            layout,
+            set_layouts: Vec::new()
+        }
+    }
+
+    pub fn cmd_bind(&self, core: &VkCore, command_buffer: vk::CommandBuffer, descriptor_set: vk::DescriptorSet) {
+        unsafe {
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, self.layout,
+                                                         0, &[descriptor_set], &[]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_pipeline(self.pipeline, None);
+            core.logical_device.destroy_pipeline_layout(self.layout, None);
+            for &layout in self.set_layouts.iter() {
+                core.logical_device.destroy_descriptor_set_layout(layout, None);
+            }
+        }
+    }
+}
+
+// Number of work groups needed to cover size invocations
+pub fn group_count(size: u32, group_size: u32) -> u32 {
+    size.div_ceil(group_size)
+}
@@ -0,0 +1,250 @@
+use std::ffi::CString;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use ash::vk;
+use clap::{CommandFactory, Parser, ValueEnum};
+use clap::error::ErrorKind;
+use winit::dpi::LogicalSize;
+use winit::event_loop::EventLoop;
+
+use crate::display_mode::DisplayMode;
+use crate::gpu_select::GpuSelection;
+use crate::internal_resolution::{DEFAULT_SHARPNESS, InternalResolution, UpscaleFilter};
+use crate::memory::{log_rebar, RebarPolicy, TransientPolicy};
+use crate::scene::{Material, Scene, SceneModel, Transform};
+use crate::transparency::WindowTransparency;
+use crate::vkcore::VkCore;
+
+pub const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+pub const DEFAULT_SCENE_PATH: &str = "graphics/assets/scenes/viking_room.ron";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RendererKind {
+    Raster,
+    Rt,
+    Hybrid // Rasterized G-buffer, ray traced shadows, reflections and ambient occlusion
+}
+
+// Window size in logical pixels or an image size in pixels, written as WIDTHxHEIGHT on the command line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub width: u32,
+    pub height: u32
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Resolution, String> {
+        let (width, height) = s.split_once('x').ok_or(format!("expected WIDTHxHEIGHT, found {}", s))?;
+        let parse = |v: &str| match v.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("invalid size {} in {}", v, s))
+        };
+
+        Ok(Resolution {
+            width: parse(width)?,
+            height: parse(height)?
+        })
+    }
+}
+
+// WIDTH:HEIGHT, like 16:9, as width over height
+fn parse_aspect(s: &str) -> Result<f32, String> {
+    let (width, height) = s.split_once(':').ok_or(format!("expected WIDTH:HEIGHT, found {}", s))?;
+    match (width.parse::<f32>(), height.parse::<f32>()) {
+        (Ok(w), Ok(h)) if w > 0.0 && h > 0.0 => Ok(w / h),
+        _ => Err(format!("invalid aspect ratio {}", s))
+    }
+}
+
+fn parse_msaa(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(n) if n.is_power_of_two() && n <= 64 => Ok(n),
+        _ => Err(format!("expected 1, 2, 4, 8, 16, 32 or 64, found {}", s))
+    }
+}
+
+// Launch options shared by the main binary and the examples. Options that only one renderer supports are rejected
+// by the other when it starts.
+#[derive(Clone, Debug, Parser)]
+#[command(name = "cubulous", about = "Raster and ray traced Vulkan renderers")]
+pub struct LaunchConfig {
+    #[arg(long, value_enum, default_value_t = RendererKind::Raster)]
+    pub renderer: RendererKind,
+    #[arg(long, value_name = "FILE", help = "Scene file (.ron or .json)")]
+    pub scene: Option<String>,
+    #[arg(long, value_name = "FILE", help = "OBJ file that replaces the scene's first model")]
+    pub model: Option<String>,
+    #[arg(long, value_name = "FILE", help = "Texture for the scene's first model")]
+    pub texture: Option<String>,
+    #[arg(long, value_name = "WIDTHxHEIGHT", default_value = "800x600")]
+    pub resolution: Resolution,
+    #[arg(long, value_enum, default_value_t = DisplayMode::Windowed,
+          help = "Exclusive falls back to borderless where VK_EXT_full_screen_exclusive is unavailable")]
+    pub display_mode: DisplayMode,
+    #[arg(long, value_enum, default_value_t = WindowTransparency::Opaque,
+          help = "Let the desktop show through where nothing is drawn, overlay also floats undecorated on top (raster \
+                  only)")]
+    pub transparency: WindowTransparency,
+    #[arg(long, value_name = "WIDTHxHEIGHT", help = "Render at this size and scale it to the window (rt only)")]
+    pub internal_size: Option<Resolution>,
+    #[arg(long, value_name = "WIDTH:HEIGHT", value_parser = parse_aspect, conflicts_with = "internal_size",
+          help = "Render at this aspect ratio, with black bars where the window's differs (rt only)")]
+    pub aspect: Option<f32>,
+    #[arg(long, value_enum, default_value_t = UpscaleFilter::Linear,
+          help = "How --internal-size and --aspect images are scaled to the window")]
+    pub upscale_filter: UpscaleFilter,
+    #[arg(long, value_name = "STOPS", default_value_t = DEFAULT_SHARPNESS,
+          help = "Sharpening of --upscale-filter edge-adaptive, 0 is the strongest and each stop halves it")]
+    pub sharpness: f32,
+    #[arg(long, help = "Present without waiting for vertical blank")]
+    pub no_vsync: bool,
+    #[arg(long, help = "Start with the latency governor enabled, L toggles it while running")]
+    pub low_latency: bool,
+    #[arg(long, value_name = "FPS", help = "Limit the frame rate on the CPU, also without vsync and in benchmarks")]
+    pub max_fps: Option<f32>,
+    #[arg(long, value_name = "CYCLES", default_value_t = 0,
+          help = "Present every CYCLES display refreshes where VK_GOOGLE_display_timing is supported (raster only)")]
+    pub frame_pacing: u32,
+    #[arg(long, value_name = "BOUNCES", default_value_t = 2,
+          help = "Reflection bounces, limited by the device's ray recursion depth (rt only)")]
+    pub reflection_bounces: u32,
+    #[arg(long, help = "Start in path tracing mode, P toggles it and [ and ] change the samples per pixel (rt only)")]
+    pub path_tracing: bool,
+    #[arg(long, help = "Start with ambient occlusion disabled, O toggles it while running (raster only)")]
+    pub no_ssao: bool,
+    #[arg(long, help = "Start with camera motion blur enabled, B toggles it while running")]
+    pub motion_blur: bool,
+    #[arg(long, help = "Use the color pipeline's exposure as is instead of adapting it to the scene")]
+    pub no_auto_exposure: bool,
+    #[arg(long, value_name = "SAMPLES", value_parser = parse_msaa,
+          help = "MSAA sample count, limited to what the device supports [default: device maximum]")]
+    pub msaa: Option<u32>,
+    #[arg(long, value_enum, default_value_t = RebarPolicy::Auto,
+          help = "When buffers the CPU writes often are put in device local memory it can map")]
+    pub rebar: RebarPolicy,
+    #[arg(long, value_enum, default_value_t = TransientPolicy::Auto,
+          help = "Whether attachments that only live during a render pass get lazily allocated memory, on tile GPUs")]
+    pub transient_attachments: TransientPolicy,
+    #[arg(long, value_name = "INDEX|NAME",
+          help = "Physical device by index or part of its name, see --list-gpus. Overrides CUBULOUS_GPU")]
+    pub gpu: Option<GpuSelection>,
+    #[arg(long, help = "List the physical devices with their indices and exit")]
+    pub list_gpus: bool,
+    #[arg(long, help = "Don't enable the Khronos validation layer")]
+    pub no_validation: bool,
+    #[arg(long, value_name = "FILE", help = "Write stats of the last frames and a backtrace here on a panic")]
+    pub crash_report: Option<PathBuf>,
+    #[arg(long, help = "Draw the model for both eyes with multiview and show them side by side (raster only)")]
+    pub stereo: bool,
+    #[arg(long, value_name = "SOCKET",
+          help = "Share the presented frames with processes that connect to this Unix socket (raster only)")]
+    pub share_frames: Option<PathBuf>,
+    #[arg(long, value_name = "CAMERA_PATH", conflicts_with_all = ["record", "replay"],
+          help = "Render a camera path and write frame time statistics (raster and rt)")]
+    pub benchmark: Option<String>,
+    #[arg(long, value_name = "FRAMES", default_value_t = 1000)]
+    pub benchmark_frames: usize,
+    #[arg(long, value_name = "INPUT_FILE", conflicts_with = "replay", help = "Record input while running (rt only)")]
+    pub record: Option<String>,
+    #[arg(long, value_name = "INPUT_FILE", requires = "hashes",
+          help = "Replay recorded input and hash every frame (rt only)")]
+    pub replay: Option<String>,
+    #[arg(long, value_name = "HASH_FILE", requires = "replay", help = "Where --replay writes frame hashes")]
+    pub hashes: Option<PathBuf>,
+    #[arg(long, value_name = "HASH_FILE", requires = "replay", help = "Hashes --replay is compared against")]
+    pub expect_hashes: Option<PathBuf>
+}
+
+// Same as launching without arguments
+impl Default for LaunchConfig {
+    fn default() -> LaunchConfig {
+        LaunchConfig::parse_from(["cubulous"])
+    }
+}
+
+impl LaunchConfig {
+    // Exits with usage information on invalid arguments
+    pub fn from_args() -> LaunchConfig {
+        LaunchConfig::parse()
+    }
+
+    // Exits with usage information like an invalid argument does, for options the picked renderer doesn't support
+    pub fn reject(&self, message: &str) -> ! {
+        LaunchConfig::command().error(ErrorKind::ArgumentConflict, message).exit()
+    }
+
+    pub fn window_size(&self) -> LogicalSize<u32> {
+        LogicalSize::new(self.resolution.width, self.resolution.height)
+    }
+
+    pub fn internal_resolution(&self) -> InternalResolution {
+        InternalResolution {
+            size: self.internal_size,
+            aspect: self.aspect,
+            filter: self.upscale_filter,
+            sharpness: self.sharpness
+        }
+    }
+
+    pub fn validation_layers(&self) -> Vec<String> {
+        match self.no_validation {
+            true => Vec::new(),
+            false => Vec::from([String::from(VALIDATION_LAYER)])
+        }
+    }
+
+    // MAILBOX waits for vertical blank without blocking the renderer, RenderTarget falls back to FIFO without it
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        match self.no_vsync {
+            true => vk::PresentModeKHR::IMMEDIATE,
+            false => vk::PresentModeKHR::MAILBOX
+        }
+    }
+
+    // --gpu, or CUBULOUS_GPU without it
+    pub fn gpu_selection(&self) -> GpuSelection {
+        self.gpu.clone().or_else(GpuSelection::from_env).unwrap_or_default()
+    }
+
+    pub fn create_core(&self, ev_loop: &EventLoop<()>, required_extensions: &Vec<CString>) -> VkCore {
+        let mut core = VkCore::new_with_window_size(ev_loop, &self.validation_layers(), required_extensions,
+                                                    self.window_size(), &self.gpu_selection(), self.transparency);
+        if let Some(samples) = self.msaa {
+            core.limit_msaa_samples(vk::SampleCountFlags::from_raw(samples));
+        }
+        core.rebar_policy = self.rebar;
+        core.transient_policy = self.transient_attachments;
+        log_rebar(&core);
+
+        core
+    }
+
+    // Loads --scene, or default_path without one, and applies --model and --texture to the first model
+    pub fn load_scene(&self, default_path: &str) -> Result<Scene, String> {
+        let mut scene = Scene::load(self.scene.as_deref().unwrap_or(default_path))?;
+        if let Some(path) = &self.model {
+            match scene.models.first_mut() {
+                Some(model) => model.path = path.clone(),
+                None => scene.models.push(SceneModel {
+                    name: String::from("model"),
+                    path: path.clone(),
+                    material: None,
+                    transform: Transform::default()
+                })
+            }
+        }
+        if let Some(texture) = &self.texture {
+            let model = scene.models.first_mut().ok_or(String::from("--texture needs a model"))?;
+            // A new material, so other models sharing the old one keep their texture
+            let name = format!("{}_texture", model.name);
+            model.material = Some(name.clone());
+            scene.materials.push(Material::textured(name.as_str(), texture.as_str()));
+        }
+        scene.validate()?;
+
+        Ok(scene)
+    }
+}
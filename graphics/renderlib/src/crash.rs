@@ -0,0 +1,121 @@
+use std::backtrace::Backtrace;
+use std::collections::VecDeque;
+use std::fmt::{Display, Write as FmtWrite};
+use std::fs;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use ash::Device;
+use tracing::error;
+
+use crate::benchmark::FrameStats;
+use crate::vkcore::VkCore;
+
+pub const CRASH_HISTORY_FRAMES: usize = 120;
+
+struct CrashState {
+    device: Option<Device>, // Cleared by disarm before the device is destroyed
+    report_path: Option<PathBuf>,
+    history: VecDeque<(u64, FrameStats)>,
+    capacity: usize,
+    frames_drawn: u64
+}
+
+impl CrashState {
+    fn report(&self, info: &dyn Display) -> String {
+        let mut report = String::new();
+        writeln!(report, "panic: {}", info).unwrap();
+        writeln!(report, "frames drawn: {}", self.frames_drawn).unwrap();
+        writeln!(report, "\n# frame frame_ms cpu_ms gpu_ms").unwrap();
+        for (frame, stats) in self.history.iter() {
+            let gpu = stats.gpu_ms.map_or(String::from("-"), |ms| format!("{:.4}", ms));
+            writeln!(report, "{} {:.4} {:.4} {}", frame, stats.frame_ms, stats.cpu_ms, gpu).unwrap();
+        }
+        writeln!(report, "\n# backtrace\n{}", Backtrace::force_capture()).unwrap();
+
+        report
+    }
+
+    fn handle_panic(&self, info: &dyn Display) {
+        error!("Renderer panicked after {} frames: {}", self.frames_drawn, info);
+        if let Some(device) = &self.device {
+            if let Err(e) = unsafe { device.device_wait_idle() } {
+                error!("device_wait_idle after panic failed: {:?}", e);
+            }
+        }
+        if let Some(path) = &self.report_path {
+            match fs::write(path, self.report(info)) {
+                Ok(_) => error!("Crash report written to {}", path.display()),
+                Err(e) => error!("Failed to write crash report {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+// Panic hook that idles the device before the process goes down. A panic mid-frame otherwise exits with work still
+// queued, which loses validation output. With a report path the stats of the last frames are written there as well.
+#[derive(Clone)]
+pub struct CrashHandler {
+    state: Arc<Mutex<CrashState>>
+}
+
+impl CrashHandler {
+    // capacity is the number of frames kept for the report. The previous hook still runs afterwards.
+    pub fn install(core: &VkCore, report_path: Option<PathBuf>, capacity: usize) -> CrashHandler {
+        let state = Arc::new(Mutex::new(CrashState {
+            device: Some(core.logical_device.clone()),
+            report_path,
+            history: VecDeque::with_capacity(capacity),
+            capacity,
+            frames_drawn: 0
+        }));
+
+        let hook_state = state.clone();
+        let previous_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            // try_lock, since a panic inside record would deadlock otherwise
+            if let Ok(state) = hook_state.try_lock() {
+                state.handle_panic(info);
+            }
+            previous_hook(info);
+            let _ = io::stdout().flush();
+            let _ = io::stderr().flush();
+        }));
+
+        CrashHandler {
+            state
+        }
+    }
+
+    pub fn record(&self, stats: FrameStats) {
+        let mut state = self.state.lock().unwrap();
+        let frame = state.frames_drawn;
+        if state.history.len() == state.capacity {
+            state.history.pop_front();
+        }
+        state.history.push_back((frame, stats));
+        state.frames_drawn += 1;
+    }
+
+    // Runs f and aborts if it panics, after the hook has idled the device. Unwinding out of an event loop callback
+    // isn't supported on every platform, and the renderer state can't be trusted after a panic mid-frame anyway.
+    pub fn run_guarded<R>(&self, f: impl FnOnce() -> R) -> R {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(r) => r,
+            Err(_) => process::abort()
+        }
+    }
+
+    // Must be called before the device is destroyed, the hook stays installed but no longer touches the device
+    pub fn disarm(&self) {
+        self.state.lock().unwrap().device = None;
+    }
+
+    // Points the hook at core's device again, after VkCore::recreate_device
+    pub fn rearm(&self, core: &VkCore) {
+        self.state.lock().unwrap().device = Some(core.logical_device.clone());
+    }
+}
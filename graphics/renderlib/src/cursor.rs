@@ -0,0 +1,96 @@
+use std::mem;
+
+use tracing::{debug, info, warn};
+use winit::event::{DeviceEvent, ElementState, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent};
+use winit::window::{CursorGrabMode, Window};
+
+pub const CURSOR_RELEASE_KEY: VirtualKeyCode = VirtualKeyCode::Escape;
+
+// Pointer lock for mouse look. Clicking into the window captures the cursor: it is hidden and locked in place, or
+// confined to the window where locking isn't supported (X11), and raw mouse motion accumulates until take_motion.
+// CURSOR_RELEASE_KEY and losing focus release it. While a UI is shown the cursor stays free, input goes to the UI
+// instead of the camera.
+#[derive(Default)]
+pub struct CursorCapture {
+    captured: bool,
+    ui_visible: bool,
+    motion: (f64, f64) // Pixels, x right and y down
+}
+
+impl CursorCapture {
+    pub fn is_captured(&self) -> bool {
+        self.captured
+    }
+
+    // Whether input events should go to the UI rather than the camera
+    pub fn ui_gets_events(&self) -> bool {
+        self.ui_visible
+    }
+
+    pub fn capture(&mut self, window: &Window) -> Result<(), String> {
+        if self.ui_visible {
+            return Err(String::from("The UI is visible"));
+        }
+        let mode = [CursorGrabMode::Locked, CursorGrabMode::Confined].into_iter()
+            .find(|&mode| window.set_cursor_grab(mode).is_ok())
+            .ok_or(String::from("The window system can't grab the cursor"))?;
+        window.set_cursor_visible(false);
+        self.captured = true;
+        self.motion = (0.0, 0.0);
+        info!(?mode, "Cursor captured, {:?} releases it", CURSOR_RELEASE_KEY);
+
+        Ok(())
+    }
+
+    pub fn release(&mut self, window: &Window) {
+        if !self.captured {
+            return;
+        }
+        if let Err(e) = window.set_cursor_grab(CursorGrabMode::None) {
+            warn!("Couldn't release the cursor: {}", e);
+        }
+        window.set_cursor_visible(true);
+        self.captured = false;
+        self.motion = (0.0, 0.0);
+        debug!("Cursor released");
+    }
+
+    // Showing the UI releases the cursor
+    pub fn set_ui_visible(&mut self, window: &Window, visible: bool) {
+        if visible {
+            self.release(window);
+        }
+        self.ui_visible = visible;
+    }
+
+    pub fn handle_window_event(&mut self, window: &Window, event: &WindowEvent) {
+        match event {
+            WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. }
+            if !self.captured && !self.ui_visible => {
+                if let Err(e) = self.capture(window) {
+                    warn!("Mouse look is unavailable: {}", e);
+                }
+            },
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(CURSOR_RELEASE_KEY), .. },
+                ..
+            } => self.release(window),
+            WindowEvent::Focused(false) => self.release(window),
+            _ => ()
+        }
+    }
+
+    // Raw motion doesn't stop at the window's edges and isn't affected by pointer acceleration
+    pub fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta: (x, y) } = event {
+            if self.captured {
+                self.motion = (self.motion.0 + x, self.motion.1 + y);
+            }
+        }
+    }
+
+    // Motion since the last call, once a frame
+    pub fn take_motion(&mut self) -> (f64, f64) {
+        mem::take(&mut self.motion)
+    }
+}
@@ -0,0 +1,107 @@
+use std::mem;
+
+use ash::vk;
+use cgmath::{Point3, Vector3};
+use tracing::warn;
+
+use crate::collision::Aabb;
+use crate::color_pipeline::ColorConstants;
+use crate::frame_constants::{FRAME_CONSTANTS_SET, FrameConstantsBuffer};
+use crate::frame_ring::{FrameRing, RingSlice};
+use crate::raster_pipeline::RasterPipeline;
+use crate::renderutils::cast_to_u8_slice;
+use crate::vkcore::VkCore;
+
+// Vertex bytes all frames in flight can hold together, 32 bytes a line
+const RING_SIZE: vk::DeviceSize = 4 * 1024 * 1024;
+
+// Matches the inputs of debug_lines.vert
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 3]
+}
+
+// Immediate mode lines in world space, for visualizing bounds, rays and the like. Lines are added every frame they
+// should be seen, update uploads them into a FrameRing and starts over. Drawn in the forward render pass from the
+// main camera, hidden by what is in front of them.
+pub struct DebugLines {
+    vertices: Vec<DebugVertex>,
+    ring: FrameRing,
+    slices: Vec<Option<RingSlice>>, // What each frame in flight draws
+    pipeline: RasterPipeline
+}
+
+impl DebugLines {
+    pub fn new(core: &VkCore, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags,
+               frame_constants: &FrameConstantsBuffer, max_frames: usize) -> DebugLines {
+        DebugLines {
+            vertices: Vec::new(),
+            ring: FrameRing::new(core, RING_SIZE, vk::BufferUsageFlags::VERTEX_BUFFER, max_frames),
+            slices: vec![None; max_frames],
+            pipeline: RasterPipeline::new_debug_lines(core, render_pass, frame_constants.set_layout(), msaa_samples)
+        }
+    }
+
+    // color is linear RGB
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 3]) {
+        self.vertices.push(DebugVertex { position: from.into(), color });
+        self.vertices.push(DebugVertex { position: to.into(), color });
+    }
+
+    // The twelve edges of the box
+    pub fn aabb(&mut self, aabb: &Aabb, color: [f32; 3]) {
+        let c = aabb.corners();
+        for (a, b) in [(0, 1), (2, 3), (4, 5), (6, 7), (0, 2), (1, 3), (4, 6), (5, 7), (0, 4), (1, 5), (2, 6), (3, 7)] {
+            self.line(c[a], c[b], color);
+        }
+    }
+
+    // The x, y and z axes from origin in red, green and blue
+    pub fn axes(&mut self, origin: Point3<f32>, length: f32) {
+        self.line(origin, origin + Vector3::unit_x() * length, [1.0, 0.0, 0.0]);
+        self.line(origin, origin + Vector3::unit_y() * length, [0.0, 1.0, 0.0]);
+        self.line(origin, origin + Vector3::unit_z() * length, [0.0, 0.0, 1.0]);
+    }
+
+    // Once the frame's fence has been waited on. The lines added since the last update are drawn in this frame, lines
+    // that don't fit the ring are dropped.
+    pub fn update(&mut self, current_frame: usize) {
+        self.ring.begin_frame(current_frame);
+        self.slices[current_frame] = match self.vertices.is_empty() {
+            true => None,
+            false => self.ring.write(self.vertices.as_slice())
+                .map_err(|e| warn!("Debug lines are not drawn: {}", e))
+                .ok()
+        };
+        self.vertices.clear();
+    }
+
+    // Inside the forward render pass, once the viewport and scissor are set. Leaves the lines' pipeline bound if
+    // there are any.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame_constants: &FrameConstantsBuffer,
+                    color_constants: &ColorConstants, current_frame: usize) {
+        let slice = match self.slices[current_frame] {
+            Some(s) => s,
+            None => return
+        };
+        let layout = self.pipeline.pipeline_layout;
+        frame_constants.cmd_bind(core, command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, FRAME_CONSTANTS_SET,
+                                 current_frame);
+        let vertex_count = (slice.size / mem::size_of::<DebugVertex>() as vk::DeviceSize) as u32;
+        unsafe {
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                  self.pipeline.pipelines[0]);
+            core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[slice.buffer], &[slice.offset]);
+            core.logical_device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                                   cast_to_u8_slice(color_constants));
+            core.logical_device.cmd_draw(command_buffer, vertex_count, 1, 0, 0);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+        self.ring.destroy(core);
+    }
+}
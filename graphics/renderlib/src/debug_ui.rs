@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use egui::epaint::{ImageDelta, Primitive, Vertex};
+use egui::{ClippedPrimitive, ImageData, TextureId};
+use tracing::warn;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::window::Window;
+
+use crate::color_pipeline::is_srgb_format;
+use crate::deletion_queue::{Deletion, DeletionQueue};
+use crate::descriptor_allocator::DescriptorAllocator;
+use crate::frame_buffers::destroy_frame_buffers;
+use crate::frame_ring::{FrameRing, RingSlice};
+use crate::raster_pipeline::RasterPipeline;
+use crate::render_target::RenderTarget;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_linear_sampler, destroy_sampler};
+use crate::ssao::{create_apply_frame_buffers, create_apply_render_pass, create_set_layout};
+use crate::texture::Texture;
+use crate::vkcore::VkCore;
+
+pub const DEBUG_UI_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::F1;
+// Vertex and index bytes all frames in flight can hold together
+const RING_SIZE: vk::DeviceSize = 4 * 1024 * 1024;
+
+// Matches the push constants in ui.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct UiConstants {
+    screen_size: [f32; 2], // In points
+    encode_srgb: u32,
+    padding: u32
+}
+
+// An egui texture as its deltas left it, RGBA rows of sRGB colors with premultiplied alpha
+struct UiImage {
+    size: [usize; 2],
+    pixels: Vec<u8>,
+    generation: u64 // Changes with every delta, see DebugUiDraws::update
+}
+
+fn image_pixels(image: &ImageData) -> Vec<u8> {
+    match image {
+        ImageData::Color(image) => image.pixels.iter().flat_map(|c| c.to_array()).collect(),
+        ImageData::Font(image) => image.srgba_pixels(None).flat_map(|c| c.to_array()).collect()
+    }
+}
+
+// The part of the framebuffer a mesh is clipped to, None if none of it is inside
+fn scissor(clip_rect: egui::Rect, pixels_per_point: f32, extent: vk::Extent2D) -> Option<vk::Rect2D> {
+    let (width, height) = (extent.width as f32, extent.height as f32);
+    let min_x = (clip_rect.min.x * pixels_per_point).round().clamp(0.0, width);
+    let min_y = (clip_rect.min.y * pixels_per_point).round().clamp(0.0, height);
+    let max_x = (clip_rect.max.x * pixels_per_point).round().clamp(min_x, width);
+    let max_y = (clip_rect.max.y * pixels_per_point).round().clamp(min_y, height);
+    if max_x <= min_x || max_y <= min_y {
+        return None;
+    }
+
+    Some(vk::Rect2D::default()
+        .offset(vk::Offset2D::default().x(min_x as i32).y(min_y as i32))
+        .extent(vk::Extent2D::default().width((max_x - min_x) as u32).height((max_y - min_y) as u32)))
+}
+
+fn write_set(core: &VkCore, set: vk::DescriptorSet, sampler: vk::Sampler, texture: &Texture) {
+    let image_info = [vk::DescriptorImageInfo::default()
+        .sampler(sampler)
+        .image_view(texture.view)
+        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+    let write = [vk::WriteDescriptorSet::default()
+        .dst_set(set)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .image_info(&image_info)];
+    unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+}
+
+// egui panels for inspecting and editing the scene while a renderer runs, hidden until DEBUG_UI_TOGGLE_KEY is
+// pressed. Window events go to egui first, the ones it consumes aren't meant for the camera or other key bindings.
+// Every frame, the panels are added to the context begin_frame returns, and end_frame tessellates them for
+// DebugUiDraws to draw. egui's textures are kept here as well, so that DebugUiDraws can upload them again after the
+// device was lost.
+pub struct DebugUi {
+    context: egui::Context,
+    state: egui_winit::State,
+    visible: bool,
+    images: HashMap<TextureId, UiImage>,
+    primitives: Vec<ClippedPrimitive>, // Of the last end_frame, empty while hidden
+    repaint: bool, // egui animates something, or asked to be shown again right away
+    generation: u64 // Of the last image delta
+}
+
+impl DebugUi {
+    pub fn new(window: &Window) -> DebugUi {
+        let mut state = egui_winit::State::new(window);
+        state.set_pixels_per_point(egui_winit::native_pixels_per_point(window));
+
+        DebugUi {
+            context: egui::Context::default(),
+            state,
+            visible: false,
+            images: HashMap::new(),
+            primitives: Vec::new(),
+            repaint: false,
+            generation: 0
+        }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+    }
+
+    // Returns whether egui consumed the event. While the panels are hidden only the toggle key is.
+    pub fn handle_window_event(&mut self, event: &WindowEvent) -> bool {
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(DEBUG_UI_TOGGLE_KEY), .. },
+            ..
+        } = event {
+            self.visible = !self.visible;
+            return true;
+        }
+        // Also while hidden, so that egui keeps up with the window's size, scale and focus
+        let response = self.state.on_event(&self.context, event);
+
+        self.visible && response.consumed
+    }
+
+    // Once per frame, None while the panels are hidden. Otherwise the panels are added to the returned context and
+    // end_frame is called before the frame is drawn.
+    pub fn begin_frame(&mut self, window: &Window) -> Option<egui::Context> {
+        let input = self.state.take_egui_input(window); // Dropped while hidden, so that it doesn't pile up
+        if !self.visible {
+            self.primitives.clear();
+            self.repaint = false;
+            return None;
+        }
+        self.context.begin_frame(input);
+
+        Some(self.context.clone())
+    }
+
+    pub fn end_frame(&mut self, window: &Window) {
+        let output = self.context.end_frame();
+        self.state.handle_platform_output(window, &self.context, output.platform_output);
+        for (id, delta) in output.textures_delta.set {
+            self.set_image(id, delta);
+        }
+        // Nothing tessellated from here on uses them, DebugUiDraws destroys their textures once frames in flight
+        // finished
+        for id in output.textures_delta.free {
+            self.images.remove(&id);
+        }
+        self.primitives = self.context.tessellate(output.shapes);
+        self.repaint = output.repaint_after.is_zero();
+    }
+
+    // egui wants to draw again even without input, which idle frame skipping should allow
+    pub fn wants_repaint(&self) -> bool {
+        self.visible && self.repaint
+    }
+
+    fn set_image(&mut self, id: TextureId, delta: ImageDelta) {
+        let [width, height] = delta.image.size();
+        let pixels = image_pixels(&delta.image);
+        self.generation += 1;
+        match (delta.pos, self.images.get_mut(&id)) {
+            (Some([x, y]), Some(image)) => {
+                let row_bytes = image.size[0] * 4;
+                for (row, patch) in pixels.chunks_exact(width * 4).enumerate().take(height) {
+                    let start = (y + row) * row_bytes + x * 4;
+                    image.pixels[start..start + patch.len()].copy_from_slice(patch);
+                }
+                image.generation = self.generation;
+            },
+            (Some(_), None) => warn!("egui updated {:?}, which it never set", id),
+            (None, _) => {
+                self.images.insert(id, UiImage {
+                    size: [width, height],
+                    pixels,
+                    generation: self.generation
+                });
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct UiDraw {
+    scissor: vk::Rect2D,
+    set: vk::DescriptorSet,
+    vertices: RingSlice,
+    indices: RingSlice,
+    index_count: u32
+}
+
+// Draws what DebugUi tessellated over the finished swap chain image, in a render pass of its own that loads it. The
+// meshes are copied into a FrameRing, and each frame allocates a descriptor set for every texture it draws. Textures
+// are uploaded again whenever DebugUi's copy of them changed, the replaced ones go to the DeletionQueue.
+pub struct DebugUiDraws {
+    extent: vk::Extent2D,
+    encode_srgb: bool, // For UNORM swap chains
+    render_pass: vk::RenderPass,
+    frame_buffers: Vec<vk::Framebuffer>,
+    pipeline: RasterPipeline,
+    set_layout: vk::DescriptorSetLayout,
+    sets: DescriptorAllocator,
+    sampler: vk::Sampler,
+    textures: HashMap<TextureId, (Texture, u64)>, // And the generation of the UiImage they were uploaded from
+    ring: FrameRing,
+    draws: Vec<Vec<UiDraw>>, // Of each frame in flight
+    constants: Vec<UiConstants>
+}
+
+impl DebugUiDraws {
+    pub fn new(core: &VkCore, render_target: &RenderTarget, max_frames: usize) -> DebugUiDraws {
+        let render_pass = create_apply_render_pass(core, render_target);
+        let set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+        ]);
+        let ratios = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)];
+
+        DebugUiDraws {
+            extent: render_target.extent,
+            encode_srgb: !is_srgb_format(render_target.surface_format),
+            render_pass,
+            frame_buffers: create_apply_frame_buffers(core, render_pass, render_target),
+            pipeline: RasterPipeline::new_ui(core, render_pass, set_layout),
+            set_layout,
+            sets: DescriptorAllocator::new(max_frames, &ratios),
+            sampler: create_linear_sampler(core),
+            textures: HashMap::new(),
+            ring: FrameRing::new(core, RING_SIZE, vk::BufferUsageFlags::VERTEX_BUFFER |
+                vk::BufferUsageFlags::INDEX_BUFFER, max_frames),
+            draws: vec![Vec::new(); max_frames],
+            constants: vec![UiConstants::default(); max_frames]
+        }
+    }
+
+    // After the swap chain was created again, nothing may still be using the old frame buffers
+    pub fn resize(&mut self, core: &VkCore, render_target: &RenderTarget) {
+        destroy_frame_buffers(core, &self.frame_buffers);
+        self.extent = render_target.extent;
+        self.frame_buffers = create_apply_frame_buffers(core, self.render_pass, render_target);
+    }
+
+    // Once the frame's fence has been waited on, after DebugUi::end_frame. frame_index is the frame being recorded,
+    // see DeletionQueue::push. Meshes that don't fit the ring are left out.
+    pub fn update(&mut self, core: &VkCore, command_pool: vk::CommandPool, deletions: &mut DeletionQueue,
+                  frame_index: u64, current_frame: usize, ui: &DebugUi) {
+        self.sets.reset_frame(core, current_frame);
+        self.ring.begin_frame(current_frame);
+        self.draws[current_frame].clear();
+
+        let freed: Vec<TextureId> = self.textures.keys().filter(|id| !ui.images.contains_key(id)).copied().collect();
+        for id in freed {
+            let (texture, _) = self.textures.remove(&id).unwrap();
+            deletions.push(frame_index, Deletion::Texture(texture));
+        }
+        for (id, image) in ui.images.iter() {
+            if self.textures.get(id).is_some_and(|(_, generation)| *generation == image.generation) {
+                continue;
+            }
+            let texture = Texture::from_rgba8(core, command_pool, image.size[0] as u32, image.size[1] as u32,
+                                              image.pixels.as_slice());
+            if let Some((replaced, _)) = self.textures.insert(*id, (texture, image.generation)) {
+                deletions.push(frame_index, Deletion::Texture(replaced));
+            }
+        }
+
+        let pixels_per_point = ui.context.pixels_per_point();
+        self.constants[current_frame] = UiConstants {
+            screen_size: [self.extent.width as f32 / pixels_per_point, self.extent.height as f32 / pixels_per_point],
+            encode_srgb: self.encode_srgb as u32,
+            padding: 0
+        };
+        let mut sets: HashMap<TextureId, vk::DescriptorSet> = HashMap::new();
+        for primitive in ui.primitives.iter() {
+            // Paint callbacks are for renderers that draw into egui's panels, which this one doesn't
+            let mesh = match &primitive.primitive {
+                Primitive::Mesh(mesh) if !mesh.indices.is_empty() => mesh,
+                _ => continue
+            };
+            let (texture, scissor) = match (self.textures.get(&mesh.texture_id),
+                                            scissor(primitive.clip_rect, pixels_per_point, self.extent)) {
+                (Some((texture, _)), Some(scissor)) => (texture, scissor),
+                _ => continue
+            };
+            let set = *sets.entry(mesh.texture_id).or_insert_with(|| {
+                let set = self.sets.allocate(core, current_frame, self.set_layout);
+                write_set(core, set, self.sampler, texture);
+                set
+            });
+            let slices = self.ring.write::<Vertex>(mesh.vertices.as_slice())
+                .and_then(|vertices| self.ring.write(mesh.indices.as_slice()).map(|indices| (vertices, indices)));
+            match slices {
+                Ok((vertices, indices)) => self.draws[current_frame].push(UiDraw {
+                    scissor,
+                    set,
+                    vertices,
+                    indices,
+                    index_count: mesh.indices.len() as u32
+                }),
+                Err(e) => {
+                    warn!("The debug UI is not drawn completely: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Once the swap chain image is otherwise finished, it stays in PRESENT_SRC_KHR. Nothing is recorded while the
+    // panels are hidden.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, image_index: u32,
+                    current_frame: usize) {
+        let draws = &self.draws[current_frame];
+        if draws.is_empty() {
+            return;
+        }
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.extent);
+        let viewports = [vk::Viewport::default()
+            .x(0.0)
+            .y(0.0)
+            .width(self.extent.width as f32)
+            .height(self.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.render_pass)
+            .framebuffer(self.frame_buffers[image_index as usize])
+            .render_area(render_area);
+        // The post-process chain may have copied into the swap chain image last
+        let image_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        let constants = [self.constants[current_frame]];
+        let logical_device = &core.logical_device;
+        let layout = self.pipeline.pipeline_layout;
+
+        unsafe {
+            logical_device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::TRANSFER |
+                                                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::DependencyFlags::empty(), &[image_barrier], &[], &[]);
+            logical_device.cmd_begin_render_pass(command_buffer, &pass_info, vk::SubpassContents::INLINE);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                             self.pipeline.pipelines[0]);
+            logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            logical_device.cmd_push_constants(command_buffer, layout,
+                                              vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, 0,
+                                              cast_to_u8_slice(&constants));
+            for draw in draws.iter() {
+                logical_device.cmd_set_scissor(command_buffer, 0, &[draw.scissor]);
+                logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                        &[draw.set], &[]);
+                logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[draw.vertices.buffer],
+                                                       &[draw.vertices.offset]);
+                logical_device.cmd_bind_index_buffer(command_buffer, draw.indices.buffer, draw.indices.offset,
+                                                     vk::IndexType::UINT32);
+                logical_device.cmd_draw_indexed(command_buffer, draw.index_count, 1, 0, 0, 0);
+            }
+            logical_device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for (texture, _) in self.textures.values() {
+            texture.destroy(core);
+        }
+        self.ring.destroy(core);
+        self.sets.destroy(core);
+        destroy_sampler(core, self.sampler);
+        self.pipeline.destroy(core);
+        destroy_frame_buffers(core, &self.frame_buffers);
+        unsafe {
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            core.logical_device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+
+use ash::vk;
+use tracing::trace;
+
+use crate::compute::ComputePipeline;
+use crate::gpu_buffer::GpuBuffer;
+use crate::raster_pipeline::RasterPipeline;
+use crate::texture::Texture;
+use crate::vkcore::VkCore;
+
+// Something frames in flight may still use, destroyed by DeletionQueue once they finished
+pub enum Deletion {
+    Buffer(GpuBuffer),
+    Texture(Texture),
+    Image(vk::Image, vk::DeviceMemory),
+    ImageView(vk::ImageView),
+    Sampler(vk::Sampler),
+    RasterPipeline(RasterPipeline),
+    ComputePipeline(ComputePipeline),
+    Pipeline(vk::Pipeline),
+    DescriptorPool(vk::DescriptorPool),
+    // Anything else, like objects owning several of the above
+    Custom(Box<dyn FnOnce(&VkCore)>)
+}
+
+impl Deletion {
+    fn destroy(self, core: &VkCore) {
+        let device = &core.logical_device;
+        match self {
+            Deletion::Buffer(b) => b.destroy(core),
+            Deletion::Texture(t) => t.destroy(core),
+            Deletion::Image(image, mem) => unsafe {
+                device.destroy_image(image, None);
+                device.free_memory(mem, None);
+            },
+            Deletion::ImageView(view) => unsafe { device.destroy_image_view(view, None) },
+            Deletion::Sampler(sampler) => unsafe { device.destroy_sampler(sampler, None) },
+            Deletion::RasterPipeline(p) => p.destroy(core),
+            Deletion::ComputePipeline(p) => p.destroy(core),
+            Deletion::Pipeline(p) => unsafe { device.destroy_pipeline(p, None) },
+            Deletion::DescriptorPool(pool) => unsafe { device.destroy_descriptor_pool(pool, None) },
+            Deletion::Custom(f) => f(core)
+        }
+    }
+}
+
+// Destroys resources once the GPU finished every frame that might reference them, without waiting for the device to
+// become idle. Each deletion is keyed by the index of the frame being recorded when it was pushed, the frame's value
+// on the timeline of submitted frames. collect is called once the fence of the current frame slot has been waited on,
+// which means frame_index - max_frames and all frames before it finished, as one queue completes them in order.
+pub struct DeletionQueue {
+    pending: VecDeque<(u64, Deletion)>, // In the order of their frame index
+    max_frames: u64
+}
+
+impl DeletionQueue {
+    pub fn new(max_frames: usize) -> DeletionQueue {
+        DeletionQueue {
+            pending: VecDeque::new(),
+            max_frames: max_frames as u64
+        }
+    }
+
+    // frame_index is the frame that is being or will next be recorded, the resource must not be used after it
+    pub fn push(&mut self, frame_index: u64, deletion: Deletion) {
+        // Keeps pending ordered, an earlier index than the last one only delays the deletion
+        let frame_index = self.pending.back().map_or(frame_index, |(last, _)| frame_index.max(*last));
+        self.pending.push_back((frame_index, deletion));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // After waiting on the fence of frame_index's slot, before recording it. Returns how many were destroyed.
+    pub fn collect(&mut self, core: &VkCore, frame_index: u64) -> usize {
+        let finished = match frame_index.checked_sub(self.max_frames) {
+            Some(f) => f,
+            None => return 0
+        };
+        let mut count = 0;
+        while self.pending.front().is_some_and(|(f, _)| *f <= finished) {
+            self.pending.pop_front().unwrap().1.destroy(core);
+            count += 1;
+        }
+        if count > 0 {
+            trace!(count, left = self.pending.len(), "Destroyed deferred resources");
+        }
+
+        count
+    }
+
+    // Once the device is idle, destroys everything regardless of the frame it was pushed in
+    pub fn destroy(&mut self, core: &VkCore) {
+        for (_, d) in self.pending.drain(..) {
+            d.destroy(core);
+        }
+    }
+}
@@ -1,12 +1,14 @@
 use ash::vk;
-use crate::image::{create_image, create_image_view, transition_image_layout};
+use crate::image::{create_image_view, create_image_with, ImageInfo, transition_image_layout};
+use crate::memory::transient_memory_flags;
 use crate::render_target::RenderTarget;
 use crate::vkcore::VkCore;
 
 pub struct Depth {
-    image: vk::Image,
+    pub image: vk::Image,
     mem: vk::DeviceMemory,
-    pub view: vk::ImageView
+    pub view: vk::ImageView,
+    pub format: vk::Format
 }
 
 fn find_supported_format(core: &VkCore, candidates: Vec<vk::Format>,
@@ -17,10 +19,12 @@ fn find_supported_format(core: &VkCore, candidates: Vec<vk::Format>,
             core.instance.get_physical_device_format_properties(core.physical_device, f)
         };
 
-        if tiling == vk::ImageTiling::LINEAR && (format_props.linear_tiling_features & features) == features {
-            retval = Ok(f);
-            break;
-        } else if tiling == vk::ImageTiling::OPTIMAL && (format_props.optimal_tiling_features & features) == features {
+        let tiling_features = match tiling {
+            vk::ImageTiling::LINEAR => format_props.linear_tiling_features,
+            vk::ImageTiling::OPTIMAL => format_props.optimal_tiling_features,
+            _ => continue
+        };
+        if (tiling_features & features) == features {
             retval = Ok(f);
             break;
         }
@@ -37,18 +41,86 @@ pub fn find_depth_format(core: &VkCore) -> vk::Format {
                           vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT).unwrap()
 }
 
+// Every device supports at least one of them as an attachment
+pub fn find_depth_stencil_format(core: &VkCore) -> vk::Format {
+    find_supported_format(core, Vec::from([vk::Format::D24_UNORM_S8_UINT,
+                              vk::Format::D32_SFLOAT_S8_UINT]),
+                          vk::ImageTiling::OPTIMAL,
+                          vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT).unwrap()
+}
+
+pub fn has_stencil(format: vk::Format) -> bool {
+    format == vk::Format::D32_SFLOAT_S8_UINT || format == vk::Format::D24_UNORM_S8_UINT
+}
+
+// Writes reference wherever a fragment passes the depth test, for pipelines that mark pixels
+pub fn stencil_replace(reference: u32) -> vk::StencilOpState {
+    vk::StencilOpState::default()
+        .fail_op(vk::StencilOp::KEEP)
+        .pass_op(vk::StencilOp::REPLACE)
+        .depth_fail_op(vk::StencilOp::KEEP)
+        .compare_op(vk::CompareOp::ALWAYS)
+        .compare_mask(0xff)
+        .write_mask(0xff)
+        .reference(reference)
+}
+
+// Only lets fragments through where compare_op(reference, stencil) holds, without changing the stencil
+pub fn stencil_test(compare_op: vk::CompareOp, reference: u32) -> vk::StencilOpState {
+    vk::StencilOpState::default()
+        .fail_op(vk::StencilOp::KEEP)
+        .pass_op(vk::StencilOp::KEEP)
+        .depth_fail_op(vk::StencilOp::KEEP)
+        .compare_op(compare_op)
+        .compare_mask(0xff)
+        .write_mask(0)
+        .reference(reference)
+}
+
 impl Depth {
+    // Only lives during the render pass, pair with setup_render_pass
     pub fn new(core: &VkCore, render_target: &RenderTarget,
                command_pool: vk::CommandPool) -> Depth {
-        let format = find_depth_format(core);
-        let (img, img_mem) = create_image(core,
-                                          render_target.extent.width, render_target.extent.height,
-                                          1, format, vk::ImageTiling::OPTIMAL,
-                                          vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
-                                          vk::MemoryPropertyFlags::DEVICE_LOCAL,
-                                          core.max_msaa_samples);
-        let depth_image_view = create_image_view(core, img, format,
-                                                 vk::ImageAspectFlags::DEPTH, 1);
+        Depth::new_with_usage(core, find_depth_format(core), render_target.extent, core.max_msaa_samples,
+                              command_pool, vk::ImageUsageFlags::TRANSIENT_ATTACHMENT, &[])
+    }
+
+    // Depth and stencil buffer, with a single sample for passes over a resolved image. The view has both aspects, so
+    // it can only be an attachment.
+    pub fn new_stencil(core: &VkCore, extent: vk::Extent2D, command_pool: vk::CommandPool) -> Depth {
+        Depth::new_with_usage(core, find_depth_stencil_format(core), extent, vk::SampleCountFlags::TYPE_1,
+                              command_pool, vk::ImageUsageFlags::empty(), &[])
+    }
+
+    // Depth buffer that can also be read by shaders, pair with setup_render_pass_stored_depth so the contents survive
+    // the render pass. Shared with the compute family so AsyncCompute work can read it too.
+    pub fn new_sampled(core: &VkCore, render_target: &RenderTarget,
+                       command_pool: vk::CommandPool) -> Depth {
+        Depth::new_with_usage(core, find_depth_format(core), render_target.extent, core.max_msaa_samples,
+                              command_pool, vk::ImageUsageFlags::SAMPLED,
+                              &[core.graphics_family_index, core.compute_family_index])
+    }
+
+    fn new_with_usage(core: &VkCore, format: vk::Format, extent: vk::Extent2D, samples: vk::SampleCountFlags,
+                      command_pool: vk::CommandPool, extra_usage: vk::ImageUsageFlags, queue_families: &[u32])
+        -> Depth {
+        let properties = match extra_usage.contains(vk::ImageUsageFlags::TRANSIENT_ATTACHMENT) {
+            true => transient_memory_flags(core),
+            false => vk::MemoryPropertyFlags::DEVICE_LOCAL
+        };
+        let (img, img_mem) = create_image_with(core, &ImageInfo {
+            properties,
+            samples,
+            queue_families,
+            ..ImageInfo::new(extent.width, extent.height, format,
+                             vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | extra_usage)
+        });
+        // Sampled views may only have one aspect, attachment views need all of the format's
+        let aspect = match has_stencil(format) && !extra_usage.contains(vk::ImageUsageFlags::SAMPLED) {
+            true => vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL,
+            false => vk::ImageAspectFlags::DEPTH
+        };
+        let depth_image_view = create_image_view(core, img, format, aspect, 1);
         transition_image_layout(core, command_pool, img, format,
                                 vk::ImageLayout::UNDEFINED,
                                 vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL, 1);
@@ -56,10 +128,15 @@ impl Depth {
         Depth {
             image: img,
             mem: img_mem,
-            view: depth_image_view
+            view: depth_image_view,
+            format
         }
     }
 
+    pub fn has_stencil(&self) -> bool {
+        has_stencil(self.format)
+    }
+
     pub fn destroy(&self, core: &VkCore) {
         unsafe {
             core.logical_device.destroy_image_view(self.view, None);
@@ -0,0 +1,188 @@
+use std::mem;
+use std::path::Path;
+
+use ash::vk;
+use cgmath::Matrix4;
+use image::{Rgb, Rgb32FImage, RgbImage};
+
+use crate::compute::{ComputePipeline, group_count};
+use crate::gpu_buffer::GpuBuffer;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_nearest_sampler, destroy_sampler};
+use crate::single_time::{begin_single_time_commands, end_single_time_commands};
+use crate::ssao::create_set_layout;
+use crate::vkcore::VkCore;
+
+const DEPTH_COPY_SHADER_PATH: &str = "graphics/shaders/spv/depth_copy.spv";
+const DEPTH_COPY_MS_SHADER_PATH: &str = "graphics/shaders/spv/depth_copy_ms.spv";
+const DEPTH_COPY_GROUP_SIZE: u32 = 8; // Matches local_size_x/y in depth_copy.glsl
+// From near to far, pixels without geometry are black
+const HEATMAP_COLORS: [[f32; 3]; 5] = [[1.0, 1.0, 0.7], [1.0, 0.65, 0.1], [0.85, 0.2, 0.3], [0.4, 0.05, 0.5],
+    [0.05, 0.0, 0.2]];
+
+// Distance from the camera plane along the view direction, like linear_depth.glsl, for a perspective or an
+// orthographic projection that is Y flipped like SceneCamera::projection
+pub fn linearize_depth(depth: f32, projection: &Matrix4<f32>) -> f32 {
+    if projection.z.w == 0.0 {
+        (projection.w.z - depth) / projection.z.z
+    } else {
+        projection.w.z / (depth + projection.z.z)
+    }
+}
+
+// A depth buffer on the host, for looking into culling, shadow acne and z-fighting. Values are what the depth test
+// compares, the buffer is cleared to 1.0 where nothing was drawn.
+pub struct DepthImage {
+    pub width: u32,
+    pub height: u32,
+    pub depths: Vec<f32>, // Rows from the top
+    pub projection: Matrix4<f32> // The frame's, for linearizing
+}
+
+impl DepthImage {
+    pub fn get(&self, x: u32, y: u32) -> f32 {
+        self.depths[(y * self.width + x) as usize]
+    }
+
+    // See linearize_depth
+    pub fn linear_depths(&self) -> Vec<f32> {
+        self.depths.iter().map(|d| linearize_depth(*d, &self.projection)).collect()
+    }
+
+    // Closest and farthest linear depth of the pixels with geometry, None if nothing was drawn
+    pub fn range(&self) -> Option<(f32, f32)> {
+        self.depths.iter()
+            .filter(|d| **d < 1.0)
+            .map(|d| linearize_depth(*d, &self.projection))
+            .fold(None, |range, d| match range {
+                Some((near, far)) => Some((d.min(near), d.max(far))),
+                None => Some((d, d))
+            })
+    }
+
+    // Linear depth stretched over range, near is bright and far is dark
+    pub fn heatmap(&self) -> RgbImage {
+        let (near, far) = self.range().unwrap_or((0.0, 1.0));
+        let scale = 1.0 / (far - near).max(f32::EPSILON);
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let depth = self.get(x, y);
+            if depth >= 1.0 {
+                return Rgb([0, 0, 0]);
+            }
+            let t = ((linearize_depth(depth, &self.projection) - near) * scale).clamp(0.0, 1.0) *
+                (HEATMAP_COLORS.len() - 1) as f32;
+            let i = (t as usize).min(HEATMAP_COLORS.len() - 2);
+            let f = t - i as f32;
+            let (a, b) = (HEATMAP_COLORS[i], HEATMAP_COLORS[i + 1]);
+            Rgb([0, 1, 2].map(|c| ((a[c] + (b[c] - a[c]) * f) * 255.0).round() as u8))
+        })
+    }
+
+    // .exr files keep the linear depth of every pixel in all three channels, .png files get the heatmap
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let result = match path.extension().and_then(|e| e.to_str()) {
+            Some("exr") => {
+                let pixels: Vec<f32> = self.linear_depths().into_iter().flat_map(|d| [d, d, d]).collect();
+                Rgb32FImage::from_raw(self.width, self.height, pixels).unwrap().save(path)
+            },
+            Some("png") => self.heatmap().save(path),
+            _ => return Err(format!("{}: depth images are saved as .exr or .png", path.display()))
+        };
+
+        result.map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+// Copies a depth buffer made with Depth::new_sampled to the host, sample 0 of every pixel when it is multisampled.
+// The depth buffer must be in DEPTH_STENCIL_READ_ONLY_OPTIMAL, as setup_render_pass_stored_depth leaves it, and
+// projection is the one it was drawn with. Builds its pipeline and waits for the graphics queue to become idle, so it
+// is meant for debugging rather than for every frame.
+pub fn read_depth(core: &VkCore, command_pool: vk::CommandPool, depth_view: vk::ImageView,
+                  samples: vk::SampleCountFlags, extent: vk::Extent2D, projection: Matrix4<f32>) -> DepthImage {
+    let set_layout = create_set_layout(core, &[
+        (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE),
+        (vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE)
+    ]);
+    let shader = match samples {
+        vk::SampleCountFlags::TYPE_1 => DEPTH_COPY_SHADER_PATH,
+        _ => DEPTH_COPY_MS_SHADER_PATH
+    };
+    let pipeline = ComputePipeline::new(core, shader, &[set_layout], mem::size_of::<[u32; 2]>() as u32);
+    let size = (extent.width * extent.height) as vk::DeviceSize * mem::size_of::<f32>() as vk::DeviceSize;
+    let buffer = GpuBuffer::new(core, size, vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+                                vk::MemoryPropertyFlags::DEVICE_LOCAL);
+    let sampler = create_nearest_sampler(core);
+
+    let pool_sizes = [
+        vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1),
+        vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+    ];
+    let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+        .max_sets(1)
+        .pool_sizes(&pool_sizes);
+    let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+    let layouts = [set_layout];
+    let allocate_info = vk::DescriptorSetAllocateInfo::default()
+        .descriptor_pool(descriptor_pool)
+        .set_layouts(&layouts);
+    let set = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap()[0] };
+    let depth_info = [vk::DescriptorImageInfo::default()
+        .sampler(sampler)
+        .image_view(depth_view)
+        .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+    let buffer_info = [vk::DescriptorBufferInfo::default()
+        .buffer(buffer.buf)
+        .offset(0)
+        .range(vk::WHOLE_SIZE)];
+    let writes = [
+        vk::WriteDescriptorSet::default()
+            .dst_set(set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&depth_info),
+        vk::WriteDescriptorSet::default()
+            .dst_set(set)
+            .dst_binding(1)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .buffer_info(&buffer_info)
+    ];
+    unsafe { core.logical_device.update_descriptor_sets(&writes, &[]) };
+
+    // The frames submitted before wrote the depth buffer
+    let depth_barrier = vk::MemoryBarrier::default()
+        .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ);
+    let push_constants = [extent.width, extent.height];
+    let command_buffer = begin_single_time_commands(core, command_pool);
+    unsafe {
+        core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                                                 vk::PipelineStageFlags::COMPUTE_SHADER, vk::DependencyFlags::empty(),
+                                                 &[depth_barrier], &[], &[]);
+        pipeline.cmd_bind(core, command_buffer, set);
+        core.logical_device.cmd_push_constants(command_buffer, pipeline.layout, vk::ShaderStageFlags::COMPUTE, 0,
+                                               cast_to_u8_slice(&push_constants));
+        core.logical_device.cmd_dispatch(command_buffer, group_count(extent.width, DEPTH_COPY_GROUP_SIZE),
+                                         group_count(extent.height, DEPTH_COPY_GROUP_SIZE), 1);
+    }
+    end_single_time_commands(core, command_pool, command_buffer);
+    let bytes = buffer.read_back(core, command_pool, 0..size);
+
+    unsafe {
+        core.logical_device.destroy_descriptor_pool(descriptor_pool, None);
+        core.logical_device.destroy_descriptor_set_layout(set_layout, None);
+    }
+    pipeline.destroy(core);
+    buffer.destroy(core);
+    destroy_sampler(core, sampler);
+
+    DepthImage {
+        width: extent.width,
+        height: extent.height,
+        depths: bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+        projection
+    }
+}
@@ -1,13 +1,37 @@
 use ash::vk;
+use crate::dynamic_ubo::DynamicUniformBuffer;
+use crate::objects::ObjectTransforms;
+use crate::skinning::BoneBuffer;
 use crate::texture::Texture;
-use crate::ubo::{UniformBuffer, UniformBufferObject};
+use crate::ubo::UniformBuffer;
 use crate::vkcore::VkCore;
 
 // Use Ash builtin to destroy the descriptor set layout
 pub fn create_descriptor_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    build_descriptor_set_layout(core, vk::DescriptorType::UNIFORM_BUFFER, false)
+}
+
+// Adds the joint matrices at binding 2 for the skinned vertex shader, see Descriptor::new_skinned
+pub fn create_skinned_descriptor_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    build_descriptor_set_layout(core, vk::DescriptorType::UNIFORM_BUFFER, true)
+}
+
+// The transforms are bound at a dynamic offset, see Descriptor::new_dynamic. The shaders are the same.
+pub fn create_dynamic_descriptor_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    build_descriptor_set_layout(core, vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, false)
+}
+
+// The view's transforms at a dynamic offset and the objects' model matrices at binding 2, see Descriptor::new_objects
+pub fn create_object_descriptor_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    build_descriptor_set_layout(core, vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, true)
+}
+
+// storage adds a vertex shader storage buffer at binding 2
+fn build_descriptor_set_layout(core: &VkCore, uniform_type: vk::DescriptorType, storage: bool)
+    -> vk::DescriptorSetLayout {
     let transform_binding = vk::DescriptorSetLayoutBinding::default()
         .binding(0)
-        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+        .descriptor_type(uniform_type)
         .descriptor_count(1)
         .stage_flags(vk::ShaderStageFlags::VERTEX);
 
@@ -17,10 +41,17 @@ pub fn create_descriptor_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
         .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
         .stage_flags(vk::ShaderStageFlags::FRAGMENT);
 
-    let binding_arr = [transform_binding, sampler_layout_binding];
+    let storage_binding = vk::DescriptorSetLayoutBinding::default()
+        .binding(2)
+        .descriptor_count(1)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .stage_flags(vk::ShaderStageFlags::VERTEX);
+
+    let binding_arr = [transform_binding, sampler_layout_binding, storage_binding];
+    let binding_count = if storage { 3 } else { 2 };
 
     let layout = vk::DescriptorSetLayoutCreateInfo::default()
-        .bindings(&binding_arr)
+        .bindings(&binding_arr[..binding_count])
         .flags(vk::DescriptorSetLayoutCreateFlags::empty());
 
     unsafe {
@@ -28,6 +59,15 @@ pub fn create_descriptor_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
     }
 }
 
+// The buffers that Descriptor::build binds. uniform_buffers holds the transforms of each frame, range is their size.
+// storage is bound at binding 2 the same way, for the bones of skinned meshes or the model matrices of objects.
+struct DescriptorBuffers<'a> {
+    uniform_type: vk::DescriptorType,
+    uniform_buffers: &'a [vk::Buffer],
+    range: vk::DeviceSize,
+    storage: Option<(&'a [vk::Buffer], vk::DeviceSize)>
+}
+
 pub struct Descriptor {
     pool: vk::DescriptorPool,
     layout: vk::DescriptorSetLayout,
@@ -37,18 +77,86 @@ pub struct Descriptor {
 impl Descriptor {
     pub fn new(core: &VkCore, ubo: &UniformBuffer, sampler: vk::Sampler,
                texture: &Texture, layout: vk::DescriptorSetLayout, max_frames: usize) -> Descriptor {
+        let buffers = DescriptorBuffers {
+            uniform_type: vk::DescriptorType::UNIFORM_BUFFER,
+            uniform_buffers: &ubo.data,
+            range: ubo.range,
+            storage: None
+        };
+        Descriptor::build(core, &buffers, sampler, texture, layout, max_frames)
+    }
+
+    // layout must come from create_skinned_descriptor_set_layout
+    pub fn new_skinned(core: &VkCore, ubo: &UniformBuffer, sampler: vk::Sampler, texture: &Texture,
+                       bones: &BoneBuffer, layout: vk::DescriptorSetLayout, max_frames: usize) -> Descriptor {
+        let buffers = DescriptorBuffers {
+            uniform_type: vk::DescriptorType::UNIFORM_BUFFER,
+            uniform_buffers: &ubo.data,
+            range: ubo.range,
+            storage: Some((&bones.data, bones.range))
+        };
+        Descriptor::build(core, &buffers, sampler, texture, layout, max_frames)
+    }
+
+    // layout must come from create_dynamic_descriptor_set_layout. The sets are bound with the offset of the block
+    // DynamicUniformBuffer::push returned, so one set per frame serves any number of views.
+    pub fn new_dynamic(core: &VkCore, uniforms: &DynamicUniformBuffer, sampler: vk::Sampler, texture: &Texture,
+                       layout: vk::DescriptorSetLayout, max_frames: usize) -> Descriptor {
+        let buffers = DescriptorBuffers {
+            uniform_type: vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            uniform_buffers: &vec![uniforms.buffer(); max_frames],
+            range: uniforms.block_size(),
+            storage: None
+        };
+        Descriptor::build(core, &buffers, sampler, texture, layout, max_frames)
+    }
+
+    // layout must come from create_object_descriptor_set_layout. Like new_dynamic, uniforms holds the views'
+    // transforms and each frame's set points at the frame's buffer of transforms.
+    pub fn new_objects(core: &VkCore, uniforms: &DynamicUniformBuffer, transforms: &ObjectTransforms,
+                       sampler: vk::Sampler, texture: &Texture, layout: vk::DescriptorSetLayout, max_frames: usize)
+        -> Descriptor {
+        let buffers = DescriptorBuffers {
+            uniform_type: vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            uniform_buffers: &vec![uniforms.buffer(); max_frames],
+            range: uniforms.block_size(),
+            storage: Some((&transforms.data, transforms.range))
+        };
+        Descriptor::build(core, &buffers, sampler, texture, layout, max_frames)
+    }
+
+    // A single set with the block instances of a voxel chunk at binding 2, which don't change between frames
+    pub fn new_instances(core: &VkCore, uniforms: &DynamicUniformBuffer, instances: vk::Buffer, range: vk::DeviceSize,
+                         sampler: vk::Sampler, texture: &Texture, layout: vk::DescriptorSetLayout) -> Descriptor {
+        let buffers = DescriptorBuffers {
+            uniform_type: vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC,
+            uniform_buffers: &[uniforms.buffer()],
+            range: uniforms.block_size(),
+            storage: Some((&[instances], range))
+        };
+        Descriptor::build(core, &buffers, sampler, texture, layout, 1)
+    }
+
+    fn build(core: &VkCore, buffers: &DescriptorBuffers, sampler: vk::Sampler, texture: &Texture,
+             layout: vk::DescriptorSetLayout, max_frames: usize) -> Descriptor {
+        let DescriptorBuffers { uniform_type, uniform_buffers, range, storage } = *buffers;
         // Build descriptor pool
         let transform_pool_size = vk::DescriptorPoolSize::default()
             .descriptor_count(max_frames as u32)
-            .ty(vk::DescriptorType::UNIFORM_BUFFER);
+            .ty(uniform_type);
         let texture_sampler_pool_size = vk::DescriptorPoolSize::default()
             .descriptor_count(max_frames as u32)
             .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER);
 
-        let pool_size = [transform_pool_size, texture_sampler_pool_size];
+        let storage_pool_size = vk::DescriptorPoolSize::default()
+            .descriptor_count(max_frames as u32)
+            .ty(vk::DescriptorType::STORAGE_BUFFER);
+
+        let pool_size = [transform_pool_size, texture_sampler_pool_size, storage_pool_size];
+        let pool_size_count = if storage.is_some() { 3 } else { 2 };
         let pool_create_info = vk::DescriptorPoolCreateInfo::default()
             .max_sets(max_frames as u32)
-            .pool_sizes(&pool_size);
+            .pool_sizes(&pool_size[..pool_size_count]);
         let pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
 
         let mut layout_vec: Vec<vk::DescriptorSetLayout> = Vec::new();
@@ -62,15 +170,15 @@ impl Descriptor {
             .set_layouts(layout_vec.as_slice());
         let sets: Vec<vk::DescriptorSet> = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
 
-        for (set, buffer) in sets.iter().zip(ubo.data.iter()) {
+        for (frame, (set, buffer)) in sets.iter().zip(uniform_buffers.iter()).enumerate() {
             let transform_buffer_info = vk::DescriptorBufferInfo::default()
                 .offset(0) // The Src buffer index to update from
                 .buffer(*buffer) // The Src buffer to update the descriptor set from
-                .range(std::mem::size_of::<UniformBufferObject>() as vk::DeviceSize);
+                .range(range);
             let buffer_info = [transform_buffer_info]; // Can also use VK_WHOLE_SIZE if updating the entire range
             let transform_desc_write = vk::WriteDescriptorSet::default() // The target descriptor set to update
                 .buffer_info(&buffer_info)
-                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_type(uniform_type)
                 .dst_array_element(0) // The descriptor set can describe an array of elements
                 .dst_binding(0) // The location in the target buffer to update
                 .dst_set(*set);
@@ -92,6 +200,23 @@ impl Descriptor {
             unsafe {
                 core.logical_device.update_descriptor_sets(&descriptor_write, &[]);
             }
+
+            if let Some((buffers, storage_range)) = storage {
+                let storage_info = [vk::DescriptorBufferInfo::default()
+                    .offset(0)
+                    .buffer(buffers[frame])
+                    .range(storage_range)];
+                let storage_write = [vk::WriteDescriptorSet::default()
+                    .buffer_info(&storage_info)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .dst_array_element(0)
+                    .dst_binding(2)
+                    .dst_set(*set)];
+
+                unsafe {
+                    core.logical_device.update_descriptor_sets(&storage_write, &[]);
+                }
+            }
         }
 
         Descriptor {
@@ -101,6 +226,25 @@ impl Descriptor {
         }
     }
 
+    // Points a frame's texture binding at a new sampler or texture, for example after the SamplerCache settings
+    // change. The frame's set must not be in use by a pending command buffer.
+    pub fn write_texture(&self, core: &VkCore, frame: usize, sampler: vk::Sampler, texture: &Texture) {
+        let image_info_array = [vk::DescriptorImageInfo::default()
+            .sampler(sampler)
+            .image_view(texture.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let image_info_write = [vk::WriteDescriptorSet::default()
+            .dst_set(self.sets[frame])
+            .dst_binding(1)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&image_info_array)];
+
+        unsafe {
+            core.logical_device.update_descriptor_sets(&image_info_write, &[]);
+        }
+    }
+
     pub fn destroy(&self, core: &VkCore) {
         unsafe {
             core.logical_device.destroy_descriptor_pool(self.pool, None);
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use tracing::debug;
+
+use crate::vkcore::VkCore;
+
+const INITIAL_SETS_PER_POOL: u32 = 16;
+const MAX_SETS_PER_POOL: u32 = 1024;
+
+#[derive(Default)]
+struct FramePools {
+    ready: Vec<vk::DescriptorPool>, // The last one is allocated from
+    full: Vec<vk::DescriptorPool>
+}
+
+// Hands out descriptor sets that live for a single frame in flight. Every frame has its own pools, which are reset
+// as a whole once the frame's fence has signaled. A new pool, larger than the last one, is created whenever the
+// current one runs out, so sets can be allocated without knowing the number in advance.
+pub struct DescriptorAllocator {
+    frames: Vec<FramePools>,
+    // Descriptors of each type per set, scaled by the number of sets in a pool. A set using more of a type than
+    // this would never fit into a pool.
+    ratios: Vec<vk::DescriptorPoolSize>,
+    sets_per_pool: u32
+}
+
+impl DescriptorAllocator {
+    pub fn new(max_frames: usize, ratios: &[vk::DescriptorPoolSize]) -> DescriptorAllocator {
+        DescriptorAllocator {
+            frames: (0..max_frames).map(|_| FramePools::default()).collect(),
+            ratios: Vec::from(ratios),
+            sets_per_pool: INITIAL_SETS_PER_POOL
+        }
+    }
+
+    fn create_pool(&mut self, core: &VkCore) -> vk::DescriptorPool {
+        let sets = self.sets_per_pool;
+        self.sets_per_pool = (sets * 2).min(MAX_SETS_PER_POOL);
+        let pool_sizes: Vec<vk::DescriptorPoolSize> = self.ratios.iter()
+            .map(|r| vk::DescriptorPoolSize::default()
+                .ty(r.ty)
+                .descriptor_count(r.descriptor_count * sets))
+            .collect();
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(sets)
+            .pool_sizes(pool_sizes.as_slice());
+        debug!(sets, "Creating descriptor pool");
+
+        unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() }
+    }
+
+    // The set stays valid until reset_frame is called for the same frame
+    pub fn allocate(&mut self, core: &VkCore, frame: usize, layout: vk::DescriptorSetLayout) -> vk::DescriptorSet {
+        let layouts = [layout];
+        let mut new_pool = false;
+        loop {
+            let pool = match self.frames[frame].ready.last() {
+                Some(pool) => *pool,
+                None => {
+                    let pool = self.create_pool(core);
+                    self.frames[frame].ready.push(pool);
+                    new_pool = true;
+                    pool
+                }
+            };
+            let allocate_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(&layouts);
+            match unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info) } {
+                Ok(sets) => return sets[0],
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) if !new_pool => {
+                    let full = self.frames[frame].ready.pop().unwrap();
+                    self.frames[frame].full.push(full);
+                }
+                Err(e) => panic!("Descriptor set allocation failed: {:?}, check the allocator's ratios", e)
+            }
+        }
+    }
+
+    // Call once the frame's fence has signaled, all sets allocated for the frame become invalid
+    pub fn reset_frame(&mut self, core: &VkCore, frame: usize) {
+        let pools = &mut self.frames[frame];
+        let full = std::mem::take(&mut pools.full);
+        pools.ready.extend(full);
+        for pool in pools.ready.iter() {
+            unsafe {
+                core.logical_device.reset_descriptor_pool(*pool, vk::DescriptorPoolResetFlags::empty()).unwrap();
+            }
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for pool in self.frames.iter().flat_map(|f| f.ready.iter().chain(f.full.iter())) {
+            unsafe { core.logical_device.destroy_descriptor_pool(*pool, None) };
+        }
+    }
+}
+
+// (binding, type, count, stages) of every binding, sorted by binding
+type LayoutKey = Vec<(u32, i32, u32, u32)>;
+
+// Returns the same DescriptorSetLayout for identical bindings, so that pipelines with matching sets share layouts.
+// Layouts are owned by the cache and destroyed with it.
+#[derive(Default)]
+pub struct LayoutCache {
+    layouts: HashMap<LayoutKey, vk::DescriptorSetLayout>
+}
+
+impl LayoutCache {
+    pub fn new() -> LayoutCache {
+        LayoutCache::default()
+    }
+
+    // Immutable samplers aren't supported, they would have to be part of the key
+    pub fn get(&mut self, core: &VkCore, bindings: &[vk::DescriptorSetLayoutBinding]) -> vk::DescriptorSetLayout {
+        assert!(bindings.iter().all(|b| b.p_immutable_samplers.is_null()),
+                "LayoutCache doesn't support immutable samplers");
+        let mut key: LayoutKey = bindings.iter()
+            .map(|b| (b.binding, b.descriptor_type.as_raw(), b.descriptor_count, b.stage_flags.as_raw()))
+            .collect();
+        key.sort();
+
+        *self.layouts.entry(key).or_insert_with(|| {
+            let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(bindings)
+                .flags(vk::DescriptorSetLayoutCreateFlags::empty());
+            unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.layouts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layouts.is_empty()
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for layout in self.layouts.values() {
+            unsafe { core.logical_device.destroy_descriptor_set_layout(*layout, None) };
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use ash::extensions::khr::Swapchain;
+use ash::{Device, Instance, vk};
+use tracing::{info, warn};
+
+// The physical devices of the group physical_device belongs to, if it has more than one. VkCore creates its logical
+// device over all of them.
+pub(crate) fn find_group(instance: &Instance, physical_device: vk::PhysicalDevice) -> Option<Vec<vk::PhysicalDevice>> {
+    let groups = unsafe {
+        let mut groups = vec![vk::PhysicalDeviceGroupProperties::default();
+                              instance.enumerate_physical_device_groups_len().unwrap()];
+        instance.enumerate_physical_device_groups(groups.as_mut_slice()).unwrap();
+        groups
+    };
+
+    groups.iter()
+        .map(|g| g.physical_devices[..g.physical_device_count as usize].to_vec())
+        .find(|devices| devices.len() > 1 && devices.contains(&physical_device))
+}
+
+// Experimental alternate frame rendering over linked GPUs. The logical device spans every physical device of the
+// group, memory of multi-instance heaps has a copy on each and command buffers without a device mask run on all of
+// them, so uploads reach every GPU. Frames are then recorded and submitted for one GPU at a time, in turn, and
+// presented from that GPU's instance of the swap chain image:
+// - LOCAL_MULTI_DEVICE if every GPU has a presentation engine
+// - REMOTE where the ones with a display read the others' images through peer memory
+// Whatever a frame reads from the frames before, like temporal history, is on another GPU and may be stale.
+pub struct DeviceGroup {
+    pub physical_devices: Vec<vk::PhysicalDevice>,
+    // How swap chain images are presented, None if the surface supports neither mode above and every frame is
+    // rendered by the first GPU
+    pub present_mode: Option<vk::DeviceGroupPresentModeFlagsKHR>
+}
+
+impl DeviceGroup {
+    pub(crate) fn new(instance: &Instance, device: &Device, surface: vk::SurfaceKHR,
+                      physical_devices: Vec<vk::PhysicalDevice>) -> DeviceGroup {
+        let loader = Swapchain::new(instance, device);
+        let surface_modes = unsafe { loader.get_device_group_surface_present_modes(surface).unwrap() };
+        let present_mode = [vk::DeviceGroupPresentModeFlagsKHR::LOCAL_MULTI_DEVICE,
+            vk::DeviceGroupPresentModeFlagsKHR::REMOTE].into_iter()
+            .find(|&m| surface_modes.contains(m));
+        match present_mode {
+            Some(mode) => info!(devices = physical_devices.len(), ?mode, "Alternate frame rendering"),
+            None => warn!(?surface_modes, "The device group can't present from every GPU, frames are rendered by the \
+                                           first one")
+        }
+
+        DeviceGroup {
+            physical_devices,
+            present_mode
+        }
+    }
+
+    // The GPU that renders the frame with this index, on the timeline of submitted frames
+    pub fn frame_device(&self, frame_index: u64) -> u32 {
+        match self.present_mode {
+            Some(_) => (frame_index % self.physical_devices.len() as u64) as u32,
+            None => 0
+        }
+    }
+
+    pub fn frame_device_mask(&self, frame_index: u64) -> u32 {
+        1 << self.frame_device(frame_index)
+    }
+
+    // Chained to the frame's vk::CommandBufferBeginInfo, so that it only runs on the frame's GPU
+    pub fn command_buffer_begin_info(&self, frame_index: u64) -> vk::DeviceGroupCommandBufferBeginInfo<'static> {
+        vk::DeviceGroupCommandBufferBeginInfo::default()
+            .device_mask(self.frame_device_mask(frame_index))
+    }
+
+    // Acquires the next image for the frame's GPU, like Swapchain::acquire_next_image
+    pub fn acquire_next_image(&self, loader: &Swapchain, swap_chain: vk::SwapchainKHR, semaphore: vk::Semaphore,
+                              frame_index: u64) -> ash::prelude::VkResult<(u32, bool)> {
+        let acquire_info = vk::AcquireNextImageInfoKHR::default()
+            .swapchain(swap_chain)
+            .timeout(u64::MAX)
+            .semaphore(semaphore)
+            .device_mask(self.frame_device_mask(frame_index));
+        unsafe { loader.acquire_next_image2(&acquire_info) }
+    }
+
+    // Chained to the frame's vk::SubmitInfo, which must have wait_count semaphores, one command buffer and
+    // signal_count semaphores. Everything waits and signals on the frame's GPU.
+    pub fn submit(&self, frame_index: u64, wait_count: usize, signal_count: usize) -> DeviceGroupSubmit {
+        let device = self.frame_device(frame_index);
+
+        DeviceGroupSubmit {
+            wait_indices: vec![device; wait_count],
+            command_buffer_masks: [1 << device],
+            signal_indices: vec![device; signal_count]
+        }
+    }
+
+    // Chained to vk::SwapchainCreateInfoKHR
+    pub fn swapchain_create_info(&self) -> vk::DeviceGroupSwapchainCreateInfoKHR<'static> {
+        vk::DeviceGroupSwapchainCreateInfoKHR::default()
+            .modes(self.present_mode.unwrap_or(vk::DeviceGroupPresentModeFlagsKHR::LOCAL))
+    }
+
+    // How the GPU local_device can use memory of heap_index on remote_device, GENERIC_SRC is needed for REMOTE
+    // presentation
+    pub fn peer_memory_features(&self, device: &Device, heap_index: u32, local_device: u32, remote_device: u32)
+        -> vk::PeerMemoryFeatureFlags {
+        unsafe { device.get_device_group_peer_memory_features(heap_index, local_device, remote_device) }
+    }
+
+    // Chained to vk::MemoryAllocateInfo for memory that has an instance on every GPU, which the others can reach
+    // as peer memory
+    pub fn memory_allocate_flags_info(&self) -> vk::MemoryAllocateFlagsInfo<'static> {
+        vk::MemoryAllocateFlagsInfo::default()
+            .flags(vk::MemoryAllocateFlags::DEVICE_MASK)
+            .device_mask((1 << self.physical_devices.len()) - 1)
+    }
+}
+
+// The device indices of a frame's submission, see DeviceGroup::submit
+pub struct DeviceGroupSubmit {
+    wait_indices: Vec<u32>,
+    command_buffer_masks: [u32; 1],
+    signal_indices: Vec<u32>
+}
+
+impl DeviceGroupSubmit {
+    pub fn info(&self) -> vk::DeviceGroupSubmitInfo<'_> {
+        vk::DeviceGroupSubmitInfo::default()
+            .wait_semaphore_device_indices(self.wait_indices.as_slice())
+            .command_buffer_device_masks(&self.command_buffer_masks)
+            .signal_semaphore_device_indices(self.signal_indices.as_slice())
+    }
+
+    // Chained to vk::PresentInfoKHR with the frame's swap chain
+    pub fn present_info(&self, mode: vk::DeviceGroupPresentModeFlagsKHR) -> vk::DeviceGroupPresentInfoKHR<'_> {
+        vk::DeviceGroupPresentInfoKHR::default()
+            .device_masks(&self.command_buffer_masks)
+            .mode(mode)
+    }
+}
@@ -0,0 +1,89 @@
+use ash::prelude::VkResult;
+use ash::vk;
+use tracing::{error, info};
+
+use crate::vkcore::VkCore;
+
+// Device losses in a row, without a frame presented in between, before the renderer gives up
+pub const MAX_RECOVERY_ATTEMPTS: u32 = 3;
+
+// Called after the renderer created its device and resources again, with the number of recoveries so far. Anything
+// the app created from the old device is gone and has to be created again from core.
+pub type DeviceLostCallback = Box<dyn FnMut(&VkCore, u32)>;
+
+// Finds VK_ERROR_DEVICE_LOST, after a GPU hang, a driver reset or a removed GPU, where the renderer would otherwise
+// panic. Renderers check their fence waits, submits and presents with it, skip the rest of the frame once the device
+// is lost, and tear down and create everything again before the next one.
+#[derive(Default)]
+pub struct DeviceLost {
+    lost: bool,
+    recoveries: u32,
+    attempts: u32, // Since the last presented frame
+    callbacks: Vec<DeviceLostCallback>
+}
+
+impl DeviceLost {
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    pub fn recoveries(&self) -> u32 {
+        self.recoveries
+    }
+
+    pub fn add_callback(&mut self, callback: DeviceLostCallback) {
+        self.callbacks.push(callback);
+    }
+
+    // Like unwrap, but returns None when the device is lost. call names the Vulkan call for the logs.
+    pub fn check<T>(&mut self, result: VkResult<T>, call: &str) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(vk::Result::ERROR_DEVICE_LOST) => {
+                self.mark_lost(call);
+                None
+            },
+            Err(e) => panic!("{} failed: {:?}", call, e)
+        }
+    }
+
+    // For results that have other errors to handle as well, like SwapchainRecreate::check_acquire. True when the
+    // device is lost, and the result shouldn't be looked at further.
+    pub fn detect<T>(&mut self, result: &VkResult<T>, call: &str) -> bool {
+        if let Err(vk::Result::ERROR_DEVICE_LOST) = result {
+            self.mark_lost(call);
+        }
+        self.lost
+    }
+
+    fn mark_lost(&mut self, call: &str) {
+        if !self.lost {
+            error!(call, recoveries = self.recoveries, "Device lost");
+        }
+        self.lost = true;
+    }
+
+    // Before the renderer tears down. Panics when recovering keeps failing, a device that is lost again before it
+    // presents anything won't come back.
+    pub fn begin_recovery(&mut self) {
+        self.attempts += 1;
+        if self.attempts > MAX_RECOVERY_ATTEMPTS {
+            panic!("The device was lost {} times in a row, giving up", MAX_RECOVERY_ATTEMPTS);
+        }
+    }
+
+    // Once everything has been created again on core's new device
+    pub fn finish(&mut self, core: &VkCore) {
+        self.lost = false;
+        self.recoveries += 1;
+        info!(recoveries = self.recoveries, "Recovered from device loss");
+        for callback in self.callbacks.iter_mut() {
+            callback(core, self.recoveries);
+        }
+    }
+
+    // After every present that went through
+    pub fn frame_presented(&mut self) {
+        self.attempts = 0;
+    }
+}
@@ -0,0 +1,50 @@
+use clap::ValueEnum;
+use tracing::info;
+use winit::window::Fullscreen;
+
+use crate::vkcore::VkCore;
+
+// How the window covers its monitor. Exclusive is borderless fullscreen where the swap chain additionally takes the
+// display over with VK_EXT_full_screen_exclusive, which skips the compositor for lower latency. The extension is
+// Windows only, Exclusive falls back to Borderless everywhere else.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DisplayMode {
+    #[default]
+    Windowed,
+    Borderless,
+    Exclusive
+}
+
+impl DisplayMode {
+    // The mode that will actually be used on this device
+    pub fn supported(self, core: &VkCore) -> DisplayMode {
+        match self {
+            DisplayMode::Exclusive if !core.full_screen_exclusive_supported => DisplayMode::Borderless,
+            mode => mode
+        }
+    }
+
+    // Resizes the window for the mode and returns the mode used. The swap chain has to be recreated afterwards,
+    // RenderTarget acquires exclusive mode when it is created.
+    pub fn apply(self, core: &VkCore) -> DisplayMode {
+        let mode = self.supported(core);
+        if mode != self {
+            info!(requested = ?self, used = ?mode, "Display mode not supported, falling back");
+        }
+        let fullscreen = match mode {
+            DisplayMode::Windowed => None,
+            DisplayMode::Borderless | DisplayMode::Exclusive =>
+                Some(Fullscreen::Borderless(core.window.current_monitor()))
+        };
+        core.window.set_fullscreen(fullscreen);
+
+        mode
+    }
+}
+
+// Application controlled exclusive mode needs the monitor the window is on
+#[cfg(windows)]
+pub(crate) fn current_hmonitor(core: &VkCore) -> ash::vk::HMONITOR {
+    use winit::platform::windows::MonitorHandleExtWindows;
+    core.window.current_monitor().map_or(std::ptr::null_mut(), |m| m.hmonitor() as ash::vk::HMONITOR)
+}
@@ -0,0 +1,202 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+use ash::extensions::google;
+use ash::vk;
+use raw_window_handle::{HasRawWindowHandle, RawWindowHandle};
+use tracing::{debug, info};
+use winit::window::Window;
+
+use crate::profiler::{PresentStatistics, PresentTiming};
+use crate::render_target::RenderTarget;
+use crate::vkcore::VkCore;
+
+// Presents kept for status, about 2 seconds at 60 Hz
+const PRESENT_HISTORY: usize = 120;
+// Presents the display hasn't reported yet are forgotten after this many, they were dropped or the report was lost
+const MAX_PENDING_PRESENTS: usize = 16;
+
+// The window system the surface belongs to. Their presentation engines differ in which present modes they support
+// and how FIFO behaves, see present_mode_fallbacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowSystem {
+    Wayland,
+    X11, // Xlib or XCB
+    Windows,
+    MacOs,
+    Other
+}
+
+impl WindowSystem {
+    pub fn detect(window: &Window) -> WindowSystem {
+        match window.raw_window_handle() {
+            RawWindowHandle::Wayland(_) => WindowSystem::Wayland,
+            RawWindowHandle::Xlib(_) | RawWindowHandle::Xcb(_) => WindowSystem::X11,
+            RawWindowHandle::Win32(_) => WindowSystem::Windows,
+            RawWindowHandle::AppKit(_) => WindowSystem::MacOs,
+            _ => WindowSystem::Other
+        }
+    }
+
+    // The present modes to try for preferred, in order. FIFO is always supported so it comes last.
+    // - Wayland compositors never tear, IMMEDIATE is mostly missing and MAILBOX is the closest without vsync. FIFO
+    //   blocks while the window is hidden, MAILBOX doesn't, so it stays preferred with vsync too.
+    // - Without a compositing manager X11 often lacks MAILBOX, FIFO_RELAXED then only tears when a frame is late.
+    pub fn present_mode_fallbacks(&self, preferred: vk::PresentModeKHR) -> Vec<vk::PresentModeKHR> {
+        let fallbacks: &[vk::PresentModeKHR] = match (self, preferred) {
+            (_, vk::PresentModeKHR::IMMEDIATE) => &[vk::PresentModeKHR::MAILBOX],
+            (WindowSystem::X11, vk::PresentModeKHR::MAILBOX) => &[vk::PresentModeKHR::FIFO_RELAXED],
+            _ => &[]
+        };
+
+        [preferred].into_iter().chain(fallbacks.iter().copied()).chain([vk::PresentModeKHR::FIFO]).collect()
+    }
+}
+
+// CLOCK_MONOTONIC, which Mesa's X11 and Wayland presentation engines report display timing in
+#[cfg(unix)]
+fn monotonic_ns() -> Option<u64> {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    match unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) } {
+        0 => Some(time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64),
+        _ => None
+    }
+}
+
+#[cfg(not(unix))]
+fn monotonic_ns() -> Option<u64> {
+    None
+}
+
+// When presented frames actually reached the display, through VK_GOOGLE_display_timing. Every present gets an id and,
+// with pacing, a desired present time a whole number of refresh cycles after the last one the display reported, so
+// that frames stay aligned to the refresh rate even when rendering is faster. The reports arrive a few frames late
+// and become PresentTimings.
+pub struct DisplayTiming {
+    loader: google::DisplayTiming,
+    refresh_ns: u64, // Of the swap chain's display, 0 until reset
+    pacing: u32, // Refresh cycles per frame, 0 to present as soon as possible
+    next_present_id: u32,
+    pending: HashMap<u32, Option<u64>>, // By present id, CLOCK_MONOTONIC at queue_present
+    last_shown: Option<(u32, u64)>, // Present id and actual present time of the latest report
+    collected: Vec<PresentTiming>, // Since the last take_collected
+    history: VecDeque<PresentTiming>
+}
+
+impl DisplayTiming {
+    // None if VK_GOOGLE_display_timing isn't supported
+    pub fn new(core: &VkCore, pacing: u32) -> Option<DisplayTiming> {
+        if !core.display_timing_supported {
+            return None;
+        }
+
+        Some(DisplayTiming {
+            loader: google::DisplayTiming::new(&core.instance, &core.logical_device),
+            refresh_ns: 0,
+            pacing,
+            next_present_id: 1,
+            pending: HashMap::new(),
+            last_shown: None,
+            collected: Vec::new(),
+            history: VecDeque::with_capacity(PRESENT_HISTORY)
+        })
+    }
+
+    // Timing is per swap chain, call after every recreate
+    pub fn reset(&mut self, render_target: &RenderTarget) {
+        self.refresh_ns = unsafe { self.loader.get_refresh_cycle_duration(render_target.swap_chain) }
+            .map(|r| r.refresh_duration)
+            .unwrap_or(0);
+        self.pending.clear();
+        self.last_shown = None;
+        debug!(refresh_hz = self.refresh_rate_hz(), "Display timing");
+    }
+
+    pub fn refresh_rate_hz(&self) -> Option<f64> {
+        match self.refresh_ns {
+            0 => None,
+            ns => Some(1_000_000_000.0 / ns as f64)
+        }
+    }
+
+    pub fn pacing(&self) -> u32 {
+        self.pacing
+    }
+
+    pub fn set_pacing(&mut self, pacing: u32) {
+        if pacing != self.pacing {
+            info!(pacing, refresh_hz = self.refresh_rate_hz(), "Frame pacing changed");
+        }
+        self.pacing = pacing;
+    }
+
+    // To chain into the next present with vk::PresentTimesInfoGOOGLE
+    pub fn next_present_time(&mut self) -> vk::PresentTimeGOOGLE {
+        let present_id = self.next_present_id;
+        self.next_present_id = self.next_present_id.wrapping_add(1).max(1);
+        if self.pending.len() >= MAX_PENDING_PRESENTS {
+            self.pending.clear();
+        }
+        self.pending.insert(present_id, monotonic_ns());
+
+        // Half a cycle early, so that the frame lands on the cycle rather than the one after it
+        let desired_present_time = match (self.pacing, self.last_shown) {
+            (0, _) | (_, None) => 0,
+            (pacing, Some((shown_id, shown_time))) => {
+                let frames = present_id.wrapping_sub(shown_id) as u64;
+                shown_time + frames * pacing as u64 * self.refresh_ns - self.refresh_ns / 2
+            }
+        };
+
+        vk::PresentTimeGOOGLE {
+            present_id,
+            desired_present_time
+        }
+    }
+
+    // Reads the reports that arrived since the last call, once a frame after presenting
+    pub fn collect(&mut self, render_target: &RenderTarget) {
+        let past = match unsafe { self.loader.get_past_presentation_timing(render_target.swap_chain) } {
+            Ok(past) => past,
+            Err(_) => return // Out of date, SwapchainRecreate finds out at the next acquire
+        };
+        for timing in past {
+            let queued_ns = self.pending.remove(&timing.present_id).flatten();
+            let present = PresentTiming {
+                present_id: timing.present_id,
+                latency_ms: queued_ns.map(|ns| timing.actual_present_time.saturating_sub(ns) as f64 / 1_000_000.0),
+                margin_ms: timing.present_margin as f64 / 1_000_000.0,
+                missed: timing.desired_present_time > 0 &&
+                    timing.actual_present_time > timing.desired_present_time + self.refresh_ns
+            };
+            self.last_shown = Some((timing.present_id, timing.actual_present_time));
+            self.collected.push(present);
+            if self.history.len() == PRESENT_HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back(present);
+        }
+    }
+
+    // The presents collected since the last call, for FrameStats::presents
+    pub fn take_collected(&mut self) -> Vec<PresentTiming> {
+        mem::take(&mut self.collected)
+    }
+
+    pub fn statistics(&self) -> PresentStatistics {
+        let presents: Vec<PresentTiming> = self.history.iter().copied().collect();
+        PresentStatistics::new(presents.as_slice())
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> String {
+        let stats = self.statistics();
+        let refresh = self.refresh_rate_hz().map_or(String::from("-"), |hz| format!("{:.0}", hz));
+        let latency = stats.mean_latency_ms.map_or(String::from("-"), |ms| format!("{:.1}", ms));
+        let pacing = match self.pacing {
+            0 => String::from("unpaced"),
+            n => format!("every {} refresh", n)
+        };
+        format!("{} Hz {} | display latency {} ms, {} missed", refresh, pacing, latency, stats.missed)
+    }
+}
@@ -0,0 +1,61 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::compute::{ComputePipeline, group_count};
+use crate::post_process::{POST_GROUP_SIZE, PostFrame};
+use crate::renderutils::cast_to_u8_slice;
+use crate::vkcore::VkCore;
+
+const DOF_SHADER_PATH: &str = "graphics/shaders/spv/dof.spv";
+// Largest circle of confusion, the gather in dof.comp costs about its square
+pub const MAX_COC_RADIUS: f32 = 12.0;
+
+// Matches the push constants in dof.comp
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct DofConstants {
+    lens: [f32; 4] // Focus distance, aperture, pixels per unit at distance 1 and MAX_COC_RADIUS
+}
+
+// Thin lens depth of field, a PostProcessChain effect. The circle of confusion of each pixel follows from its view
+// depth and the camera's focus distance and aperture. Each pixel then gathers the neighbours whose circle covers
+// it, along a golden angle spiral.
+pub struct DepthOfField {
+    pipeline: ComputePipeline
+}
+
+impl DepthOfField {
+    // set_layout is the PostProcessChain's
+    pub(crate) fn new(core: &VkCore, set_layout: vk::DescriptorSetLayout) -> DepthOfField {
+        DepthOfField {
+            pipeline: ComputePipeline::new(core, DOF_SHADER_PATH, &[set_layout],
+                                           mem::size_of::<DofConstants>() as u32)
+        }
+    }
+
+    // A pinhole camera has everything in focus
+    pub fn is_enabled(frame: &PostFrame) -> bool {
+        frame.aperture > 0.0 && frame.focus_distance > 0.0
+    }
+
+    pub(crate) fn cmd_apply(&self, core: &VkCore, command_buffer: vk::CommandBuffer, set: vk::DescriptorSet,
+                            extent: vk::Extent2D, frame: &PostFrame) {
+        // proj[1][1] is the cotangent of half the vertical field of view
+        let pixel_scale = frame.projection.y.y.abs() * extent.height as f32 * 0.5;
+        let constants = [DofConstants {
+            lens: [frame.focus_distance, frame.aperture, pixel_scale, MAX_COC_RADIUS]
+        }];
+        unsafe {
+            self.pipeline.cmd_bind(core, command_buffer, set);
+            core.logical_device.cmd_push_constants(command_buffer, self.pipeline.layout,
+                                                   vk::ShaderStageFlags::COMPUTE, 0, cast_to_u8_slice(&constants));
+            core.logical_device.cmd_dispatch(command_buffer, group_count(extent.width, POST_GROUP_SIZE),
+                                             group_count(extent.height, POST_GROUP_SIZE), 1);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+    }
+}
@@ -0,0 +1,79 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::vkcore::VkCore;
+
+// Per-object uniform blocks packed into one buffer, bound through a UNIFORM_BUFFER_DYNAMIC descriptor with the block's
+// offset as dynamic offset in cmd_bind_descriptor_sets. Saves a buffer and descriptor set per object and frame. Every
+// frame in flight fills its own region, so the CPU writes one frame's blocks while the GPU reads the other's. Blocks
+// start at multiples of minUniformBufferOffsetAlignment.
+pub struct DynamicUniformBuffer {
+    buffer: GpuBuffer,
+    mapped: *mut u8,
+    block_size: vk::DeviceSize, // Range of the descriptors, the largest block that fits
+    stride: vk::DeviceSize, // block_size rounded up to the alignment
+    blocks_per_frame: usize,
+    used: Vec<usize> // Blocks pushed since each frame's reset
+}
+
+impl DynamicUniformBuffer {
+    pub fn new(core: &VkCore, block_size: vk::DeviceSize, blocks_per_frame: usize, max_frames: usize)
+        -> DynamicUniformBuffer {
+        let properties = unsafe { core.instance.get_physical_device_properties(core.physical_device) };
+        let alignment = properties.limits.min_uniform_buffer_offset_alignment.max(1);
+        let stride = block_size.div_ceil(alignment) * alignment;
+        let size = stride * (blocks_per_frame * max_frames) as vk::DeviceSize;
+        let buffer = GpuBuffer::new_mapped(core, size, vk::BufferUsageFlags::UNIFORM_BUFFER);
+        let mapped = unsafe {
+            core.logical_device.map_memory(buffer.mem, 0, size, vk::MemoryMapFlags::empty()).unwrap() as *mut u8
+        };
+
+        DynamicUniformBuffer {
+            buffer,
+            mapped,
+            block_size,
+            stride,
+            blocks_per_frame,
+            used: vec![0; max_frames]
+        }
+    }
+
+    // For descriptor writes, every set can point at the whole buffer since the dynamic offset picks the block
+    pub fn buffer(&self) -> vk::Buffer {
+        self.buffer.buf
+    }
+
+    pub fn block_size(&self) -> vk::DeviceSize {
+        self.block_size
+    }
+
+    pub fn blocks_per_frame(&self) -> usize {
+        self.blocks_per_frame
+    }
+
+    // Starts filling the frame's region again once its fence has been waited on, before its first push
+    pub fn reset(&mut self, current_frame: usize) {
+        self.used[current_frame] = 0;
+    }
+
+    // Copies block into the frame's region, returns the dynamic offset to bind it with. Errors once the region is full.
+    pub fn push<T: Copy>(&mut self, current_frame: usize, block: &T) -> Result<u32, String> {
+        assert!(mem::size_of::<T>() as vk::DeviceSize <= self.block_size);
+        let index = self.used[current_frame];
+        if index == self.blocks_per_frame {
+            return Err(format!("All {} uniform blocks of the frame are used", self.blocks_per_frame));
+        }
+        self.used[current_frame] += 1;
+        let offset = self.stride * (current_frame * self.blocks_per_frame + index) as vk::DeviceSize;
+        unsafe { (self.mapped.add(offset as usize) as *mut T).write_unaligned(*block) };
+
+        Ok(offset as u32)
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe { core.logical_device.unmap_memory(self.buffer.mem) };
+        self.buffer.destroy(core);
+    }
+}
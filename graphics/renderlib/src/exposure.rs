@@ -0,0 +1,181 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+
+use crate::compute::{ComputePipeline, group_count};
+use crate::gpu_buffer::GpuBuffer;
+use crate::post_process::POST_GROUP_SIZE;
+use crate::renderutils::cast_to_u8_slice;
+use crate::vkcore::VkCore;
+
+const HISTOGRAM_SHADER_PATH: &str = "graphics/shaders/spv/luminance_histogram.spv";
+const ADAPT_SHADER_PATH: &str = "graphics/shaders/spv/exposure_adapt.spv";
+pub const HISTOGRAM_BINS: usize = 256; // Matches both shaders
+// Range of the histogram in log2 scene luminance, darker pixels land in bin 0 and are left out of the average
+pub const MIN_LOG_LUMINANCE: f32 = -10.0;
+pub const LOG_LUMINANCE_RANGE: f32 = 16.0;
+
+// How the exposure follows the scene. Adapting to a darker scene takes longer than to a brighter one, like eyes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoExposureSettings {
+    pub key: f32, // Scene luminance the average is mapped to, 0.18 is middle grey
+    pub speed_up: f32, // Per second, while the exposure increases
+    pub speed_down: f32, // Per second, while the exposure decreases
+    pub min_exposure: f32,
+    pub max_exposure: f32
+}
+
+impl Default for AutoExposureSettings {
+    fn default() -> AutoExposureSettings {
+        AutoExposureSettings {
+            key: 0.18,
+            speed_up: 1.0,
+            speed_down: 3.0,
+            min_exposure: 1.0 / 64.0,
+            max_exposure: 64.0
+        }
+    }
+}
+
+// What AutoExposure needs to know about the frame being metered
+#[derive(Clone, Copy, Debug)]
+pub struct ExposureMetering {
+    pub settings: AutoExposureSettings,
+    pub exposure: f32, // The frame was rendered with, see ColorConstants
+    pub gamma: f32,
+    pub delta_time: f32 // Seconds since the frame before
+}
+
+// Matches the push constants in luminance_histogram.comp
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct HistogramConstants {
+    range: [f32; 4] // Min log2 luminance, log2 luminance range, exposure and gamma of the frame
+}
+
+// Matches the push constants in exposure_adapt.comp
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct AdaptConstants {
+    range: [f32; 4], // Min log2 luminance, log2 luminance range, delta time and key
+    speed: [f32; 4] // speed_up, speed_down, min_exposure and max_exposure
+}
+
+// Eye adaptation, metered by the PostProcessChain. A histogram of the frame's log luminance, divided by the exposure
+// it was rendered with, gives the average scene luminance. The adapted exposure moves towards the one that maps that
+// average to the key, exponentially over time, and is copied to a host visible buffer per frame in flight. Renderers
+// read it with adapted_exposure and render the following frames with it, so it lags a frame or two behind.
+pub struct AutoExposure {
+    histogram_pipeline: ComputePipeline,
+    adapt_pipeline: ComputePipeline,
+    histogram: GpuBuffer, // Cleared by the adapt pass once read
+    state: GpuBuffer, // The adapted exposure
+    readback: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>
+}
+
+impl AutoExposure {
+    // set_layout is the PostProcessChain's metering layout: the color image, the histogram and the state
+    pub(crate) fn new(core: &VkCore, command_pool: vk::CommandPool, set_layout: vk::DescriptorSetLayout,
+                      max_frames: usize) -> AutoExposure {
+        let histogram = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                   &[0u32; HISTOGRAM_BINS], vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let state = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER |
+                                                   vk::BufferUsageFlags::TRANSFER_SRC,
+                                               &[1.0f32], vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let mut readback: Vec<GpuBuffer> = Vec::with_capacity(max_frames);
+        let mut mapped: Vec<*mut c_void> = Vec::with_capacity(max_frames);
+        for _ in 0..max_frames {
+            let buffer = GpuBuffer::new(core, mem::size_of::<f32>() as vk::DeviceSize,
+                                        vk::BufferUsageFlags::TRANSFER_DST,
+                                        vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                            vk::MemoryPropertyFlags::HOST_COHERENT);
+            let pointer = unsafe {
+                core.logical_device.map_memory(buffer.mem, 0, buffer.size, vk::MemoryMapFlags::empty()).unwrap()
+            };
+            unsafe { (pointer as *mut f32).write(1.0) };
+            mapped.push(pointer);
+            readback.push(buffer);
+        }
+
+        AutoExposure {
+            histogram_pipeline: ComputePipeline::new(core, HISTOGRAM_SHADER_PATH, &[set_layout],
+                                                     mem::size_of::<HistogramConstants>() as u32),
+            adapt_pipeline: ComputePipeline::new(core, ADAPT_SHADER_PATH, &[set_layout],
+                                                 mem::size_of::<AdaptConstants>() as u32),
+            histogram,
+            state,
+            readback,
+            mapped
+        }
+    }
+
+    // Bind as bindings 1 and 2 of the metering set, both STORAGE_BUFFER
+    pub(crate) fn buffer_infos(&self) -> [[vk::DescriptorBufferInfo; 1]; 2] {
+        [&self.histogram, &self.state].map(|b| [vk::DescriptorBufferInfo::default()
+            .buffer(b.buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)])
+    }
+
+    // Of the last metered frame that used frame's buffer. Call once frame's fence has been waited on.
+    pub fn adapted_exposure(&self, frame: usize) -> f32 {
+        unsafe { (self.mapped[frame] as *const f32).read() }
+    }
+
+    pub(crate) fn cmd_meter(&self, core: &VkCore, command_buffer: vk::CommandBuffer, set: vk::DescriptorSet,
+                            extent: vk::Extent2D, metering: &ExposureMetering, frame: usize) {
+        let settings = metering.settings;
+        let histogram_constants = [HistogramConstants {
+            range: [MIN_LOG_LUMINANCE, LOG_LUMINANCE_RANGE, metering.exposure, metering.gamma]
+        }];
+        let adapt_constants = [AdaptConstants {
+            range: [MIN_LOG_LUMINANCE, LOG_LUMINANCE_RANGE, metering.delta_time, settings.key],
+            speed: [settings.speed_up, settings.speed_down, settings.min_exposure, settings.max_exposure]
+        }];
+        let histogram_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let state_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+        let copy_region = vk::BufferCopy::default()
+            .src_offset(0)
+            .dst_offset(0)
+            .size(mem::size_of::<f32>() as vk::DeviceSize);
+
+        unsafe {
+            self.histogram_pipeline.cmd_bind(core, command_buffer, set);
+            core.logical_device.cmd_push_constants(command_buffer, self.histogram_pipeline.layout,
+                                                   vk::ShaderStageFlags::COMPUTE, 0,
+                                                   cast_to_u8_slice(&histogram_constants));
+            core.logical_device.cmd_dispatch(command_buffer, group_count(extent.width, POST_GROUP_SIZE),
+                                             group_count(extent.height, POST_GROUP_SIZE), 1);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[histogram_barrier], &[], &[]);
+            self.adapt_pipeline.cmd_bind(core, command_buffer, set);
+            core.logical_device.cmd_push_constants(command_buffer, self.adapt_pipeline.layout,
+                                                   vk::ShaderStageFlags::COMPUTE, 0,
+                                                   cast_to_u8_slice(&adapt_constants));
+            core.logical_device.cmd_dispatch(command_buffer, 1, 1, 1);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::TRANSFER,
+                                                     vk::DependencyFlags::empty(), &[state_barrier], &[], &[]);
+            core.logical_device.cmd_copy_buffer(command_buffer, self.state.buf, self.readback[frame].buf,
+                                                &[copy_region]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.histogram_pipeline.destroy(core);
+        self.adapt_pipeline.destroy(core);
+        self.histogram.destroy(core);
+        self.state.destroy(core);
+        for b in self.readback.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+    }
+}
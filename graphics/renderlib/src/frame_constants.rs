@@ -0,0 +1,145 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+use cgmath::{Matrix4, SquareMatrix, Vector4};
+
+use crate::clock::Clock;
+use crate::gpu_buffer::GpuBuffer;
+use crate::scene::SceneCamera;
+use crate::ssao::create_set_layout;
+use crate::vkcore::VkCore;
+
+// Where pipelines bind the frame constants, FRAME_SET in frame_constants.glsl. Pipelines whose set 0 is taken by
+// older bindings define FRAME_SET before including the header instead.
+pub const FRAME_CONSTANTS_SET: u32 = 0;
+
+// Matches FrameConstants in frame_constants.glsl, values almost every shader needs. Camera values are the main view's.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct FrameConstants {
+    pub view: Matrix4<f32>,
+    pub proj: Matrix4<f32>,
+    pub view_proj: Matrix4<f32>,
+    pub inverse_view: Matrix4<f32>,
+    pub inverse_proj: Matrix4<f32>,
+    pub inverse_view_proj: Matrix4<f32>,
+    pub eye: Vector4<f32>, // w is unused
+    pub resolution: Vector4<f32>, // Width and height of the main view in pixels, then their reciprocals
+    pub time: Vector4<f32>, // x is the clock's time, y its delta and z the real time since startup, in seconds
+    pub info: Vector4<u32> // x is the frame index, y the debug flags
+}
+
+impl FrameConstants {
+    // debug_flags are bits the renderer and its shaders agree on
+    pub fn new(camera: &SceneCamera, extent: vk::Extent2D, clock: &Clock, frame_index: u64, debug_flags: u32)
+        -> FrameConstants {
+        let view = camera.view();
+        let proj = camera.projection(extent);
+        let view_proj = proj * view;
+        let eye = camera.eye();
+        let (width, height) = (extent.width.max(1) as f32, extent.height.max(1) as f32);
+
+        FrameConstants {
+            view,
+            proj,
+            view_proj,
+            inverse_view: view.invert().unwrap(),
+            inverse_proj: proj.invert().unwrap(),
+            inverse_view_proj: view_proj.invert().unwrap(),
+            eye: Vector4::new(eye.x, eye.y, eye.z, 1.0),
+            resolution: Vector4::new(width, height, 1.0 / width, 1.0 / height),
+            time: Vector4::new(clock.seconds(), clock.delta().as_secs_f32(), clock.since_startup().as_secs_f32(),
+                               0.0),
+            // Wraps after 2^32 frames, shaders only use it to vary noise
+            info: Vector4::new(frame_index as u32, debug_flags, 0, 0)
+        }
+    }
+}
+
+// The frame constants of every frame in flight, one uniform buffer and descriptor set each. The set layout is visible
+// to all stages, so raster, compute and ray tracing pipelines can all include it in their layouts.
+pub struct FrameConstantsBuffer {
+    uniform_buffers: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>
+}
+
+impl FrameConstantsBuffer {
+    pub fn new(core: &VkCore, max_frames: usize) -> FrameConstantsBuffer {
+        let uniform_size = mem::size_of::<FrameConstants>() as vk::DeviceSize;
+        let uniform_buffers: Vec<GpuBuffer> = (0..max_frames)
+            .map(|_| GpuBuffer::new_mapped(core, uniform_size, vk::BufferUsageFlags::UNIFORM_BUFFER))
+            .collect();
+        let mapped: Vec<*mut c_void> = uniform_buffers.iter()
+            .map(|b| unsafe {
+                core.logical_device.map_memory(b.mem, 0, uniform_size, vk::MemoryMapFlags::empty()).unwrap()
+            })
+            .collect();
+
+        let set_layout = create_set_layout(core, &[(vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::ALL)]);
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(max_frames as u32)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        for (set, buffer) in sets.iter().zip(uniform_buffers.iter()) {
+            let uniform_info = [vk::DescriptorBufferInfo::default()
+                .buffer(buffer.buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write = [vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&uniform_info)];
+            unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+        }
+
+        FrameConstantsBuffer {
+            uniform_buffers,
+            mapped,
+            set_layout,
+            descriptor_pool,
+            sets
+        }
+    }
+
+    // For pipeline layouts, at FRAME_CONSTANTS_SET or the set the shaders define as FRAME_SET
+    pub fn set_layout(&self) -> vk::DescriptorSetLayout {
+        self.set_layout
+    }
+
+    // Once the frame's fence has been waited on, before anything of the frame is recorded
+    pub fn update(&self, current_frame: usize, constants: &FrameConstants) {
+        unsafe { (self.mapped[current_frame] as *mut FrameConstants).write(*constants) };
+    }
+
+    pub fn cmd_bind(&self, core: &VkCore, command_buffer: vk::CommandBuffer, bind_point: vk::PipelineBindPoint,
+                    layout: vk::PipelineLayout, set: u32, current_frame: usize) {
+        unsafe {
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, bind_point, layout, set,
+                                                         &[self.sets[current_frame]], &[]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+        for b in self.uniform_buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+// Sleeping can overshoot by about a scheduler tick, the rest of the wait spins
+const SPIN_MARGIN: Duration = Duration::from_micros(1500);
+// A frame later than this behind its start time doesn't make the next ones start early to catch up
+const MAX_LAG: Duration = Duration::from_millis(50);
+
+// Caps the frame rate on the CPU, independent of the present mode. With FIFO the display already limits it, with
+// MAILBOX or IMMEDIATE nothing does and a simple scene renders thousands of frames a second. wait sleeps until shortly
+// before the next frame's start time and spins the rest, sleeping alone is too coarse for high rates. Start times are
+// spaced evenly from the first frame rather than from when the last one finished, so that the average rate is exact.
+pub struct FrameLimiter {
+    max_fps: Option<f32>, // None renders as fast as the present mode allows
+    next_frame: Option<Instant>,
+    last_wait_ms: f64
+}
+
+impl FrameLimiter {
+    pub fn new(max_fps: Option<f32>) -> FrameLimiter {
+        FrameLimiter {
+            max_fps: max_fps.filter(|&fps| fps > 0.0),
+            next_frame: None,
+            last_wait_ms: 0.0
+        }
+    }
+
+    pub fn max_fps(&self) -> Option<f32> {
+        self.max_fps
+    }
+
+    // None or a rate that isn't positive removes the limit
+    pub fn set_max_fps(&mut self, max_fps: Option<f32>) {
+        let max_fps = max_fps.filter(|&fps| fps > 0.0);
+        if max_fps != self.max_fps {
+            info!(?max_fps, "Frame rate limit changed");
+        }
+        self.max_fps = max_fps;
+        self.next_frame = None;
+    }
+
+    // Time the last wait call blocked for
+    pub fn last_wait_ms(&self) -> f64 {
+        self.last_wait_ms
+    }
+
+    // Call once at the start of every frame, before input is read
+    pub fn wait(&mut self) {
+        let interval = match self.max_fps {
+            Some(fps) => Duration::from_secs_f64(1.0 / fps as f64),
+            None => {
+                self.last_wait_ms = 0.0;
+                return;
+            }
+        };
+
+        let wait_start = Instant::now();
+        let deadline = match self.next_frame {
+            Some(deadline) if deadline + MAX_LAG > wait_start => deadline,
+            _ => wait_start
+        };
+        if let Some(sleep) = deadline.checked_duration_since(wait_start + SPIN_MARGIN) {
+            thread::sleep(sleep);
+        }
+        while Instant::now() < deadline {
+            std::hint::spin_loop();
+        }
+
+        self.next_frame = Some(deadline + interval);
+        self.last_wait_ms = wait_start.elapsed().as_secs_f64() * 1000.0;
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> String {
+        match self.max_fps {
+            Some(fps) => format!("limit {:.0} fps, waited {:.1} ms", fps, self.last_wait_ms),
+            None => String::from("no fps limit")
+        }
+    }
+}
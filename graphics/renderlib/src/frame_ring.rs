@@ -0,0 +1,102 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::vkcore::VkCore;
+
+// Part of a FrameRing's buffer, bind it with its offset. Only valid in the frame it was allocated for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RingSlice {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize
+}
+
+// One mapped buffer that immediate mode drawing, like DebugLines, fills with new vertices every frame instead of
+// rewriting buffers the GPU may still read. Allocations go round the buffer, and the ones of a frame are recycled once
+// its fence has been waited on again, by the next begin_frame for it. Frames finish in order, so the oldest bytes are
+// always the ones freed.
+pub struct FrameRing {
+    buffer: GpuBuffer,
+    mapped: *mut u8,
+    capacity: vk::DeviceSize,
+    head: vk::DeviceSize, // Where the next allocation starts, before alignment
+    in_flight: vk::DeviceSize, // Bytes allocated by frames that may not have finished, including padding
+    frame_bytes: Vec<vk::DeviceSize>, // What each frame allocated, freed by its next begin_frame
+    current_frame: usize
+}
+
+impl FrameRing {
+    // usage is what the slices are bound as, like VERTEX_BUFFER | INDEX_BUFFER
+    pub fn new(core: &VkCore, capacity: vk::DeviceSize, usage: vk::BufferUsageFlags, max_frames: usize) -> FrameRing {
+        let buffer = GpuBuffer::new_mapped(core, capacity, usage);
+        let mapped = buffer.map(core) as *mut u8;
+
+        FrameRing {
+            buffer,
+            mapped,
+            capacity,
+            head: 0,
+            in_flight: 0,
+            frame_bytes: vec![0; max_frames],
+            current_frame: 0
+        }
+    }
+
+    pub fn capacity(&self) -> vk::DeviceSize {
+        self.capacity
+    }
+
+    // Bytes that can't be allocated until their frames finish
+    pub fn in_flight(&self) -> vk::DeviceSize {
+        self.in_flight
+    }
+
+    // Once the frame's fence has been waited on, before its first allocation. Frees what it allocated last time.
+    pub fn begin_frame(&mut self, current_frame: usize) {
+        self.in_flight -= self.frame_bytes[current_frame];
+        self.frame_bytes[current_frame] = 0;
+        self.current_frame = current_frame;
+    }
+
+    // size bytes at a multiple of alignment, which must be a power of two. Allocations don't wrap, the end of the
+    // buffer is skipped if they don't fit there. Errors if the frames in flight hold too much of the buffer.
+    pub fn allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize)
+        -> Result<(RingSlice, *mut u8), String> {
+        let aligned = (self.head + alignment - 1) & !(alignment - 1);
+        let (offset, padding) = match aligned + size <= self.capacity {
+            true => (aligned, aligned - self.head),
+            false => (0, self.capacity - self.head)
+        };
+        if self.in_flight + padding + size > self.capacity {
+            return Err(format!("{} bytes don't fit the frame ring, {} of {} are in flight", size, self.in_flight,
+                               self.capacity));
+        }
+        self.head = offset + size;
+        self.in_flight += padding + size;
+        self.frame_bytes[self.current_frame] += padding + size;
+        let slice = RingSlice {
+            buffer: self.buffer.buf,
+            offset,
+            size
+        };
+
+        Ok((slice, unsafe { self.mapped.add(offset as usize) }))
+    }
+
+    // Copies items into a new allocation aligned for T
+    pub fn write<T: Copy>(&mut self, items: &[T]) -> Result<RingSlice, String> {
+        let size = mem::size_of_val(items) as vk::DeviceSize;
+        let alignment = mem::align_of::<T>().max(4) as vk::DeviceSize;
+        let (slice, mapped) = self.allocate(size, alignment)?;
+        unsafe { (mapped as *mut T).copy_from_nonoverlapping(items.as_ptr(), items.len()) };
+
+        Ok(slice)
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe { core.logical_device.unmap_memory(self.buffer.mem) };
+        self.buffer.destroy(core);
+    }
+}
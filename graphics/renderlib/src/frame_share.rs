@@ -0,0 +1,449 @@
+use std::ffi::c_void;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use ash::extensions::khr::{ExternalMemoryFd, ExternalSemaphoreFd};
+use ash::vk;
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::gpu_buffer::find_buf_index;
+use crate::vkcore::VkCore;
+
+// Number of images a consumer can read from while the next frames are copied into the others
+pub const SHARE_RING_SIZE: usize = 3;
+const SHARE_USAGE: vk::ImageUsageFlags = vk::ImageUsageFlags::from_raw(
+    vk::ImageUsageFlags::TRANSFER_SRC.as_raw() | vk::ImageUsageFlags::TRANSFER_DST.as_raw() |
+        vk::ImageUsageFlags::SAMPLED.as_raw());
+const MEMORY_HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags = vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD;
+const SEMAPHORE_HANDLE_TYPE: vk::ExternalSemaphoreHandleTypeFlags = vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_FD;
+
+struct SharedImage {
+    image: vk::Image,
+    memory: vk::DeviceMemory, // Dedicated
+    size: vk::DeviceSize,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    done_sem: vk::Semaphore
+}
+
+// What a consumer needs besides the fds to import the images, sent as a JSON line with them
+#[derive(Clone, Debug, Serialize)]
+pub struct SharedFrameInfo {
+    pub width: u32,
+    pub height: u32,
+    pub format: i32, // vk::Format, the swap chain's
+    pub usage: u32, // vk::ImageUsageFlags the images were created with, optimal tiling and a single mip and layer
+    pub sizes: Vec<vk::DeviceSize>, // Of each image's dedicated allocation
+    pub frame: u64, // The last frame copied, 0 for none yet
+    // The consumer's physical device must have the same ones, opaque fds can't cross devices or drivers
+    pub device_uuid: [u8; vk::UUID_SIZE],
+    pub driver_uuid: [u8; vk::UUID_SIZE]
+}
+
+// Exported fds for one consumer, see SharedFrames::export. Each consumer imports its own and closes them when done.
+pub struct SharedFrameHandles {
+    pub info: SharedFrameInfo,
+    pub memory_fds: Vec<OwnedFd>, // One per image
+    pub semaphore_fd: OwnedFd
+}
+
+impl SharedFrameHandles {
+    // Writes the info as a JSON line with the memory fds and then the semaphore fd attached as SCM_RIGHTS
+    pub fn send(&self, stream: &UnixStream) -> io::Result<()> {
+        let mut message = serde_json::to_vec(&self.info).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        message.push(b'\n');
+        let fds: Vec<RawFd> = self.memory_fds.iter().chain([&self.semaphore_fd]).map(|f| f.as_raw_fd()).collect();
+        send_with_fds(stream, message.as_slice(), fds.as_slice())
+    }
+}
+
+fn send_with_fds(stream: &UnixStream, bytes: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_size = mem::size_of_val(fds) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size) } as usize];
+    let mut iov = libc::iovec { iov_base: bytes.as_ptr() as *mut c_void, iov_len: bytes.len() };
+    let mut message: libc::msghdr = unsafe { mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr() as *mut c_void;
+    message.msg_controllen = control.len() as _;
+
+    let sent = unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(header) as *mut RawFd, fds.len());
+        libc::sendmsg(stream.as_raw_fd(), &message, 0)
+    };
+    match sent {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n if (n as usize) < bytes.len() => Err(io::Error::new(io::ErrorKind::WriteZero, "Partial frame share message")),
+        _ => Ok(())
+    }
+}
+
+// Zero copy frame sharing with other processes, like compositors or capture tools, through Vulkan external memory.
+// Each presented frame is copied on the transfer queue into the next of SHARE_RING_SIZE exportable images, which
+// then signals an exportable timeline semaphore with the frame's number, counting from 1. A consumer waits for
+// value n and reads image (n - 1) % SHARE_RING_SIZE, which stays untouched for the next SHARE_RING_SIZE - 1 frames.
+// Images are left in GENERAL and released to VK_QUEUE_FAMILY_EXTERNAL. Recreate with the swap chain.
+pub struct SharedFrames {
+    command_pool: vk::CommandPool,
+    images: Vec<SharedImage>,
+    timeline: vk::Semaphore,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    pub frame_count: u64 // The timeline's value once the last submitted copy is done
+}
+
+impl SharedFrames {
+    // format is the swap chain format. Fails if the device can't export images of it or doesn't support
+    // external_share_supported.
+    pub fn new(core: &VkCore, extent: vk::Extent2D, format: vk::Format) -> Result<SharedFrames, String> {
+        if !core.external_share_supported {
+            return Err(String::from("External memory and semaphore fds aren't supported"));
+        }
+        let mut external_info = vk::PhysicalDeviceExternalImageFormatInfo::default()
+            .handle_type(MEMORY_HANDLE_TYPE);
+        let format_info = vk::PhysicalDeviceImageFormatInfo2::default()
+            .format(format)
+            .ty(vk::ImageType::TYPE_2D)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(SHARE_USAGE)
+            .push_next(&mut external_info);
+        let mut external_properties = vk::ExternalImageFormatProperties::default();
+        let mut properties = vk::ImageFormatProperties2::default()
+            .push_next(&mut external_properties);
+        unsafe {
+            core.instance.get_physical_device_image_format_properties2(core.physical_device, &format_info,
+                                                                       &mut properties)
+        }.map_err(|e| format!("Can't share images of {:?}: {}", format, e))?;
+        if !external_properties.external_memory_properties.external_memory_features
+            .contains(vk::ExternalMemoryFeatureFlags::EXPORTABLE) {
+            return Err(format!("Images of {:?} can't be exported", format));
+        }
+
+        let pool_create_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(core.transfer_family_index);
+        let command_pool = unsafe { core.logical_device.create_command_pool(&pool_create_info, None).unwrap() };
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(SHARE_RING_SIZE as u32);
+        let command_buffers = unsafe { core.logical_device.allocate_command_buffers(&alloc_info).unwrap() };
+        let images = command_buffers.into_iter()
+            .map(|command_buffer| create_shared_image(core, extent, format, command_buffer))
+            .collect();
+
+        let mut type_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let mut export_info = vk::ExportSemaphoreCreateInfo::default()
+            .handle_types(SEMAPHORE_HANDLE_TYPE);
+        let sem_create_info = vk::SemaphoreCreateInfo::default()
+            .push_next(&mut type_info)
+            .push_next(&mut export_info);
+        let timeline = unsafe { core.logical_device.create_semaphore(&sem_create_info, None).unwrap() };
+
+        Ok(SharedFrames {
+            command_pool,
+            images,
+            timeline,
+            extent,
+            format,
+            frame_count: 0
+        })
+    }
+
+    // Queues a copy of image, which must be in PRESENT_SRC_KHR once render_finished is signaled. The returned
+    // semaphore replaces render_finished as the present wait semaphore, like with FrameCapture::submit.
+    pub fn submit(&mut self, core: &VkCore, image: vk::Image, render_finished: vk::Semaphore) -> vk::Semaphore {
+        let shared = &self.images[self.frame_count as usize % SHARE_RING_SIZE];
+        unsafe { core.logical_device.wait_for_fences(&[shared.fence], true, u64::MAX).unwrap() };
+
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let subresource = vk::ImageSubresourceLayers::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1);
+        let barrier = |image: vk::Image| vk::ImageMemoryBarrier::default()
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED) // Swap chain images are shared concurrently
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+        // The previous contents are discarded, consumers are done with them by now
+        let to_copy_barriers = [
+            barrier(image)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .old_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+                .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+            barrier(shared.image)
+                .src_access_mask(vk::AccessFlags::empty())
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+        ];
+        let after_copy_barriers = [
+            barrier(image)
+                .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .dst_access_mask(vk::AccessFlags::empty())
+                .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                .new_layout(vk::ImageLayout::PRESENT_SRC_KHR),
+            barrier(shared.image)
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::empty())
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(vk::ImageLayout::GENERAL)
+                .src_queue_family_index(core.transfer_family_index)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_EXTERNAL)
+        ];
+        let copy_region = [vk::ImageCopy::default()
+            .src_subresource(subresource)
+            .dst_subresource(subresource)
+            .extent(vk::Extent3D::default()
+                .width(self.extent.width)
+                .height(self.extent.height)
+                .depth(1))];
+
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let wait_sems = [render_finished];
+        let wait_stages = [vk::PipelineStageFlags::TRANSFER];
+        let command_buffers = [shared.command_buffer];
+        let sig_sems = [shared.done_sem, self.timeline];
+        let sig_values = [0, self.frame_count + 1]; // The binary semaphore's value is ignored
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::default()
+            .signal_semaphore_values(&sig_values);
+        let submit_info = [vk::SubmitInfo::default()
+            .wait_semaphores(&wait_sems)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&sig_sems)
+            .push_next(&mut timeline_info)];
+
+        let command_buffer = shared.command_buffer;
+        unsafe {
+            core.logical_device.reset_fences(&[shared.fence]).unwrap();
+            core.logical_device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty()).unwrap();
+            core.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                     &[], &[], &to_copy_barriers);
+            core.logical_device.cmd_copy_image(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                               shared.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &copy_region);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                     vk::DependencyFlags::empty(), &[], &[], &after_copy_barriers);
+            core.logical_device.end_command_buffer(command_buffer).unwrap();
+            core.logical_device.queue_submit(core.transfer_queue, &submit_info, shared.fence).unwrap();
+        }
+
+        self.frame_count += 1;
+        shared.done_sem
+    }
+
+    // New fds of the images' memory and the timeline semaphore, for one consumer
+    pub fn export(&self, core: &VkCore) -> Result<SharedFrameHandles, String> {
+        let memory_loader = ExternalMemoryFd::new(&core.instance, &core.logical_device);
+        let semaphore_loader = ExternalSemaphoreFd::new(&core.instance, &core.logical_device);
+        let memory_fds = self.images.iter()
+            .map(|shared| {
+                let get_info = vk::MemoryGetFdInfoKHR::default()
+                    .memory(shared.memory)
+                    .handle_type(MEMORY_HANDLE_TYPE);
+                unsafe { memory_loader.get_memory_fd(&get_info) }
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+                    .map_err(|e| format!("Couldn't export a shared image: {}", e))
+            })
+            .collect::<Result<Vec<OwnedFd>, String>>()?;
+        let get_info = vk::SemaphoreGetFdInfoKHR::default()
+            .semaphore(self.timeline)
+            .handle_type(SEMAPHORE_HANDLE_TYPE);
+        let semaphore_fd = unsafe { semaphore_loader.get_semaphore_fd(&get_info) }
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+            .map_err(|e| format!("Couldn't export the frame semaphore: {}", e))?;
+
+        let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+        let mut properties = vk::PhysicalDeviceProperties2::default()
+            .push_next(&mut id_properties);
+        unsafe { core.instance.get_physical_device_properties2(core.physical_device, &mut properties) };
+
+        Ok(SharedFrameHandles {
+            info: SharedFrameInfo {
+                width: self.extent.width,
+                height: self.extent.height,
+                format: self.format.as_raw(),
+                usage: SHARE_USAGE.as_raw(),
+                sizes: self.images.iter().map(|shared| shared.size).collect(),
+                frame: self.frame_count,
+                device_uuid: id_properties.device_uuid,
+                driver_uuid: id_properties.driver_uuid
+            },
+            memory_fds,
+            semaphore_fd
+        })
+    }
+
+    // Waits for the outstanding copies, unless the device is lost
+    pub fn destroy(&self, core: &VkCore) {
+        let fences: Vec<vk::Fence> = self.images.iter().map(|shared| shared.fence).collect();
+        unsafe {
+            let _ = core.logical_device.wait_for_fences(fences.as_slice(), true, u64::MAX);
+            for shared in self.images.iter() {
+                core.logical_device.destroy_fence(shared.fence, None);
+                core.logical_device.destroy_semaphore(shared.done_sem, None);
+                core.logical_device.destroy_image(shared.image, None);
+                core.logical_device.free_memory(shared.memory, None);
+            }
+            core.logical_device.destroy_semaphore(self.timeline, None);
+            core.logical_device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
+
+fn create_shared_image(core: &VkCore, extent: vk::Extent2D, format: vk::Format, command_buffer: vk::CommandBuffer)
+    -> SharedImage {
+    let mut external_info = vk::ExternalMemoryImageCreateInfo::default()
+        .handle_types(MEMORY_HANDLE_TYPE);
+    let image_info = vk::ImageCreateInfo::default()
+        .image_type(vk::ImageType::TYPE_2D)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(1)
+        .array_layers(1)
+        .format(format)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(SHARE_USAGE)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .push_next(&mut external_info);
+    let image = unsafe { core.logical_device.create_image(&image_info, None).unwrap() };
+    let mem_reqs = unsafe { core.logical_device.get_image_memory_requirements(image) };
+
+    // Dedicated, as some drivers require for exported images, so that the consumer imports exactly this image
+    let mut export_info = vk::ExportMemoryAllocateInfo::default()
+        .handle_types(MEMORY_HANDLE_TYPE);
+    let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::default()
+        .image(image);
+    let alloc_info = vk::MemoryAllocateInfo::default()
+        .memory_type_index(find_buf_index(core, vk::MemoryPropertyFlags::DEVICE_LOCAL, mem_reqs).unwrap())
+        .allocation_size(mem_reqs.size)
+        .push_next(&mut export_info)
+        .push_next(&mut dedicated_info);
+    let memory = unsafe { core.logical_device.allocate_memory(&alloc_info, None).unwrap() };
+    unsafe { core.logical_device.bind_image_memory(image, memory, 0).unwrap() };
+
+    let fence_create_info = vk::FenceCreateInfo::default()
+        .flags(vk::FenceCreateFlags::SIGNALED);
+    let sem_create_info = vk::SemaphoreCreateInfo::default();
+    let (fence, done_sem) = unsafe {
+        (core.logical_device.create_fence(&fence_create_info, None).unwrap(),
+         core.logical_device.create_semaphore(&sem_create_info, None).unwrap())
+    };
+
+    SharedImage {
+        image,
+        memory,
+        size: mem_reqs.size,
+        command_buffer,
+        fence,
+        done_sem
+    }
+}
+
+// Shares frames with every process that connects to a Unix socket. A connecting consumer gets SharedFrameHandles,
+// and again whenever the frames are recreated with the swap chain or the device.
+pub struct FrameShare {
+    listener: UnixListener,
+    socket_path: PathBuf,
+    clients: Vec<UnixStream>,
+    frames: Option<SharedFrames>
+}
+
+impl FrameShare {
+    // Replaces a stale socket file at socket_path
+    pub fn bind(core: &VkCore, socket_path: &Path, extent: vk::Extent2D, format: vk::Format)
+        -> Result<FrameShare, String> {
+        let frames = SharedFrames::new(core, extent, format)?;
+        if socket_path.exists() {
+            fs::remove_file(socket_path).map_err(|e| format!("Couldn't remove {}: {}", socket_path.display(), e))?;
+        }
+        let listener = UnixListener::bind(socket_path)
+            .and_then(|l| l.set_nonblocking(true).map(|_| l))
+            .map_err(|e| format!("Couldn't listen on {}: {}", socket_path.display(), e))?;
+        info!(path = %socket_path.display(), "Sharing frames");
+
+        Ok(FrameShare {
+            listener,
+            socket_path: socket_path.to_path_buf(),
+            clients: Vec::new(),
+            frames: Some(frames)
+        })
+    }
+
+    // Sends the handles to consumers that connected since the last frame
+    pub fn poll(&mut self, core: &VkCore) {
+        let frames = match self.frames.as_ref() {
+            Some(f) => f,
+            None => return
+        };
+        while let Ok((stream, _)) = self.listener.accept() {
+            match frames.export(core).and_then(|h| h.send(&stream).map_err(|e| e.to_string())) {
+                Ok(()) => self.clients.push(stream),
+                Err(e) => warn!("Couldn't share frames with a consumer: {}", e)
+            }
+        }
+    }
+
+    // See SharedFrames::submit, render_finished is returned as is while there are no frames
+    pub fn submit(&mut self, core: &VkCore, image: vk::Image, render_finished: vk::Semaphore) -> vk::Semaphore {
+        match self.frames.as_mut() {
+            Some(frames) => frames.submit(core, image, render_finished),
+            None => render_finished
+        }
+    }
+
+    // Before the device or the swap chain is destroyed
+    pub fn release(&mut self, core: &VkCore) {
+        if let Some(frames) = self.frames.take() {
+            frames.destroy(core);
+        }
+    }
+
+    // After the swap chain is recreated, on a new device or not. Connected consumers get the new handles and the
+    // ones that can't be reached are dropped.
+    pub fn recreate(&mut self, core: &VkCore, extent: vk::Extent2D, format: vk::Format) {
+        self.release(core);
+        self.frames = match SharedFrames::new(core, extent, format) {
+            Ok(frames) => Some(frames),
+            Err(e) => {
+                warn!("Stopped sharing frames: {}", e);
+                None
+            }
+        };
+        if let Some(frames) = self.frames.as_ref() {
+            self.clients.retain(|stream| {
+                frames.export(core).and_then(|h| h.send(stream).map_err(|e| e.to_string())).is_ok()
+            });
+        }
+    }
+
+    pub fn destroy(&mut self, core: &VkCore) {
+        self.release(core);
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
@@ -0,0 +1,217 @@
+use ash::vk;
+
+use crate::depth::find_depth_format;
+use crate::image::{create_image, create_image_view, StorageImage};
+use crate::raster_pipeline::RasterPipeline;
+use crate::vkcore::VkCore;
+
+pub const ALBEDO_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+pub const NORMAL_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+// Full precision, rays start from these positions
+pub const POSITION_FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+const ATTACHMENT_FORMATS: [vk::Format; 3] = [ALBEDO_FORMAT, NORMAL_FORMAT, POSITION_FORMAT];
+
+// Attachments are cleared to zero, so a position with w = 0 is a pixel where nothing was drawn
+fn create_render_pass(core: &VkCore) -> vk::RenderPass {
+    let mut attachment_desc: Vec<vk::AttachmentDescription> = ATTACHMENT_FORMATS.iter()
+        .map(|&format| vk::AttachmentDescription::default()
+            .format(format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::GENERAL))
+        .collect();
+    attachment_desc.push(vk::AttachmentDescription::default()
+        .format(find_depth_format(core))
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL));
+    let color_refs: Vec<vk::AttachmentReference> = (0..ATTACHMENT_FORMATS.len())
+        .map(|a| vk::AttachmentReference::default()
+            .attachment(a as u32)
+            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+        .collect();
+    let depth_ref = vk::AttachmentReference::default()
+        .attachment(ATTACHMENT_FORMATS.len() as u32)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+    let subpass = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(color_refs.as_slice())
+        .depth_stencil_attachment(&depth_ref)];
+    // The previous frame's compute passes read the attachments, this frame's read what the pass wrote
+    let dependencies = [
+        vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::COMPUTE_SHADER)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT |
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE |
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+        vk::SubpassDependency::default()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::COMPUTE_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+    ];
+    let render_pass_create_info = vk::RenderPassCreateInfo::default()
+        .attachments(attachment_desc.as_slice())
+        .subpasses(&subpass)
+        .dependencies(&dependencies);
+
+    unsafe { core.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+}
+
+// The window sized images and the frame buffer over them
+struct GBufferImages {
+    attachments: Vec<StorageImage>, // In ATTACHMENT_FORMATS order
+    depth_image: vk::Image,
+    depth_mem: vk::DeviceMemory,
+    depth_view: vk::ImageView,
+    frame_buffer: vk::Framebuffer
+}
+
+impl GBufferImages {
+    fn new(core: &VkCore, command_pool: vk::CommandPool, render_pass: vk::RenderPass,
+           extent: vk::Extent2D) -> GBufferImages {
+        let attachments: Vec<StorageImage> = ATTACHMENT_FORMATS.iter()
+            .map(|&format| StorageImage::new(core, command_pool, extent, format,
+                                             vk::ImageUsageFlags::COLOR_ATTACHMENT))
+            .collect();
+        let depth_format = find_depth_format(core);
+        let (depth_image, depth_mem) = create_image(core, extent.width, extent.height, 1, depth_format,
+                                                    vk::ImageTiling::OPTIMAL,
+                                                    vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                                                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                    vk::SampleCountFlags::TYPE_1);
+        let depth_view = create_image_view(core, depth_image, depth_format, vk::ImageAspectFlags::DEPTH, 1);
+        let views: Vec<vk::ImageView> = attachments.iter().map(|a| a.view).chain([depth_view]).collect();
+        let create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(views.as_slice())
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let frame_buffer = unsafe { core.logical_device.create_framebuffer(&create_info, None).unwrap() };
+
+        GBufferImages {
+            attachments,
+            depth_image,
+            depth_mem,
+            depth_view,
+            frame_buffer
+        }
+    }
+
+    fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_framebuffer(self.frame_buffer, None);
+            core.logical_device.destroy_image_view(self.depth_view, None);
+            core.logical_device.destroy_image(self.depth_image, None);
+            core.logical_device.free_memory(self.depth_mem, None);
+        }
+        for a in self.attachments.iter() {
+            a.destroy(core);
+        }
+    }
+}
+
+// What each pixel sees, rasterized for later compute passes to shade: the albedo, the world space normal and the
+// world space position. The attachments stay in the GENERAL layout and are bound as storage images. A single set
+// of them is shared by the frames in flight, the render pass waits for the previous frame's compute passes.
+pub struct GBuffer {
+    images: GBufferImages,
+    render_pass: vk::RenderPass,
+    pipeline: RasterPipeline,
+    extent: vk::Extent2D
+}
+
+impl GBuffer {
+    // layout is set 0 of the pipeline, as created by create_descriptor_set_layout
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D,
+               layout: vk::DescriptorSetLayout) -> GBuffer {
+        let render_pass = create_render_pass(core);
+
+        GBuffer {
+            images: GBufferImages::new(core, command_pool, render_pass, extent),
+            render_pass,
+            pipeline: RasterPipeline::new_gbuffer(core, render_pass, layout, ATTACHMENT_FORMATS.len()),
+            extent
+        }
+    }
+
+    // Nothing may still be using the images, the storage image descriptors have to be written again
+    pub fn resize(&mut self, core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D) {
+        self.images.destroy(core);
+        self.images = GBufferImages::new(core, command_pool, self.render_pass, extent);
+        self.extent = extent;
+    }
+
+    pub fn pipeline_layout(&self) -> vk::PipelineLayout {
+        self.pipeline.pipeline_layout
+    }
+
+    // Bind as STORAGE_IMAGE descriptors
+    pub fn albedo_info(&self) -> [vk::DescriptorImageInfo; 1] {
+        self.images.attachments[0].info()
+    }
+
+    pub fn normal_info(&self) -> [vk::DescriptorImageInfo; 1] {
+        self.images.attachments[1].info()
+    }
+
+    pub fn position_info(&self) -> [vk::DescriptorImageInfo; 1] {
+        self.images.attachments[2].info()
+    }
+
+    // Begins the render pass with the pipeline bound and the viewport and scissor set. The caller binds set 0 and
+    // draws, then calls cmd_end.
+    pub fn cmd_begin(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.extent);
+        let mut clear_values = vec![vk::ClearValue { color: vk::ClearColorValue { float32: [0.0; 4] } };
+                                    ATTACHMENT_FORMATS.len()];
+        clear_values.push(vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue::default().depth(1.0).stencil(0)
+        });
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.render_pass)
+            .framebuffer(self.images.frame_buffer)
+            .render_area(render_area)
+            .clear_values(clear_values.as_slice());
+        let viewport = vk::Viewport::default()
+            .width(self.extent.width as f32)
+            .height(self.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0);
+
+        unsafe {
+            core.logical_device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                  self.pipeline.pipelines[0]);
+            core.logical_device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+            core.logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+        }
+    }
+
+    pub fn cmd_end(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        unsafe { core.logical_device.cmd_end_render_pass(command_buffer) };
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.images.destroy(core);
+        self.pipeline.destroy(core);
+        unsafe { core.logical_device.destroy_render_pass(self.render_pass, None) };
+    }
+}
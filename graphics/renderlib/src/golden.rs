@@ -0,0 +1,183 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{GenericImageView, Rgba, RgbaImage};
+use tracing::info;
+
+// Test support for comparing rendered frames against checked in reference images. Set UPDATE_GOLDEN=1 to write the
+// rendered frames as the new references instead of comparing.
+pub const GOLDEN_DIR: &str = "graphics/tests/golden";
+const SSIM_WINDOW: u32 = 8;
+
+// Drivers round differently, so exact matches are too strict. A frame passes when no channel is further off than
+// max_channel_delta on more than max_differing_fraction of the pixels and the structure still matches.
+#[derive(Clone, Copy, Debug)]
+pub struct GoldenTolerance {
+    pub max_channel_delta: u8,
+    pub max_differing_fraction: f64,
+    pub min_ssim: f64
+}
+
+impl Default for GoldenTolerance {
+    fn default() -> GoldenTolerance {
+        GoldenTolerance {
+            max_channel_delta: 8,
+            max_differing_fraction: 0.001,
+            min_ssim: 0.98
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct GoldenReport {
+    pub max_channel_delta: u8,
+    pub differing_pixels: usize, // Pixels with a channel beyond the tolerance
+    pub ssim: f64
+}
+
+impl GoldenReport {
+    pub fn passes(&self, tolerance: &GoldenTolerance, pixel_count: usize) -> bool {
+        self.differing_pixels as f64 <= tolerance.max_differing_fraction * pixel_count as f64 &&
+            self.ssim >= tolerance.min_ssim
+    }
+}
+
+fn luma(p: &Rgba<u8>) -> f64 {
+    0.2126 * p[0] as f64 + 0.7152 * p[1] as f64 + 0.0722 * p[2] as f64
+}
+
+// Mean SSIM of the luma over non overlapping SSIM_WINDOW sized windows, 1.0 for identical images
+pub fn ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    assert_eq!(a.dimensions(), b.dimensions());
+    let c1 = (0.01 * 255.0f64).powi(2);
+    let c2 = (0.03 * 255.0f64).powi(2);
+    let (width, height) = a.dimensions();
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for wy in (0..height).step_by(SSIM_WINDOW as usize) {
+        for wx in (0..width).step_by(SSIM_WINDOW as usize) {
+            let w = SSIM_WINDOW.min(width - wx);
+            let h = SSIM_WINDOW.min(height - wy);
+            let values: Vec<(f64, f64)> = a.view(wx, wy, w, h).pixels().zip(b.view(wx, wy, w, h).pixels())
+                .map(|((_, _, pa), (_, _, pb))| (luma(&pa), luma(&pb)))
+                .collect();
+            let n = values.len() as f64;
+            let mean_a = values.iter().map(|v| v.0).sum::<f64>() / n;
+            let mean_b = values.iter().map(|v| v.1).sum::<f64>() / n;
+            let var_a = values.iter().map(|v| (v.0 - mean_a).powi(2)).sum::<f64>() / n;
+            let var_b = values.iter().map(|v| (v.1 - mean_b).powi(2)).sum::<f64>() / n;
+            let covariance = values.iter().map(|v| (v.0 - mean_a) * (v.1 - mean_b)).sum::<f64>() / n;
+
+            total += ((2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2)) /
+                ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            windows += 1;
+        }
+    }
+
+    total / windows as f64
+}
+
+pub fn compare_images(expected: &RgbaImage, actual: &RgbaImage, tolerance: &GoldenTolerance) -> GoldenReport {
+    assert_eq!(expected.dimensions(), actual.dimensions());
+    let mut max_channel_delta: u8 = 0;
+    let mut differing_pixels: usize = 0;
+    for (e, a) in expected.pixels().zip(actual.pixels()) {
+        let delta = e.0.iter().zip(a.0.iter()).map(|(x, y)| x.abs_diff(*y)).max().unwrap();
+        max_channel_delta = max_channel_delta.max(delta);
+        if delta > tolerance.max_channel_delta {
+            differing_pixels += 1;
+        }
+    }
+
+    GoldenReport {
+        max_channel_delta,
+        differing_pixels,
+        ssim: ssim(expected, actual)
+    }
+}
+
+// Absolute per channel difference scaled up so small errors are visible, alpha is forced to opaque
+fn diff_image(expected: &RgbaImage, actual: &RgbaImage) -> RgbaImage {
+    RgbaImage::from_fn(expected.width(), expected.height(), |x, y| {
+        let (e, a) = (expected.get_pixel(x, y), actual.get_pixel(x, y));
+        let channel = |c: usize| e[c].abs_diff(a[c]).saturating_mul(8);
+        Rgba([channel(0), channel(1), channel(2), 255])
+    })
+}
+
+pub fn golden_path(name: &str) -> PathBuf {
+    Path::new(GOLDEN_DIR).join(format!("{}.png", name))
+}
+
+// Compares the PNG at actual against the reference called name. On failure the rendered frame and a diff image are
+// left next to the reference as <name>.actual.png and <name>.diff.png.
+pub fn check_golden(name: &str, actual: &Path, tolerance: &GoldenTolerance) -> Result<GoldenReport, String> {
+    let reference = golden_path(name);
+    let actual_image = image::open(actual).map_err(|e| format!("Failed to read {}: {}", actual.display(), e))?
+        .to_rgba8();
+
+    if env::var("UPDATE_GOLDEN").is_ok_and(|v| v == "1") {
+        fs::create_dir_all(GOLDEN_DIR).unwrap();
+        actual_image.save(&reference).map_err(|e| format!("Failed to write {}: {}", reference.display(), e))?;
+        info!("Updated {}", reference.display());
+        return Ok(GoldenReport {
+            max_channel_delta: 0,
+            differing_pixels: 0,
+            ssim: 1.0
+        });
+    }
+
+    let expected_image = image::open(&reference)
+        .map_err(|e| format!("Failed to read {} ({}), run with UPDATE_GOLDEN=1 to create it", reference.display(), e))?
+        .to_rgba8();
+    if expected_image.dimensions() != actual_image.dimensions() {
+        return Err(format!("{}: expected {:?}, rendered {:?}", name, expected_image.dimensions(),
+                           actual_image.dimensions()));
+    }
+
+    let report = compare_images(&expected_image, &actual_image, tolerance);
+    match report.passes(tolerance, (actual_image.width() * actual_image.height()) as usize) {
+        true => Ok(report),
+        false => {
+            actual_image.save(reference.with_extension("actual.png")).unwrap();
+            diff_image(&expected_image, &actual_image).save(reference.with_extension("diff.png")).unwrap();
+            Err(format!("{}: {} pixels differ by more than {}, max delta {}, SSIM {:.4}", name,
+                        report.differing_pixels, tolerance.max_channel_delta, report.max_channel_delta, report.ssim))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(offset: u8) -> RgbaImage {
+        RgbaImage::from_fn(32, 24, |x, y| Rgba([(x * 8) as u8 + offset, (y * 10) as u8, 128, 255]))
+    }
+
+    #[test]
+    fn identical_images_match() {
+        let report = compare_images(&gradient(0), &gradient(0), &GoldenTolerance::default());
+        assert_eq!(report.max_channel_delta, 0);
+        assert_eq!(report.differing_pixels, 0);
+        assert!((report.ssim - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn small_offsets_stay_within_tolerance() {
+        let tolerance = GoldenTolerance::default();
+        let report = compare_images(&gradient(0), &gradient(2), &tolerance);
+        assert_eq!(report.max_channel_delta, 2);
+        assert!(report.passes(&tolerance, 32 * 24));
+    }
+
+    #[test]
+    fn structural_changes_fail() {
+        let tolerance = GoldenTolerance::default();
+        let flat = RgbaImage::from_pixel(32, 24, Rgba([128, 128, 128, 255]));
+        let report = compare_images(&gradient(0), &flat, &tolerance);
+        assert!(!report.passes(&tolerance, 32 * 24));
+    }
+}
@@ -1,5 +1,9 @@
+use std::ffi::c_void;
 use std::mem;
+use std::ops::Range;
 use ash::vk;
+use crate::capabilities::{buffer_device_address, device_address_allocate_flags_info};
+use crate::memory::{direct_upload, mapped_memory_flags, UploadPath};
 use crate::single_time::{begin_single_time_commands, end_single_time_commands};
 use crate::vkcore::VkCore;
 
@@ -7,23 +11,23 @@ pub(crate) fn find_buf_index(core: &VkCore, mem_props: vk::MemoryPropertyFlags,
     -> Result<u32, ()> {
     let phys_mem_props = unsafe { core.instance.get_physical_device_memory_properties(core.physical_device)};
 
-    let mut idx = -1;
-    let mut retval = Err(());
     for i in 0..phys_mem_props.memory_type_count {
         if ((1 << i) & mem_reqs.memory_type_bits) > 0 && // If this physical memory type is valid for the requirement
             phys_mem_props.memory_types.get(i as usize).unwrap()
                 .property_flags
                 .contains(mem_props) {
-            idx = i as i64;
-            break;
+            return Ok(i);
         }
     }
 
-    if idx >= -1 {
-        retval = Ok(idx as u32);
-    }
+    Err(())
+}
 
-    retval
+// Whether the host sees device writes to the memory type and the other way round without flushes and invalidations
+pub(crate) fn is_coherent(core: &VkCore, memory_type_index: u32) -> bool {
+    let phys_mem_props = unsafe { core.instance.get_physical_device_memory_properties(core.physical_device) };
+    phys_mem_props.memory_types[memory_type_index as usize].property_flags
+        .contains(vk::MemoryPropertyFlags::HOST_COHERENT)
 }
 
 pub(crate) fn copy_buffer(core: &VkCore, cmd_pool: vk::CommandPool, src_buf: vk::Buffer, dest_buf: vk::Buffer,
@@ -57,12 +61,18 @@ pub fn create_buffer(core: &VkCore,
 
     let mem_reqs = unsafe { core.logical_device.get_buffer_memory_requirements(buffer)};
 
+    // Callers can't tell whether the memory is coherent, so those that map it ask for HOST_COHERENT. GpuBuffer::new
+    // also takes non-coherent memory.
     let idx = find_buf_index(core, mem_props, mem_reqs).unwrap();
 
-    // Explicit flushes are required otherwise
     let alloc_info = vk::MemoryAllocateInfo::default()
         .allocation_size(mem_reqs.size)
         .memory_type_index(idx);
+    let mut address_flags = device_address_allocate_flags_info();
+    let alloc_info = match usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) {
+        true => alloc_info.push_next(&mut address_flags),
+        false => alloc_info
+    };
     let buffer_mem = unsafe { core.logical_device.allocate_memory(&alloc_info, None).unwrap()};
     unsafe { core.logical_device.bind_buffer_memory(buffer, buffer_mem, 0).unwrap() };
 
@@ -72,7 +82,41 @@ pub fn create_buffer(core: &VkCore,
 pub struct GpuBuffer {
     pub buf: vk::Buffer,
     pub mem: vk::DeviceMemory,
-    pub item_count: usize
+    pub item_count: usize,
+    pub size: vk::DeviceSize,
+    // False if host writes need flush and device writes invalidate before the other side sees them
+    pub coherent: bool
+}
+
+// Copy of part of a GpuBuffer on its way to the host, see GpuBuffer::read_back_async. wait must be called to free it.
+pub struct Readback {
+    staging: GpuBuffer,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence
+}
+
+impl Readback {
+    pub fn is_ready(&self, core: &VkCore) -> bool {
+        unsafe { core.logical_device.get_fence_status(self.fence).unwrap() }
+    }
+
+    // Blocks until the copy has finished
+    pub fn wait(self, core: &VkCore) -> Vec<u8> {
+        let data = unsafe {
+            core.logical_device.wait_for_fences(&[self.fence], true, u64::MAX).unwrap();
+            let mapped = self.staging.map(core) as *const u8;
+            self.staging.invalidate(core, 0..self.staging.size);
+            let data = std::slice::from_raw_parts(mapped, self.staging.size as usize).to_vec();
+            core.logical_device.unmap_memory(self.staging.mem);
+            core.logical_device.destroy_fence(self.fence, None);
+            core.logical_device.free_command_buffers(self.command_pool, &[self.command_buffer]);
+            data
+        };
+        self.staging.destroy(core);
+
+        data
+    }
 }
 
 impl GpuBuffer {
@@ -89,71 +133,129 @@ impl GpuBuffer {
 
         let mem_reqs = unsafe { core.logical_device.get_buffer_memory_requirements(buffer) };
 
+        // Without HOST_COHERENT in memtype, any host visible type will do, see flush and invalidate
         let idx = find_buf_index(core, memtype, mem_reqs).unwrap();
 
-        // Explicit flushes are required otherwise
         let alloc_info = vk::MemoryAllocateInfo::default()
             .allocation_size(mem_reqs.size)
             .memory_type_index(idx);
+        // The memory of buffers whose address is taken has to be allocated for it, see buffer_device_address
+        let mut address_flags = device_address_allocate_flags_info();
+        let alloc_info = match usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS) {
+            true => alloc_info.push_next(&mut address_flags),
+            false => alloc_info
+        };
         let buffer_mem = unsafe { core.logical_device.allocate_memory(&alloc_info, None).unwrap() };
         unsafe { core.logical_device.bind_buffer_memory(buffer, buffer_mem, 0).unwrap() };
 
         GpuBuffer {
             buf: buffer,
             mem: buffer_mem,
-            item_count: 0
+            item_count: 0,
+            size,
+            coherent: is_coherent(core, idx)
         }
     }
 
     pub fn new_initialized<T>(core: &VkCore, cmd_pool: vk::CommandPool, usage_flags: vk::BufferUsageFlags, items: &[T],
                               memtype: vk::MemoryPropertyFlags) -> GpuBuffer {
-        let data_size: vk::DeviceSize = (mem::size_of::<T>() * items.len()) as vk::DeviceSize;
+        let data_size: vk::DeviceSize = mem::size_of_val(items) as vk::DeviceSize;
         let item_count = items.len();
 
-        let mut host_flags = vk::BufferUsageFlags::empty();
-        let mut host_mem_props = vk::MemoryPropertyFlags::empty();
-        if memtype == vk::MemoryPropertyFlags::DEVICE_LOCAL {
-            host_flags = vk::BufferUsageFlags::TRANSFER_SRC;
-            host_mem_props = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
-        }
-        else {
-            host_flags = usage_flags;
-            host_mem_props = memtype;
-        }
+        let (host_flags, host_mem_props) = match memtype == vk::MemoryPropertyFlags::DEVICE_LOCAL {
+            // Written once, flushed below if needed
+            true => (vk::BufferUsageFlags::TRANSFER_SRC, vk::MemoryPropertyFlags::HOST_VISIBLE),
+            false => (usage_flags, memtype)
+        };
 
-        let (host_mem, host_buf) = create_buffer(core, data_size, host_flags, host_mem_props);
+        let mut host_buf = GpuBuffer::new(core, data_size, host_flags, host_mem_props);
 
         unsafe {
-            let dev_memory = core.logical_device
-                .map_memory(host_mem,
-                            0,
-                            data_size,
-                            vk::MemoryMapFlags::empty())
-                .unwrap() as *mut T;
+            let dev_memory = host_buf.map(core) as *mut T;
             dev_memory.copy_from_nonoverlapping(items.as_ptr(), item_count);
-            unsafe {
-                core.logical_device.unmap_memory(host_mem);
-            }
+            host_buf.flush(core, 0..data_size);
+            core.logical_device.unmap_memory(host_buf.mem);
         }
 
         if memtype == vk::MemoryPropertyFlags::DEVICE_LOCAL {
             let mut device_buf = GpuBuffer::new(core, data_size, usage_flags |
                 vk::BufferUsageFlags::TRANSFER_DST, vk::MemoryPropertyFlags::DEVICE_LOCAL);
-            copy_buffer(core, cmd_pool, host_buf, device_buf.buf, data_size);
+            copy_buffer(core, cmd_pool, host_buf.buf, device_buf.buf, data_size);
             device_buf.item_count = item_count;
-            unsafe {
-                core.logical_device.destroy_buffer(host_buf, None);
-                core.logical_device.free_memory(host_mem, None);
-            }
+            host_buf.destroy(core);
 
             device_buf
         } else {
-            GpuBuffer {
-                buf: host_buf,
-                mem: host_mem,
-                item_count,
-            }
+            host_buf.item_count = item_count;
+            host_buf
+        }
+    }
+
+    // For buffers the host maps and rewrites every frame, like uniform buffers. Device local if core.rebar_policy
+    // allows it, host memory otherwise, see mapped_memory_flags.
+    pub fn new_mapped(core: &VkCore, size: vk::DeviceSize, usage: vk::BufferUsageFlags) -> GpuBuffer {
+        GpuBuffer::new(core, size, usage, mapped_memory_flags(core))
+    }
+
+    // Device local buffer with items for data the host writes once and the GPU reads often, like instance buffers.
+    // Written straight into the buffer if core.rebar_policy allows it, through a staging copy on cmd_pool otherwise.
+    pub fn new_uploaded<T>(core: &VkCore, cmd_pool: vk::CommandPool, usage_flags: vk::BufferUsageFlags, items: &[T])
+        -> GpuBuffer {
+        if !direct_upload(core) {
+            core.upload_counters.record(UploadPath::Staged);
+            return GpuBuffer::new_initialized(core, cmd_pool, usage_flags, items,
+                                              vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        }
+        core.upload_counters.record(UploadPath::Direct);
+        let data_size = mem::size_of_val(items) as vk::DeviceSize;
+        let mut buffer = GpuBuffer::new(core, data_size, usage_flags,
+                                        vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_VISIBLE);
+        unsafe {
+            (buffer.map(core) as *mut T).copy_from_nonoverlapping(items.as_ptr(), items.len());
+            buffer.flush(core, 0..data_size);
+            core.logical_device.unmap_memory(buffer.mem);
         }
+        buffer.item_count = items.len();
+
+        buffer
+    }
+
+    // Maps the whole allocation, which flush and invalidate rely on for ranges that reach the end of the buffer
+    pub fn map(&self, core: &VkCore) -> *mut c_void {
+        unsafe { core.logical_device.map_memory(self.mem, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty()).unwrap() }
+    }
+
+    // Makes host writes to range of the mapped buffer visible to the device, before the submission that reads them.
+    // Does nothing for coherent memory.
+    pub fn flush(&self, core: &VkCore, range: Range<vk::DeviceSize>) {
+        if !self.coherent {
+            let ranges = [self.atom_range(core, range)];
+            unsafe { core.logical_device.flush_mapped_memory_ranges(&ranges).unwrap() };
+        }
+    }
+
+    // Makes device writes to range visible to the host, once the submission that wrote them has finished and before
+    // the host reads the mapped buffer. Does nothing for coherent memory.
+    pub fn invalidate(&self, core: &VkCore, range: Range<vk::DeviceSize>) {
+        if !self.coherent {
+            let ranges = [self.atom_range(core, range)];
+            unsafe { core.logical_device.invalidate_mapped_memory_ranges(&ranges).unwrap() };
+        }
+    }
+
+    // range widened to multiples of nonCoherentAtomSize, or to the end of the mapping when it would pass the buffer's
+    // end, as the allocation may be no larger than the buffer
+    fn atom_range(&self, core: &VkCore, range: Range<vk::DeviceSize>) -> vk::MappedMemoryRange<'_> {
+        assert!(range.start <= range.end && range.end <= self.size, "Invalid range {:?} of a {} byte buffer", range,
+                self.size);
+        let properties = unsafe { core.instance.get_physical_device_properties(core.physical_device) };
+        let atom = properties.limits.non_coherent_atom_size.max(1);
+        let start = range.start / atom * atom;
+        let end = range.end.div_ceil(atom) * atom;
+        vk::MappedMemoryRange::default()
+            .memory(self.mem)
+            .offset(start)
+            .size(if end >= self.size { vk::WHOLE_SIZE } else { end - start })
     }
 
     pub fn destroy(&self, core: &VkCore) {
@@ -163,11 +265,77 @@ impl GpuBuffer {
         }
     }
 
-    pub fn get_device_address(&self, core: &VkCore) -> vk::DeviceAddress {
-        let addr_info = vk::BufferDeviceAddressInfo::default()
-            .buffer(self.buf);
+    // Copies range of the buffer to the host, after all work submitted to the graphics queue so far. Writes from
+    // other queues must be waited on separately. The buffer needs TRANSFER_SRC usage and command_pool has to belong
+    // to the graphics family.
+    pub fn read_back(&self, core: &VkCore, command_pool: vk::CommandPool, range: Range<vk::DeviceSize>) -> Vec<u8> {
+        self.read_back_async(core, command_pool, range).wait(core)
+    }
+
+    // Same as read_back, but returns as soon as the copy is submitted
+    pub fn read_back_async(&self, core: &VkCore, command_pool: vk::CommandPool, range: Range<vk::DeviceSize>)
+        -> Readback {
+        assert!(range.start < range.end && range.end <= self.size, "Invalid readback range {:?} of a {} byte buffer",
+                range, self.size);
+        let size = range.end - range.start;
+        // Any host visible type, wait invalidates it if it isn't coherent
+        let staging = GpuBuffer::new(core, size, vk::BufferUsageFlags::TRANSFER_DST,
+                                     vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        // Any earlier write to the buffer, then the copy before the host reads the staging buffer
+        let src_barrier = vk::BufferMemoryBarrier::default()
+            .buffer(self.buf)
+            .offset(range.start)
+            .size(size)
+            .src_access_mask(vk::AccessFlags::MEMORY_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+        let host_barrier = vk::BufferMemoryBarrier::default()
+            .buffer(staging.buf)
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+        let copy_region = [vk::BufferCopy::default()
+            .src_offset(range.start)
+            .dst_offset(0)
+            .size(size)];
+
         unsafe {
-            core.logical_device.get_buffer_device_address(&addr_info)
+            let command_buffer = core.logical_device.allocate_command_buffers(&alloc_info).unwrap()[0];
+            let fence = core.logical_device.create_fence(&vk::FenceCreateInfo::default(), None).unwrap();
+            core.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
+                                                     vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                     &[], &[src_barrier], &[]);
+            core.logical_device.cmd_copy_buffer(command_buffer, self.buf, staging.buf, &copy_region);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::HOST, vk::DependencyFlags::empty(),
+                                                     &[], &[host_barrier], &[]);
+            core.logical_device.end_command_buffer(command_buffer).unwrap();
+            let command_buffers = [command_buffer];
+            let submit_info = [vk::SubmitInfo::default()
+                .command_buffers(&command_buffers)];
+            core.logical_device.queue_submit(core.graphics_queue, &submit_info, fence).unwrap();
+
+            Readback {
+                staging,
+                command_pool,
+                command_buffer,
+                fence
+            }
         }
     }
+
+    pub fn get_device_address(&self, core: &VkCore) -> vk::DeviceAddress {
+        buffer_device_address(core, self.buf)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,131 @@
+use std::env;
+use std::ffi::CStr;
+use std::str::FromStr;
+
+use ash::{Instance, vk};
+
+use crate::capabilities::instance_api_version;
+use crate::vkcore::load_entry;
+
+// Selects the GPU like --gpu, which takes precedence over it
+pub const GPU_ENV_VAR: &str = "CUBULOUS_GPU";
+
+// Which physical device VkCore renders with. Devices picked by index or name must still support what the renderers
+// need, they just don't have to be discrete.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum GpuSelection {
+    // The first suitable discrete GPU, or the first suitable device of another type if there is none
+    #[default]
+    Auto,
+    Index(usize), // Of enumerate_gpus
+    Name(String) // The first device whose name contains it, ignoring case
+}
+
+// "auto", an index or part of a name
+impl FromStr for GpuSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GpuSelection, String> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(String::from("expected auto, an index or part of a device name"));
+        }
+
+        Ok(match s.parse::<usize>() {
+            Ok(index) => GpuSelection::Index(index),
+            Err(_) if s.eq_ignore_ascii_case("auto") => GpuSelection::Auto,
+            Err(_) => GpuSelection::Name(String::from(s))
+        })
+    }
+}
+
+impl GpuSelection {
+    // From GPU_ENV_VAR, None if it isn't set
+    pub fn from_env() -> Option<GpuSelection> {
+        env::var(GPU_ENV_VAR).ok().and_then(|v| v.parse().ok())
+    }
+
+    pub fn matches(&self, gpu: &GpuInfo) -> bool {
+        match self {
+            GpuSelection::Auto => true,
+            GpuSelection::Index(index) => gpu.index == *index,
+            GpuSelection::Name(name) => gpu.name.to_lowercase().contains(name.to_lowercase().as_str())
+        }
+    }
+}
+
+// A physical device as the driver reports it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GpuInfo {
+    pub index: usize, // In the order the instance enumerates them, which GpuSelection::Index refers to
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    pub driver_version: u32, // Encoded the vendor's way
+    pub vendor_id: u32,
+    pub device_id: u32
+}
+
+impl GpuInfo {
+    pub(crate) fn new(index: usize, properties: &vk::PhysicalDeviceProperties) -> GpuInfo {
+        let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+
+        GpuInfo {
+            index,
+            name: name.to_string_lossy().into_owned(),
+            device_type: properties.device_type,
+            api_version: properties.api_version,
+            driver_version: properties.driver_version,
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => "discrete",
+            vk::PhysicalDeviceType::INTEGRATED_GPU => "integrated",
+            vk::PhysicalDeviceType::VIRTUAL_GPU => "virtual",
+            vk::PhysicalDeviceType::CPU => "cpu",
+            _ => "other"
+        }
+    }
+
+    // One line for listings, like "1: NVIDIA GeForce RTX 3060 Laptop GPU (discrete, Vulkan 1.3.242)"
+    pub fn summary(&self) -> String {
+        format!("{}: {} ({}, Vulkan {}.{}.{})", self.index, self.name, self.type_name(),
+                vk::api_version_major(self.api_version), vk::api_version_minor(self.api_version),
+                vk::api_version_patch(self.api_version))
+    }
+}
+
+pub(crate) fn instance_gpus(instance: &Instance) -> Vec<(vk::PhysicalDevice, GpuInfo)> {
+    let physical_devices = unsafe { instance.enumerate_physical_devices().unwrap() };
+    physical_devices.into_iter().enumerate()
+        .map(|(i, device)| {
+            let properties = unsafe { instance.get_physical_device_properties(device) };
+            (device, GpuInfo::new(i, &properties))
+        })
+        .collect()
+}
+
+// Every physical device, whether the renderers can use it or not, without creating a window. Loads Vulkan like
+// VkCore, from VK_LIB_PATH.
+pub fn enumerate_gpus() -> Result<Vec<GpuInfo>, String> {
+    let entry = load_entry();
+    let app_info = vk::ApplicationInfo::default()
+        .api_version(instance_api_version(&entry));
+    // Required for MacOs compatibility
+    let extensions = [vk::KhrPortabilityEnumerationFn::NAME.as_ptr()];
+    let create_info = vk::InstanceCreateInfo::default()
+        .application_info(&app_info)
+        .enabled_extension_names(&extensions)
+        .flags(vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR);
+    let instance = unsafe { entry.create_instance(&create_info, None) }
+        .map_err(|e| format!("Couldn't create a Vulkan instance: {}", e))?;
+    let gpus = instance_gpus(&instance).into_iter().map(|(_, gpu)| gpu).collect();
+    unsafe { instance.destroy_instance(None) };
+
+    Ok(gpus)
+}
+
@@ -0,0 +1,196 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+use crate::color_pipeline::ColorConstants;
+use crate::frame_constants::{FRAME_CONSTANTS_SET, FrameConstantsBuffer};
+use crate::gpu_buffer::GpuBuffer;
+use crate::raster_pipeline::RasterPipeline;
+use crate::renderutils::cast_to_u8_slice;
+use crate::ssao::create_set_layout;
+use crate::vkcore::VkCore;
+
+pub const GRID_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::G;
+
+// The ground grid is the z = 0 plane, the x axis is highlighted in red and the y axis in green
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridSettings {
+    pub enabled: bool,
+    pub cell_size: f32, // World units between the minor lines
+    pub major_every: u32, // Cells between the major lines
+    pub fade_distance: f32, // From the camera, where the grid has faded out
+    pub color: [f32; 4], // Linear RGB of the lines and their opacity
+    pub axes: bool
+}
+
+impl Default for GridSettings {
+    fn default() -> GridSettings {
+        GridSettings {
+            enabled: false,
+            cell_size: 1.0,
+            major_every: 10,
+            fade_distance: 100.0,
+            color: [0.5, 0.5, 0.5, 0.6],
+            axes: true
+        }
+    }
+}
+
+impl GridSettings {
+    // Sizes are kept positive and there is at least one cell between major lines
+    pub fn validated(&self) -> GridSettings {
+        GridSettings {
+            cell_size: self.cell_size.max(f32::EPSILON),
+            major_every: self.major_every.max(1),
+            fade_distance: self.fade_distance.max(f32::EPSILON),
+            ..*self
+        }
+    }
+}
+
+// Matches GridUniforms in grid.frag, the camera comes from the frame constants
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct GridUniforms {
+    line_color: [f32; 4],
+    params: [f32; 4]
+}
+
+// An infinite ground grid for spatial reference, computed per pixel from the view ray so that it covers whatever the
+// frustum sees. Lines stay a pixel wide at any distance and fade out towards fade_distance. Drawn in the forward render
+// pass after the sky, only from the main camera, so water doesn't reflect it.
+pub struct Grid {
+    settings: GridSettings,
+    uniform_buffers: Vec<GpuBuffer>, // Per frame in flight
+    mapped: Vec<*mut c_void>,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    pipeline: RasterPipeline
+}
+
+impl Grid {
+    pub fn new(core: &VkCore, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags,
+               frame_constants: &FrameConstantsBuffer, settings: GridSettings, max_frames: usize) -> Grid {
+        let uniform_size = mem::size_of::<GridUniforms>() as vk::DeviceSize;
+        let uniform_buffers: Vec<GpuBuffer> = (0..max_frames)
+            .map(|_| GpuBuffer::new_mapped(core, uniform_size, vk::BufferUsageFlags::UNIFORM_BUFFER))
+            .collect();
+        let mapped: Vec<*mut c_void> = uniform_buffers.iter()
+            .map(|b| unsafe {
+                core.logical_device.map_memory(b.mem, 0, uniform_size, vk::MemoryMapFlags::empty()).unwrap()
+            })
+            .collect();
+
+        let set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::FRAGMENT)
+        ]);
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(max_frames as u32)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        for (set, buffer) in sets.iter().zip(uniform_buffers.iter()) {
+            let uniform_info = [vk::DescriptorBufferInfo::default()
+                .buffer(buffer.buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write = [vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&uniform_info)];
+            unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+        }
+
+        Grid {
+            settings: settings.validated(),
+            uniform_buffers,
+            mapped,
+            set_layout,
+            descriptor_pool,
+            sets,
+            pipeline: RasterPipeline::new_grid(core, render_pass, frame_constants.set_layout(), set_layout,
+                                               msaa_samples)
+        }
+    }
+
+    // Applied from the next update
+    pub fn set_settings(&mut self, settings: GridSettings) {
+        self.settings = settings.validated();
+    }
+
+    pub fn settings(&self) -> GridSettings {
+        self.settings
+    }
+
+    // Toggles the grid with GRID_TOGGLE_KEY
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(GRID_TOGGLE_KEY), .. },
+            ..
+        } = event {
+            self.settings.enabled = !self.settings.enabled;
+        }
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> &'static str {
+        match self.settings.enabled {
+            true => "grid on",
+            false => "grid off"
+        }
+    }
+
+    // Once the frame's fence has been waited on
+    pub fn update(&self, current_frame: usize) {
+        let s = self.settings;
+        let uniforms = GridUniforms {
+            line_color: s.color,
+            params: [s.cell_size, s.major_every as f32, s.axes as u32 as f32, s.fade_distance]
+        };
+        unsafe { (self.mapped[current_frame] as *mut GridUniforms).write(uniforms) };
+    }
+
+    // Inside the forward render pass after the sky, once the viewport and scissor are set. The grid is seen from the
+    // frame constants' camera. Records nothing while disabled, leaves the grid's pipeline bound otherwise.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame_constants: &FrameConstantsBuffer,
+                    color_constants: &ColorConstants, current_frame: usize) {
+        if !self.settings.enabled {
+            return;
+        }
+        let layout = self.pipeline.pipeline_layout;
+        frame_constants.cmd_bind(core, command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, FRAME_CONSTANTS_SET,
+                                 current_frame);
+        unsafe {
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                  self.pipeline.pipelines[0]);
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 1,
+                                                         &[self.sets[current_frame]], &[]);
+            core.logical_device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                                   cast_to_u8_slice(color_constants));
+            core.logical_device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+        for b in self.uniform_buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+    }
+}
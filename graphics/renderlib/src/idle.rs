@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+use winit::event::WindowEvent;
+use winit::event_loop::ControlFlow;
+
+use crate::settings::IdleBehavior;
+
+// A focused window without input for this long is idle
+const IDLE_TIMEOUT: Duration = Duration::from_secs(2);
+// While idle the event loop still wakes up this often, RendererProxy commands don't wake it
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Decides when a renderer may stop rendering to save power. The window is idle while unfocused, or when there was
+// no input and no RendererCommand for IDLE_TIMEOUT. Then, depending on IdleBehavior, the event loop waits instead of
+// polling and redraws are skipped or limited to a reduced rate. Any activity resumes rendering at full speed from
+// the next frame. Redraws the window system requests, after a resize for example, are always drawn.
+pub struct IdleTracker {
+    focused: bool,
+    last_activity: Instant,
+    last_redraw: Instant,
+    idle: bool // Logged on changes
+}
+
+impl Default for IdleTracker {
+    fn default() -> IdleTracker {
+        let now = Instant::now();
+        IdleTracker {
+            focused: true,
+            last_activity: now,
+            last_redraw: now,
+            idle: false
+        }
+    }
+}
+
+impl IdleTracker {
+    // For activity that isn't a window event, like processed commands or raw mouse motion
+    pub fn mark_active(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::Focused(focused) => {
+                self.focused = *focused;
+                self.mark_active();
+            },
+            WindowEvent::KeyboardInput { .. } | WindowEvent::MouseInput { .. } | WindowEvent::MouseWheel { .. } |
+            WindowEvent::CursorMoved { .. } | WindowEvent::Touch(_) | WindowEvent::Resized(_) |
+            WindowEvent::ScaleFactorChanged { .. } => self.mark_active(),
+            _ => ()
+        }
+    }
+
+    pub fn is_idle(&self, behavior: IdleBehavior) -> bool {
+        behavior != IdleBehavior::Continuous && (!self.focused || self.last_activity.elapsed() >= IDLE_TIMEOUT)
+    }
+
+    // Once per event loop iteration, at Event::MainEventsCleared. Sets how long the loop may wait for events and
+    // returns whether a frame should be drawn.
+    pub fn next_frame(&mut self, behavior: IdleBehavior, control_flow: &mut ControlFlow) -> bool {
+        let idle = self.is_idle(behavior);
+        if idle != self.idle {
+            debug!(idle, focused = self.focused, ?behavior, "Idle state changed");
+            self.idle = idle;
+        }
+
+        let now = Instant::now();
+        let poll = now + COMMAND_POLL_INTERVAL;
+        let redraw = match (idle, behavior) {
+            (false, _) | (true, IdleBehavior::Continuous) => {
+                control_flow.set_poll();
+                true
+            },
+            (true, IdleBehavior::Pause) => {
+                control_flow.set_wait_until(poll);
+                false
+            },
+            (true, IdleBehavior::Reduced { fps }) => {
+                let interval = Duration::from_secs_f64(1.0 / fps.max(0.1) as f64);
+                let redraw = self.last_redraw + interval <= now;
+                let last_redraw = if redraw { now } else { self.last_redraw };
+                control_flow.set_wait_until((last_redraw + interval).min(poll));
+                redraw
+            }
+        };
+        if redraw {
+            self.last_redraw = now;
+        }
+
+        redraw
+    }
+}
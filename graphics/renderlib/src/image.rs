@@ -1,28 +1,102 @@
 use ash::vk;
+use crate::depth::has_stencil;
 use crate::gpu_buffer::find_buf_index;
 use crate::single_time::{begin_single_time_commands, end_single_time_commands};
 use crate::vkcore::VkCore;
 
+// What create_image_with creates. ImageInfo::new starts from a single layer and sample, optimally tiled device local
+// image that only one queue family uses.
+#[derive(Clone, Copy)]
+pub struct ImageInfo<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub mip_levels: u32,
+    pub array_layers: u32, // Stereo eyes, cube faces, etc.
+    pub format: vk::Format,
+    pub tiling: vk::ImageTiling,
+    pub usage: vk::ImageUsageFlags,
+    pub properties: vk::MemoryPropertyFlags,
+    pub samples: vk::SampleCountFlags,
+    // Usable from every family without ownership transfers. Duplicates are ignored, when only one family is left the
+    // image is exclusive.
+    pub queue_families: &'a [u32],
+    pub flags: vk::ImageCreateFlags
+}
+
+impl<'a> ImageInfo<'a> {
+    pub fn new(width: u32, height: u32, format: vk::Format, usage: vk::ImageUsageFlags) -> ImageInfo<'a> {
+        ImageInfo {
+            width,
+            height,
+            mip_levels: 1,
+            array_layers: 1,
+            format,
+            tiling: vk::ImageTiling::OPTIMAL,
+            usage,
+            properties: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            samples: vk::SampleCountFlags::TYPE_1,
+            queue_families: &[],
+            flags: vk::ImageCreateFlags::empty()
+        }
+    }
+}
+
 pub fn create_image(core: &VkCore, width: u32, height: u32, mip_levels: u32, format: vk::Format,
                     tiling: vk::ImageTiling, usage: vk::ImageUsageFlags,
                     properties: vk::MemoryPropertyFlags, samples: vk::SampleCountFlags)
     -> (vk::Image, vk::DeviceMemory) {
+    create_image_with(core, &ImageInfo {
+        mip_levels,
+        tiling,
+        properties,
+        samples,
+        ..ImageInfo::new(width, height, format, usage)
+    })
+}
+
+// Same as create_image, but its views may have any format of the same size, like UNORM views of an SRGB image.
+// usage only has to be supported by the views' formats, not by format itself.
+pub fn create_mutable_image(core: &VkCore, width: u32, height: u32, format: vk::Format, tiling: vk::ImageTiling,
+                            usage: vk::ImageUsageFlags, properties: vk::MemoryPropertyFlags)
+    -> (vk::Image, vk::DeviceMemory) {
+    create_image_with(core, &ImageInfo {
+        tiling,
+        properties,
+        flags: vk::ImageCreateFlags::MUTABLE_FORMAT | vk::ImageCreateFlags::EXTENDED_USAGE,
+        ..ImageInfo::new(width, height, format, usage)
+    })
+}
+
+pub fn create_image_with(core: &VkCore, info: &ImageInfo) -> (vk::Image, vk::DeviceMemory) {
+    let mut family_indices: Vec<u32> = Vec::new();
+    for &f in info.queue_families {
+        if !family_indices.contains(&f) {
+            family_indices.push(f);
+        }
+    }
+
     let image_extent = vk::Extent3D::default()
-        .height(height)
-        .width(width)
+        .height(info.height)
+        .width(info.width)
         .depth(1);
 
     let image_info = vk::ImageCreateInfo::default()
-        .flags(vk::ImageCreateFlags::empty())
+        .flags(info.flags)
         .extent(image_extent)
-        .mip_levels(mip_levels)
+        .mip_levels(info.mip_levels)
         .image_type(vk::ImageType::TYPE_2D)
-        .array_layers(1)
-        .format(format)
-        .tiling(tiling)
+        .array_layers(info.array_layers)
+        .format(info.format)
+        .tiling(info.tiling)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .usage(usage) // Sampled allows access from shader
-        .samples(samples);
+        .usage(info.usage) // Sampled allows access from shader
+        .samples(info.samples);
+    let image_info = match family_indices.len() {
+        0 | 1 => image_info.sharing_mode(vk::SharingMode::EXCLUSIVE),
+        _ => image_info
+            .sharing_mode(vk::SharingMode::CONCURRENT)
+            .queue_family_indices(family_indices.as_slice())
+    };
 
     let mem_reqs: vk::MemoryRequirements;
     let texture_image: vk::Image;
@@ -32,9 +106,21 @@ pub fn create_image(core: &VkCore, width: u32, height: u32, mip_levels: u32, for
         mem_reqs = core.logical_device.get_image_memory_requirements(texture_image);
     }
 
+    // Lazily allocated memory doesn't suit every transient attachment's format and samples, device local memory does
+    let memory_type_index = find_buf_index(core, info.properties, mem_reqs)
+        .or_else(|_| find_buf_index(core, info.properties & !vk::MemoryPropertyFlags::LAZILY_ALLOCATED, mem_reqs))
+        .unwrap();
     let alloc_info = vk::MemoryAllocateInfo::default()
-        .memory_type_index(find_buf_index(core, properties, mem_reqs).unwrap())
+        .memory_type_index(memory_type_index)
         .allocation_size(mem_reqs.size);
+    // An instance on every GPU of the group, which the others can read as peer memory
+    #[cfg(feature = "device-group")]
+    let mut group_flags = core.device_group.as_ref().map(|g| g.memory_allocate_flags_info());
+    #[cfg(feature = "device-group")]
+    let alloc_info = match group_flags.as_mut() {
+        Some(flags) => alloc_info.push_next(flags),
+        None => alloc_info
+    };
 
     let texture_mem = unsafe { core.logical_device.allocate_memory(&alloc_info, None).unwrap() };
     unsafe { core.logical_device.bind_image_memory(texture_image, texture_mem, 0).unwrap() };
@@ -42,10 +128,6 @@ pub fn create_image(core: &VkCore, width: u32, height: u32, mip_levels: u32, for
     (texture_image, texture_mem)
 }
 
-fn has_stencil_component(format: vk::Format) -> bool {
-    format == vk::Format::D32_SFLOAT_S8_UINT || format == vk::Format::D24_UNORM_S8_UINT
-}
-
 pub(crate) fn transition_image_layout(core: &VkCore,
                            command_pool: vk::CommandPool,
                            image: vk::Image,
@@ -53,11 +135,20 @@ pub(crate) fn transition_image_layout(core: &VkCore,
                            old_layout: vk::ImageLayout,
                            new_layout: vk::ImageLayout,
                                       mip_levels: u32) {
+    let command_buffer = begin_single_time_commands(core, command_pool);
+    cmd_transition_image_layout(core, command_buffer, image, format, old_layout, new_layout, mip_levels);
+    end_single_time_commands(core, command_pool, command_buffer);
+}
+
+// Like transition_image_layout, recorded into command_buffer, e.g. one of a SingleTimeContext
+pub(crate) fn cmd_transition_image_layout(core: &VkCore, command_buffer: vk::CommandBuffer, image: vk::Image,
+                                          format: vk::Format, old_layout: vk::ImageLayout,
+                                          new_layout: vk::ImageLayout, mip_levels: u32) {
     let mut aspect_mask = vk::ImageAspectFlags::COLOR;
     if new_layout == vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL {
         aspect_mask = vk::ImageAspectFlags::DEPTH;
 
-        if has_stencil_component(format) {
+        if has_stencil(format) {
             aspect_mask |= vk::ImageAspectFlags::STENCIL;
         }
     }
@@ -104,21 +195,18 @@ pub(crate) fn transition_image_layout(core: &VkCore,
 
     let barrier_arr = [barrier];
 
-    let commmand_buffer = begin_single_time_commands(core, command_pool);
-
-    unsafe { core.logical_device.cmd_pipeline_barrier(commmand_buffer,
+    unsafe { core.logical_device.cmd_pipeline_barrier(command_buffer,
                                                                source_stage,
                                                                dest_stage,
                                                                vk::DependencyFlags::empty(),
                                                                &[],
                                                                &[],
                                                                &barrier_arr); }
-
-    end_single_time_commands(core, command_pool, commmand_buffer);
 }
 
-pub(crate) fn copy_buffer_to_image(core: &VkCore, command_pool: vk::CommandPool,
-                        buffer: vk::Buffer, image: vk::Image, width: u32, height: u32) {
+// Into level 0, which must be in TRANSFER_DST_OPTIMAL
+pub(crate) fn cmd_copy_buffer_to_image(core: &VkCore, command_buffer: vk::CommandBuffer, buffer: vk::Buffer,
+                                       image: vk::Image, width: u32, height: u32) {
     let sub_resource_layers = vk::ImageSubresourceLayers::default()
         .mip_level(0)
         .base_array_layer(0)
@@ -140,25 +228,34 @@ pub(crate) fn copy_buffer_to_image(core: &VkCore, command_pool: vk::CommandPool,
         .image_offset(image_offset)
         .image_extent(image_extent)];
 
-    let command_buffer = begin_single_time_commands(core, command_pool);
     unsafe { core.logical_device.cmd_copy_buffer_to_image(command_buffer, buffer,
                                                                    image,
                                                                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
                                                                    &region); }
-    end_single_time_commands(core, command_pool, command_buffer);
 }
 
 pub fn create_image_view(core: &VkCore, image: vk::Image, format: vk::Format,
                          aspect_flags: vk::ImageAspectFlags, mip_levels: u32) -> vk::ImageView {
+    create_image_view_array(core, image, format, aspect_flags, mip_levels, 1)
+}
+
+// Views with more than one layer are created as 2D arrays so that shaders and multiview render passes can index them
+pub fn create_image_view_array(core: &VkCore, image: vk::Image, format: vk::Format,
+                               aspect_flags: vk::ImageAspectFlags, mip_levels: u32,
+                               array_layers: u32) -> vk::ImageView {
+    let view_type = match array_layers {
+        1 => vk::ImageViewType::TYPE_2D,
+        _ => vk::ImageViewType::TYPE_2D_ARRAY
+    };
     let subresource_range = vk::ImageSubresourceRange::default()
         .aspect_mask(aspect_flags)
         .base_mip_level(0)
         .level_count(mip_levels)
         .base_array_layer(0)
-        .layer_count(1);
+        .layer_count(array_layers);
     let view_info = vk::ImageViewCreateInfo::default()
         .image(image)
-        .view_type(vk::ImageViewType::TYPE_2D)
+        .view_type(view_type)
         .format(format)
         .subresource_range(subresource_range);
 
@@ -166,4 +263,64 @@ pub fn create_image_view(core: &VkCore, image: vk::Image, format: vk::Format,
         .create_image_view(&view_info, None)
         .unwrap()
     }
-}
\ No newline at end of file
+}
+// Single sample color image for compute passes, kept in the GENERAL layout for its whole lifetime
+pub struct StorageImage {
+    pub image: vk::Image,
+    mem: vk::DeviceMemory,
+    pub view: vk::ImageView
+}
+
+impl StorageImage {
+    // extra_usage is added to STORAGE, e.g. TRANSFER_SRC for images that get blitted
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D, format: vk::Format,
+               extra_usage: vk::ImageUsageFlags) -> StorageImage {
+        let (image, mem) = create_image(core, extent.width, extent.height, 1, format, vk::ImageTiling::OPTIMAL,
+                                        vk::ImageUsageFlags::STORAGE | extra_usage,
+                                        vk::MemoryPropertyFlags::DEVICE_LOCAL, vk::SampleCountFlags::TYPE_1);
+        let view = create_image_view(core, image, format, vk::ImageAspectFlags::COLOR, 1);
+
+        let barrier = vk::ImageMemoryBarrier::default()
+            .image(image)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(vk::ImageSubresourceRange::default()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(0)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1));
+        let command_buffer = begin_single_time_commands(core, command_pool);
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                     vk::PipelineStageFlags::ALL_COMMANDS,
+                                                     vk::DependencyFlags::empty(), &[], &[], &[barrier]);
+        }
+        end_single_time_commands(core, command_pool, command_buffer);
+
+        StorageImage {
+            image,
+            mem,
+            view
+        }
+    }
+
+    // Bind as a STORAGE_IMAGE descriptor
+    pub fn info(&self) -> [vk::DescriptorImageInfo; 1] {
+        [vk::DescriptorImageInfo::default()
+            .image_view(self.view)
+            .image_layout(vk::ImageLayout::GENERAL)]
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_image_view(self.view, None);
+            core.logical_device.destroy_image(self.image, None);
+            core.logical_device.free_memory(self.mem, None);
+        }
+    }
+}
@@ -1,8 +1,173 @@
 use std::mem;
 
+use ash::vk;
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::vkcore::VkCore;
+
 #[repr(C)]
 #[derive(Clone, Debug, Copy)]
 pub struct Index {
     pub data: [u16; 12]
 }
 
+// Integer types that can be bound as index data, maps each to its Vulkan index type
+pub trait IndexElement: Copy {
+    const INDEX_TYPE: vk::IndexType;
+
+    fn to_u32(self) -> u32;
+}
+
+// Needs VK_EXT_index_type_uint8, see VkCore::index_type_uint8_supported
+impl IndexElement for u8 {
+    const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT8_EXT;
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+impl IndexElement for u16 {
+    const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT16;
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+impl IndexElement for u32 {
+    const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT32;
+
+    fn to_u32(self) -> u32 {
+        self
+    }
+}
+
+// 0xFF is the 8 bit restart value, so it becomes 0xFFFF rather than a valid 16 bit index
+pub fn widen_u8_indices(indices: &[u8]) -> Vec<u16> {
+    indices.iter().map(|&i| match i {
+        u8::MAX => u16::MAX,
+        _ => i as u16
+    }).collect()
+}
+
+// Returns the indices as u16 if every one of them fits, None otherwise. 0xFFFF is left out since it is the primitive
+// restart value for 16 bit indices.
+pub fn narrow_indices(indices: &[u32]) -> Option<Vec<u16>> {
+    match indices.iter().all(|&i| i < u16::MAX as u32) {
+        true => Some(indices.iter().map(|&i| i as u16).collect()),
+        false => None
+    }
+}
+
+// Same as narrow_indices for 8 bit indices, 0xFF is left out for the same reason
+pub fn narrow_indices_u8(indices: &[u32]) -> Option<Vec<u8>> {
+    match indices.iter().all(|&i| i < u8::MAX as u32) {
+        true => Some(indices.iter().map(|&i| i as u8).collect()),
+        false => None
+    }
+}
+
+// Device index data that remembers its element type, so binds and acceleration structure builds always agree with
+// the data layout
+pub struct IndexBuffer {
+    pub buffer: GpuBuffer,
+    pub index_type: vk::IndexType
+}
+
+impl IndexBuffer {
+    // Stores the indices in the smallest type the device supports that fits the model, which cuts the memory and
+    // bandwidth needed
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, usage: vk::BufferUsageFlags, indices: &[u32],
+               memtype: vk::MemoryPropertyFlags) -> IndexBuffer {
+        if core.index_type_uint8_supported {
+            if let Some(narrow) = narrow_indices_u8(indices) {
+                return IndexBuffer::new_typed(core, command_pool, usage, narrow.as_slice(), memtype);
+            }
+        }
+
+        match narrow_indices(indices) {
+            Some(narrow) => IndexBuffer::new_typed(core, command_pool, usage, narrow.as_slice(), memtype),
+            None => IndexBuffer::new_typed(core, command_pool, usage, indices, memtype)
+        }
+    }
+
+    // Falls back to u16 indices when VK_EXT_index_type_uint8 is not enabled
+    pub fn new_u8(core: &VkCore, command_pool: vk::CommandPool, usage: vk::BufferUsageFlags, indices: &[u8],
+                  memtype: vk::MemoryPropertyFlags) -> IndexBuffer {
+        match core.index_type_uint8_supported {
+            true => IndexBuffer::new_typed(core, command_pool, usage, indices, memtype),
+            false => IndexBuffer::new_typed(core, command_pool, usage, widen_u8_indices(indices).as_slice(), memtype)
+        }
+    }
+
+    // T must not be u8 unless VK_EXT_index_type_uint8 is enabled, use new_u8 for those
+    pub fn new_typed<T: IndexElement>(core: &VkCore, command_pool: vk::CommandPool, usage: vk::BufferUsageFlags,
+                                      indices: &[T], memtype: vk::MemoryPropertyFlags) -> IndexBuffer {
+        IndexBuffer {
+            buffer: GpuBuffer::new_initialized(core, command_pool, usage, indices, memtype),
+            index_type: T::INDEX_TYPE
+        }
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.buffer.item_count as u32
+    }
+
+    pub fn index_size(&self) -> usize {
+        match self.index_type {
+            vk::IndexType::UINT8_EXT => mem::size_of::<u8>(),
+            vk::IndexType::UINT16 => mem::size_of::<u16>(),
+            vk::IndexType::UINT32 => mem::size_of::<u32>(),
+            _ => panic!("Unsupported index type {:?}", self.index_type)
+        }
+    }
+
+    pub fn cmd_bind(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        unsafe { core.logical_device.cmd_bind_index_buffer(command_buffer, self.buffer.buf, 0, self.index_type) };
+    }
+
+    pub fn get_device_address(&self, core: &VkCore) -> vk::DeviceAddress {
+        self.buffer.get_device_address(core)
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.buffer.destroy(core);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widen_u8_keeps_values() {
+        assert_eq!(widen_u8_indices(&[0, 1, 2, 254]), vec![0u16, 1, 2, 254]);
+    }
+
+    #[test]
+    fn widen_u8_maps_restart_value() {
+        assert_eq!(widen_u8_indices(&[3, u8::MAX, 4]), vec![3u16, u16::MAX, 4]);
+    }
+
+    #[test]
+    fn narrow_u8_only_when_all_fit() {
+        assert_eq!(narrow_indices_u8(&[0, 17, 254]), Some(vec![0u8, 17, 254]));
+        assert_eq!(narrow_indices_u8(&[0, 255]), None);
+        assert_eq!(narrow_indices_u8(&[0, 1000]), None);
+    }
+
+    #[test]
+    fn narrow_u16_only_when_all_fit() {
+        assert_eq!(narrow_indices(&[0, 300, 65534]), Some(vec![0u16, 300, 65534]));
+        assert_eq!(narrow_indices(&[0, 65535]), None);
+        assert_eq!(narrow_indices(&[70000]), None);
+    }
+
+    #[test]
+    fn index_types_match_element_size() {
+        assert_eq!(<u8 as IndexElement>::INDEX_TYPE, vk::IndexType::UINT8_EXT);
+        assert_eq!(<u16 as IndexElement>::INDEX_TYPE, vk::IndexType::UINT16);
+        assert_eq!(<u32 as IndexElement>::INDEX_TYPE, vk::IndexType::UINT32);
+    }
+}
@@ -0,0 +1,242 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+use winit::dpi::PhysicalSize;
+use winit::event::{ElementState, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+
+// Keys that keep their name in recordings, anything else is replayed by scancode only
+macro_rules! named_keys {
+    ($($key:ident),*) => {
+        fn key_name(key: VirtualKeyCode) -> Option<&'static str> {
+            match key {
+                $(VirtualKeyCode::$key => Some(stringify!($key)),)*
+                _ => None
+            }
+        }
+
+        fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+            match name {
+                $(stringify!($key) => Some(VirtualKeyCode::$key),)*
+                _ => None
+            }
+        }
+    };
+}
+
+named_keys!(A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+            Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+            F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+            Up, Down, Left, Right, Space, Return, Escape, Tab, Back, LShift, RShift, LControl, RControl, LAlt, RAlt);
+
+// The subset of winit window events that affects rendering, in a form that can be written to and read from a file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    Key { scancode: u32, key: Option<VirtualKeyCode>, pressed: bool },
+    CursorMoved { x: f64, y: f64 }, // Physical pixels
+    MouseButton { button: MouseButton, pressed: bool },
+    WheelLines { x: f32, y: f32 },
+    WheelPixels { x: f64, y: f64 },
+    MouseMotion { x: f64, y: f64 } // Pixels while the cursor is captured, summed over a frame, see CursorCapture
+}
+
+fn parse_state(state: &str) -> Result<bool, String> {
+    match state {
+        "down" => Ok(true),
+        "up" => Ok(false),
+        _ => Err(format!("expected down or up, found {}", state))
+    }
+}
+
+fn state_name(pressed: bool) -> &'static str {
+    match pressed {
+        true => "down",
+        false => "up"
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(value: Option<&str>) -> Result<T, String> {
+    let value = value.ok_or(String::from("missing value"))?;
+    value.parse::<T>().map_err(|_| format!("invalid value {}", value))
+}
+
+impl InputEvent {
+    pub fn from_window_event(event: &WindowEvent) -> Option<InputEvent> {
+        match event {
+            WindowEvent::KeyboardInput { input: KeyboardInput { scancode, state, virtual_keycode, .. }, .. } =>
+                Some(InputEvent::Key {
+                    scancode: *scancode,
+                    key: *virtual_keycode,
+                    pressed: *state == ElementState::Pressed
+                }),
+            WindowEvent::CursorMoved { position, .. } => Some(InputEvent::CursorMoved { x: position.x, y: position.y }),
+            WindowEvent::MouseInput { state, button, .. } => Some(InputEvent::MouseButton {
+                button: *button,
+                pressed: *state == ElementState::Pressed
+            }),
+            WindowEvent::MouseWheel { delta: MouseScrollDelta::LineDelta(x, y), .. } =>
+                Some(InputEvent::WheelLines { x: *x, y: *y }),
+            WindowEvent::MouseWheel { delta: MouseScrollDelta::PixelDelta(p), .. } =>
+                Some(InputEvent::WheelPixels { x: p.x, y: p.y }),
+            _ => None
+        }
+    }
+
+    fn to_line(self) -> String {
+        match self {
+            InputEvent::Key { scancode, key, pressed } => {
+                let name = key.and_then(key_name).unwrap_or("-");
+                format!("key {} {} {}", scancode, name, state_name(pressed))
+            },
+            InputEvent::CursorMoved { x, y } => format!("cursor {} {}", x, y),
+            InputEvent::MouseButton { button, pressed } => {
+                let name = match button {
+                    MouseButton::Left => String::from("left"),
+                    MouseButton::Right => String::from("right"),
+                    MouseButton::Middle => String::from("middle"),
+                    MouseButton::Other(n) => n.to_string()
+                };
+                format!("button {} {}", name, state_name(pressed))
+            },
+            InputEvent::WheelLines { x, y } => format!("wheel_lines {} {}", x, y),
+            InputEvent::WheelPixels { x, y } => format!("wheel_pixels {} {}", x, y),
+            InputEvent::MouseMotion { x, y } => format!("motion {} {}", x, y)
+        }
+    }
+
+    fn from_fields(fields: &[&str]) -> Result<InputEvent, String> {
+        let mut values = fields.iter().skip(1).copied();
+        let event = match fields.first().copied() {
+            Some("key") => {
+                let scancode = parse_value::<u32>(values.next())?;
+                let key = key_from_name(values.next().ok_or(String::from("missing key name"))?);
+                InputEvent::Key { scancode, key, pressed: parse_state(values.next().unwrap_or(""))? }
+            },
+            Some("cursor") => InputEvent::CursorMoved { x: parse_value(values.next())?, y: parse_value(values.next())? },
+            Some("button") => {
+                let button = match values.next() {
+                    Some("left") => MouseButton::Left,
+                    Some("right") => MouseButton::Right,
+                    Some("middle") => MouseButton::Middle,
+                    other => MouseButton::Other(parse_value::<u16>(other)?)
+                };
+                InputEvent::MouseButton { button, pressed: parse_state(values.next().unwrap_or(""))? }
+            },
+            Some("wheel_lines") => InputEvent::WheelLines { x: parse_value(values.next())?, y: parse_value(values.next())? },
+            Some("wheel_pixels") => InputEvent::WheelPixels { x: parse_value(values.next())?, y: parse_value(values.next())? },
+            Some("motion") => InputEvent::MouseMotion {
+                x: parse_value(values.next())?,
+                y: parse_value(values.next())?
+            },
+            Some(kind) => return Err(format!("unknown event {}", kind)),
+            None => return Err(String::from("missing event"))
+        };
+
+        Ok(event)
+    }
+}
+
+// Writes input events during an interactive session. Each line is "frame time_ms event...", where frame is the
+// number of frames drawn before the event arrived. Replays apply events by frame, the time is only informational.
+pub struct InputRecorder {
+    writer: BufWriter<File>,
+    start: Instant
+}
+
+impl InputRecorder {
+    // size is the window size at the start of the session, replays are only comparable at the same size
+    pub fn new(path: &str, size: PhysicalSize<u32>) -> Result<InputRecorder, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "# frame time_ms event").unwrap();
+        writeln!(writer, "size {} {}", size.width, size.height).unwrap();
+
+        Ok(InputRecorder {
+            writer,
+            start: Instant::now()
+        })
+    }
+
+    // Events that don't affect rendering are skipped
+    pub fn record(&mut self, frame: u64, event: &WindowEvent) {
+        if let Some(input) = InputEvent::from_window_event(event) {
+            self.record_input(frame, input);
+        }
+    }
+
+    // For input that doesn't come from a window event, like InputEvent::MouseMotion
+    pub fn record_input(&mut self, frame: u64, input: InputEvent) {
+        let time_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        writeln!(self.writer, "{} {:.3} {}", frame, time_ms, input.to_line()).unwrap();
+    }
+
+    // Marks the frame the session ended on so replays render the same number of frames
+    pub fn finish(&mut self, frame: u64) {
+        writeln!(self.writer, "end {}", frame).unwrap();
+        self.writer.flush().unwrap();
+    }
+}
+
+pub struct InputReplay {
+    pub size: Option<PhysicalSize<u32>>, // Window size of the recorded session
+    pub frame_count: u64, // Frames to render, from the end line or one past the last event
+    events: Vec<(u64, InputEvent)>,
+    next: usize
+}
+
+impl InputReplay {
+    pub fn load(path: &str) -> Result<InputReplay, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let mut size: Option<PhysicalSize<u32>> = None;
+        let mut end: Option<u64> = None;
+        let mut events: Vec<(u64, InputEvent)> = Vec::new();
+        for (line_idx, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields[0] {
+                "size" => parse_value::<u32>(fields.get(1).copied())
+                    .and_then(|w| Ok(PhysicalSize::new(w, parse_value::<u32>(fields.get(2).copied())?)))
+                    .map(|s| size = Some(s)),
+                "end" => parse_value::<u64>(fields.get(1).copied()).map(|f| end = Some(f)),
+                _ => parse_value::<u64>(fields.first().copied())
+                    .and_then(|frame| Ok((frame, InputEvent::from_fields(fields.get(2..).unwrap_or(&[]))?)))
+                    .map(|e| events.push(e))
+            };
+            if let Err(e) = parsed {
+                return Err(format!("{}:{}: {}", path, line_idx + 1, e));
+            }
+        }
+
+        // Recordings are written in frame order, but keep hand edited files working
+        events.sort_by_key(|(frame, _)| *frame);
+        let frame_count = match end {
+            Some(f) => f,
+            None => events.last().map_or(0, |(frame, _)| frame + 1)
+        };
+
+        Ok(InputReplay {
+            size,
+            frame_count,
+            events,
+            next: 0
+        })
+    }
+
+    // Events to apply before drawing frame, in recorded order. Frames must be requested in increasing order.
+    pub fn events_for_frame(&mut self, frame: u64) -> Vec<InputEvent> {
+        let start = self.next;
+        while self.next < self.events.len() && self.events[self.next].0 <= frame {
+            self.next += 1;
+        }
+
+        self.events[start..self.next].iter().map(|(_, e)| *e).collect()
+    }
+
+    pub fn is_finished(&self, frames_drawn: u64) -> bool {
+        frames_drawn >= self.frame_count
+    }
+}
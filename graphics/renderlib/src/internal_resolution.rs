@@ -0,0 +1,236 @@
+use ash::vk;
+use clap::ValueEnum;
+
+use crate::config::Resolution;
+use crate::vkcore::VkCore;
+
+// How the rendered image is scaled to the window
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum UpscaleFilter {
+    Nearest,
+    #[default]
+    Linear,
+    // Nearest, at the largest whole multiple of the rendered size that fits, so that pixel art stays even. Falls back
+    // to Nearest when the window is smaller than the rendered image.
+    Integer,
+    // Edge adaptive upsampling with sharpening in compute, see Upscaler. Keeps edges crisp when the rendered image is
+    // well below the window's size, and falls back to Linear when it isn't smaller.
+    EdgeAdaptive
+}
+
+impl UpscaleFilter {
+    pub fn vk_filter(&self) -> vk::Filter {
+        match self {
+            UpscaleFilter::Linear | UpscaleFilter::EdgeAdaptive => vk::Filter::LINEAR,
+            UpscaleFilter::Nearest | UpscaleFilter::Integer => vk::Filter::NEAREST
+        }
+    }
+}
+
+// An image in the layout it is blitted in, extent is its size
+#[derive(Clone, Copy)]
+pub struct BlitImage {
+    pub image: vk::Image,
+    pub layout: vk::ImageLayout,
+    pub extent: vk::Extent2D
+}
+
+// Stops below the strongest sharpening, the default of --sharpness
+pub const DEFAULT_SHARPNESS: f32 = 0.2;
+
+// The size of the image a renderer draws, independent of the swap chain's. Where its aspect ratio differs from the
+// window's, it is centered with black bars above and below (letterbox) or at the sides (pillarbox). The default
+// follows the window and scales nothing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InternalResolution {
+    pub size: Option<Resolution>, // Fixed size in pixels, None to follow the window
+    pub aspect: Option<f32>, // Width over height when following the window, ignored with a fixed size
+    pub filter: UpscaleFilter,
+    // Of EdgeAdaptive, in stops below the strongest. 0 is the sharpest, each stop halves it.
+    pub sharpness: f32
+}
+
+impl Default for InternalResolution {
+    fn default() -> InternalResolution {
+        InternalResolution {
+            size: None,
+            aspect: None,
+            filter: UpscaleFilter::default(),
+            sharpness: DEFAULT_SHARPNESS
+        }
+    }
+}
+
+impl InternalResolution {
+    pub fn validated(self) -> Result<InternalResolution, String> {
+        match (self.aspect, self.sharpness) {
+            (Some(aspect), _) if !(aspect.is_finite() && aspect > 0.0) =>
+                Err(format!("The aspect ratio must be above 0, not {}", aspect)),
+            (_, sharpness) if !(sharpness.is_finite() && sharpness >= 0.0) =>
+                Err(format!("The sharpness must be 0 or more stops, not {}", sharpness)),
+            _ => Ok(self)
+        }
+    }
+
+    // The size of the rendered image for a window of window_extent
+    pub fn extent(&self, window_extent: vk::Extent2D) -> vk::Extent2D {
+        match (self.size, self.aspect) {
+            (Some(size), _) => vk::Extent2D { width: size.width, height: size.height },
+            (None, Some(aspect)) => fit_aspect(aspect, window_extent),
+            (None, None) => window_extent
+        }
+    }
+
+    // Where the rendered image goes in the window, the rest of the window is black
+    pub fn placement(&self, window_extent: vk::Extent2D) -> vk::Rect2D {
+        let extent = self.extent(window_extent);
+        let scale = (window_extent.width / extent.width).min(window_extent.height / extent.height);
+        let placed = match self.filter {
+            UpscaleFilter::Integer if scale > 0 => vk::Extent2D {
+                width: extent.width * scale,
+                height: extent.height * scale
+            },
+            _ => fit_aspect(extent.width as f32 / extent.height as f32, window_extent)
+        };
+
+        vk::Rect2D {
+            offset: vk::Offset2D {
+                x: ((window_extent.width - placed.width) / 2) as i32,
+                y: ((window_extent.height - placed.height) / 2) as i32
+            },
+            extent: placed
+        }
+    }
+
+    // True if the rendered image covers the window pixel for pixel
+    pub fn is_native(&self, window_extent: vk::Extent2D) -> bool {
+        self.extent(window_extent) == window_extent
+    }
+
+    // True if the filter is EdgeAdaptive and the rendered image is enlarged to fit the window
+    pub fn needs_upscaler(&self, window_extent: vk::Extent2D) -> bool {
+        let extent = self.extent(window_extent);
+        let placed = self.placement(window_extent).extent;
+        self.filter == UpscaleFilter::EdgeAdaptive && placed.width > extent.width && placed.height > extent.height
+    }
+
+    // Blits source, all of the rendered image, to target, the swap chain image with the window's extent, clearing the
+    // bars. target must be in TRANSFER_DST_OPTIMAL or GENERAL.
+    pub fn cmd_blit(&self, core: &VkCore, command_buffer: vk::CommandBuffer, source: vk::Image,
+                    source_layout: vk::ImageLayout, target: &BlitImage) {
+        let source = BlitImage { image: source, layout: source_layout, extent: self.extent(target.extent) };
+        cmd_blit_placed(core, command_buffer, &source, target, self.placement(target.extent), self.filter.vk_filter());
+    }
+}
+
+// Blits all of source to placement in target and clears the rest of target
+pub(crate) fn cmd_blit_placed(core: &VkCore, command_buffer: vk::CommandBuffer, source: &BlitImage,
+                              target: &BlitImage, placement: vk::Rect2D, filter: vk::Filter) {
+    let source_extent = source.extent;
+    let subresource = vk::ImageSubresourceLayers::default()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+    let blit_region = vk::ImageBlit::default()
+        .src_subresource(subresource)
+        .dst_subresource(subresource)
+        .src_offsets([vk::Offset3D::default(),
+            vk::Offset3D::default().x(source_extent.width as i32).y(source_extent.height as i32).z(1)])
+        .dst_offsets([vk::Offset3D::default().x(placement.offset.x).y(placement.offset.y),
+            vk::Offset3D::default()
+                .x(placement.offset.x + placement.extent.width as i32)
+                .y(placement.offset.y + placement.extent.height as i32)
+                .z(1)]);
+
+    unsafe {
+        if placement.extent != target.extent {
+            let range = vk::ImageSubresourceRange::default()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .level_count(1)
+                .layer_count(1);
+            core.logical_device.cmd_clear_color_image(command_buffer, target.image, target.layout,
+                                                      &vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 1.0] },
+                                                      &[range]);
+            // The blit writes over part of the clear
+            let barrier = vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::TRANSFER,
+                                                     vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+        }
+        core.logical_device.cmd_blit_image(command_buffer, source.image, source.layout, target.image, target.layout,
+                                           &[blit_region], filter);
+    }
+}
+
+// The largest extent of the aspect ratio inside bounds, at least a pixel wide and high
+fn fit_aspect(aspect: f32, bounds: vk::Extent2D) -> vk::Extent2D {
+    let (width, height) = match (bounds.width as f32 / bounds.height as f32) > aspect {
+        true => ((bounds.height as f32 * aspect).round() as u32, bounds.height),
+        false => (bounds.width, (bounds.width as f32 / aspect).round() as u32)
+    };
+
+    vk::Extent2D {
+        width: width.clamp(1, bounds.width),
+        height: height.clamp(1, bounds.height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: vk::Extent2D = vk::Extent2D { width: 1920, height: 1080 };
+
+    #[test]
+    fn follows_the_window_by_default() {
+        let resolution = InternalResolution::default();
+        assert_eq!(resolution.extent(WINDOW), WINDOW);
+        assert_eq!(resolution.placement(WINDOW), vk::Rect2D { offset: vk::Offset2D::default(), extent: WINDOW });
+    }
+
+    #[test]
+    fn narrower_aspect_is_pillarboxed() {
+        let resolution = InternalResolution { aspect: Some(4.0 / 3.0), ..InternalResolution::default() };
+        assert_eq!(resolution.extent(WINDOW), vk::Extent2D { width: 1440, height: 1080 });
+        assert_eq!(resolution.placement(WINDOW).offset, vk::Offset2D { x: 240, y: 0 });
+    }
+
+    #[test]
+    fn integer_scale_centers_a_whole_multiple() {
+        let resolution = InternalResolution {
+            size: Some(Resolution { width: 320, height: 240 }),
+            filter: UpscaleFilter::Integer,
+            ..InternalResolution::default()
+        };
+        let placement = resolution.placement(WINDOW);
+        assert_eq!(placement.extent, vk::Extent2D { width: 1280, height: 960 });
+        assert_eq!(placement.offset, vk::Offset2D { x: 320, y: 60 });
+    }
+
+    #[test]
+    fn integer_scale_shrinks_images_larger_than_the_window() {
+        let resolution = InternalResolution {
+            size: Some(Resolution { width: 3840, height: 2160 }),
+            filter: UpscaleFilter::Integer,
+            ..InternalResolution::default()
+        };
+        assert_eq!(resolution.placement(WINDOW).extent, WINDOW);
+    }
+
+    #[test]
+    fn edge_adaptive_upscales_only_smaller_images() {
+        let smaller = InternalResolution {
+            size: Some(Resolution { width: 1280, height: 720 }),
+            filter: UpscaleFilter::EdgeAdaptive,
+            ..InternalResolution::default()
+        };
+        let larger = InternalResolution { size: Some(Resolution { width: 3840, height: 2160 }), ..smaller };
+        assert!(smaller.needs_upscaler(WINDOW));
+        assert!(!larger.needs_upscaler(WINDOW));
+        assert!(!InternalResolution { filter: UpscaleFilter::EdgeAdaptive, ..InternalResolution::default() }
+            .needs_upscaler(WINDOW));
+    }
+}
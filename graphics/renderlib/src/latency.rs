@@ -0,0 +1,133 @@
+use std::time::Instant;
+
+use ash::extensions::khr::PresentWait;
+use ash::vk;
+use tracing::info;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+use crate::render_target::RenderTarget;
+use crate::vkcore::VkCore;
+
+// A window that is minimized or covered may never display a frame, so waits give up after this long
+const PRESENT_WAIT_TIMEOUT_NS: u64 = 100_000_000;
+pub const LOW_LATENCY_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::L;
+
+// Keeps the CPU from running ahead of the display, so that input sampled for a frame is as fresh as possible when
+// the frame reaches the screen. With VK_KHR_present_wait the governor waits until the previous frame has been
+// displayed, without it only until the GPU has finished it.
+pub struct LatencyGovernor {
+    present_wait: Option<PresentWait>,
+    enabled: bool,
+    last_present_id: u64, // 0 while nothing has been presented to the current swap chain
+    last_wait_ms: f64
+}
+
+impl LatencyGovernor {
+    pub fn new(core: &VkCore, enabled: bool) -> LatencyGovernor {
+        let present_wait = match core.present_wait_supported {
+            true => Some(PresentWait::new(&core.instance, &core.logical_device)),
+            false => None
+        };
+
+        LatencyGovernor {
+            present_wait,
+            enabled,
+            last_present_id: 0,
+            last_wait_ms: 0.0
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.enabled {
+            info!(enabled, present_wait = self.uses_present_wait(), "Low latency mode changed");
+        }
+        self.enabled = enabled;
+    }
+
+    // Toggles the governor when LOW_LATENCY_TOGGLE_KEY is pressed
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(LOW_LATENCY_TOGGLE_KEY), .. },
+            ..
+        } = event {
+            self.set_enabled(!self.enabled);
+        }
+    }
+
+    pub fn uses_present_wait(&self) -> bool {
+        self.present_wait.is_some()
+    }
+
+    // Time the last throttle call blocked for
+    pub fn last_wait_ms(&self) -> f64 {
+        self.last_wait_ms
+    }
+
+    // Call before input is read for the next frame. previous_fence is the fence of the last submitted frame, it is
+    // only waited on when present wait is unavailable.
+    pub fn throttle(&mut self, core: &VkCore, render_target: &RenderTarget, previous_fence: vk::Fence) {
+        if !self.enabled {
+            self.last_wait_ms = 0.0;
+            return;
+        }
+
+        let wait_start = Instant::now();
+        match &self.present_wait {
+            Some(present_wait) => {
+                if self.last_present_id > 0 {
+                    let result = unsafe {
+                        present_wait.wait_for_present(render_target.swap_chain, self.last_present_id,
+                                                      PRESENT_WAIT_TIMEOUT_NS)
+                    };
+                    // An out of date swap chain is SwapchainRecreate's problem, the frame is dropped there. A lost
+                    // device is left to the renderer's DeviceLost, which finds it again at the next submit.
+                    match result {
+                        Ok(_) | Err(vk::Result::TIMEOUT) | Err(vk::Result::SUBOPTIMAL_KHR) |
+                        Err(vk::Result::ERROR_OUT_OF_DATE_KHR) |
+                        Err(vk::Result::ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT) |
+                        Err(vk::Result::ERROR_DEVICE_LOST) => (),
+                        Err(e) => panic!("wait_for_present failed: {:?}", e)
+                    }
+                }
+            },
+            None => {
+                let result = unsafe { core.logical_device.wait_for_fences(&[previous_fence], true, u64::MAX) };
+                match result {
+                    Ok(_) | Err(vk::Result::ERROR_DEVICE_LOST) => (),
+                    Err(e) => panic!("wait_for_fences failed: {:?}", e)
+                }
+            }
+        }
+        self.last_wait_ms = wait_start.elapsed().as_secs_f64() * 1000.0;
+    }
+
+    // Id to chain into the next present with vk::PresentIdKHR, None when present ids aren't supported. Ids are
+    // assigned while the governor is disabled too, so that enabling it takes effect on the next frame.
+    pub fn next_present_id(&mut self) -> Option<u64> {
+        self.present_wait.as_ref()?;
+        self.last_present_id += 1;
+
+        Some(self.last_present_id)
+    }
+
+    // Present ids are per swap chain, call after every recreate
+    pub fn reset(&mut self) {
+        self.last_present_id = 0;
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> String {
+        let method = match self.uses_present_wait() {
+            true => "present wait",
+            false => "fence"
+        };
+        match self.enabled {
+            true => format!("low latency on ({}), waited {:.2} ms", method, self.last_wait_ms),
+            false => String::from("low latency off")
+        }
+    }
+}
@@ -1,18 +1,107 @@
 pub mod renderutils;
+pub mod address_table;
+pub mod animation;
+pub mod async_compute;
+pub mod aux_camera;
+pub mod backend;
+pub mod benchmark;
+pub mod bindless;
+pub mod capabilities;
+pub mod capture;
+pub mod clock;
 pub mod depth;
+pub mod depth_readback;
 pub mod color;
+pub mod clustered_lights;
+pub mod collision;
+pub mod color_pipeline;
+pub mod compute;
+pub mod crash;
+pub mod cursor;
+pub mod config;
+pub mod debug_lines;
+pub mod debug_ui;
+pub mod deletion_queue;
 pub mod descriptor;
+pub mod descriptor_allocator;
+#[cfg(feature = "device-group")]
+pub mod device_group;
+pub mod device_lost;
+pub mod display_mode;
+pub mod display_timing;
+pub mod dof;
+pub mod dynamic_ubo;
+pub mod exposure;
+pub mod frame_constants;
+pub mod frame_limiter;
 pub mod frame_buffers;
+pub mod frame_ring;
+#[cfg(unix)]
+pub mod frame_share;
+pub mod gbuffer;
+pub mod golden;
 pub mod gpu_buffer;
+pub mod gpu_select;
+pub mod grid;
+pub mod idle;
 pub mod image;
 pub mod index;
+pub mod input_replay;
+pub mod internal_resolution;
+pub mod latency;
+pub mod logging;
+pub mod material_editor;
+pub mod material_panel;
+pub mod memory;
+pub mod mesh_optimizer;
 pub mod model;
+pub mod motion_blur;
+pub mod multiview;
+pub mod objects;
+#[cfg(feature = "indirect-draw")]
+pub mod occlusion;
+pub mod offscreen;
+pub mod outline;
+#[cfg(feature = "physics")]
+pub mod physics;
+pub mod pipeline_manager;
+pub mod post_process;
+pub mod preload;
+pub mod prelude;
+pub mod profiler;
+pub mod proxy;
 pub mod raster_pipeline;
+pub mod reflection;
 pub mod render_pass;
 pub mod render_target;
 pub mod sampler;
+pub mod scene;
+pub mod scene_bvh;
+pub mod settings;
+pub mod shader_permutation;
+pub mod shadow;
 pub mod single_time;
+pub mod skinning;
+pub mod sky;
+pub mod sparse_texture;
+pub mod specialization;
+pub mod ssao;
+pub mod stats_overlay;
+pub mod terrain;
 pub mod texture;
+pub mod texture_streaming;
+pub mod transparency;
 pub mod ubo;
+pub mod upscale;
 pub mod vertex;
+pub mod viewport;
 pub mod vkcore;
+pub mod voxel;
+pub mod voxel_edit;
+pub mod voxel_mesh;
+pub mod voxel_streaming;
+pub mod voxel_visibility;
+pub mod water;
+pub mod worldgen;
+
+pub use ash;
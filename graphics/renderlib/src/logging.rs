@@ -0,0 +1,18 @@
+use std::io;
+
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+// Sends tracing output to stderr, filtered by RUST_LOG, for example RUST_LOG=renderlib=debug,rt_renderer=trace.
+// Closed spans report how long they were busy, which times the init phases, pipeline creation and AS builds.
+// Only the first call installs a subscriber, later calls do nothing.
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(io::stderr)
+        .try_init();
+}
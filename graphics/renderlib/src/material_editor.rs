@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+use tracing::{debug, warn};
+
+use crate::scene::{Material, Scene};
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// The materials of a scene file, edited while a renderer draws them. Edited materials are sent to the renderer with
+// RendererProxy::set_material, which updates its GPU side from the next frame, and save writes them back into the
+// file. poll hot reloads the materials when the file or one of their textures changes on disk, for example after
+// another program edited them.
+pub struct MaterialEditor {
+    scene_path: String,
+    materials: Vec<Material>,
+    modified: bool, // Edited since the last load or save
+    timestamps: HashMap<String, Option<SystemTime>> // Of the scene file and the textures, when they were last read
+}
+
+impl MaterialEditor {
+    pub fn load(scene_path: &str) -> Result<MaterialEditor, String> {
+        let scene = Scene::load(scene_path)?;
+        let mut editor = MaterialEditor {
+            scene_path: scene_path.to_string(),
+            materials: scene.materials,
+            modified: false,
+            timestamps: HashMap::new()
+        };
+        editor.update_timestamps();
+
+        Ok(editor)
+    }
+
+    pub fn scene_path(&self) -> &str {
+        self.scene_path.as_str()
+    }
+
+    pub fn materials(&self) -> &[Material] {
+        self.materials.as_slice()
+    }
+
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.materials.iter().find(|m| m.name == name)
+    }
+
+    // Replaces the material with the same name, its texture must exist
+    pub fn set(&mut self, material: Material) -> Result<(), String> {
+        if let Some(path) = material.texture.as_ref().filter(|p| !Path::new(p).is_file()) {
+            return Err(format!("{} not found", path));
+        }
+        let slot = self.materials.iter_mut()
+            .find(|m| m.name == material.name)
+            .ok_or_else(|| format!("{} has no material {}", self.scene_path, material.name))?;
+        if *slot != material {
+            *slot = material;
+            self.modified = true;
+            self.update_timestamps(); // A new texture is watched from now on
+        }
+
+        Ok(())
+    }
+
+    // There are edits that aren't saved
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    // Writes the materials into the scene file. The rest of the scene is read again first, so that it stays as it is
+    // on disk.
+    pub fn save(&mut self) -> Result<(), String> {
+        let mut scene = Scene::load(self.scene_path.as_str())?;
+        scene.materials = self.materials.clone();
+        scene.validate().map_err(|e| format!("{}: {}", self.scene_path, e))?;
+        scene.save(self.scene_path.as_str())?;
+        self.modified = false;
+        self.update_timestamps();
+        debug!(path = self.scene_path.as_str(), "Saved {} materials", self.materials.len());
+
+        Ok(())
+    }
+
+    // Reads the scene file again, unsaved edits are lost. Returns the materials that changed.
+    pub fn revert(&mut self) -> Result<Vec<Material>, String> {
+        let scene = Scene::load(self.scene_path.as_str())?;
+        let changed: Vec<Material> = scene.materials.iter()
+            .filter(|m| self.material(m.name.as_str()) != Some(*m))
+            .cloned()
+            .collect();
+        self.materials = scene.materials;
+        self.modified = false;
+        self.update_timestamps();
+
+        Ok(changed)
+    }
+
+    // Call regularly, for example once a frame. Returns the materials that need to be sent to the renderer again:
+    // the ones that changed in the scene file, which replace unsaved edits, and the ones whose texture changed. A scene
+    // file that fails to load, like one saved halfway, is read again once it changes again.
+    pub fn poll(&mut self) -> Vec<Material> {
+        let changed: Vec<&String> = self.timestamps.iter()
+            .filter(|(path, time)| modified_time(path.as_str()) != **time)
+            .map(|(path, _)| path)
+            .collect();
+        if changed.is_empty() {
+            return Vec::new();
+        }
+        let scene_changed = changed.iter().any(|p| **p == self.scene_path);
+        let textures: Vec<String> = changed.into_iter().filter(|p| **p != self.scene_path).cloned().collect();
+
+        let mut reloaded = match scene_changed {
+            true => self.revert().unwrap_or_else(|e| {
+                warn!("The materials are not reloaded: {}", e);
+                Vec::new()
+            }),
+            false => Vec::new()
+        };
+        for material in self.materials.iter().filter(|m| m.texture.as_ref().is_some_and(|t| textures.contains(t))) {
+            if !reloaded.iter().any(|m| m.name == material.name) {
+                reloaded.push(material.clone());
+            }
+        }
+        self.update_timestamps();
+        debug!(materials = reloaded.len(), "Reloaded materials");
+
+        reloaded
+    }
+
+    fn update_timestamps(&mut self) {
+        self.timestamps = self.materials.iter()
+            .filter_map(|m| m.texture.as_ref())
+            .chain(Some(&self.scene_path))
+            .map(|path| (path.clone(), modified_time(path.as_str())))
+            .collect();
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use egui::{Color32, DragValue, Grid, ScrollArea, Slider, Ui, Window};
+
+use crate::material_editor::MaterialEditor;
+use crate::scene::Material;
+
+enum PanelAction {
+    Save,
+    Revert
+}
+
+// The materials of a MaterialEditor in a debug UI window. The materials that show returns were edited in it and have
+// to be sent to the renderer, like the ones poll hot reloads.
+pub struct MaterialPanel {
+    editor: MaterialEditor,
+    texture_inputs: HashMap<String, String>, // The texture paths being typed, applied on enter or when leaving the field
+    message: Option<(String, bool)> // The result of the last save, revert or edit and whether it is an error
+}
+
+impl MaterialPanel {
+    pub fn new(editor: MaterialEditor) -> MaterialPanel {
+        MaterialPanel {
+            editor,
+            texture_inputs: HashMap::new(),
+            message: None
+        }
+    }
+
+    pub fn editor(&self) -> &MaterialEditor {
+        &self.editor
+    }
+
+    // See MaterialEditor::poll, call it while the panel is hidden too
+    pub fn poll(&mut self) -> Vec<Material> {
+        let reloaded = self.editor.poll();
+        for material in reloaded.iter() {
+            self.texture_inputs.remove(&material.name);
+        }
+
+        reloaded
+    }
+
+    // Shows an error that happened while drawing an edited material
+    pub fn set_error(&mut self, error: String) {
+        self.message = Some((error, true));
+    }
+
+    pub fn show(&mut self, context: &egui::Context) -> Vec<Material> {
+        let mut action = None;
+        let mut edits = Vec::new();
+        Window::new("Materials").default_width(300.0).show(context, |ui| {
+            ui.label(self.editor.scene_path());
+            ui.horizontal(|ui| {
+                if ui.add_enabled(self.editor.is_modified(), egui::Button::new("Save")).clicked() {
+                    action = Some(PanelAction::Save);
+                }
+                if ui.button("Revert").clicked() {
+                    action = Some(PanelAction::Revert);
+                }
+                if self.editor.is_modified() {
+                    ui.label("Unsaved changes");
+                }
+            });
+            if let Some((message, error)) = self.message.as_ref() {
+                match error {
+                    true => ui.colored_label(Color32::LIGHT_RED, message),
+                    false => ui.label(message)
+                };
+            }
+            ui.separator();
+
+            ScrollArea::vertical().show(ui, |ui| {
+                for material in self.editor.materials() {
+                    let mut edited = material.clone();
+                    let texture_input = self.texture_inputs.entry(material.name.clone())
+                        .or_insert_with(|| material.texture.clone().unwrap_or_default());
+                    ui.collapsing(material.name.as_str(), |ui| material_controls(ui, &mut edited, texture_input));
+                    if edited != *material {
+                        edits.push(edited);
+                    }
+                }
+            });
+        });
+
+        let mut changed = Vec::new();
+        for material in edits {
+            match self.editor.set(material.clone()) {
+                Ok(()) => {
+                    self.message = None;
+                    changed.push(material);
+                }
+                Err(e) => self.message = Some((e, true))
+            }
+        }
+        match action {
+            Some(PanelAction::Save) => self.message = Some(match self.editor.save() {
+                Ok(()) => ("Saved".to_string(), false),
+                Err(e) => (e, true)
+            }),
+            Some(PanelAction::Revert) => match self.editor.revert() {
+                Ok(reverted) => {
+                    self.texture_inputs.clear();
+                    self.message = None;
+                    changed.extend(reverted);
+                }
+                Err(e) => self.message = Some((e, true))
+            },
+            None => ()
+        }
+
+        changed
+    }
+}
+
+fn material_controls(ui: &mut Ui, material: &mut Material, texture_input: &mut String) {
+    Grid::new(material.name.as_str()).num_columns(2).show(ui, |ui| {
+        ui.label("Base color");
+        ui.color_edit_button_rgba_unmultiplied(&mut material.base_color);
+        ui.end_row();
+
+        ui.label("Metallic");
+        ui.add(Slider::new(&mut material.metallic, 0.0..=1.0));
+        ui.end_row();
+
+        ui.label("Roughness");
+        ui.add(Slider::new(&mut material.roughness, 0.0..=1.0));
+        ui.end_row();
+
+        ui.label("Emissive");
+        ui.color_edit_button_rgb(&mut material.emissive);
+        ui.end_row();
+
+        ui.label("Emissive strength");
+        ui.add(DragValue::new(&mut material.emissive_strength).speed(0.1).clamp_range(0.0..=f32::MAX));
+        ui.end_row();
+
+        ui.label("Texture");
+        if ui.text_edit_singleline(texture_input).lost_focus() {
+            let texture = Some(texture_input.trim().to_string()).filter(|t| !t.is_empty());
+            if texture != material.texture {
+                material.texture = texture;
+            }
+        }
+        ui.end_row();
+    });
+}
@@ -0,0 +1,206 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ash::vk;
+use clap::ValueEnum;
+use tracing::debug;
+
+use crate::vkcore::VkCore;
+
+// Without resizable BAR, the host can map at most this much of the device local memory
+const BAR_WINDOW_SIZE: vk::DeviceSize = 256 * 1024 * 1024;
+
+// Whether buffers the host writes often go to device local memory the host can map, see mapped_memory_flags. The GPU
+// reads such memory at full speed instead of across the bus.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum RebarPolicy {
+    // Only with resizable BAR or on integrated GPUs, where the memory is large enough to not run out
+    #[default]
+    Auto,
+    // Also in the 256 MiB window most discrete GPUs have without resizable BAR
+    Always,
+    // Host memory for buffers rewritten every frame, staging copies for the others
+    Never
+}
+
+// Whether attachments that only live during a render pass, with TRANSIENT_ATTACHMENT usage, get lazily allocated
+// memory. Tile based GPUs, mostly integrated and mobile ones, keep such attachments in tile memory and only back
+// them with real memory if they run out of it. Other devices have no lazily allocated memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TransientPolicy {
+    // Lazily allocated where the device has such memory
+    #[default]
+    Auto,
+    // Always device local memory, to compare bandwidth on a tile based GPU
+    Never
+}
+
+// How buffers that the host writes got their data to the device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadPath {
+    Direct, // Mapped device local memory
+    Host, // Mapped host memory the GPU reads across the bus
+    Staged // Device local memory filled by a copy from a staging buffer
+}
+
+// Buffers created on each UploadPath since startup
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UploadStats {
+    pub direct: usize,
+    pub host: usize,
+    pub staged: usize
+}
+
+// Counts the buffers behind UploadStats, on VkCore so that every constructor can count without a &mut
+#[derive(Debug, Default)]
+pub struct UploadCounters {
+    direct: AtomicUsize,
+    host: AtomicUsize,
+    staged: AtomicUsize
+}
+
+impl UploadCounters {
+    pub(crate) fn record(&self, path: UploadPath) {
+        let counter = match path {
+            UploadPath::Direct => &self.direct,
+            UploadPath::Host => &self.host,
+            UploadPath::Staged => &self.staged
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> UploadStats {
+        UploadStats {
+            direct: self.direct.load(Ordering::Relaxed),
+            host: self.host.load(Ordering::Relaxed),
+            staged: self.staged.load(Ordering::Relaxed)
+        }
+    }
+}
+
+// Size of the largest heap with a memory type that is device local, host visible and coherent, None if there is none
+pub fn device_host_visible_heap(core: &VkCore) -> Option<vk::DeviceSize> {
+    let properties = unsafe { core.instance.get_physical_device_memory_properties(core.physical_device) };
+    let flags = vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_VISIBLE |
+        vk::MemoryPropertyFlags::HOST_COHERENT;
+    properties.memory_types[..properties.memory_type_count as usize].iter()
+        .filter(|t| t.property_flags.contains(flags))
+        .map(|t| properties.memory_heaps[t.heap_index as usize].size)
+        .max()
+}
+
+// The host can map more device local memory than the fixed BAR window allows, with resizable BAR or on an integrated
+// GPU
+pub fn has_resizable_bar(core: &VkCore) -> bool {
+    device_host_visible_heap(core).is_some_and(|size| size > BAR_WINDOW_SIZE)
+}
+
+// Whether core.rebar_policy lets buffers the host writes go to device local memory on this device
+pub fn direct_upload(core: &VkCore) -> bool {
+    match core.rebar_policy {
+        RebarPolicy::Auto => has_resizable_bar(core),
+        RebarPolicy::Always => device_host_visible_heap(core).is_some(),
+        RebarPolicy::Never => false
+    }
+}
+
+// Memory properties for a buffer the host maps and rewrites every frame, like a uniform buffer. Counts the buffer in
+// UploadStats.
+pub fn mapped_memory_flags(core: &VkCore) -> vk::MemoryPropertyFlags {
+    let host_flags = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+    match direct_upload(core) {
+        true => {
+            core.upload_counters.record(UploadPath::Direct);
+            host_flags | vk::MemoryPropertyFlags::DEVICE_LOCAL
+        },
+        false => {
+            core.upload_counters.record(UploadPath::Host);
+            host_flags
+        }
+    }
+}
+
+// Logs which memory the policy picks, once the device is known
+pub(crate) fn log_rebar(core: &VkCore) {
+    debug!(policy = ?core.rebar_policy, device_host_visible_heap = ?device_host_visible_heap(core),
+           resizable_bar = has_resizable_bar(core), direct_upload = direct_upload(core), "Upload memory");
+    debug!(policy = ?core.transient_policy, lazily_allocated = has_lazily_allocated_memory(core),
+           "Transient attachment memory");
+}
+
+// Tile based GPUs have a memory type that is only allocated when an attachment doesn't fit into tile memory
+pub fn has_lazily_allocated_memory(core: &VkCore) -> bool {
+    let properties = unsafe { core.instance.get_physical_device_memory_properties(core.physical_device) };
+    properties.memory_types[..properties.memory_type_count as usize].iter()
+        .any(|t| t.property_flags.contains(vk::MemoryPropertyFlags::LAZILY_ALLOCATED))
+}
+
+// Memory properties for an image with TRANSIENT_ATTACHMENT usage, like a multisampled color buffer that is resolved
+// and never stored. Images whose memory requirements exclude lazily allocated memory get device local memory, see
+// create_image.
+pub fn transient_memory_flags(core: &VkCore) -> vk::MemoryPropertyFlags {
+    match core.transient_policy == TransientPolicy::Auto && has_lazily_allocated_memory(core) {
+        true => vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::LAZILY_ALLOCATED,
+        false => vk::MemoryPropertyFlags::DEVICE_LOCAL
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HeapStats {
+    pub size: vk::DeviceSize,
+    pub device_local: bool,
+    // What the driver currently lets this process allocate from the heap, and what it has allocated, both None
+    // without VK_EXT_memory_budget
+    pub budget: Option<vk::DeviceSize>,
+    pub usage: Option<vk::DeviceSize>
+}
+
+// Snapshot of the device's memory heaps. The budget changes with what other processes allocate, query again rather
+// than keeping one around.
+#[derive(Clone, Debug)]
+pub struct MemoryStats {
+    pub heaps: Vec<HeapStats>,
+    pub resizable_bar: bool,
+    pub uploads: UploadStats
+}
+
+impl MemoryStats {
+    pub fn query(core: &VkCore) -> MemoryStats {
+        let mut budget_properties = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        let mut properties2 = vk::PhysicalDeviceMemoryProperties2::default();
+        if core.memory_budget_supported {
+            properties2 = properties2.push_next(&mut budget_properties);
+        }
+        unsafe { core.instance.get_physical_device_memory_properties2(core.physical_device, &mut properties2) };
+        let properties = properties2.memory_properties;
+
+        let heaps = (0..properties.memory_heap_count as usize)
+            .map(|i| HeapStats {
+                size: properties.memory_heaps[i].size,
+                device_local: properties.memory_heaps[i].flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                budget: Some(budget_properties.heap_budget[i]).filter(|_| core.memory_budget_supported),
+                usage: Some(budget_properties.heap_usage[i]).filter(|_| core.memory_budget_supported)
+            })
+            .collect();
+
+        MemoryStats {
+            heaps,
+            resizable_bar: has_resizable_bar(core),
+            uploads: core.upload_counters.stats()
+        }
+    }
+
+    // Of all device local heaps, their sizes without VK_EXT_memory_budget
+    pub fn device_local_budget(&self) -> vk::DeviceSize {
+        self.heaps.iter()
+            .filter(|h| h.device_local)
+            .map(|h| h.budget.unwrap_or(h.size))
+            .sum()
+    }
+
+    pub fn device_local_usage(&self) -> Option<vk::DeviceSize> {
+        self.heaps.iter()
+            .filter(|h| h.device_local)
+            .map(|h| h.usage)
+            .sum()
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3};
+
+use crate::vertex::Vertex;
+
+// Post-transform cache entries that optimize_vertex_cache scores for, larger than any GPU's so that it doesn't depend
+// on the hardware
+const SCORED_CACHE_SIZE: usize = 32;
+// Of the FIFO cache cache_miss_ratio and optimize_overdraw simulate, in the range of current GPUs
+pub const SIMULATED_CACHE_SIZE: usize = 16;
+// Scoring constants from Tom Forsyth's "Linear-Speed Vertex Cache Optimisation"
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+// Bitwise, so that -0.0 and 0.0 stay apart like they do on the GPU
+fn vertex_key(v: &Vertex) -> [u32; 8] {
+    [v.pos[0].to_bits(), v.pos[1].to_bits(), v.pos[2].to_bits(), v.color[0].to_bits(), v.color[1].to_bits(),
+        v.color[2].to_bits(), v.tex_coord[0].to_bits(), v.tex_coord[1].to_bits()]
+}
+
+// Merges vertices with equal attributes. The remaining ones keep the order they are first used in.
+pub fn deduplicate_vertices(vertices: &[Vertex], indices: &[u32]) -> (Vec<Vertex>, Vec<u32>) {
+    let mut unique: Vec<Vertex> = Vec::new();
+    let mut index_of: HashMap<[u32; 8], u32> = HashMap::new();
+    let indices = indices.iter()
+        .map(|&i| {
+            let v = vertices[i as usize];
+            *index_of.entry(vertex_key(&v)).or_insert_with(|| {
+                unique.push(v);
+                (unique.len() - 1) as u32
+            })
+        })
+        .collect();
+
+    (unique, indices)
+}
+
+// Misses per triangle of a FIFO cache of cache_size vertices, between 0.5 for a regular grid and 3.0
+pub fn cache_miss_ratio(indices: &[u32], cache_size: usize) -> f32 {
+    if indices.len() < 3 {
+        return 0.0;
+    }
+    let mut cache: Vec<u32> = Vec::with_capacity(cache_size + 1);
+    let mut misses = 0;
+    for &i in indices.iter() {
+        if !cache.contains(&i) {
+            misses += 1;
+            cache.insert(0, i);
+            cache.truncate(cache_size);
+        }
+    }
+
+    misses as f32 / (indices.len() / 3) as f32
+}
+
+fn vertex_score(cache_position: Option<usize>, triangles_left: usize) -> f32 {
+    if triangles_left == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        None => 0.0,
+        Some(p) if p < 3 => LAST_TRIANGLE_SCORE, // Used by the last triangle, not favoured over the rest
+        Some(p) => (1.0 - (p - 3) as f32 / (SCORED_CACHE_SIZE - 3) as f32).powf(CACHE_DECAY_POWER)
+    };
+
+    cache_score + VALENCE_BOOST_SCALE * (triangles_left as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+// Reorders the triangles of indices so that consecutive ones share vertices, with Forsyth's greedy algorithm. The
+// next triangle is the best scored one of the vertices in the cache, or the first one left if none of them has
+// triangles left.
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    // The triangles of each vertex, as ranges of adjacency
+    let mut offsets = vec![0usize; vertex_count + 1];
+    for &i in indices[..triangle_count * 3].iter() {
+        offsets[i as usize + 1] += 1;
+    }
+    for v in 0..vertex_count {
+        offsets[v + 1] += offsets[v];
+    }
+    let mut adjacency = vec![0usize; offsets[vertex_count]];
+    let mut filled = offsets.clone();
+    for (n, &i) in indices[..triangle_count * 3].iter().enumerate() {
+        adjacency[filled[i as usize]] = n / 3;
+        filled[i as usize] += 1;
+    }
+
+    let mut triangles_left: Vec<usize> = (0..vertex_count).map(|v| offsets[v + 1] - offsets[v]).collect();
+    let mut cache_positions: Vec<Option<usize>> = vec![None; vertex_count];
+    let mut scores: Vec<f32> = triangles_left.iter().map(|&t| vertex_score(None, t)).collect();
+    let triangle = |t: usize| [indices[3 * t] as usize, indices[3 * t + 1] as usize, indices[3 * t + 2] as usize];
+    let mut emitted = vec![false; triangle_count];
+    let mut cache: Vec<usize> = Vec::with_capacity(SCORED_CACHE_SIZE + 3);
+    let mut next_unemitted = 0;
+    let mut optimized: Vec<u32> = Vec::with_capacity(triangle_count * 3);
+
+    let triangle_score = |scores: &[f32], t: usize| -> f32 { triangle(t).iter().map(|&v| scores[v]).sum() };
+    let mut best = (0..triangle_count)
+        .max_by(|&a, &b| triangle_score(&scores, a).total_cmp(&triangle_score(&scores, b)));
+    while let Some(t) = best {
+        emitted[t] = true;
+        let vertices = triangle(t);
+        optimized.extend(vertices.iter().map(|&v| v as u32));
+
+        let mut new_cache: Vec<usize> = Vec::with_capacity(SCORED_CACHE_SIZE + 3);
+        for &v in vertices.iter() {
+            triangles_left[v] -= 1;
+            if !new_cache.contains(&v) {
+                new_cache.push(v);
+            }
+        }
+        new_cache.extend(cache.iter().filter(|&v| !vertices.contains(v)));
+        // Also rescores the vertices that just dropped out
+        for (p, &v) in new_cache.iter().enumerate() {
+            cache_positions[v] = match p < SCORED_CACHE_SIZE {
+                true => Some(p),
+                false => None
+            };
+            scores[v] = vertex_score(cache_positions[v], triangles_left[v]);
+        }
+
+        best = None;
+        let mut best_score = f32::MIN;
+        for &v in new_cache.iter() {
+            for &adjacent in adjacency[offsets[v]..offsets[v + 1]].iter().filter(|&&a| !emitted[a]) {
+                let score = triangle_score(&scores, adjacent);
+                if score > best_score {
+                    best = Some(adjacent);
+                    best_score = score;
+                }
+            }
+        }
+        new_cache.truncate(SCORED_CACHE_SIZE);
+        cache = new_cache;
+
+        if best.is_none() {
+            while next_unemitted < triangle_count && emitted[next_unemitted] {
+                next_unemitted += 1;
+            }
+            if next_unemitted < triangle_count {
+                best = Some(next_unemitted);
+            }
+        }
+    }
+
+    optimized
+}
+
+// Reorders clusters of cache optimized triangles so that the ones facing away from the mesh's center come first.
+// Seen from outside, those tend to be in front of the others and to hide them behind the depth test. Clusters start at
+// the triangles that miss the cache with all their vertices, so that the cache efficiency stays.
+pub fn optimize_overdraw(indices: &[u32], vertices: &[Vertex]) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 {
+        return indices.to_vec();
+    }
+    let position = |i: u32| Vector3::from(vertices[i as usize].pos);
+
+    let mut cluster_starts: Vec<usize> = Vec::new();
+    let mut cache: Vec<u32> = Vec::with_capacity(SIMULATED_CACHE_SIZE + 1);
+    for t in 0..triangle_count {
+        let mut misses = 0;
+        for &i in indices[3 * t..3 * t + 3].iter() {
+            if !cache.contains(&i) {
+                misses += 1;
+                cache.insert(0, i);
+                cache.truncate(SIMULATED_CACHE_SIZE);
+            }
+        }
+        if misses == 3 {
+            cluster_starts.push(t);
+        }
+    }
+    if cluster_starts.first() != Some(&0) {
+        cluster_starts.insert(0, 0);
+    }
+
+    // Area weighted, the cross products have twice the triangle's area
+    let mut mesh_center = Vector3::new(0.0, 0.0, 0.0);
+    let mut mesh_area = 0.0;
+    let mut clusters: Vec<(f32, usize, usize)> = Vec::with_capacity(cluster_starts.len()); // (Sort key, first, end)
+    let mut centers: Vec<(Vector3<f32>, Vector3<f32>, f32)> = Vec::with_capacity(cluster_starts.len());
+    for (c, &first) in cluster_starts.iter().enumerate() {
+        let end = cluster_starts.get(c + 1).copied().unwrap_or(triangle_count);
+        let mut center = Vector3::new(0.0, 0.0, 0.0);
+        let mut normal = Vector3::new(0.0, 0.0, 0.0);
+        let mut area = 0.0;
+        for t in first..end {
+            let (p0, p1, p2) = (position(indices[3 * t]), position(indices[3 * t + 1]), position(indices[3 * t + 2]));
+            let cross = (p1 - p0).cross(p2 - p0);
+            let triangle_area = cross.magnitude();
+            center += (p0 + p1 + p2) / 3.0 * triangle_area;
+            normal += cross;
+            area += triangle_area;
+        }
+        mesh_center += center;
+        mesh_area += area;
+        centers.push((center, normal, area));
+        clusters.push((0.0, first, end));
+    }
+    if mesh_area > 0.0 {
+        mesh_center /= mesh_area;
+    }
+    for (cluster, (center, normal, area)) in clusters.iter_mut().zip(centers.iter()) {
+        if *area > 0.0 && normal.magnitude2() > 0.0 {
+            cluster.0 = (center / *area - mesh_center).dot(normal.normalize());
+        }
+    }
+    clusters.sort_by(|a, b| b.0.total_cmp(&a.0)); // Stable, clusters with equal keys keep their order
+
+    clusters.iter().flat_map(|&(_, first, end)| indices[3 * first..3 * end].iter().copied()).collect()
+}
+
+// Orders the vertices by their first use in indices, which it rewrites, so that the vertex fetches of consecutive
+// triangles are close in memory. Vertices that no index refers to are left out.
+pub fn optimize_vertex_fetch(vertices: &[Vertex], indices: &mut [u32]) -> Vec<Vertex> {
+    let mut remap: Vec<Option<u32>> = vec![None; vertices.len()];
+    let mut ordered: Vec<Vertex> = Vec::with_capacity(vertices.len());
+    for i in indices.iter_mut() {
+        let old = *i as usize;
+        *i = *remap[old].get_or_insert_with(|| {
+            ordered.push(vertices[old]);
+            (ordered.len() - 1) as u32
+        });
+    }
+
+    ordered
+}
@@ -1,32 +1,285 @@
+use std::path::Path;
+
 use tobj;
-use crate::vertex::Vertex;
+use tracing::{info, warn};
 
+use crate::mesh_optimizer::{cache_miss_ratio, deduplicate_vertices, optimize_overdraw, optimize_vertex_cache,
+                            optimize_vertex_fetch, SIMULATED_CACHE_SIZE};
+use crate::scene::Material;
+use crate::vertex::{Dequantization, QuantizedVertex, Vertex, VertexAttribute, VertexLayout};
 
-pub fn load_model(path: &str) -> (Vec<Vertex>, Vec<u32>) {
-    let mut vertex_vec: Vec<Vertex> = Vec::new();
-    let mut index_vec: Vec<u32> = Vec::new();
-    let (models, _) =
-        tobj::load_obj(path, &tobj::LoadOptions::default()).unwrap();
+// A range of a model's indices that is drawn with one material
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Submesh {
+    pub first_index: u32,
+    pub index_count: u32,
+    pub material: Option<usize> // Into Model::materials, None for faces without a usemtl
+}
+
+// The models of an OBJ file in one vertex and index list. Every group or object, split by material, is a submesh.
+#[derive(Clone, Debug)]
+pub struct Model {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub submeshes: Vec<Submesh>,
+    pub materials: Vec<Material> // From the .mtl files the OBJ names
+}
+
+// MTL has no metalness, roughness follows the specular exponent. Textures are relative to the OBJ file, missing ones
+// are left out so that the diffuse color is drawn instead.
+fn mtl_material(material: &tobj::Material, directory: &Path) -> Material {
+    let texture = match material.diffuse_texture.is_empty() {
+        true => None,
+        false => {
+            let path = directory.join(&material.diffuse_texture);
+            match path.is_file() {
+                true => Some(path.to_string_lossy().into_owned()),
+                false => {
+                    warn!(material = material.name, texture = %path.display(), "Diffuse texture not found");
+                    None
+                }
+            }
+        }
+    };
+    let emissive = material.unknown_param.get("Ke")
+        .map(|ke| ke.split_whitespace().filter_map(|c| c.parse::<f32>().ok()).collect::<Vec<f32>>())
+        .filter(|ke| ke.len() == 3)
+        .map_or([0.0, 0.0, 0.0], |ke| [ke[0], ke[1], ke[2]]);
+
+    Material {
+        name: material.name.clone(),
+        base_color: [material.diffuse[0], material.diffuse[1], material.diffuse[2], 1.0],
+        texture,
+        metallic: 0.0,
+        roughness: (2.0 / (material.shininess.max(0.0) + 2.0)).sqrt(),
+        emissive,
+        emissive_strength: 1.0
+    }
+}
+
+// tobj only merges the vertices of a model that share their OBJ indices. Equal vertices are merged across the whole
+// file, then each submesh's triangles are reordered for the vertex cache and overdraw, which keeps the submeshes'
+// index ranges.
+fn optimize(path: &str, vertices: &[Vertex], indices: &[u32], submeshes: &[Submesh]) -> (Vec<Vertex>, Vec<u32>) {
+    let (unique, mut optimized) = deduplicate_vertices(vertices, indices);
+    for s in submeshes.iter() {
+        let range = s.first_index as usize..(s.first_index + s.index_count) as usize;
+        let cache_ordered = optimize_vertex_cache(&optimized[range.clone()], unique.len());
+        let ordered = optimize_overdraw(&cache_ordered, &unique);
+        optimized[range].copy_from_slice(&ordered);
+    }
+    let unique = optimize_vertex_fetch(&unique, &mut optimized);
+    info!(path, vertices = vertices.len(), unique_vertices = unique.len(),
+          cache_miss_ratio = cache_miss_ratio(indices, SIMULATED_CACHE_SIZE),
+          optimized_cache_miss_ratio = cache_miss_ratio(&optimized, SIMULATED_CACHE_SIZE), "Optimized model");
+
+    (unique, optimized)
+}
+
+pub fn load_obj(path: &str) -> Result<Model, String> {
+    let options = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ..tobj::LoadOptions::default()
+    };
+    let (models, materials) = tobj::load_obj(path, &options).map_err(|e| format!("Failed to load {}: {}", path, e))?;
+    let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+    let materials: Vec<Material> = match materials {
+        Ok(materials) => materials.iter().map(|m| mtl_material(m, directory)).collect(),
+        Err(e) => {
+            warn!(path, "Materials not loaded: {}", e);
+            Vec::new()
+        }
+    };
+
+    let mut vertices: Vec<Vertex> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    let mut submeshes: Vec<Submesh> = Vec::new();
     for m in models.iter() {
-        for n in 0..(m.mesh.positions.len() / 3) { // Push the vertices/texcords for each face
-            let pos: [f32; 3] = [
-                *m.mesh.positions.get((3 * n + 0) as usize).unwrap(),
-                *m.mesh.positions.get((3 * n + 1) as usize).unwrap(),
-                *m.mesh.positions.get((3 * n + 2) as usize).unwrap()
-            ];
-            let tex_cord: [f32; 2] = [
-                *m.mesh.texcoords.get((2 * n) as usize).unwrap(),
-                1.0 - *m.mesh.texcoords.get((2 * n + 1) as usize).unwrap()
-            ];
-            let color: [f32; 3] = [1.0, 1.0, 1.0];
-            vertex_vec.push(Vertex {
-                pos,
-                color,
-                tex_coord: tex_cord,
+        let mesh = &m.mesh;
+        let first_vertex = vertices.len() as u32;
+        for n in 0..(mesh.positions.len() / 3) {
+            let tex_coord = match mesh.texcoords.is_empty() {
+                true => [0.0, 0.0],
+                false => [mesh.texcoords[2 * n], 1.0 - mesh.texcoords[2 * n + 1]]
+            };
+            vertices.push(Vertex {
+                pos: [mesh.positions[3 * n], mesh.positions[3 * n + 1], mesh.positions[3 * n + 2]],
+                color: [1.0, 1.0, 1.0],
+                tex_coord
             });
         }
-        index_vec = m.mesh.indices.clone()
+        submeshes.push(Submesh {
+            first_index: indices.len() as u32,
+            index_count: mesh.indices.len() as u32,
+            material: mesh.material_id.filter(|&i| i < materials.len())
+        });
+        indices.extend(mesh.indices.iter().map(|i| first_vertex + i));
     }
+    let (vertices, indices) = optimize(path, &vertices, &indices, &submeshes);
+
+    Ok(Model {
+        vertices,
+        indices,
+        submeshes,
+        materials
+    })
+}
+
+// All submeshes of an OBJ file as one, without the materials
+pub fn load_model(path: &str) -> (Vec<Vertex>, Vec<u32>) {
+    let model = load_obj(path).unwrap();
+
+    (model.vertices, model.indices)
+}
+
+// Interleaved vertices in the format of layout, for pipelines created with RasterPipeline::new_with_layout
+pub struct MeshData {
+    pub layout: VertexLayout,
+    pub vertices: Vec<f32>, // layout.stride(0) bytes per vertex
+    pub indices: Vec<u32>
+}
+
+// A MeshData compressed into VertexLayout::quantized, for pipelines created with RasterPipeline::new_quantized.
+// dequantization is pushed for each draw.
+pub struct QuantizedMeshData {
+    pub layout: VertexLayout,
+    pub vertices: Vec<QuantizedVertex>,
+    pub indices: Vec<u32>,
+    pub dequantization: Dequantization
+}
+
+// Offset and size of each component's range, a size of 1 for flat ranges so that they still dequantize
+fn component_ranges<const N: usize>(values: impl Iterator<Item = [f32; N]>) -> ([f32; N], [f32; N]) {
+    let (min, max) = values.fold(([f32::INFINITY; N], [f32::NEG_INFINITY; N]), |(min, max), v| {
+        (std::array::from_fn(|i| min[i].min(v[i])), std::array::from_fn(|i| max[i].max(v[i])))
+    });
+    let offset = min.map(|m| if m.is_finite() { m } else { 0.0 });
+    let size = std::array::from_fn(|i| if max[i] > min[i] { max[i] - min[i] } else { 1.0 });
+
+    (offset, size)
+}
+
+// offsets are in floats from the start of a vertex
+fn read_attribute<'a>(vertex: &'a [f32], offsets: &[(VertexAttribute, usize)], attribute: VertexAttribute)
+    -> Option<&'a [f32]> {
+    let floats = attribute.size() as usize / std::mem::size_of::<f32>();
+    offsets.iter().find(|(a, _)| *a == attribute).map(|&(_, o)| &vertex[o..o + floats])
+}
+
+fn unorm16(value: f32, offset: f32, size: f32) -> u16 {
+    (((value - offset) / size).clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+}
+
+impl MeshData {
+    // Attributes the layout doesn't have are left at white, a zero normal and texture coordinate 0. Positions keep
+    // 1 / 65535 of the mesh's size on each axis, texture coordinates the same of their range.
+    pub fn quantized(&self) -> QuantizedMeshData {
+        let floats_per_vertex = self.layout.stride(0) as usize / std::mem::size_of::<f32>();
+        let mut offsets: Vec<(VertexAttribute, usize)> = Vec::new();
+        let mut offset = 0;
+        for a in self.layout.attributes() {
+            offsets.push((a, offset));
+            offset += a.size() as usize / std::mem::size_of::<f32>();
+        }
+        let read = |vertex, attribute| read_attribute(vertex, &offsets, attribute);
+        let vertices: Vec<&[f32]> = self.vertices.chunks_exact(floats_per_vertex).collect();
+
+        let (position_offset, position_scale) = component_ranges(vertices.iter()
+            .map(|v| <[f32; 3]>::try_from(read(v, VertexAttribute::Position).unwrap()).unwrap()));
+        let (tex_coord_offset, tex_coord_scale) = component_ranges(vertices.iter()
+            .filter_map(|v| read(v, VertexAttribute::TexCoord))
+            .map(|t| <[f32; 2]>::try_from(t).unwrap()));
+        let quantized = vertices.iter()
+            .map(|v| {
+                let pos = read(v, VertexAttribute::Position).unwrap();
+                let normal = read(v, VertexAttribute::Normal).map_or([0.0; 3], |n| {
+                    let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt().max(f32::EPSILON);
+                    [n[0] / length, n[1] / length, n[2] / length]
+                });
+                let tex_coord = read(v, VertexAttribute::TexCoord).unwrap_or(&tex_coord_offset[..]);
+                let color = read(v, VertexAttribute::Color).unwrap_or(&[1.0, 1.0, 1.0][..]);
+                QuantizedVertex {
+                    pos: [unorm16(pos[0], position_offset[0], position_scale[0]),
+                        unorm16(pos[1], position_offset[1], position_scale[1]),
+                        unorm16(pos[2], position_offset[2], position_scale[2]), 0],
+                    normal: [normal[0], normal[1], normal[2], 0.0].map(|c| (c * i16::MAX as f32).round() as i16),
+                    tex_coord: [unorm16(tex_coord[0], tex_coord_offset[0], tex_coord_scale[0]),
+                        unorm16(tex_coord[1], tex_coord_offset[1], tex_coord_scale[1])],
+                    color: [color[0], color[1], color[2], 1.0].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+                }
+            })
+            .collect();
+
+        QuantizedMeshData {
+            layout: VertexLayout::quantized(),
+            vertices: quantized,
+            indices: self.indices.clone(),
+            dequantization: Dequantization {
+                position_offset: [position_offset[0], position_offset[1], position_offset[2], 0.0],
+                position_scale: [position_scale[0], position_scale[1], position_scale[2], 0.0],
+                tex_coord: [tex_coord_offset[0], tex_coord_offset[1], tex_coord_scale[0], tex_coord_scale[1]]
+            }
+        }
+    }
+}
+
+// Loads all models of an OBJ file into one mesh, with the attributes every model has. Positions are always there,
+// vertex colors, texture coordinates and normals only if the file has them, in that order.
+pub fn load_mesh(path: &str) -> Result<MeshData, String> {
+    let options = tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ..tobj::LoadOptions::default()
+    };
+    let (models, _) = tobj::load_obj(path, &options).map_err(|e| format!("Failed to load {}: {}", path, e))?;
+    let all = |has: fn(&tobj::Mesh) -> bool| !models.is_empty() && models.iter().all(|m| has(&m.mesh));
+    let mut attributes = vec![VertexAttribute::Position];
+    if all(|m| !m.vertex_color.is_empty()) {
+        attributes.push(VertexAttribute::Color);
+    }
+    if all(|m| !m.texcoords.is_empty()) {
+        attributes.push(VertexAttribute::TexCoord);
+    }
+    if all(|m| !m.normals.is_empty()) {
+        attributes.push(VertexAttribute::Normal);
+    }
+    let layout = VertexLayout::from_attributes(&attributes);
+    let floats_per_vertex = layout.stride(0) as usize / std::mem::size_of::<f32>();
+
+    let mut vertices: Vec<f32> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    for m in models.iter() {
+        let mesh = &m.mesh;
+        let first_vertex = (vertices.len() / floats_per_vertex) as u32;
+        for n in 0..(mesh.positions.len() / 3) {
+            vertices.extend_from_slice(&mesh.positions[3 * n..3 * n + 3]);
+            if layout.has(VertexAttribute::Color) {
+                vertices.extend_from_slice(&mesh.vertex_color[3 * n..3 * n + 3]);
+            }
+            if layout.has(VertexAttribute::TexCoord) { // Flipped like in load_model
+                vertices.extend_from_slice(&[mesh.texcoords[2 * n], 1.0 - mesh.texcoords[2 * n + 1]]);
+            }
+            if layout.has(VertexAttribute::Normal) {
+                vertices.extend_from_slice(&mesh.normals[3 * n..3 * n + 3]);
+            }
+        }
+        indices.extend(mesh.indices.iter().map(|i| first_vertex + i));
+    }
+
+    Ok(MeshData {
+        layout,
+        vertices,
+        indices
+    })
+}
+
+// Both variants of load_mesh, for drawing the quantized one and keeping the full one for the CPU or comparisons
+pub fn load_mesh_variants(path: &str) -> Result<(MeshData, QuantizedMeshData), String> {
+    let mesh = load_mesh(path)?;
+    let quantized = mesh.quantized();
+    info!(path, vertex_bytes = mesh.vertices.len() * std::mem::size_of::<f32>(),
+          quantized_vertex_bytes = quantized.vertices.len() * std::mem::size_of::<QuantizedVertex>(), "Quantized mesh");
 
-    (vertex_vec, index_vec)
-}
\ No newline at end of file
+    Ok((mesh, quantized))
+}
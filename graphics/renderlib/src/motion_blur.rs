@@ -0,0 +1,121 @@
+use std::mem;
+
+use ash::vk;
+use cgmath::{Matrix4, SquareMatrix};
+use winit::event::VirtualKeyCode;
+
+use crate::compute::{ComputePipeline, group_count};
+use crate::post_process::{POST_GROUP_SIZE, PostFrame};
+use crate::renderutils::cast_to_u8_slice;
+use crate::vkcore::VkCore;
+
+const MOTION_BLUR_SHADER_PATH: &str = "graphics/shaders/spv/motion_blur.spv";
+pub const MOTION_BLUR_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::B;
+pub const MAX_MOTION_BLUR_SAMPLES: u32 = 32;
+// Longest blur in pixels, so that a camera cut doesn't smear the whole image
+pub const MAX_BLUR_LENGTH: f32 = 48.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MotionBlurSettings {
+    pub enabled: bool,
+    pub shutter: f32, // Fraction of the frame the shutter is open for, 0.5 is a 180 degree shutter
+    pub samples: u32 // Along the motion of each pixel
+}
+
+impl Default for MotionBlurSettings {
+    fn default() -> MotionBlurSettings {
+        MotionBlurSettings {
+            enabled: false,
+            shutter: 0.5,
+            samples: 8
+        }
+    }
+}
+
+impl MotionBlurSettings {
+    pub fn validated(&self) -> MotionBlurSettings {
+        MotionBlurSettings {
+            enabled: self.enabled,
+            shutter: self.shutter.clamp(0.0, 1.0),
+            samples: self.samples.clamp(2, MAX_MOTION_BLUR_SAMPLES)
+        }
+    }
+
+    // Applies MOTION_BLUR_TOGGLE_KEY, returns false for other keys
+    pub fn handle_key(&mut self, key: VirtualKeyCode) -> bool {
+        match key {
+            MOTION_BLUR_TOGGLE_KEY => self.enabled = !self.enabled,
+            _ => return false
+        }
+        true
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> &'static str {
+        match self.enabled {
+            true => "motion blur on",
+            false => "motion blur off"
+        }
+    }
+}
+
+// Matches the push constants in motion_blur.comp
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct MotionBlurConstants {
+    reprojection: Matrix4<f32>, // From this frame's view space to the previous frame's clip space
+    blur: [f32; 4] // proj[0][0], proj[1][1], shutter and samples
+}
+
+// Camera motion blur, a PostProcessChain effect. Each pixel's view position is rebuilt from the view depth and
+// reprojected with the previous frame's view projection, which gives its velocity on screen. The pixel then
+// averages samples along that velocity, scaled by the shutter. There is no velocity buffer, so moving objects are
+// only blurred by how the camera moves relative to them.
+pub struct MotionBlur {
+    pipeline: ComputePipeline,
+    settings: MotionBlurSettings
+}
+
+impl MotionBlur {
+    // set_layout is the PostProcessChain's
+    pub(crate) fn new(core: &VkCore, set_layout: vk::DescriptorSetLayout) -> MotionBlur {
+        MotionBlur {
+            pipeline: ComputePipeline::new(core, MOTION_BLUR_SHADER_PATH, &[set_layout],
+                                           mem::size_of::<MotionBlurConstants>() as u32),
+            settings: MotionBlurSettings::default()
+        }
+    }
+
+    pub fn settings(&self) -> MotionBlurSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: MotionBlurSettings) {
+        self.settings = settings.validated();
+    }
+
+    // Needs the previous frame, so never on the first one or when the renderer leaves it out
+    pub fn is_enabled(&self, frame: &PostFrame) -> bool {
+        self.settings.enabled && self.settings.shutter > 0.0 && frame.previous_view_projection.is_some()
+    }
+
+    pub(crate) fn cmd_apply(&self, core: &VkCore, command_buffer: vk::CommandBuffer, set: vk::DescriptorSet,
+                            extent: vk::Extent2D, frame: &PostFrame) {
+        let previous = frame.previous_view_projection.unwrap();
+        let constants = [MotionBlurConstants {
+            reprojection: previous * frame.view.invert().unwrap(),
+            blur: [frame.projection.x.x, frame.projection.y.y, self.settings.shutter, self.settings.samples as f32]
+        }];
+        unsafe {
+            self.pipeline.cmd_bind(core, command_buffer, set);
+            core.logical_device.cmd_push_constants(command_buffer, self.pipeline.layout,
+                                                   vk::ShaderStageFlags::COMPUTE, 0, cast_to_u8_slice(&constants));
+            core.logical_device.cmd_dispatch(command_buffer, group_count(extent.width, POST_GROUP_SIZE),
+                                             group_count(extent.height, POST_GROUP_SIZE), 1);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+    }
+}
@@ -0,0 +1,357 @@
+use ash::vk;
+use cgmath::Matrix4;
+
+use crate::color_pipeline::ColorConstants;
+use crate::depth::find_depth_format;
+use crate::descriptor::{create_descriptor_set_layout, Descriptor};
+use crate::image::{create_image_view_array, create_image_with, ImageInfo};
+use crate::raster_pipeline::RasterPipeline;
+use crate::render_target::RenderTarget;
+use crate::renderutils::cast_to_u8_slice;
+use crate::texture::Texture;
+use crate::ubo::UniformBuffer;
+use crate::vkcore::VkCore;
+
+// Left eye in layer 0, right eye in layer 1
+pub const STEREO_VIEW_COUNT: u32 = 2;
+pub const STEREO_VIEW_MASK: u32 = 0b11;
+// Between the eyes, in scene units of a meter
+pub const DEFAULT_EYE_SEPARATION: f32 = 0.064;
+
+// Layered color/depth attachments rendered in a single pass by VK_KHR_multiview (core in Vulkan 1.1). Each bit of
+// the view mask broadcasts the subpass to the layer with the same index, so the same target works for stereo and
+// for up to 6 cubemap/probe faces.
+pub struct MultiviewTarget {
+    pub extent: vk::Extent2D, // Per layer extent
+    pub view_count: u32,
+    pub color_format: vk::Format,
+    pub depth_format: vk::Format,
+    color_image: vk::Image,
+    color_mem: vk::DeviceMemory,
+    pub color_view: vk::ImageView,
+    depth_image: vk::Image,
+    depth_mem: vk::DeviceMemory,
+    pub depth_view: vk::ImageView,
+    pub render_pass: vk::RenderPass,
+    pub frame_buffer: vk::Framebuffer
+}
+
+pub fn setup_multiview_render_pass(core: &VkCore, color_format: vk::Format, depth_format: vk::Format,
+                                   view_mask: u32) -> vk::RenderPass {
+    let color_attachment_desc = vk::AttachmentDescription::default()
+        .format(color_format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL); // Layers are blitted out after the pass
+
+    let depth_attachment_desc = vk::AttachmentDescription::default()
+        .format(depth_format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let attachment_desc_array = [color_attachment_desc, depth_attachment_desc];
+    let color_attachment_ref = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    let depth_attachment_ref = vk::AttachmentReference::default()
+        .attachment(1)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let subpass_array = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&color_attachment_ref)
+        .depth_stencil_attachment(&depth_attachment_ref)];
+
+    let dependencies = [vk::SubpassDependency::default()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(0)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+        .src_access_mask(vk::AccessFlags::empty())
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+        .dependency_flags(vk::DependencyFlags::empty())];
+
+    // One view mask per subpass. The correlation mask hints that the views are spatially close, which lets the
+    // implementation share work between them (true for stereo eyes, not for cube faces).
+    let view_masks = [view_mask];
+    let correlation_masks = [view_mask];
+    let mut multiview_create_info = vk::RenderPassMultiviewCreateInfo::default()
+        .view_masks(&view_masks)
+        .correlation_masks(&correlation_masks);
+
+    let render_pass_create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachment_desc_array)
+        .subpasses(&subpass_array)
+        .dependencies(&dependencies)
+        .push_next(&mut multiview_create_info);
+
+    unsafe { core.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+}
+
+impl MultiviewTarget {
+    pub fn new(core: &VkCore, extent: vk::Extent2D, color_format: vk::Format, view_count: u32) -> MultiviewTarget {
+        assert!(core.multiview_supported, "Multiview rendering requested but not supported by the device");
+        assert!(view_count > 0 && view_count <= 32);
+
+        let depth_format = find_depth_format(core);
+        let view_mask = u32::MAX >> (32 - view_count);
+
+        let (color_image, color_mem) = create_image_with(core, &ImageInfo {
+            array_layers: view_count,
+            ..ImageInfo::new(extent.width, extent.height, color_format,
+                             vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC |
+                                 vk::ImageUsageFlags::SAMPLED)
+        });
+        let color_view = create_image_view_array(core, color_image, color_format, vk::ImageAspectFlags::COLOR,
+                                                 1, view_count);
+        let (depth_image, depth_mem) = create_image_with(core, &ImageInfo {
+            array_layers: view_count,
+            ..ImageInfo::new(extent.width, extent.height, depth_format, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+        });
+        let depth_view = create_image_view_array(core, depth_image, depth_format, vk::ImageAspectFlags::DEPTH,
+                                                 1, view_count);
+
+        let render_pass = setup_multiview_render_pass(core, color_format, depth_format, view_mask);
+
+        let attachments = [color_view, depth_view];
+        let frame_buffer_create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1); // Must be 1 for multiview, the layer count comes from the view mask
+        let frame_buffer = unsafe {
+            core.logical_device.create_framebuffer(&frame_buffer_create_info, None).unwrap()
+        };
+
+        MultiviewTarget {
+            extent,
+            view_count,
+            color_format,
+            depth_format,
+            color_image,
+            color_mem,
+            color_view,
+            depth_image,
+            depth_mem,
+            depth_view,
+            render_pass,
+            frame_buffer
+        }
+    }
+
+    pub fn new_stereo(core: &VkCore, eye_extent: vk::Extent2D, color_format: vk::Format) -> MultiviewTarget {
+        MultiviewTarget::new(core, eye_extent, color_format, STEREO_VIEW_COUNT)
+    }
+
+    // Copies every layer side by side into dst_image, which must be in TRANSFER_DST_OPTIMAL. Used to preview stereo
+    // output on a regular swap chain until a VR runtime takes the layers directly.
+    pub fn cmd_blit_side_by_side(&self, core: &VkCore, command_buffer: vk::CommandBuffer, dst_image: vk::Image,
+                                 dst_extent: vk::Extent2D) {
+        let slot_width = dst_extent.width / self.view_count;
+        let mut regions: Vec<vk::ImageBlit> = Vec::with_capacity(self.view_count as usize);
+        for layer in 0..self.view_count {
+            let src_subresource = vk::ImageSubresourceLayers::default()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(0)
+                .base_array_layer(layer)
+                .layer_count(1);
+            let dst_subresource = vk::ImageSubresourceLayers::default()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(0)
+                .base_array_layer(0)
+                .layer_count(1);
+            regions.push(vk::ImageBlit::default()
+                .src_subresource(src_subresource)
+                .src_offsets([vk::Offset3D::default(),
+                    vk::Offset3D::default().x(self.extent.width as i32).y(self.extent.height as i32).z(1)])
+                .dst_subresource(dst_subresource)
+                .dst_offsets([vk::Offset3D::default().x((slot_width * layer) as i32),
+                    vk::Offset3D::default()
+                        .x((slot_width * (layer + 1)) as i32)
+                        .y(dst_extent.height as i32)
+                        .z(1)]));
+        }
+
+        unsafe {
+            core.logical_device.cmd_blit_image(command_buffer, self.color_image,
+                                               vk::ImageLayout::TRANSFER_SRC_OPTIMAL, dst_image,
+                                               vk::ImageLayout::TRANSFER_DST_OPTIMAL, regions.as_slice(),
+                                               vk::Filter::LINEAR);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_framebuffer(self.frame_buffer, None);
+            core.logical_device.destroy_render_pass(self.render_pass, None);
+            core.logical_device.destroy_image_view(self.depth_view, None);
+            core.logical_device.destroy_image(self.depth_image, None);
+            core.logical_device.free_memory(self.depth_mem, None);
+            core.logical_device.destroy_image_view(self.color_view, None);
+            core.logical_device.destroy_image(self.color_image, None);
+            core.logical_device.free_memory(self.color_mem, None);
+        }
+    }
+}
+
+// A model drawn for both eyes in one multiview pass, then blitted side by side over the swap chain image. Each eye
+// gets half of the window. The model is sampled through its texture binding only, so virtual textures show the
+// placeholder.
+pub struct StereoView {
+    target: MultiviewTarget,
+    pipeline: RasterPipeline,
+    uniform_buffer: UniformBuffer,
+    descriptor: Descriptor,
+    layout: vk::DescriptorSetLayout, // Owned by the descriptor
+    window_extent: vk::Extent2D,
+    pub eye_separation: f32
+}
+
+fn eye_extent(render_target: &RenderTarget) -> vk::Extent2D {
+    vk::Extent2D {
+        width: (render_target.extent.width / STEREO_VIEW_COUNT).max(1),
+        height: render_target.extent.height
+    }
+}
+
+impl StereoView {
+    pub fn new(core: &VkCore, render_target: &RenderTarget, sampler: vk::Sampler, texture: &Texture,
+               max_frames: usize) -> StereoView {
+        let target = MultiviewTarget::new_stereo(core, eye_extent(render_target), render_target.surface_format);
+        let layout = create_descriptor_set_layout(core);
+        let pipeline = RasterPipeline::new_multiview(core, target.render_pass, layout);
+        let uniform_buffer = UniformBuffer::new_stereo(core, max_frames);
+        let descriptor = Descriptor::new(core, &uniform_buffer, sampler, texture, layout, max_frames);
+
+        StereoView {
+            target,
+            pipeline,
+            uniform_buffer,
+            descriptor,
+            layout,
+            window_extent: render_target.extent,
+            eye_separation: DEFAULT_EYE_SEPARATION
+        }
+    }
+
+    // Per eye extent, for the projection passed to set_transforms
+    pub fn eye_extent(&self) -> vk::Extent2D {
+        self.target.extent
+    }
+
+    // view is the camera's, between the eyes
+    pub fn set_transforms(&self, frame: usize, model: Matrix4<f32>, view: Matrix4<f32>, proj: Matrix4<f32>) {
+        self.uniform_buffer.set_stereo_transforms(frame, model, view, proj, self.eye_separation);
+    }
+
+    // The frame's set must not be in use by a pending command buffer
+    pub fn write_texture(&self, core: &VkCore, frame: usize, sampler: vk::Sampler, texture: &Texture) {
+        self.descriptor.write_texture(core, frame, sampler, texture);
+    }
+
+    // The render pass is compatible with the multiview pipeline only, the pipeline is created again
+    pub fn resize(&mut self, core: &VkCore, render_target: &RenderTarget) {
+        self.pipeline.destroy(core);
+        self.target.destroy(core);
+        self.target = MultiviewTarget::new_stereo(core, eye_extent(render_target),
+                                                  render_target.surface_format);
+        self.window_extent = render_target.extent;
+        self.pipeline = RasterPipeline::new_multiview(core, self.target.render_pass, self.layout);
+    }
+
+    // Last thing over the swap chain image, which must be in PRESENT_SRC_KHR and stays there. draw binds the model's
+    // vertex and index buffers and records its draw, the pipeline, descriptor set and push constants are bound.
+    pub fn cmd_draw<F: FnOnce()>(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize,
+                                 color_constants: &ColorConstants, present_image: vk::Image, draw: F) {
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 1.0]
+                }
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0
+                }
+            }
+        ];
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.target.extent);
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.target.render_pass)
+            .framebuffer(self.target.frame_buffer)
+            .render_area(render_area)
+            .clear_values(&clear_values);
+        let viewports = [vk::Viewport::default()
+            .width(self.target.extent.width as f32)
+            .height(self.target.extent.height as f32)
+            .max_depth(1.0)];
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let layout_barrier = |old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier::default()
+                .image(present_image)
+                .subresource_range(subresource_range)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        };
+        // The passes before may have drawn or copied into the swap chain image last
+        let to_blit = [layout_barrier(vk::ImageLayout::PRESENT_SRC_KHR, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                      vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                                      vk::AccessFlags::TRANSFER_WRITE)];
+        let from_blit = [layout_barrier(vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::PRESENT_SRC_KHR,
+                                        vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::empty())];
+        let logical_device = &core.logical_device;
+
+        unsafe {
+            logical_device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                             self.pipeline.pipelines[0]);
+            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                    self.pipeline.pipeline_layout, 0, &[self.descriptor.sets[frame]],
+                                                    &[]);
+            logical_device.cmd_push_constants(command_buffer, self.pipeline.pipeline_layout,
+                                              vk::ShaderStageFlags::FRAGMENT, 0, cast_to_u8_slice(color_constants));
+            draw();
+            logical_device.cmd_end_render_pass(command_buffer);
+            logical_device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::TRANSFER |
+                                                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(), &[],
+                                                &[], &to_blit);
+            self.target.cmd_blit_side_by_side(core, command_buffer, present_image, self.window_extent);
+            logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::DependencyFlags::empty(),
+                                                &[], &[], &from_blit);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+        self.descriptor.destroy(core);
+        self.uniform_buffer.destroy(core);
+        self.target.destroy(core);
+    }
+}
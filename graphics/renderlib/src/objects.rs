@@ -0,0 +1,647 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::path::Path;
+use std::sync::Arc;
+
+use ash::vk;
+use cgmath::{Matrix4, Point3};
+use tracing::debug;
+
+use crate::clustered_lights::{ClusteredLights, ForwardDraw};
+use crate::collision::{Aabb, BoundingSphere, Frustum, MeshBvh, Ray, RayHit};
+use crate::deletion_queue::{Deletion, DeletionQueue};
+use crate::descriptor::{create_object_descriptor_set_layout, Descriptor};
+use crate::dynamic_ubo::DynamicUniformBuffer;
+use crate::gpu_buffer::{create_buffer, GpuBuffer};
+use crate::index::IndexBuffer;
+use crate::model::{load_obj, Model, Submesh};
+use crate::raster_pipeline::RasterPipeline;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_sampler, destroy_sampler};
+use crate::scene::Material;
+use crate::scene_bvh::SceneBvh;
+use crate::texture::Texture;
+use crate::vertex::Vertex;
+use crate::vkcore::VkCore;
+
+// Triangles in the layout load_obj returns. Submeshes with a material are drawn with it instead of the object's, a
+// mesh without submeshes is drawn as one.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub submeshes: Vec<Submesh>,
+    pub materials: Vec<Material>, // The submeshes' materials
+    pub bounds: Aabb, // Object space, of all vertices, see Mesh::update_bounds
+    pub bounding_sphere: BoundingSphere
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>) -> Mesh {
+        let mut mesh = Mesh {
+            vertices,
+            indices,
+            submeshes: Vec::new(),
+            materials: Vec::new(),
+            bounds: Aabb::empty(),
+            bounding_sphere: BoundingSphere::empty()
+        };
+        mesh.update_bounds();
+
+        mesh
+    }
+
+    pub fn from_model(model: Model) -> Mesh {
+        Mesh {
+            submeshes: model.submeshes,
+            materials: model.materials,
+            ..Mesh::new(model.vertices, model.indices)
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Mesh, String> {
+        if !Path::new(path).is_file() {
+            return Err(format!("{} not found", path));
+        }
+
+        Ok(Mesh::from_model(load_obj(path)?))
+    }
+
+    // After changing vertices in place
+    pub fn update_bounds(&mut self) {
+        let positions: Vec<Point3<f32>> = self.vertices.iter().map(|v| Point3::from(v.pos)).collect();
+        self.bounds = Aabb::from_points(positions.iter().copied());
+        self.bounding_sphere = BoundingSphere::from_points(&positions);
+    }
+
+    // The submeshes that have triangles, the whole mesh with the object's material if it has none
+    fn draw_ranges(&self) -> Vec<Submesh> {
+        match self.submeshes.is_empty() {
+            true => vec![Submesh {
+                first_index: 0,
+                index_count: self.indices.len() as u32,
+                material: None
+            }],
+            false => self.submeshes.iter().filter(|s| s.index_count > 0).copied().collect()
+        }
+    }
+
+    // Of the vertex and index data, objects with equal hashes share their buffers and acceleration structures
+    pub fn hash(&self) -> MeshHash {
+        let mut hasher = DefaultHasher::new();
+        for v in self.vertices.iter() {
+            for f in v.pos.iter().chain(v.color.iter()).chain(v.tex_coord.iter()) {
+                f.to_bits().hash(&mut hasher);
+            }
+        }
+        self.indices.hash(&mut hasher);
+
+        MeshHash(hasher.finish())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MeshHash(u64);
+
+// Stays the same until the object is removed, later objects have greater ids
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectId(u32);
+
+// A mesh placed in the world with a material. Objects share their mesh, adding the same one again doesn't copy it.
+#[derive(Clone, Debug)]
+pub struct SceneObject {
+    pub mesh: Arc<Mesh>,
+    pub mesh_hash: MeshHash,
+    pub transform: Matrix4<f32>,
+    pub material: Material
+}
+
+impl SceneObject {
+    pub fn new(mesh: Arc<Mesh>, transform: Matrix4<f32>, material: Material) -> SceneObject {
+        SceneObject {
+            mesh_hash: mesh.hash(),
+            mesh,
+            transform,
+            material
+        }
+    }
+
+    pub fn world_bounds(&self) -> Aabb {
+        self.mesh.bounds.transformed(self.transform)
+    }
+
+    pub fn world_bounding_sphere(&self) -> BoundingSphere {
+        self.mesh.bounding_sphere.transformed(self.transform)
+    }
+}
+
+// The objects added and removed at runtime, on the CPU. Renderers keep their device side in step with the ids, see
+// ObjectDraws, and can create it again from here. A SceneBvh over the objects' world space bounds follows every
+// change, it culls, picks and orders GPU instance lists.
+#[derive(Default)]
+pub struct ObjectList {
+    objects: Vec<(ObjectId, SceneObject)>,
+    bvhs: HashMap<MeshHash, (MeshBvh, usize)>, // For pick, shared like the meshes, with the number of users
+    scene_bvh: SceneBvh,
+    next_id: u32,
+    generation: u32
+}
+
+impl ObjectList {
+    pub fn add(&mut self, object: SceneObject) -> ObjectId {
+        let id = ObjectId(self.next_id);
+        self.next_id += 1;
+        let (_, users) = self.bvhs.entry(object.mesh_hash)
+            .or_insert_with(|| (MeshBvh::from_mesh(object.mesh.as_ref()), 0));
+        *users += 1;
+        self.scene_bvh.insert(id, object.world_bounds());
+        self.objects.push((id, object));
+        self.generation = self.generation.wrapping_add(1);
+
+        id
+    }
+
+    pub fn remove(&mut self, id: ObjectId) -> Result<SceneObject, String> {
+        let index = self.index(id)?;
+        self.generation = self.generation.wrapping_add(1);
+        let (_, object) = self.objects.remove(index);
+        self.scene_bvh.remove(id);
+        let users = &mut self.bvhs.get_mut(&object.mesh_hash).unwrap().1;
+        *users -= 1;
+        if *users == 0 {
+            self.bvhs.remove(&object.mesh_hash);
+        }
+
+        Ok(object)
+    }
+
+    fn index(&self, id: ObjectId) -> Result<usize, String> {
+        self.objects.binary_search_by_key(&id, |(i, _)| *i).map_err(|_| format!("{:?} doesn't exist", id))
+    }
+
+    // Moves an object, its bounds are refitted in the SceneBvh
+    pub fn set_transform(&mut self, id: ObjectId, transform: Matrix4<f32>) -> Result<(), String> {
+        let index = self.index(id)?;
+        let object = &mut self.objects[index].1;
+        object.transform = transform;
+        self.scene_bvh.update(id, object.world_bounds());
+        self.generation = self.generation.wrapping_add(1);
+
+        Ok(())
+    }
+
+    // The object a world space ray hits first, and where on its mesh
+    pub fn pick(&self, ray: &Ray) -> Option<(ObjectId, RayHit)> {
+        self.scene_bvh.closest_hit(ray, f32::INFINITY, |id, t_max| {
+            let object = self.get(id).unwrap();
+            self.bvhs[&object.mesh_hash].0.intersect_transformed(ray, object.transform, t_max).map(|hit| (hit.t, hit))
+        })
+    }
+
+    // The objects whose bounds intersect the frustum, in the order they were added
+    pub fn visible(&self, frustum: &Frustum) -> Vec<ObjectId> {
+        let mut visible = self.scene_bvh.cull(frustum);
+        visible.sort_unstable();
+
+        visible
+    }
+
+    // World space, empty without objects
+    pub fn bounds(&self) -> Aabb {
+        self.scene_bvh.bounds()
+    }
+
+    // Around the objects' world space spheres, for framing all of them with SceneCamera::framing. Empty without
+    // objects.
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        let spheres: Vec<BoundingSphere> = self.objects.iter().map(|(_, o)| o.world_bounding_sphere()).collect();
+        BoundingSphere::enclosing(&spheres)
+    }
+
+    pub fn scene_bvh(&self) -> &SceneBvh {
+        &self.scene_bvh
+    }
+
+    pub fn get(&self, id: ObjectId) -> Option<&SceneObject> {
+        self.index(id).ok().map(|i| &self.objects[i].1)
+    }
+
+    // In the order they were added
+    pub fn iter(&self) -> impl Iterator<Item = &(ObjectId, SceneObject)> {
+        self.objects.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    // Changes with every add, remove and set_transform
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    // Replaces the objects' materials named like material, the materials of their meshes' submeshes are kept. Returns
+    // the number of objects that use it.
+    pub fn set_material(&mut self, material: &Material) -> usize {
+        let mut count = 0;
+        for (_, object) in self.objects.iter_mut().filter(|(_, o)| o.material.name == material.name) {
+            object.material = material.clone();
+            count += 1;
+        }
+
+        count
+    }
+}
+
+// Material colors are linear, textures are sampled as sRGB
+fn srgb_bytes(color: [f32; 4]) -> [u8; 4] {
+    let encode = |c: f32| (c.clamp(0.0, 1.0).powf(1.0 / 2.2) * 255.0).round() as u8;
+    [encode(color[0]), encode(color[1]), encode(color[2]), (color[3].clamp(0.0, 1.0) * 255.0).round() as u8]
+}
+
+// The material's texture, or a flat one of its base color
+pub(crate) fn material_texture(core: &VkCore, command_pool: vk::CommandPool, material: &Material) -> Texture {
+    match material.texture.as_ref() {
+        Some(path) => Texture::new(core, command_pool, path.as_str()),
+        None => Texture::from_rgba8(core, command_pool, 1, 1, &srgb_bytes(material.base_color))
+    }
+}
+
+// Vertex and index buffers shared by the objects with the same mesh
+struct DrawMesh {
+    vertex_buffer: GpuBuffer,
+    index_buffer: IndexBuffer,
+    users: usize
+}
+
+impl DrawMesh {
+    fn destroy(&self, core: &VkCore) {
+        self.index_buffer.destroy(core);
+        self.vertex_buffer.destroy(core);
+    }
+}
+
+// A draw call of an object, with its material's texture
+struct SubmeshDraw {
+    first_index: u32,
+    index_count: u32,
+    material: Option<String>, // Name of the object's material, None with one of the mesh's
+    texture: Texture,
+    sampler: vk::Sampler,
+    descriptor: Descriptor, // One set per frame, the view's transforms are bound at a dynamic offset
+    generation: u32, // Of the texture, changed by ObjectDraws::set_material
+    written: Vec<u32> // The generation each frame's sets point at
+}
+
+impl SubmeshDraw {
+    fn destroy(&self, core: &VkCore) {
+        self.descriptor.destroy(core);
+        destroy_sampler(core, self.sampler);
+        self.texture.destroy(core);
+    }
+}
+
+struct ObjectDraw {
+    id: ObjectId,
+    mesh: MeshHash,
+    transform: Matrix4<f32>,
+    submeshes: Vec<SubmeshDraw>
+}
+
+impl ObjectDraw {
+    fn destroy(&self, core: &VkCore) {
+        for s in self.submeshes.iter() {
+            s.destroy(core);
+        }
+    }
+}
+
+// Objects ObjectDraws draws at most, the size of ObjectTransforms
+pub const MAX_OBJECT_DRAWS: usize = 1024;
+
+// A view's matrices, in a uniform block per view and frame. The objects' come from ObjectTransforms.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ViewTransforms {
+    pub(crate) view: Matrix4<f32>,
+    pub(crate) proj: Matrix4<f32>
+}
+
+// Per frame host visible storage buffers with a model matrix per object slot, read by the objects' vertex shader at
+// gl_InstanceIndex. Objects are drawn with their slot as first instance, so moving one only rewrites its matrix, its
+// descriptor sets and vertex data stay as they are.
+pub struct ObjectTransforms {
+    pub(crate) data: Vec<vk::Buffer>,
+    pub(crate) range: vk::DeviceSize, // Size of a single frame's matrices
+    mem: Vec<vk::DeviceMemory>,
+    mapped: Vec<*mut c_void>,
+    capacity: usize
+}
+
+impl ObjectTransforms {
+    pub fn new(core: &VkCore, max_frames: usize, capacity: usize) -> ObjectTransforms {
+        let range = (mem::size_of::<Matrix4<f32>>() * capacity) as vk::DeviceSize;
+        let mut transforms = ObjectTransforms {
+            data: vec![],
+            range,
+            mem: vec![],
+            mapped: vec![],
+            capacity
+        };
+
+        for _ in 0..max_frames {
+            let (buf_mem, buffer) = create_buffer(core, range, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                  vk::MemoryPropertyFlags::HOST_COHERENT |
+                                                      vk::MemoryPropertyFlags::HOST_VISIBLE);
+            transforms.mem.push(buf_mem);
+            transforms.data.push(buffer);
+            transforms.mapped.push(unsafe {
+                core.logical_device.map_memory(buf_mem, 0, range, vk::MemoryMapFlags::empty()).unwrap()
+            });
+        }
+
+        transforms
+    }
+
+    // Writes matrices to the first slots of the frame's buffer, which the frame's command buffer must not be using
+    pub fn update(&self, current_frame: usize, matrices: &[Matrix4<f32>]) {
+        assert!(matrices.len() <= self.capacity);
+        unsafe {
+            (self.mapped[current_frame] as *mut Matrix4<f32>)
+                .copy_from_nonoverlapping(matrices.as_ptr(), matrices.len());
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for (buf, mem) in self.data.iter().zip(self.mem.iter()) {
+            unsafe {
+                core.logical_device.destroy_buffer(*buf, None);
+                core.logical_device.free_memory(*mem, None);
+            }
+        }
+    }
+}
+
+// The raster side of an ObjectList, drawn with the clustered forward pipeline after the renderer's own geometry.
+// Every frame, update writes the objects' model matrices to ObjectTransforms in the order of draws, which is each
+// object's slot, and set_transforms a uniform block per view. Every object has one draw per submesh with its
+// material's texture, or a flat texture of its base color without one. The forward shader has no other material
+// inputs. Removed objects, and meshes no object uses anymore, may still be drawn by frames in flight, so they are
+// handed to the renderer's DeletionQueue.
+pub struct ObjectDraws {
+    meshes: HashMap<MeshHash, DrawMesh>,
+    draws: Vec<ObjectDraw>,
+    set_layout: vk::DescriptorSetLayout, // Of the pipeline, every object's descriptor has its own
+    pipeline: RasterPipeline,
+    uniforms: DynamicUniformBuffer, // Every view's transforms
+    view_offsets: Vec<u32>, // Of each view's transforms in the frame being recorded, see set_transforms
+    transforms: ObjectTransforms,
+    max_frames: usize
+}
+
+impl ObjectDraws {
+    pub fn new(core: &VkCore, render_pass: vk::RenderPass, lights: &ClusteredLights,
+               msaa_samples: vk::SampleCountFlags, max_frames: usize, views: usize) -> ObjectDraws {
+        let set_layout = create_object_descriptor_set_layout(core);
+        let block_size = mem::size_of::<ViewTransforms>() as vk::DeviceSize;
+
+        ObjectDraws {
+            meshes: HashMap::new(),
+            draws: Vec::new(),
+            set_layout,
+            pipeline: RasterPipeline::new_objects(core, render_pass, set_layout, lights.set_layout(), msaa_samples),
+            uniforms: DynamicUniformBuffer::new(core, block_size, views, max_frames),
+            view_offsets: vec![0; views],
+            transforms: ObjectTransforms::new(core, max_frames, MAX_OBJECT_DRAWS),
+            max_frames
+        }
+    }
+
+    // id is the object's in the renderer's ObjectList. The mesh's buffers are created unless another object uses
+    // them already.
+    pub fn add(&mut self, core: &VkCore, command_pool: vk::CommandPool, id: ObjectId, object: &SceneObject)
+        -> Result<(), String> {
+        if object.mesh.indices.is_empty() {
+            return Err(format!("The mesh of {:?} has no triangles", id));
+        }
+        if self.draws.len() == MAX_OBJECT_DRAWS {
+            return Err(format!("At most {} objects are drawn", MAX_OBJECT_DRAWS));
+        }
+        let ranges = object.mesh.draw_ranges();
+        let materials: Vec<&Material> = ranges.iter()
+            .map(|r| r.material.map_or(&object.material, |m| &object.mesh.materials[m]))
+            .collect();
+        // Checked before anything is created, load_obj leaves out the textures it doesn't find
+        if let Some(path) = materials.iter().filter_map(|m| m.texture.as_ref()).find(|p| !Path::new(p).is_file()) {
+            return Err(format!("{} not found", path));
+        }
+
+        let mesh = self.meshes.entry(object.mesh_hash).or_insert_with(|| {
+            debug!(vertices = object.mesh.vertices.len(), indices = object.mesh.indices.len(), "Created object mesh");
+            DrawMesh {
+                vertex_buffer: GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::VERTEX_BUFFER,
+                                                          object.mesh.vertices.as_slice(),
+                                                          vk::MemoryPropertyFlags::DEVICE_LOCAL),
+                index_buffer: IndexBuffer::new(core, command_pool, vk::BufferUsageFlags::INDEX_BUFFER,
+                                               object.mesh.indices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL),
+                users: 0
+            }
+        });
+        mesh.users += 1;
+
+        let submeshes = ranges.iter().zip(materials.iter()).map(|(range, material)| {
+            let texture = material_texture(core, command_pool, material);
+            let sampler = create_sampler(core, texture.mip_levels);
+            let set_layout = create_object_descriptor_set_layout(core); // Destroyed with the descriptor
+            let descriptor = Descriptor::new_objects(core, &self.uniforms, &self.transforms, sampler, &texture,
+                                                     set_layout, self.max_frames);
+            SubmeshDraw {
+                first_index: range.first_index,
+                index_count: range.index_count,
+                material: range.material.is_none().then(|| object.material.name.clone()),
+                texture,
+                sampler,
+                descriptor,
+                generation: 0,
+                written: vec![0; self.max_frames]
+            }
+        }).collect();
+        self.draws.push(ObjectDraw {
+            id,
+            mesh: object.mesh_hash,
+            transform: object.transform,
+            submeshes
+        });
+
+        Ok(())
+    }
+
+    // frame_index is the frame being recorded next, see DeletionQueue::push
+    pub fn remove(&mut self, deletions: &mut DeletionQueue, frame_index: u64, id: ObjectId) -> Result<(), String> {
+        let index = match self.draws.iter().position(|d| d.id == id) {
+            Some(index) => index,
+            None => return Err(format!("{:?} isn't drawn", id))
+        };
+        let draw = self.draws.remove(index);
+        let mesh = self.meshes.get_mut(&draw.mesh).unwrap();
+        mesh.users -= 1;
+        if mesh.users == 0 {
+            let mesh = self.meshes.remove(&draw.mesh).unwrap();
+            deletions.push(frame_index, Deletion::Custom(Box::new(move |core| mesh.destroy(core))));
+        }
+        deletions.push(frame_index, Deletion::Custom(Box::new(move |core| draw.destroy(core))));
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.draws.is_empty()
+    }
+
+    // Once the frame's fence has been waited on, before its first set_transforms. The ring region is still read by the
+    // frame that last used the slot until then.
+    pub fn begin_frame(&mut self, current_frame: usize) {
+        self.uniforms.reset(current_frame);
+    }
+
+    // Once per frame and view, after begin_frame
+    pub fn set_transforms(&mut self, current_frame: usize, view_index: usize, view: Matrix4<f32>,
+                          proj: Matrix4<f32>) {
+        let transforms = ViewTransforms {
+            view,
+            proj
+        };
+        // Fits, there is a block per view
+        self.view_offsets[view_index] = self.uniforms.push(current_frame, &transforms).unwrap();
+    }
+
+    // For the frames recorded from now on, only the object's slot in ObjectTransforms changes. See
+    // ObjectList::set_transform.
+    pub fn set_transform(&mut self, id: ObjectId, transform: Matrix4<f32>) -> Result<(), String> {
+        match self.draws.iter_mut().find(|d| d.id == id) {
+            Some(d) => {
+                d.transform = transform;
+                Ok(())
+            },
+            None => Err(format!("{:?} isn't drawn", id))
+        }
+    }
+
+    // Draws the submeshes with the object material named like material with its texture, or its base color without
+    // one, see update. The replaced textures are handed to deletions, like the objects remove drops. Returns the number
+    // of objects that use it.
+    pub fn set_material(&mut self, core: &VkCore, command_pool: vk::CommandPool, deletions: &mut DeletionQueue,
+                        frame_index: u64, material: &Material) -> Result<usize, String> {
+        if let Some(path) = material.texture.as_ref().filter(|p| !Path::new(p).is_file()) {
+            return Err(format!("{} not found", path));
+        }
+        let mut count = 0;
+        for d in self.draws.iter_mut() {
+            let mut uses = false;
+            for s in d.submeshes.iter_mut().filter(|s| s.material.as_deref() == Some(material.name.as_str())) {
+                let texture = material_texture(core, command_pool, material);
+                let sampler = create_sampler(core, texture.mip_levels);
+                let old_texture = mem::replace(&mut s.texture, texture);
+                let old_sampler = mem::replace(&mut s.sampler, sampler);
+                deletions.push(frame_index, Deletion::Texture(old_texture));
+                deletions.push(frame_index, Deletion::Sampler(old_sampler));
+                s.generation = s.generation.wrapping_add(1);
+                uses = true;
+            }
+            count += uses as usize;
+        }
+
+        Ok(count)
+    }
+
+    // Writes the objects' model matrices for the frame and points its sets at the textures set_material replaced, once
+    // the frame's fence was waited on
+    pub fn update(&mut self, core: &VkCore, current_frame: usize) {
+        let models: Vec<Matrix4<f32>> = self.draws.iter().map(|d| d.transform).collect();
+        self.transforms.update(current_frame, &models);
+        for s in self.draws.iter_mut().flat_map(|d| d.submeshes.iter_mut()) {
+            if s.written[current_frame] != s.generation {
+                s.descriptor.write_texture(core, current_frame, s.sampler, &s.texture);
+                s.written[current_frame] = s.generation;
+            }
+        }
+    }
+
+    // Inside the forward render pass, after the viewport and scissor are set, and after update with no add or remove
+    // in between. Only the objects in visible are drawn, which must be sorted like ObjectList::visible returns them.
+    // Leaves the objects' pipeline bound.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, draw: &ForwardDraw,
+                    visible: &[ObjectId]) {
+        if visible.is_empty() {
+            return;
+        }
+        let ForwardDraw { lights, color_constants, current_frame, view_index } = *draw;
+        let layout = self.pipeline.pipeline_layout;
+        unsafe {
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                  self.pipeline.pipelines[0]);
+            lights.cmd_bind(core, command_buffer, layout, current_frame);
+            core.logical_device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                                   cast_to_u8_slice(color_constants));
+            for (slot, d) in self.draws.iter().enumerate().filter(|(_, d)| visible.binary_search(&d.id).is_ok()) {
+                let mesh = &self.meshes[&d.mesh];
+                core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[mesh.vertex_buffer.buf], &[0]);
+                mesh.index_buffer.cmd_bind(core, command_buffer);
+                for s in d.submeshes.iter() {
+                    core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                                 layout, 0, &[s.descriptor.sets[current_frame]],
+                                                                 &[self.view_offsets[view_index]]);
+                    core.logical_device.cmd_draw_indexed(command_buffer, s.index_count, 1, s.first_index, 0,
+                                                         slot as u32);
+                }
+            }
+        }
+    }
+
+    // Only the geometry, for passes that bring their own pipeline such as PointShadows::cmd_render. set_model is
+    // called with each object's transform before it is drawn.
+    pub fn cmd_draw_geometry<F: Fn(Matrix4<f32>)>(&self, core: &VkCore, command_buffer: vk::CommandBuffer,
+                                                  set_model: F) {
+        self.cmd_draw_geometry_where(core, command_buffer, |_| true, set_model);
+    }
+
+    // Same as cmd_draw_geometry, for the objects in ids only. Ids that aren't drawn are skipped.
+    pub fn cmd_draw_geometry_of<F: Fn(Matrix4<f32>)>(&self, core: &VkCore, command_buffer: vk::CommandBuffer,
+                                                     ids: &[ObjectId], set_model: F) {
+        self.cmd_draw_geometry_where(core, command_buffer, |id| ids.contains(&id), set_model);
+    }
+
+    fn cmd_draw_geometry_where<P: Fn(ObjectId) -> bool, F: Fn(Matrix4<f32>)>(&self, core: &VkCore,
+                                                                             command_buffer: vk::CommandBuffer,
+                                                                             drawn: P, set_model: F) {
+        for d in self.draws.iter().filter(|d| drawn(d.id)) {
+            let mesh = &self.meshes[&d.mesh];
+            set_model(d.transform);
+            unsafe {
+                core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[mesh.vertex_buffer.buf], &[0]);
+                mesh.index_buffer.cmd_bind(core, command_buffer);
+                core.logical_device.cmd_draw_indexed(command_buffer, mesh.index_buffer.index_count(), 1, 0, 0, 0);
+            }
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for d in self.draws.iter() {
+            d.destroy(core);
+        }
+        for m in self.meshes.values() {
+            m.destroy(core);
+        }
+        self.pipeline.destroy(core);
+        self.uniforms.destroy(core);
+        self.transforms.destroy(core);
+        unsafe { core.logical_device.destroy_descriptor_set_layout(self.set_layout, None) };
+    }
+}
@@ -0,0 +1,496 @@
+use std::cell::Cell;
+use std::mem;
+
+use ash::extensions::khr;
+use ash::vk;
+use cgmath::{Matrix4, Point3};
+
+use crate::compute::{ComputePipeline, group_count};
+use crate::gpu_buffer::GpuBuffer;
+use crate::image::{create_image_with, ImageInfo};
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::create_nearest_sampler;
+use crate::single_time::{begin_single_time_commands, end_single_time_commands};
+use crate::vkcore::VkCore;
+
+const PYRAMID_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+const PYRAMID_INIT_SHADER_PATH: &str = "graphics/shaders/spv/depth_pyramid_init.spv";
+const PYRAMID_INIT_MS_SHADER_PATH: &str = "graphics/shaders/spv/depth_pyramid_init_ms.spv";
+const PYRAMID_REDUCE_SHADER_PATH: &str = "graphics/shaders/spv/depth_pyramid.spv";
+const CULL_SHADER_PATH: &str = "graphics/shaders/spv/occlusion_cull.spv";
+const PYRAMID_GROUP_SIZE: u32 = 8; // Matches local_size_x/y in the pyramid shaders
+const CULL_GROUP_SIZE: u32 = 64; // Matches local_size_x in occlusion_cull.comp
+
+// World space bounding box of one draw, vec4s to match the std430 layout in occlusion_cull.comp
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct DrawBounds {
+    pub min: [f32; 4],
+    pub max: [f32; 4]
+}
+
+impl DrawBounds {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> DrawBounds {
+        DrawBounds {
+            min: [min.x, min.y, min.z, 0.0],
+            max: [max.x, max.y, max.z, 0.0]
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct CullConstants {
+    view_proj: Matrix4<f32>,
+    pyramid_size: [f32; 2],
+    input_count: u32,
+    culling_enabled: u32
+}
+
+fn mip_levels_for(extent: vk::Extent2D) -> u32 {
+    32 - extent.width.max(extent.height).max(1).leading_zeros()
+}
+
+fn create_level_view(core: &VkCore, image: vk::Image, base_level: u32, level_count: u32) -> vk::ImageView {
+    let view_create_info = vk::ImageViewCreateInfo::default()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(PYRAMID_FORMAT)
+        .subresource_range(vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(base_level)
+            .level_count(level_count)
+            .base_array_layer(0)
+            .layer_count(1));
+
+    unsafe { core.logical_device.create_image_view(&view_create_info, None).unwrap() }
+}
+
+// Hierarchical Z buffer, every texel of level n holds the farthest depth of the texels it covers in level n - 1.
+// Kept in the GENERAL layout for its whole lifetime, and shared with the compute family for AsyncCompute builds.
+struct DepthPyramid {
+    image: vk::Image,
+    mem: vk::DeviceMemory,
+    view: vk::ImageView, // Every level, sampled by the culling shader
+    level_views: Vec<vk::ImageView>, // One per level, written by the pyramid shaders
+    extent: vk::Extent2D
+}
+
+impl DepthPyramid {
+    fn new(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D) -> DepthPyramid {
+        let mip_levels = mip_levels_for(extent);
+        let (image, mem) = create_image_with(core, &ImageInfo {
+            mip_levels,
+            queue_families: &[core.graphics_family_index, core.compute_family_index],
+            ..ImageInfo::new(extent.width, extent.height, PYRAMID_FORMAT,
+                             vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+        });
+        let view = create_level_view(core, image, 0, mip_levels);
+        let level_views = (0..mip_levels).map(|l| create_level_view(core, image, l, 1)).collect();
+
+        let barrier = vk::ImageMemoryBarrier::default()
+            .image(image)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(vk::ImageSubresourceRange::default()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .base_mip_level(0)
+                .level_count(mip_levels)
+                .base_array_layer(0)
+                .layer_count(1));
+        let command_buffer = begin_single_time_commands(core, command_pool);
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[], &[], &[barrier]);
+        }
+        end_single_time_commands(core, command_pool, command_buffer);
+
+        DepthPyramid {
+            image,
+            mem,
+            view,
+            level_views,
+            extent
+        }
+    }
+
+    fn level_extent(&self, level: usize) -> (u32, u32) {
+        ((self.extent.width >> level).max(1), (self.extent.height >> level).max(1))
+    }
+
+    fn destroy(&self, core: &VkCore) {
+        unsafe {
+            for v in self.level_views.iter() {
+                core.logical_device.destroy_image_view(*v, None);
+            }
+            core.logical_device.destroy_image_view(self.view, None);
+            core.logical_device.destroy_image(self.image, None);
+            core.logical_device.free_memory(self.mem, None);
+        }
+    }
+}
+
+fn create_pyramid_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    let bindings = [
+        vk::DescriptorSetLayoutBinding::default() // Depth buffer, level 0 only
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE),
+        vk::DescriptorSetLayoutBinding::default() // Previous level, every other level
+            .binding(1)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE),
+        vk::DescriptorSetLayoutBinding::default() // Level being written
+            .binding(2)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+    ];
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(&bindings);
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+fn create_cull_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    let mut bindings = vec![
+        vk::DescriptorSetLayoutBinding::default() // Depth pyramid
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+    ];
+    for b in 1..5 { // Bounds, input draws, output draws, draw count
+        bindings.push(vk::DescriptorSetLayoutBinding::default()
+            .binding(b)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE));
+    }
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(bindings.as_slice());
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+// GPU occlusion culling for indexed indirect draws. Each frame:
+// 1. cmd_cull, outside a render pass. Tests every draw against the pyramid built from the previous frame's depth and
+//    compacts the visible ones into the output draw buffer.
+// 2. cmd_draw, inside the render pass, with the vertex/index buffers and pipeline already bound.
+// 3. cmd_build_pyramid, after the render pass, or cmd_build_pyramid_async in the AsyncCompute submission that
+//    follows the frame. The depth buffer must come from Depth::new_sampled and the render pass from
+//    setup_render_pass_stored_depth.
+// Until the first pyramid is built every draw is treated as visible.
+pub struct OcclusionCuller {
+    pyramid: DepthPyramid,
+    pyramid_set_layout: vk::DescriptorSetLayout,
+    cull_set_layout: vk::DescriptorSetLayout,
+    pyramid_init: ComputePipeline,
+    pyramid_reduce: ComputePipeline,
+    cull: ComputePipeline,
+    descriptor_pool: vk::DescriptorPool,
+    pyramid_sets: Vec<vk::DescriptorSet>, // One per pyramid level
+    cull_set: vk::DescriptorSet,
+    sampler: vk::Sampler,
+    bounds: GpuBuffer,
+    input_draws: GpuBuffer,
+    output_draws: GpuBuffer,
+    draw_count: GpuBuffer,
+    max_draws: u32,
+    draw_indirect_count: Option<khr::DrawIndirectCount>,
+    pyramid_built: Cell<bool>
+}
+
+impl OcclusionCuller {
+    // draws[n] is culled with bounds[n]. depth_samples is the sample count of the depth buffer.
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D, depth_view: vk::ImageView,
+               depth_samples: vk::SampleCountFlags, draws: &[vk::DrawIndexedIndirectCommand],
+               bounds: &[DrawBounds]) -> OcclusionCuller {
+        assert_eq!(draws.len(), bounds.len());
+        let pyramid_set_layout = create_pyramid_set_layout(core);
+        let cull_set_layout = create_cull_set_layout(core);
+        let init_shader = match depth_samples {
+            vk::SampleCountFlags::TYPE_1 => PYRAMID_INIT_SHADER_PATH,
+            _ => PYRAMID_INIT_MS_SHADER_PATH
+        };
+        let pyramid_init = ComputePipeline::new(core, init_shader, &[pyramid_set_layout], 0);
+        let pyramid_reduce = ComputePipeline::new(core, PYRAMID_REDUCE_SHADER_PATH, &[pyramid_set_layout], 0);
+        let cull = ComputePipeline::new(core, CULL_SHADER_PATH, &[cull_set_layout],
+                                        mem::size_of::<CullConstants>() as u32);
+
+        let draws_size = (mem::size_of::<vk::DrawIndexedIndirectCommand>() * draws.len()) as vk::DeviceSize;
+        let bounds_buf = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER, bounds,
+                                                    vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let input_draws = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER, draws,
+                                                     vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let output_draws = GpuBuffer::new(core, draws_size,
+                                          vk::BufferUsageFlags::STORAGE_BUFFER |
+                                              vk::BufferUsageFlags::INDIRECT_BUFFER |
+                                              vk::BufferUsageFlags::TRANSFER_DST,
+                                          vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let draw_count = GpuBuffer::new(core, mem::size_of::<u32>() as vk::DeviceSize,
+                                        vk::BufferUsageFlags::STORAGE_BUFFER |
+                                            vk::BufferUsageFlags::INDIRECT_BUFFER |
+                                            vk::BufferUsageFlags::TRANSFER_DST |
+                                            vk::BufferUsageFlags::TRANSFER_SRC,
+                                        vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let draw_indirect_count = match core.draw_indirect_count_supported {
+            true => Some(khr::DrawIndirectCount::new(&core.instance, &core.logical_device)),
+            false => None
+        };
+
+        let mut culler = OcclusionCuller {
+            pyramid: DepthPyramid::new(core, command_pool, extent),
+            pyramid_set_layout,
+            cull_set_layout,
+            pyramid_init,
+            pyramid_reduce,
+            cull,
+            descriptor_pool: vk::DescriptorPool::null(),
+            pyramid_sets: Vec::new(),
+            cull_set: vk::DescriptorSet::null(),
+            sampler: create_nearest_sampler(core),
+            bounds: bounds_buf,
+            input_draws,
+            output_draws,
+            draw_count,
+            max_draws: draws.len() as u32,
+            draw_indirect_count,
+            pyramid_built: Cell::new(false)
+        };
+        culler.write_descriptor_sets(core, depth_view);
+
+        culler
+    }
+
+    fn write_descriptor_sets(&mut self, core: &VkCore, depth_view: vk::ImageView) {
+        let level_count = self.pyramid.level_views.len() as u32;
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(level_count + 1),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(level_count * 2),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(4)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(level_count + 1)
+            .pool_sizes(&pool_sizes);
+        self.descriptor_pool = unsafe {
+            core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap()
+        };
+
+        let mut layouts = vec![self.pyramid_set_layout; level_count as usize];
+        layouts.push(self.cull_set_layout);
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let mut sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        self.cull_set = sets.pop().unwrap();
+        self.pyramid_sets = sets;
+
+        let depth_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(depth_view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+        let level_infos: Vec<[vk::DescriptorImageInfo; 1]> = self.pyramid.level_views.iter()
+            .map(|&v| [vk::DescriptorImageInfo::default()
+                .image_view(v)
+                .image_layout(vk::ImageLayout::GENERAL)])
+            .collect();
+        let mut writes: Vec<vk::WriteDescriptorSet> = Vec::new();
+        for (level, &set) in self.pyramid_sets.iter().enumerate() {
+            // Level 0 reads the depth buffer, every other level reads the one before it
+            match level {
+                0 => writes.push(vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&depth_info)),
+                _ => writes.push(vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&level_infos[level - 1]))
+            }
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&level_infos[level]));
+        }
+
+        let pyramid_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(self.pyramid.view)
+            .image_layout(vk::ImageLayout::GENERAL)];
+        let buffer_infos: Vec<[vk::DescriptorBufferInfo; 1]> = [&self.bounds, &self.input_draws,
+            &self.output_draws, &self.draw_count].iter()
+            .map(|b| [vk::DescriptorBufferInfo::default()
+                .buffer(b.buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)])
+            .collect();
+        writes.push(vk::WriteDescriptorSet::default()
+            .dst_set(self.cull_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&pyramid_info));
+        for (n, info) in buffer_infos.iter().enumerate() {
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(self.cull_set)
+                .dst_binding(n as u32 + 1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(info));
+        }
+
+        unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+    }
+
+    // Call after the swap chain and depth buffer were recreated
+    pub fn resize(&mut self, core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D,
+                  depth_view: vk::ImageView) {
+        unsafe { core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None) };
+        self.pyramid.destroy(core);
+        self.pyramid = DepthPyramid::new(core, command_pool, extent);
+        self.pyramid_built.set(false);
+        self.write_descriptor_sets(core, depth_view);
+    }
+
+    pub fn cmd_cull(&self, core: &VkCore, command_buffer: vk::CommandBuffer, view_proj: Matrix4<f32>) {
+        let constants = [CullConstants {
+            view_proj,
+            pyramid_size: [self.pyramid.extent.width as f32, self.pyramid.extent.height as f32],
+            input_count: self.max_draws,
+            culling_enabled: self.pyramid_built.get() as u32
+        }];
+        // Previous frame's indirect reads and pyramid writes
+        let clear_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+        let cull_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let draw_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ);
+
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer,
+                                                     vk::PipelineStageFlags::DRAW_INDIRECT |
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::TRANSFER,
+                                                     vk::DependencyFlags::empty(), &[clear_barrier], &[], &[]);
+            core.logical_device.cmd_fill_buffer(command_buffer, self.draw_count.buf, 0, vk::WHOLE_SIZE, 0);
+            if self.draw_indirect_count.is_none() {
+                // Without a draw count every slot is drawn, zeroed commands draw nothing
+                core.logical_device.cmd_fill_buffer(command_buffer, self.output_draws.buf, 0, vk::WHOLE_SIZE, 0);
+            }
+            core.logical_device.cmd_pipeline_barrier(command_buffer,
+                                                     vk::PipelineStageFlags::TRANSFER |
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[cull_barrier], &[], &[]);
+            self.cull.cmd_bind(core, command_buffer, self.cull_set);
+            core.logical_device.cmd_push_constants(command_buffer, self.cull.layout, vk::ShaderStageFlags::COMPUTE,
+                                                   0, cast_to_u8_slice(&constants));
+            core.logical_device.cmd_dispatch(command_buffer, group_count(self.max_draws, CULL_GROUP_SIZE), 1, 1);
+            // The fragment test stages keep the render pass from clearing the depth buffer while the previous
+            // frame's pyramid build may still read it
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::DRAW_INDIRECT |
+                                                         vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS |
+                                                         vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                                                     vk::DependencyFlags::empty(), &[draw_barrier], &[], &[]);
+        }
+    }
+
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        let stride = mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32;
+        unsafe {
+            match &self.draw_indirect_count {
+                Some(loader) => loader.cmd_draw_indexed_indirect_count(command_buffer, self.output_draws.buf, 0,
+                                                                       self.draw_count.buf, 0, self.max_draws,
+                                                                       stride),
+                None => core.logical_device.cmd_draw_indexed_indirect(command_buffer, self.output_draws.buf, 0,
+                                                                      self.max_draws, stride)
+            }
+        }
+    }
+
+    // Draws that passed the last submitted cull, for debugging. Stalls until the graphics queue gets there.
+    pub fn visible_draw_count(&self, core: &VkCore, command_pool: vk::CommandPool) -> u32 {
+        let bytes = self.draw_count.read_back(core, command_pool, 0..mem::size_of::<u32>() as vk::DeviceSize);
+        u32::from_ne_bytes(bytes.as_slice().try_into().unwrap())
+    }
+
+    pub fn cmd_build_pyramid(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        self.record_pyramid_build(core, command_buffer,
+                                  vk::PipelineStageFlags::LATE_FRAGMENT_TESTS | vk::PipelineStageFlags::COMPUTE_SHADER);
+    }
+
+    // For a command buffer submitted with AsyncCompute::submit. The semaphore wait on the frame's graphics
+    // submission already covers the depth writes and culling reads, the compute queue may not support the
+    // fragment stages anyway.
+    pub fn cmd_build_pyramid_async(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        self.record_pyramid_build(core, command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER);
+    }
+
+    fn record_pyramid_build(&self, core: &VkCore, command_buffer: vk::CommandBuffer,
+                            src_stages: vk::PipelineStageFlags) {
+        // Depth writes from the render pass and culling reads of the old pyramid
+        let depth_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let level_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, src_stages,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[depth_barrier], &[], &[]);
+            for (level, &set) in self.pyramid_sets.iter().enumerate() {
+                let pipeline = match level {
+                    0 => &self.pyramid_init,
+                    _ => &self.pyramid_reduce
+                };
+                let (width, height) = self.pyramid.level_extent(level);
+                pipeline.cmd_bind(core, command_buffer, set);
+                core.logical_device.cmd_dispatch(command_buffer, group_count(width, PYRAMID_GROUP_SIZE),
+                                                 group_count(height, PYRAMID_GROUP_SIZE), 1);
+                core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::DependencyFlags::empty(), &[level_barrier], &[], &[]);
+            }
+        }
+        self.pyramid_built.set(true);
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.pyramid_set_layout, None);
+            core.logical_device.destroy_descriptor_set_layout(self.cull_set_layout, None);
+            core.logical_device.destroy_sampler(self.sampler, None);
+        }
+        self.pyramid_init.destroy(core);
+        self.pyramid_reduce.destroy(core);
+        self.cull.destroy(core);
+        self.pyramid.destroy(core);
+        self.bounds.destroy(core);
+        self.input_draws.destroy(core);
+        self.output_draws.destroy(core);
+        self.draw_count.destroy(core);
+    }
+}
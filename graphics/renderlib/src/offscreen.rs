@@ -0,0 +1,205 @@
+use ash::vk;
+
+use crate::depth::find_depth_format;
+use crate::image::{create_image, create_image_view};
+use crate::memory::transient_memory_flags;
+use crate::vkcore::VkCore;
+
+// An image with its memory and a view of all of it
+pub(crate) struct Attachment {
+    pub image: vk::Image,
+    mem: vk::DeviceMemory,
+    pub view: vk::ImageView
+}
+
+impl Attachment {
+    pub fn new(core: &VkCore, extent: vk::Extent2D, format: vk::Format, usage: vk::ImageUsageFlags,
+               samples: vk::SampleCountFlags, aspect: vk::ImageAspectFlags) -> Attachment {
+        let properties = match usage.contains(vk::ImageUsageFlags::TRANSIENT_ATTACHMENT) {
+            true => transient_memory_flags(core),
+            false => vk::MemoryPropertyFlags::DEVICE_LOCAL
+        };
+        let (image, mem) = create_image(core, extent.width, extent.height, 1, format, vk::ImageTiling::OPTIMAL, usage,
+                                        properties, samples);
+        let view = create_image_view(core, image, format, aspect, 1);
+
+        Attachment {
+            image,
+            mem,
+            view
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_image_view(self.view, None);
+            core.logical_device.destroy_image(self.image, None);
+            core.logical_device.free_memory(self.mem, None);
+        }
+    }
+}
+
+// Compatible with setup_render_pass_stored_depth for the same formats and samples, so the forward pipelines draw into
+// offscreen targets as well. The resolved image ends up readable by fragment shaders.
+pub(crate) fn create_offscreen_render_pass(core: &VkCore, surface_format: vk::Format, depth_format: vk::Format,
+                                 samples: vk::SampleCountFlags) -> vk::RenderPass {
+    let attachment_desc = [
+        vk::AttachmentDescription::default()
+            .format(surface_format)
+            .samples(samples)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+        vk::AttachmentDescription::default()
+            .format(depth_format)
+            .samples(samples)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+        vk::AttachmentDescription::default()
+            .format(surface_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    ];
+    let color_ref = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    let depth_ref = vk::AttachmentReference::default()
+        .attachment(1)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+    let resolve_ref = [vk::AttachmentReference::default()
+        .attachment(2)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    let subpass = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&color_ref)
+        .depth_stencil_attachment(&depth_ref)
+        .resolve_attachments(&resolve_ref)];
+    // The previous frame reads the resolved image, and this frame's readers wait for the resolve
+    let dependencies = [
+        vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER |
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT |
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE |
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+        vk::SubpassDependency::default()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+    ];
+    let render_pass_create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachment_desc)
+        .subpasses(&subpass)
+        .dependencies(&dependencies);
+
+    unsafe { core.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+}
+
+// Where the scene is drawn again from another camera, like the water's reflection, for the pass of
+// create_offscreen_render_pass
+pub(crate) struct OffscreenTarget {
+    color: Attachment, // Multisampled, resolved into resolved
+    depth: Attachment,
+    pub resolved: Attachment,
+    frame_buffer: vk::Framebuffer,
+    pub extent: vk::Extent2D
+}
+
+impl OffscreenTarget {
+    pub fn new(core: &VkCore, render_pass: vk::RenderPass, surface_format: vk::Format, extent: vk::Extent2D)
+        -> OffscreenTarget {
+        let color = Attachment::new(core, extent, surface_format,
+                                    vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                                    core.max_msaa_samples, vk::ImageAspectFlags::COLOR);
+        let depth = Attachment::new(core, extent, find_depth_format(core),
+                                    vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT |
+                                        vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                                    core.max_msaa_samples, vk::ImageAspectFlags::DEPTH);
+        let resolved = Attachment::new(core, extent, surface_format,
+                                       vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                                       vk::SampleCountFlags::TYPE_1, vk::ImageAspectFlags::COLOR);
+        let attachments = [color.view, depth.view, resolved.view];
+        let create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let frame_buffer = unsafe { core.logical_device.create_framebuffer(&create_info, None).unwrap() };
+
+        OffscreenTarget {
+            color,
+            depth,
+            resolved,
+            frame_buffer,
+            extent
+        }
+    }
+
+    // Clears the target and sets the viewport and scissor to all of it, draw records the scene inside the render pass
+    pub fn cmd_draw<F: FnOnce()>(&self, core: &VkCore, command_buffer: vk::CommandBuffer, render_pass: vk::RenderPass,
+                                 draw: F) {
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 1.0]
+                }
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue::default()
+                    .depth(1.0)
+                    .stencil(0)
+            }
+        ];
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.extent);
+        let viewports = [vk::Viewport::default()
+            .x(0.0)
+            .y(0.0)
+            .width(self.extent.width as f32)
+            .height(self.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(render_pass)
+            .framebuffer(self.frame_buffer)
+            .render_area(render_area)
+            .clear_values(&clear_values);
+
+        unsafe {
+            core.logical_device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            core.logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            core.logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+        }
+        draw();
+        unsafe { core.logical_device.cmd_end_render_pass(command_buffer) };
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe { core.logical_device.destroy_framebuffer(self.frame_buffer, None) };
+        self.color.destroy(core);
+        self.depth.destroy(core);
+        self.resolved.destroy(core);
+    }
+}
+
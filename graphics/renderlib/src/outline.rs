@@ -0,0 +1,419 @@
+use ash::vk;
+use cgmath::Matrix4;
+
+use crate::depth::{Depth, find_depth_stencil_format};
+use crate::frame_buffers::destroy_frame_buffers;
+use crate::image::{create_image, create_image_view};
+use crate::objects::{ObjectDraws, ObjectId};
+use crate::raster_pipeline::RasterPipeline;
+use crate::render_target::RenderTarget;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_nearest_sampler, destroy_sampler};
+use crate::ssao::create_set_layout;
+use crate::vkcore::VkCore;
+
+const MASK_FORMAT: vk::Format = vk::Format::R8_UNORM;
+const MAX_OUTLINE_WIDTH: u32 = 8; // Must match MAX_WIDTH in outline.frag, every pixel reads (2 * width + 1)^2 texels
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutlineSettings {
+    pub color: [f32; 4], // Linear RGB of the outline and its opacity
+    pub width: u32 // In pixels, at most MAX_OUTLINE_WIDTH
+}
+
+impl Default for OutlineSettings {
+    fn default() -> OutlineSettings {
+        OutlineSettings {
+            color: [1.0, 0.45, 0.05, 1.0],
+            width: 2
+        }
+    }
+}
+
+impl OutlineSettings {
+    pub fn validated(&self) -> OutlineSettings {
+        OutlineSettings {
+            width: self.width.clamp(1, MAX_OUTLINE_WIDTH),
+            ..*self
+        }
+    }
+}
+
+// Matches the push constants in outline_mask.vert
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct MaskConstants {
+    model_view_proj: Matrix4<f32>
+}
+
+// Matches the push constants in outline.frag
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OutlineConstants {
+    color: [f32; 4],
+    params: [i32; 4] // The width
+}
+
+// Cleared every time the selection is drawn, and left for the outline's fragment shader to read. The stencil marks the
+// same pixels for the outline's render pass.
+fn create_mask_render_pass(core: &VkCore, stencil_format: vk::Format) -> vk::RenderPass {
+    let attachment_desc = [
+        vk::AttachmentDescription::default()
+            .format(MASK_FORMAT)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        vk::AttachmentDescription::default()
+            .format(stencil_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(vk::AttachmentLoadOp::CLEAR)
+            .stencil_store_op(vk::AttachmentStoreOp::STORE)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+    ];
+    let attachment_ref = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    let stencil_ref = vk::AttachmentReference::default()
+        .attachment(1)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+    let subpass = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&attachment_ref)
+        .depth_stencil_attachment(&stencil_ref)];
+    // The previous frame's outline reads the mask and the stencil before they are cleared, this frame's reads them
+    // after they are drawn
+    let dependencies = [
+        vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT |
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dependency_flags(vk::DependencyFlags::empty()),
+        vk::SubpassDependency::default()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT |
+                vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ)
+            .dependency_flags(vk::DependencyFlags::empty())
+    ];
+    let render_pass_create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachment_desc)
+        .subpasses(&subpass)
+        .dependencies(&dependencies);
+
+    unsafe { core.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+}
+
+// Like ssao::create_apply_render_pass, with the mask pass' stencil to test against
+fn create_apply_render_pass(core: &VkCore, render_target: &RenderTarget, stencil_format: vk::Format)
+    -> vk::RenderPass {
+    let attachment_desc = [
+        vk::AttachmentDescription::default()
+            .format(render_target.surface_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::LOAD)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .final_layout(vk::ImageLayout::PRESENT_SRC_KHR),
+        vk::AttachmentDescription::default()
+            .format(stencil_format)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::DONT_CARE)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .stencil_load_op(vk::AttachmentLoadOp::LOAD)
+            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .initial_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+    ];
+    let attachment_ref = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    let stencil_ref = vk::AttachmentReference::default()
+        .attachment(1)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+    let subpass = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&attachment_ref)
+        .depth_stencil_attachment(&stencil_ref)];
+    // The main render pass' resolve writes, the mask pass waits for its own
+    let dependencies = [vk::SubpassDependency::default()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(0)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dependency_flags(vk::DependencyFlags::empty())];
+    let render_pass_create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachment_desc)
+        .subpasses(&subpass)
+        .dependencies(&dependencies);
+
+    unsafe { core.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+}
+
+fn create_apply_frame_buffers(core: &VkCore, render_pass: vk::RenderPass, render_target: &RenderTarget,
+                              stencil_view: vk::ImageView) -> Vec<vk::Framebuffer> {
+    render_target.image_views.iter()
+        .map(|v| {
+            let attachments = [*v, stencil_view];
+            let create_info = vk::FramebufferCreateInfo::default()
+                .render_pass(render_pass)
+                .attachments(&attachments)
+                .width(render_target.extent.width)
+                .height(render_target.extent.height)
+                .layers(1);
+
+            unsafe { core.logical_device.create_framebuffer(&create_info, None).unwrap() }
+        })
+        .collect()
+}
+
+// The window sized silhouette of the selection, in color for sampling and in the stencil for testing
+struct Mask {
+    image: vk::Image,
+    mem: vk::DeviceMemory,
+    view: vk::ImageView,
+    stencil: Depth,
+    frame_buffer: vk::Framebuffer
+}
+
+impl Mask {
+    fn new(core: &VkCore, command_pool: vk::CommandPool, render_pass: vk::RenderPass, extent: vk::Extent2D) -> Mask {
+        let (image, mem) = create_image(core, extent.width, extent.height, 1, MASK_FORMAT, vk::ImageTiling::OPTIMAL,
+                                        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                                        vk::MemoryPropertyFlags::DEVICE_LOCAL, vk::SampleCountFlags::TYPE_1);
+        let view = create_image_view(core, image, MASK_FORMAT, vk::ImageAspectFlags::COLOR, 1);
+        let stencil = Depth::new_stencil(core, extent, command_pool);
+        let attachments = [view, stencil.view];
+        let create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let frame_buffer = unsafe { core.logical_device.create_framebuffer(&create_info, None).unwrap() };
+
+        Mask {
+            image,
+            mem,
+            view,
+            stencil,
+            frame_buffer
+        }
+    }
+
+    fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_framebuffer(self.frame_buffer, None);
+            core.logical_device.destroy_image_view(self.view, None);
+            core.logical_device.destroy_image(self.image, None);
+            core.logical_device.free_memory(self.mem, None);
+        }
+        self.stencil.destroy(core);
+    }
+}
+
+// Selection highlight for the raster renderer. cmd_draw, once the swap chain image is otherwise finished:
+// 1. Draws the selected objects into a single channel mask and a stencil, without depth testing so that the outline
+//    also shows where the selection is hidden behind something.
+// 2. Dilates the mask by the outline's width in a fullscreen pass, and blends the outline's color over the swap chain
+//    image wherever the dilation reaches but the mask doesn't. The stencil test skips the selection's own pixels
+//    before their fragments run the dilation.
+// Nothing is recorded while the selection is empty.
+pub struct Outline {
+    settings: OutlineSettings,
+    extent: vk::Extent2D,
+    mask_render_pass: vk::RenderPass,
+    mask: Mask,
+    mask_pipeline: RasterPipeline,
+    sampler: vk::Sampler,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    apply_render_pass: vk::RenderPass,
+    apply_frame_buffers: Vec<vk::Framebuffer>,
+    apply: RasterPipeline
+}
+
+impl Outline {
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget, settings: OutlineSettings)
+        -> Outline {
+        let stencil_format = find_depth_stencil_format(core);
+        let mask_render_pass = create_mask_render_pass(core, stencil_format);
+        let set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT)
+        ]);
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = [set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let set = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap()[0] };
+        let apply_render_pass = create_apply_render_pass(core, render_target, stencil_format);
+        let mask = Mask::new(core, command_pool, mask_render_pass, render_target.extent);
+        let apply_frame_buffers = create_apply_frame_buffers(core, apply_render_pass, render_target, mask.stencil.view);
+
+        let outline = Outline {
+            settings: settings.validated(),
+            extent: render_target.extent,
+            mask_render_pass,
+            mask,
+            mask_pipeline: RasterPipeline::new_outline_mask(core, mask_render_pass),
+            sampler: create_nearest_sampler(core),
+            set_layout,
+            descriptor_pool,
+            set,
+            apply_render_pass,
+            apply_frame_buffers,
+            apply: RasterPipeline::new_outline(core, apply_render_pass, set_layout)
+        };
+        outline.write_descriptor_set(core);
+
+        outline
+    }
+
+    fn write_descriptor_set(&self, core: &VkCore) {
+        let mask_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(self.mask.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let write = [vk::WriteDescriptorSet::default()
+            .dst_set(self.set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(&mask_info)];
+        unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+    }
+
+    // After the swap chain was created again, nothing may still be using the old mask
+    pub fn resize(&mut self, core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget) {
+        self.mask.destroy(core);
+        destroy_frame_buffers(core, &self.apply_frame_buffers);
+        self.extent = render_target.extent;
+        self.mask = Mask::new(core, command_pool, self.mask_render_pass, render_target.extent);
+        self.apply_frame_buffers = create_apply_frame_buffers(core, self.apply_render_pass, render_target,
+                                                              self.mask.stencil.view);
+        self.write_descriptor_set(core);
+    }
+
+    pub fn settings(&self) -> OutlineSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: OutlineSettings) {
+        self.settings = settings.validated();
+    }
+
+    // Records both passes after everything else was drawn into the swap chain image, which is left in
+    // PRESENT_SRC_KHR. view_proj must be the one the image was drawn with.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, image_index: u32,
+                    view_proj: Matrix4<f32>, objects: &ObjectDraws, selection: &[ObjectId]) {
+        if selection.is_empty() {
+            return;
+        }
+        let constants = [OutlineConstants {
+            color: self.settings.color,
+            params: [self.settings.width as i32, 0, 0, 0]
+        }];
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.extent);
+        let viewports = [vk::Viewport::default()
+            .x(0.0)
+            .y(0.0)
+            .width(self.extent.width as f32)
+            .height(self.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue { float32: [0.0; 4] }
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue::default().depth(1.0).stencil(0)
+            }
+        ];
+        let mask_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.mask_render_pass)
+            .framebuffer(self.mask.frame_buffer)
+            .render_area(render_area)
+            .clear_values(&clear_values);
+        let apply_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.apply_render_pass)
+            .framebuffer(self.apply_frame_buffers[image_index as usize])
+            .render_area(render_area);
+        // The post-process chain may have copied into the swap chain image last
+        let image_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        let logical_device = &core.logical_device;
+        let mask_layout = self.mask_pipeline.pipeline_layout;
+
+        unsafe {
+            logical_device.cmd_begin_render_pass(command_buffer, &mask_pass_info, vk::SubpassContents::INLINE);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                             self.mask_pipeline.pipelines[0]);
+            logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+            objects.cmd_draw_geometry_of(core, command_buffer, selection, |model| {
+                let constants = [MaskConstants { model_view_proj: view_proj * model }];
+                logical_device.cmd_push_constants(command_buffer, mask_layout, vk::ShaderStageFlags::VERTEX, 0,
+                                                  cast_to_u8_slice(&constants));
+            });
+            logical_device.cmd_end_render_pass(command_buffer);
+
+            logical_device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::TRANSFER |
+                                                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::DependencyFlags::empty(), &[image_barrier], &[], &[]);
+            logical_device.cmd_begin_render_pass(command_buffer, &apply_pass_info, vk::SubpassContents::INLINE);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                             self.apply.pipelines[0]);
+            logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                    self.apply.pipeline_layout, 0, &[self.set], &[]);
+            logical_device.cmd_push_constants(command_buffer, self.apply.pipeline_layout,
+                                              vk::ShaderStageFlags::FRAGMENT, 0, cast_to_u8_slice(&constants));
+            logical_device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            logical_device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.mask.destroy(core);
+        destroy_frame_buffers(core, &self.apply_frame_buffers);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            core.logical_device.destroy_render_pass(self.mask_render_pass, None);
+            core.logical_device.destroy_render_pass(self.apply_render_pass, None);
+        }
+        destroy_sampler(core, self.sampler);
+        self.mask_pipeline.destroy(core);
+        self.apply.destroy(core);
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use cgmath::{InnerSpace, Matrix3, Matrix4, Quaternion, Vector3};
+use rapier3d::na::{Quaternion as NaQuaternion, UnitQuaternion};
+use rapier3d::prelude::*;
+
+use crate::collision::Aabb;
+use crate::objects::{ObjectId, SceneObject};
+
+pub use rapier3d; // Consumers must use the same rapier3d as renderlib
+
+pub const FIXED_TIMESTEP: Duration = Duration::from_nanos(16_666_667);
+const MAX_STEPS_PER_UPDATE: u32 = 5; // Time beyond this is dropped, so that a slow frame doesn't snowball
+
+// What a body's collider is generated from, in the object's space with its transform's scale applied
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColliderShape {
+    Aabb, // Box around the mesh's vertices, cheapest
+    ConvexHull // Of the mesh's vertices, closer to round or slanted meshes
+}
+
+// Rigid bodies for scene objects in a rapier3d world, stepped at FIXED_TIMESTEP. Poses are isometries, so the scale of
+// an object's transform when it is added is kept and applied again by moving_transforms. Z is up, like the scene.
+pub struct PhysicsWorld {
+    gravity: Vector<Real>,
+    integration_parameters: IntegrationParameters,
+    pipeline: PhysicsPipeline,
+    islands: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    bodies: RigidBodySet,
+    colliders: ColliderSet,
+    impulse_joints: ImpulseJointSet,
+    multibody_joints: MultibodyJointSet,
+    ccd_solver: CCDSolver,
+    handles: HashMap<ObjectId, (RigidBodyHandle, Vector3<f32>)>, // And the object's scale
+    accumulated: Duration // Not yet stepped
+}
+
+impl Default for PhysicsWorld {
+    fn default() -> PhysicsWorld {
+        PhysicsWorld::new(Vector3::new(0.0, 0.0, -9.81))
+    }
+}
+
+// Translation, rotation and scale of a transform without shear
+fn decompose(transform: Matrix4<f32>) -> (Vector3<f32>, Quaternion<f32>, Vector3<f32>) {
+    let scale = Vector3::new(transform.x.truncate().magnitude(), transform.y.truncate().magnitude(),
+                             transform.z.truncate().magnitude());
+    let rotation = Matrix3::from_cols(transform.x.truncate() / scale.x, transform.y.truncate() / scale.y,
+                                      transform.z.truncate() / scale.z);
+
+    (transform.w.truncate(), Quaternion::from(rotation), scale)
+}
+
+impl PhysicsWorld {
+    pub fn new(gravity: Vector3<f32>) -> PhysicsWorld {
+        PhysicsWorld {
+            gravity: vector![gravity.x, gravity.y, gravity.z],
+            integration_parameters: IntegrationParameters {
+                dt: FIXED_TIMESTEP.as_secs_f32(),
+                ..IntegrationParameters::default()
+            },
+            pipeline: PhysicsPipeline::new(),
+            islands: IslandManager::new(),
+            broad_phase: BroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            bodies: RigidBodySet::new(),
+            colliders: ColliderSet::new(),
+            impulse_joints: ImpulseJointSet::new(),
+            multibody_joints: MultibodyJointSet::new(),
+            ccd_solver: CCDSolver::new(),
+            handles: HashMap::new(),
+            accumulated: Duration::ZERO
+        }
+    }
+
+    // A body for an object at its current transform, id is the object's in the renderer, see add_object. Fixed bodies
+    // never move, use them for the ground and walls.
+    pub fn add_body(&mut self, id: ObjectId, object: &SceneObject, body_type: RigidBodyType, shape: ColliderShape)
+        -> Result<(), String> {
+        if self.handles.contains_key(&id) {
+            return Err(format!("{:?} already has a body", id));
+        }
+        let (translation, rotation, scale) = decompose(object.transform);
+        let points: Vec<Point<Real>> = object.mesh.vertices.iter()
+            .map(|v| point![v.pos[0] * scale.x, v.pos[1] * scale.y, v.pos[2] * scale.z])
+            .collect();
+        let collider = match shape {
+            ColliderShape::Aabb => {
+                let aabb = Aabb::from_points(points.iter().map(|p| cgmath::Point3::new(p.x, p.y, p.z)));
+                if aabb.is_empty() {
+                    return Err(format!("The mesh of {:?} has no vertices", id));
+                }
+                let (half, center) = (aabb.size() / 2.0, aabb.center());
+                ColliderBuilder::cuboid(half.x, half.y, half.z).translation(vector![center.x, center.y, center.z])
+            },
+            ColliderShape::ConvexHull => match ColliderBuilder::convex_hull(points.as_slice()) {
+                Some(collider) => collider,
+                None => return Err(format!("The mesh of {:?} has no convex hull", id))
+            }
+        };
+        let rotation = UnitQuaternion::from_quaternion(NaQuaternion::new(rotation.s, rotation.v.x, rotation.v.y,
+                                                                         rotation.v.z));
+        let body = RigidBodyBuilder::new(body_type)
+            .position(Isometry::from_parts(vector![translation.x, translation.y, translation.z].into(), rotation))
+            .build();
+        let handle = self.bodies.insert(body);
+        self.colliders.insert_with_parent(collider.build(), handle, &mut self.bodies);
+        self.handles.insert(id, (handle, scale));
+
+        Ok(())
+    }
+
+    pub fn remove_body(&mut self, id: ObjectId) -> Result<(), String> {
+        let (handle, _) = self.handles.remove(&id).ok_or_else(|| format!("{:?} has no body", id))?;
+        self.bodies.remove(handle, &mut self.islands, &mut self.colliders, &mut self.impulse_joints,
+                           &mut self.multibody_joints, true);
+
+        Ok(())
+    }
+
+    // For forces, impulses and velocities
+    pub fn body_mut(&mut self, id: ObjectId) -> Option<&mut RigidBody> {
+        let (handle, _) = *self.handles.get(&id)?;
+        self.bodies.get_mut(handle)
+    }
+
+    // Steps the world as many times as FIXED_TIMESTEP fits into the time since the last update, at most
+    // MAX_STEPS_PER_UPDATE times. Returns the number of steps.
+    pub fn update(&mut self, elapsed: Duration) -> u32 {
+        self.accumulated += elapsed;
+        let mut steps = 0;
+        while self.accumulated >= FIXED_TIMESTEP {
+            if steps == MAX_STEPS_PER_UPDATE {
+                self.accumulated = Duration::ZERO;
+                break;
+            }
+            self.pipeline.step(&self.gravity, &self.integration_parameters, &mut self.islands,
+                               &mut self.broad_phase, &mut self.narrow_phase, &mut self.bodies, &mut self.colliders,
+                               &mut self.impulse_joints, &mut self.multibody_joints, &mut self.ccd_solver, None, &(),
+                               &());
+            self.accumulated -= FIXED_TIMESTEP;
+            steps += 1;
+        }
+
+        steps
+    }
+
+    // Object transforms of the bodies that can move and aren't asleep, with the scale they were added with
+    pub fn moving_transforms(&self) -> Vec<(ObjectId, Matrix4<f32>)> {
+        self.handles.iter()
+            .filter_map(|(id, (handle, scale))| {
+                let body = self.bodies.get(*handle)?;
+                if body.is_fixed() || body.is_sleeping() {
+                    return None;
+                }
+                let (t, r) = (body.translation(), body.rotation());
+                let rotation = Quaternion::new(r.w, r.i, r.j, r.k);
+                let transform = Matrix4::from_translation(Vector3::new(t.x, t.y, t.z)) * Matrix4::from(rotation) *
+                    Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
+                Some((*id, transform))
+            })
+            .collect()
+    }
+}
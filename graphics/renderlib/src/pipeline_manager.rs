@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::mem;
+
+use ash::vk;
+use tracing::debug;
+
+use crate::color_pipeline::ColorConstants;
+use crate::raster_pipeline::{BlendMode, RasterPipeline};
+use crate::specialization::SpecConstants;
+use crate::vertex::VertexLayout;
+use crate::vkcore::VkCore;
+
+// Everything a raster pipeline is created from. Pipelines are only compatible with the render pass they were created
+// for and the ones with the same attachment formats and sample counts, so the render pass is part of the key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineDesc {
+    pub shader_paths: Vec<String>, // [vert, frag]
+    pub vertex_layout: VertexLayout,
+    pub set_layouts: Vec<vk::DescriptorSetLayout>,
+    pub push_constant_stages: vk::ShaderStageFlags,
+    pub push_constant_size: u32,
+    pub render_pass: vk::RenderPass,
+    pub msaa_samples: vk::SampleCountFlags,
+    pub color_attachments: usize, // 0 for depth only passes
+    pub blend: BlendMode,
+    pub depth_write: bool,
+    pub depth_compare_op: vk::CompareOp, // ALWAYS to draw over the depth buffer
+    pub cull_mode: vk::CullModeFlags,
+    pub polygon_mode: vk::PolygonMode, // LINE for wireframes needs the fillModeNonSolid feature
+    pub spec_constants: SpecConstants
+}
+
+impl PipelineDesc {
+    // Like RasterPipeline::new_with_shaders: Vertex buffers, a single alpha blended color attachment, a depth buffer
+    // that is tested and written, and ColorConstants for the fragment shader. Variants change the fields from here.
+    pub fn forward(render_pass: vk::RenderPass, set_layouts: &[vk::DescriptorSetLayout], shader_paths: &[&str],
+                   msaa_samples: vk::SampleCountFlags) -> PipelineDesc {
+        PipelineDesc {
+            shader_paths: shader_paths.iter().map(|p| p.to_string()).collect(),
+            vertex_layout: VertexLayout::vertex(),
+            set_layouts: set_layouts.to_vec(),
+            push_constant_stages: vk::ShaderStageFlags::FRAGMENT,
+            push_constant_size: mem::size_of::<ColorConstants>() as u32,
+            render_pass,
+            msaa_samples,
+            color_attachments: 1,
+            blend: BlendMode::Alpha,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::LESS,
+            cull_mode: vk::CullModeFlags::BACK,
+            polygon_mode: vk::PolygonMode::FILL,
+            spec_constants: SpecConstants::new()
+        }
+    }
+}
+
+// Raster pipelines by their PipelineDesc, shared by the passes of a renderer so that each variant (wireframe, depth
+// only, another blend mode or sample count, ...) is only created once, when it is first asked for
+#[derive(Default)]
+pub struct PipelineManager {
+    pipelines: HashMap<PipelineDesc, RasterPipeline>
+}
+
+impl PipelineManager {
+    pub fn new() -> PipelineManager {
+        PipelineManager::default()
+    }
+
+    // Creating a variant loads its shaders, which can stall the frame that first uses it
+    pub fn get(&mut self, core: &VkCore, desc: &PipelineDesc) -> &RasterPipeline {
+        if !self.pipelines.contains_key(desc) {
+            debug!(shaders = ?desc.shader_paths, ?desc.blend, ?desc.polygon_mode, ?desc.msaa_samples,
+                   "Creating pipeline variant");
+            self.pipelines.insert(desc.clone(), RasterPipeline::from_desc(core, desc));
+        }
+
+        &self.pipelines[desc]
+    }
+
+    pub fn contains(&self, desc: &PipelineDesc) -> bool {
+        self.pipelines.contains_key(desc)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pipelines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pipelines.is_empty()
+    }
+
+    // Before render_pass is destroyed, like on swap chain recreation, while no frame uses its pipelines
+    pub fn remove_render_pass(&mut self, core: &VkCore, render_pass: vk::RenderPass) {
+        self.pipelines.retain(|desc, pipeline| {
+            let keep = desc.render_pass != render_pass;
+            if !keep {
+                pipeline.destroy(core);
+            }
+            keep
+        });
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for p in self.pipelines.values() {
+            p.destroy(core);
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use std::cell::Cell;
+use std::mem;
+
+use ash::vk;
+use cgmath::Matrix4;
+use winit::event::{ElementState, KeyboardInput, WindowEvent};
+
+use crate::color_pipeline::LINEAR_INTERMEDIATE_FORMAT;
+use crate::compute::{ComputePipeline, group_count};
+use crate::dof::DepthOfField;
+use crate::exposure::{AutoExposure, ExposureMetering};
+use crate::image::StorageImage;
+use crate::internal_resolution::{BlitImage, InternalResolution, UpscaleFilter};
+use crate::motion_blur::{MotionBlur, MotionBlurSettings};
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_nearest_sampler, destroy_sampler};
+use crate::vkcore::VkCore;
+
+pub const VIEW_DEPTH_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+const LINEAR_DEPTH_SHADER_PATH: &str = "graphics/shaders/spv/linear_depth.spv";
+const LINEAR_DEPTH_MS_SHADER_PATH: &str = "graphics/shaders/spv/linear_depth_ms.spv";
+pub(crate) const POST_GROUP_SIZE: u32 = 8; // Matches local_size_x/y in every post-process shader
+
+// What the effects need to know about the frame being processed
+#[derive(Clone, Copy, Debug)]
+pub struct PostFrame {
+    pub projection: Matrix4<f32>, // Y flipped like SceneCamera::projection
+    pub view: Matrix4<f32>,
+    // Of the frame before, None turns motion blur off. Left out on the first frame and in benchmark and capture
+    // modes, whose frames shouldn't depend on the ones before them.
+    pub previous_view_projection: Option<Matrix4<f32>>,
+    pub focus_distance: f32, // See SceneCamera
+    pub aperture: f32,
+    pub exposure: Option<ExposureMetering>, // None leaves the adapted exposure as it is
+    pub frame: usize // In flight
+}
+
+fn create_set_layout(core: &VkCore, bindings: &[vk::DescriptorType]) -> vk::DescriptorSetLayout {
+    let bindings: Vec<vk::DescriptorSetLayoutBinding> = bindings.iter().enumerate()
+        .map(|(b, &ty)| vk::DescriptorSetLayoutBinding::default()
+            .binding(b as u32)
+            .descriptor_type(ty)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE))
+        .collect();
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(bindings.as_slice());
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+fn blit_region(extent: vk::Extent2D) -> vk::ImageBlit {
+    let subresource = vk::ImageSubresourceLayers::default()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+    let offsets = [vk::Offset3D::default(),
+        vk::Offset3D::default().x(extent.width as i32).y(extent.height as i32).z(1)];
+
+    vk::ImageBlit::default()
+        .src_subresource(subresource)
+        .dst_subresource(subresource)
+        .src_offsets(offsets)
+        .dst_offsets(offsets)
+}
+
+// Compute effects over the finished, linear color of a frame, shared by both renderers. Each frame that has an
+// effect enabled (see is_active):
+// 1. cmd_copy_in, with the rendered image in a TRANSFER_SRC capable layout. Blitting decodes sRGB sources.
+// 2. cmd_apply, which meters the exposure and runs the enabled effects in order, each reading the output of the one
+//    before it. The view depth image must be written by then, either by the renderer (view_depth_info) or from the
+//    depth buffer passed to new.
+// 3. cmd_copy_out to the swap chain image, in a TRANSFER_DST capable layout.
+// Effect order: depth of field, motion blur.
+pub struct PostProcessChain {
+    set_layout: vk::DescriptorSetLayout, // Input color, output color and view depth, shared by every effect
+    linear_depth_set_layout: vk::DescriptorSetLayout,
+    exposure_set_layout: vk::DescriptorSetLayout,
+    linear_depth: Option<ComputePipeline>, // Only with a depth buffer
+    descriptor_pool: vk::DescriptorPool,
+    sets: [vk::DescriptorSet; 2], // sets[i] reads images[i] and writes images[1 - i]
+    linear_depth_set: vk::DescriptorSet,
+    exposure_set: vk::DescriptorSet, // Meters images[0]
+    images: [StorageImage; 2],
+    view_depth: StorageImage, // Distance from the camera plane, along the view direction
+    depth_sampler: vk::Sampler,
+    auto_exposure: AutoExposure,
+    depth_of_field: DepthOfField,
+    motion_blur: MotionBlur,
+    output: Cell<usize>, // Index of the image the last cmd_apply ended in
+    extent: vk::Extent2D
+}
+
+fn create_images(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D) -> [StorageImage; 2] {
+    [0, 1].map(|_| StorageImage::new(core, command_pool, extent, LINEAR_INTERMEDIATE_FORMAT,
+                                     vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST))
+}
+
+impl PostProcessChain {
+    // depth_buffer is a depth buffer from Depth::new_sampled and its sample count. Without one the renderer writes
+    // the view depth itself.
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D,
+               depth_buffer: Option<(vk::ImageView, vk::SampleCountFlags)>, max_frames: usize) -> PostProcessChain {
+        let set_layout = create_set_layout(core, &[vk::DescriptorType::STORAGE_IMAGE; 3]);
+        let linear_depth_set_layout = create_set_layout(core, &[vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::DescriptorType::STORAGE_IMAGE]);
+        let exposure_set_layout = create_set_layout(core, &[vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorType::STORAGE_BUFFER, vk::DescriptorType::STORAGE_BUFFER]);
+        let linear_depth = depth_buffer.map(|(_, samples)| {
+            let shader = match samples {
+                vk::SampleCountFlags::TYPE_1 => LINEAR_DEPTH_SHADER_PATH,
+                _ => LINEAR_DEPTH_MS_SHADER_PATH
+            };
+            ComputePipeline::new(core, shader, &[linear_depth_set_layout], mem::size_of::<[f32; 4]>() as u32)
+        });
+
+        let mut chain = PostProcessChain {
+            set_layout,
+            linear_depth_set_layout,
+            exposure_set_layout,
+            linear_depth,
+            descriptor_pool: vk::DescriptorPool::null(),
+            sets: [vk::DescriptorSet::null(); 2],
+            linear_depth_set: vk::DescriptorSet::null(),
+            exposure_set: vk::DescriptorSet::null(),
+            images: create_images(core, command_pool, extent),
+            view_depth: StorageImage::new(core, command_pool, extent, VIEW_DEPTH_FORMAT, vk::ImageUsageFlags::empty()),
+            depth_sampler: create_nearest_sampler(core),
+            auto_exposure: AutoExposure::new(core, command_pool, exposure_set_layout, max_frames),
+            depth_of_field: DepthOfField::new(core, set_layout),
+            motion_blur: MotionBlur::new(core, set_layout),
+            output: Cell::new(0),
+            extent
+        };
+        chain.write_descriptor_sets(core, depth_buffer.map(|(view, _)| view));
+
+        chain
+    }
+
+    fn write_descriptor_sets(&mut self, core: &VkCore, depth_view: Option<vk::ImageView>) {
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(8),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(2)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(4)
+            .pool_sizes(&pool_sizes);
+        self.descriptor_pool = unsafe {
+            core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap()
+        };
+
+        let layouts = [self.set_layout, self.set_layout, self.linear_depth_set_layout, self.exposure_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        (self.sets, self.linear_depth_set, self.exposure_set) = ([sets[0], sets[1]], sets[2], sets[3]);
+
+        let image_infos = [self.images[0].info(), self.images[1].info()];
+        let view_depth_info = self.view_depth.info();
+        let exposure_buffer_infos = self.auto_exposure.buffer_infos();
+        let mut writes: Vec<vk::WriteDescriptorSet> = Vec::new();
+        for (i, &set) in self.sets.iter().enumerate() {
+            for (binding, info) in [&image_infos[i], &image_infos[1 - i], &view_depth_info].into_iter().enumerate() {
+                writes.push(vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(info));
+            }
+        }
+        writes.push(vk::WriteDescriptorSet::default()
+            .dst_set(self.exposure_set)
+            .dst_binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+            .image_info(&image_infos[0]));
+        for (i, info) in exposure_buffer_infos.iter().enumerate() {
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(self.exposure_set)
+                .dst_binding(i as u32 + 1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(info));
+        }
+        let depth_info = depth_view.map(|view| [vk::DescriptorImageInfo::default()
+            .sampler(self.depth_sampler)
+            .image_view(view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)]);
+        if let Some(info) = depth_info.as_ref() {
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(self.linear_depth_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(info));
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(self.linear_depth_set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&view_depth_info));
+        }
+
+        unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+    }
+
+    // Call after the swap chain was recreated, with the new depth buffer if the chain was created with one
+    pub fn resize(&mut self, core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D,
+                  depth_view: Option<vk::ImageView>) {
+        unsafe { core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None) };
+        self.images.iter().for_each(|i| i.destroy(core));
+        self.view_depth.destroy(core);
+        self.images = create_images(core, command_pool, extent);
+        self.view_depth = StorageImage::new(core, command_pool, extent, VIEW_DEPTH_FORMAT,
+                                            vk::ImageUsageFlags::empty());
+        self.extent = extent;
+        self.write_descriptor_sets(core, depth_view);
+    }
+
+    // Whether frame needs any effect, the chain can be skipped entirely otherwise
+    pub fn is_active(&self, frame: &PostFrame) -> bool {
+        frame.exposure.is_some() || DepthOfField::is_enabled(frame) || self.motion_blur.is_enabled(frame)
+    }
+
+    // See AutoExposure::adapted_exposure
+    pub fn adapted_exposure(&self, frame: usize) -> f32 {
+        self.auto_exposure.adapted_exposure(frame)
+    }
+
+    pub fn motion_blur_settings(&self) -> MotionBlurSettings {
+        self.motion_blur.settings()
+    }
+
+    pub fn set_motion_blur_settings(&mut self, settings: MotionBlurSettings) {
+        self.motion_blur.set_settings(settings);
+    }
+
+    // Toggles motion blur with MOTION_BLUR_TOGGLE_KEY
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(key), .. },
+            ..
+        } = event {
+            let mut settings = self.motion_blur.settings();
+            if settings.handle_key(*key) {
+                self.motion_blur.set_settings(settings);
+            }
+        }
+    }
+
+    // Bind as a STORAGE_IMAGE descriptor for renderers that write the view depth themselves, r32f in GENERAL
+    pub fn view_depth_info(&self) -> vk::DescriptorImageInfo {
+        self.view_depth.info()[0]
+    }
+
+    pub fn cmd_copy_in(&self, core: &VkCore, command_buffer: vk::CommandBuffer, source: vk::Image,
+                       source_layout: vk::ImageLayout) {
+        // The previous frame's reads of the image
+        let barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer,
+                                                     vk::PipelineStageFlags::TRANSFER |
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::TRANSFER,
+                                                     vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+            core.logical_device.cmd_blit_image(command_buffer, source, source_layout, self.images[0].image,
+                                               vk::ImageLayout::GENERAL, &[blit_region(self.extent)],
+                                               vk::Filter::NEAREST);
+        }
+    }
+
+    pub fn cmd_apply(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: &PostFrame) {
+        // The copy in, and whatever wrote the depth
+        let input_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_WRITE |
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let effect_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let (groups_x, groups_y) = (group_count(self.extent.width, POST_GROUP_SIZE),
+                                    group_count(self.extent.height, POST_GROUP_SIZE));
+        let mut current = 0;
+
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[input_barrier], &[], &[]);
+            if let Some(metering) = frame.exposure.as_ref() {
+                self.auto_exposure.cmd_meter(core, command_buffer, self.exposure_set, self.extent, metering,
+                                             frame.frame);
+            }
+            if let Some(pipeline) = self.linear_depth.as_ref() {
+                let projection = [[frame.projection.x.x, frame.projection.y.y, frame.projection.z.z,
+                    frame.projection.w.z]];
+                pipeline.cmd_bind(core, command_buffer, self.linear_depth_set);
+                core.logical_device.cmd_push_constants(command_buffer, pipeline.layout, vk::ShaderStageFlags::COMPUTE,
+                                                       0, cast_to_u8_slice(&projection));
+                core.logical_device.cmd_dispatch(command_buffer, groups_x, groups_y, 1);
+                core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::DependencyFlags::empty(), &[effect_barrier], &[], &[]);
+            }
+            if DepthOfField::is_enabled(frame) {
+                self.depth_of_field.cmd_apply(core, command_buffer, self.sets[current], self.extent, frame);
+                current = 1 - current;
+                core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::DependencyFlags::empty(), &[effect_barrier], &[], &[]);
+            }
+            if self.motion_blur.is_enabled(frame) {
+                self.motion_blur.cmd_apply(core, command_buffer, self.sets[current], self.extent, frame);
+                current = 1 - current;
+                core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                         vk::DependencyFlags::empty(), &[effect_barrier], &[], &[]);
+            }
+        }
+        self.output.set(current);
+    }
+
+    pub fn cmd_copy_out(&self, core: &VkCore, command_buffer: vk::CommandBuffer, target: vk::Image,
+                        target_layout: vk::ImageLayout) {
+        let native = InternalResolution { filter: UpscaleFilter::Nearest, ..InternalResolution::default() };
+        self.cmd_scale_out(core, command_buffer, target, target_layout, &native, self.extent);
+    }
+
+    // Like cmd_copy_out, into a target of window_extent that resolution places the chain's images in. The chain must
+    // have the size of resolution's image for that window.
+    pub fn cmd_scale_out(&self, core: &VkCore, command_buffer: vk::CommandBuffer, target: vk::Image,
+                         target_layout: vk::ImageLayout, resolution: &InternalResolution,
+                         window_extent: vk::Extent2D) {
+        let barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer,
+                                                     vk::PipelineStageFlags::TRANSFER |
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::TRANSFER,
+                                                     vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+        }
+        let target = BlitImage { image: target, layout: target_layout, extent: window_extent };
+        resolution.cmd_blit(core, command_buffer, self.images[self.output.get()].image, vk::ImageLayout::GENERAL,
+                            &target);
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            core.logical_device.destroy_descriptor_set_layout(self.linear_depth_set_layout, None);
+            core.logical_device.destroy_descriptor_set_layout(self.exposure_set_layout, None);
+        }
+        if let Some(pipeline) = self.linear_depth.as_ref() {
+            pipeline.destroy(core);
+        }
+        self.auto_exposure.destroy(core);
+        self.depth_of_field.destroy(core);
+        self.motion_blur.destroy(core);
+        self.images.iter().for_each(|i| i.destroy(core));
+        self.view_depth.destroy(core);
+        destroy_sampler(core, self.depth_sampler);
+    }
+}
@@ -0,0 +1,41 @@
+use std::panic;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use tracing::debug;
+
+// Work started on its own thread, such as decoding an asset while the instance and device are created, that is only
+// waited on once its result is needed. A panic on the thread is raised again by wait, like it would have been had the
+// work been done there.
+pub struct Preload<T> {
+    name: String,
+    handle: JoinHandle<T>
+}
+
+impl<T: Send + 'static> Preload<T> {
+    // name is also the thread's
+    pub fn spawn<F>(name: &str, work: F) -> Preload<T> where F: FnOnce() -> T + Send + 'static {
+        let start = Instant::now();
+        let thread_name = name.to_string();
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let result = work();
+                debug!(name = thread_name.as_str(), "Preloaded in {:?}", start.elapsed());
+                result
+            })
+            .unwrap();
+
+        Preload {
+            name: name.to_string(),
+            handle
+        }
+    }
+
+    pub fn wait(self) -> T {
+        let start = Instant::now();
+        let result = self.handle.join().unwrap_or_else(|e| panic::resume_unwind(e));
+        debug!(name = self.name.as_str(), "Waited {:?} for the preload", start.elapsed());
+        result
+    }
+}
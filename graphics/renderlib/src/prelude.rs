@@ -0,0 +1,110 @@
+// Everything a renderer built on renderlib normally needs, use renderlib::prelude::*. Modules that are only useful
+// for extending renderlib itself (single_time, image, ...) stay reachable through their full paths.
+pub use ash::vk; // Consumers must use the same ash as renderlib
+
+pub use crate::address_table::{AddressIndex, ShaderAddressTable};
+pub use crate::animation::{AnimationClip, load_gltf_skinned, Skeleton};
+pub use crate::async_compute::AsyncCompute;
+pub use crate::aux_camera::{AuxCamera, AuxCameraId, AuxCameras, MAX_AUX_CAMERAS};
+pub use crate::backend::{Backend, NullBackend, SwapchainResources, VkBackend, VkResources};
+pub use crate::benchmark::{Benchmark, CameraSpline, FrameStats};
+pub use crate::bindless::{BufferHandle, HeapIndices, ImageHandle, RESOURCE_HEAP_SET, ResourceHeap, SamplerHandle};
+pub use crate::capabilities::{buffer_device_address, Capabilities, FeatureSource, MIN_API_VERSION, TARGET_API_VERSION};
+pub use crate::capture::{CaptureOutput, compare_hash_files, FrameCapture};
+pub use crate::clock::Clock;
+pub use crate::clustered_lights::{ClusterCamera, ClusteredLights, ForwardDraw, ForwardPass};
+pub use crate::collision::{Aabb, BoundingSphere, cast_voxels, Frustum, MeshBvh, Ray, ray_triangle, RayHit, VoxelHit};
+pub use crate::color::Color;
+pub use crate::color_pipeline::{ColorConstants, ColorPipeline};
+pub use crate::compute::ComputePipeline;
+pub use crate::config::{LaunchConfig, RendererKind, Resolution};
+pub use crate::crash::CrashHandler;
+pub use crate::cursor::{CURSOR_RELEASE_KEY, CursorCapture};
+pub use crate::debug_lines::DebugLines;
+pub use crate::debug_ui::{DEBUG_UI_TOGGLE_KEY, DebugUi, DebugUiDraws};
+pub use crate::deletion_queue::{Deletion, DeletionQueue};
+pub use crate::depth::{Depth, find_depth_format, find_depth_stencil_format, has_stencil, stencil_replace, stencil_test};
+pub use crate::depth_readback::{DepthImage, linearize_depth, read_depth};
+pub use crate::descriptor::{create_descriptor_set_layout, create_skinned_descriptor_set_layout, Descriptor};
+pub use crate::descriptor_allocator::{DescriptorAllocator, LayoutCache};
+#[cfg(feature = "device-group")]
+pub use crate::device_group::{DeviceGroup, DeviceGroupSubmit};
+pub use crate::device_lost::{DeviceLost, DeviceLostCallback};
+pub use crate::display_mode::DisplayMode;
+pub use crate::display_timing::{DisplayTiming, WindowSystem};
+pub use crate::dynamic_ubo::DynamicUniformBuffer;
+pub use crate::dof::DepthOfField;
+pub use crate::exposure::{AutoExposure, AutoExposureSettings, ExposureMetering};
+pub use crate::frame_constants::{FRAME_CONSTANTS_SET, FrameConstants, FrameConstantsBuffer};
+pub use crate::frame_limiter::FrameLimiter;
+pub use crate::frame_buffers::{destroy_frame_buffers, setup_frame_buffers};
+pub use crate::frame_ring::{FrameRing, RingSlice};
+#[cfg(unix)]
+pub use crate::frame_share::{FrameShare, SHARE_RING_SIZE, SharedFrameHandles, SharedFrameInfo, SharedFrames};
+pub use crate::gbuffer::GBuffer;
+pub use crate::gpu_buffer::{GpuBuffer, Readback};
+pub use crate::gpu_select::{enumerate_gpus, GpuInfo, GpuSelection};
+pub use crate::grid::{Grid, GridSettings};
+pub use crate::idle::IdleTracker;
+pub use crate::index::{IndexBuffer, IndexElement};
+pub use crate::input_replay::{InputEvent, InputRecorder, InputReplay};
+pub use crate::internal_resolution::{BlitImage, DEFAULT_SHARPNESS, InternalResolution, UpscaleFilter};
+pub use crate::latency::LatencyGovernor;
+pub use crate::material_editor::MaterialEditor;
+pub use crate::material_panel::MaterialPanel;
+pub use crate::memory::{HeapStats, MemoryStats, RebarPolicy, TransientPolicy, UploadPath, UploadStats};
+pub use crate::mesh_optimizer::{cache_miss_ratio, deduplicate_vertices, optimize_overdraw, optimize_vertex_cache,
+                                optimize_vertex_fetch};
+pub use crate::model::{load_mesh, load_mesh_variants, load_model, load_obj, MeshData, Model, QuantizedMeshData,
+                       Submesh};
+pub use crate::motion_blur::{MotionBlur, MotionBlurSettings};
+pub use crate::multiview::{MultiviewTarget, StereoView};
+pub use crate::objects::{MAX_OBJECT_DRAWS, Mesh, MeshHash, ObjectDraws, ObjectId, ObjectList, ObjectTransforms,
+                         SceneObject};
+#[cfg(feature = "indirect-draw")]
+pub use crate::occlusion::{DrawBounds, OcclusionCuller};
+pub use crate::outline::{Outline, OutlineSettings};
+#[cfg(feature = "physics")]
+pub use crate::physics::{ColliderShape, FIXED_TIMESTEP, PhysicsWorld};
+pub use crate::pipeline_manager::{PipelineDesc, PipelineManager};
+pub use crate::post_process::{PostFrame, PostProcessChain};
+pub use crate::preload::Preload;
+pub use crate::profiler::{GpuTimer, PipelineStatistics, PipelineStatsQueries, PresentStatistics, PresentTiming};
+pub use crate::proxy::{CommandQueue, RendererCommand, RendererProxy, RendererSetting, Reply};
+pub use crate::raster_pipeline::{BlendMode, RasterPipeline};
+pub use crate::reflection::{PipelineReflection, ReflectedBinding, ShaderReflection};
+pub use crate::render_pass::{destroy_render_pass, setup_render_pass, setup_render_pass_stored_depth};
+pub use crate::render_target::{RenderTarget, SwapchainCallback, SwapchainRecreate};
+pub use crate::renderutils::{cast_to_u8_slice, setup_sync_objects};
+pub use crate::sampler::{create_nearest_sampler, create_sampler, destroy_sampler, SamplerCache, TextureSettings};
+pub use crate::scene::{CameraProjection, Light, Material, Scene, SceneCamera, SceneModel, SceneTerrain, SceneWater,
+                       Transform, Wave};
+pub use crate::scene_bvh::SceneBvh;
+pub use crate::settings::{ExposureMode, IdleBehavior, RendererSettings};
+pub use crate::shader_permutation::{PermutationKey, preprocess, ShaderPermutations};
+pub use crate::shadow::{PointShadows, ShadowSettings};
+pub use crate::skinning::{BoneBuffer, ComputeSkinner};
+pub use crate::sky::{Sky, SkySettings};
+pub use crate::sparse_texture::{create_virtual_texture_set_layout, sparse_textures_supported};
+pub use crate::specialization::{SpecConstants, SpecValue};
+pub use crate::ssao::{Ssao, SsaoSettings};
+pub use crate::stats_overlay::StatsOverlay;
+pub use crate::terrain::{Heightmap, Terrain, TerrainMesh, TerrainSettings};
+pub use crate::texture::Texture;
+pub use crate::texture_streaming::{decode_texture, DecodedTexture, StreamingSettings, TextureId, TextureStreamer,
+                                   VirtualTextureId};
+pub use crate::transparency::WindowTransparency;
+pub use crate::ubo::UniformBuffer;
+pub use crate::upscale::Upscaler;
+pub use crate::vertex::{Dequantization, QuantizedVertex, SkinnedVertex, Vertex, VertexAttribute, VertexLayout,
+                        VoxelVertex, DEQUANTIZATION_OFFSET, VOXEL_OCCLUSION};
+pub use crate::viewport::{editor_quad, MAX_VIEWPORTS, Viewport, ViewportLayout, ViewportRect};
+pub use crate::vkcore::VkCore;
+pub use crate::voxel::{chunk_bounds, chunk_of, chunk_origin, cube_mesh, AIR, Block, BlockInstance, CHUNK_SIZE,
+                       ChunkCoord, ChunkInstances, VoxelChunk, VoxelDraws, VoxelMeshing, VoxelWorld};
+pub use crate::voxel_edit::{BlockClick, BlockEdit, BlockEditor, PLACE_BLOCK_BUTTON, REMOVE_BLOCK_BUTTON, target_block};
+pub use crate::voxel_mesh::{palette_tiles, GreedyMesher, MAX_CHUNK_QUADS, MeshedChunk};
+pub use crate::voxel_streaming::{ChunkChanges, ChunkSource, ChunkStreamer, ChunkStreamingSettings};
+pub use crate::voxel_visibility::{FaceConnections, visible_chunks, VISIBILITY_DISTANCE};
+pub use crate::water::{Water, WaterInfo, WaterSettings};
+pub use crate::worldgen::{Biome, NoiseLayer, perlin, value_noise, WorldGen, WorldGenSettings};
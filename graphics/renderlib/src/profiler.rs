@@ -0,0 +1,232 @@
+use std::cell::Cell;
+use std::ops::Add;
+use ash::vk;
+use crate::vkcore::VkCore;
+
+// The counters PipelineStatsQueries collects, in the order of their bits, which is the order of the results
+const STATISTICS: vk::QueryPipelineStatisticFlags = vk::QueryPipelineStatisticFlags::from_raw(
+    vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_VERTICES.as_raw() |
+        vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_PRIMITIVES.as_raw() |
+        vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS.as_raw() |
+        vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES.as_raw() |
+        vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS.as_raw());
+const STATISTIC_COUNT: usize = 5;
+
+// Two timestamps per frame in flight, bracketing everything recorded between cmd_begin and cmd_end
+pub struct GpuTimer {
+    pool: vk::QueryPool,
+    timestamp_period: f64, // Nanoseconds per timestamp tick
+    recorded: Vec<Cell<bool>> // Queries that were never reset must not be read back
+}
+
+impl GpuTimer {
+    pub fn new(core: &VkCore, max_frames: usize) -> GpuTimer {
+        let properties = unsafe { core.instance.get_physical_device_properties(core.physical_device) };
+        let pool_create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count((max_frames * 2) as u32);
+        let pool = unsafe { core.logical_device.create_query_pool(&pool_create_info, None).unwrap() };
+
+        GpuTimer {
+            pool,
+            timestamp_period: properties.limits.timestamp_period as f64,
+            recorded: (0..max_frames).map(|_| Cell::new(false)).collect()
+        }
+    }
+
+    pub fn cmd_begin(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize) {
+        unsafe {
+            core.logical_device.cmd_reset_query_pool(command_buffer, self.pool, (frame * 2) as u32, 2);
+            core.logical_device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE, self.pool,
+                                                    (frame * 2) as u32);
+        }
+        self.recorded[frame].set(true);
+    }
+
+    pub fn cmd_end(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize) {
+        unsafe {
+            core.logical_device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                    self.pool, (frame * 2 + 1) as u32);
+        }
+    }
+
+    // GPU time of the last submission recorded for this frame slot. Only valid once the frame's fence has signaled,
+    // returns None if the slot has not been used yet.
+    pub fn frame_time_ms(&self, core: &VkCore, frame: usize) -> Option<f64> {
+        if !self.recorded[frame].get() {
+            return None;
+        }
+
+        let mut timestamps: [u64; 2] = [0, 0];
+        let result = unsafe {
+            core.logical_device.get_query_pool_results(self.pool, (frame * 2) as u32, &mut timestamps,
+                                                       vk::QueryResultFlags::TYPE_64)
+        };
+
+        match result {
+            Ok(_) => Some(timestamps[1].wrapping_sub(timestamps[0]) as f64 * self.timestamp_period / 1_000_000.0),
+            Err(_) => None // NOT_READY
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe { core.logical_device.destroy_query_pool(self.pool, None) };
+    }
+}
+
+// What the GPU processed in a pass, for judging culling and overdraw
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    pub vertices: u64, // Assembled, including the ones of culled primitives
+    pub primitives: u64, // Assembled
+    pub vertex_invocations: u64, // Below vertices where the vertex cache hits
+    pub clipped_primitives: u64, // Left after clipping, about what was rasterized
+    pub fragment_invocations: u64
+}
+
+impl PipelineStatistics {
+    pub fn total(passes: &[(&'static str, PipelineStatistics)]) -> PipelineStatistics {
+        passes.iter().fold(PipelineStatistics::default(), |total, &(_, s)| total + s)
+    }
+
+    // Fragment shader invocations per pixel, 1 if every pixel was shaded once. Depth tested early fragments that
+    // fail aren't shaded, so this is what culling and sorting leave.
+    pub fn overdraw(&self, pixels: u64) -> f64 {
+        self.fragment_invocations as f64 / pixels.max(1) as f64
+    }
+}
+
+impl Add for PipelineStatistics {
+    type Output = PipelineStatistics;
+
+    fn add(self, other: PipelineStatistics) -> PipelineStatistics {
+        PipelineStatistics {
+            vertices: self.vertices + other.vertices,
+            primitives: self.primitives + other.primitives,
+            vertex_invocations: self.vertex_invocations + other.vertex_invocations,
+            clipped_primitives: self.clipped_primitives + other.clipped_primitives,
+            fragment_invocations: self.fragment_invocations + other.fragment_invocations
+        }
+    }
+}
+
+// One present as the display reported it through DisplayTiming, some frames after it was queued
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PresentTiming {
+    pub present_id: u32,
+    // From queue_present to the start of the refresh cycle that showed the frame. None where the presentation
+    // engine's clock isn't CLOCK_MONOTONIC.
+    pub latency_ms: Option<f64>,
+    pub margin_ms: f64, // How much earlier the frame was ready than it had to be for its refresh cycle
+    pub missed: bool // Shown after the refresh cycle that was asked for, see DisplayTiming::set_pacing
+}
+
+// Presents summed up, for the stats overlay and reports
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PresentStatistics {
+    pub presents: usize,
+    pub mean_latency_ms: Option<f64>,
+    pub max_latency_ms: Option<f64>,
+    pub mean_margin_ms: f64,
+    pub missed: usize
+}
+
+impl PresentStatistics {
+    pub fn new(presents: &[PresentTiming]) -> PresentStatistics {
+        let latencies: Vec<f64> = presents.iter().filter_map(|p| p.latency_ms).collect();
+        let mean = |values: &mut dyn Iterator<Item = f64>, count: usize| match count {
+            0 => None,
+            n => Some(values.sum::<f64>() / n as f64)
+        };
+
+        PresentStatistics {
+            presents: presents.len(),
+            mean_latency_ms: mean(&mut latencies.iter().copied(), latencies.len()),
+            max_latency_ms: latencies.iter().copied().reduce(f64::max),
+            mean_margin_ms: mean(&mut presents.iter().map(|p| p.margin_ms), presents.len()).unwrap_or(0.0),
+            missed: presents.iter().filter(|p| p.missed).count()
+        }
+    }
+}
+
+// Pipeline statistics queries around named passes, one of each per frame in flight. Needs the pipelineStatisticsQuery
+// feature. Passes must begin and end outside of render passes, and each at most once a frame.
+pub struct PipelineStatsQueries {
+    pool: vk::QueryPool,
+    passes: &'static [&'static str],
+    recorded: Vec<Vec<Cell<bool>>> // By frame and pass, like GpuTimer's
+}
+
+impl PipelineStatsQueries {
+    // None if the device can't count
+    pub fn new(core: &VkCore, passes: &'static [&'static str], max_frames: usize) -> Option<PipelineStatsQueries> {
+        let features = unsafe { core.instance.get_physical_device_features(core.physical_device) };
+        if features.pipeline_statistics_query != vk::TRUE {
+            return None;
+        }
+        let pool_create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::PIPELINE_STATISTICS)
+            .query_count((max_frames * passes.len()) as u32)
+            .pipeline_statistics(STATISTICS);
+        let pool = unsafe { core.logical_device.create_query_pool(&pool_create_info, None).unwrap() };
+
+        Some(PipelineStatsQueries {
+            pool,
+            passes,
+            recorded: (0..max_frames).map(|_| passes.iter().map(|_| Cell::new(false)).collect()).collect()
+        })
+    }
+
+    fn query(&self, frame: usize, pass: usize) -> u32 {
+        (frame * self.passes.len() + pass) as u32
+    }
+
+    // At the start of the frame's command buffer, before any pass
+    pub fn cmd_reset(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize) {
+        unsafe {
+            core.logical_device.cmd_reset_query_pool(command_buffer, self.pool, self.query(frame, 0),
+                                                     self.passes.len() as u32);
+        }
+        self.recorded[frame].iter().for_each(|r| r.set(false));
+    }
+
+    // pass indexes the names given to new
+    pub fn cmd_begin(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize, pass: usize) {
+        unsafe {
+            core.logical_device.cmd_begin_query(command_buffer, self.pool, self.query(frame, pass),
+                                                vk::QueryControlFlags::empty());
+        }
+        self.recorded[frame][pass].set(true);
+    }
+
+    pub fn cmd_end(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize, pass: usize) {
+        unsafe { core.logical_device.cmd_end_query(command_buffer, self.pool, self.query(frame, pass)) };
+    }
+
+    // The passes recorded in the last submission of this frame slot, by name. Only valid once the frame's fence has
+    // signaled, passes that weren't recorded or aren't available are left out.
+    pub fn results(&self, core: &VkCore, frame: usize) -> Vec<(&'static str, PipelineStatistics)> {
+        self.passes.iter().enumerate()
+            .filter(|&(pass, _)| self.recorded[frame][pass].get())
+            .filter_map(|(pass, &name)| {
+                let mut counters = [[0u64; STATISTIC_COUNT]; 1];
+                unsafe {
+                    core.logical_device.get_query_pool_results(self.pool, self.query(frame, pass), &mut counters,
+                                                               vk::QueryResultFlags::TYPE_64)
+                }.ok()?; // NOT_READY
+                let [vertices, primitives, vertex_invocations, clipped_primitives, fragment_invocations] = counters[0];
+                Some((name, PipelineStatistics {
+                    vertices,
+                    primitives,
+                    vertex_invocations,
+                    clipped_primitives,
+                    fragment_invocations
+                }))
+            })
+            .collect()
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe { core.logical_device.destroy_query_pool(self.pool, None) };
+    }
+}
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::color_pipeline::ColorPipeline;
+use crate::display_mode::DisplayMode;
+use crate::grid::GridSettings;
+use crate::motion_blur::MotionBlurSettings;
+use crate::outline::OutlineSettings;
+use crate::scene::{CameraProjection, Material, SceneCamera};
+use crate::settings::RendererSettings;
+use crate::sky::SkySettings;
+use crate::viewport::Viewport;
+
+// Result of a command that can fail, sent once the renderer has processed it. recv fails if the renderer exited
+// before getting to the command.
+pub type Reply<T> = Receiver<Result<T, String>>;
+
+// Settings both renderers share, see RendererProxy::set_setting
+#[derive(Clone, Copy, Debug)]
+pub enum RendererSetting {
+    Renderer(RendererSettings),
+    ColorPipeline(ColorPipeline),
+    MotionBlur(MotionBlurSettings),
+    LowLatency(bool),
+    MaxFps(Option<f32>), // None removes the limit, see FrameLimiter
+    DisplayMode(DisplayMode),
+    Sky(SkySettings), // Also moves the sun light, see set_sky
+    Grid(GridSettings), // Only drawn by the raster renderer
+    Outline(OutlineSettings), // Of the selection, also only drawn by the raster renderer
+    Projection(CameraProjection), // Of the current camera, invalid projections are ignored
+    Paused(bool), // Stops the animation clock, see Clock
+    TimeScale(f32)
+}
+
+pub enum RendererCommand {
+    LoadModel { path: String, texture: Option<String>, reply: Sender<Result<(), String>> },
+    SetCamera(SceneCamera),
+    FrameScene { margin: f32 }, // See RasterRenderer::frame_scene
+    Screenshot { path: PathBuf, reply: Sender<Result<PathBuf, String>> }, // PNG of the next presented frame
+    SetSetting(RendererSetting),
+    StepFrame, // Advances the paused clock by one frame, see Clock::step
+    SetViewports(Vec<Viewport>), // Split screen, only drawn by the raster renderer
+    SetMaterial { material: Material, reply: Sender<Result<(), String>> }
+}
+
+// Lets other threads, like a game or editor, drive a renderer whose run_blocking owns it on the event loop thread.
+// Clones all talk to the same renderer, commands are processed in order at the start of its next frame.
+#[derive(Clone)]
+pub struct RendererProxy {
+    sender: Sender<RendererCommand>
+}
+
+impl RendererProxy {
+    // Replaces the drawn model, the current texture is kept without one
+    pub fn load_model(&self, path: &str, texture: Option<&str>) -> Reply<()> {
+        let (reply, receiver) = mpsc::channel();
+        // A renderer that has exited drops the reply sender with the command, which fails recv
+        let _ = self.sender.send(RendererCommand::LoadModel {
+            path: path.to_string(),
+            texture: texture.map(str::to_string),
+            reply
+        });
+
+        receiver
+    }
+
+    pub fn set_camera(&self, camera: SceneCamera) -> Result<(), String> {
+        self.send(RendererCommand::SetCamera(camera))
+    }
+
+    // Moves the camera to show everything that is drawn, like after load_model
+    pub fn frame_scene(&self, margin: f32) -> Result<(), String> {
+        self.send(RendererCommand::FrameScene { margin })
+    }
+
+    // The reply carries path once the PNG has been written
+    pub fn screenshot(&self, path: PathBuf) -> Reply<PathBuf> {
+        let (reply, receiver) = mpsc::channel();
+        let _ = self.sender.send(RendererCommand::Screenshot { path, reply });
+
+        receiver
+    }
+
+    pub fn set_setting(&self, setting: RendererSetting) -> Result<(), String> {
+        self.send(RendererCommand::SetSetting(setting))
+    }
+
+    // Only while paused with RendererSetting::Paused
+    pub fn step_frame(&self) -> Result<(), String> {
+        self.send(RendererCommand::StepFrame)
+    }
+
+    // The first viewport's camera becomes the current camera, see RasterRenderer::set_viewports
+    pub fn set_viewports(&self, viewports: Vec<Viewport>) -> Result<(), String> {
+        self.send(RendererCommand::SetViewports(viewports))
+    }
+
+    // Replaces the material with the same name wherever it is drawn, for live editing, see MaterialEditor
+    pub fn set_material(&self, material: Material) -> Reply<()> {
+        let (reply, receiver) = mpsc::channel();
+        let _ = self.sender.send(RendererCommand::SetMaterial { material, reply });
+
+        receiver
+    }
+
+    fn send(&self, command: RendererCommand) -> Result<(), String> {
+        self.sender.send(command).map_err(|_| String::from("The renderer has exited"))
+    }
+}
+
+// Renderers check screenshot paths before capturing, writing the PNG panics otherwise
+pub fn check_screenshot_path(path: &Path) -> Result<(), String> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => Err(format!("{} is not a directory",
+                                                                                  dir.display())),
+        _ => Ok(())
+    }
+}
+
+// The renderer's end of its proxies
+pub struct CommandQueue {
+    sender: Sender<RendererCommand>,
+    receiver: Receiver<RendererCommand>
+}
+
+impl Default for CommandQueue {
+    fn default() -> CommandQueue {
+        let (sender, receiver) = mpsc::channel();
+        CommandQueue {
+            sender,
+            receiver
+        }
+    }
+}
+
+impl CommandQueue {
+    pub fn proxy(&self) -> RendererProxy {
+        RendererProxy {
+            sender: self.sender.clone()
+        }
+    }
+
+    // Everything sent since the last call, doesn't block
+    pub fn drain(&self) -> Vec<RendererCommand> {
+        self.receiver.try_iter().collect()
+    }
+}
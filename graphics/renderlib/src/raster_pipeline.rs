@@ -6,8 +6,18 @@ use std::mem;
 
 use ash::vk;
 use ash::vk::PipelineLayoutCreateFlags;
-
-use crate::vertex::Vertex;
+use tracing::{info_span, warn};
+
+use crate::aux_camera::HudConstants;
+use crate::color_pipeline::ColorConstants;
+use crate::debug_ui::UiConstants;
+use crate::depth::{stencil_replace, stencil_test};
+use crate::outline::{MaskConstants, OutlineConstants};
+use crate::pipeline_manager::PipelineDesc;
+use crate::reflection::PipelineReflection;
+use crate::shadow::ShadowConstants;
+use crate::specialization::SpecConstants;
+use crate::vertex::{Dequantization, DEQUANTIZATION_OFFSET, VertexAttribute, VertexLayout};
 use crate::vkcore::VkCore;
 
 fn load_shader(path: &str) -> Result<Vec<u8>, String> {
@@ -24,9 +34,59 @@ fn load_shader(path: &str) -> Result<Vec<u8>, String> {
 }
 
 
-fn load_all_shaders(core: &VkCore) -> Vec<vk::ShaderModule> {
-    let shader_paths = ["graphics/shaders/spv/vert.spv", "graphics/shaders/spv/frag.spv"];
-
+const DEFAULT_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/vert.spv", "graphics/shaders/spv/frag.spv"];
+// Same fragment shader, the vertex shader picks its matrices with gl_ViewIndex
+const MULTIVIEW_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/multiview_vert.spv", "graphics/shaders/spv/frag.spv"];
+// Same fragment shader, the vertex shader blends the joint matrices of each SkinnedVertex
+const SKINNED_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/skinned_vert.spv", "graphics/shaders/spv/frag.spv"];
+// Same fragment shader, the vertex shader dequantizes QuantizedVertex attributes
+const QUANTIZED_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/quantized_vert.spv", "graphics/shaders/spv/frag.spv"];
+// Default vertex shader, the fragment shader is lit by ClusteredLights
+const CLUSTERED_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/vert.spv",
+    "graphics/shaders/spv/forward_lit_frag.spv"];
+// Like CLUSTERED_SHADER_PATHS, the vertex shader reads the model matrix from a storage buffer at gl_InstanceIndex
+const OBJECT_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/objects_vert.spv",
+    "graphics/shaders/spv/forward_lit_frag.spv"];
+// Like OBJECT_SHADER_PATHS, the vertex shader offsets the block mesh by the block at gl_InstanceIndex
+const VOXEL_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/voxel_vert.spv",
+    "graphics/shaders/spv/forward_lit_frag.spv"];
+// World space quads from voxel_mesh.comp, the fragment shader repeats the block's atlas tile across each
+const VOXEL_MESH_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/voxel_mesh_vert.spv",
+    "graphics/shaders/spv/voxel_mesh_frag.spv"];
+// Samples a SparseTexture instead of the texture binding
+const VIRTUAL_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/vert.spv",
+    "graphics/shaders/spv/forward_virtual_frag.spv"];
+// Blends a TerrainMesh's layers by the splat map at the texture binding
+const TERRAIN_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/vert.spv",
+    "graphics/shaders/spv/terrain_frag.spv"];
+// Displaces a Water grid, the fragment shader reads the depth buffer with or without multisampling
+const WATER_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/water_vert.spv", "graphics/shaders/spv/water_frag.spv"];
+const WATER_MS_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/water_vert.spv",
+    "graphics/shaders/spv/water_ms_frag.spv"];
+// A triangle at the far plane, the fragment shader evaluates the sky along the view ray
+const SKY_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/sky_vert.spv", "graphics/shaders/spv/sky_frag.spv"];
+// A triangle covering the viewport, the fragment shader intersects the view ray with the ground plane
+const GRID_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/grid_vert.spv", "graphics/shaders/spv/grid_frag.spv"];
+// World space lines with a color per vertex
+const DEBUG_LINES_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/debug_lines_vert.spv",
+    "graphics/shaders/spv/debug_lines_frag.spv"];
+// Writes the distance to a point light as depth, one cube face per draw or all six with multiview
+const SHADOW_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/shadow_vert.spv", "graphics/shaders/spv/shadow_frag.spv"];
+const SHADOW_MULTIVIEW_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/shadow_multiview_vert.spv",
+    "graphics/shaders/spv/shadow_frag.spv"];
+// Selected objects as a silhouette, and the outline around it over the finished image
+const OUTLINE_MASK_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/outline_mask_vert.spv",
+    "graphics/shaders/spv/outline_mask_frag.spv"];
+const OUTLINE_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/fullscreen_vert.spv",
+    "graphics/shaders/spv/outline_frag.spv"];
+// Auxiliary camera views blended over the finished image
+const HUD_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/fullscreen_vert.spv", "graphics/shaders/spv/hud_frag.spv"];
+// egui's meshes in points, with a texture per draw
+const UI_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/ui_vert.spv", "graphics/shaders/spv/ui_frag.spv"];
+// Default vertex shader, the fragment shader writes the attachments of a GBuffer
+const GBUFFER_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/vert.spv", "graphics/shaders/spv/gbuffer_frag.spv"];
+
+pub(crate) fn load_all_shaders(core: &VkCore, shader_paths: &[&str]) -> Vec<vk::ShaderModule> {
     let mut shader_modules: Vec<vk::ShaderModule> = Vec::with_capacity(shader_paths.len());
     for sp in shader_paths.iter() {
         let shader_spv = load_shader(sp).unwrap();
@@ -46,27 +106,391 @@ fn load_all_shaders(core: &VkCore) -> Vec<vk::ShaderModule> {
     shader_modules
 }
 
-fn setup_pipeline_layout(core: &VkCore, layout: vk::DescriptorSetLayout) -> vk::PipelineLayout  {
-    let ubo_layout_binding_arr = [layout];
-
-    let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::default()
-        .set_layouts(&ubo_layout_binding_arr)
+// push_constant_size of 0 creates a layout without push constants
+fn setup_pipeline_layout(core: &VkCore, layouts: &[vk::DescriptorSetLayout], push_constant_stages: vk::ShaderStageFlags,
+                         push_constant_size: u32) -> vk::PipelineLayout  {
+    let push_constant_ranges = [
+        vk::PushConstantRange::default()
+            .offset(0)
+            .size(push_constant_size)
+            .stage_flags(push_constant_stages)
+    ];
+
+    let mut pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::default()
+        .set_layouts(layouts)
         .flags(PipelineLayoutCreateFlags::empty());
+    if push_constant_size > 0 {
+        pipeline_layout_create_info = pipeline_layout_create_info.push_constant_ranges(&push_constant_ranges);
+    }
 
     unsafe {
         core.logical_device.create_pipeline_layout(&pipeline_layout_create_info, None).unwrap()
     }
 }
 
+// How every color attachment of a pipeline is blended with what was drawn before
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Opaque,
+    Alpha, // By the source alpha
+    Additive, // Adds the color, the destination alpha is kept
+    Premultiplied // Like Alpha, for colors that are already multiplied by their alpha
+}
+
+// Fixed function state that differs between the pipelines
+#[derive(Clone, Copy)]
+struct PipelineState {
+    msaa_samples: vk::SampleCountFlags,
+    depth_write: bool,
+    depth_compare_op: vk::CompareOp,
+    stencil: Option<vk::StencilOpState>, // For front and back faces, see stencil_replace and stencil_test
+    color_attachments: usize, // Depth only passes have none
+    blend: BlendMode,
+    topology: vk::PrimitiveTopology,
+    polygon_mode: vk::PolygonMode, // LINE for wireframes needs the fillModeNonSolid feature
+    cull_mode: vk::CullModeFlags,
+    push_constant_stages: vk::ShaderStageFlags,
+    push_constant_size: u32
+}
+
+impl PipelineState {
+    // A single blended color attachment, a depth buffer that is tested and written, and ColorConstants for the fragment
+    // shader
+    fn forward(msaa_samples: vk::SampleCountFlags) -> PipelineState {
+        PipelineState {
+            msaa_samples,
+            depth_write: true,
+            depth_compare_op: vk::CompareOp::LESS,
+            stencil: None,
+            color_attachments: 1,
+            blend: BlendMode::Alpha,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            push_constant_stages: vk::ShaderStageFlags::FRAGMENT,
+            push_constant_size: mem::size_of::<ColorConstants>() as u32
+        }
+    }
+}
+
 pub struct RasterPipeline {
     pub pipeline_layout: vk::PipelineLayout,
-    pub pipelines: Vec<vk::Pipeline>
+    pub pipelines: Vec<vk::Pipeline>,
+    pub set_layouts: Vec<vk::DescriptorSetLayout> // Owned by the pipeline, only for new_reflected
 }
 
 impl RasterPipeline {
     pub fn new(core: &VkCore, render_pass: vk::RenderPass,
                layout: vk::DescriptorSetLayout, msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
-        fn setup_pipeline_stages(shader_modules: &Vec<vk::ShaderModule>) -> Vec<vk::PipelineShaderStageCreateInfo> {
+        RasterPipeline::new_with_shaders(core, render_pass, layout, msaa_samples, &DEFAULT_SHADER_PATHS)
+    }
+
+    // For render passes created with setup_multiview_render_pass. The descriptor layout is the same as the default
+    // pipeline, but the bound uniform buffer must come from UniformBuffer::new_stereo.
+    pub fn new_multiview(core: &VkCore, render_pass: vk::RenderPass,
+                         layout: vk::DescriptorSetLayout) -> RasterPipeline {
+        RasterPipeline::new_with_shaders(core, render_pass, layout, vk::SampleCountFlags::TYPE_1,
+                                         &MULTIVIEW_SHADER_PATHS)
+    }
+
+    // For SkinnedVertex buffers, layout must come from create_skinned_descriptor_set_layout
+    pub fn new_skinned(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                       msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout], msaa_samples, &SKINNED_SHADER_PATHS,
+                              &VertexLayout::skinned())
+    }
+
+    // For QuantizedMeshData buffers, with the descriptor layout of the default pipeline. The push constants hold the
+    // ColorConstants and the mesh's Dequantization at DEQUANTIZATION_OFFSET, both stages see all of them, so the
+    // ColorConstants have to be pushed for both stages too.
+    pub fn new_quantized(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                         msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        let state = PipelineState {
+            push_constant_stages: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            push_constant_size: DEQUANTIZATION_OFFSET + mem::size_of::<Dequantization>() as u32,
+            ..PipelineState::forward(msaa_samples)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[layout], &QUANTIZED_SHADER_PATHS,
+                                         &VertexLayout::quantized(), state)
+    }
+
+    // shader_paths are in [vert, frag] order
+    pub fn new_with_shaders(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                            msaa_samples: vk::SampleCountFlags, shader_paths: &[&str]) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout], msaa_samples, shader_paths, &VertexLayout::vertex())
+    }
+
+    // Like new_with_shaders, with values for the specialization constants of both stages
+    pub fn new_specialized(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                           msaa_samples: vk::SampleCountFlags, shader_paths: &[&str],
+                           spec_constants: &SpecConstants) -> RasterPipeline {
+        RasterPipeline::build_specialized(core, render_pass, &[layout], shader_paths, &VertexLayout::vertex(),
+                                          PipelineState::forward(msaa_samples), spec_constants)
+    }
+
+    // Lit by the lights of a ClusteredLights, whose set_layout is bound as set 1
+    pub fn new_clustered(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                         light_layout: vk::DescriptorSetLayout, msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout, light_layout], msaa_samples, &CLUSTERED_SHADER_PATHS,
+                              &VertexLayout::vertex())
+    }
+
+    // Like new_clustered for ObjectDraws, layout must come from create_object_descriptor_set_layout
+    pub fn new_objects(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                       light_layout: vk::DescriptorSetLayout, msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout, light_layout], msaa_samples, &OBJECT_SHADER_PATHS,
+                              &VertexLayout::vertex())
+    }
+
+    // Like new_objects for VoxelDraws, with the chunk's block instances at binding 2 instead of model matrices
+    pub fn new_voxels(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                      light_layout: vk::DescriptorSetLayout, msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout, light_layout], msaa_samples, &VOXEL_SHADER_PATHS,
+                              &VertexLayout::vertex())
+    }
+
+    // Like new_voxels for greedy meshed chunks, with the palette's atlas tiles at binding 2
+    pub fn new_voxel_mesh(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                          light_layout: vk::DescriptorSetLayout, msaa_samples: vk::SampleCountFlags)
+        -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout, light_layout], msaa_samples, &VOXEL_MESH_SHADER_PATHS,
+                              &VertexLayout::voxel())
+    }
+
+    // Like new_clustered, with a SparseTexture's set_layout bound as set 2
+    pub fn new_clustered_virtual(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                                 light_layout: vk::DescriptorSetLayout, virtual_layout: vk::DescriptorSetLayout,
+                                 msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout, light_layout, virtual_layout], msaa_samples,
+                              &VIRTUAL_SHADER_PATHS, &VertexLayout::vertex())
+    }
+
+    // Like new_clustered, with a TerrainMesh's material layout bound as set 2
+    pub fn new_terrain(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                       light_layout: vk::DescriptorSetLayout, material_layout: vk::DescriptorSetLayout,
+                       msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout, light_layout, material_layout], msaa_samples,
+                              &TERRAIN_SHADER_PATHS, &VertexLayout::vertex())
+    }
+
+    // For single sample passes over the resolved frame, like Water's. depth_samples is the sample count of the depth
+    // buffer bound to layout, which the fragment shader tests against itself.
+    pub fn new_water(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                     depth_samples: vk::SampleCountFlags) -> RasterPipeline {
+        let shader_paths = match depth_samples {
+            vk::SampleCountFlags::TYPE_1 => WATER_SHADER_PATHS,
+            _ => WATER_MS_SHADER_PATHS
+        };
+        RasterPipeline::build(core, render_pass, &[layout], vk::SampleCountFlags::TYPE_1, &shader_paths,
+                              &VertexLayout::vertex())
+    }
+
+    // Like new_fullscreen, inside the forward render pass after the opaque geometry. The triangle lies on the far
+    // plane and only covers what nothing else was drawn over, without writing depth.
+    pub fn new_sky(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                   msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        let state = PipelineState {
+            depth_write: false,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            ..PipelineState::forward(msaa_samples)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[layout], &SKY_SHADER_PATHS, &VertexLayout::new(), state)
+    }
+
+    // Like new_sky, blended over everything drawn before it. The fragment shader writes the depth of the ground plane,
+    // which is tested but not written. The frame constants are set 0 and the grid's uniforms set 1.
+    pub fn new_grid(core: &VkCore, render_pass: vk::RenderPass, frame_layout: vk::DescriptorSetLayout,
+                    layout: vk::DescriptorSetLayout, msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        let state = PipelineState {
+            depth_write: false,
+            ..PipelineState::forward(msaa_samples)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[frame_layout, layout], &GRID_SHADER_PATHS,
+                                         &VertexLayout::new(), state)
+    }
+
+    // Lines from every two vertices of DebugVertex buffers, tested against the depth buffer without writing it. The
+    // frame constants are set 0.
+    pub fn new_debug_lines(core: &VkCore, render_pass: vk::RenderPass, frame_layout: vk::DescriptorSetLayout,
+                           msaa_samples: vk::SampleCountFlags) -> RasterPipeline {
+        let state = PipelineState {
+            depth_write: false,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            topology: vk::PrimitiveTopology::LINE_LIST,
+            cull_mode: vk::CullModeFlags::NONE,
+            ..PipelineState::forward(msaa_samples)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[frame_layout], &DEBUG_LINES_SHADER_PATHS,
+                                         &VertexLayout::from_attributes(&[VertexAttribute::Position,
+                                             VertexAttribute::Color]), state)
+    }
+
+    // For the depth only render passes of PointShadows, with ShadowConstants for both stages. Both sides of the
+    // triangles cast shadows.
+    pub fn new_shadow(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                      multiview: bool) -> RasterPipeline {
+        let shader_paths = match multiview {
+            true => SHADOW_MULTIVIEW_SHADER_PATHS,
+            false => SHADOW_SHADER_PATHS
+        };
+        let state = PipelineState {
+            color_attachments: 0,
+            cull_mode: vk::CullModeFlags::NONE,
+            push_constant_stages: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            push_constant_size: mem::size_of::<ShadowConstants>() as u32,
+            ..PipelineState::forward(vk::SampleCountFlags::TYPE_1)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[layout], &shader_paths, &VertexLayout::vertex(), state)
+    }
+
+    // For the render pass of a GBuffer, with one unblended output per attachment. Single sample, since the
+    // attachments are read per pixel by compute passes.
+    pub fn new_gbuffer(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                       color_attachments: usize) -> RasterPipeline {
+        let state = PipelineState {
+            color_attachments,
+            blend: BlendMode::Opaque,
+            ..PipelineState::forward(vk::SampleCountFlags::TYPE_1)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[layout], &GBUFFER_SHADER_PATHS,
+                                         &VertexLayout::vertex(), state)
+    }
+
+    // For the mask render pass of an Outline, a model view projection matrix is pushed for the vertex shader. Both
+    // sides of the triangles are drawn, and nothing is blended into the single channel attachment. The stencil is set
+    // to 1 wherever the mask is, without depth testing.
+    pub fn new_outline_mask(core: &VkCore, render_pass: vk::RenderPass) -> RasterPipeline {
+        let state = PipelineState {
+            depth_write: false,
+            depth_compare_op: vk::CompareOp::ALWAYS,
+            stencil: Some(stencil_replace(1)),
+            blend: BlendMode::Opaque,
+            cull_mode: vk::CullModeFlags::NONE,
+            push_constant_stages: vk::ShaderStageFlags::VERTEX,
+            push_constant_size: mem::size_of::<MaskConstants>() as u32,
+            ..PipelineState::forward(vk::SampleCountFlags::TYPE_1)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[], &OUTLINE_MASK_SHADER_PATHS,
+                                         &VertexLayout::vertex(), state)
+    }
+
+    // Like new_fullscreen, with OutlineConstants instead of ColorConstants, and only where new_outline_mask left the
+    // stencil at 0
+    pub fn new_outline(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout) -> RasterPipeline {
+        let state = PipelineState {
+            depth_write: false,
+            depth_compare_op: vk::CompareOp::ALWAYS,
+            stencil: Some(stencil_test(vk::CompareOp::NOT_EQUAL, 1)),
+            push_constant_size: mem::size_of::<OutlineConstants>() as u32,
+            ..PipelineState::forward(vk::SampleCountFlags::TYPE_1)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[layout], &OUTLINE_SHADER_PATHS,
+                                         &VertexLayout::new(), state)
+    }
+
+    // Like new_fullscreen, with HudConstants instead of ColorConstants, for the HUD pass of AuxCameras
+    pub fn new_hud(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout) -> RasterPipeline {
+        let state = PipelineState {
+            push_constant_size: mem::size_of::<HudConstants>() as u32,
+            ..PipelineState::forward(vk::SampleCountFlags::TYPE_1)
+        };
+        RasterPipeline::build_with_state(core, render_pass, &[layout], &HUD_SHADER_PATHS, &VertexLayout::new(), state)
+    }
+
+    // For the render pass of DebugUiDraws over the finished image, with UiConstants for both stages. egui's vertices
+    // are a 2D position, a texture coordinate and an sRGB color with premultiplied alpha, in any winding order.
+    pub fn new_ui(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout) -> RasterPipeline {
+        let state = PipelineState {
+            blend: BlendMode::Premultiplied,
+            cull_mode: vk::CullModeFlags::NONE,
+            push_constant_stages: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            push_constant_size: mem::size_of::<UiConstants>() as u32,
+            ..PipelineState::forward(vk::SampleCountFlags::TYPE_1)
+        };
+        let vertex_layout = VertexLayout::from_attributes(&[
+            VertexAttribute::Custom { location: 0, format: vk::Format::R32G32_SFLOAT },
+            VertexAttribute::TexCoord,
+            VertexAttribute::Custom { location: 1, format: vk::Format::R8G8B8A8_UNORM }
+        ]);
+        RasterPipeline::build_with_state(core, render_pass, &[layout], &UI_SHADER_PATHS, &vertex_layout, state)
+    }
+
+    // A single triangle covering the viewport, drawn with cmd_draw(3) and no vertex buffers. For single sample
+    // passes over a finished image, shader_paths are in [vert, frag] order.
+    pub fn new_fullscreen(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                          shader_paths: &[&str]) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout], vk::SampleCountFlags::TYPE_1, shader_paths,
+                              &VertexLayout::new())
+    }
+
+    // For meshes whose vertices don't match Vertex, like ones loaded with load_mesh. The vertex shader has to read
+    // the attributes at their VertexAttribute::location.
+    pub fn new_with_layout(core: &VkCore, render_pass: vk::RenderPass, layout: vk::DescriptorSetLayout,
+                           msaa_samples: vk::SampleCountFlags, shader_paths: &[&str],
+                           vertex_layout: &VertexLayout) -> RasterPipeline {
+        RasterPipeline::build(core, render_pass, &[layout], msaa_samples, shader_paths, vertex_layout)
+    }
+
+    // Forward state, with the set layouts, push constants and vertex inputs derived from the shaders, see
+    // PipelineReflection. Descriptor sets are allocated with set_layouts, vertex buffers hold the inputs interleaved
+    // in location order.
+    pub fn new_reflected(core: &VkCore, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags,
+                         shader_paths: &[&str]) -> RasterPipeline {
+        let reflection = PipelineReflection::load(shader_paths).unwrap();
+        let set_layouts = reflection.create_set_layouts(core);
+        let state = PipelineState {
+            push_constant_stages: reflection.push_constant_stages,
+            push_constant_size: reflection.push_constant_size,
+            ..PipelineState::forward(msaa_samples)
+        };
+        let mut pipeline = RasterPipeline::build_specialized(core, render_pass, set_layouts.as_slice(), shader_paths,
+                                                             &reflection.vertex_layout(), state,
+                                                             &SpecConstants::new());
+        pipeline.set_layouts = set_layouts;
+
+        pipeline
+    }
+
+    // Usually through a PipelineManager, which creates every variant once
+    pub fn from_desc(core: &VkCore, desc: &PipelineDesc) -> RasterPipeline {
+        let state = PipelineState {
+            msaa_samples: desc.msaa_samples,
+            depth_write: desc.depth_write,
+            depth_compare_op: desc.depth_compare_op,
+            stencil: None,
+            color_attachments: desc.color_attachments,
+            blend: desc.blend,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: desc.polygon_mode,
+            cull_mode: desc.cull_mode,
+            push_constant_stages: desc.push_constant_stages,
+            push_constant_size: desc.push_constant_size
+        };
+        let shader_paths: Vec<&str> = desc.shader_paths.iter().map(|p| p.as_str()).collect();
+        RasterPipeline::build_specialized(core, desc.render_pass, &desc.set_layouts, &shader_paths,
+                                          &desc.vertex_layout, state, &desc.spec_constants)
+    }
+
+    fn build(core: &VkCore, render_pass: vk::RenderPass, layouts: &[vk::DescriptorSetLayout],
+             msaa_samples: vk::SampleCountFlags, shader_paths: &[&str],
+             vertex_layout: &VertexLayout) -> RasterPipeline {
+        RasterPipeline::build_with_state(core, render_pass, layouts, shader_paths, vertex_layout,
+                                         PipelineState::forward(msaa_samples))
+    }
+
+    fn build_with_state(core: &VkCore, render_pass: vk::RenderPass, layouts: &[vk::DescriptorSetLayout],
+                        shader_paths: &[&str], vertex_layout: &VertexLayout, state: PipelineState) -> RasterPipeline {
+        RasterPipeline::build_specialized(core, render_pass, layouts, shader_paths, vertex_layout, state,
+                                          &SpecConstants::new())
+    }
+
+    fn build_specialized(core: &VkCore, render_pass: vk::RenderPass, layouts: &[vk::DescriptorSetLayout],
+                         shader_paths: &[&str], vertex_layout: &VertexLayout, state: PipelineState,
+                         spec_constants: &SpecConstants) -> RasterPipeline {
+        let _span = info_span!("raster_pipeline_create", vertex_shader = shader_paths[0]).entered();
+        fn setup_pipeline_stages<'a>(shader_modules: &[vk::ShaderModule],
+                                     spec_info: Option<&'a vk::SpecializationInfo<'a>>)
+                                     -> Vec<vk::PipelineShaderStageCreateInfo<'a>> {
             // Reminder that shader modules are in [vert, frag] order
             let create_bits = [vk::ShaderStageFlags::VERTEX,
                 vk::ShaderStageFlags::FRAGMENT];
@@ -74,29 +498,44 @@ impl RasterPipeline {
                 shader_modules.len());
             for (sm, flag) in shader_modules.iter()
                 .zip(create_bits) {
-                create_info.push(vk::PipelineShaderStageCreateInfo::default()
+                let mut stage = vk::PipelineShaderStageCreateInfo::default()
                     .name(CStr::from_bytes_with_nul(b"main\0").unwrap())
                     .stage(flag)
-                    .module(*sm)
-                );
+                    .module(*sm);
+                if let Some(info) = spec_info {
+                    stage = stage.specialization_info(info);
+                }
+                create_info.push(stage);
             }
 
             create_info
         }
 
-        let shader_modules = load_all_shaders(core);
-
-        let pipeline_stages = setup_pipeline_stages(&shader_modules);
+        let shader_modules = load_all_shaders(core, shader_paths);
+        // Mismatches are bugs, but the shaders might still get by, e.g. with push constants they never read
+        match PipelineReflection::load(shader_paths) {
+            Ok(reflection) => {
+                let checks = [reflection.check_vertex_layout(vertex_layout),
+                    reflection.check_push_constants(state.push_constant_stages, state.push_constant_size)];
+                for e in checks.into_iter().filter_map(Result::err) {
+                    warn!(vertex_shader = shader_paths[0], "The pipeline doesn't fit its shaders: {}", e);
+                }
+            },
+            Err(e) => warn!("Can't check the pipeline against its shaders: {}", e)
+        }
 
-        let vertex_binding_descriptions = [Vertex::get_binding_description()];
-        let vertex_attribute_descriptions = &Vertex::get_attribute_descriptions();
+        let spec_data = spec_constants.data();
+        let spec_info = spec_data.info();
+        let pipeline_stages = setup_pipeline_stages(&shader_modules, spec_info.as_ref());
 
+        let vertex_binding_descriptions = vertex_layout.binding_descriptions();
+        let vertex_attribute_descriptions = vertex_layout.attribute_descriptions();
         let vertex_inputs = vk::PipelineVertexInputStateCreateInfo::default() // Describe the format of each Vertex buffer entry
-            .vertex_attribute_descriptions(vertex_attribute_descriptions)
-            .vertex_binding_descriptions(&vertex_binding_descriptions);
+            .vertex_attribute_descriptions(vertex_attribute_descriptions.as_slice())
+            .vertex_binding_descriptions(vertex_binding_descriptions.as_slice());
 
         let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST) // Triangle from every three vertices
+            .topology(state.topology) // Usually a triangle from every three vertices
             .primitive_restart_enable(false); // ??
 
         let viewport_state = vk::PipelineViewportStateCreateInfo::default()
@@ -106,9 +545,9 @@ impl RasterPipeline {
         let rasterization_state = vk::PipelineRasterizationStateCreateInfo::default()
             .depth_clamp_enable(false) // Clamps (?) fragments beyond the far and near planes to said planes
             .rasterizer_discard_enable(false) // Makes geometry not pass through the rasterizer
-            .polygon_mode(vk::PolygonMode::FILL) // Determines whether polygons are represented as points, lines or surfaces
+            .polygon_mode(state.polygon_mode) // Determines whether polygons are represented as points, lines or surfaces
             .line_width(1.0) // Line thickness in units of fragment numbers (probably roughly equivalent to pixels?)
-            .cull_mode(vk::CullModeFlags::BACK) // Cull the back faces of geometry
+            .cull_mode(state.cull_mode) // Usually the back faces of geometry
             .front_face(vk::FrontFace::COUNTER_CLOCKWISE) // Rules for determining if a face is front ??
             .depth_bias_enable(false) // Parameters for transforming depth values
             .depth_bias_constant_factor(0.0)
@@ -117,22 +556,28 @@ impl RasterPipeline {
 
         let multisample_state = vk::PipelineMultisampleStateCreateInfo::default()
             .sample_shading_enable(true) // Disabled for now
-            .rasterization_samples(msaa_samples)
+            .rasterization_samples(state.msaa_samples)
             .min_sample_shading(0.2)
             // .sample_mask() Leave NULL
             .alpha_to_coverage_enable(false)
             .alpha_to_one_enable(false);
 
-        let additive_color_blending_create_infos = [
+        let (src_color_factor, dst_color_factor) = match state.blend {
+            BlendMode::Additive => (vk::BlendFactor::ONE, vk::BlendFactor::ONE),
+            BlendMode::Premultiplied => (vk::BlendFactor::ONE, vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            _ => (vk::BlendFactor::SRC_ALPHA, vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        };
+        let additive_color_blending_create_infos = vec![
             vk::PipelineColorBlendAttachmentState::default()
                 .color_write_mask(vk::ColorComponentFlags::RGBA)
-                .blend_enable(true)
-                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-                .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .blend_enable(state.blend != BlendMode::Opaque)
+                .src_color_blend_factor(src_color_factor)
+                .dst_color_blend_factor(dst_color_factor)
                 .color_blend_op(vk::BlendOp::ADD) // Blend operation
                 .src_alpha_blend_factor(vk::BlendFactor::ONE)
                 .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
-                .alpha_blend_op(vk::BlendOp::ADD)
+                .alpha_blend_op(vk::BlendOp::ADD);
+            state.color_attachments
         ];
 
         let blend_constants: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
@@ -140,7 +585,7 @@ impl RasterPipeline {
         let color_blending_create_info = vk::PipelineColorBlendStateCreateInfo::default()
             .logic_op_enable(false) // Note that enabling this disables all of the attachment states effects
             .logic_op(vk::LogicOp::COPY)
-            .attachments(&additive_color_blending_create_infos)
+            .attachments(additive_color_blending_create_infos.as_slice())
             .blend_constants(blend_constants);
 
         let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
@@ -148,15 +593,17 @@ impl RasterPipeline {
         let dynamic_state_create_info = vk::PipelineDynamicStateCreateInfo::default()
             .dynamic_states(&dynamic_states);
 
-        let pipeline_layout = setup_pipeline_layout(core, layout);
+        let pipeline_layout = setup_pipeline_layout(core, layouts, state.push_constant_stages,
+                                                    state.push_constant_size);
 
         let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::default()
             .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS)
+            .depth_write_enable(state.depth_write)
+            .depth_compare_op(state.depth_compare_op)
             .depth_bounds_test_enable(false)
-            .front(vk::StencilOpState::default())
-            .back(vk::StencilOpState::default());
+            .stencil_test_enable(state.stencil.is_some())
+            .front(state.stencil.unwrap_or_default())
+            .back(state.stencil.unwrap_or_default());
 
         let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
             .stages(&pipeline_stages)
@@ -182,16 +629,20 @@ impl RasterPipeline {
 
         RasterPipeline {
             pipeline_layout,
-            pipelines
+            pipelines,
+            set_layouts: Vec::new()
         }
     }
 
-    pub fn destroy(&mut self, core: &VkCore) {
+    pub fn destroy(&self, core: &VkCore) {
         unsafe {
             for s in self.pipelines.iter() {
                 core.logical_device.destroy_pipeline(*s, None);
             }
             core.logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+            for &layout in self.set_layouts.iter() {
+                core.logical_device.destroy_descriptor_set_layout(layout, None);
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::fs;
+
+use ash::vk;
+use rspirv::dr::{Instruction, Module, Operand};
+use rspirv::spirv::{Decoration, Dim, ExecutionModel, Op, StorageClass};
+
+use crate::vertex::{VertexAttribute, VertexLayout};
+use crate::vkcore::VkCore;
+
+// A descriptor one or more stages of a pipeline declare
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReflectedBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32, // 0 for runtime sized arrays
+    pub stages: vk::ShaderStageFlags
+}
+
+// What a pipeline layout and vertex input state need from a single shader module
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+    pub bindings: Vec<ReflectedBinding>,
+    pub push_constant_size: u32, // From offset 0 to the end of the block, 0 without push constants
+    pub inputs: Vec<(u32, vk::Format)> // By location, only kept for vertex shaders
+}
+
+// The decorations and types of a module by id
+struct Ids<'a> {
+    decorations: HashMap<(u32, Decoration), Option<u32>>, // With the literal after it, if any
+    member_decorations: HashMap<(u32, u32, Decoration), u32>,
+    definitions: HashMap<u32, &'a Instruction>
+}
+
+impl<'a> Ids<'a> {
+    fn new(module: &'a Module) -> Ids<'a> {
+        let mut decorations = HashMap::new();
+        let mut member_decorations = HashMap::new();
+        for annotation in module.annotations.iter() {
+            match (annotation.class.opcode, annotation.operands.as_slice()) {
+                (Op::Decorate, [Operand::IdRef(id), Operand::Decoration(d), rest @ ..]) => {
+                    let literal = match rest.first() {
+                        Some(Operand::LiteralInt32(v)) => Some(*v),
+                        _ => None
+                    };
+                    decorations.insert((*id, *d), literal);
+                },
+                (Op::MemberDecorate, [Operand::IdRef(id), Operand::LiteralInt32(member), Operand::Decoration(d),
+                    Operand::LiteralInt32(v), ..]) => {
+                    member_decorations.insert((*id, *member, *d), *v);
+                },
+                _ => {}
+            }
+        }
+        let definitions = module.types_global_values.iter()
+            .filter_map(|i| i.result_id.map(|id| (id, i)))
+            .collect();
+
+        Ids {
+            decorations,
+            member_decorations,
+            definitions
+        }
+    }
+
+    fn decoration(&self, id: u32, decoration: Decoration) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).copied().flatten()
+    }
+
+    fn has_decoration(&self, id: u32, decoration: Decoration) -> bool {
+        self.decorations.contains_key(&(id, decoration))
+    }
+
+    fn definition(&self, id: u32) -> Result<&'a Instruction, String> {
+        self.definitions.get(&id).copied().ok_or_else(|| format!("Undefined id {}", id))
+    }
+
+    fn id_operand(&self, instruction: &Instruction, n: usize) -> Result<u32, String> {
+        match instruction.operands.get(n) {
+            Some(Operand::IdRef(id)) => Ok(*id),
+            o => Err(format!("Expected an id as operand {} of {:?}, not {:?}", n, instruction.class.opcode, o))
+        }
+    }
+
+    fn literal_operand(&self, instruction: &Instruction, n: usize) -> Result<u32, String> {
+        match instruction.operands.get(n) {
+            Some(Operand::LiteralInt32(v)) => Ok(*v),
+            o => Err(format!("Expected a literal as operand {} of {:?}, not {:?}", n, instruction.class.opcode, o))
+        }
+    }
+
+    // Of an OpConstant, for array lengths
+    fn constant(&self, id: u32) -> Result<u32, String> {
+        self.literal_operand(self.definition(id)?, 0)
+    }
+
+    // Following the layout decorations, like the shader reads it
+    fn size(&self, type_id: u32) -> Result<u32, String> {
+        let definition = self.definition(type_id)?;
+        match definition.class.opcode {
+            Op::TypeInt | Op::TypeFloat => Ok(self.literal_operand(definition, 0)? / 8),
+            Op::TypeBool => Ok(4),
+            Op::TypeVector => Ok(self.size(self.id_operand(definition, 0)?)? * self.literal_operand(definition, 1)?),
+            Op::TypeArray => {
+                let length = self.constant(self.id_operand(definition, 1)?)?;
+                let stride = match self.decoration(type_id, Decoration::ArrayStride) {
+                    Some(stride) => stride,
+                    None => self.size(self.id_operand(definition, 0)?)?
+                };
+                Ok(length * stride)
+            },
+            Op::TypeStruct => {
+                let mut size = 0;
+                for member in 0..definition.operands.len() {
+                    let member_type = self.id_operand(definition, member)?;
+                    let offset = self.member_decorations.get(&(type_id, member as u32, Decoration::Offset))
+                        .copied()
+                        .unwrap_or(size);
+                    let member_size = match self.member_decorations.get(&(type_id, member as u32,
+                                                                            Decoration::MatrixStride)) {
+                        Some(stride) => {
+                            let columns = self.literal_operand(self.definition(member_type)?, 1)?;
+                            stride * columns
+                        },
+                        None => self.size(member_type)?
+                    };
+                    size = size.max(offset + member_size);
+                }
+                Ok(size)
+            },
+            Op::TypeMatrix => {
+                let columns = self.literal_operand(definition, 1)?;
+                Ok(self.size(self.id_operand(definition, 0)?)? * columns)
+            },
+            op => Err(format!("No size known for {:?}", op))
+        }
+    }
+
+    // Of a variable's pointee, with the array around it peeled off
+    fn descriptor(&self, storage_class: StorageClass, type_id: u32) -> Result<(vk::DescriptorType, u32), String> {
+        let definition = self.definition(type_id)?;
+        match definition.class.opcode {
+            Op::TypeArray => {
+                let (descriptor_type, _) = self.descriptor(storage_class, self.id_operand(definition, 0)?)?;
+                Ok((descriptor_type, self.constant(self.id_operand(definition, 1)?)?))
+            },
+            Op::TypeRuntimeArray => Ok((self.descriptor(storage_class, self.id_operand(definition, 0)?)?.0, 0)),
+            Op::TypeStruct => match storage_class {
+                StorageClass::StorageBuffer => Ok((vk::DescriptorType::STORAGE_BUFFER, 1)),
+                _ if self.has_decoration(type_id, Decoration::BufferBlock) => {
+                    Ok((vk::DescriptorType::STORAGE_BUFFER, 1))
+                },
+                _ => Ok((vk::DescriptorType::UNIFORM_BUFFER, 1))
+            },
+            Op::TypeSampler => Ok((vk::DescriptorType::SAMPLER, 1)),
+            Op::TypeSampledImage => {
+                let image = self.definition(self.id_operand(definition, 0)?)?;
+                match image.operands.get(1) {
+                    Some(Operand::Dim(Dim::DimBuffer)) => Ok((vk::DescriptorType::UNIFORM_TEXEL_BUFFER, 1)),
+                    _ => Ok((vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 1))
+                }
+            },
+            Op::TypeImage => {
+                let buffer = matches!(definition.operands.get(1), Some(Operand::Dim(Dim::DimBuffer)));
+                let storage = self.literal_operand(definition, 5)? == 2; // Sampled is 1 for sampled images
+                match (definition.operands.get(1), buffer, storage) {
+                    (Some(Operand::Dim(Dim::DimSubpassData)), _, _) => Ok((vk::DescriptorType::INPUT_ATTACHMENT, 1)),
+                    (_, true, true) => Ok((vk::DescriptorType::STORAGE_TEXEL_BUFFER, 1)),
+                    (_, true, false) => Ok((vk::DescriptorType::UNIFORM_TEXEL_BUFFER, 1)),
+                    (_, false, true) => Ok((vk::DescriptorType::STORAGE_IMAGE, 1)),
+                    (_, false, false) => Ok((vk::DescriptorType::SAMPLED_IMAGE, 1))
+                }
+            },
+            Op::TypeAccelerationStructureKHR => Ok((vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, 1)),
+            op => Err(format!("No descriptor type known for {:?}", op))
+        }
+    }
+
+    // The vertex attribute format of a scalar or vector input
+    fn input_format(&self, type_id: u32) -> Result<vk::Format, String> {
+        let definition = self.definition(type_id)?;
+        let (component, count) = match definition.class.opcode {
+            Op::TypeVector => (self.definition(self.id_operand(definition, 0)?)?, self.literal_operand(definition, 1)?),
+            _ => (definition, 1)
+        };
+        let width = self.literal_operand(component, 0)?;
+        let class = match component.class.opcode {
+            Op::TypeFloat => 0,
+            Op::TypeInt if self.literal_operand(component, 1)? == 1 => 1,
+            Op::TypeInt => 2,
+            op => return Err(format!("Vertex inputs of {:?} aren't supported", op))
+        };
+        if width != 32 {
+            return Err(format!("Vertex inputs of {} bit components aren't supported", width));
+        }
+        let formats = [
+            [vk::Format::R32_SFLOAT, vk::Format::R32G32_SFLOAT, vk::Format::R32G32B32_SFLOAT,
+                vk::Format::R32G32B32A32_SFLOAT],
+            [vk::Format::R32_SINT, vk::Format::R32G32_SINT, vk::Format::R32G32B32_SINT, vk::Format::R32G32B32A32_SINT],
+            [vk::Format::R32_UINT, vk::Format::R32G32_UINT, vk::Format::R32G32B32_UINT, vk::Format::R32G32B32A32_UINT]
+        ];
+
+        Ok(formats[class][count as usize - 1])
+    }
+}
+
+fn stage_flags(model: ExecutionModel) -> vk::ShaderStageFlags {
+    match model {
+        ExecutionModel::Vertex => vk::ShaderStageFlags::VERTEX,
+        ExecutionModel::TessellationControl => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+        ExecutionModel::TessellationEvaluation => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+        ExecutionModel::Geometry => vk::ShaderStageFlags::GEOMETRY,
+        ExecutionModel::Fragment => vk::ShaderStageFlags::FRAGMENT,
+        ExecutionModel::GLCompute => vk::ShaderStageFlags::COMPUTE,
+        ExecutionModel::RayGenerationKHR => vk::ShaderStageFlags::RAYGEN_KHR,
+        ExecutionModel::IntersectionKHR => vk::ShaderStageFlags::INTERSECTION_KHR,
+        ExecutionModel::AnyHitKHR => vk::ShaderStageFlags::ANY_HIT_KHR,
+        ExecutionModel::ClosestHitKHR => vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+        ExecutionModel::MissKHR => vk::ShaderStageFlags::MISS_KHR,
+        ExecutionModel::CallableKHR => vk::ShaderStageFlags::CALLABLE_KHR,
+        ExecutionModel::TaskNV => vk::ShaderStageFlags::TASK_NV,
+        ExecutionModel::MeshNV => vk::ShaderStageFlags::MESH_NV,
+        _ => vk::ShaderStageFlags::empty()
+    }
+}
+
+impl ShaderReflection {
+    // code is a whole SPIR-V module with a single entry point
+    pub fn new(code: &[u8]) -> Result<ShaderReflection, String> {
+        let module = rspirv::dr::load_bytes(code).map_err(|e| format!("Invalid SPIR-V: {}", e))?;
+        let ids = Ids::new(&module);
+        let stage = match module.entry_points.first().and_then(|e| e.operands.first()) {
+            Some(Operand::ExecutionModel(model)) => stage_flags(*model),
+            _ => return Err(String::from("The module has no entry point"))
+        };
+
+        let mut bindings: Vec<ReflectedBinding> = Vec::new();
+        let mut push_constant_size = 0;
+        let mut inputs: Vec<(u32, vk::Format)> = Vec::new();
+        for variable in module.types_global_values.iter().filter(|i| i.class.opcode == Op::Variable) {
+            let (id, pointer) = match (variable.result_id, variable.result_type) {
+                (Some(id), Some(pointer)) => (id, ids.definition(pointer)?),
+                _ => continue
+            };
+            let storage_class = match variable.operands.first() {
+                Some(Operand::StorageClass(storage_class)) => *storage_class,
+                _ => continue
+            };
+            let pointee = ids.id_operand(pointer, 1)?;
+            match storage_class {
+                StorageClass::Uniform | StorageClass::UniformConstant | StorageClass::StorageBuffer => {
+                    let (set, binding) = match (ids.decoration(id, Decoration::DescriptorSet),
+                                                ids.decoration(id, Decoration::Binding)) {
+                        (Some(set), Some(binding)) => (set, binding),
+                        (None, Some(binding)) => (0, binding), // glslang leaves out set 0 at times
+                        _ => continue
+                    };
+                    let (descriptor_type, count) = ids.descriptor(storage_class, pointee)?;
+                    bindings.push(ReflectedBinding { set, binding, descriptor_type, count, stages: stage });
+                },
+                StorageClass::PushConstant => push_constant_size = push_constant_size.max(ids.size(pointee)?),
+                StorageClass::Input if stage == vk::ShaderStageFlags::VERTEX => {
+                    if let Some(location) = ids.decoration(id, Decoration::Location) {
+                        inputs.push((location, ids.input_format(pointee)?));
+                    }
+                },
+                _ => {}
+            }
+        }
+        bindings.sort_by_key(|b| (b.set, b.binding));
+        inputs.sort_by_key(|(location, _)| *location);
+
+        Ok(ShaderReflection {
+            stage,
+            bindings,
+            push_constant_size,
+            inputs
+        })
+    }
+
+    pub fn load(path: &str) -> Result<ShaderReflection, String> {
+        let code = fs::read(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+        ShaderReflection::new(code.as_slice()).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+// The stages of a pipeline together, what its hand written layouts can be derived from or checked against
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipelineReflection {
+    pub bindings: Vec<ReflectedBinding>, // Ordered by set and binding, with the stages of every shader using them
+    pub push_constant_stages: vk::ShaderStageFlags,
+    pub push_constant_size: u32,
+    pub vertex_inputs: Vec<(u32, vk::Format)>
+}
+
+fn is_integer_format(format: vk::Format) -> bool {
+    let name = format!("{:?}", format);
+    name.ends_with("_UINT") || name.ends_with("_SINT")
+}
+
+impl PipelineReflection {
+    // Fails when stages declare the same binding differently
+    pub fn new(shaders: &[ShaderReflection]) -> Result<PipelineReflection, String> {
+        let mut reflection = PipelineReflection::default();
+        for shader in shaders.iter() {
+            for binding in shader.bindings.iter() {
+                match reflection.bindings.iter_mut().find(|b| (b.set, b.binding) == (binding.set, binding.binding)) {
+                    Some(b) if (b.descriptor_type, b.count) != (binding.descriptor_type, binding.count) => {
+                        return Err(format!("Set {} binding {} is a {:?} of {} in one stage and a {:?} of {} in \
+                                            another", binding.set, binding.binding, b.descriptor_type, b.count,
+                                           binding.descriptor_type, binding.count));
+                    },
+                    Some(b) => b.stages |= binding.stages,
+                    None => reflection.bindings.push(*binding)
+                }
+            }
+            if shader.push_constant_size > 0 {
+                reflection.push_constant_stages |= shader.stage;
+                reflection.push_constant_size = reflection.push_constant_size.max(shader.push_constant_size);
+            }
+            if shader.stage == vk::ShaderStageFlags::VERTEX {
+                reflection.vertex_inputs = shader.inputs.clone();
+            }
+        }
+        reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+
+        Ok(reflection)
+    }
+
+    pub fn load(shader_paths: &[&str]) -> Result<PipelineReflection, String> {
+        let shaders = shader_paths.iter()
+            .map(|p| ShaderReflection::load(p))
+            .collect::<Result<Vec<ShaderReflection>, String>>()?;
+        PipelineReflection::new(shaders.as_slice()).map_err(|e| format!("{}: {}", shader_paths.join(", "), e))
+    }
+
+    // Sets without bindings in between the used ones still count
+    pub fn set_count(&self) -> u32 {
+        self.bindings.iter().map(|b| b.set + 1).max().unwrap_or(0)
+    }
+
+    pub fn set_layout_bindings(&self, set: u32) -> Vec<vk::DescriptorSetLayoutBinding<'static>> {
+        self.bindings.iter()
+            .filter(|b| b.set == set)
+            .map(|b| vk::DescriptorSetLayoutBinding::default()
+                .binding(b.binding)
+                .descriptor_type(b.descriptor_type)
+                .descriptor_count(b.count.max(1))
+                .stage_flags(b.stages))
+            .collect()
+    }
+
+    // One per set, for RasterPipeline::new_reflected and ComputePipeline::new_reflected. Runtime sized arrays get a
+    // single descriptor.
+    pub fn create_set_layouts(&self, core: &VkCore) -> Vec<vk::DescriptorSetLayout> {
+        (0..self.set_count())
+            .map(|set| {
+                let bindings = self.set_layout_bindings(set);
+                let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                    .bindings(bindings.as_slice());
+                unsafe { core.logical_device.create_descriptor_set_layout(&create_info, None).unwrap() }
+            })
+            .collect()
+    }
+
+    pub fn push_constant_range(&self) -> Option<vk::PushConstantRange> {
+        match self.push_constant_size {
+            0 => None,
+            size => Some(vk::PushConstantRange::default()
+                .offset(0)
+                .size(size)
+                .stage_flags(self.push_constant_stages))
+        }
+    }
+
+    // A single binding with the inputs in location order
+    pub fn vertex_layout(&self) -> VertexLayout {
+        let attributes: Vec<VertexAttribute> = self.vertex_inputs.iter()
+            .map(|&(location, format)| VertexAttribute::Custom { location, format })
+            .collect();
+        VertexLayout::from_attributes(attributes.as_slice())
+    }
+
+    // Every binding the shaders use has to be in bindings, as the same type, with at least as many descriptors and
+    // visible to the stages using it
+    pub fn check_set_layout(&self, set: u32, bindings: &[vk::DescriptorSetLayoutBinding]) -> Result<(), String> {
+        for reflected in self.bindings.iter().filter(|b| b.set == set) {
+            let binding = bindings.iter()
+                .find(|b| b.binding == reflected.binding)
+                .ok_or_else(|| format!("Set {} has no binding {}", set, reflected.binding))?;
+            // SPIR-V doesn't tell dynamic buffers apart, the layout decides
+            let descriptor_type = match binding.descriptor_type {
+                vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC => vk::DescriptorType::UNIFORM_BUFFER,
+                vk::DescriptorType::STORAGE_BUFFER_DYNAMIC => vk::DescriptorType::STORAGE_BUFFER,
+                other => other
+            };
+            if descriptor_type != reflected.descriptor_type {
+                return Err(format!("Set {} binding {} is a {:?}, the shaders use a {:?}", set, reflected.binding,
+                                   binding.descriptor_type, reflected.descriptor_type));
+            }
+            if binding.descriptor_count < reflected.count {
+                return Err(format!("Set {} binding {} has {} descriptors, the shaders use {}", set, reflected.binding,
+                                   binding.descriptor_count, reflected.count));
+            }
+            if !binding.stage_flags.contains(reflected.stages) {
+                return Err(format!("Set {} binding {} is visible to {:?}, the shaders use it in {:?}", set,
+                                   reflected.binding, binding.stage_flags, reflected.stages));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_push_constants(&self, stages: vk::ShaderStageFlags, size: u32) -> Result<(), String> {
+        if self.push_constant_size > size {
+            return Err(format!("{} bytes of push constants, the shaders use {}", size, self.push_constant_size));
+        }
+        if !stages.contains(self.push_constant_stages) {
+            return Err(format!("Push constants are visible to {:?}, the shaders use them in {:?}", stages,
+                               self.push_constant_stages));
+        }
+
+        Ok(())
+    }
+
+    // Every vertex shader input needs an attribute, integer inputs integer formats and float inputs the rest
+    pub fn check_vertex_layout(&self, layout: &VertexLayout) -> Result<(), String> {
+        let attributes = layout.attribute_descriptions();
+        for &(location, format) in self.vertex_inputs.iter() {
+            let attribute = attributes.iter()
+                .find(|a| a.location == location)
+                .ok_or_else(|| format!("No vertex attribute at location {}", location))?;
+            if is_integer_format(attribute.format) != is_integer_format(format) {
+                return Err(format!("The vertex attribute at location {} is {:?}, the shader reads {:?}", location,
+                                   attribute.format, format));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPV_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../shaders/spv/");
+
+    fn reflect(shader: &str) -> ShaderReflection {
+        ShaderReflection::load((String::from(SPV_DIR) + shader).as_str()).unwrap()
+    }
+
+    #[test]
+    fn default_shaders_match_their_layouts() {
+        let vert = reflect("vert.spv");
+        assert_eq!(vert.stage, vk::ShaderStageFlags::VERTEX);
+        assert_eq!(vert.inputs, vec![(0, vk::Format::R32G32B32_SFLOAT), (1, vk::Format::R32G32B32_SFLOAT),
+                                     (2, vk::Format::R32G32_SFLOAT)]);
+
+        let reflection = PipelineReflection::new(&[vert, reflect("frag.spv")]).unwrap();
+        let uniform = reflection.bindings.iter().find(|b| (b.set, b.binding) == (0, 0)).unwrap();
+        assert_eq!(uniform.descriptor_type, vk::DescriptorType::UNIFORM_BUFFER);
+        let sampler = reflection.bindings.iter().find(|b| (b.set, b.binding) == (0, 1)).unwrap();
+        assert_eq!(sampler.descriptor_type, vk::DescriptorType::COMBINED_IMAGE_SAMPLER);
+        assert_eq!(sampler.stages, vk::ShaderStageFlags::FRAGMENT);
+        assert!(reflection.check_vertex_layout(&VertexLayout::vertex()).is_ok());
+        assert!(reflection.check_vertex_layout(&VertexLayout::new()).is_err());
+        assert!(reflection.check_set_layout(0, reflection.set_layout_bindings(0).as_slice()).is_ok());
+        assert!(reflection.check_set_layout(0, &reflection.set_layout_bindings(0)[1..]).is_err());
+    }
+
+    #[test]
+    fn ray_tracing_shaders_have_their_stages() {
+        assert_eq!(reflect("rgen.spv").stage, vk::ShaderStageFlags::RAYGEN_KHR);
+        assert_eq!(reflect("rmiss.spv").stage, vk::ShaderStageFlags::MISS_KHR);
+        assert_eq!(reflect("rchit.spv").stage, vk::ShaderStageFlags::CLOSEST_HIT_KHR);
+        let rgen = reflect("rgen.spv");
+        assert!(rgen.bindings.iter().any(|b| b.descriptor_type == vk::DescriptorType::ACCELERATION_STRUCTURE_KHR));
+    }
+}
@@ -1,15 +1,33 @@
 use ash::vk;
 
+use crate::depth::has_stencil;
 use crate::render_target::RenderTarget;
 use crate::vkcore::VkCore;
 
 pub fn setup_render_pass(core: &VkCore, render_target: &RenderTarget,
                          depth_format: vk::Format, samples: vk::SampleCountFlags) -> vk::RenderPass {
+    build_render_pass(core, render_target, depth_format, samples, vk::AttachmentStoreOp::DONT_CARE,
+                      vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+}
+
+// Keeps the depth buffer after the pass and leaves it readable by shaders, for depth buffers made with
+// Depth::new_sampled
+pub fn setup_render_pass_stored_depth(core: &VkCore, render_target: &RenderTarget,
+                                      depth_format: vk::Format, samples: vk::SampleCountFlags) -> vk::RenderPass {
+    build_render_pass(core, render_target, depth_format, samples, vk::AttachmentStoreOp::STORE,
+                      vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+}
+
+fn build_render_pass(core: &VkCore, render_target: &RenderTarget, depth_format: vk::Format,
+                     samples: vk::SampleCountFlags, depth_store_op: vk::AttachmentStoreOp,
+                     depth_final_layout: vk::ImageLayout) -> vk::RenderPass {
     let attachment_desc = vk::AttachmentDescription::default() // Color attachment
         .format(render_target.surface_format) // Should match the format of swap chain images
         .samples(samples)
         .load_op(vk::AttachmentLoadOp::CLEAR) // What to do with pre existing data in the attachment before rendering
-        .store_op(vk::AttachmentStoreOp::STORE) // What to do with data in attachment after rendering
+        // What to do with data in attachment after rendering. Only the resolved image is kept, so the samples can
+        // stay in tile memory, see Color.
+        .store_op(vk::AttachmentStoreOp::DONT_CARE)
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE) // Not sure what stencil buffer is
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED) // image layout pre render
@@ -25,15 +43,20 @@ pub fn setup_render_pass(core: &VkCore, render_target: &RenderTarget,
         .initial_layout(vk::ImageLayout::UNDEFINED) // image layout pre render
         .final_layout(vk::ImageLayout::PRESENT_SRC_KHR); // Ready for presentation, not sure how that maps to a layout
 
+    // The stencil is cleared and kept like the depth when the format has one
+    let (stencil_load_op, stencil_store_op) = match has_stencil(depth_format) {
+        true => (vk::AttachmentLoadOp::CLEAR, depth_store_op),
+        false => (vk::AttachmentLoadOp::DONT_CARE, vk::AttachmentStoreOp::DONT_CARE)
+    };
     let depth_attachment_desc = vk::AttachmentDescription::default()
         .format(depth_format)
         .samples(samples)
         .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::DONT_CARE)
-        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .store_op(depth_store_op)
+        .stencil_load_op(stencil_load_op)
+        .stencil_store_op(stencil_store_op)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+        .final_layout(depth_final_layout);
 
     let attachment_desc_array = [attachment_desc, depth_attachment_desc, color_attachment_desc];
     let attachment_ref = vk::AttachmentReference::default()
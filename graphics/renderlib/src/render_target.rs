@@ -1,25 +1,56 @@
 use num::clamp;
 
 use ash::{vk};
+use ash::extensions::ext::FullScreenExclusive;
 use ash::extensions::khr::Swapchain;
+use ash::prelude::VkResult;
 use ash::vk::ImageView;
 
+use tracing::{debug, warn};
+use winit::event::WindowEvent;
 use winit::window::Window;
 
+#[cfg(windows)]
+use crate::display_mode::current_hmonitor;
+use crate::display_mode::DisplayMode;
+use crate::display_timing::WindowSystem;
 use crate::image::create_image_view;
+use crate::transparency::format_has_alpha;
 use crate::vkcore::VkCore;
 
 pub struct RenderTarget {
     pub swap_loader: Swapchain,
     pub swap_chain: vk::SwapchainKHR,
     pub surface_format: vk::Format,
+    pub composite_alpha: vk::CompositeAlphaFlagsKHR, // Anything but OPAQUE blends with the desktop
     pub extent: vk::Extent2D,
-    pub(crate) image_views: Vec<vk::ImageView>,
+    pub image_views: Vec<vk::ImageView>,
+    pub shared_families: Vec<u32>, // The queue families the images are CONCURRENT between, empty when EXCLUSIVE
+    full_screen_exclusive: Option<FullScreenExclusive> // Set while exclusive mode is acquired
 }
 
 impl RenderTarget {
     pub fn new(core: &VkCore, image_usage: vk::ImageUsageFlags, color_format: vk::Format,
                color_space: Option<vk::ColorSpaceKHR>) -> RenderTarget {
+        RenderTarget::new_with_present_mode(core, image_usage, color_format, color_space,
+                                            vk::PresentModeKHR::MAILBOX)
+    }
+
+    // IMMEDIATE disables vsync entirely, which benchmarks need to measure more frames than the display refresh rate.
+    // Falls back to FIFO when the preferred mode is not supported, since FIFO is always available.
+    pub fn new_with_present_mode(core: &VkCore, image_usage: vk::ImageUsageFlags, color_format: vk::Format,
+                                 color_space: Option<vk::ColorSpaceKHR>,
+                                 preferred_present_mode: vk::PresentModeKHR) -> RenderTarget {
+        RenderTarget::new_with_display_mode(core, image_usage, color_format, color_space, preferred_present_mode,
+                                            DisplayMode::Windowed, false)
+    }
+
+    // The window has to be set up for display_mode already, see DisplayMode::apply. Exclusive mode is acquired
+    // here, if the driver refuses it the swap chain still presents like borderless fullscreen. transfer_reads shares
+    // the images with the transfer queue, for a running FrameCapture or FrameShare that copies every frame.
+    pub fn new_with_display_mode(core: &VkCore, image_usage: vk::ImageUsageFlags, color_format: vk::Format,
+                                 color_space: Option<vk::ColorSpaceKHR>, preferred_present_mode: vk::PresentModeKHR,
+                                 display_mode: DisplayMode, transfer_reads: bool) -> RenderTarget {
         fn choose_swap_extent(window: &Window, capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::Extent2D {
             if capabilities.current_extent.width != u32::MAX {
                 capabilities.current_extent
@@ -72,15 +103,19 @@ impl RenderTarget {
                 Some(x) => x,
                 None => &core.supported_surface_formats[0]
             };
+        // A transparent window needs the alpha channel to reach the compositor
+        let surface_format = match core.transparency.is_transparent() && !format_has_alpha(surface_format.format) {
+            true => core.supported_surface_formats.iter()
+                .find(|f| format_has_alpha(f.format))
+                .unwrap_or(surface_format),
+            false => surface_format
+        };
+        let composite_alpha = core.transparency.composite_alpha(&capabilities);
 
-        let presentation_mode =
-            match core.present_modes
-                .iter()
-                .find(|p|**p == vk::PresentModeKHR::MAILBOX)
-            {
-                Some(x) => *x,
-                None => vk::PresentModeKHR::FIFO
-            };
+        let presentation_mode = WindowSystem::detect(&core.window).present_mode_fallbacks(preferred_present_mode)
+            .into_iter()
+            .find(|p| core.present_modes.contains(p))
+            .unwrap_or(vk::PresentModeKHR::FIFO);
 
         let extent = choose_swap_extent(&core.window, &capabilities);
 
@@ -98,14 +133,21 @@ impl RenderTarget {
             .surface(core.surface)
             .image_usage(image_usage)
             .pre_transform(capabilities.current_transform)
-            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+            .composite_alpha(composite_alpha)
             .present_mode(presentation_mode)
             .clipped(true)
             .old_swapchain(vk::SwapchainKHR::null());
 
-        let family_indices;
-        if core.graphics_family_index != core.present_family_index {
-            family_indices = [core.graphics_family_index, core.present_family_index];
+        // EXCLUSIVE images keep their compression, a FrameCapture moves single frames to the transfer queue with
+        // ownership transfers. Images that are CONCURRENT for presentation anyway are shared with it too.
+        let mut family_indices = vec![core.graphics_family_index];
+        if core.present_family_index != core.graphics_family_index {
+            family_indices.push(core.present_family_index);
+        }
+        if (transfer_reads || family_indices.len() > 1) && !family_indices.contains(&core.transfer_family_index) {
+            family_indices.push(core.transfer_family_index);
+        }
+        if family_indices.len() > 1 {
             swap_create_info = swap_create_info
                 .image_sharing_mode(vk::SharingMode::CONCURRENT)
                 .queue_family_indices(&family_indices);
@@ -114,6 +156,32 @@ impl RenderTarget {
             swap_create_info = swap_create_info
                 .image_sharing_mode(vk::SharingMode::EXCLUSIVE);
         }
+        let shared_families = match family_indices.len() > 1 {
+            true => family_indices.clone(),
+            false => Vec::new()
+        };
+
+        let exclusive = display_mode.supported(core) == DisplayMode::Exclusive;
+        let mut exclusive_info = vk::SurfaceFullScreenExclusiveInfoEXT::default()
+            .full_screen_exclusive(vk::FullScreenExclusiveEXT::APPLICATION_CONTROLLED);
+        #[cfg(windows)]
+        let mut exclusive_win32_info = vk::SurfaceFullScreenExclusiveWin32InfoEXT::default()
+            .hmonitor(current_hmonitor(core));
+        if exclusive {
+            swap_create_info = swap_create_info.push_next(&mut exclusive_info);
+            #[cfg(windows)]
+            {
+                swap_create_info = swap_create_info.push_next(&mut exclusive_win32_info);
+            }
+        }
+
+        // Which GPUs present the images, see DeviceGroup
+        #[cfg(feature = "device-group")]
+        let mut group_info = core.device_group.as_ref().map(|g| g.swapchain_create_info());
+        #[cfg(feature = "device-group")]
+        if let Some(group_info) = group_info.as_mut() {
+            swap_create_info = swap_create_info.push_next(group_info);
+        }
 
         let swap_loader = Swapchain::new(&core.instance, &core.logical_device);
         let swap_chain: vk::SwapchainKHR;
@@ -121,6 +189,19 @@ impl RenderTarget {
             swap_chain = swap_loader
                 .create_swapchain(&swap_create_info, None).unwrap();
         }
+        let full_screen_exclusive = match exclusive {
+            true => {
+                let loader = FullScreenExclusive::new(&core.instance, &core.logical_device);
+                match unsafe { loader.acquire_full_screen_exclusive_mode(swap_chain) } {
+                    Ok(_) => Some(loader),
+                    Err(e) => {
+                        warn!("Full screen exclusive mode not granted, presenting borderless: {:?}", e);
+                        None
+                    }
+                }
+            },
+            false => None
+        };
         // Image views are only needed by the raster renderer
         let image_views = match image_usage & vk::ImageUsageFlags::COLOR_ATTACHMENT {
             vk::ImageUsageFlags::COLOR_ATTACHMENT => setup_image_views(core,
@@ -134,18 +215,106 @@ impl RenderTarget {
             swap_chain,
             swap_loader,
             surface_format: surface_format.format,
+            composite_alpha,
             extent,
-            image_views
+            image_views,
+            shared_families,
+            full_screen_exclusive
         }
     }
 
+    // Whether cleared pixels show the desktop behind the window, see WindowTransparency
+    pub fn is_transparent(&self) -> bool {
+        self.composite_alpha != vk::CompositeAlphaFlagsKHR::OPAQUE
+    }
+
     pub fn destroy(&self, core: &VkCore) {
         unsafe {
             for &v in self.image_views.iter() {
                 core.logical_device.destroy_image_view(v, None);
             }
 
+            if let Some(loader) = &self.full_screen_exclusive {
+                let _ = loader.release_full_screen_exclusive_mode(self.swap_chain);
+            }
             self.swap_loader.destroy_swapchain(self.swap_chain, None);
         }
     }
 }
+
+pub type SwapchainCallback = Box<dyn FnMut(&VkCore, &RenderTarget)>;
+
+// Collects the reasons to recreate the swap chain during a frame so the renderer can do it once, at the start of the
+// next frame. Callbacks run after every recreate so that anything sized to the swap chain (UI, post processing
+// targets) can follow.
+#[derive(Default)]
+pub struct SwapchainRecreate {
+    pending: bool,
+    callbacks: Vec<SwapchainCallback>
+}
+
+impl SwapchainRecreate {
+    pub fn request(&mut self) {
+        self.pending = true;
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    pub fn add_callback(&mut self, callback: SwapchainCallback) {
+        self.callbacks.push(callback);
+    }
+
+    // Resizes and DPI changes both change the surface extent
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::Resized(size) => {
+                debug!(width = size.width, height = size.height, "Window resized");
+                self.request();
+            },
+            WindowEvent::ScaleFactorChanged { scale_factor, new_inner_size } => {
+                debug!(scale_factor, width = new_inner_size.width, height = new_inner_size.height,
+                       "Window scale factor changed");
+                self.request();
+            },
+            _ => ()
+        }
+    }
+
+    // Returns the image index, or None when the frame has to be skipped. A suboptimal swap chain can still be
+    // presented to, so it only schedules a recreate.
+    pub fn check_acquire(&mut self, result: VkResult<(u32, bool)>) -> Option<u32> {
+        match result {
+            Ok((image_index, suboptimal)) => {
+                if suboptimal {
+                    self.request();
+                }
+                Some(image_index)
+            },
+            // Exclusive mode is lost when the user alt-tabs away, recreating acquires it again
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) | Err(vk::Result::ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT) => {
+                self.request();
+                None
+            },
+            Err(e) => panic!("acquire_next_image failed: {:?}", e)
+        }
+    }
+
+    pub fn check_present(&mut self, result: VkResult<bool>) {
+        match result {
+            Ok(false) => (),
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) |
+            Err(vk::Result::ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT) => self.request(),
+            Err(e) => panic!("queue_present failed: {:?}", e)
+        }
+    }
+
+    // Call once the new swap chain exists
+    pub fn finish(&mut self, core: &VkCore, render_target: &RenderTarget) {
+        self.pending = false;
+        for callback in self.callbacks.iter_mut() {
+            callback(core, render_target);
+        }
+    }
+}
@@ -1,8 +1,55 @@
+use std::collections::HashMap;
+
 use ash::vk;
 use crate::vkcore::VkCore;
 
+// Global texture filtering settings, shared by every sampler handed out by a SamplerCache
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TextureSettings {
+    pub max_anisotropy: f32, // 1.0 or lower disables anisotropic filtering
+    pub trilinear: bool, // Blend between mip levels, nearest mip level otherwise
+    pub lod_bias: f32, // Added to the computed mip level, negative values sharpen
+    pub max_mip_level: Option<u32> // Clamp on the most detailed mip level, None samples the full chain
+}
+
+impl Default for TextureSettings {
+    fn default() -> TextureSettings {
+        TextureSettings {
+            max_anisotropy: 16.0,
+            trilinear: true,
+            lod_bias: 0.0,
+            max_mip_level: None
+        }
+    }
+}
+
+impl TextureSettings {
+    // Clamps the settings to what the device supports
+    pub fn validated(&self, limits: &vk::PhysicalDeviceLimits) -> TextureSettings {
+        TextureSettings {
+            max_anisotropy: self.max_anisotropy.clamp(1.0, limits.max_sampler_anisotropy),
+            trilinear: self.trilinear,
+            lod_bias: self.lod_bias.clamp(-limits.max_sampler_lod_bias, limits.max_sampler_lod_bias),
+            max_mip_level: self.max_mip_level
+        }
+    }
+}
+
 pub fn create_sampler(core: &VkCore, mip_levels: u32) -> vk::Sampler {
     let properties = unsafe { core.instance.get_physical_device_properties(core.physical_device) };
+    create_sampler_with_settings(core, mip_levels, &TextureSettings::default().validated(&properties.limits))
+}
+
+// settings must already be validated against the device limits
+pub fn create_sampler_with_settings(core: &VkCore, mip_levels: u32, settings: &TextureSettings) -> vk::Sampler {
+    let mipmap_mode = match settings.trilinear {
+        true => vk::SamplerMipmapMode::LINEAR,
+        false => vk::SamplerMipmapMode::NEAREST
+    };
+    let min_lod = match settings.max_mip_level {
+        Some(level) => level.min(mip_levels.saturating_sub(1)) as f32,
+        None => 0.0
+    };
 
     let sampler_create_info = vk::SamplerCreateInfo::default()
         .mag_filter(vk::Filter::LINEAR) // How to interpolate magnified or minified texels
@@ -10,22 +57,131 @@ pub fn create_sampler(core: &VkCore, mip_levels: u32) -> vk::Sampler {
         .address_mode_u(vk::SamplerAddressMode::REPEAT) // How to extend the texture beyond the reference image
         .address_mode_v(vk::SamplerAddressMode::REPEAT)
         .address_mode_w(vk::SamplerAddressMode::REPEAT)
-        .anisotropy_enable(true) // Enable texture up/down sampling
-        .max_anisotropy(properties.limits.max_sampler_anisotropy)
+        .anisotropy_enable(settings.max_anisotropy > 1.0) // Enable texture up/down sampling
+        .max_anisotropy(settings.max_anisotropy)
         .border_color(vk::BorderColor::INT_OPAQUE_BLACK) // What color to paint areas not covered by the texture
         .unnormalized_coordinates(false) // true - coordinates are [0, texture extent], false - coordinates are [0, 1]
         .compare_enable(false)
         .compare_op(vk::CompareOp::ALWAYS)
-        .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
-        .mip_lod_bias(0.0)
-        .min_lod(0.0)
+        .mipmap_mode(mipmap_mode)
+        .mip_lod_bias(settings.lod_bias)
+        .min_lod(min_lod)
         .max_lod(mip_levels as f32);
 
     unsafe { core.logical_device.create_sampler(&sampler_create_info, None)
         .unwrap() }
 }
 
+// For texelFetch and exact texel reads, every mip level
+pub fn create_nearest_sampler(core: &VkCore) -> vk::Sampler {
+    let sampler_create_info = vk::SamplerCreateInfo::default()
+        .mag_filter(vk::Filter::NEAREST)
+        .min_filter(vk::Filter::NEAREST)
+        .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+        .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .min_lod(0.0)
+        .max_lod(vk::LOD_CLAMP_NONE);
+
+    unsafe { core.logical_device.create_sampler(&sampler_create_info, None).unwrap() }
+}
+
+// Bilinear without mip levels, for render targets sampled at a different size
+pub fn create_linear_sampler(core: &VkCore) -> vk::Sampler {
+    let sampler_create_info = vk::SamplerCreateInfo::default()
+        .mag_filter(vk::Filter::LINEAR)
+        .min_filter(vk::Filter::LINEAR)
+        .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+        .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .min_lod(0.0)
+        .max_lod(0.0);
+
+    unsafe { core.logical_device.create_sampler(&sampler_create_info, None).unwrap() }
+}
+
 pub fn destroy_sampler(core: &VkCore, sampler: vk::Sampler) {
     unsafe { core.logical_device.destroy_sampler(sampler, None); }
 }
 
+// Hands out one sampler per mip level count. Changing the settings retires every cached sampler, new ones are only
+// created when they are next requested. Retired samplers may still be referenced by frames in flight, so they are
+// destroyed by end_frame once max_frames frames have passed.
+pub struct SamplerCache {
+    settings: TextureSettings,
+    limits: vk::PhysicalDeviceLimits,
+    generation: u64,
+    samplers: HashMap<u32, vk::Sampler>,
+    retired: Vec<(vk::Sampler, usize)>, // Sampler and the number of frames left until it can be destroyed
+    max_frames: usize
+}
+
+impl SamplerCache {
+    pub fn new(core: &VkCore, settings: TextureSettings, max_frames: usize) -> SamplerCache {
+        let properties = unsafe { core.instance.get_physical_device_properties(core.physical_device) };
+
+        SamplerCache {
+            settings: settings.validated(&properties.limits),
+            limits: properties.limits,
+            generation: 0,
+            samplers: HashMap::new(),
+            retired: Vec::new(),
+            max_frames
+        }
+    }
+
+    // The settings actually in use after validation
+    pub fn settings(&self) -> TextureSettings {
+        self.settings
+    }
+
+    // Incremented on every settings change. Descriptor sets written with an older generation's samplers should be
+    // rewritten.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // Returns true if the validated settings differ from the current ones
+    pub fn set_settings(&mut self, settings: TextureSettings) -> bool {
+        let validated = settings.validated(&self.limits);
+        if validated == self.settings {
+            return false;
+        }
+
+        self.settings = validated;
+        self.generation += 1;
+        for (_, s) in self.samplers.drain() {
+            self.retired.push((s, self.max_frames));
+        }
+
+        true
+    }
+
+    pub fn get(&mut self, core: &VkCore, mip_levels: u32) -> vk::Sampler {
+        let settings = self.settings;
+        *self.samplers.entry(mip_levels)
+            .or_insert_with(|| create_sampler_with_settings(core, mip_levels, &settings))
+    }
+
+    // Call once per submitted frame
+    pub fn end_frame(&mut self, core: &VkCore) {
+        for r in self.retired.iter_mut() {
+            r.1 = r.1.saturating_sub(1);
+            if r.1 == 0 {
+                destroy_sampler(core, r.0);
+            }
+        }
+        self.retired.retain(|r| r.1 > 0);
+    }
+
+    pub fn destroy(&mut self, core: &VkCore) {
+        for (_, s) in self.samplers.drain() {
+            destroy_sampler(core, s);
+        }
+        for (s, _) in self.retired.drain(..) {
+            destroy_sampler(core, s);
+        }
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs;
+use std::path::Path;
+
+use ash::vk;
+use cgmath::{Angle, Deg, InnerSpace, Matrix4, ortho, perspective, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::collision::BoundingSphere;
+
+// Scene description shared by both renderers, stored as RON (.ron) or JSON (.json). Vectors are plain arrays so the
+// files stay readable, for example in RON
+//     (camera: (eye: (2.0, 2.0, 2.0), target: (0.0, 0.0, 0.0)),
+//      models: [(name: "room", path: "graphics/models/viking_room.obj", material: Some("room"))],
+//      materials: [(name: "room", texture: Some("graphics/textures/viking_room.png"))],
+//      lights: [Directional(direction: (-1.0, -1.0, -1.0))],
+//      terrain: Some((heightmap: "graphics/textures/heightmap.png", size: 64.0)),
+//      water: Some((height: 1.5)))
+// Fields that are left out take their defaults.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    pub camera: SceneCamera,
+    #[serde(default)]
+    pub models: Vec<SceneModel>,
+    #[serde(default)]
+    pub materials: Vec<Material>,
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    #[serde(default)]
+    pub terrain: Option<SceneTerrain>,
+    #[serde(default)]
+    pub water: Option<SceneWater>
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneCamera {
+    pub eye: [f32; 3],
+    pub target: [f32; 3],
+    #[serde(default = "default_up")]
+    pub up: [f32; 3],
+    #[serde(default)]
+    pub projection: CameraProjection,
+    #[serde(default)]
+    pub focus_distance: Option<f32>, // Distance to the plane in focus, the target's when left out
+    #[serde(default)]
+    pub aperture: f32 // Lens diameter in world units, 0 is a pinhole camera without depth of field
+}
+
+// Orthographic views keep sizes independent of the distance, for example for top down maps. Their height is the world
+// units they span vertically, the width follows the aspect ratio. Water, SSAO and depth of field reconstruct view
+// depth from the depth buffer as if it was perspective, they are off in orthographic views.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CameraProjection {
+    Perspective { fov_y_degrees: f32, near: f32, far: f32 },
+    Orthographic { height: f32, near: f32, far: f32 }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneModel {
+    pub name: String,
+    pub path: String, // OBJ file
+    #[serde(default)]
+    pub material: Option<String>, // Name of an entry in Scene::materials
+    #[serde(default)]
+    pub transform: Transform
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Transform {
+    #[serde(default)]
+    pub translation: [f32; 3],
+    #[serde(default)]
+    pub rotation_degrees: [f32; 3], // Euler angles, applied in x, y, z order
+    #[serde(default = "default_scale")]
+    pub scale: [f32; 3]
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Material {
+    pub name: String,
+    #[serde(default = "default_color")]
+    pub base_color: [f32; 4], // Linear RGBA, multiplied with the texture
+    #[serde(default)]
+    pub texture: Option<String>,
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+    #[serde(default)]
+    pub emissive: [f32; 3], // Linear RGB radiance
+    #[serde(default = "default_emissive_strength")]
+    pub emissive_strength: f32 // Scales emissive, so that colors can stay in 0..1
+}
+
+// Heightmap terrain centered on the origin, in the XY plane with heights along Z, see renderlib::terrain
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneTerrain {
+    pub heightmap: String, // 16-bit grayscale PNG, or raw little endian 16-bit samples (.raw, .r16) of a square map
+    #[serde(default = "default_terrain_size")]
+    pub size: f32, // Of a side, in world units
+    #[serde(default = "default_height_scale")]
+    pub height_scale: f32, // Height of the largest sample
+    #[serde(default)]
+    pub splat_map: Option<String>, // RGBA weights of the layers, only the first layer without one
+    #[serde(default)]
+    pub layers: Vec<String>, // Up to four textures, flat colors stand in for the missing ones
+    #[serde(default = "default_layer_tiling")]
+    pub layer_tiling: f32 // Times the layers repeat across the terrain
+}
+
+// Animated water plane centered on the origin at a fixed height along Z, see renderlib::water
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneWater {
+    #[serde(default)]
+    pub height: f32, // Of the surface at rest
+    #[serde(default = "default_water_size")]
+    pub size: f32, // Of a side, in world units
+    #[serde(default = "default_water_color")]
+    pub color: [f32; 3], // Linear color of deep water
+    #[serde(default = "default_clarity")]
+    pub clarity: f32, // Depth below the surface at which the bottom has faded into the water's color
+    #[serde(default = "default_waves")]
+    pub waves: Vec<Wave> // Up to four, summed
+}
+
+// Gerstner wave, crests move at the speed of deep water waves of their length
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Wave {
+    pub direction: [f32; 2], // Of travel in the XY plane, normalized when the water is created
+    pub wavelength: f32,
+    pub amplitude: f32, // Height of the crests above the rest height
+    #[serde(default)]
+    pub steepness: f32 // 0 for a sine wave, towards 1 for sharp crests. Should sum to 1 at most over all waves.
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Light {
+    Directional {
+        direction: [f32; 3], // Direction the light travels in
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f32
+    },
+    Point {
+        position: [f32; 3],
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        #[serde(default = "default_intensity")]
+        intensity: f32,
+        #[serde(default = "default_range")]
+        range: f32,
+        #[serde(default)]
+        cast_shadows: bool, // Given a cube of PointShadows while it is among the closest casting lights
+        #[serde(default)]
+        radius: f32, // The path tracer treats it as a sphere of this radius, with soft shadows
+        #[serde(default = "default_shadow_samples")]
+        shadow_samples: u32 // Shadow rays towards the sphere per path tracing sample
+    }
+}
+
+fn default_up() -> [f32; 3] { [0.0, 0.0, 1.0] }
+fn default_scale() -> [f32; 3] { [1.0, 1.0, 1.0] }
+fn default_color() -> [f32; 4] { [1.0, 1.0, 1.0, 1.0] }
+fn default_roughness() -> f32 { 1.0 }
+fn default_emissive_strength() -> f32 { 1.0 }
+fn default_light_color() -> [f32; 3] { [1.0, 1.0, 1.0] }
+fn default_intensity() -> f32 { 1.0 }
+fn default_range() -> f32 { 10.0 }
+fn default_shadow_samples() -> u32 { 1 }
+fn default_terrain_size() -> f32 { 64.0 }
+fn default_height_scale() -> f32 { 8.0 }
+fn default_layer_tiling() -> f32 { 16.0 }
+fn default_water_size() -> f32 { 64.0 }
+fn default_water_color() -> [f32; 3] { [0.02, 0.08, 0.1] }
+fn default_clarity() -> f32 { 2.0 }
+fn default_waves() -> Vec<Wave> {
+    vec![Wave { direction: [1.0, 0.2], wavelength: 8.0, amplitude: 0.1, steepness: 0.4 },
+         Wave { direction: [0.3, 1.0], wavelength: 3.1, amplitude: 0.04, steepness: 0.3 },
+         Wave { direction: [-0.7, 0.6], wavelength: 1.3, amplitude: 0.015, steepness: 0.2 }]
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform {
+            translation: [0.0, 0.0, 0.0],
+            rotation_degrees: [0.0, 0.0, 0.0],
+            scale: default_scale()
+        }
+    }
+}
+
+impl Transform {
+    pub fn matrix(&self) -> Matrix4<f32> {
+        let [rx, ry, rz] = self.rotation_degrees;
+        Matrix4::from_translation(Vector3::from(self.translation)) *
+            Matrix4::from_angle_z(Deg(rz)) * Matrix4::from_angle_y(Deg(ry)) * Matrix4::from_angle_x(Deg(rx)) *
+            Matrix4::from_nonuniform_scale(self.scale[0], self.scale[1], self.scale[2])
+    }
+}
+
+impl Material {
+    // Plain material that only uses a texture
+    pub fn textured(name: &str, texture: &str) -> Material {
+        Material {
+            name: String::from(name),
+            base_color: default_color(),
+            texture: Some(String::from(texture)),
+            metallic: 0.0,
+            roughness: default_roughness(),
+            emissive: [0.0, 0.0, 0.0],
+            emissive_strength: default_emissive_strength()
+        }
+    }
+
+    // Emitted radiance, emissive scaled by emissive_strength
+    pub fn emission(&self) -> [f32; 3] {
+        self.emissive.map(|c| c * self.emissive_strength)
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission().iter().any(|c| *c > 0.0)
+    }
+}
+
+impl Default for CameraProjection {
+    fn default() -> CameraProjection {
+        CameraProjection::Perspective { fov_y_degrees: 45.0, near: 0.1, far: 10.0 }
+    }
+}
+
+impl CameraProjection {
+    // Y is flipped for Vulkan's clip space, like UniformBuffer::build_transforms
+    pub fn matrix(&self, extent: vk::Extent2D) -> Matrix4<f32> {
+        let aspect = extent.width as f32 / extent.height as f32;
+        let mut projection = match *self {
+            CameraProjection::Perspective { fov_y_degrees, near, far } =>
+                perspective(Deg(fov_y_degrees), aspect, near, far),
+            CameraProjection::Orthographic { height, near, far } => {
+                let (half_width, half_height) = (height * aspect / 2.0, height / 2.0);
+                ortho(-half_width, half_width, -half_height, half_height, near, far)
+            }
+        };
+        projection.y.y *= -1.0;
+        projection
+    }
+
+    pub fn near(&self) -> f32 {
+        match *self {
+            CameraProjection::Perspective { near, .. } | CameraProjection::Orthographic { near, .. } => near
+        }
+    }
+
+    pub fn far(&self) -> f32 {
+        match *self {
+            CameraProjection::Perspective { far, .. } | CameraProjection::Orthographic { far, .. } => far
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            CameraProjection::Perspective { fov_y_degrees, .. } if !(fov_y_degrees > 0.0 && fov_y_degrees < 180.0) =>
+                Err(format!("the field of view must be between 0 and 180 degrees, not {}", fov_y_degrees)),
+            CameraProjection::Perspective { near, .. } if near <= 0.0 =>
+                Err(format!("a perspective near plane must be in front of the camera, not at {}", near)),
+            CameraProjection::Orthographic { height, .. } if height <= 0.0 =>
+                Err(format!("an orthographic view must have a positive height, not {}", height)),
+            _ if self.far() <= self.near() =>
+                Err(format!("the far plane at {} must be beyond the near plane at {}", self.far(), self.near())),
+            _ => Ok(())
+        }
+    }
+}
+
+impl SceneCamera {
+    pub fn eye(&self) -> Point3<f32> {
+        Point3::from(self.eye)
+    }
+
+    pub fn target(&self) -> Point3<f32> {
+        Point3::from(self.target)
+    }
+
+    pub fn focal_distance(&self) -> f32 {
+        self.focus_distance.unwrap_or_else(|| (self.target() - self.eye()).magnitude())
+    }
+
+    pub fn view(&self) -> Matrix4<f32> {
+        Matrix4::look_at_rh(self.eye(), self.target(), Vector3::from(self.up))
+    }
+
+    // See CameraProjection::matrix
+    pub fn projection(&self, extent: vk::Extent2D) -> Matrix4<f32> {
+        self.projection.matrix(extent)
+    }
+
+    // Looks at the sphere's center along the current view direction, from just far enough for all of it to be in view.
+    // Orthographic views move in front of it and take its height instead. margin is the space left around the sphere,
+    // which spans 1 / (1 + margin) of the narrower extent of the view. The far plane grows to hold the sphere and the
+    // focus moves to its center. An empty sphere leaves the camera as it is.
+    pub fn framing(&self, sphere: &BoundingSphere, extent: vk::Extent2D, margin: f32) -> SceneCamera {
+        if sphere.is_empty() {
+            return self.clone();
+        }
+        let sphere = BoundingSphere {
+            radius: sphere.radius * (1.0 + margin.max(0.0)),
+            ..*sphere
+        };
+        let direction = self.target() - self.eye();
+        let direction = match direction.magnitude2() > 0.0 {
+            true => direction.normalize(),
+            false => Vector3::unit_y()
+        };
+        // The narrower of the vertical and horizontal extent
+        let narrow_aspect = (extent.width as f32 / extent.height as f32).min(1.0);
+        let (distance, projection) = match self.projection {
+            CameraProjection::Perspective { fov_y_degrees, near, far } => {
+                let tan_half_fov = Deg(fov_y_degrees / 2.0).tan() * narrow_aspect;
+                let sin_half_fov = tan_half_fov / (1.0 + tan_half_fov * tan_half_fov).sqrt();
+                let distance = (sphere.radius / sin_half_fov).max(near + sphere.radius);
+                let far = far.max(distance + sphere.radius);
+                (distance, CameraProjection::Perspective { fov_y_degrees, near, far })
+            },
+            CameraProjection::Orthographic { near, far, .. } => {
+                let distance = near + sphere.radius;
+                (distance, CameraProjection::Orthographic {
+                    height: 2.0 * sphere.radius / narrow_aspect,
+                    near,
+                    far: far.max(distance + sphere.radius)
+                })
+            }
+        };
+
+        SceneCamera {
+            eye: (sphere.center - direction * distance).into(),
+            target: sphere.center.into(),
+            projection,
+            focus_distance: None,
+            ..self.clone()
+        }
+    }
+}
+
+enum SceneFormat {
+    Ron,
+    Json
+}
+
+fn scene_format(path: &str) -> Result<SceneFormat, String> {
+    match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("ron") => Ok(SceneFormat::Ron),
+        Some("json") => Ok(SceneFormat::Json),
+        _ => Err(format!("{}: scene files must end in .ron or .json", path))
+    }
+}
+
+impl Scene {
+    pub fn load(path: &str) -> Result<Scene, String> {
+        let contents = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let scene: Scene = match scene_format(path)? {
+            SceneFormat::Ron => ron::from_str(contents.as_str()).map_err(|e| format!("{}: {}", path, e))?,
+            SceneFormat::Json => serde_json::from_str(contents.as_str()).map_err(|e| format!("{}: {}", path, e))?
+        };
+        scene.validate().map_err(|e| format!("{}: {}", path, e))?;
+
+        Ok(scene)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let contents = match scene_format(path)? {
+            SceneFormat::Ron => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+                .map_err(|e| e.to_string())?,
+            SceneFormat::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string())?
+        };
+        fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+
+    // Checks references between entries, the file syntax is checked while parsing
+    pub fn validate(&self) -> Result<(), String> {
+        self.camera.projection.validate()?;
+        for m in self.models.iter() {
+            if let Some(material) = &m.material {
+                if self.material(material).is_none() {
+                    return Err(format!("model {} uses unknown material {}", m.name, material));
+                }
+            }
+        }
+        if let Some(terrain) = &self.terrain {
+            if terrain.layers.len() > 4 {
+                return Err(format!("the terrain has {} layers, at most 4 are blended", terrain.layers.len()));
+            }
+        }
+        if let Some(water) = &self.water {
+            if water.waves.len() > 4 {
+                return Err(format!("the water has {} waves, at most 4 are summed", water.waves.len()));
+            }
+            if water.waves.iter().any(|w| w.wavelength <= 0.0 || w.direction == [0.0, 0.0]) {
+                return Err(String::from("water waves need a direction and a positive wavelength"));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.materials.iter().find(|m| m.name == name)
+    }
+}
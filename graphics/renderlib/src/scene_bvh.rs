@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+
+use crate::collision::{Aabb, build_bvh, BvhNode, closest_hit, Frustum, Ray};
+use crate::objects::ObjectId;
+
+const MAX_LEAF_OBJECTS: usize = 2;
+
+// Bounding volume hierarchy over the world space bounds of scene objects, for frustum culling, picking on the CPU and
+// the order of GPU instance lists. Adding and removing objects builds it again, moving one only refits the bounds from
+// its leaf up to the root. Refitting loosens the tree, so it is built again once there have been as many refits as
+// there are objects.
+#[derive(Default)]
+pub struct SceneBvh {
+    items: Vec<(ObjectId, Aabb)>, // Leaf order
+    nodes: Vec<BvhNode>,
+    parents: Vec<usize>, // Of every node, the root is its own
+    leaves: HashMap<ObjectId, (usize, usize)>, // Index in items and of the leaf node
+    refits: usize // Since the last build
+}
+
+impl SceneBvh {
+    // Replaces the bounds of an object that is already in the tree
+    pub fn insert(&mut self, id: ObjectId, bounds: Aabb) {
+        self.items.retain(|(i, _)| *i != id);
+        self.items.push((id, bounds));
+        self.build();
+    }
+
+    // False if the object isn't in the tree
+    pub fn remove(&mut self, id: ObjectId) -> bool {
+        let len = self.items.len();
+        self.items.retain(|(i, _)| *i != id);
+        let removed = self.items.len() != len;
+        if removed {
+            self.build();
+        }
+
+        removed
+    }
+
+    // For an object that moved, false if it isn't in the tree
+    pub fn update(&mut self, id: ObjectId, bounds: Aabb) -> bool {
+        let (item, mut node) = match self.leaves.get(&id) {
+            Some(&leaf) => leaf,
+            None => return false
+        };
+        self.items[item].1 = bounds;
+        self.refits += 1;
+        if self.refits > self.items.len() {
+            self.build();
+            return true;
+        }
+        loop {
+            let n = self.nodes[node];
+            self.nodes[node].bounds = match n.count {
+                0 => {
+                    let [left, right] = n.children(node);
+                    self.nodes[left].bounds.union(&self.nodes[right].bounds)
+                },
+                _ => self.items[n.first..n.first + n.count].iter().fold(Aabb::empty(), |b, (_, a)| b.union(a))
+            };
+            if node == 0 {
+                return true;
+            }
+            node = self.parents[node];
+        }
+    }
+
+    fn build(&mut self) {
+        self.nodes = build_bvh(self.items.as_mut_slice(), |(_, bounds)| *bounds, MAX_LEAF_OBJECTS);
+        self.parents = vec![0; self.nodes.len()];
+        self.leaves.clear();
+        for (i, n) in self.nodes.iter().enumerate() {
+            match n.count {
+                0 => for c in n.children(i) {
+                    self.parents[c] = i;
+                },
+                _ => for item in n.first..n.first + n.count {
+                    self.leaves.insert(self.items[item].0, (item, i));
+                }
+            }
+        }
+        self.refits = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map(|n| n.bounds).unwrap_or_else(Aabb::empty)
+    }
+
+    // In leaf order, where objects close to each other are close in the order too
+    pub fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        self.items.iter().map(|(id, _)| *id)
+    }
+
+    // The objects whose bounds intersect the frustum, in leaf order
+    pub fn cull(&self, frustum: &Frustum) -> Vec<ObjectId> {
+        let mut visible = Vec::new();
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            if !frustum.intersects(&node.bounds) {
+                continue;
+            }
+            match node.count {
+                0 => stack.extend(node.children(n).iter().rev()),
+                _ => visible.extend(self.items[node.first..node.first + node.count].iter()
+                    .filter(|(_, bounds)| frustum.intersects(bounds))
+                    .map(|(id, _)| *id))
+            }
+        }
+
+        visible
+    }
+
+    // The closest of the hits hit_object reports for the objects whose bounds the ray passes through. hit_object gets
+    // an object and the t of the closest hit so far, and returns the hit's t and whatever the caller needs from it.
+    pub fn closest_hit<H, F: FnMut(ObjectId, f32) -> Option<(f32, H)>>(&self, ray: &Ray, t_max: f32,
+                                                                      mut hit_object: F) -> Option<(ObjectId, H)> {
+        closest_hit(self.nodes.as_slice(), ray, t_max, |i, t_limit| {
+            let (id, bounds) = self.items[i];
+            bounds.intersect_ray(ray, t_limit)?;
+            hit_object(id, t_limit).map(|(t, hit)| (t, (id, hit)))
+        }).map(|(_, hit)| hit)
+    }
+}
@@ -0,0 +1,57 @@
+use crate::config::LaunchConfig;
+use crate::exposure::AutoExposureSettings;
+
+// How the exposure of ColorPipeline is picked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExposureMode {
+    // ColorPipeline::exposure is used as is
+    Manual,
+    // Adapts to the scene, see AutoExposure. ColorPipeline::exposure compensates the adapted exposure.
+    Auto(AutoExposureSettings)
+}
+
+// What a renderer does while idle, see IdleTracker
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdleBehavior {
+    // Renders every frame, like a game
+    Continuous,
+    // Stops rendering until there is input or a RendererCommand, for tools that only change on user action
+    Pause,
+    // Renders at most at this rate, so that animations keep moving slowly
+    Reduced { fps: f32 }
+}
+
+// Settings both renderers share, see set_settings on each
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RendererSettings {
+    pub exposure: ExposureMode,
+    pub idle_behavior: IdleBehavior
+}
+
+impl Default for RendererSettings {
+    fn default() -> RendererSettings {
+        RendererSettings {
+            exposure: ExposureMode::Auto(AutoExposureSettings::default()),
+            idle_behavior: IdleBehavior::Continuous
+        }
+    }
+}
+
+impl RendererSettings {
+    pub fn from_config(config: &LaunchConfig) -> RendererSettings {
+        RendererSettings {
+            exposure: match config.no_auto_exposure {
+                true => ExposureMode::Manual,
+                false => ExposureMode::Auto(AutoExposureSettings::default())
+            },
+            idle_behavior: IdleBehavior::Continuous
+        }
+    }
+
+    pub fn auto_exposure(&self) -> Option<AutoExposureSettings> {
+        match self.exposure {
+            ExposureMode::Auto(settings) => Some(settings),
+            ExposureMode::Manual => None
+        }
+    }
+}
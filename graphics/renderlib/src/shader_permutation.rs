@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+// Relative to the working directory like the shipped .spv files. The preprocessed source of every permutation is
+// committed to DEFAULT_PERMUTATION_DIR and compiled offline into DEFAULT_SPV_DIR, like the other shaders.
+pub const DEFAULT_SOURCE_DIR: &str = "graphics/shaders/src";
+pub const DEFAULT_PERMUTATION_DIR: &str = "graphics/shaders/src/permutations";
+pub const DEFAULT_SPV_DIR: &str = "graphics/shaders/spv";
+
+// The #defines a shader is compiled with, such as a lighting model, a debug mode or a feature toggle. Keys with the
+// same defines name the same binary, whatever order they were added in.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PermutationKey {
+    defines: BTreeMap<String, String>
+}
+
+impl PermutationKey {
+    pub fn new() -> PermutationKey {
+        PermutationKey::default()
+    }
+
+    // #define name value, replacing an earlier value
+    pub fn define(mut self, name: &str, value: &str) -> PermutationKey {
+        self.defines.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    // #define name, for #ifdef toggles
+    pub fn flag(self, name: &str) -> PermutationKey {
+        self.define(name, "")
+    }
+
+    pub fn defines(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.defines.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    fn define_lines(&self) -> String {
+        self.defines.iter()
+            .map(|(name, value)| match value.is_empty() {
+                true => format!("#define {}\n", name),
+                false => format!("#define {} {}\n", name, value)
+            })
+            .collect()
+    }
+}
+
+// FNV-1a, unlike DefaultHasher it stays the same across Rust versions, so the cache outlives toolchain updates
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+fn include_path(line: &str) -> Option<&str> {
+    let rest = line.trim_start().strip_prefix("#include")?.trim();
+    rest.strip_prefix('"').and_then(|r| r.strip_suffix('"'))
+        .or_else(|| rest.strip_prefix('<').and_then(|r| r.strip_suffix('>')))
+}
+
+// Pastes the file at every #include like GL_GOOGLE_include_directive does, an include inside #ifdef stays inside it.
+// Paths are relative to the including file, then to include_dirs. stack holds the files being expanded, to catch
+// cycles.
+fn expand(path: &Path, include_dirs: &[PathBuf], stack: &mut Vec<PathBuf>, out: &mut String) -> Result<(), String> {
+    if stack.iter().any(|p| p == path) {
+        return Err(format!("{} includes itself through {}", path.display(),
+                           stack.iter().map(|p| p.display().to_string()).collect::<Vec<String>>().join(" -> ")));
+    }
+    let source = fs::read_to_string(path).map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    stack.push(path.to_path_buf());
+    for line in source.lines() {
+        match include_path(line) {
+            Some(included) => {
+                let candidates = path.parent().into_iter().chain(include_dirs.iter().map(PathBuf::as_path));
+                let resolved = candidates.map(|dir| dir.join(included))
+                    .find(|p| p.is_file())
+                    .ok_or_else(|| format!("{} includes {}, which isn't found", path.display(), included))?;
+                expand(resolved.as_path(), include_dirs, stack, out)?;
+            },
+            None if line.trim_start().starts_with("#extension GL_GOOGLE_include_directive") => {}, // Resolved here
+            None => {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    stack.pop();
+
+    Ok(())
+}
+
+// The shader at path with its includes resolved and the key's defines after the #version line, as one source
+pub fn preprocess(path: &str, key: &PermutationKey, include_dirs: &[PathBuf]) -> Result<String, String> {
+    let mut expanded = String::new();
+    expand(Path::new(path), include_dirs, &mut Vec::new(), &mut expanded)?;
+    let defines = key.define_lines();
+    match expanded.find("#version") {
+        Some(start) => {
+            let end = expanded[start..].find('\n').map_or(expanded.len(), |n| start + n + 1);
+            expanded.insert_str(end, defines.as_str());
+            Ok(expanded)
+        },
+        None => Err(format!("{} has no #version", path))
+    }
+}
+
+// Finds the SPIR-V of permutations of the GLSL sources in source_dir. Permutations are compiled offline: write_source
+// puts a permutation's preprocessed source into permutation_dir, which is compiled with glslc into spv_dir like any
+// other shader, and both are committed. Names hold a hash of the defines, so a permutation keeps its files. A binary
+// whose source no longer matches the shader and its includes is reported as out of date instead of being loaded.
+pub struct ShaderPermutations {
+    source_dir: PathBuf,
+    permutation_dir: PathBuf,
+    spv_dir: PathBuf,
+    include_dirs: Vec<PathBuf>
+}
+
+impl Default for ShaderPermutations {
+    fn default() -> ShaderPermutations {
+        ShaderPermutations::new(DEFAULT_SOURCE_DIR, DEFAULT_PERMUTATION_DIR, DEFAULT_SPV_DIR)
+    }
+}
+
+impl ShaderPermutations {
+    pub fn new(source_dir: &str, permutation_dir: &str, spv_dir: &str) -> ShaderPermutations {
+        ShaderPermutations {
+            source_dir: PathBuf::from(source_dir),
+            permutation_dir: PathBuf::from(permutation_dir),
+            spv_dir: PathBuf::from(spv_dir),
+            include_dirs: vec![PathBuf::from(source_dir)]
+        }
+    }
+
+    // Searched after the including file's directory, source_dir is always the first
+    pub fn add_include_dir(&mut self, dir: &str) {
+        self.include_dirs.push(PathBuf::from(dir));
+    }
+
+    // The permutation's preprocessed source and SPIR-V paths
+    fn paths(&self, shader: &str, key: &PermutationKey) -> Result<(PathBuf, PathBuf), String> {
+        let (stem, extension) = shader.rsplit_once('.').ok_or_else(|| format!("{} has no stage extension", shader))?;
+        let name = format!("{}_{}_{:016x}", stem.replace(['/', '\\'], "_"), extension,
+                           fnv1a(key.define_lines().as_bytes()));
+        Ok((self.permutation_dir.join(format!("{}.{}", name, extension)), self.spv_dir.join(format!("{}.spv", name))))
+    }
+
+    fn preprocess(&self, shader: &str, key: &PermutationKey) -> Result<String, String> {
+        preprocess(&self.source_dir.join(shader).to_string_lossy(), key, self.include_dirs.as_slice())
+    }
+
+    // Writes the preprocessed source of shader with key's defines for the offline compiler and returns its path,
+    // compile it with glslc into the path spv_path expects
+    pub fn write_source(&self, shader: &str, key: &PermutationKey) -> Result<PathBuf, String> {
+        let source = self.preprocess(shader, key)?;
+        let (source_path, spv_path) = self.paths(shader, key)?;
+        fs::create_dir_all(&self.permutation_dir)
+            .map_err(|e| format!("Can't create {}: {}", self.permutation_dir.display(), e))?;
+        fs::write(&source_path, source.as_bytes())
+            .map_err(|e| format!("Can't write {}: {}", source_path.display(), e))?;
+        debug!(shader, source = %source_path.display(), spv = %spv_path.display(), "Wrote shader permutation");
+
+        Ok(source_path)
+    }
+
+    // The SPIR-V of shader, a file in source_dir like "forward_lit.frag", with key's defines. The path can be handed
+    // to the pipeline constructors in place of a shipped .spv file. Fails when the permutation hasn't been compiled
+    // or its source changed since, see write_source.
+    pub fn spv_path(&self, shader: &str, key: &PermutationKey) -> Result<String, String> {
+        let (source_path, spv_path) = self.paths(shader, key)?;
+        let source = self.preprocess(shader, key)?;
+        match fs::read_to_string(&source_path) {
+            Ok(compiled) if spv_path.is_file() && compiled == source => Ok(spv_path.to_string_lossy().into_owned()),
+            Ok(_) if spv_path.is_file() => Err(format!("{} with {:?} changed since {} was compiled, write its source \
+                                                        and compile it again", shader, key.defines,
+                                                       spv_path.display())),
+            _ => Err(format!("{} with {:?} has no SPIR-V at {}, write its source and compile it with glslc", shader,
+                             key.defines, spv_path.display()))
+        }
+    }
+
+    // Like spv_path for every stage of a pipeline, in the same order
+    pub fn spv_paths(&self, shaders: &[&str], key: &PermutationKey) -> Result<Vec<String>, String> {
+        shaders.iter().map(|s| self.spv_path(s, key)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn write_sources(dir: &str, files: &[(&str, &str)]) -> PathBuf {
+        // Without the files of earlier runs, such as compiled permutations
+        let dir = env::temp_dir().join(dir);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, source) in files.iter() {
+            fs::write(dir.join(name), source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn includes_are_pasted_with_the_defines_after_the_version() {
+        let dir = write_sources("cubulous_preprocess", &[
+            ("main.frag", "#version 460\n#extension GL_GOOGLE_include_directive : enable\n#ifdef DEBUG\n\
+                           #include \"common.glsl\"\n#endif\nvoid main() {}\n"),
+            ("common.glsl", "float common() { return MODEL; }\n")
+        ]);
+        let key = PermutationKey::new().flag("DEBUG").define("MODEL", "2");
+        let source = preprocess(&dir.join("main.frag").to_string_lossy(), &key, &[]).unwrap();
+        assert_eq!(source, "#version 460\n#define DEBUG\n#define MODEL 2\n#ifdef DEBUG\n\
+                            float common() { return MODEL; }\n#endif\nvoid main() {}\n");
+    }
+
+    #[test]
+    fn cyclic_includes_fail() {
+        let dir = write_sources("cubulous_preprocess_cycle", &[
+            ("a.vert", "#version 460\n#include \"b.glsl\"\n"),
+            ("b.glsl", "#include \"a.vert\"\n")
+        ]);
+        assert!(preprocess(&dir.join("a.vert").to_string_lossy(), &PermutationKey::new(), &[]).is_err());
+    }
+
+    #[test]
+    fn permutations_need_an_up_to_date_binary() {
+        let dir = write_sources("cubulous_permutations", &[("main.frag", "#version 460\nvoid main() {}\n")]);
+        let dir = dir.to_string_lossy();
+        let permutations = ShaderPermutations::new(&dir, &format!("{}/permutations", dir), &dir);
+        let key = PermutationKey::new().flag("DEBUG");
+        let source_path = permutations.write_source("main.frag", &key).unwrap();
+        assert!(permutations.spv_path("main.frag", &key).is_err());
+
+        let spv_path = permutations.paths("main.frag", &key).unwrap().1;
+        fs::write(&spv_path, [0u8; 4]).unwrap(); // Stands in for the compiled binary
+        assert_eq!(permutations.spv_path("main.frag", &key).unwrap(), spv_path.to_string_lossy());
+        fs::write(format!("{}/main.frag", dir), "#version 460\nvoid main() { discard; }\n").unwrap();
+        assert!(permutations.spv_path("main.frag", &key).unwrap_err().contains("changed"));
+        assert!(source_path.is_file());
+    }
+
+    #[test]
+    fn keys_ignore_the_define_order() {
+        let a = PermutationKey::new().flag("A").define("B", "1");
+        let b = PermutationKey::new().define("B", "1").flag("A");
+        assert_eq!(a, b);
+        assert_eq!(a.define_lines(), b.define_lines());
+        assert_ne!(a, PermutationKey::new().flag("A"));
+    }
+}
@@ -0,0 +1,487 @@
+use std::cell::Cell;
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+use cgmath::{Deg, Matrix4, MetricSpace, perspective, Point3, Vector3};
+use tracing::debug;
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::image::{create_image_view_array, create_image_with, ImageInfo};
+use crate::raster_pipeline::RasterPipeline;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_nearest_sampler, destroy_sampler};
+use crate::scene::Light;
+use crate::single_time::{begin_single_time_commands, end_single_time_commands};
+use crate::ssao::create_set_layout;
+use crate::vkcore::VkCore;
+
+const SHADOW_FORMAT: vk::Format = vk::Format::D16_UNORM; // Holds the distance to the light over its range
+const CUBE_FACES: u32 = 6;
+const CUBE_VIEW_MASK: u32 = 0b11_1111;
+const SHADOW_NEAR: f32 = 0.05;
+// Forward and up of the faces in layer order, +x, -x, +y, -y, +z and -z. Sampling uses the same matrices, so any up
+// perpendicular to the face works.
+const FACE_AXES: [([f32; 3], [f32; 3]); CUBE_FACES as usize] = [
+    ([1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, -1.0, 0.0])
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowSettings {
+    pub enabled: bool,
+    pub resolution: u32, // Of a cube face
+    pub max_lights: u32, // Cubes in the atlas, the casting lights closest to the camera get one
+    pub bias: f32 // In world units, how far behind the stored distance a surface still counts as lit
+}
+
+impl Default for ShadowSettings {
+    fn default() -> ShadowSettings {
+        ShadowSettings {
+            enabled: true,
+            resolution: 512,
+            max_lights: 8,
+            bias: 0.05
+        }
+    }
+}
+
+// Matches ShadowSlot in shadowcommon.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct ShadowSlotGpu {
+    faces: [Matrix4<f32>; CUBE_FACES as usize],
+    light: [f32; 4], // Position and range
+    params: [f32; 4] // x is the bias
+}
+
+// Matches the push constants in shadow.glsl and shadow.frag. The model is pushed by cmd_set_model, the rest by
+// cmd_render.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ShadowConstants {
+    model: Matrix4<f32>,
+    slot: [u32; 4] // The slot and, without multiview, the face
+}
+
+// What a slot's faces were rendered for, lights are told apart by their sphere
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ShadowCaster {
+    position: [f32; 3],
+    range: f32
+}
+
+impl ShadowCaster {
+    fn face_view_projections(&self) -> [Matrix4<f32>; CUBE_FACES as usize] {
+        let projection = perspective(Deg(90.0), 1.0, SHADOW_NEAR, self.range.max(2.0 * SHADOW_NEAR));
+        let eye = Point3::from(self.position);
+        FACE_AXES.map(|(forward, up)| projection * Matrix4::look_at_rh(eye, eye + Vector3::from(forward),
+                                                                       Vector3::from(up)))
+    }
+}
+
+// Depth only, every layer of the attachment is cleared and ends up readable by the forward pass
+fn create_shadow_render_pass(core: &VkCore, multiview: bool) -> vk::RenderPass {
+    let attachment_desc = [vk::AttachmentDescription::default()
+        .format(SHADOW_FORMAT)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+    let depth_ref = vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+    let subpass = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .depth_stencil_attachment(&depth_ref)];
+    // The previous frames' forward passes may still sample the layers
+    let dependencies = [
+        vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_stage_mask(vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS |
+                vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+        vk::SubpassDependency::default()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL)
+            .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+    ];
+    // The faces look in different directions, so they aren't correlated
+    let view_masks = [CUBE_VIEW_MASK];
+    let mut multiview_create_info = vk::RenderPassMultiviewCreateInfo::default()
+        .view_masks(&view_masks);
+    let mut render_pass_create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachment_desc)
+        .subpasses(&subpass)
+        .dependencies(&dependencies);
+    if multiview {
+        render_pass_create_info = render_pass_create_info.push_next(&mut multiview_create_info);
+    }
+
+    unsafe { core.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+}
+
+fn create_layer_view(core: &VkCore, image: vk::Image, base_layer: u32, layer_count: u32) -> vk::ImageView {
+    let subresource_range = vk::ImageSubresourceRange::default()
+        .aspect_mask(vk::ImageAspectFlags::DEPTH)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(base_layer)
+        .layer_count(layer_count);
+    let view_info = vk::ImageViewCreateInfo::default()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+        .format(SHADOW_FORMAT)
+        .subresource_range(subresource_range);
+
+    unsafe { core.logical_device.create_image_view(&view_info, None).unwrap() }
+}
+
+// Slots that never had a light are bound for sampling as well
+fn make_readable(core: &VkCore, command_pool: vk::CommandPool, image: vk::Image, layers: u32) {
+    let barrier = [vk::ImageMemoryBarrier::default()
+        .old_layout(vk::ImageLayout::UNDEFINED)
+        .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+        .src_access_mask(vk::AccessFlags::empty())
+        .dst_access_mask(vk::AccessFlags::SHADER_READ)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(layers))];
+    let command_buffer = begin_single_time_commands(core, command_pool);
+    unsafe {
+        core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                 vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                 vk::DependencyFlags::empty(), &[], &[], &barrier);
+    }
+    end_single_time_commands(core, command_pool, command_buffer);
+}
+
+// Omnidirectional shadows of the point lights with cast_shadows set, for ClusteredLights. Every shadowed light gets a
+// slot of six layers in a shared 2D array atlas, one per cube face, holding the distance to the light over its range.
+// The atlas has a slot for each of ShadowSettings::max_lights, update hands them to the casting lights closest to
+// the camera. A light keeps its slot while it stays among them, and its faces are only rendered again once it moved,
+// so static lights cost nothing after their first frame. With multiview all faces of a slot are rendered in one pass.
+// The faces are rendered from the geometry the renderer draws in cmd_render, call invalidate when it changes.
+pub struct PointShadows {
+    settings: ShadowSettings,
+    multiview: bool,
+    points: Vec<Option<ShadowCaster>>, // One per point light in the order of set_lights, None if it casts none
+    slots: Vec<Option<ShadowCaster>>, // What each slot holds, kept after its light stopped being shadowed
+    assigned: Vec<Option<u32>>, // The slot of each point light, see shadow_slots
+    rendered: Vec<Cell<bool>>, // Slots whose faces are up to date
+    image: vk::Image,
+    mem: vk::DeviceMemory,
+    view: vk::ImageView, // All layers, sampled by the forward pass
+    targets: Vec<(vk::ImageView, vk::Framebuffer)>, // One per slot with multiview, per layer otherwise
+    render_pass: vk::RenderPass,
+    pipeline: RasterPipeline,
+    sampler: vk::Sampler,
+    slot_buffers: Vec<GpuBuffer>, // Per frame in flight
+    mapped: Vec<*mut c_void>,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>
+}
+
+impl PointShadows {
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, settings: ShadowSettings, max_frames: usize)
+        -> PointShadows {
+        let slot_count = settings.max_lights.max(1);
+        let layers = slot_count * CUBE_FACES;
+        let multiview = core.multiview_supported;
+        let (image, mem) = create_image_with(core, &ImageInfo {
+            array_layers: layers,
+            ..ImageInfo::new(settings.resolution, settings.resolution, SHADOW_FORMAT,
+                             vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+        });
+        make_readable(core, command_pool, image, layers);
+        let view = create_image_view_array(core, image, SHADOW_FORMAT, vk::ImageAspectFlags::DEPTH, 1, layers);
+
+        let render_pass = create_shadow_render_pass(core, multiview);
+        let (target_count, target_layers) = match multiview {
+            true => (slot_count, CUBE_FACES),
+            false => (layers, 1)
+        };
+        let targets: Vec<(vk::ImageView, vk::Framebuffer)> = (0..target_count)
+            .map(|t| {
+                let target_view = create_layer_view(core, image, t * target_layers, target_layers);
+                let attachments = [target_view];
+                let create_info = vk::FramebufferCreateInfo::default()
+                    .render_pass(render_pass)
+                    .attachments(&attachments)
+                    .width(settings.resolution)
+                    .height(settings.resolution)
+                    .layers(1); // Multiview takes the layers from the view mask
+                (target_view, unsafe { core.logical_device.create_framebuffer(&create_info, None).unwrap() })
+            })
+            .collect();
+
+        let buffer_size = (mem::size_of::<ShadowSlotGpu>() * slot_count as usize) as vk::DeviceSize;
+        let slot_buffers: Vec<GpuBuffer> = (0..max_frames)
+            .map(|_| GpuBuffer::new(core, buffer_size, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT))
+            .collect();
+        let mapped: Vec<*mut c_void> = slot_buffers.iter()
+            .map(|b| unsafe {
+                core.logical_device.map_memory(b.mem, 0, buffer_size, vk::MemoryMapFlags::empty()).unwrap()
+            })
+            .collect();
+        let set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+        ]);
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(max_frames as u32)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let set_layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(set_layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        for (set, buffer) in sets.iter().zip(slot_buffers.iter()) {
+            let buffer_info = [vk::DescriptorBufferInfo::default()
+                .buffer(buffer.buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write = [vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&buffer_info)];
+            unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+        }
+        debug!(slots = slot_count, resolution = settings.resolution, multiview, "Created point light shadows");
+
+        PointShadows {
+            settings,
+            multiview,
+            points: Vec::new(),
+            slots: vec![None; slot_count as usize],
+            assigned: Vec::new(),
+            rendered: (0..slot_count).map(|_| Cell::new(false)).collect(),
+            image,
+            mem,
+            view,
+            targets,
+            render_pass,
+            pipeline: RasterPipeline::new_shadow(core, render_pass, set_layout, multiview),
+            sampler: create_nearest_sampler(core),
+            slot_buffers,
+            mapped,
+            set_layout,
+            descriptor_pool,
+            sets
+        }
+    }
+
+    pub fn settings(&self) -> ShadowSettings {
+        self.settings
+    }
+
+    // Takes the casting point lights, in the order ClusteredLights::set_lights keeps them. Slots are handed out
+    // again in the next update.
+    pub fn set_lights(&mut self, lights: &[Light]) {
+        self.points = lights.iter()
+            .filter_map(|l| match *l {
+                Light::Point { position, range, cast_shadows, .. } => Some(match cast_shadows {
+                    true => Some(ShadowCaster { position, range }),
+                    false => None
+                }),
+                Light::Directional { .. } => None
+            })
+            .collect();
+    }
+
+    // Renders every slot's faces again, after the shadow casting geometry changed
+    pub fn invalidate(&self) {
+        self.rendered.iter().for_each(|r| r.set(false));
+    }
+
+    // The atlas slot of each point light, None for the unshadowed ones
+    pub fn shadow_slots(&self) -> &[Option<u32>] {
+        self.assigned.as_slice()
+    }
+
+    // Call once the frame's fence has been waited on, eye is the camera's position
+    pub fn update(&mut self, frame: usize, eye: Point3<f32>) {
+        // How close each casting light's sphere comes to the camera
+        let mut wanted: Vec<(usize, f32)> = self.points.iter().enumerate()
+            .filter(|_| self.settings.enabled)
+            .filter_map(|(i, p)| p.map(|c| (i, (eye.distance(Point3::from(c.position)) - c.range).max(0.0))))
+            .collect();
+        wanted.sort_by(|a, b| a.1.total_cmp(&b.1));
+        wanted.truncate(self.slots.len());
+
+        // Slots keep the lights they already hold, the other wanted lights take the remaining ones
+        let mut assigned: Vec<Option<u32>> = vec![None; self.points.len()];
+        let mut free: Vec<usize> = Vec::new();
+        for (slot, holder) in self.slots.iter().enumerate() {
+            let kept = wanted.iter()
+                .find(|&&(i, _)| holder.is_some() && self.points[i] == *holder && assigned[i].is_none());
+            match kept {
+                Some(&(i, _)) => assigned[i] = Some(slot as u32),
+                None => free.push(slot)
+            }
+        }
+        let mut free = free.into_iter();
+        for &(i, _) in wanted.iter() {
+            if assigned[i].is_none() {
+                let slot = free.next().unwrap(); // There are at most as many wanted lights as slots
+                self.slots[slot] = self.points[i];
+                self.rendered[slot].set(false);
+                assigned[i] = Some(slot as u32);
+            }
+        }
+        self.assigned = assigned;
+
+        let unused = ShadowSlotGpu {
+            faces: [Matrix4::from_scale(1.0); CUBE_FACES as usize],
+            light: [0.0, 0.0, 0.0, 1.0],
+            params: [0.0; 4]
+        };
+        let gpu_slots: Vec<ShadowSlotGpu> = self.slots.iter()
+            .map(|s| match s {
+                Some(caster) => ShadowSlotGpu {
+                    faces: caster.face_view_projections(),
+                    light: [caster.position[0], caster.position[1], caster.position[2], caster.range],
+                    params: [self.settings.bias, 0.0, 0.0, 0.0]
+                },
+                None => unused
+            })
+            .collect();
+        unsafe {
+            (self.mapped[frame] as *mut ShadowSlotGpu).copy_from_nonoverlapping(gpu_slots.as_ptr(), gpu_slots.len());
+        }
+    }
+
+    // Renders the faces of the slots whose light changed, before the render passes that sample them. draw records
+    // the shadow casters with cmd_set_model before each draw, it is called once per slot with multiview and once per
+    // face otherwise, with the light's position.
+    pub fn cmd_render<F: Fn(Point3<f32>)>(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize,
+                                          draw: F) {
+        let extent = vk::Extent2D {
+            width: self.settings.resolution,
+            height: self.settings.resolution
+        };
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(extent);
+        let viewports = [vk::Viewport::default()
+            .x(0.0)
+            .y(0.0)
+            .width(extent.width as f32)
+            .height(extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let clear_values = [vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue::default()
+                .depth(1.0)
+                .stencil(0)
+        }];
+        let layout = self.pipeline.pipeline_layout;
+        let faces = match self.multiview {
+            true => 1,
+            false => CUBE_FACES
+        };
+
+        for (slot, holder) in self.slots.iter().enumerate() {
+            let caster = match holder {
+                Some(caster) if !self.rendered[slot].get() => caster,
+                _ => continue
+            };
+            for face in 0..faces {
+                let target = slot * faces as usize + face as usize;
+                let render_pass_info = vk::RenderPassBeginInfo::default()
+                    .render_pass(self.render_pass)
+                    .framebuffer(self.targets[target].1)
+                    .render_area(render_area)
+                    .clear_values(&clear_values);
+                let constants = [slot as u32, face, 0, 0];
+                unsafe {
+                    core.logical_device.cmd_begin_render_pass(command_buffer, &render_pass_info,
+                                                              vk::SubpassContents::INLINE);
+                    core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                          self.pipeline.pipelines[0]);
+                    core.logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+                    core.logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+                    core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                                 layout, 0, &[self.sets[frame]], &[]);
+                    core.logical_device.cmd_push_constants(command_buffer, layout,
+                                                           vk::ShaderStageFlags::VERTEX |
+                                                               vk::ShaderStageFlags::FRAGMENT,
+                                                           mem::size_of::<Matrix4<f32>>() as u32,
+                                                           cast_to_u8_slice(&constants));
+                }
+                draw(Point3::from(caster.position));
+                unsafe { core.logical_device.cmd_end_render_pass(command_buffer) };
+            }
+            self.rendered[slot].set(true);
+        }
+    }
+
+    // Inside draw of cmd_render, model places the vertices of the next draws in the world
+    pub fn cmd_set_model(&self, core: &VkCore, command_buffer: vk::CommandBuffer, model: Matrix4<f32>) {
+        unsafe {
+            core.logical_device.cmd_push_constants(command_buffer, self.pipeline.pipeline_layout,
+                                                   vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT, 0,
+                                                   cast_to_u8_slice(&model));
+        }
+    }
+
+    // The atlas, as sampled by forward_lit.glsl
+    pub(crate) fn atlas_info(&self) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(self.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    }
+
+    // The frame's face matrices and lights of every slot
+    pub(crate) fn slot_buffer(&self, frame: usize) -> vk::Buffer {
+        self.slot_buffers[frame].buf
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+        destroy_sampler(core, self.sampler);
+        unsafe {
+            for (view, frame_buffer) in self.targets.iter() {
+                core.logical_device.destroy_framebuffer(*frame_buffer, None);
+                core.logical_device.destroy_image_view(*view, None);
+            }
+            core.logical_device.destroy_render_pass(self.render_pass, None);
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            core.logical_device.destroy_image_view(self.view, None);
+            core.logical_device.destroy_image(self.image, None);
+            core.logical_device.free_memory(self.mem, None);
+        }
+        for b in self.slot_buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use ash::vk;
+use crate::gpu_buffer::GpuBuffer;
 use crate::vkcore::VkCore;
 
 pub fn begin_single_time_commands(core: &VkCore, command_pool: vk::CommandPool) -> vk::CommandBuffer {
@@ -17,7 +18,18 @@ pub fn begin_single_time_commands(core: &VkCore, command_pool: vk::CommandPool)
     command_buffer
 }
 
+// Blocks until command_buffer finished, other work on the graphics queue isn't waited for
 pub fn end_single_time_commands(core: &VkCore, command_pool: vk::CommandPool, command_buffer: vk::CommandBuffer) {
+    let fence = submit_single_time_commands(core, command_buffer);
+
+    unsafe {
+        core.logical_device.wait_for_fences(&[fence], true, u64::MAX).unwrap();
+        core.logical_device.destroy_fence(fence, None);
+        core.logical_device.free_command_buffers(command_pool, &[command_buffer]);
+    }
+}
+
+fn submit_single_time_commands(core: &VkCore, command_buffer: vk::CommandBuffer) -> vk::Fence {
     unsafe { core.logical_device.end_command_buffer(command_buffer).unwrap(); }
 
     let command_buffers = [command_buffer];
@@ -25,8 +37,138 @@ pub fn end_single_time_commands(core: &VkCore, command_pool: vk::CommandPool, co
         .command_buffers(&command_buffers)];
 
     unsafe {
-        core.logical_device.queue_submit(core.graphics_queue, &submit_info, vk::Fence::null()).unwrap();
-        core.logical_device.queue_wait_idle(core.graphics_queue).unwrap();
-        core.logical_device.free_command_buffers(command_pool, &command_buffers);
+        let fence = core.logical_device.create_fence(&vk::FenceCreateInfo::default(), None).unwrap();
+        core.logical_device.queue_submit(core.graphics_queue, &submit_info, fence).unwrap();
+        fence
+    }
+}
+
+// A submission of a SingleTimeContext, later ones compare greater
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SubmissionId(u64);
+
+struct Submission {
+    id: SubmissionId,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    retired: Vec<GpuBuffer>
+}
+
+// Batches one time operations, like uploads, layout transitions and acceleration structure builds, into a single
+// command buffer and submission. Operations that depend on each other need their own barriers, they are recorded
+// one after the other. Each submission signals a fence that callers either wait on or poll, instead of idling the
+// queue, and the buffers retired with it are destroyed once it finished. Submissions go to the graphics queue, so
+// they finish in order.
+pub struct SingleTimeContext {
+    command_pool: vk::CommandPool,
+    recording: Option<vk::CommandBuffer>,
+    retired: Vec<GpuBuffer>, // Read by the recorded operations
+    in_flight: Vec<Submission>,
+    next_id: u64
+}
+
+impl SingleTimeContext {
+    // command_pool is a graphics pool
+    pub fn new(command_pool: vk::CommandPool) -> SingleTimeContext {
+        SingleTimeContext {
+            command_pool,
+            recording: None,
+            retired: Vec::new(),
+            in_flight: Vec::new(),
+            next_id: 1 // SubmissionId(0) stands for no submission, which is always complete
+        }
+    }
+
+    pub fn command_pool(&self) -> vk::CommandPool {
+        self.command_pool
+    }
+
+    // Where the next operation is recorded, shared by everything up to the next submit
+    pub fn command_buffer(&mut self, core: &VkCore) -> vk::CommandBuffer {
+        *self.recording.get_or_insert_with(|| begin_single_time_commands(core, self.command_pool))
+    }
+
+    // For buffers the recorded operations read from, like staging buffers. Destroyed once their submission finished.
+    pub fn retire(&mut self, buffer: GpuBuffer) {
+        self.retired.push(buffer);
+    }
+
+    // Everything recorded so far. Without anything recorded, the last submission is returned again.
+    pub fn submit(&mut self, core: &VkCore) -> SubmissionId {
+        self.reclaim(core);
+        if self.recording.is_none() && self.retired.is_empty() {
+            return SubmissionId(self.next_id - 1);
+        }
+        let command_buffer = self.command_buffer(core);
+        let fence = submit_single_time_commands(core, command_buffer);
+        self.recording = None;
+        let id = SubmissionId(self.next_id);
+        self.next_id += 1;
+        self.in_flight.push(Submission {
+            id,
+            command_buffer,
+            fence,
+            retired: self.retired.drain(..).collect()
+        });
+
+        id
+    }
+
+    pub fn is_complete(&mut self, core: &VkCore, id: SubmissionId) -> bool {
+        self.reclaim(core);
+        self.in_flight.first().is_none_or(|s| s.id > id)
+    }
+
+    // Blocks until id and the submissions before it finished
+    pub fn wait(&mut self, core: &VkCore, id: SubmissionId) {
+        let fences: Vec<vk::Fence> = self.in_flight.iter().filter(|s| s.id <= id).map(|s| s.fence).collect();
+        if !fences.is_empty() {
+            unsafe { core.logical_device.wait_for_fences(fences.as_slice(), true, u64::MAX).unwrap() };
+        }
+        self.reclaim(core);
+    }
+
+    // Submits what was recorded and waits for it, like end_single_time_commands
+    pub fn flush(&mut self, core: &VkCore) {
+        let id = self.submit(core);
+        self.wait(core, id);
+    }
+
+    // A lost device never signals the fences, its submissions count as finished
+    fn reclaim(&mut self, core: &VkCore) {
+        let finished = self.in_flight.iter()
+            .take_while(|s| !matches!(unsafe { core.logical_device.get_fence_status(s.fence) }, Ok(false)))
+            .count();
+        for submission in self.in_flight.drain(..finished) {
+            destroy_submission(core, self.command_pool, &submission);
+        }
+    }
+
+    // Waits for the submissions in flight, operations recorded since the last submit are dropped
+    pub fn destroy(&self, core: &VkCore) {
+        let fences: Vec<vk::Fence> = self.in_flight.iter().map(|s| s.fence).collect();
+        if !fences.is_empty() {
+            // Fails once the device is lost, when nothing is running anymore either
+            let _ = unsafe { core.logical_device.wait_for_fences(fences.as_slice(), true, u64::MAX) };
+        }
+        for submission in self.in_flight.iter() {
+            destroy_submission(core, self.command_pool, submission);
+        }
+        if let Some(command_buffer) = self.recording {
+            unsafe { core.logical_device.free_command_buffers(self.command_pool, &[command_buffer]) };
+        }
+        for buffer in self.retired.iter() {
+            buffer.destroy(core);
+        }
+    }
+}
+
+fn destroy_submission(core: &VkCore, command_pool: vk::CommandPool, submission: &Submission) {
+    unsafe {
+        core.logical_device.destroy_fence(submission.fence, None);
+        core.logical_device.free_command_buffers(command_pool, &[submission.command_buffer]);
     }
-}
\ No newline at end of file
+    for buffer in submission.retired.iter() {
+        buffer.destroy(core);
+    }
+}
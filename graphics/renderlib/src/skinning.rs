@@ -0,0 +1,184 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+use cgmath::Matrix4;
+
+use crate::compute::{ComputePipeline, group_count};
+use crate::gpu_buffer::{create_buffer, GpuBuffer};
+use crate::renderutils::cast_to_u8_slice;
+use crate::vertex::SkinnedVertex;
+use crate::vkcore::VkCore;
+
+const SKINNING_SHADER_PATH: &str = "graphics/shaders/spv/skinning.spv";
+const SKINNING_GROUP_SIZE: u32 = 64; // Must match local_size_x in skinning.comp
+
+// Per frame host visible storage buffers holding one skinning matrix per joint, from Skeleton::skin_matrices
+pub struct BoneBuffer {
+    pub(crate) data: Vec<vk::Buffer>,
+    pub(crate) range: vk::DeviceSize, // Size of a single frame's matrices
+    mem: Vec<vk::DeviceMemory>,
+    mapped: Vec<*mut c_void>,
+    joint_count: usize
+}
+
+impl BoneBuffer {
+    pub fn new(core: &VkCore, max_frames: usize, joint_count: usize) -> BoneBuffer {
+        let range = (mem::size_of::<Matrix4<f32>>() * joint_count) as vk::DeviceSize;
+        let mut bone_buffer = BoneBuffer {
+            data: vec![],
+            range,
+            mem: vec![],
+            mapped: vec![],
+            joint_count
+        };
+
+        for _ in 0..max_frames {
+            let (buf_mem, buffer) = create_buffer(core, range, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                  vk::MemoryPropertyFlags::HOST_COHERENT |
+                                                      vk::MemoryPropertyFlags::HOST_VISIBLE);
+            bone_buffer.mem.push(buf_mem);
+            bone_buffer.data.push(buffer);
+            bone_buffer.mapped.push(unsafe {
+                core.logical_device.map_memory(buf_mem, 0, range, vk::MemoryMapFlags::empty()).unwrap()
+            });
+        }
+
+        bone_buffer
+    }
+
+    pub fn update(&self, current_frame: usize, matrices: &[Matrix4<f32>]) {
+        assert_eq!(matrices.len(), self.joint_count);
+        unsafe {
+            (self.mapped[current_frame] as *mut Matrix4<f32>)
+                .copy_from_nonoverlapping(matrices.as_ptr(), matrices.len());
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for (buf, mem) in self.data.iter().zip(self.mem.iter()) {
+            unsafe {
+                core.logical_device.destroy_buffer(*buf, None);
+                core.logical_device.free_memory(*mem, None);
+            }
+        }
+    }
+}
+
+// Skins the bind pose on the GPU and writes tightly packed vec3 positions, which can be bound as a vertex buffer or
+// used as acceleration structure build input to refit a BLAS every frame
+pub struct ComputeSkinner {
+    pipeline: ComputePipeline,
+    set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    bind_pose: GpuBuffer,
+    pub positions: GpuBuffer,
+    vertex_count: u32
+}
+
+impl ComputeSkinner {
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, vertices: &[SkinnedVertex], bones: &BoneBuffer)
+        -> ComputeSkinner {
+        let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..3).map(|b| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(b) // 0 bind pose, 1 bones, 2 skinned positions
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        }).collect();
+        let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .bindings(bindings.as_slice());
+        let set_layout = unsafe {
+            core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap()
+        };
+        let pipeline = ComputePipeline::new(core, SKINNING_SHADER_PATH, &[set_layout],
+                                            mem::size_of::<u32>() as u32);
+
+        let bind_pose = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER, vertices,
+                                                   vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let positions = GpuBuffer::new(core, (mem::size_of::<f32>() * 3 * vertices.len()) as vk::DeviceSize,
+                                       vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER |
+                                           vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR |
+                                           vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                                       vk::MemoryPropertyFlags::DEVICE_LOCAL);
+
+        let frames = bones.data.len() as u32;
+        let pool_size = [
+            vk::DescriptorPoolSize::default()
+                .descriptor_count(frames * 3)
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(frames)
+            .pool_sizes(&pool_size);
+        let pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; frames as usize];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+
+        for (set, bone_buf) in sets.iter().zip(bones.data.iter()) {
+            let buffer_infos = [
+                [vk::DescriptorBufferInfo::default().buffer(bind_pose.buf).offset(0).range(vk::WHOLE_SIZE)],
+                [vk::DescriptorBufferInfo::default().buffer(*bone_buf).offset(0).range(bones.range)],
+                [vk::DescriptorBufferInfo::default().buffer(positions.buf).offset(0).range(vk::WHOLE_SIZE)]
+            ];
+            let writes: Vec<vk::WriteDescriptorSet> = buffer_infos.iter().enumerate().map(|(b, info)| {
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(b as u32)
+                    .dst_array_element(0)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(info)
+            }).collect();
+            unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+        }
+
+        ComputeSkinner {
+            pipeline,
+            set_layout,
+            pool,
+            sets,
+            bind_pose,
+            positions,
+            vertex_count: vertices.len() as u32
+        }
+    }
+
+    pub fn vertex_count(&self) -> u32 {
+        self.vertex_count
+    }
+
+    // Skins with the frame's bone matrices. The positions are ready for vertex input and acceleration structure
+    // builds recorded after this call.
+    pub fn cmd_skin(&self, core: &VkCore, command_buffer: vk::CommandBuffer, current_frame: usize) {
+        self.pipeline.cmd_bind(core, command_buffer, self.sets[current_frame]);
+        unsafe {
+            core.logical_device.cmd_push_constants(command_buffer, self.pipeline.layout,
+                                                   vk::ShaderStageFlags::COMPUTE, 0,
+                                                   cast_to_u8_slice(&self.vertex_count));
+            core.logical_device.cmd_dispatch(command_buffer, group_count(self.vertex_count, SKINNING_GROUP_SIZE), 1, 1);
+
+            let barrier = [vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::VERTEX_ATTRIBUTE_READ |
+                    vk::AccessFlags::SHADER_READ)]; // Builds read their geometry input as SHADER_READ
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::VERTEX_INPUT |
+                                                         vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                                                     vk::DependencyFlags::empty(), &barrier, &[], &[]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.positions.destroy(core);
+        self.bind_pose.destroy(core);
+        self.pipeline.destroy(core);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
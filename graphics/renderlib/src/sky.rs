@@ -0,0 +1,216 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3, Vector4};
+
+use crate::color_pipeline::ColorConstants;
+use crate::gpu_buffer::GpuBuffer;
+use crate::raster_pipeline::RasterPipeline;
+use crate::renderutils::cast_to_u8_slice;
+use crate::scene::Light;
+use crate::ssao::create_set_layout;
+use crate::vkcore::VkCore;
+
+// Optical depths of the atmosphere at the zenith for 680, 550 and 440 nm, from the appendix of Preetham et al.
+const RAYLEIGH_DEPTH: [f32; 3] = [0.0421, 0.1001, 0.2489];
+const AEROSOL_DEPTH: [f32; 3] = [1.651, 2.175, 2.907]; // Times the Ångström coefficient of the turbidity
+
+// Where and when the sky is seen. x points east, y north and z up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SkySettings {
+    pub time_of_day: f32, // Local solar time in hours, the sun is highest at 12
+    pub day_of_year: u32, // 0 is January 1st, sets the sun's declination
+    pub latitude: f32, // In degrees, negative south of the equator
+    pub turbidity: f32, // Haze of the atmosphere, 2 is a clear sky and 10 a hazy one
+    pub sky_intensity: f32, // Scales the model's luminance in kcd/m² to the renderers' exposure
+    pub sun_intensity: f32 // Of the sun light with the sun at the zenith and no haze
+}
+
+impl Default for SkySettings {
+    fn default() -> SkySettings {
+        SkySettings {
+            time_of_day: 10.0,
+            day_of_year: 80,
+            latitude: 45.0,
+            turbidity: 3.0,
+            sky_intensity: 0.05,
+            sun_intensity: 1.0
+        }
+    }
+}
+
+impl SkySettings {
+    // Normalized, pointing towards the sun
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        // Lowest at the December solstice
+        let year_angle = (360.0 / 365.0 * (self.day_of_year as f32 + 10.0)).to_radians();
+        let declination = (-23.44f32).to_radians() * year_angle.cos();
+        let (sin_d, cos_d) = declination.sin_cos();
+        let (sin_l, cos_l) = self.latitude.to_radians().sin_cos();
+        let hour_angle = ((self.time_of_day - 12.0) * 15.0).to_radians();
+
+        Vector3::new(-cos_d * hour_angle.sin(),
+                     sin_d * cos_l - cos_d * sin_l * hour_angle.cos(),
+                     sin_d * sin_l + cos_d * cos_l * hour_angle.cos()).normalize()
+    }
+
+    // What reaches the ground of the sun's light through the atmosphere, black once the sun has set
+    pub fn sun_color(&self) -> Vector3<f32> {
+        let elevation = self.sun_direction().z;
+        if elevation <= 0.0 {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+        // Kasten and Young's air mass, which stays finite at the horizon
+        let zenith_angle = elevation.acos().to_degrees();
+        let air_mass = 1.0 / (elevation + 0.50572 * (96.07995 - zenith_angle).powf(-1.6364));
+        let angstrom = (0.04608 * self.turbidity - 0.04586).max(0.0);
+        let transmittance = |c: usize| (-air_mass * (RAYLEIGH_DEPTH[c] + angstrom * AEROSOL_DEPTH[c])).exp();
+
+        Vector3::new(transmittance(0), transmittance(1), transmittance(2))
+    }
+
+    // The directional light matching the sky
+    pub fn sun_light(&self) -> Light {
+        let direction = -self.sun_direction();
+        let color = self.sun_color();
+        Light::Directional {
+            direction: [direction.x, direction.y, direction.z],
+            color: [color.x, color.y, color.z],
+            intensity: self.sun_intensity
+        }
+    }
+}
+
+// Matches SkyUniforms in sky.vert and sky.frag
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct SkyUniforms {
+    inverse_view_proj: Matrix4<f32>,
+    sun: [f32; 4],
+    settings: [f32; 4]
+}
+
+// Preetham's analytic daylight model behind the raster renderer's scene, see sky.glsl. Drawn in the forward render
+// pass after the opaque geometry, on the far plane where nothing else was drawn. Like TerrainMesh, every view has
+// its own uniforms per frame in flight.
+pub struct Sky {
+    settings: SkySettings,
+    max_frames: usize,
+    uniform_buffers: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    pipeline: RasterPipeline
+}
+
+impl Sky {
+    pub fn new(core: &VkCore, render_pass: vk::RenderPass, msaa_samples: vk::SampleCountFlags,
+               settings: SkySettings, max_frames: usize, views: usize) -> Sky {
+        let slots = max_frames * views;
+        let uniform_size = mem::size_of::<SkyUniforms>() as vk::DeviceSize;
+        let uniform_buffers: Vec<GpuBuffer> = (0..slots)
+            .map(|_| GpuBuffer::new_mapped(core, uniform_size, vk::BufferUsageFlags::UNIFORM_BUFFER))
+            .collect();
+        let mapped: Vec<*mut c_void> = uniform_buffers.iter()
+            .map(|b| unsafe {
+                core.logical_device.map_memory(b.mem, 0, uniform_size, vk::MemoryMapFlags::empty()).unwrap()
+            })
+            .collect();
+
+        let set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
+        ]);
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(slots as u32)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(slots as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; slots];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        for (set, buffer) in sets.iter().zip(uniform_buffers.iter()) {
+            let uniform_info = [vk::DescriptorBufferInfo::default()
+                .buffer(buffer.buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let write = [vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&uniform_info)];
+            unsafe { core.logical_device.update_descriptor_sets(&write, &[]) };
+        }
+
+        Sky {
+            settings,
+            max_frames,
+            uniform_buffers,
+            mapped,
+            set_layout,
+            descriptor_pool,
+            sets,
+            pipeline: RasterPipeline::new_sky(core, render_pass, set_layout, msaa_samples)
+        }
+    }
+
+    fn slot(&self, current_frame: usize, view_index: usize) -> usize {
+        view_index * self.max_frames + current_frame
+    }
+
+    // Applied from the next update
+    pub fn set_settings(&mut self, settings: SkySettings) {
+        self.settings = settings;
+    }
+
+    pub fn settings(&self) -> SkySettings {
+        self.settings
+    }
+
+    // Once the frame's fence has been waited on, with the view and projection view_index is drawn with
+    pub fn update(&self, current_frame: usize, view_index: usize, view: Matrix4<f32>, proj: Matrix4<f32>) {
+        let mut rotation = view;
+        rotation.w = Vector4::new(0.0, 0.0, 0.0, 1.0);
+        let sun = self.settings.sun_direction();
+        let uniforms = SkyUniforms {
+            inverse_view_proj: (proj * rotation).invert().unwrap(),
+            sun: [sun.x, sun.y, sun.z, self.settings.turbidity],
+            settings: [self.settings.sky_intensity, 0.0, 0.0, 0.0]
+        };
+        unsafe { (self.mapped[self.slot(current_frame, view_index)] as *mut SkyUniforms).write(uniforms) };
+    }
+
+    // Inside the forward render pass after the opaque geometry, once the viewport and scissor are set. Leaves the
+    // sky's pipeline bound.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, color_constants: &ColorConstants,
+                    current_frame: usize, view_index: usize) {
+        let layout = self.pipeline.pipeline_layout;
+        let set = self.sets[self.slot(current_frame, view_index)];
+        unsafe {
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                  self.pipeline.pipelines[0]);
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                         &[set], &[]);
+            core.logical_device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                                   cast_to_u8_slice(color_constants));
+            core.logical_device.cmd_draw(command_buffer, 3, 1, 0, 0);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+        for b in self.uniform_buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+    }
+}
@@ -0,0 +1,747 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::c_void;
+
+use ash::vk;
+use tracing::debug;
+
+use crate::gpu_buffer::{find_buf_index, GpuBuffer};
+use crate::image::create_image_view;
+use crate::sampler::{create_sampler, destroy_sampler};
+use crate::single_time::{begin_single_time_commands, end_single_time_commands};
+use crate::texture::TEXTURE_FORMAT;
+use crate::texture_streaming::{full_mip_levels, mip_extent, MipChain};
+use crate::vkcore::VkCore;
+
+pub const PAGE_TABLE_SIZE: u32 = 64; // Cells across and down at most, matches virtual_texture.glsl
+// The grid size, then a byte per cell
+const PAGE_TABLE_BYTES: usize = 16 + (PAGE_TABLE_SIZE * PAGE_TABLE_SIZE) as usize;
+const FEEDBACK_BYTES: usize = 4 * (PAGE_TABLE_SIZE * PAGE_TABLE_SIZE) as usize;
+const NOT_WANTED: u32 = u32::MAX;
+const MAX_PAGES_PER_BATCH: usize = 32;
+const PAGES_PER_CHUNK: u32 = 64; // Page memory is allocated in chunks, drivers limit the number of allocations
+const SPARSE_USAGE: vk::ImageUsageFlags = vk::ImageUsageFlags::from_raw(
+    vk::ImageUsageFlags::TRANSFER_DST.as_raw() | vk::ImageUsageFlags::SAMPLED.as_raw());
+
+// Level and page coordinates within it
+type PageKey = (u32, u32, u32);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PageState {
+    Loading,
+    Resident,
+    Evicting(usize) // Left out of the page table, unbound after the frames left
+}
+
+struct Page {
+    slot: (usize, u32), // Chunk and page within it
+    state: PageState,
+    last_used: u64 // Update that last saw it wanted
+}
+
+// Binds and copies pages, or the mip tail when pages is empty
+struct PageBatch {
+    pages: Vec<PageKey>,
+    staging: GpuBuffer,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    bind_sem: Option<vk::Semaphore>,
+    done_sem: vk::Semaphore
+}
+
+// Sampled as set 2 by RasterPipeline::new_clustered_virtual, see virtual_texture.glsl
+pub fn create_virtual_texture_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    let bindings = [
+        vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+        vk::DescriptorSetLayoutBinding::default()
+            .binding(1)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+        vk::DescriptorSetLayoutBinding::default()
+            .binding(2)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+    ];
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(&bindings);
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+// Whether sparse textures of the streaming format can be created at all
+pub fn sparse_textures_supported(core: &VkCore) -> bool {
+    core.sparse_residency_supported && !unsafe {
+        core.instance.get_physical_device_sparse_image_format_properties(core.physical_device, TEXTURE_FORMAT,
+                                                                         vk::ImageType::TYPE_2D,
+                                                                         vk::SampleCountFlags::TYPE_1, SPARSE_USAGE,
+                                                                         vk::ImageTiling::OPTIMAL)
+    }.is_empty()
+}
+
+// A partially resident texture for images too large to keep whole, like terrain textures, driven by TextureStreamer.
+// The image has its full mip chain but only the mip tail, the levels smaller than a page, is always bound. The
+// texture is split into cells, a grid of at most PAGE_TABLE_SIZE x PAGE_TABLE_SIZE over its UV space. The forward
+// pass reports the level it wants in every cell through the feedback buffer. After the frame, the pages those levels
+// need are bound to memory on the graphics queue and their texels copied in on the transfer queue, coarsest levels
+// first. The page table UBO holds the finest level of every cell whose pages are all resident, the shader never
+// samples finer. Pages that aren't wanted any more are evicted, least recently wanted first, once the budget is used.
+pub(crate) struct SparseTexture {
+    path: String,
+    width: u32,
+    height: u32,
+    mip_levels: u32,
+    tail_level: u32, // First level of the mip tail
+    granularity: vk::Extent3D, // Page size in texels
+    page_size: vk::DeviceSize,
+    memory_type: u32,
+    image: vk::Image,
+    view: vk::ImageView,
+    sampler: vk::Sampler,
+    tail_memory: Vec<vk::DeviceMemory>,
+    chunks: Vec<vk::DeviceMemory>,
+    free_slots: Vec<(usize, u32)>,
+    pages: HashMap<PageKey, Page>,
+    grid: (u32, u32), // Cells across and down
+    page_table: Vec<u8>, // Finest resident level per cell
+    page_table_buffers: Vec<GpuBuffer>, // Per frame in flight
+    feedback_buffers: Vec<GpuBuffer>,
+    mapped: Vec<(*mut c_void, *mut c_void)>, // Page table and feedback
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    chain: Option<MipChain>, // None while it is being decoded
+    tail_uploaded: bool,
+    batch: Option<PageBatch>, // At most one in flight
+    update_count: u64,
+    max_frames: usize
+}
+
+impl SparseTexture {
+    // command_pool is a graphics pool. The image is created grey with a full mip chain, and stays so until the
+    // decoded chain is set.
+    pub(crate) fn new(core: &VkCore, command_pool: vk::CommandPool, set_layout: vk::DescriptorSetLayout, path: &str,
+                      width: u32, height: u32, max_frames: usize) -> Result<SparseTexture, String> {
+        let mip_levels = full_mip_levels(width, height);
+        let mut family_indices = vec![core.graphics_family_index];
+        if core.transfer_family_index != core.graphics_family_index {
+            family_indices.push(core.transfer_family_index);
+        }
+        let image_info = vk::ImageCreateInfo::default()
+            .flags(vk::ImageCreateFlags::SPARSE_BINDING | vk::ImageCreateFlags::SPARSE_RESIDENCY)
+            .extent(vk::Extent3D::default()
+                .width(width)
+                .height(height)
+                .depth(1))
+            .mip_levels(mip_levels)
+            .image_type(vk::ImageType::TYPE_2D)
+            .array_layers(1)
+            .format(TEXTURE_FORMAT)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .initial_layout(vk::ImageLayout::UNDEFINED)
+            .usage(SPARSE_USAGE)
+            .samples(vk::SampleCountFlags::TYPE_1);
+        let image_info = match family_indices.len() {
+            1 => image_info.sharing_mode(vk::SharingMode::EXCLUSIVE),
+            _ => image_info
+                .sharing_mode(vk::SharingMode::CONCURRENT)
+                .queue_family_indices(family_indices.as_slice())
+        };
+        let image = unsafe { core.logical_device.create_image(&image_info, None).unwrap() };
+        let mem_reqs = unsafe { core.logical_device.get_image_memory_requirements(image) };
+        let sparse_reqs = unsafe { core.logical_device.get_image_sparse_memory_requirements(image) };
+        let color_reqs = sparse_reqs.iter()
+            .find(|r| r.format_properties.aspect_mask.contains(vk::ImageAspectFlags::COLOR))
+            .copied();
+        let color_reqs = match color_reqs {
+            Some(r) if r.image_mip_tail_first_lod > 0 && r.image_mip_tail_first_lod < mip_levels => r,
+            _ => {
+                unsafe { core.logical_device.destroy_image(image, None) };
+                return Err(format!("{} has no mip tail and pages both as a sparse image, stream it instead", path));
+            }
+        };
+        let memory_type = find_buf_index(core, vk::MemoryPropertyFlags::DEVICE_LOCAL, mem_reqs).unwrap();
+
+        // The mip tail, and the metadata some devices need, stay bound for the image's whole lifetime
+        let mut tail_memory: Vec<vk::DeviceMemory> = Vec::new();
+        let mut tail_binds: Vec<vk::SparseMemoryBind> = Vec::new();
+        for r in sparse_reqs.iter().filter(|r| r.image_mip_tail_size > 0) {
+            let alloc_info = vk::MemoryAllocateInfo::default()
+                .allocation_size(r.image_mip_tail_size)
+                .memory_type_index(memory_type);
+            let memory = unsafe { core.logical_device.allocate_memory(&alloc_info, None).unwrap() };
+            let flags = match r.format_properties.aspect_mask.contains(vk::ImageAspectFlags::METADATA) {
+                true => vk::SparseMemoryBindFlags::METADATA,
+                false => vk::SparseMemoryBindFlags::empty()
+            };
+            tail_binds.push(vk::SparseMemoryBind::default()
+                .resource_offset(r.image_mip_tail_offset)
+                .size(r.image_mip_tail_size)
+                .memory(memory)
+                .memory_offset(0)
+                .flags(flags));
+            tail_memory.push(memory);
+        }
+        let opaque_binds = [vk::SparseImageOpaqueMemoryBindInfo::default()
+            .image(image)
+            .binds(tail_binds.as_slice())];
+        let bind_info = vk::BindSparseInfo::default()
+            .image_opaque_binds(&opaque_binds);
+        unsafe {
+            let fence = core.logical_device.create_fence(&vk::FenceCreateInfo::default(), None).unwrap();
+            core.logical_device.queue_bind_sparse(core.graphics_queue, &[bind_info], fence).unwrap();
+            core.logical_device.wait_for_fences(&[fence], true, u64::MAX).unwrap();
+            core.logical_device.destroy_fence(fence, None);
+        }
+
+        // Pages are copied in while it is sampled, so it stays in GENERAL. Unbound levels are left undefined, the
+        // page table keeps the shader away from them.
+        let tail_level = color_reqs.image_mip_tail_first_lod;
+        let all_levels = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(mip_levels)
+            .base_array_layer(0)
+            .layer_count(1);
+        let barrier = vk::ImageMemoryBarrier::default()
+            .image(image)
+            .subresource_range(all_levels)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+        let tail_levels = all_levels
+            .base_mip_level(tail_level)
+            .level_count(mip_levels - tail_level);
+        let grey = vk::ClearColorValue {
+            float32: [0.5, 0.5, 0.5, 1.0]
+        };
+        let command_buffer = begin_single_time_commands(core, command_pool);
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                     vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                     &[], &[], &[barrier]);
+            core.logical_device.cmd_clear_color_image(command_buffer, image, vk::ImageLayout::GENERAL, &grey,
+                                                      &[tail_levels]);
+        }
+        end_single_time_commands(core, command_pool, command_buffer);
+
+        let granularity = color_reqs.format_properties.image_granularity;
+        let grid = ((width.div_ceil(granularity.width)).min(PAGE_TABLE_SIZE),
+                    (height.div_ceil(granularity.height)).min(PAGE_TABLE_SIZE));
+        let page_table = vec![tail_level as u8; (grid.0 * grid.1) as usize];
+
+        let mut page_table_buffers: Vec<GpuBuffer> = Vec::with_capacity(max_frames);
+        let mut feedback_buffers: Vec<GpuBuffer> = Vec::with_capacity(max_frames);
+        let mut mapped: Vec<(*mut c_void, *mut c_void)> = Vec::with_capacity(max_frames);
+        for _ in 0..max_frames {
+            let page_table_buffer = GpuBuffer::new(core, PAGE_TABLE_BYTES as vk::DeviceSize,
+                                                   vk::BufferUsageFlags::UNIFORM_BUFFER,
+                                                   vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                       vk::MemoryPropertyFlags::HOST_COHERENT);
+            let feedback_buffer = GpuBuffer::new(core, FEEDBACK_BYTES as vk::DeviceSize,
+                                                 vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                 vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                     vk::MemoryPropertyFlags::HOST_COHERENT);
+            let pointers = unsafe {
+                (core.logical_device.map_memory(page_table_buffer.mem, 0, page_table_buffer.size,
+                                                vk::MemoryMapFlags::empty()).unwrap(),
+                 core.logical_device.map_memory(feedback_buffer.mem, 0, feedback_buffer.size,
+                                                vk::MemoryMapFlags::empty()).unwrap())
+            };
+            unsafe {
+                std::slice::from_raw_parts_mut(pointers.1 as *mut u32, FEEDBACK_BYTES / 4).fill(NOT_WANTED);
+            }
+            page_table_buffers.push(page_table_buffer);
+            feedback_buffers.push(feedback_buffer);
+            mapped.push(pointers);
+        }
+
+        let view = create_image_view(core, image, TEXTURE_FORMAT, vk::ImageAspectFlags::COLOR, mip_levels);
+        let sampler = create_sampler(core, mip_levels);
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(max_frames as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(max_frames as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(max_frames as u32)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        for (frame, set) in sets.iter().enumerate() {
+            let image_info = [vk::DescriptorImageInfo::default()
+                .sampler(sampler)
+                .image_view(view)
+                .image_layout(vk::ImageLayout::GENERAL)];
+            let page_table_info = [vk::DescriptorBufferInfo::default()
+                .buffer(page_table_buffers[frame].buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let feedback_info = [vk::DescriptorBufferInfo::default()
+                .buffer(feedback_buffers[frame].buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let writes = [
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(&image_info),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .buffer_info(&page_table_info),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(2)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(&feedback_info)
+            ];
+            unsafe { core.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        let texture = SparseTexture {
+            path: path.to_string(),
+            width,
+            height,
+            mip_levels,
+            tail_level,
+            granularity,
+            page_size: mem_reqs.alignment,
+            memory_type,
+            image,
+            view,
+            sampler,
+            tail_memory,
+            chunks: Vec::new(),
+            free_slots: Vec::new(),
+            pages: HashMap::new(),
+            grid,
+            page_table,
+            page_table_buffers,
+            feedback_buffers,
+            mapped,
+            descriptor_pool,
+            sets,
+            chain: None,
+            tail_uploaded: false,
+            batch: None,
+            update_count: 0,
+            max_frames
+        };
+        for frame in 0..max_frames {
+            texture.write_page_table(frame);
+        }
+        debug!(path, width, height, tail_level, page_width = granularity.width, page_height = granularity.height,
+               "Created sparse texture");
+
+        Ok(texture)
+    }
+
+    pub(crate) fn set_chain(&mut self, chain: MipChain) {
+        self.chain = Some(chain);
+    }
+
+    pub(crate) fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    pub(crate) fn cmd_bind(&self, core: &VkCore, command_buffer: vk::CommandBuffer,
+                           pipeline_layout: vk::PipelineLayout, frame: usize) {
+        unsafe {
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                         pipeline_layout, 2, &[self.sets[frame]], &[]);
+        }
+    }
+
+    // Of the bound pages, the mip tail not included
+    pub(crate) fn resident_bytes(&self) -> vk::DeviceSize {
+        (self.chunks.len() as vk::DeviceSize * PAGES_PER_CHUNK as vk::DeviceSize -
+            self.free_slots.len() as vk::DeviceSize) * self.page_size
+    }
+
+    fn write_page_table(&self, frame: usize) {
+        let size = [self.grid.0, self.grid.1, 0, 0];
+        unsafe {
+            (self.mapped[frame].0 as *mut [u32; 4]).write(size);
+            (self.mapped[frame].0 as *mut u8).add(16)
+                .copy_from_nonoverlapping(self.page_table.as_ptr(), self.page_table.len());
+        }
+    }
+
+    // Texel rectangle of a page, pages at the right and bottom edges are cut off by the level's size
+    fn page_rect(&self, (level, x, y): PageKey) -> (vk::Offset3D, vk::Extent3D) {
+        let (level_width, level_height) = mip_extent(self.width, self.height, level);
+        let offset = vk::Offset3D::default()
+            .x((x * self.granularity.width) as i32)
+            .y((y * self.granularity.height) as i32);
+        let extent = vk::Extent3D::default()
+            .width(self.granularity.width.min(level_width - x * self.granularity.width))
+            .height(self.granularity.height.min(level_height - y * self.granularity.height))
+            .depth(1);
+
+        (offset, extent)
+    }
+
+    // Pages at level that cover cell
+    fn cell_pages(&self, cell: u32, level: u32) -> Vec<PageKey> {
+        let (level_width, level_height) = mip_extent(self.width, self.height, level);
+        let (cell_x, cell_y) = (cell % self.grid.0, cell / self.grid.0);
+        let x_texels = (cell_x * level_width / self.grid.0, ((cell_x + 1) * level_width).div_ceil(self.grid.0));
+        let y_texels = (cell_y * level_height / self.grid.1, ((cell_y + 1) * level_height).div_ceil(self.grid.1));
+        let mut pages: Vec<PageKey> = Vec::new();
+        for y in y_texels.0 / self.granularity.height..=(y_texels.1.max(1) - 1) / self.granularity.height {
+            for x in x_texels.0 / self.granularity.width..=(x_texels.1.max(1) - 1) / self.granularity.width {
+                pages.push((level, x, y));
+            }
+        }
+
+        pages
+    }
+
+    fn update_page_table(&mut self) {
+        for cell in 0..self.grid.0 * self.grid.1 {
+            let mut finest = self.tail_level;
+            while finest > 0 && self.cell_pages(cell, finest - 1).iter()
+                .all(|p| self.pages.get(p).is_some_and(|p| p.state == PageState::Resident)) {
+                finest -= 1;
+            }
+            self.page_table[cell as usize] = finest as u8;
+        }
+    }
+
+    // A free page of memory, None when the budget is used up
+    fn allocate_slot(&mut self, core: &VkCore, budget: vk::DeviceSize) -> Option<(usize, u32)> {
+        if let Some(slot) = self.free_slots.pop() {
+            return Some(slot);
+        }
+        let chunk_size = self.page_size * PAGES_PER_CHUNK as vk::DeviceSize;
+        if self.resident_bytes() + chunk_size > budget {
+            return None;
+        }
+        let alloc_info = vk::MemoryAllocateInfo::default()
+            .allocation_size(chunk_size)
+            .memory_type_index(self.memory_type);
+        let memory = unsafe { core.logical_device.allocate_memory(&alloc_info, None).unwrap() };
+        self.chunks.push(memory);
+        let chunk = self.chunks.len() - 1;
+        self.free_slots.extend((1..PAGES_PER_CHUNK).rev().map(|p| (chunk, p)));
+
+        Some((chunk, 0))
+    }
+
+    // Once per frame, after the frame's fence was waited on. budget caps the page memory, the mip tail isn't part of
+    // it. Returns the semaphores of the copies that finished, for TextureStreamer::take_waits.
+    pub(crate) fn update(&mut self, core: &VkCore, transfer_pool: vk::CommandPool, frame: usize,
+                         budget: vk::DeviceSize) -> Vec<vk::Semaphore> {
+        self.update_count += 1;
+        let mut finished: Vec<vk::Semaphore> = Vec::new();
+        let mut page_table_changed = false;
+
+        // A lost device never finishes its batch, it is destroyed with the texture
+        let batch_done = self.batch.as_ref()
+            .is_some_and(|b| matches!(unsafe { core.logical_device.get_fence_status(b.fence) }, Ok(true)));
+        if batch_done {
+            let batch = self.batch.take().unwrap();
+            for key in batch.pages.iter() {
+                if let Some(page) = self.pages.get_mut(key) {
+                    page.state = PageState::Resident;
+                }
+            }
+            page_table_changed = !batch.pages.is_empty();
+            unsafe {
+                core.logical_device.destroy_fence(batch.fence, None);
+                core.logical_device.free_command_buffers(transfer_pool, &[batch.command_buffer]);
+                if let Some(sem) = batch.bind_sem {
+                    core.logical_device.destroy_semaphore(sem, None);
+                }
+            }
+            batch.staging.destroy(core);
+            finished.push(batch.done_sem);
+        }
+
+        let mut unbinds: Vec<PageKey> = Vec::new();
+        for (key, page) in self.pages.iter_mut() {
+            if let PageState::Evicting(frames_left) = page.state {
+                match frames_left.saturating_sub(1) {
+                    0 => unbinds.push(*key),
+                    left => page.state = PageState::Evicting(left)
+                }
+            }
+        }
+
+        // What the frames using this frame's buffer wanted, the pages those levels need are kept or loaded
+        let feedback = unsafe {
+            std::slice::from_raw_parts_mut(self.mapped[frame].1 as *mut u32, (self.grid.0 * self.grid.1) as usize)
+        };
+        let mut missing: HashSet<PageKey> = HashSet::new();
+        for (cell, wanted) in feedback.iter_mut().enumerate() {
+            let level = *wanted;
+            *wanted = NOT_WANTED;
+            for level in level..self.tail_level {
+                for key in self.cell_pages(cell as u32, level) {
+                    match self.pages.get_mut(&key) {
+                        Some(page) => {
+                            page.last_used = self.update_count;
+                            if let PageState::Evicting(_) = page.state {
+                                page.state = PageState::Resident; // Still bound
+                                page_table_changed = true;
+                            }
+                        },
+                        None => {
+                            missing.insert(key);
+                        }
+                    }
+                }
+            }
+        }
+        unbinds.retain(|key| matches!(self.pages[key].state, PageState::Evicting(_)));
+
+        // Coarsest first, so that every cell improves a level at a time
+        let mut loads: Vec<PageKey> = Vec::new();
+        if self.batch.is_none() && self.chain.is_some() && self.tail_uploaded {
+            let mut missing: Vec<PageKey> = missing.into_iter().collect();
+            missing.sort_by(|a, b| b.cmp(a));
+            for key in missing.into_iter().take(MAX_PAGES_PER_BATCH) {
+                let slot = match self.allocate_slot(core, budget) {
+                    Some(slot) => slot,
+                    None => {
+                        // Evicts the least recently wanted pages, their memory is free once no frame can sample them
+                        let mut victims: Vec<(PageKey, u64)> = self.pages.iter()
+                            .filter(|(_, p)| p.state == PageState::Resident && p.last_used < self.update_count)
+                            .map(|(k, p)| (*k, p.last_used))
+                            .collect();
+                        victims.sort_by_key(|&(_, last_used)| last_used);
+                        for (victim, _) in victims.into_iter().take(MAX_PAGES_PER_BATCH - loads.len()) {
+                            self.pages.get_mut(&victim).unwrap().state = PageState::Evicting(self.max_frames);
+                            page_table_changed = true;
+                        }
+                        break;
+                    }
+                };
+                self.pages.insert(key, Page {
+                    slot,
+                    state: PageState::Loading,
+                    last_used: self.update_count
+                });
+                loads.push(key);
+            }
+        }
+
+        // The slot can be bound again right away, nothing samples the unbound page
+        for key in unbinds.iter() {
+            let page = self.pages.remove(key).unwrap();
+            self.free_slots.push(page.slot);
+        }
+        if !loads.is_empty() || !unbinds.is_empty() {
+            self.bind_pages(core, transfer_pool, loads, unbinds);
+        } else if !self.tail_uploaded && self.chain.is_some() && self.batch.is_none() {
+            self.upload_tail(core, transfer_pool);
+        }
+
+        if page_table_changed {
+            self.update_page_table();
+        }
+        self.write_page_table(frame);
+
+        finished
+    }
+
+    fn bind_pages(&mut self, core: &VkCore, transfer_pool: vk::CommandPool, loads: Vec<PageKey>,
+                  unbinds: Vec<PageKey>) {
+        let mut binds: Vec<vk::SparseImageMemoryBind> = Vec::with_capacity(loads.len() + unbinds.len());
+        for key in loads.iter() {
+            let (chunk, page) = self.pages[key].slot;
+            let (offset, extent) = self.page_rect(*key);
+            binds.push(vk::SparseImageMemoryBind::default()
+                .subresource(vk::ImageSubresource::default()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(key.0)
+                    .array_layer(0))
+                .offset(offset)
+                .extent(extent)
+                .memory(self.chunks[chunk])
+                .memory_offset(page as vk::DeviceSize * self.page_size));
+        }
+        for key in unbinds.iter() {
+            let (offset, extent) = self.page_rect(*key);
+            binds.push(vk::SparseImageMemoryBind::default()
+                .subresource(vk::ImageSubresource::default()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .mip_level(key.0)
+                    .array_layer(0))
+                .offset(offset)
+                .extent(extent)
+                .memory(vk::DeviceMemory::null()));
+        }
+        let image_binds = [vk::SparseImageMemoryBindInfo::default()
+            .image(self.image)
+            .binds(binds.as_slice())];
+        let bind_sem = match loads.is_empty() {
+            true => None,
+            false => Some(unsafe {
+                core.logical_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None).unwrap()
+            })
+        };
+        let sig_sems: Vec<vk::Semaphore> = bind_sem.into_iter().collect();
+        let bind_info = vk::BindSparseInfo::default()
+            .image_binds(&image_binds)
+            .signal_semaphores(sig_sems.as_slice());
+        unsafe {
+            core.logical_device.queue_bind_sparse(core.graphics_queue, &[bind_info], vk::Fence::null()).unwrap();
+        }
+        debug!(path = self.path.as_str(), loads = loads.len(), unbinds = unbinds.len(), "Bound sparse pages");
+
+        if let Some(bind_sem) = bind_sem {
+            let regions: Vec<(PageKey, vk::Offset3D, vk::Extent3D)> = loads.iter()
+                .map(|key| {
+                    let (offset, extent) = self.page_rect(*key);
+                    (*key, offset, extent)
+                })
+                .collect();
+            self.batch = Some(self.copy_regions(core, transfer_pool, regions.as_slice(), Some(bind_sem), loads));
+        }
+    }
+
+    // The mip tail is bound from the start, it only waits for the decoded chain
+    fn upload_tail(&mut self, core: &VkCore, transfer_pool: vk::CommandPool) {
+        let regions: Vec<(PageKey, vk::Offset3D, vk::Extent3D)> = (self.tail_level..self.mip_levels)
+            .map(|level| {
+                let (width, height) = mip_extent(self.width, self.height, level);
+                ((level, 0, 0), vk::Offset3D::default(), vk::Extent3D::default()
+                    .width(width)
+                    .height(height)
+                    .depth(1))
+            })
+            .collect();
+        self.batch = Some(self.copy_regions(core, transfer_pool, regions.as_slice(), None, Vec::new()));
+        self.tail_uploaded = true;
+    }
+
+    // Copies the texels of regions from the decoded chain on the transfer queue, after bind_sem
+    fn copy_regions(&self, core: &VkCore, transfer_pool: vk::CommandPool,
+                    regions: &[(PageKey, vk::Offset3D, vk::Extent3D)], bind_sem: Option<vk::Semaphore>,
+                    pages: Vec<PageKey>) -> PageBatch {
+        let chain = self.chain.as_ref().unwrap();
+        let size: vk::DeviceSize = regions.iter()
+            .map(|(_, _, e)| e.width as vk::DeviceSize * e.height as vk::DeviceSize * 4)
+            .sum();
+        let staging = GpuBuffer::new(core, size, vk::BufferUsageFlags::TRANSFER_SRC,
+                                     vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let mut copies: Vec<vk::BufferImageCopy> = Vec::with_capacity(regions.len());
+        unsafe {
+            let mapped = staging.map(core) as *mut u8;
+            let mut offset = 0;
+            for &((level, _, _), image_offset, extent) in regions {
+                let (level_width, _) = mip_extent(self.width, self.height, level);
+                let pixels = &chain.levels[level as usize];
+                let row_bytes = (extent.width * 4) as usize;
+                for row in 0..extent.height {
+                    let src = ((image_offset.y as u32 + row) * level_width + image_offset.x as u32) as usize * 4;
+                    mapped.add(offset + row as usize * row_bytes)
+                        .copy_from_nonoverlapping(pixels[src..].as_ptr(), row_bytes);
+                }
+                copies.push(vk::BufferImageCopy::default()
+                    .buffer_offset(offset as vk::DeviceSize)
+                    .buffer_row_length(0) // Tightly packed
+                    .buffer_image_height(0)
+                    .image_subresource(vk::ImageSubresourceLayers::default()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .mip_level(level)
+                        .base_array_layer(0)
+                        .layer_count(1))
+                    .image_offset(image_offset)
+                    .image_extent(extent));
+                offset += row_bytes * extent.height as usize;
+            }
+            staging.flush(core, 0..size);
+            core.logical_device.unmap_memory(staging.mem);
+        }
+
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(transfer_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let (command_buffer, fence, done_sem) = unsafe {
+            (core.logical_device.allocate_command_buffers(&alloc_info).unwrap()[0],
+             core.logical_device.create_fence(&vk::FenceCreateInfo::default(), None).unwrap(),
+             core.logical_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None).unwrap())
+        };
+        let wait_sems: Vec<vk::Semaphore> = bind_sem.into_iter().collect();
+        let wait_stages = vec![vk::PipelineStageFlags::TRANSFER; wait_sems.len()];
+        let command_buffers = [command_buffer];
+        let sig_sems = [done_sem];
+        let submit_info = [vk::SubmitInfo::default()
+            .wait_semaphores(wait_sems.as_slice())
+            .wait_dst_stage_mask(wait_stages.as_slice())
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&sig_sems)];
+        unsafe {
+            core.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+            core.logical_device.cmd_copy_buffer_to_image(command_buffer, staging.buf, self.image,
+                                                         vk::ImageLayout::GENERAL, copies.as_slice());
+            core.logical_device.end_command_buffer(command_buffer).unwrap();
+            core.logical_device.queue_submit(core.transfer_queue, &submit_info, fence).unwrap();
+        }
+
+        PageBatch {
+            pages,
+            staging,
+            command_buffer,
+            fence,
+            bind_sem,
+            done_sem
+        }
+    }
+
+    // Doesn't wait for the device, which may be lost
+    pub(crate) fn destroy(&self, core: &VkCore, transfer_pool: vk::CommandPool) {
+        if let Some(batch) = self.batch.as_ref() {
+            batch.staging.destroy(core);
+            unsafe {
+                core.logical_device.destroy_fence(batch.fence, None);
+                core.logical_device.destroy_semaphore(batch.done_sem, None);
+                if let Some(sem) = batch.bind_sem {
+                    core.logical_device.destroy_semaphore(sem, None);
+                }
+                core.logical_device.free_command_buffers(transfer_pool, &[batch.command_buffer]);
+            }
+        }
+        for (page_table_buffer, feedback_buffer) in self.page_table_buffers.iter().zip(self.feedback_buffers.iter()) {
+            unsafe {
+                core.logical_device.unmap_memory(page_table_buffer.mem);
+                core.logical_device.unmap_memory(feedback_buffer.mem);
+            }
+            page_table_buffer.destroy(core);
+            feedback_buffer.destroy(core);
+        }
+        destroy_sampler(core, self.sampler);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_image_view(self.view, None);
+            core.logical_device.destroy_image(self.image, None);
+            for memory in self.tail_memory.iter().chain(self.chunks.iter()) {
+                core.logical_device.free_memory(*memory, None);
+            }
+        }
+    }
+}
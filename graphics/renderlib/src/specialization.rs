@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use ash::vk;
+
+// The value of a specialization constant, its type must match the constant's declaration in the shader. Compared and
+// hashed by its bits, so that it can key a PipelineDesc.
+#[derive(Clone, Copy, Debug)]
+pub enum SpecValue {
+    Bool(bool), // Passed as a VkBool32
+    U32(u32),
+    I32(i32),
+    F32(f32)
+}
+
+impl SpecValue {
+    fn bytes(&self) -> [u8; 4] {
+        match *self {
+            SpecValue::Bool(b) => (b as vk::Bool32).to_ne_bytes(),
+            SpecValue::U32(u) => u.to_ne_bytes(),
+            SpecValue::I32(i) => i.to_ne_bytes(),
+            SpecValue::F32(f) => f.to_ne_bytes()
+        }
+    }
+}
+
+impl PartialEq for SpecValue {
+    fn eq(&self, other: &SpecValue) -> bool {
+        mem::discriminant(self) == mem::discriminant(other) && self.bytes() == other.bytes()
+    }
+}
+
+impl Eq for SpecValue {}
+
+impl Hash for SpecValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        self.bytes().hash(state);
+    }
+}
+
+impl From<bool> for SpecValue {
+    fn from(b: bool) -> SpecValue {
+        SpecValue::Bool(b)
+    }
+}
+
+impl From<u32> for SpecValue {
+    fn from(u: u32) -> SpecValue {
+        SpecValue::U32(u)
+    }
+}
+
+impl From<i32> for SpecValue {
+    fn from(i: i32) -> SpecValue {
+        SpecValue::I32(i)
+    }
+}
+
+impl From<f32> for SpecValue {
+    fn from(f: f32) -> SpecValue {
+        SpecValue::F32(f)
+    }
+}
+
+// Values for the layout(constant_id = ...) constants of a pipeline's shaders, by constant_id. The same values are
+// passed to every stage, constants a stage doesn't declare are ignored by it and the ones left out keep the default
+// from the shader. That way one SPIR-V file gives several variants without compiling the GLSL again.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SpecConstants {
+    values: BTreeMap<u32, SpecValue> // Ordered so that equal maps give equal SpecializationInfo data
+}
+
+impl SpecConstants {
+    pub fn new() -> SpecConstants {
+        SpecConstants::default()
+    }
+
+    // For building the map in one expression, like SpecConstants::new().with(0, 4u32).with(1, true)
+    pub fn with(mut self, constant_id: u32, value: impl Into<SpecValue>) -> SpecConstants {
+        self.set(constant_id, value);
+        self
+    }
+
+    pub fn set(&mut self, constant_id: u32, value: impl Into<SpecValue>) {
+        self.values.insert(constant_id, value.into());
+    }
+
+    pub fn remove(&mut self, constant_id: u32) -> Option<SpecValue> {
+        self.values.remove(&constant_id)
+    }
+
+    pub fn get(&self, constant_id: u32) -> Option<SpecValue> {
+        self.values.get(&constant_id).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // The map entries and packed values, which have to outlive the pipeline creation that uses SpecData::info
+    pub fn data(&self) -> SpecData {
+        let size = mem::size_of::<u32>(); // Every SpecValue is 32 bits wide
+        let entries = self.values.keys().enumerate()
+            .map(|(i, &constant_id)| vk::SpecializationMapEntry::default()
+                .constant_id(constant_id)
+                .offset((i * size) as u32)
+                .size(size))
+            .collect();
+        let data = self.values.values().flat_map(|v| v.bytes()).collect();
+
+        SpecData {
+            entries,
+            data
+        }
+    }
+}
+
+pub struct SpecData {
+    entries: Vec<vk::SpecializationMapEntry>,
+    data: Vec<u8>
+}
+
+impl SpecData {
+    // None without any constants, so that stages can leave their specialization info out
+    pub fn info(&self) -> Option<vk::SpecializationInfo<'_>> {
+        match self.entries.is_empty() {
+            true => None,
+            false => Some(vk::SpecializationInfo::default()
+                .map_entries(self.entries.as_slice())
+                .data(self.data.as_slice()))
+        }
+    }
+}
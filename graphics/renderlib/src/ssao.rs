@@ -0,0 +1,445 @@
+use std::mem;
+
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, Vector3};
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+use crate::compute::{ComputePipeline, group_count};
+use crate::frame_buffers::destroy_frame_buffers;
+use crate::gpu_buffer::GpuBuffer;
+use crate::image::StorageImage;
+use crate::raster_pipeline::RasterPipeline;
+use crate::render_target::RenderTarget;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_nearest_sampler, destroy_sampler};
+use crate::texture::Texture;
+use crate::vkcore::VkCore;
+
+pub const SSAO_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::O;
+const OCCLUSION_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+const SSAO_SHADER_PATH: &str = "graphics/shaders/spv/ssao.spv";
+const SSAO_MS_SHADER_PATH: &str = "graphics/shaders/spv/ssao_ms.spv";
+const BLUR_SHADER_PATH: &str = "graphics/shaders/spv/ssao_blur.spv";
+const APPLY_SHADER_PATHS: [&str; 2] = ["graphics/shaders/spv/fullscreen_vert.spv",
+    "graphics/shaders/spv/ssao_apply_frag.spv"];
+const SSAO_GROUP_SIZE: u32 = 8; // Matches local_size_x/y in ssao.glsl and ssao_blur.comp
+const KERNEL_SIZE: usize = 16; // Must match ssao.glsl
+const NOISE_SIZE: u32 = 4; // The blur averages NOISE_SIZE squared pixels, which hides the rotation pattern
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    pub radius: f32, // View space radius of the sampled hemisphere
+    pub bias: f32, // Depth difference below which a sample doesn't occlude, avoids self occlusion on flat surfaces
+    pub intensity: f32 // Exponent applied to the unoccluded share, higher is darker
+}
+
+impl Default for SsaoSettings {
+    fn default() -> SsaoSettings {
+        SsaoSettings {
+            enabled: true,
+            radius: 0.5,
+            bias: 0.025,
+            intensity: 1.5
+        }
+    }
+}
+
+// Matches the push constants in ssao.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct SsaoConstants {
+    projection: [f32; 4], // proj[0][0], proj[1][1], proj[2][2] and proj[3][2], all that is needed for a perspective
+    settings: [f32; 4] // radius, bias, intensity
+}
+
+// Deterministic values in [0, 1), the kernel and noise don't need to change between runs
+fn hash_unit(i: u32) -> f32 {
+    let mut x = i.wrapping_mul(0x9e37_79b9);
+    x = (x ^ (x >> 16)).wrapping_mul(0x85eb_ca6b);
+    x = (x ^ (x >> 13)).wrapping_mul(0xc2b2_ae35);
+    (x ^ (x >> 16)) as f32 / 4_294_967_296.0
+}
+
+// Points in the unit hemisphere around +z, more of them close to the center where occluders matter most
+fn hemisphere_kernel() -> Vec<[f32; 4]> {
+    (0..KERNEL_SIZE as u32)
+        .map(|i| {
+            let direction = Vector3::new(hash_unit(i * 3) * 2.0 - 1.0, hash_unit(i * 3 + 1) * 2.0 - 1.0,
+                                         hash_unit(i * 3 + 2)).normalize();
+            let t = i as f32 / KERNEL_SIZE as f32;
+            let v = direction * (0.1 + 0.9 * t * t);
+            [v.x, v.y, v.z, 0.0]
+        })
+        .collect()
+}
+
+// Random rotations around the surface normal, tiled over the screen
+fn rotation_noise() -> Vec<f32> {
+    (0..NOISE_SIZE * NOISE_SIZE)
+        .flat_map(|i| {
+            let seed = 0x1000 + i * 2;
+            [hash_unit(seed) * 2.0 - 1.0, hash_unit(seed + 1) * 2.0 - 1.0, 0.0, 0.0]
+        })
+        .collect()
+}
+
+fn create_occlusion_image(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D) -> StorageImage {
+    StorageImage::new(core, command_pool, extent, OCCLUSION_FORMAT, vk::ImageUsageFlags::empty())
+}
+
+pub(crate) fn create_set_layout(core: &VkCore, bindings: &[(vk::DescriptorType, vk::ShaderStageFlags)])
+    -> vk::DescriptorSetLayout {
+    let bindings: Vec<vk::DescriptorSetLayoutBinding> = bindings.iter().enumerate()
+        .map(|(b, &(ty, stages))| vk::DescriptorSetLayoutBinding::default()
+            .binding(b as u32)
+            .descriptor_type(ty)
+            .descriptor_count(1)
+            .stage_flags(stages))
+        .collect();
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(bindings.as_slice());
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+// Overwrites the resolved swap chain image, which the main render pass left in PRESENT_SRC_KHR
+pub(crate) fn create_apply_render_pass(core: &VkCore, render_target: &RenderTarget) -> vk::RenderPass {
+    let attachment_desc = [vk::AttachmentDescription::default()
+        .format(render_target.surface_format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::LOAD)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)];
+    let attachment_ref = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+    let subpass = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&attachment_ref)];
+    // The main render pass' resolve writes
+    let dependencies = [vk::SubpassDependency::default()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(0)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dependency_flags(vk::DependencyFlags::empty())];
+    let render_pass_create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachment_desc)
+        .subpasses(&subpass)
+        .dependencies(&dependencies);
+
+    unsafe { core.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+}
+
+pub(crate) fn create_apply_frame_buffers(core: &VkCore, render_pass: vk::RenderPass, render_target: &RenderTarget)
+    -> Vec<vk::Framebuffer> {
+    render_target.image_views.iter()
+        .map(|v| {
+            let attachments = [*v];
+            let create_info = vk::FramebufferCreateInfo::default()
+                .render_pass(render_pass)
+                .attachments(&attachments)
+                .width(render_target.extent.width)
+                .height(render_target.extent.height)
+                .layers(1);
+
+            unsafe { core.logical_device.create_framebuffer(&create_info, None).unwrap() }
+        })
+        .collect()
+}
+
+// Screen-space ambient occlusion for the raster renderer. cmd_apply, after the main render pass:
+// 1. Reconstructs view space positions and normals from the depth buffer, and counts how many points of a
+//    hemisphere around each surface lie behind the depth buffer. The hemisphere is rotated per pixel by a tiled
+//    noise texture, which trades banding for high frequency noise.
+// 2. Blurs the result over the noise tile.
+// 3. Multiplies the resolved swap chain image by it. The raster renderer draws unlit textures, so all of its light is
+//    ambient and the whole color is darkened.
+// The depth buffer must come from Depth::new_sampled and the render pass from setup_render_pass_stored_depth.
+pub struct Ssao {
+    settings: SsaoSettings,
+    ssao_set_layout: vk::DescriptorSetLayout,
+    blur_set_layout: vk::DescriptorSetLayout,
+    apply_set_layout: vk::DescriptorSetLayout,
+    ssao: ComputePipeline,
+    blur: ComputePipeline,
+    apply_render_pass: vk::RenderPass,
+    apply: RasterPipeline,
+    apply_frame_buffers: Vec<vk::Framebuffer>,
+    descriptor_pool: vk::DescriptorPool,
+    ssao_set: vk::DescriptorSet,
+    blur_set: vk::DescriptorSet,
+    apply_set: vk::DescriptorSet,
+    occlusion: StorageImage, // Written by the SSAO pass
+    blurred: StorageImage, // Written by the blur pass
+    depth_sampler: vk::Sampler,
+    kernel: GpuBuffer,
+    noise: Texture,
+    extent: vk::Extent2D
+}
+
+impl Ssao {
+    // depth_samples is the sample count of the depth buffer
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget, depth_view: vk::ImageView,
+               depth_samples: vk::SampleCountFlags, settings: SsaoSettings) -> Ssao {
+        let ssao_set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE), // Depth buffer
+            (vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE), // Occlusion
+            (vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::COMPUTE), // Kernel
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::COMPUTE) // Rotation noise
+        ]);
+        let blur_set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE), // Occlusion
+            (vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE) // Blurred occlusion
+        ]);
+        let apply_set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::FRAGMENT) // Blurred occlusion
+        ]);
+        let ssao_shader = match depth_samples {
+            vk::SampleCountFlags::TYPE_1 => SSAO_SHADER_PATH,
+            _ => SSAO_MS_SHADER_PATH
+        };
+        let ssao = ComputePipeline::new(core, ssao_shader, &[ssao_set_layout],
+                                        mem::size_of::<SsaoConstants>() as u32);
+        let blur = ComputePipeline::new(core, BLUR_SHADER_PATH, &[blur_set_layout], 0);
+        let apply_render_pass = create_apply_render_pass(core, render_target);
+        let apply = RasterPipeline::new_fullscreen(core, apply_render_pass, apply_set_layout, &APPLY_SHADER_PATHS);
+        let kernel = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                hemisphere_kernel().as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let noise = Texture::from_rgba32f(core, command_pool, NOISE_SIZE, NOISE_SIZE, &rotation_noise());
+
+        let mut ssao = Ssao {
+            settings,
+            ssao_set_layout,
+            blur_set_layout,
+            apply_set_layout,
+            ssao,
+            blur,
+            apply_render_pass,
+            apply,
+            apply_frame_buffers: create_apply_frame_buffers(core, apply_render_pass, render_target),
+            descriptor_pool: vk::DescriptorPool::null(),
+            ssao_set: vk::DescriptorSet::null(),
+            blur_set: vk::DescriptorSet::null(),
+            apply_set: vk::DescriptorSet::null(),
+            occlusion: create_occlusion_image(core, command_pool, render_target.extent),
+            blurred: create_occlusion_image(core, command_pool, render_target.extent),
+            depth_sampler: create_nearest_sampler(core),
+            kernel,
+            noise,
+            extent: render_target.extent
+        };
+        ssao.write_descriptor_sets(core, depth_view);
+
+        ssao
+    }
+
+    fn write_descriptor_sets(&mut self, core: &VkCore, depth_view: vk::ImageView) {
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(2),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(4),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(3)
+            .pool_sizes(&pool_sizes);
+        self.descriptor_pool = unsafe {
+            core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap()
+        };
+
+        let layouts = [self.ssao_set_layout, self.blur_set_layout, self.apply_set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        (self.ssao_set, self.blur_set, self.apply_set) = (sets[0], sets[1], sets[2]);
+
+        let depth_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.depth_sampler)
+            .image_view(depth_view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+        let noise_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.depth_sampler)
+            .image_view(self.noise.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let kernel_info = [vk::DescriptorBufferInfo::default()
+            .buffer(self.kernel.buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)];
+        let (occlusion_info, blurred_info) = (self.occlusion.info(), self.blurred.info());
+        let mut writes = vec![
+            vk::WriteDescriptorSet::default()
+                .dst_set(self.ssao_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&depth_info),
+            vk::WriteDescriptorSet::default()
+                .dst_set(self.ssao_set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&kernel_info),
+            vk::WriteDescriptorSet::default()
+                .dst_set(self.ssao_set)
+                .dst_binding(3)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(&noise_info)
+        ];
+        for (set, binding, info) in [(self.ssao_set, 1, &occlusion_info), (self.blur_set, 0, &occlusion_info),
+                                     (self.blur_set, 1, &blurred_info), (self.apply_set, 0, &blurred_info)] {
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(info));
+        }
+
+        unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+    }
+
+    // Call after the swap chain and depth buffer were recreated
+    pub fn resize(&mut self, core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget,
+                  depth_view: vk::ImageView) {
+        unsafe { core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None) };
+        destroy_frame_buffers(core, &self.apply_frame_buffers);
+        self.occlusion.destroy(core);
+        self.blurred.destroy(core);
+        self.apply_frame_buffers = create_apply_frame_buffers(core, self.apply_render_pass, render_target);
+        self.occlusion = create_occlusion_image(core, command_pool, render_target.extent);
+        self.blurred = create_occlusion_image(core, command_pool, render_target.extent);
+        self.extent = render_target.extent;
+        self.write_descriptor_sets(core, depth_view);
+    }
+
+    pub fn settings(&self) -> SsaoSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: SsaoSettings) {
+        self.settings = settings;
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.settings.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.enabled
+    }
+
+    // Toggles SSAO on SSAO_TOGGLE_KEY
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        if let WindowEvent::KeyboardInput {
+            input: KeyboardInput { state: ElementState::Pressed, virtual_keycode: Some(SSAO_TOGGLE_KEY), .. },
+            ..
+        } = event {
+            self.set_enabled(!self.settings.enabled);
+        }
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> &'static str {
+        match self.settings.enabled {
+            true => "ssao on",
+            false => "ssao off"
+        }
+    }
+
+    // Records all three passes, after the main render pass has ended. projection must be the one the depth buffer
+    // was drawn with. Records nothing while disabled.
+    pub fn cmd_apply(&self, core: &VkCore, command_buffer: vk::CommandBuffer, image_index: u32,
+                     projection: Matrix4<f32>) {
+        if !self.settings.enabled {
+            return;
+        }
+        let constants = [SsaoConstants {
+            projection: [projection.x.x, projection.y.y, projection.z.z, projection.w.z],
+            settings: [self.settings.radius, self.settings.bias, self.settings.intensity.max(0.0), 0.0]
+        }];
+        // Depth writes of the render pass, and the previous frame's reads of the images about to be written
+        let depth_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let blur_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.extent);
+        let viewports = [vk::Viewport::default()
+            .x(0.0)
+            .y(0.0)
+            .width(self.extent.width as f32)
+            .height(self.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.apply_render_pass)
+            .framebuffer(self.apply_frame_buffers[image_index as usize])
+            .render_area(render_area);
+        let (groups_x, groups_y) = (group_count(self.extent.width, SSAO_GROUP_SIZE),
+                                    group_count(self.extent.height, SSAO_GROUP_SIZE));
+        let logical_device = &core.logical_device;
+
+        unsafe {
+            logical_device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::LATE_FRAGMENT_TESTS |
+                                                    vk::PipelineStageFlags::FRAGMENT_SHADER |
+                                                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                vk::DependencyFlags::empty(), &[depth_barrier], &[], &[]);
+            self.ssao.cmd_bind(core, command_buffer, self.ssao_set);
+            logical_device.cmd_push_constants(command_buffer, self.ssao.layout, vk::ShaderStageFlags::COMPUTE, 0,
+                                              cast_to_u8_slice(&constants));
+            logical_device.cmd_dispatch(command_buffer, groups_x, groups_y, 1);
+            logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                vk::DependencyFlags::empty(), &[blur_barrier], &[], &[]);
+            self.blur.cmd_bind(core, command_buffer, self.blur_set);
+            logical_device.cmd_dispatch(command_buffer, groups_x, groups_y, 1);
+            logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                vk::DependencyFlags::empty(), &[blur_barrier], &[], &[]);
+
+            logical_device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                             self.apply.pipelines[0]);
+            logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                    self.apply.pipeline_layout, 0, &[self.apply_set], &[]);
+            logical_device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            logical_device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.ssao_set_layout, None);
+            core.logical_device.destroy_descriptor_set_layout(self.blur_set_layout, None);
+            core.logical_device.destroy_descriptor_set_layout(self.apply_set_layout, None);
+            core.logical_device.destroy_render_pass(self.apply_render_pass, None);
+        }
+        destroy_frame_buffers(core, &self.apply_frame_buffers);
+        destroy_sampler(core, self.depth_sampler);
+        self.ssao.destroy(core);
+        self.blur.destroy(core);
+        self.apply.destroy(core);
+        self.occlusion.destroy(core);
+        self.blurred.destroy(core);
+        self.kernel.destroy(core);
+        self.noise.destroy(core);
+    }
+}
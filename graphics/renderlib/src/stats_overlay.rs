@@ -0,0 +1,80 @@
+use std::time::{Duration, Instant};
+
+use winit::window::Window;
+
+use crate::benchmark::FrameStats;
+use crate::profiler::PipelineStatistics;
+
+// Values are averaged over this long so that they're readable
+const REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+
+// Frame statistics shown in the window title
+pub struct StatsOverlay {
+    title: String,
+    frames: u32,
+    frame_ms: f64,
+    cpu_ms: f64,
+    gpu_ms: f64,
+    gpu_frames: u32, // GPU times aren't available for the first frames in flight
+    pipeline: PipelineStatistics, // Summed over all passes and frames
+    pipeline_frames: u32,
+    last_refresh: Instant
+}
+
+impl StatsOverlay {
+    // title is shown in front of the statistics
+    pub fn new(title: &str) -> StatsOverlay {
+        StatsOverlay {
+            title: String::from(title),
+            frames: 0,
+            frame_ms: 0.0,
+            cpu_ms: 0.0,
+            gpu_ms: 0.0,
+            gpu_frames: 0,
+            pipeline: PipelineStatistics::default(),
+            pipeline_frames: 0,
+            last_refresh: Instant::now()
+        }
+    }
+
+    // extra is appended after the timings, e.g. LatencyGovernor::status
+    pub fn record(&mut self, window: &Window, stats: &FrameStats, extra: &str) {
+        self.frames += 1;
+        self.frame_ms += stats.frame_ms;
+        self.cpu_ms += stats.cpu_ms;
+        if let Some(gpu_ms) = stats.gpu_ms {
+            self.gpu_ms += gpu_ms;
+            self.gpu_frames += 1;
+        }
+        if !stats.passes.is_empty() {
+            self.pipeline = self.pipeline + PipelineStatistics::total(stats.passes.as_slice());
+            self.pipeline_frames += 1;
+        }
+        if self.last_refresh.elapsed() < REFRESH_INTERVAL {
+            return;
+        }
+
+        let frames = self.frames as f64;
+        let gpu = match self.gpu_frames {
+            0 => String::from("-"),
+            n => format!("{:.2}", self.gpu_ms / n as f64)
+        };
+        let mut title = format!("{} | {:.0} fps | frame {:.2} ms | cpu {:.2} ms | gpu {} ms", self.title,
+                                frames * 1000.0 / self.frame_ms.max(f64::EPSILON), self.frame_ms / frames,
+                                self.cpu_ms / frames, gpu);
+        // Offscreen passes count towards the overdraw too, FrameStats::passes has them apart
+        if self.pipeline_frames > 0 {
+            let (size, n) = (window.inner_size(), self.pipeline_frames as u64);
+            let pixels = size.width as u64 * size.height as u64;
+            title = format!("{} | {:.2}M tris | overdraw {:.2}x", title,
+                            self.pipeline.clipped_primitives as f64 / n as f64 / 1_000_000.0,
+                            self.pipeline.overdraw(pixels * n));
+        }
+        if !extra.is_empty() {
+            title = format!("{} | {}", title, extra);
+        }
+        window.set_title(title.as_str());
+
+        *self = StatsOverlay::new(self.title.as_str());
+    }
+}
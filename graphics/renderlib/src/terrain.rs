@@ -0,0 +1,478 @@
+use std::fs;
+use std::path::Path;
+
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, MetricSpace, Point3, SquareMatrix, Vector3};
+use tracing::debug;
+
+use crate::clustered_lights::{ForwardDraw, ForwardPass};
+use crate::descriptor::{create_descriptor_set_layout, Descriptor};
+use crate::gpu_buffer::GpuBuffer;
+use crate::index::IndexBuffer;
+use crate::raster_pipeline::RasterPipeline;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_sampler, destroy_sampler};
+use crate::scene::SceneTerrain;
+use crate::texture::Texture;
+use crate::ubo::UniformBuffer;
+use crate::vertex::Vertex;
+use crate::vkcore::VkCore;
+
+const MAX_LAYERS: usize = 4; // SPLAT_LAYERS in splat_map.glsl
+// Flat sRGB colors of missing layers, grass, dirt, rock and snow
+const LAYER_COLORS: [[u8; 4]; MAX_LAYERS] = [[86, 125, 70, 255], [120, 96, 72, 255], [128, 128, 128, 255],
+    [240, 240, 240, 255]];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainSettings {
+    pub chunks: u32, // Across and down
+    pub chunk_quads: u32, // Quads across a chunk at full resolution, a power of two
+    pub lod_levels: u32, // Each level halves the quads across a chunk
+    // In chunk widths, chunks closer than this get their full resolution and each doubling of the distance drops a
+    // level
+    pub lod_distance: f32,
+    pub skirt_depth: f32 // Of the height scale, skirts hang down from the chunk edges to hide the cracks between levels
+}
+
+impl Default for TerrainSettings {
+    fn default() -> TerrainSettings {
+        TerrainSettings {
+            chunks: 8,
+            chunk_quads: 64,
+            lod_levels: 4,
+            lod_distance: 1.5,
+            skirt_depth: 0.05
+        }
+    }
+}
+
+// Heights from 0 to 1, row by row
+pub struct Heightmap {
+    pub width: u32,
+    pub height: u32,
+    heights: Vec<f32>
+}
+
+impl Heightmap {
+    // 16-bit grayscale images, 8-bit ones are widened. Raw files are square maps of little endian 16-bit samples.
+    pub fn load(path: &str) -> Result<Heightmap, String> {
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+        let (width, height, samples): (u32, u32, Vec<u16>) = match extension.as_deref() {
+            Some("raw") | Some("r16") => {
+                let bytes = fs::read(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+                let side = ((bytes.len() / 2) as f64).sqrt() as u32;
+                if bytes.len() % 2 != 0 || (side * side * 2) as usize != bytes.len() {
+                    return Err(format!("{} isn't a square map of 16-bit samples", path));
+                }
+                (side, side, bytes.chunks_exact(2).map(|s| u16::from_le_bytes([s[0], s[1]])).collect())
+            },
+            _ => {
+                let image = image::open(path).map_err(|e| format!("Can't decode {}: {}", path, e))?.to_luma16();
+                (image.width(), image.height(), image.into_raw())
+            }
+        };
+        if width < 2 || height < 2 {
+            return Err(format!("{} needs at least 2 x 2 samples", path));
+        }
+
+        Ok(Heightmap {
+            width,
+            height,
+            heights: samples.into_iter().map(|s| s as f32 / u16::MAX as f32).collect()
+        })
+    }
+
+    // Bilinear, u and v from 0 to 1 span the map and are clamped to it
+    pub fn sample(&self, u: f32, v: f32) -> f32 {
+        let x = u.clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let y = v.clamp(0.0, 1.0) * (self.height - 1) as f32;
+        let (x0, y0) = ((x as u32).min(self.width - 2), (y as u32).min(self.height - 2));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let texel = |x: u32, y: u32| self.heights[(y * self.width + x) as usize];
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+        let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+// A chunk's index ranges, full resolution first, and what its level is picked by
+pub struct TerrainChunk {
+    pub center: Point3<f32>,
+    pub radius: f32,
+    pub lods: Vec<(u32, u32)> // First index and index count
+}
+
+pub struct TerrainGeometry {
+    pub vertices: Vec<Vertex>,
+    pub indices: Vec<u32>,
+    pub chunks: Vec<TerrainChunk>
+}
+
+impl TerrainGeometry {
+    // Index ranges to draw from eye, one per chunk. lod_distance is in world units.
+    pub fn select_lods(chunks: &[TerrainChunk], eye: Point3<f32>, lod_distance: f32) -> Vec<(u32, u32)> {
+        chunks.iter()
+            .map(|c| {
+                let distance = (eye.distance(c.center) - c.radius).max(0.0);
+                let level = ((distance / lod_distance).log2().floor() + 1.0).max(0.0) as usize;
+                c.lods[level.min(c.lods.len() - 1)]
+            })
+            .collect()
+    }
+}
+
+// Heightmap terrain on the CPU. Answers height queries for collisions and builds the geometry TerrainMesh draws.
+pub struct Terrain {
+    heightmap: Heightmap,
+    pub size: f32,
+    pub height_scale: f32,
+    pub splat_map: Option<String>,
+    pub layers: Vec<String>,
+    pub layer_tiling: f32,
+    pub settings: TerrainSettings
+}
+
+impl Terrain {
+    // Only checks that the material's files exist, TerrainMesh::new loads them
+    pub fn load(scene_terrain: &SceneTerrain, settings: TerrainSettings) -> Result<Terrain, String> {
+        if !settings.chunk_quads.is_power_of_two() {
+            return Err(format!("Terrain chunks need a power of two quads across, not {}", settings.chunk_quads));
+        }
+        if let Some(missing) = scene_terrain.splat_map.iter().chain(scene_terrain.layers.iter())
+            .find(|p| !Path::new(p).is_file()) {
+            return Err(format!("{} is not a file", missing));
+        }
+        let heightmap = Heightmap::load(scene_terrain.heightmap.as_str())?;
+        debug!(path = scene_terrain.heightmap.as_str(), width = heightmap.width, height = heightmap.height,
+               "Loaded heightmap");
+
+        Ok(Terrain {
+            heightmap,
+            size: scene_terrain.size,
+            height_scale: scene_terrain.height_scale,
+            splat_map: scene_terrain.splat_map.clone(),
+            layers: scene_terrain.layers.clone(),
+            layer_tiling: scene_terrain.layer_tiling,
+            settings
+        })
+    }
+
+    fn uv(&self, x: f32, y: f32) -> Option<(f32, f32)> {
+        let (u, v) = (x / self.size + 0.5, y / self.size + 0.5);
+        match (0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v) {
+            true => Some((u, v)),
+            false => None
+        }
+    }
+
+    // Height of the surface above the point, None outside the terrain. Follows the heightmap, chunks drawn at coarser
+    // levels of detail can be a little above or below it.
+    pub fn height_at(&self, x: f32, y: f32) -> Option<f32> {
+        self.uv(x, y).map(|(u, v)| self.heightmap.sample(u, v) * self.height_scale)
+    }
+
+    // Upward surface normal, from the slopes a sample apart
+    pub fn normal_at(&self, x: f32, y: f32) -> Option<Vector3<f32>> {
+        self.uv(x, y)?;
+        let (dx, dy) = (self.size / (self.heightmap.width - 1) as f32, self.size / (self.heightmap.height - 1) as f32);
+        let height = |x: f32, y: f32| {
+            let (u, v) = (x / self.size + 0.5, y / self.size + 0.5);
+            self.heightmap.sample(u, v) * self.height_scale
+        };
+        let slope_x = (height(x + dx, y) - height(x - dx, y)) / (2.0 * dx);
+        let slope_y = (height(x, y + dy) - height(x, y - dy)) / (2.0 * dy);
+        Some(Vector3::new(-slope_x, -slope_y, 1.0).normalize())
+    }
+
+    fn vertex(&self, u: f32, v: f32, drop: f32) -> Vertex {
+        Vertex {
+            pos: [(u - 0.5) * self.size, (v - 0.5) * self.size, self.heightmap.sample(u, v) * self.height_scale - drop],
+            color: [1.0, 1.0, 1.0],
+            tex_coord: [u, v]
+        }
+    }
+
+    // Every chunk has its own vertices, a grid and a skirt below its edges, and index ranges for each level
+    pub fn geometry(&self) -> TerrainGeometry {
+        let settings = &self.settings;
+        let quads = settings.chunk_quads;
+        let total_quads = (settings.chunks * quads) as f32;
+        let levels = settings.lod_levels.clamp(1, quads.trailing_zeros() + 1);
+        let skirt_drop = settings.skirt_depth * self.height_scale;
+        // Counter-clockwise around the chunk seen from above, so that the skirts face outwards
+        let perimeter: Vec<(u32, u32)> = (0..quads).map(|i| (i, 0))
+            .chain((0..quads).map(|j| (quads, j)))
+            .chain((1..=quads).rev().map(|i| (i, quads)))
+            .chain((1..=quads).rev().map(|j| (0, j)))
+            .collect();
+
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        let mut chunks: Vec<TerrainChunk> = Vec::with_capacity((settings.chunks * settings.chunks) as usize);
+        for chunk_y in 0..settings.chunks {
+            for chunk_x in 0..settings.chunks {
+                let base = vertices.len() as u32;
+                let uv = |i: u32, j: u32| (((chunk_x * quads + i) as f32 / total_quads),
+                                           ((chunk_y * quads + j) as f32 / total_quads));
+                for j in 0..=quads {
+                    for i in 0..=quads {
+                        let (u, v) = uv(i, j);
+                        vertices.push(self.vertex(u, v, 0.0));
+                    }
+                }
+                let skirt_base = vertices.len() as u32;
+                for &(i, j) in perimeter.iter() {
+                    let (u, v) = uv(i, j);
+                    vertices.push(self.vertex(u, v, skirt_drop));
+                }
+
+                let grid = |i: u32, j: u32| base + j * (quads + 1) + i;
+                let mut lods: Vec<(u32, u32)> = Vec::with_capacity(levels as usize);
+                for level in 0..levels {
+                    let step = 1 << level;
+                    let first = indices.len() as u32;
+                    for j in (0..quads).step_by(step as usize) {
+                        for i in (0..quads).step_by(step as usize) {
+                            indices.extend([grid(i, j), grid(i + step, j), grid(i + step, j + step),
+                                grid(i, j), grid(i + step, j + step), grid(i, j + step)]);
+                        }
+                    }
+                    let count = perimeter.len() as u32;
+                    for k in (0..count).step_by(step as usize) {
+                        let next = (k + step) % count;
+                        let (top, next_top) = (grid(perimeter[k as usize].0, perimeter[k as usize].1),
+                                               grid(perimeter[next as usize].0, perimeter[next as usize].1));
+                        let (bottom, next_bottom) = (skirt_base + k, skirt_base + next);
+                        indices.extend([top, bottom, next_bottom, top, next_bottom, next_top]);
+                    }
+                    lods.push((first, indices.len() as u32 - first));
+                }
+
+                let (u, v) = uv(quads / 2, quads / 2);
+                let center = Point3::from(self.vertex(u, v, 0.0).pos);
+                let half_width = self.size / settings.chunks as f32 / 2.0;
+                chunks.push(TerrainChunk {
+                    center,
+                    radius: (2.0 * half_width * half_width + self.height_scale * self.height_scale).sqrt(),
+                    lods
+                });
+            }
+        }
+
+        TerrainGeometry {
+            vertices,
+            indices,
+            chunks
+        }
+    }
+}
+
+// Binding 0 holds the layers, binding 1 the tiling, see splat_map.glsl
+fn create_material_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    let bindings = [
+        vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .descriptor_count(MAX_LAYERS as u32)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+        vk::DescriptorSetLayoutBinding::default()
+            .binding(1)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+    ];
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(&bindings);
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+// Draws a Terrain in the forward pass, lit by ClusteredLights like the models. Set 0 has its own transforms, with
+// the terrain at the origin, and the splat map as its texture. The layers are set 2. Chunks pick their level of
+// detail on the CPU every time cmd_draw records. Every view, like a water reflection next to the main camera, has
+// its own transforms.
+pub struct TerrainMesh {
+    chunks: Vec<TerrainChunk>,
+    lod_distance: f32, // In world units
+    vertex_buffer: GpuBuffer,
+    index_buffer: IndexBuffer,
+    uniform_buffer: UniformBuffer,
+    descriptor: Descriptor, // One set per frame and view, see slot
+    max_frames: usize,
+    splat_map: Texture,
+    splat_sampler: vk::Sampler,
+    layers: Vec<Texture>,
+    layer_sampler: vk::Sampler,
+    params: GpuBuffer,
+    material_layout: vk::DescriptorSetLayout,
+    material_pool: vk::DescriptorPool,
+    material_set: vk::DescriptorSet,
+    pipeline: RasterPipeline
+}
+
+impl TerrainMesh {
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, pass: &ForwardPass, terrain: &Terrain) -> TerrainMesh {
+        let ForwardPass { render_pass, lights, msaa_samples, max_frames, views } = *pass;
+        let geometry = terrain.geometry();
+        let vertex_buffer = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::VERTEX_BUFFER,
+                                                       geometry.vertices.as_slice(),
+                                                       vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let index_buffer = IndexBuffer::new(core, command_pool, vk::BufferUsageFlags::INDEX_BUFFER,
+                                            geometry.indices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+
+        // Weights aren't colors, so the splat map is kept linear
+        let splat_map = match terrain.splat_map.as_ref() {
+            Some(path) => {
+                let image = image::open(path).unwrap().to_rgba8();
+                let weights: Vec<f32> = image.as_raw().iter().map(|w| *w as f32 / 255.0).collect();
+                Texture::from_rgba32f(core, command_pool, image.width(), image.height(), weights.as_slice())
+            },
+            None => Texture::from_rgba32f(core, command_pool, 1, 1, &[1.0, 0.0, 0.0, 0.0])
+        };
+        let layers: Vec<Texture> = (0..MAX_LAYERS)
+            .map(|i| match terrain.layers.get(i) {
+                Some(path) => Texture::new(core, command_pool, path),
+                None => Texture::from_rgba8(core, command_pool, 1, 1, &LAYER_COLORS[i])
+            })
+            .collect();
+        let splat_sampler = create_sampler(core, 1);
+        let layer_sampler = create_sampler(core, layers.iter().map(|l| l.mip_levels).max().unwrap());
+        let params = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::UNIFORM_BUFFER,
+                                                &[[terrain.layer_tiling, 0.0, 0.0, 0.0]],
+                                                vk::MemoryPropertyFlags::DEVICE_LOCAL);
+
+        let uniform_buffer = UniformBuffer::new(core, max_frames * views);
+        let set_layout = create_descriptor_set_layout(core); // Destroyed with the descriptor
+        let descriptor = Descriptor::new(core, &uniform_buffer, splat_sampler, &splat_map, set_layout,
+                                         max_frames * views);
+        let material_layout = create_material_set_layout(core);
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(MAX_LAYERS as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+        let material_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let set_layouts = [material_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(material_pool)
+            .set_layouts(&set_layouts);
+        let material_set = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap()[0] };
+        let layer_infos: Vec<vk::DescriptorImageInfo> = layers.iter()
+            .map(|l| vk::DescriptorImageInfo::default()
+                .sampler(layer_sampler)
+                .image_view(l.view)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL))
+            .collect();
+        let params_info = [vk::DescriptorBufferInfo::default()
+            .buffer(params.buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)];
+        let writes = [
+            vk::WriteDescriptorSet::default()
+                .dst_set(material_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(layer_infos.as_slice()),
+            vk::WriteDescriptorSet::default()
+                .dst_set(material_set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&params_info)
+        ];
+        unsafe { core.logical_device.update_descriptor_sets(&writes, &[]) };
+
+        let pipeline = RasterPipeline::new_terrain(core, render_pass, set_layout, lights.set_layout(),
+                                                   material_layout, msaa_samples);
+        debug!(vertices = geometry.vertices.len(), indices = geometry.indices.len(), chunks = geometry.chunks.len(),
+               "Created terrain mesh");
+
+        TerrainMesh {
+            chunks: geometry.chunks,
+            lod_distance: terrain.settings.lod_distance * terrain.size / terrain.settings.chunks as f32,
+            vertex_buffer,
+            index_buffer,
+            uniform_buffer,
+            descriptor,
+            max_frames,
+            splat_map,
+            splat_sampler,
+            layers,
+            layer_sampler,
+            params,
+            material_layout,
+            material_pool,
+            material_set,
+            pipeline
+        }
+    }
+
+    // Views of a frame follow each other
+    fn slot(&self, current_frame: usize, view_index: usize) -> usize {
+        view_index * self.max_frames + current_frame
+    }
+
+    pub fn set_transforms(&self, current_frame: usize, view_index: usize, view: Matrix4<f32>, proj: Matrix4<f32>) {
+        self.uniform_buffer.set_transforms(self.slot(current_frame, view_index), Matrix4::identity(), view, proj);
+    }
+
+    // Inside the forward render pass, after the viewport and scissor are set. Leaves the terrain's pipeline bound.
+    // eye is the camera position of draw's view.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, draw: &ForwardDraw, eye: Point3<f32>) {
+        let ForwardDraw { lights, color_constants, current_frame, view_index } = *draw;
+        let layout = self.pipeline.pipeline_layout;
+        let set = self.descriptor.sets[self.slot(current_frame, view_index)];
+        unsafe {
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                  self.pipeline.pipelines[0]);
+            core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buf], &[0]);
+            self.index_buffer.cmd_bind(core, command_buffer);
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                         &[set], &[]);
+            lights.cmd_bind(core, command_buffer, layout, current_frame);
+            core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 2,
+                                                         &[self.material_set], &[]);
+            core.logical_device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                                   cast_to_u8_slice(color_constants));
+            for (first, count) in TerrainGeometry::select_lods(self.chunks.as_slice(), eye, self.lod_distance) {
+                core.logical_device.cmd_draw_indexed(command_buffer, count, 1, first, 0, 0);
+            }
+        }
+    }
+
+    // Only the geometry, with the level of detail seen from eye, for passes that bring their own pipeline such as
+    // PointShadows::cmd_render. The terrain is at the origin.
+    pub fn cmd_draw_geometry(&self, core: &VkCore, command_buffer: vk::CommandBuffer, eye: Point3<f32>) {
+        unsafe {
+            core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buf], &[0]);
+            self.index_buffer.cmd_bind(core, command_buffer);
+            for (first, count) in TerrainGeometry::select_lods(self.chunks.as_slice(), eye, self.lod_distance) {
+                core.logical_device.cmd_draw_indexed(command_buffer, count, 1, first, 0, 0);
+            }
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.material_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.material_layout, None);
+        }
+        self.descriptor.destroy(core);
+        self.uniform_buffer.destroy(core);
+        self.params.destroy(core);
+        destroy_sampler(core, self.layer_sampler);
+        destroy_sampler(core, self.splat_sampler);
+        for layer in self.layers.iter() {
+            layer.destroy(core);
+        }
+        self.splat_map.destroy(core);
+        self.index_buffer.destroy(core);
+        self.vertex_buffer.destroy(core);
+    }
+}
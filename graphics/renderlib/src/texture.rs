@@ -1,19 +1,25 @@
 use std::cmp::max;
+use std::mem;
 use ash::vk;
 use ash::vk::Offset3D;
 use image::EncodableLayout;
 use image::io::Reader;
-use crate::gpu_buffer::{create_buffer};
-use crate::image::{create_image_view, create_image, copy_buffer_to_image, transition_image_layout};
-use crate::single_time::{begin_single_time_commands, end_single_time_commands};
+use crate::gpu_buffer::{create_buffer, GpuBuffer};
+use crate::image::{create_image_view, create_image, cmd_copy_buffer_to_image, cmd_transition_image_layout};
+use crate::single_time::SingleTimeContext;
 use crate::vkcore::VkCore;
 
+// sRGB, for color images with 8 bits per channel
+pub const TEXTURE_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+// Linear, for HDR images such as environment maps
+pub const HDR_TEXTURE_FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+
 fn create_texture_image_view(core: &VkCore, image: vk::Image, mip_levels: u32) -> vk::ImageView {
     create_image_view(core, image, vk::Format::R8G8B8A8_SRGB, vk::ImageAspectFlags::COLOR, mip_levels)
 }
 
-fn generate_mip_maps(core: &VkCore, command_pool: vk::CommandPool, image: vk::Image, image_format: vk::Format,
-                     tex_width: u32, tex_height: u32, mip_levels: u32) {
+fn cmd_generate_mip_maps(core: &VkCore, cmd_buffer: vk::CommandBuffer, image: vk::Image, image_format: vk::Format,
+                         tex_width: u32, tex_height: u32, mip_levels: u32) {
     let format_properties = unsafe {
         core.instance
             .get_physical_device_format_properties(core.physical_device, image_format)
@@ -23,8 +29,6 @@ fn generate_mip_maps(core: &VkCore, command_pool: vk::CommandPool, image: vk::Im
                    vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
                vk::FormatFeatureFlags::empty());
 
-    let cmd_buffer = begin_single_time_commands(core, command_pool);
-
     let mut sub_resource_range = vk::ImageSubresourceRange::default()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .base_array_layer(0)
@@ -128,13 +132,11 @@ fn generate_mip_maps(core: &VkCore, command_pool: vk::CommandPool, image: vk::Im
                                                           &[], &[],
                                                           &[barrier.clone()]);
     }
-
-    end_single_time_commands(core, command_pool, cmd_buffer);
 }
 
 pub struct Texture {
     image: vk::Image,
-    pub(crate) view: vk::ImageView,
+    pub view: vk::ImageView,
     mem: vk::DeviceMemory,
     pub mip_levels: u32
 }
@@ -169,18 +171,23 @@ impl Texture {
                                                             vk::ImageUsageFlags::SAMPLED,
                                                         vk::MemoryPropertyFlags::DEVICE_LOCAL,
                                                         vk::SampleCountFlags::TYPE_1);
-        transition_image_layout(core, command_pool, texture_image,
-                                vk::Format::R8G8B8A8_SRGB, vk::ImageLayout::UNDEFINED,
-                                vk::ImageLayout::TRANSFER_DST_OPTIMAL, mip_levels);
-        copy_buffer_to_image(core, command_pool, img_buf, texture_image,
-                             img.width(), img.height());
+        // One submission for the upload and the mip chain
+        let mut context = SingleTimeContext::new(command_pool);
+        let command_buffer = context.command_buffer(core);
+        cmd_transition_image_layout(core, command_buffer, texture_image,
+                                    vk::Format::R8G8B8A8_SRGB, vk::ImageLayout::UNDEFINED,
+                                    vk::ImageLayout::TRANSFER_DST_OPTIMAL, mip_levels);
+        cmd_copy_buffer_to_image(core, command_buffer, img_buf, texture_image,
+                                 img.width(), img.height());
         // transition_image_layout(logical_layer, command_pool, texture_image,
         //                         vk::Format::R8G8B8A8_SRGB,
         //                         vk::ImageLayout::TRANSFER_DST_OPTIMAL,
         //                         vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, mip_levels);
-        generate_mip_maps(core, command_pool, texture_image,
-                          vk::Format::R8G8B8A8_SRGB, img.width(),
-                          img.height(), mip_levels);
+        cmd_generate_mip_maps(core, command_buffer, texture_image,
+                              vk::Format::R8G8B8A8_SRGB, img.width(),
+                              img.height(), mip_levels);
+        context.flush(core);
+        context.destroy(core);
 
         let texture_image_view = create_texture_image_view(core, texture_image, mip_levels);
 
@@ -197,6 +204,119 @@ impl Texture {
         }
     }
 
+    // pixels holds sRGB RGBA values, row by row. Without mip maps.
+    pub fn from_rgba8(core: &VkCore, command_pool: vk::CommandPool, width: u32, height: u32, pixels: &[u8]) -> Texture {
+        let mut context = SingleTimeContext::new(command_pool);
+        let texture = Texture::record_rgba8(core, &mut context, width, height, pixels);
+        context.flush(core);
+        context.destroy(core);
+
+        texture
+    }
+
+    // Like from_rgba8, the upload is only recorded into context. The texture can be sampled once the submission it
+    // goes out with finished, so several of them can share one.
+    pub fn record_rgba8(core: &VkCore, context: &mut SingleTimeContext, width: u32, height: u32, pixels: &[u8])
+        -> Texture {
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+        let img_size = pixels.len() as vk::DeviceSize;
+        let staging = GpuBuffer::new(core, img_size, vk::BufferUsageFlags::TRANSFER_SRC,
+                                     vk::MemoryPropertyFlags::HOST_VISIBLE);
+        unsafe {
+            let mapped = staging.map(core) as *mut u8;
+            mapped.copy_from_nonoverlapping(pixels.as_ptr(), pixels.len());
+            staging.flush(core, 0..img_size);
+            core.logical_device.unmap_memory(staging.mem);
+        };
+
+        let (texture_image, texture_mem) = create_image(core, width, height, 1, TEXTURE_FORMAT,
+                                                        vk::ImageTiling::OPTIMAL,
+                                                        vk::ImageUsageFlags::TRANSFER_DST |
+                                                            vk::ImageUsageFlags::SAMPLED,
+                                                        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                        vk::SampleCountFlags::TYPE_1);
+        let command_buffer = context.command_buffer(core);
+        cmd_transition_image_layout(core, command_buffer, texture_image, TEXTURE_FORMAT,
+                                    vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, 1);
+        cmd_copy_buffer_to_image(core, command_buffer, staging.buf, texture_image, width, height);
+        cmd_transition_image_layout(core, command_buffer, texture_image, TEXTURE_FORMAT,
+                                    vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                                    1);
+        context.retire(staging);
+        let texture_image_view = create_texture_image_view(core, texture_image, 1);
+
+        Texture {
+            image: texture_image,
+            view: texture_image_view,
+            mem: texture_mem,
+            mip_levels: 1
+        }
+    }
+
+    // For images filled elsewhere, like TextureStreamer's uploads. The texture owns them from here on.
+    pub(crate) fn from_parts(image: vk::Image, view: vk::ImageView, mem: vk::DeviceMemory, mip_levels: u32)
+        -> Texture {
+        Texture {
+            image,
+            view,
+            mem,
+            mip_levels
+        }
+    }
+
+    // Radiance HDR or OpenEXR file, without mip maps
+    pub fn new_hdr(core: &VkCore, command_pool: vk::CommandPool, path: &str) -> Texture {
+        let img = Reader::open(path).unwrap().decode().unwrap().to_rgba32f();
+        Texture::from_rgba32f(core, command_pool, img.width(), img.height(), img.as_raw())
+    }
+
+    // pixels holds linear RGBA values, row by row
+    pub fn from_rgba32f(core: &VkCore, command_pool: vk::CommandPool, width: u32, height: u32, pixels: &[f32])
+        -> Texture {
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+        let img_size = mem::size_of_val(pixels) as vk::DeviceSize;
+        let (img_mem, img_buf) = create_buffer(core, img_size, vk::BufferUsageFlags::TRANSFER_SRC,
+                                               vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                   vk::MemoryPropertyFlags::HOST_COHERENT);
+        unsafe {
+            let mapped = core.logical_device.map_memory(img_mem, 0, img_size, vk::MemoryMapFlags::empty())
+                .unwrap() as *mut f32;
+            mapped.copy_from_nonoverlapping(pixels.as_ptr(), pixels.len());
+            core.logical_device.unmap_memory(img_mem);
+        };
+
+        let (texture_image, texture_mem) = create_image(core, width, height, 1, HDR_TEXTURE_FORMAT,
+                                                        vk::ImageTiling::OPTIMAL,
+                                                        vk::ImageUsageFlags::TRANSFER_DST |
+                                                            vk::ImageUsageFlags::SAMPLED,
+                                                        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                        vk::SampleCountFlags::TYPE_1);
+        let mut context = SingleTimeContext::new(command_pool);
+        let command_buffer = context.command_buffer(core);
+        cmd_transition_image_layout(core, command_buffer, texture_image, HDR_TEXTURE_FORMAT,
+                                    vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, 1);
+        cmd_copy_buffer_to_image(core, command_buffer, img_buf, texture_image, width, height);
+        cmd_transition_image_layout(core, command_buffer, texture_image, HDR_TEXTURE_FORMAT,
+                                    vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                                    1);
+        context.flush(core);
+        context.destroy(core);
+        let texture_image_view = create_image_view(core, texture_image, HDR_TEXTURE_FORMAT,
+                                                   vk::ImageAspectFlags::COLOR, 1);
+
+        unsafe {
+            core.logical_device.destroy_buffer(img_buf, None);
+            core.logical_device.free_memory(img_mem, None);
+        }
+
+        Texture {
+            image: texture_image,
+            view: texture_image_view,
+            mem: texture_mem,
+            mip_levels: 1
+        }
+    }
+
     pub fn destroy(&self, core: &VkCore) {
         unsafe {
             core.logical_device.destroy_image_view(self.view, None);
@@ -0,0 +1,614 @@
+use std::cmp::max;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use ash::vk;
+use image::imageops::{self, FilterType};
+use tracing::{debug, error};
+
+use crate::gpu_buffer::GpuBuffer;
+use crate::image::{create_image_view, create_image_with, ImageInfo};
+use crate::memory::MemoryStats;
+use crate::sparse_texture::{sparse_textures_supported, SparseTexture};
+use crate::texture::{Texture, TEXTURE_FORMAT};
+use crate::vkcore::VkCore;
+
+// Largest side of the mip levels that always stay resident once a texture is decoded
+const RESIDENT_SIZE: u32 = 64;
+const PLACEHOLDER_PIXEL: [u8; 4] = [128, 128, 128, 255];
+// Textures with a larger side are loaded as virtual textures where the device supports them, see wants_virtual
+const VIRTUAL_TEXTURE_SIZE: u32 = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamingSettings {
+    pub budget_fraction: f32, // Of the device local memory budget, see MemoryStats
+    pub max_budget: Option<vk::DeviceSize>, // In bytes, caps the budget on top of budget_fraction
+    // Textures closer to the camera than this get their full resolution, each doubling of the distance drops a level
+    pub full_resolution_distance: f32,
+    pub max_uploads: usize // Uploads in flight at once
+}
+
+impl Default for StreamingSettings {
+    fn default() -> StreamingSettings {
+        StreamingSettings {
+            budget_fraction: 0.25,
+            max_budget: None,
+            full_resolution_distance: 4.0,
+            max_uploads: 2
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextureId(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtualTextureId(usize);
+
+#[derive(Clone, Copy, Debug)]
+enum DecodeTarget {
+    Streamed(TextureId),
+    Virtual(VirtualTextureId)
+}
+
+// Every level of a decoded texture, level 0 first, as tightly packed sRGB RGBA
+pub(crate) struct MipChain {
+    pub(crate) levels: Vec<Vec<u8>>
+}
+
+pub(crate) fn mip_extent(width: u32, height: u32, level: u32) -> (u32, u32) {
+    (max(width >> level, 1), max(height >> level, 1))
+}
+
+pub(crate) fn full_mip_levels(width: u32, height: u32) -> u32 {
+    ((height.max(width) as f64).log(2.0).floor() as u32) + 1
+}
+
+// On the decode thread
+fn decode_mip_chain(path: &str, mip_levels: u32) -> Result<MipChain, String> {
+    let mut image = image::open(path).map_err(|e| format!("Can't decode {}: {}", path, e))?.to_rgba8();
+    let mut levels: Vec<Vec<u8>> = Vec::with_capacity(mip_levels as usize);
+    for level in 0..mip_levels {
+        if level > 0 {
+            let (width, height) = mip_extent(image.width(), image.height(), 1);
+            image = imageops::resize(&image, width, height, FilterType::Triangle);
+        }
+        levels.push(image.as_raw().clone());
+    }
+
+    Ok(MipChain {
+        levels
+    })
+}
+
+// A texture decoded ahead of its streamer, see decode_texture
+pub struct DecodedTexture {
+    path: String,
+    width: u32,
+    height: u32,
+    chain: MipChain
+}
+
+impl DecodedTexture {
+    pub fn path(&self) -> &str {
+        self.path.as_str()
+    }
+
+    pub fn extent(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+// The whole mip chain, like the decode thread builds it. Doesn't need a device, so it can run while one is created
+// and the result be handed to TextureStreamer::load_decoded.
+pub fn decode_texture(path: &str) -> Result<DecodedTexture, String> {
+    let (width, height) = image::image_dimensions(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+    let chain = decode_mip_chain(path, full_mip_levels(width, height))?;
+
+    Ok(DecodedTexture {
+        path: path.to_string(),
+        width,
+        height,
+        chain
+    })
+}
+
+fn streaming_budget(core: &VkCore, settings: &StreamingSettings) -> vk::DeviceSize {
+    let budget = (MemoryStats::query(core).device_local_budget() as f64 * settings.budget_fraction as f64)
+        as vk::DeviceSize;
+    settings.max_budget.map_or(budget, |m| budget.min(m))
+}
+
+struct StreamedTexture {
+    path: String,
+    width: u32,
+    height: u32,
+    mip_levels: u32, // Of the full chain
+    coarsest_base: u32, // Base level when only the RESIDENT_SIZE levels are resident
+    chain: Option<MipChain>, // None while it is being decoded
+    resident: Option<(Texture, u32)>, // And its base level, None until the first upload finished
+    distance: f32,
+    uploading: bool,
+    generation: u64
+}
+
+impl StreamedTexture {
+    // Of the levels from base on
+    fn bytes(&self, base: u32) -> vk::DeviceSize {
+        (base..self.mip_levels)
+            .map(|l| {
+                let (width, height) = mip_extent(self.width, self.height, l);
+                width as vk::DeviceSize * height as vk::DeviceSize * 4
+            })
+            .sum()
+    }
+
+    fn resident_base(&self) -> Option<u32> {
+        self.resident.as_ref().map(|(_, base)| *base)
+    }
+
+    fn wanted_base(&self, full_resolution_distance: f32) -> u32 {
+        let level = (self.distance / full_resolution_distance).log2().floor().max(0.0);
+        (level as u32).min(self.coarsest_base)
+    }
+}
+
+struct Upload {
+    id: TextureId,
+    base: u32,
+    texture: Texture,
+    staging: GpuBuffer,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    done_sem: vk::Semaphore
+}
+
+// Kept until no frame in flight can use them anymore
+struct Retired {
+    texture: Option<Texture>,
+    semaphore: vk::Semaphore,
+    frames_left: usize
+}
+
+// Streams textures in at the resolution they are seen with. load only reads the file's size, a background thread
+// decodes the file and builds the mip chain, and until then the texture is a grey placeholder. Every frame update
+// picks a base level per texture from its distance to the camera, the closest textures get the VRAM budget first.
+// A texture that changes level is uploaded again on the transfer queue, as a new image with only the levels from the
+// base on, which replaces the old one once the copy finished. The levels up to RESIDENT_SIZE always stay.
+// The decoded chains stay in system memory, so dropping levels and streaming them in again doesn't touch the disk.
+// Renderers rewrite their descriptors whenever generation changes, and wait on take_waits before sampling.
+// Textures too large to keep whole can be loaded as virtual textures instead, see SparseTexture. They get what is
+// left of the budget once the regular textures took theirs.
+pub struct TextureStreamer {
+    settings: StreamingSettings,
+    budget: vk::DeviceSize,
+    max_frames: usize,
+    placeholder: Texture,
+    textures: Vec<StreamedTexture>,
+    virtual_textures: Vec<SparseTexture>,
+    command_pool: vk::CommandPool, // Of the transfer family
+    uploads: Vec<Upload>,
+    retired: Vec<Retired>,
+    waits: Vec<vk::Semaphore>,
+    jobs: Sender<(DecodeTarget, String, u32)>,
+    decoded: Receiver<(DecodeTarget, Result<MipChain, String>)>
+}
+
+impl TextureStreamer {
+    // command_pool is a graphics pool, for the placeholder
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, settings: StreamingSettings, max_frames: usize)
+        -> TextureStreamer {
+        let pool_create_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+            .queue_family_index(core.transfer_family_index);
+        let transfer_pool = unsafe { core.logical_device.create_command_pool(&pool_create_info, None).unwrap() };
+
+        let (jobs, job_receiver) = mpsc::channel::<(DecodeTarget, String, u32)>();
+        let (decoded_sender, decoded) = mpsc::channel();
+        // Ends once the streamer, and with it jobs, is dropped
+        thread::Builder::new()
+            .name(String::from("texture-decode"))
+            .spawn(move || {
+                for (target, path, mip_levels) in job_receiver {
+                    if decoded_sender.send((target, decode_mip_chain(path.as_str(), mip_levels))).is_err() {
+                        break;
+                    }
+                }
+            })
+            .unwrap();
+
+        TextureStreamer {
+            settings,
+            budget: streaming_budget(core, &settings),
+            max_frames,
+            placeholder: Texture::from_rgba8(core, command_pool, 1, 1, &PLACEHOLDER_PIXEL),
+            textures: Vec::new(),
+            virtual_textures: Vec::new(),
+            command_pool: transfer_pool,
+            uploads: Vec::new(),
+            retired: Vec::new(),
+            waits: Vec::new(),
+            jobs,
+            decoded
+        }
+    }
+
+    // Returns at once, the texture is the placeholder until its first levels are uploaded
+    pub fn load(&mut self, path: &str) -> Result<TextureId, String> {
+        let (width, height) = image::image_dimensions(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+        let id = self.push_texture(path, width, height, None);
+        self.jobs.send((DecodeTarget::Streamed(id), path.to_string(), self.textures[id.0].mip_levels))
+            .map_err(|_| String::from("The decode thread has exited"))?;
+
+        Ok(id)
+    }
+
+    // Like load, skipping the decode thread. Its first levels are uploaded by the next update.
+    pub fn load_decoded(&mut self, texture: DecodedTexture) -> TextureId {
+        self.push_texture(texture.path.as_str(), texture.width, texture.height, Some(texture.chain))
+    }
+
+    fn push_texture(&mut self, path: &str, width: u32, height: u32, chain: Option<MipChain>) -> TextureId {
+        let mip_levels = full_mip_levels(width, height);
+        let coarsest_base = (0..mip_levels)
+            .find(|&l| max(width, height) >> l <= RESIDENT_SIZE)
+            .unwrap_or(mip_levels - 1);
+        let id = TextureId(self.textures.len());
+        self.textures.push(StreamedTexture {
+            path: path.to_string(),
+            width,
+            height,
+            mip_levels,
+            coarsest_base,
+            chain,
+            resident: None,
+            distance: f32::MAX,
+            uploading: false,
+            generation: 0
+        });
+
+        id
+    }
+
+    // Whether path is better off loaded with load_virtual
+    pub fn wants_virtual(core: &VkCore, path: &str) -> bool {
+        sparse_textures_supported(core) && image::image_dimensions(path)
+            .is_ok_and(|(width, height)| width.max(height) > VIRTUAL_TEXTURE_SIZE)
+    }
+
+    // Like load_virtual, skipping the decode thread
+    pub fn load_virtual_decoded(&mut self, core: &VkCore, command_pool: vk::CommandPool,
+                                set_layout: vk::DescriptorSetLayout, texture: DecodedTexture)
+        -> Result<VirtualTextureId, String> {
+        let id = self.push_virtual(core, command_pool, set_layout, texture.path.as_str(), texture.width,
+                                   texture.height)?;
+        self.virtual_textures[id.0].set_chain(texture.chain);
+
+        Ok(id)
+    }
+
+    // Fails without sparse residency. command_pool is a graphics pool and set_layout the one from
+    // create_virtual_texture_set_layout. The texture is grey until it is decoded, then streams in by what the
+    // feedback of the frames asks for.
+    pub fn load_virtual(&mut self, core: &VkCore, command_pool: vk::CommandPool, set_layout: vk::DescriptorSetLayout,
+                        path: &str) -> Result<VirtualTextureId, String> {
+        let (width, height) = image::image_dimensions(path).map_err(|e| format!("Can't read {}: {}", path, e))?;
+        let id = self.push_virtual(core, command_pool, set_layout, path, width, height)?;
+        self.jobs.send((DecodeTarget::Virtual(id), path.to_string(), full_mip_levels(width, height)))
+            .map_err(|_| String::from("The decode thread has exited"))?;
+
+        Ok(id)
+    }
+
+    fn push_virtual(&mut self, core: &VkCore, command_pool: vk::CommandPool, set_layout: vk::DescriptorSetLayout,
+                    path: &str, width: u32, height: u32) -> Result<VirtualTextureId, String> {
+        if !sparse_textures_supported(core) {
+            return Err(format!("Can't load {} as a virtual texture, the device has no sparse residency", path));
+        }
+        let texture = SparseTexture::new(core, command_pool, set_layout, path, width, height, self.max_frames)?;
+        let id = VirtualTextureId(self.virtual_textures.len());
+        self.virtual_textures.push(texture);
+
+        Ok(id)
+    }
+
+    // Binds the texture as set 2 of a RasterPipeline::new_clustered_virtual pipeline
+    pub fn cmd_bind_virtual(&self, core: &VkCore, command_buffer: vk::CommandBuffer,
+                            pipeline_layout: vk::PipelineLayout, id: VirtualTextureId, frame: usize) {
+        self.virtual_textures[id.0].cmd_bind(core, command_buffer, pipeline_layout, frame);
+    }
+
+    // For the regular texture binding of pipelines that sample a virtual texture instead
+    pub fn placeholder(&self) -> &Texture {
+        &self.placeholder
+    }
+
+    // Distance from the camera to what the texture is drawn on, kept until the next request. Textures that are
+    // never requested stay at their coarsest levels.
+    pub fn request(&mut self, id: TextureId, distance: f32) {
+        self.textures[id.0].distance = distance;
+    }
+
+    pub fn texture(&self, id: TextureId) -> &Texture {
+        self.textures[id.0].resident.as_ref().map_or(&self.placeholder, |(texture, _)| texture)
+    }
+
+    // Changes whenever texture returns a new image
+    pub fn generation(&self, id: TextureId) -> u64 {
+        self.textures[id.0].generation
+    }
+
+    // Of the full chain, for the sampler
+    pub fn mip_levels(&self, id: TextureId) -> u32 {
+        self.textures[id.0].mip_levels
+    }
+
+    // Upload semaphores the next graphics submission has to wait on at FRAGMENT_SHADER. Take them only once the
+    // submission is certain to happen, like AsyncCompute::take_wait.
+    pub fn take_waits(&mut self) -> Vec<vk::Semaphore> {
+        mem::take(&mut self.waits)
+    }
+
+    pub fn set_settings(&mut self, core: &VkCore, settings: StreamingSettings) {
+        self.settings = settings;
+        self.budget = streaming_budget(core, &settings);
+    }
+
+    pub fn settings(&self) -> StreamingSettings {
+        self.settings
+    }
+
+    pub fn resident_bytes(&self) -> vk::DeviceSize {
+        self.textures.iter()
+            .filter_map(|t| t.resident_base().map(|base| t.bytes(base)))
+            .sum::<vk::DeviceSize>() + self.virtual_textures.iter().map(|t| t.resident_bytes()).sum::<vk::DeviceSize>()
+    }
+
+    pub fn status(&self) -> String {
+        format!("textures {:.1}/{:.1} MB, {} uploading", self.resident_bytes() as f64 / (1024.0 * 1024.0),
+                self.budget as f64 / (1024.0 * 1024.0), self.uploads.len())
+    }
+
+    // Once per frame, after the fence of frame was waited on
+    pub fn update(&mut self, core: &VkCore, frame: usize) {
+        for (target, result) in self.decoded.try_iter() {
+            match (target, result) {
+                (DecodeTarget::Streamed(id), Ok(chain)) => {
+                    let texture = &mut self.textures[id.0];
+                    debug!(path = texture.path.as_str(), levels = texture.mip_levels, "Decoded texture");
+                    texture.chain = Some(chain);
+                },
+                (DecodeTarget::Streamed(id), Err(e)) => {
+                    error!(path = self.textures[id.0].path.as_str(), "Texture stays a placeholder: {}", e)
+                },
+                (DecodeTarget::Virtual(id), Ok(chain)) => {
+                    debug!(path = self.virtual_textures[id.0].path(), "Decoded virtual texture");
+                    self.virtual_textures[id.0].set_chain(chain);
+                },
+                (DecodeTarget::Virtual(id), Err(e)) => {
+                    error!(path = self.virtual_textures[id.0].path(), "Virtual texture stays grey: {}", e)
+                }
+            }
+        }
+
+        for r in self.retired.iter_mut() {
+            r.frames_left = r.frames_left.saturating_sub(1);
+        }
+        let waits = &self.waits;
+        self.retired.retain(|r| {
+            if r.frames_left > 0 || waits.contains(&r.semaphore) {
+                return true;
+            }
+            if let Some(texture) = r.texture.as_ref() {
+                texture.destroy(core);
+            }
+            unsafe { core.logical_device.destroy_semaphore(r.semaphore, None) };
+            false
+        });
+
+        // A lost device never finishes its uploads, they are destroyed with the streamer
+        let (finished, pending): (Vec<Upload>, Vec<Upload>) = self.uploads.drain(..)
+            .partition(|u| matches!(unsafe { core.logical_device.get_fence_status(u.fence) }, Ok(true)));
+        self.uploads = pending;
+        for upload in finished {
+            self.finish_upload(core, upload);
+        }
+
+        // Closest first, each texture gets the levels it wants as long as they fit in what is left of the budget
+        let mut order: Vec<usize> = (0..self.textures.len()).filter(|&i| self.textures[i].chain.is_some()).collect();
+        order.sort_by(|&a, &b| self.textures[a].distance.total_cmp(&self.textures[b].distance));
+        let mut remaining = self.budget;
+        let mut changes: Vec<(usize, u32)> = Vec::new();
+        for i in order {
+            let texture = &self.textures[i];
+            let mut base = texture.wanted_base(self.settings.full_resolution_distance);
+            while base < texture.coarsest_base && texture.bytes(base) > remaining {
+                base += 1;
+            }
+            remaining = remaining.saturating_sub(texture.bytes(base));
+            if !texture.uploading && texture.resident_base() != Some(base) {
+                changes.push((i, base));
+            }
+        }
+        // Dropping levels first frees memory for the textures that gain some
+        changes.sort_by_key(|&(i, base)| self.textures[i].resident_base().is_none_or(|b| base <= b));
+        let free_slots = self.settings.max_uploads.saturating_sub(self.uploads.len());
+        for (i, base) in changes.into_iter().take(free_slots) {
+            self.start_upload(core, TextureId(i), base);
+        }
+
+        for texture in self.virtual_textures.iter_mut() {
+            for done_sem in texture.update(core, self.command_pool, frame, remaining) {
+                self.retired.push(Retired {
+                    texture: None,
+                    semaphore: done_sem,
+                    frames_left: self.max_frames
+                });
+                self.waits.push(done_sem);
+            }
+            remaining = remaining.saturating_sub(texture.resident_bytes());
+        }
+    }
+
+    fn start_upload(&mut self, core: &VkCore, id: TextureId, base: u32) {
+        let texture = &mut self.textures[id.0];
+        let chain = texture.chain.as_ref().unwrap();
+        let levels = texture.mip_levels - base;
+        let size = texture.bytes(base);
+        let staging = GpuBuffer::new(core, size, vk::BufferUsageFlags::TRANSFER_SRC,
+                                     vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let mut regions: Vec<vk::BufferImageCopy> = Vec::with_capacity(levels as usize);
+        unsafe {
+            let mapped = staging.map(core) as *mut u8;
+            let mut offset = 0;
+            for level in base..texture.mip_levels {
+                let pixels = &chain.levels[level as usize];
+                mapped.add(offset).copy_from_nonoverlapping(pixels.as_ptr(), pixels.len());
+                let (width, height) = mip_extent(texture.width, texture.height, level);
+                regions.push(vk::BufferImageCopy::default()
+                    .buffer_offset(offset as vk::DeviceSize)
+                    .buffer_row_length(0) // Tightly packed
+                    .buffer_image_height(0)
+                    .image_subresource(vk::ImageSubresourceLayers::default()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .mip_level(level - base)
+                        .base_array_layer(0)
+                        .layer_count(1))
+                    .image_offset(vk::Offset3D::default())
+                    .image_extent(vk::Extent3D::default()
+                        .width(width)
+                        .height(height)
+                        .depth(1)));
+                offset += pixels.len();
+            }
+            staging.flush(core, 0..size);
+            core.logical_device.unmap_memory(staging.mem);
+        }
+
+        // Sampled on the graphics queue without ownership transfers
+        let (width, height) = mip_extent(texture.width, texture.height, base);
+        let (image, image_mem) = create_image_with(core, &ImageInfo {
+            mip_levels: levels,
+            queue_families: &[core.graphics_family_index, core.transfer_family_index],
+            ..ImageInfo::new(width, height, TEXTURE_FORMAT,
+                             vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED)
+        });
+        let view = create_image_view(core, image, TEXTURE_FORMAT, vk::ImageAspectFlags::COLOR, levels);
+
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(levels)
+            .base_array_layer(0)
+            .layer_count(1);
+        let to_dst_barrier = vk::ImageMemoryBarrier::default()
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+        // The fragment shaders see the copy through the semaphore the frame waits on, see take_waits
+        let to_read_barrier = vk::ImageMemoryBarrier::default()
+            .image(image)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::empty())
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED);
+
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let (command_buffer, fence, done_sem) = unsafe {
+            (core.logical_device.allocate_command_buffers(&alloc_info).unwrap()[0],
+             core.logical_device.create_fence(&vk::FenceCreateInfo::default(), None).unwrap(),
+             core.logical_device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None).unwrap())
+        };
+        let command_buffers = [command_buffer];
+        let sig_sems = [done_sem];
+        let submit_info = [vk::SubmitInfo::default()
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&sig_sems)];
+        unsafe {
+            core.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                     vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                     &[], &[], &[to_dst_barrier]);
+            core.logical_device.cmd_copy_buffer_to_image(command_buffer, staging.buf, image,
+                                                         vk::ImageLayout::TRANSFER_DST_OPTIMAL, regions.as_slice());
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                     vk::DependencyFlags::empty(), &[], &[], &[to_read_barrier]);
+            core.logical_device.end_command_buffer(command_buffer).unwrap();
+            core.logical_device.queue_submit(core.transfer_queue, &submit_info, fence).unwrap();
+        }
+        debug!(path = texture.path.as_str(), base, bytes = size, "Streaming texture");
+        texture.uploading = true;
+
+        self.uploads.push(Upload {
+            id,
+            base,
+            texture: Texture::from_parts(image, view, image_mem, levels),
+            staging,
+            command_buffer,
+            fence,
+            done_sem
+        });
+    }
+
+    fn finish_upload(&mut self, core: &VkCore, upload: Upload) {
+        unsafe {
+            core.logical_device.destroy_fence(upload.fence, None);
+            core.logical_device.free_command_buffers(self.command_pool, &[upload.command_buffer]);
+        }
+        upload.staging.destroy(core);
+
+        let texture = &mut self.textures[upload.id.0];
+        let old = texture.resident.replace((upload.texture, upload.base)).map(|(old, _)| old);
+        texture.uploading = false;
+        texture.generation += 1;
+        self.retired.push(Retired {
+            texture: old,
+            semaphore: upload.done_sem,
+            frames_left: self.max_frames
+        });
+        self.waits.push(upload.done_sem);
+    }
+
+    // Doesn't wait for the device, which may be lost
+    pub fn destroy(&self, core: &VkCore) {
+        self.placeholder.destroy(core);
+        for t in self.textures.iter() {
+            if let Some((texture, _)) = t.resident.as_ref() {
+                texture.destroy(core);
+            }
+        }
+        for u in self.uploads.iter() {
+            u.texture.destroy(core);
+            u.staging.destroy(core);
+            unsafe {
+                core.logical_device.destroy_fence(u.fence, None);
+                core.logical_device.destroy_semaphore(u.done_sem, None);
+            }
+        }
+        for r in self.retired.iter() {
+            if let Some(texture) = r.texture.as_ref() {
+                texture.destroy(core);
+            }
+            unsafe { core.logical_device.destroy_semaphore(r.semaphore, None) };
+        }
+        for t in self.virtual_textures.iter() {
+            t.destroy(core, self.command_pool);
+        }
+        unsafe { core.logical_device.destroy_command_pool(self.command_pool, None) };
+    }
+}
@@ -0,0 +1,67 @@
+use ash::vk;
+use clap::ValueEnum;
+use tracing::{info, warn};
+use winit::window::{WindowBuilder, WindowLevel};
+
+// Composite alpha modes that blend the swap chain image with what is behind the window, in order of preference.
+// Cleared pixels are transparent black and drawn ones opaque, so the image is premultiplied either way.
+const TRANSPARENT_COMPOSITE_ALPHA: [vk::CompositeAlphaFlagsKHR; 3] = [
+    vk::CompositeAlphaFlagsKHR::PRE_MULTIPLIED,
+    vk::CompositeAlphaFlagsKHR::POST_MULTIPLIED,
+    vk::CompositeAlphaFlagsKHR::INHERIT // The window system decides, with a transparent window that's blending
+];
+
+// Whether the desktop shows through where nothing is drawn. The window has to be created transparent, X11 picks an
+// ARGB visual for it, so this can't change while running. Needs a compositor, without one the window stays opaque.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum WindowTransparency {
+    #[default]
+    Opaque,
+    Transparent,
+    // Transparent, without decorations and above other windows, for desktop widgets
+    Overlay
+}
+
+impl WindowTransparency {
+    pub fn is_transparent(self) -> bool {
+        self != WindowTransparency::Opaque
+    }
+
+    pub(crate) fn window_builder(self, builder: WindowBuilder) -> WindowBuilder {
+        match self {
+            WindowTransparency::Opaque => builder,
+            WindowTransparency::Transparent => builder.with_transparent(true),
+            WindowTransparency::Overlay => builder
+                .with_transparent(true)
+                .with_decorations(false)
+                .with_window_level(WindowLevel::AlwaysOnTop)
+        }
+    }
+
+    // The swap chain's composite alpha, OPAQUE if the surface can't blend
+    pub(crate) fn composite_alpha(self, capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::CompositeAlphaFlagsKHR {
+        if !self.is_transparent() {
+            return vk::CompositeAlphaFlagsKHR::OPAQUE;
+        }
+
+        match TRANSPARENT_COMPOSITE_ALPHA.into_iter().find(|&a| capabilities.supported_composite_alpha.contains(a)) {
+            Some(composite_alpha) => {
+                info!(?composite_alpha, "Transparent window");
+                composite_alpha
+            },
+            None => {
+                warn!(supported = ?capabilities.supported_composite_alpha,
+                      "The surface can't blend with the desktop, the window stays opaque");
+                vk::CompositeAlphaFlagsKHR::OPAQUE
+            }
+        }
+    }
+}
+
+// Swap chain formats whose alpha reaches the compositor
+pub(crate) fn format_has_alpha(format: vk::Format) -> bool {
+    matches!(format,
+        vk::Format::B8G8R8A8_SRGB | vk::Format::B8G8R8A8_UNORM | vk::Format::R8G8B8A8_SRGB |
+        vk::Format::R8G8B8A8_UNORM | vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32 |
+        vk::Format::R16G16B16A16_SFLOAT)
+}
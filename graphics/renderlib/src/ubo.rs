@@ -1,9 +1,12 @@
+use std::ffi::c_void;
 use std::mem;
-// use std::time::Instant;
 
 use ash::vk;
-use cgmath::{Matrix4, Deg, Point3, Vector3, perspective};
+use cgmath::{Matrix4, Deg, Point3, Vector3};
+use crate::clock::Clock;
+use crate::scene::CameraProjection;
 use crate::gpu_buffer::{create_buffer};
+use crate::memory::mapped_memory_flags;
 use crate::render_target::RenderTarget;
 use crate::vkcore::VkCore;
 
@@ -16,36 +19,57 @@ pub(crate) struct UniformBufferObject {
     proj: Matrix4<f32>
 }
 
+// Indexed by gl_ViewIndex in the multiview vertex shader, 0 is the left eye and 1 is the right eye
+#[repr(C)]
+#[derive(Clone, Debug, Copy)]
+pub(crate) struct StereoUniformBufferObject {
+    model: Matrix4<f32>,
+    view: [Matrix4<f32>; 2],
+    proj: [Matrix4<f32>; 2]
+}
+
 pub struct  UniformBuffer {
     pub(crate) data: Vec<vk::Buffer>,
+    pub(crate) range: vk::DeviceSize, // Size of a single frame's UBO
     mem: Vec<vk::DeviceMemory>,
-    mapped: Vec<*mut UniformBufferObject>
-    // start_time: Instant
+    mapped: Vec<*mut c_void>
+}
+
+fn build_view() -> Matrix4<f32> {
+    Matrix4::look_at_rh(Point3::new(2.0, 2.0, 2.0),
+                        Point3::new(0.0, 0.0, 0.0),
+                        Vector3::new(0.0, 0.0, 1.0))
 }
 
 impl UniformBuffer {
     pub fn new(core: &VkCore, max_frames: usize) -> UniformBuffer {
-        let buffer_size: vk::DeviceSize = mem::size_of::<UniformBufferObject>() as vk::DeviceSize;
-        // let start_time = Instant::now();
+        UniformBuffer::new_sized(core, max_frames, mem::size_of::<UniformBufferObject>() as vk::DeviceSize)
+    }
+
+    // Per view matrices for multiview rendering, see build_stereo_transforms
+    pub fn new_stereo(core: &VkCore, max_frames: usize) -> UniformBuffer {
+        UniformBuffer::new_sized(core, max_frames, mem::size_of::<StereoUniformBufferObject>() as vk::DeviceSize)
+    }
+
+    fn new_sized(core: &VkCore, max_frames: usize, buffer_size: vk::DeviceSize) -> UniformBuffer {
         let mut uniform_buffer: UniformBuffer = UniformBuffer {
             data: vec![],
+            range: buffer_size,
             mem: vec![],
             mapped: vec![]
-            // start_time
         };
 
         for _ in 0..max_frames {
             let (buf_mem, buffer) = create_buffer(core, buffer_size, vk::BufferUsageFlags::UNIFORM_BUFFER,
-                                                  vk::MemoryPropertyFlags::HOST_COHERENT |
-                                                      vk::MemoryPropertyFlags::HOST_VISIBLE);
+                                                  mapped_memory_flags(core));
             uniform_buffer.mem.push(buf_mem);
             uniform_buffer.data.push(buffer);
 
-            let dev_memory: *mut UniformBufferObject;
+            let dev_memory: *mut c_void;
             unsafe {
                 dev_memory = core.logical_device
                     .map_memory(buf_mem, 0, buffer_size, vk::MemoryMapFlags::empty())
-                    .unwrap() as *mut UniformBufferObject;
+                    .unwrap();
             }
             uniform_buffer.mapped.push(dev_memory);
         }
@@ -53,26 +77,83 @@ impl UniformBuffer {
         uniform_buffer
     }
 
-    pub fn build_transforms(&self, render_target: &RenderTarget, current_frame: usize) {
-        // let current_time = Instant::now();
-        // let time = current_time.duration_since(self.start_time).as_millis() as f32 / 1000.0;
-        let time = 0.0;
+    // Same matrices build_transforms uploads, for CPU side culling and projection
+    pub fn view_projection(render_target: &RenderTarget, projection: &CameraProjection) -> Matrix4<f32> {
+        projection.matrix(render_target.extent) * build_view()
+    }
+
+    // The model turns 90 degrees per second of the clock's time, so it stops while the clock is paused
+    pub fn build_transforms(&self, render_target: &RenderTarget, current_frame: usize, projection: &CameraProjection,
+                            clock: &Clock) {
+        let time = clock.seconds();
+
+        let transform_matrices = [UniformBufferObject {
+            model: Matrix4::from_angle_z(Deg(90.0 * time)),
+            view: build_view(),
+            proj: projection.matrix(render_target.extent)
+        }];
+
+        assert_eq!(self.range, mem::size_of::<UniformBufferObject>() as vk::DeviceSize);
+        unsafe {
+            (self.mapped[current_frame] as *mut UniformBufferObject)
+                .copy_from_nonoverlapping(transform_matrices.as_ptr(), transform_matrices.len());
+        }
+    }
 
-        let mut perspective = perspective(Deg(45.0),
-                                          (render_target.extent.width as f32) /
-                                              (render_target.extent.height as f32),
-                                          0.1, 10.0);
-        perspective.y.y *= -1.0;
+    // Same as build_transforms with the matrices supplied by the caller, for example from a Scene
+    pub fn set_transforms(&self, current_frame: usize, model: Matrix4<f32>, view: Matrix4<f32>, proj: Matrix4<f32>) {
         let transform_matrices = [UniformBufferObject {
+            model,
+            view,
+            proj
+        }];
+
+        assert_eq!(self.range, mem::size_of::<UniformBufferObject>() as vk::DeviceSize);
+        unsafe {
+            (self.mapped[current_frame] as *mut UniformBufferObject)
+                .copy_from_nonoverlapping(transform_matrices.as_ptr(), transform_matrices.len());
+        }
+    }
+
+    // eye_extent is the size of a single eye's layer. The eyes are offset along the view space x axis by half of
+    // eye_separation each, which keeps both view directions parallel.
+    pub fn build_stereo_transforms(&self, eye_extent: vk::Extent2D, current_frame: usize, eye_separation: f32,
+                                   projection: &CameraProjection, clock: &Clock) {
+        let time = clock.seconds();
+
+        let view = build_view();
+        let proj = projection.matrix(eye_extent);
+        let half_separation = eye_separation / 2.0;
+        let transform_matrices = [StereoUniformBufferObject {
             model: Matrix4::from_angle_z(Deg(90.0 * time)),
-            view: Matrix4::look_at_rh(Point3::new(2.0, 2.0, 2.0),
-                                      Point3::new(0.0, 0.0, 0.0),
-                                      Vector3::new(0.0, 0.0, 1.0)),
-            proj: perspective
+            view: [Matrix4::from_translation(Vector3::new(half_separation, 0.0, 0.0)) * view, // Left
+                Matrix4::from_translation(Vector3::new(-half_separation, 0.0, 0.0)) * view], // Right
+            proj: [proj, proj]
+        }];
+
+        assert_eq!(self.range, mem::size_of::<StereoUniformBufferObject>() as vk::DeviceSize);
+        unsafe {
+            (self.mapped[current_frame] as *mut StereoUniformBufferObject)
+                .copy_from_nonoverlapping(transform_matrices.as_ptr(), transform_matrices.len());
+        }
+    }
+
+    // Same as build_stereo_transforms with the matrices of a camera supplied by the caller, view is the one between
+    // the eyes
+    pub fn set_stereo_transforms(&self, current_frame: usize, model: Matrix4<f32>, view: Matrix4<f32>,
+                                 proj: Matrix4<f32>, eye_separation: f32) {
+        let half_separation = eye_separation / 2.0;
+        let transform_matrices = [StereoUniformBufferObject {
+            model,
+            view: [Matrix4::from_translation(Vector3::new(half_separation, 0.0, 0.0)) * view, // Left
+                Matrix4::from_translation(Vector3::new(-half_separation, 0.0, 0.0)) * view], // Right
+            proj: [proj, proj]
         }];
 
+        assert_eq!(self.range, mem::size_of::<StereoUniformBufferObject>() as vk::DeviceSize);
         unsafe {
-            self.mapped[current_frame].copy_from_nonoverlapping(transform_matrices.as_ptr(), transform_matrices.len());
+            (self.mapped[current_frame] as *mut StereoUniformBufferObject)
+                .copy_from_nonoverlapping(transform_matrices.as_ptr(), transform_matrices.len());
         }
     }
 
@@ -0,0 +1,188 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::color_pipeline::LINEAR_INTERMEDIATE_FORMAT;
+use crate::compute::{ComputePipeline, group_count};
+use crate::image::StorageImage;
+use crate::internal_resolution::{BlitImage, cmd_blit_placed, InternalResolution};
+use crate::renderutils::cast_to_u8_slice;
+use crate::ssao::create_set_layout;
+use crate::vkcore::VkCore;
+
+const EASU_SHADER_PATH: &str = "graphics/shaders/spv/upscale_easu.spv";
+const RCAS_SHADER_PATH: &str = "graphics/shaders/spv/upscale_rcas.spv";
+const UPSCALE_GROUP_SIZE: u32 = 8; // Matches local_size_x/y in both shaders
+
+// Matches the push constants in upscale_rcas.comp
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct RcasConstants {
+    sharpness: f32 // exp2(-stops), 1 is the strongest
+}
+
+// Edge adaptive spatial upsampling with sharpening, in the style of FSR 1, for the filter UpscaleFilter::EdgeAdaptive.
+// upscale_easu.comp fits a Lanczos like kernel to the 12 nearest texels of each output pixel, stretched along the
+// edge through them and clamped to the 4 nearest to avoid ringing. upscale_rcas.comp then sharpens the result with a
+// cross of 5 texels, limited so that no pixel clips. Each frame the rendered image is copied in, cmd_apply runs both
+// passes at the size it takes in the window, and cmd_copy_out places the result like InternalResolution::placement.
+pub struct Upscaler {
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: [vk::DescriptorSet; 2], // EASU reads input and writes upscaled, RCAS reads upscaled and writes output
+    easu: ComputePipeline,
+    rcas: ComputePipeline,
+    input: StorageImage, // Of the rendered image's extent
+    upscaled: StorageImage, // Of placement's extent, like output
+    output: StorageImage,
+    input_extent: vk::Extent2D,
+    window_extent: vk::Extent2D,
+    placement: vk::Rect2D,
+    sharpness: f32
+}
+
+impl Upscaler {
+    // Recreate it with the swap chain, its images have resolution's sizes for window_extent
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, resolution: &InternalResolution,
+               window_extent: vk::Extent2D) -> Upscaler {
+        let input_extent = resolution.extent(window_extent);
+        let placement = resolution.placement(window_extent);
+        let storage_image = (vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::COMPUTE);
+        let set_layout = create_set_layout(core, &[storage_image, storage_image]);
+        let input = StorageImage::new(core, command_pool, input_extent, LINEAR_INTERMEDIATE_FORMAT,
+                                      vk::ImageUsageFlags::TRANSFER_DST);
+        let upscaled = StorageImage::new(core, command_pool, placement.extent, LINEAR_INTERMEDIATE_FORMAT,
+                                         vk::ImageUsageFlags::empty());
+        let output = StorageImage::new(core, command_pool, placement.extent, LINEAR_INTERMEDIATE_FORMAT,
+                                       vk::ImageUsageFlags::TRANSFER_SRC);
+
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_IMAGE)
+            .descriptor_count(4)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(2)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = [set_layout, set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        let sets = [sets[0], sets[1]];
+
+        let (input_info, upscaled_info, output_info) = (input.info(), upscaled.info(), output.info());
+        let mut writes: Vec<vk::WriteDescriptorSet> = Vec::new();
+        for (set, infos) in sets.iter().zip([[&input_info, &upscaled_info], [&upscaled_info, &output_info]]) {
+            for (binding, info) in infos.into_iter().enumerate() {
+                writes.push(vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(info));
+            }
+        }
+        unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+
+        Upscaler {
+            set_layout,
+            descriptor_pool,
+            sets,
+            easu: ComputePipeline::new(core, EASU_SHADER_PATH, &[set_layout], 0),
+            rcas: ComputePipeline::new(core, RCAS_SHADER_PATH, &[set_layout],
+                                       mem::size_of::<RcasConstants>() as u32),
+            input,
+            upscaled,
+            output,
+            input_extent,
+            window_extent,
+            placement,
+            sharpness: resolution.sharpness
+        }
+    }
+
+    // What to render or copy into instead of cmd_copy_in, like with PostProcessChain::cmd_copy_out. In GENERAL.
+    pub fn input_image(&self) -> vk::Image {
+        self.input.image
+    }
+
+    // Blits all of source, the rendered image, into the input. An SRGB source is decoded.
+    pub fn cmd_copy_in(&self, core: &VkCore, command_buffer: vk::CommandBuffer, source: vk::Image,
+                       source_layout: vk::ImageLayout) {
+        // The previous frame's reads of the input
+        let barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE);
+        let window = vk::Rect2D { offset: vk::Offset2D::default(), extent: self.input_extent };
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::TRANSFER,
+                                                     vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+        }
+        let source = BlitImage { image: source, layout: source_layout, extent: self.input_extent };
+        let input = BlitImage { image: self.input.image, layout: vk::ImageLayout::GENERAL, extent: self.input_extent };
+        cmd_blit_placed(core, command_buffer, &source, &input, window, vk::Filter::NEAREST);
+    }
+
+    // Once the input has been written, by a transfer or a compute shader
+    pub fn cmd_apply(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        // The input's writes, and the previous frame's reads of the upscaled and output images
+        let input_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let easu_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let constants = [RcasConstants { sharpness: (-self.sharpness).exp2() }];
+        let (groups_x, groups_y) = (group_count(self.placement.extent.width, UPSCALE_GROUP_SIZE),
+                                    group_count(self.placement.extent.height, UPSCALE_GROUP_SIZE));
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer,
+                                                     vk::PipelineStageFlags::TRANSFER |
+                                                         vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[input_barrier], &[], &[]);
+            self.easu.cmd_bind(core, command_buffer, self.sets[0]);
+            core.logical_device.cmd_dispatch(command_buffer, groups_x, groups_y, 1);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[easu_barrier], &[], &[]);
+            self.rcas.cmd_bind(core, command_buffer, self.sets[1]);
+            core.logical_device.cmd_push_constants(command_buffer, self.rcas.layout, vk::ShaderStageFlags::COMPUTE,
+                                                   0, cast_to_u8_slice(&constants));
+            core.logical_device.cmd_dispatch(command_buffer, groups_x, groups_y, 1);
+        }
+    }
+
+    // Into target, the swap chain image the upscaler was created for, clearing the bars. target must be in
+    // TRANSFER_DST_OPTIMAL or GENERAL.
+    pub fn cmd_copy_out(&self, core: &VkCore, command_buffer: vk::CommandBuffer, target: vk::Image,
+                        target_layout: vk::ImageLayout) {
+        let barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::TRANSFER,
+                                                     vk::DependencyFlags::empty(), &[barrier], &[], &[]);
+        }
+        let output = BlitImage {
+            image: self.output.image,
+            layout: vk::ImageLayout::GENERAL,
+            extent: self.placement.extent
+        };
+        let target = BlitImage { image: target, layout: target_layout, extent: self.window_extent };
+        cmd_blit_placed(core, command_buffer, &output, &target, self.placement, vk::Filter::NEAREST);
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+        self.easu.destroy(core);
+        self.rcas.destroy(core);
+        self.input.destroy(core);
+        self.upscaled.destroy(core);
+        self.output.destroy(core);
+    }
+}
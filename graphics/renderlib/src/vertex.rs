@@ -3,6 +3,10 @@ use std::mem;
 
 use ash::vk;
 
+use crate::color_pipeline::ColorConstants;
+use crate::renderutils::cast_to_u8_slice;
+use crate::vkcore::VkCore;
+
 #[repr(C)]
 #[derive(Clone, Debug, Copy)]
 pub struct Vertex {
@@ -12,14 +16,14 @@ pub struct Vertex {
 }
 
 impl Vertex {
-    pub(crate) fn get_binding_description() -> vk::VertexInputBindingDescription {
+    pub fn get_binding_description() -> vk::VertexInputBindingDescription {
         vk::VertexInputBindingDescription::default()
             .binding(0)
             .stride(mem::size_of::<Vertex>() as u32) // Number of bytes per entry in the binding
             .input_rate(vk::VertexInputRate::VERTEX) // ??
     }
 
-    pub(crate) fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 3] {
+    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 3] {
         [vk::VertexInputAttributeDescription {
             location: 0,
             binding: 0, // Index of the vertex binding
@@ -39,3 +43,291 @@ impl Vertex {
         }]
     }
 }
+
+// Vertex influenced by up to four joints, see animation.rs. Joint indices refer to Skeleton::joints.
+#[repr(C)]
+#[derive(Clone, Debug, Copy)]
+pub struct SkinnedVertex {
+    pub pos: [f32; 3],
+    pub color: [f32; 3],
+    pub tex_coord: [f32; 2],
+    pub joints: [u32; 4],
+    pub weights: [f32; 4] // Should sum to 1
+}
+
+impl SkinnedVertex {
+    pub fn get_binding_description() -> vk::VertexInputBindingDescription {
+        vk::VertexInputBindingDescription::default()
+            .binding(0)
+            .stride(mem::size_of::<SkinnedVertex>() as u32)
+            .input_rate(vk::VertexInputRate::VERTEX)
+    }
+
+    // Locations 0-2 match Vertex
+    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 5] {
+        [vk::VertexInputAttributeDescription {
+            location: 0,
+            binding: 0,
+            format: vk::Format::R32G32B32_SFLOAT,
+            offset: offset_of!(SkinnedVertex, pos) as u32
+        }, vk::VertexInputAttributeDescription {
+            location: 1,
+            binding: 0,
+            format: vk::Format::R32G32B32_SFLOAT,
+            offset: offset_of!(SkinnedVertex, color) as u32
+        }, vk::VertexInputAttributeDescription {
+            location: 2,
+            binding: 0,
+            format: vk::Format::R32G32_SFLOAT,
+            offset: offset_of!(SkinnedVertex, tex_coord) as u32
+        }, vk::VertexInputAttributeDescription {
+            location: 3,
+            binding: 0,
+            format: vk::Format::R32G32B32A32_UINT,
+            offset: offset_of!(SkinnedVertex, joints) as u32
+        }, vk::VertexInputAttributeDescription {
+            location: 4,
+            binding: 0,
+            format: vk::Format::R32G32B32A32_SFLOAT,
+            offset: offset_of!(SkinnedVertex, weights) as u32
+        }]
+    }
+}
+
+// Vertex of greedy meshed voxel chunks, written by voxel_mesh.comp. The block is in color's red component, occlusion
+// is how many of the three blocks around the corner on the face's side are air, 0 to 3, see VOXEL_OCCLUSION.
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default, PartialEq)]
+pub struct VoxelVertex {
+    pub pos: [f32; 3],
+    pub color: [f32; 3],
+    pub tex_coord: [f32; 2],
+    pub occlusion: f32
+}
+
+// Location 7 of voxel_mesh.vert, after the fixed ones
+pub const VOXEL_OCCLUSION: VertexAttribute = VertexAttribute::Custom { location: 7, format: vk::Format::R32_SFLOAT };
+
+// Vertex compressed by MeshData::quantized to 24 bytes, against 44 for a MeshData vertex with all four attributes.
+// Positions and texture coordinates span the mesh's range, Dequantization maps them back.
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Default, PartialEq)]
+pub struct QuantizedVertex {
+    pub pos: [u16; 4], // w is padding
+    pub normal: [i16; 4], // Signed normalized, w is padding
+    pub tex_coord: [u16; 2],
+    pub color: [u8; 4] // Alpha is 255
+}
+
+// Offset of Dequantization in the push constants of quantized pipelines, after the fragment shader's ColorConstants
+pub const DEQUANTIZATION_OFFSET: u32 = mem::size_of::<ColorConstants>() as u32;
+
+// Mirrors the push constants of quantized.vert. Unsigned normalized components read as 0 to 1 in the shader, which
+// scales them into the mesh's range.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dequantization {
+    pub position_offset: [f32; 4], // The minimum of the mesh's bounds, w unused
+    pub position_scale: [f32; 4], // Their size
+    pub tex_coord: [f32; 4] // Offset in xy, scale in zw
+}
+
+impl Dequantization {
+    // Like the shader does it, for checking the precision on the CPU
+    pub fn position(&self, v: &QuantizedVertex) -> [f32; 3] {
+        [0, 1, 2].map(|i| self.position_offset[i] + self.position_scale[i] * v.pos[i] as f32 / u16::MAX as f32)
+    }
+
+    pub fn tex_coord(&self, v: &QuantizedVertex) -> [f32; 2] {
+        [0, 1].map(|i| self.tex_coord[i] + self.tex_coord[i + 2] * v.tex_coord[i] as f32 / u16::MAX as f32)
+    }
+
+    // Before drawing the mesh with a pipeline from RasterPipeline::new_quantized
+    pub fn cmd_push(&self, core: &VkCore, command_buffer: vk::CommandBuffer, layout: vk::PipelineLayout) {
+        unsafe {
+            core.logical_device.cmd_push_constants(command_buffer, layout,
+                                                   vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                                                   DEQUANTIZATION_OFFSET, cast_to_u8_slice(self));
+        }
+    }
+}
+
+// Inputs of a vertex shader. Each has a fixed location, so that a shader reads the same attribute wherever a
+// VertexLayout puts it and meshes without some of them can still use fitting pipelines. Locations 0-4 match Vertex
+// and SkinnedVertex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VertexAttribute {
+    Position, // vec3
+    Color, // vec3
+    TexCoord, // vec2
+    Joints, // uvec4, see SkinnedVertex
+    Weights, // vec4
+    Normal, // vec3
+    Tangent, // vec4, w is the handedness of the bitangent
+    Custom { location: u32, format: vk::Format }
+}
+
+impl VertexAttribute {
+    pub fn location(&self) -> u32 {
+        match *self {
+            VertexAttribute::Position => 0,
+            VertexAttribute::Color => 1,
+            VertexAttribute::TexCoord => 2,
+            VertexAttribute::Joints => 3,
+            VertexAttribute::Weights => 4,
+            VertexAttribute::Normal => 5,
+            VertexAttribute::Tangent => 6,
+            VertexAttribute::Custom { location, .. } => location
+        }
+    }
+
+    pub fn format(&self) -> vk::Format {
+        match *self {
+            VertexAttribute::Position | VertexAttribute::Color | VertexAttribute::Normal =>
+                vk::Format::R32G32B32_SFLOAT,
+            VertexAttribute::TexCoord => vk::Format::R32G32_SFLOAT,
+            VertexAttribute::Joints => vk::Format::R32G32B32A32_UINT,
+            VertexAttribute::Weights | VertexAttribute::Tangent => vk::Format::R32G32B32A32_SFLOAT,
+            VertexAttribute::Custom { format, .. } => format
+        }
+    }
+
+    // The normalized integer attribute at the same location that QuantizedVertex stores. Joints, weights, tangents and
+    // custom attributes aren't quantized.
+    pub fn quantized(&self) -> VertexAttribute {
+        let format = match *self {
+            VertexAttribute::Position => vk::Format::R16G16B16A16_UNORM,
+            VertexAttribute::Color => vk::Format::R8G8B8A8_UNORM,
+            VertexAttribute::TexCoord => vk::Format::R16G16_UNORM,
+            VertexAttribute::Normal => vk::Format::R16G16B16A16_SNORM,
+            _ => return *self
+        };
+        VertexAttribute::Custom { location: self.location(), format }
+    }
+
+    // Bytes in a vertex, only 32 bit component formats and those of quantized attributes are known for Custom ones
+    pub fn size(&self) -> u32 {
+        match self.format() {
+            vk::Format::R8G8B8A8_UNORM | vk::Format::R16G16_UNORM => 4,
+            vk::Format::R16G16B16A16_UNORM | vk::Format::R16G16B16A16_SNORM => 8,
+            vk::Format::R32_SFLOAT | vk::Format::R32_UINT | vk::Format::R32_SINT => 4,
+            vk::Format::R32G32_SFLOAT | vk::Format::R32G32_UINT | vk::Format::R32G32_SINT => 8,
+            vk::Format::R32G32B32_SFLOAT | vk::Format::R32G32B32_UINT | vk::Format::R32G32B32_SINT => 12,
+            vk::Format::R32G32B32A32_SFLOAT | vk::Format::R32G32B32A32_UINT | vk::Format::R32G32B32A32_SINT => 16,
+            f => panic!("No size known for vertex attribute format {:?}", f)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct LayoutBinding {
+    stride: u32,
+    input_rate: vk::VertexInputRate
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct LayoutAttribute {
+    binding: u32,
+    attribute: VertexAttribute,
+    offset: u32
+}
+
+// The vertex buffer bindings of a pipeline and the attributes interleaved in each, built at runtime like
+// VertexLayout::new().attribute(VertexAttribute::Position).attribute(VertexAttribute::Normal). Attributes are packed
+// in the order they are added, without padding, which matches #[repr(C)] structs of 32 bit fields like Vertex.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VertexLayout {
+    bindings: Vec<LayoutBinding>,
+    attributes: Vec<LayoutAttribute>
+}
+
+impl VertexLayout {
+    // Without bindings, for vertices generated in the vertex shader like fullscreen triangles
+    pub fn new() -> VertexLayout {
+        VertexLayout::default()
+    }
+
+    // Like Vertex::get_attribute_descriptions
+    pub fn vertex() -> VertexLayout {
+        VertexLayout::from_attributes(&[VertexAttribute::Position, VertexAttribute::Color, VertexAttribute::TexCoord])
+    }
+
+    // Like SkinnedVertex::get_attribute_descriptions
+    pub fn skinned() -> VertexLayout {
+        VertexLayout::from_attributes(&[VertexAttribute::Position, VertexAttribute::Color, VertexAttribute::TexCoord,
+            VertexAttribute::Joints, VertexAttribute::Weights])
+    }
+
+    // Like VoxelVertex
+    pub fn voxel() -> VertexLayout {
+        VertexLayout::vertex().attribute(VOXEL_OCCLUSION)
+    }
+
+    // Like QuantizedVertex
+    pub fn quantized() -> VertexLayout {
+        VertexLayout::from_attributes(&[VertexAttribute::Position.quantized(), VertexAttribute::Normal.quantized(),
+            VertexAttribute::TexCoord.quantized(), VertexAttribute::Color.quantized()])
+    }
+
+    // A single binding with the attributes interleaved in this order
+    pub fn from_attributes(attributes: &[VertexAttribute]) -> VertexLayout {
+        attributes.iter().fold(VertexLayout::new(), |layout, &a| layout.attribute(a))
+    }
+
+    // Starts the next binding, the following attributes are read from its buffer
+    pub fn binding(mut self, input_rate: vk::VertexInputRate) -> VertexLayout {
+        self.bindings.push(LayoutBinding { stride: 0, input_rate });
+        self
+    }
+
+    // Appended to the last binding, which is a per vertex one if there was none. Panics if the location is taken.
+    pub fn attribute(mut self, attribute: VertexAttribute) -> VertexLayout {
+        if self.attributes.iter().any(|a| a.attribute.location() == attribute.location()) {
+            panic!("{:?} reuses location {} of {:?}", attribute, attribute.location(), self);
+        }
+        if self.bindings.is_empty() {
+            self = self.binding(vk::VertexInputRate::VERTEX);
+        }
+        let binding = self.bindings.len() - 1;
+        self.attributes.push(LayoutAttribute {
+            binding: binding as u32,
+            attribute,
+            offset: self.bindings[binding].stride
+        });
+        self.bindings[binding].stride += attribute.size();
+        self
+    }
+
+    pub fn has(&self, attribute: VertexAttribute) -> bool {
+        self.attributes.iter().any(|a| a.attribute == attribute)
+    }
+
+    pub fn attributes(&self) -> impl Iterator<Item = VertexAttribute> + '_ {
+        self.attributes.iter().map(|a| a.attribute)
+    }
+
+    // Bytes per vertex, or per instance, in a binding's buffer
+    pub fn stride(&self, binding: u32) -> u32 {
+        self.bindings[binding as usize].stride
+    }
+
+    pub fn binding_descriptions(&self) -> Vec<vk::VertexInputBindingDescription> {
+        self.bindings.iter().enumerate()
+            .map(|(i, b)| vk::VertexInputBindingDescription::default()
+                .binding(i as u32)
+                .stride(b.stride)
+                .input_rate(b.input_rate))
+            .collect()
+    }
+
+    pub fn attribute_descriptions(&self) -> Vec<vk::VertexInputAttributeDescription> {
+        self.attributes.iter()
+            .map(|a| vk::VertexInputAttributeDescription {
+                location: a.attribute.location(),
+                binding: a.binding,
+                format: a.attribute.format(),
+                offset: a.offset
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,145 @@
+use ash::vk;
+use cgmath::{InnerSpace, Matrix4, Vector3};
+
+use crate::scene::{CameraProjection, SceneCamera};
+
+pub const MAX_VIEWPORTS: usize = 4; // Each one has its own transforms in every frame in flight
+
+// Part of the window a camera is drawn into, in fractions of the window's size from its top left corner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewportRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32
+}
+
+impl ViewportRect {
+    pub const FULL: ViewportRect = ViewportRect { x: 0.0, y: 0.0, width: 1.0, height: 1.0 };
+
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> ViewportRect {
+        ViewportRect { x, y, width, height }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let inside = |start: f32, size: f32| start >= 0.0 && size > 0.0 && start + size <= 1.0;
+        match inside(self.x, self.width) && inside(self.y, self.height) {
+            true => Ok(()),
+            false => Err(format!("{:?} must be a non-empty part of the window", self))
+        }
+    }
+
+    // Whole pixels of a target, edges shared with a neighboring rect round to the same pixel so nothing is left out
+    pub fn pixels(&self, extent: vk::Extent2D) -> vk::Rect2D {
+        let (w, h) = (extent.width as f32, extent.height as f32);
+        let (left, top) = ((self.x * w).round() as u32, (self.y * h).round() as u32);
+        let (right, bottom) = (((self.x + self.width) * w).round() as u32, ((self.y + self.height) * h).round() as u32);
+        vk::Rect2D::default()
+            .offset(vk::Offset2D::default().x(left as i32).y(top as i32))
+            .extent(vk::Extent2D::default().width((right - left).max(1)).height((bottom - top).max(1)))
+    }
+
+    // The camera's aspect ratio follows this, see SceneCamera::projection
+    pub fn extent(&self, extent: vk::Extent2D) -> vk::Extent2D {
+        self.pixels(extent).extent
+    }
+
+    pub fn viewport(&self, extent: vk::Extent2D) -> vk::Viewport {
+        let pixels = self.pixels(extent);
+        vk::Viewport::default()
+            .x(pixels.offset.x as f32)
+            .y(pixels.offset.y as f32)
+            .width(pixels.extent.width as f32)
+            .height(pixels.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)
+    }
+
+    // A point of the window in pixels, relative to the rect's top left corner if it is inside
+    pub fn local_point(&self, extent: vk::Extent2D, x: f32, y: f32) -> Option<(f32, f32)> {
+        let pixels = self.pixels(extent);
+        let (x, y) = (x - pixels.offset.x as f32, y - pixels.offset.y as f32);
+        match x >= 0.0 && y >= 0.0 && x < pixels.extent.width as f32 && y < pixels.extent.height as f32 {
+            true => Some((x, y)),
+            false => None
+        }
+    }
+
+    // Maps clip space of a projection made for the rect onto the whole window's, for passes that don't set the
+    // rect as their viewport but have to line up with what was drawn in it, like culling against the depth buffer
+    pub fn clip_transform(&self) -> Matrix4<f32> {
+        let center = [(self.x + self.width / 2.0) * 2.0 - 1.0, (self.y + self.height / 2.0) * 2.0 - 1.0];
+        Matrix4::from_translation(Vector3::new(center[0], center[1], 0.0)) *
+            Matrix4::from_nonuniform_scale(self.width, self.height, 1.0)
+    }
+}
+
+// How the window is split between cameras, see ViewportLayout::rects
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ViewportLayout {
+    Single,
+    SideBySide, // Left and right halves
+    OverUnder, // Top and bottom halves
+    Quad // Quarters in reading order
+}
+
+impl ViewportLayout {
+    pub fn rects(&self) -> Vec<ViewportRect> {
+        match self {
+            ViewportLayout::Single => vec![ViewportRect::FULL],
+            ViewportLayout::SideBySide => vec![ViewportRect::new(0.0, 0.0, 0.5, 1.0),
+                                               ViewportRect::new(0.5, 0.0, 0.5, 1.0)],
+            ViewportLayout::OverUnder => vec![ViewportRect::new(0.0, 0.0, 1.0, 0.5),
+                                              ViewportRect::new(0.0, 0.5, 1.0, 0.5)],
+            ViewportLayout::Quad => vec![ViewportRect::new(0.0, 0.0, 0.5, 0.5), ViewportRect::new(0.5, 0.0, 0.5, 0.5),
+                                         ViewportRect::new(0.0, 0.5, 0.5, 0.5), ViewportRect::new(0.5, 0.5, 0.5, 0.5)]
+        }
+    }
+
+    // One viewport per rect, the cameras are taken in order and there must be one for every rect
+    pub fn viewports(&self, cameras: &[SceneCamera]) -> Result<Vec<Viewport>, String> {
+        let rects = self.rects();
+        if cameras.len() != rects.len() {
+            return Err(format!("{:?} has {} viewports, not {}", self, rects.len(), cameras.len()));
+        }
+
+        Ok(rects.into_iter().zip(cameras).map(|(rect, camera)| Viewport { rect, camera: camera.clone() }).collect())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Viewport {
+    pub rect: ViewportRect,
+    pub camera: SceneCamera
+}
+
+impl Viewport {
+    pub fn validate(&self) -> Result<(), String> {
+        self.rect.validate()?;
+        self.camera.projection.validate()
+    }
+}
+
+// The quad view of an editor: the camera in the top left, and orthographic views from the top, the front (along +Y)
+// and the right side (along -X) of its target in the other quarters. height is the world units the orthographic views
+// span vertically.
+pub fn editor_quad(camera: &SceneCamera, height: f32) -> Vec<Viewport> {
+    let distance = (camera.target() - camera.eye()).magnitude().max(height);
+    let projection = CameraProjection::Orthographic { height, near: 0.01, far: distance * 2.0 };
+    let from = |direction: Vector3<f32>, up: [f32; 3]| SceneCamera {
+        eye: (camera.target() - direction * distance).into(),
+        target: camera.target,
+        up,
+        projection,
+        focus_distance: None,
+        aperture: 0.0
+    };
+    let cameras = [
+        camera.clone(),
+        from(Vector3::new(0.0, 0.0, -1.0), [0.0, 1.0, 0.0]),
+        from(Vector3::new(0.0, 1.0, 0.0), [0.0, 0.0, 1.0]),
+        from(Vector3::new(-1.0, 0.0, 0.0), [0.0, 0.0, 1.0])
+    ];
+
+    ViewportLayout::Quad.viewports(&cameras).unwrap()
+}
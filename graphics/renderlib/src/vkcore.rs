@@ -5,25 +5,65 @@ use std::path::Path;
 use ash::extensions::khr;
 use ash::{Entry, Instance, vk, Device};
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle};
+use tracing::{debug, info, info_span};
 use winit::dpi::LogicalSize;
 use winit::event_loop::EventLoop;
 use winit::window::{Icon, WindowBuilder, Window};
 
+#[cfg(feature = "device-group")]
+use crate::device_group::{DeviceGroup, find_group};
+use crate::gpu_select::{GpuInfo, GpuSelection, instance_gpus};
+use crate::capabilities::{Capabilities, FeatureSource, instance_api_version, MIN_API_VERSION};
+use crate::memory::{RebarPolicy, TransientPolicy, UploadCounters};
+use crate::transparency::WindowTransparency;
+
 pub struct VkCore {
     _entry: Entry,
     pub window: Window,
+    pub transparency: WindowTransparency, // The window was created with it, see RenderTarget
     pub instance: Instance,
     pub(crate) surface: vk::SurfaceKHR,
     pub(crate) surface_loader: khr::Surface,
     pub physical_device: vk::PhysicalDevice,
+    pub gpu: GpuInfo, // The physical device's, see GpuSelection
     pub present_family_index: u32,
     pub graphics_family_index: u32,
+    pub transfer_family_index: u32, // Dedicated transfer family if the device has one, the graphics family otherwise
+    pub compute_family_index: u32, // Compute family without graphics if there is one, the graphics family otherwise
     pub(crate) supported_surface_formats: Vec<vk::SurfaceFormatKHR>,
     pub(crate) present_modes: Vec<vk::PresentModeKHR>,
     pub max_msaa_samples: vk::SampleCountFlags,
     pub present_queue: vk::Queue,
     pub graphics_queue: vk::Queue,
-    pub logical_device: Device
+    pub transfer_queue: vk::Queue,
+    pub compute_queue: vk::Queue,
+    pub logical_device: Device,
+    pub multiview_supported: bool,
+    pub index_type_uint8_supported: bool, // VK_EXT_index_type_uint8 is enabled, u8 indices can be bound directly
+    pub draw_indirect_count_supported: bool, // VK_KHR_draw_indirect_count is enabled
+    pub full_screen_exclusive_supported: bool, // VK_EXT_full_screen_exclusive is enabled, Windows only
+    pub present_wait_supported: bool, // VK_KHR_present_id and VK_KHR_present_wait are both enabled
+    pub memory_budget_supported: bool, // VK_EXT_memory_budget is enabled, see MemoryStats
+    // Sparse binding and 2D sparse residency are enabled, and the graphics queue binds sparse memory. See
+    // SparseTexture.
+    pub sparse_residency_supported: bool,
+    // Update after bind, partially bound and variable count descriptor arrays with non-uniform indexing of sampled
+    // images and storage buffers are enabled. See ResourceHeap.
+    pub descriptor_indexing_supported: bool,
+    // VK_KHR_external_memory_fd and VK_KHR_external_semaphore_fd are enabled with timeline semaphores, Unix only. See
+    // SharedFrames.
+    pub external_share_supported: bool,
+    pub display_timing_supported: bool, // VK_GOOGLE_display_timing is enabled, see DisplayTiming
+    pub capabilities: Capabilities, // The negotiated version and where promoted features come from
+    pub rebar_policy: RebarPolicy, // Where buffers the host writes often are allocated, see mapped_memory_flags
+    pub transient_policy: TransientPolicy, // Where transient attachments are allocated, see transient_memory_flags
+    pub upload_counters: UploadCounters, // See MemoryStats::uploads
+    // The logical device spans the physical device's group, see DeviceGroup
+    #[cfg(feature = "device-group")]
+    pub device_group: Option<DeviceGroup>,
+    group_devices: Vec<vk::PhysicalDevice>, // Empty for a single device, kept for recreate_device
+    required_extensions: Vec<CString>, // Kept for recreate_device
+    surface_capabilities2: bool
 }
 
 fn get_max_usable_sample_count(properties: &vk::PhysicalDeviceProperties) -> vk::SampleCountFlags {
@@ -56,20 +96,256 @@ fn get_max_usable_sample_count(properties: &vk::PhysicalDeviceProperties) -> vk:
     retval
 }
 
-impl VkCore {
-    pub fn new(ev_loop: &EventLoop<()>, required_layers: &Vec<String>, required_extensions: &Vec<CString>) -> VkCore {
-        fn load_entry() -> Entry {
-            let vk_lib_env = env::var("VK_LIB_PATH").unwrap();
-            let vk_lib_path = Path::new(&vk_lib_env);
+pub(crate) fn load_entry() -> Entry {
+    let vk_lib_env = env::var("VK_LIB_PATH").unwrap();
+    let vk_lib_path = Path::new(&vk_lib_env);
 
-            let entry_local: Entry;
-            unsafe {
-                entry_local = Entry::load_from(vk_lib_path.to_str().unwrap()).unwrap();
-            }
+    let entry_local: Entry;
+    unsafe {
+        entry_local = Entry::load_from(vk_lib_path.to_str().unwrap()).unwrap();
+    }
+
+    entry_local
+}
+
+// The families that logical_init creates a queue of, the same family may be used more than once
+#[derive(Clone, Copy)]
+struct QueueFamilies {
+    graphics: u32,
+    presentation: u32,
+    transfer: u32,
+    compute: u32
+}
+
+// The physical device that physical_init selected, with what it supports
+struct PhysicalSelection {
+    device: vk::PhysicalDevice,
+    gpu: GpuInfo,
+    families: QueueFamilies,
+    surface_formats: Vec<vk::SurfaceFormatKHR>,
+    present_modes: Vec<vk::PresentModeKHR>,
+    max_msaa_samples: vk::SampleCountFlags
+}
 
-            entry_local
+// What logical_init created and enabled, the VkCore fields of the same names
+struct LogicalDevice {
+    present_queue: vk::Queue,
+    graphics_queue: vk::Queue,
+    transfer_queue: vk::Queue,
+    compute_queue: vk::Queue,
+    device: Device,
+    multiview_supported: bool,
+    index_type_uint8_supported: bool,
+    draw_indirect_count_supported: bool,
+    full_screen_exclusive_supported: bool,
+    present_wait_supported: bool, // Present id and present wait
+    memory_budget_supported: bool,
+    sparse_residency_supported: bool,
+    descriptor_indexing_supported: bool,
+    external_share_supported: bool, // External memory and semaphore fds
+    display_timing_supported: bool
+}
+
+// Also used to create the device again after it was lost, see VkCore::recreate_device
+fn logical_init(instance: &Instance, physical_device: &vk::PhysicalDevice, families: QueueFamilies,
+                required_extensions: &[CString], surface_capabilities2: bool, group_devices: &[vk::PhysicalDevice],
+                capabilities: &Capabilities) -> LogicalDevice {
+    let QueueFamilies { graphics: graphics_family, presentation: presentation_family, transfer: transfer_family,
+        compute: compute_family } = families;
+    let available_extensions = unsafe {
+        instance.enumerate_device_extension_properties(*physical_device).unwrap()
+    };
+    let extension_available = |ext: &CStr| available_extensions.iter().any(|e| {
+        let name = unsafe { CStr::from_ptr(e.extension_name.as_ptr()) };
+        name == ext
+    });
+    // Optional, u8 indices are widened to u16 on devices without it
+    let uint8_extension_available = extension_available(vk::ExtIndexTypeUint8Fn::NAME);
+    // Optional, indirect draws fall back to a fixed draw count with zeroed unused commands
+    let draw_indirect_count_supported = extension_available(vk::KhrDrawIndirectCountFn::NAME);
+    // Optional, DisplayMode::Exclusive falls back to borderless fullscreen without it
+    let full_screen_exclusive_supported = surface_capabilities2 &&
+        extension_available(vk::ExtFullScreenExclusiveFn::NAME);
+    // Optional, LatencyGovernor waits on the previous frame's fence without it
+    let present_wait_extensions_available = extension_available(vk::KhrPresentIdFn::NAME) &&
+        extension_available(vk::KhrPresentWaitFn::NAME);
+    // Optional, MemoryStats only has the heap sizes without it
+    let memory_budget_supported = extension_available(vk::ExtMemoryBudgetFn::NAME);
+    // Optional, SharedFrames::new fails without them
+    let external_fd_extensions_available = cfg!(unix) && extension_available(vk::KhrExternalMemoryFdFn::NAME) &&
+        extension_available(vk::KhrExternalSemaphoreFdFn::NAME);
+    // Optional, DisplayTiming::new returns None without it
+    let display_timing_supported = extension_available(vk::GoogleDisplayTimingFn::NAME);
+    let mut extensions_cvec: Vec<*const c_char> = required_extensions
+        .iter()
+        .map(|e| e.as_ptr())
+        .collect();
+    // The promoted features the negotiated version doesn't have as core, see Capabilities
+    extensions_cvec.extend(capabilities.extensions().iter().map(|e| e.as_ptr()));
+    if uint8_extension_available {
+        extensions_cvec.push(vk::ExtIndexTypeUint8Fn::NAME.as_ptr());
+    }
+    if draw_indirect_count_supported {
+        extensions_cvec.push(vk::KhrDrawIndirectCountFn::NAME.as_ptr());
+    }
+    if full_screen_exclusive_supported {
+        extensions_cvec.push(vk::ExtFullScreenExclusiveFn::NAME.as_ptr());
+    }
+    if present_wait_extensions_available {
+        extensions_cvec.push(vk::KhrPresentIdFn::NAME.as_ptr());
+        extensions_cvec.push(vk::KhrPresentWaitFn::NAME.as_ptr());
+    }
+    if memory_budget_supported {
+        extensions_cvec.push(vk::ExtMemoryBudgetFn::NAME.as_ptr());
+    }
+    if external_fd_extensions_available {
+        extensions_cvec.push(vk::KhrExternalMemoryFdFn::NAME.as_ptr());
+        extensions_cvec.push(vk::KhrExternalSemaphoreFdFn::NAME.as_ptr());
+    }
+    if display_timing_supported {
+        extensions_cvec.push(vk::GoogleDisplayTimingFn::NAME.as_ptr());
+    }
+
+    let queue_priority: [f32; 1] = [1.0];
+    // One queue per distinct family
+    let mut families = vec![graphics_family];
+    for f in [presentation_family, transfer_family, compute_family] {
+        if !families.contains(&f) {
+            families.push(f);
         }
+    }
+    let qci: Vec<vk::DeviceQueueCreateInfo> = families.iter()
+        .map(|&f| vk::DeviceQueueCreateInfo::default()
+            .queue_family_index(f)
+            .queue_priorities(&queue_priority))
+        .collect();
+
+    let mut rt_features = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+    let mut accel_features = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+    let mut address_features = vk::PhysicalDeviceBufferDeviceAddressFeatures::default(); // Core since 1.2
+    let mut multiview_features = vk::PhysicalDeviceMultiviewFeatures::default(); // Core since 1.1
+    let mut uint8_features = vk::PhysicalDeviceIndexTypeUint8FeaturesEXT::default();
+    let mut present_id_features = vk::PhysicalDevicePresentIdFeaturesKHR::default();
+    let mut present_wait_features = vk::PhysicalDevicePresentWaitFeaturesKHR::default();
+    let mut ray_query_features = vk::PhysicalDeviceRayQueryFeaturesKHR::default();
+    let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default(); // Core since 1.2
+    let mut timeline_features = vk::PhysicalDeviceTimelineSemaphoreFeatures::default(); // Core since 1.2
+    let mut features2 = vk::PhysicalDeviceFeatures2::default()
+        .push_next(&mut rt_features)
+        .push_next(&mut accel_features)
+        .push_next(&mut multiview_features);
+    // Structures of newer versions only where the device has them, from the version or an extension
+    if capabilities.buffer_device_address.is_available() {
+        features2 = features2.push_next(&mut address_features);
+    }
+    if capabilities.api_version >= vk::API_VERSION_1_2 {
+        features2 = features2.push_next(&mut indexing_features);
+    }
+    if capabilities.timeline_semaphore.is_available() {
+        features2 = features2.push_next(&mut timeline_features);
+    }
+    if uint8_extension_available {
+        features2 = features2.push_next(&mut uint8_features);
+    }
+    if present_wait_extensions_available {
+        features2 = features2
+            .push_next(&mut present_id_features)
+            .push_next(&mut present_wait_features);
+    }
+    // Only for renderers that require VK_KHR_ray_query, such as the hybrid one
+    if required_extensions.iter().any(|e| e.as_c_str() == vk::KhrRayQueryFn::NAME) {
+        features2 = features2.push_next(&mut ray_query_features);
+    }
+    unsafe {
+        instance.get_physical_device_features2(*physical_device, &mut features2)
+    }
+
+    let mut group_info = vk::DeviceGroupDeviceCreateInfo::default()
+        .physical_devices(group_devices);
+    let mut device_create_info = vk::DeviceCreateInfo::default()
+        .enabled_extension_names(&extensions_cvec)
+        .queue_create_infos(qci.as_slice())
+        .push_next(&mut features2);
+    // Core since 1.1, physical_device must be one of them
+    if !group_devices.is_empty() {
+        device_create_info = device_create_info.push_next(&mut group_info);
+    }
+
+    let logical_device = unsafe { instance.create_device(*physical_device, &device_create_info,
+                                                              None).unwrap() };
+    // The structures in features2's chain are only read once features2 isn't used anymore, the chain borrows them
+    // until then. Every queried feature is enabled.
+    // Optional, TextureStreamer::load_virtual fails without it
+    let graphics_family_flags = unsafe {
+        instance.get_physical_device_queue_family_properties(*physical_device)[graphics_family as usize].queue_flags
+    };
+    let sparse_residency_supported = features2.features.sparse_binding == vk::TRUE &&
+        features2.features.sparse_residency_image2_d == vk::TRUE &&
+        graphics_family_flags.contains(vk::QueueFlags::SPARSE_BINDING);
+    let multiview_supported = multiview_features.multiview == vk::TRUE;
+    let index_type_uint8_supported = uint8_extension_available &&
+        uint8_features.index_type_uint8 == vk::TRUE;
+    let present_wait_supported = present_wait_extensions_available &&
+        present_id_features.present_id == vk::TRUE && present_wait_features.present_wait == vk::TRUE;
+    // Optional, ResourceHeap::new fails without it
+    let descriptor_indexing_supported = indexing_features.runtime_descriptor_array == vk::TRUE &&
+        indexing_features.descriptor_binding_partially_bound == vk::TRUE &&
+        indexing_features.descriptor_binding_variable_descriptor_count == vk::TRUE &&
+        indexing_features.descriptor_binding_sampled_image_update_after_bind == vk::TRUE &&
+        indexing_features.descriptor_binding_storage_buffer_update_after_bind == vk::TRUE &&
+        indexing_features.descriptor_binding_update_unused_while_pending == vk::TRUE &&
+        indexing_features.shader_sampled_image_array_non_uniform_indexing == vk::TRUE &&
+        indexing_features.shader_storage_buffer_array_non_uniform_indexing == vk::TRUE;
+    // SharedFrames waits and signals through the core 1.2 timeline semaphore commands
+    let external_share_supported = external_fd_extensions_available &&
+        capabilities.timeline_semaphore == FeatureSource::Core && timeline_features.timeline_semaphore == vk::TRUE;
+
+    let present_queue = unsafe {
+        logical_device
+            .get_device_queue(presentation_family, 0)
+    };
+    let graphics_queue = unsafe {
+        logical_device
+            .get_device_queue(graphics_family, 0)
+    };
+    let transfer_queue = unsafe {
+        logical_device
+            .get_device_queue(transfer_family, 0)
+    };
+    let compute_queue = unsafe {
+        logical_device
+            .get_device_queue(compute_family, 0)
+    };
+
+    LogicalDevice {
+        present_queue,
+        graphics_queue,
+        transfer_queue,
+        compute_queue,
+        device: logical_device,
+        multiview_supported,
+        index_type_uint8_supported,
+        draw_indirect_count_supported,
+        full_screen_exclusive_supported,
+        present_wait_supported,
+        memory_budget_supported,
+        sparse_residency_supported,
+        descriptor_indexing_supported,
+        external_share_supported,
+        display_timing_supported
+    }
+}
+
+impl VkCore {
+    pub fn new(ev_loop: &EventLoop<()>, required_layers: &Vec<String>, required_extensions: &Vec<CString>) -> VkCore {
+        VkCore::new_with_window_size(ev_loop, required_layers, required_extensions, LogicalSize::new(800, 600),
+                                     &GpuSelection::default(), WindowTransparency::Opaque)
+    }
 
+    // Panics if no physical device that gpu selects supports the required extensions and the renderers' features
+    pub fn new_with_window_size(ev_loop: &EventLoop<()>, required_layers: &Vec<String>,
+                                required_extensions: &Vec<CString>, window_size: LogicalSize<u32>,
+                                gpu: &GpuSelection, transparency: WindowTransparency) -> VkCore {
         fn read_window_icon(path: &str) -> Option<Icon> {
             // From https://docs.rs/png/latest/png/
             let decoder = png::Decoder::new(File::open(path).unwrap()); // TODO Worry about proper asset import paths later
@@ -87,11 +363,13 @@ impl VkCore {
             Icon::from_rgba(bytes.iter().cloned().collect(), width, height).ok()
         }
 
-        fn init_window(event_loop: &EventLoop<()>) -> Window {
-            WindowBuilder::new()
+        fn init_window(event_loop: &EventLoop<()>, window_size: LogicalSize<u32>, transparency: WindowTransparency)
+            -> Window {
+            let builder = WindowBuilder::new()
                 .with_title("Hello Triangle")
-                .with_inner_size(LogicalSize::new(800, 600))
-                .with_window_icon(read_window_icon("graphics/assets/g1141.png"))
+                .with_inner_size(window_size)
+                .with_window_icon(read_window_icon("graphics/assets/g1141.png"));
+            transparency.window_builder(builder)
                 .build(event_loop)
                 .unwrap()
         }
@@ -125,19 +403,17 @@ impl VkCore {
             let mut extensions_found = false;
 
             unsafe {
-                println!("Winit Extensions:");
                 for ext in available_extensions {
                     let c_str = CString::from(CStr::from_ptr(*ext));
                     let ext_str = c_str.to_str().unwrap();
                     let s = String::from(ext_str);
                     required_extensions.push(s);
-                    println!("{}", ext_str);
+                    debug!(extension = ext_str, "Winit extension");
                 }
 
                 // Ensure that the Vulkan instance will support the required Winit extensions
                 let vk_extensions = entry.enumerate_instance_extension_properties(None).unwrap();
 
-                println!("\nVulkan Extensions:");
                 for ext in vk_extensions {
                     let ext_name = String::from(
                         CStr::from_ptr(ext.extension_name.as_ptr())
@@ -151,14 +427,21 @@ impl VkCore {
                             break;
                         }
                     }
-                    println!("{}", ext_name);
+                    debug!(extension = ext_name.as_str(), "Vulkan instance extension");
                 }
             }
 
             extensions_found
         }
 
-        fn instance_init(entry: &Entry, window: &Window, required_layers: &Vec<String>) -> Result<Instance, String> {
+        fn instance_extension_present(entry: &Entry, extension: &CStr) -> bool {
+            entry.enumerate_instance_extension_properties(None).unwrap().iter()
+                .any(|e| unsafe { CStr::from_ptr(e.extension_name.as_ptr()) } == extension)
+        }
+
+        // surface_capabilities2 enables VK_KHR_get_surface_capabilities2, which VK_EXT_full_screen_exclusive needs
+        fn instance_init(entry: &Entry, window: &Window, required_layers: &Vec<String>, surface_capabilities2: bool,
+                         api_version: u32) -> Result<Instance, String> {
             // Get all the window manager extensions that Vulkan can use
             let mut winit_extensions =
                 ash_window::enumerate_required_extensions(window.raw_display_handle())
@@ -177,7 +460,7 @@ impl VkCore {
 
                 // Specifies all the versions and names associated with this custom renderer
                 let app_info = vk::ApplicationInfo::default()
-                    .api_version(vk::make_api_version(0, 1, 3, 0))
+                    .api_version(api_version)
                     .application_version(0)
                     .engine_name(engine_name)
                     .engine_version(0)
@@ -185,6 +468,9 @@ impl VkCore {
 
                 // Required for MacOs compatibility
                 winit_extensions.push(vk::KhrPortabilityEnumerationFn::NAME.as_ptr());
+                if surface_capabilities2 {
+                    winit_extensions.push(vk::KhrGetSurfaceCapabilities2Fn::NAME.as_ptr());
+                }
                 let create_flags = vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR;
 
                 // Wrap previous stuff into a higher level struct
@@ -199,7 +485,9 @@ impl VkCore {
                 let layer_names_raw: Vec<*const c_char>;
                 let layer_names_cstring: Vec<CString>;
 
-                println!("\nValidation support present");
+                if !required_layers.is_empty() {
+                    info!(layers = ?required_layers, "Validation layers enabled");
+                }
                 let layer_names_string: Vec<&str> = required_layers
                     .iter()
                     .map(|s| s.as_str())
@@ -223,14 +511,15 @@ impl VkCore {
             }
         }
 
-        fn physical_init(instance: &Instance, surface_loader: &khr::Surface, surface: vk::SurfaceKHR,
-                         required_extensions: &Vec<CString>)
-                         -> Option<(vk::PhysicalDevice, // Physical device handle
-                               u32, // Presentation family index
-                               u32, // graphics family index
-                               Vec<vk::SurfaceFormatKHR>, // Supported surface formats
-                               Vec<vk::PresentModeKHR>, // presentation modes
-                               vk::SampleCountFlags)> // max msaa samples
+        // The queue families and surface support of device, or why the renderers can't use it. Doesn't check the
+        // device type, see GpuSelection::Auto.
+        fn check_physical_device(instance: &Instance, surface_loader: &khr::Surface, surface: vk::SurfaceKHR,
+                                 device: vk::PhysicalDevice, required_extensions: &Vec<CString>,
+                                 instance_version: u32)
+                                 -> Result<(QueueFamilies,
+                                            Vec<vk::SurfaceFormatKHR>, // Supported surface formats
+                                            Vec<vk::PresentModeKHR>), // presentation modes
+                                           String>
         {
             fn required_physical_extensions_present(instance: &Instance,
                                                     physical_device: vk::PhysicalDevice,
@@ -245,185 +534,152 @@ impl VkCore {
                         .collect();
                 }
 
-                println!("\nDevice extensions:");
-                for e in dev_extensions.clone() {
-                    println!("{}", e);
+                for e in dev_extensions.iter() {
+                    debug!(extension = *e, "Device extension");
                 }
 
                 required_extensions.iter()
                     .all(|e| dev_extensions.contains(&e.to_str().unwrap()))
             }
 
-            let physical_devices: Vec<vk::PhysicalDevice>;
-            unsafe {
-                physical_devices = instance.enumerate_physical_devices().unwrap();
-            }
-
-            // Get the first physical device that satisfies the suitability check
             // Suitability requirements:
-            // - Discrete GPU
-            // - Geometry shaders
+            // - Vulkan 1.1, geometry shaders, anisotropic filtering, ray tracing pipelines and buffer device addresses,
+            //   which are core since 1.2 and come from VK_KHR_buffer_device_address before
             // - supports these logical requirements:
             //      - Graphics pipelines
             //      - Can present images to the window manager surface
-            let mut present_family_index: u32 = 0;
-            let mut graphics_family_index: u32 = 0;
-            let mut present_family_found = false;
-            let mut graphics_family_found = false;
-            let mut dev_found = false;
-            let mut dev_idx: usize = 0;
-            let mut present_modes: Vec<vk::PresentModeKHR> = vec![];
-            let mut surface_formats: Vec<vk::SurfaceFormatKHR> = vec![];
-            let mut max_msaa_samples: vk::SampleCountFlags = vk::SampleCountFlags::TYPE_1;
-
-            // For each physical device
-            for (p_idx, device) in physical_devices.iter().enumerate() {
-                let dev_properties: vk::PhysicalDeviceProperties;
-                let dev_features: vk::PhysicalDeviceFeatures;
-                let mut rt_features: vk::PhysicalDeviceRayTracingPipelineFeaturesKHR =
-                    vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
-                let mut buf_features = vk::PhysicalDeviceBufferDeviceAddressFeaturesEXT::default();
-                let mut features2 = vk::PhysicalDeviceFeatures2::default()
-                    .push_next(&mut rt_features)
-                    .push_next(&mut buf_features);
-                unsafe {
-                    dev_properties = instance.get_physical_device_properties(*device);
-                    dev_features = instance.get_physical_device_features(*device);
-                    instance.get_physical_device_features2(*device, &mut features2);
-                }
-
-                // Ensure that at least one kind of surface color/pixel format is supported
-                unsafe {
-                    surface_formats = surface_loader
-                        .get_physical_device_surface_formats(*device, surface).unwrap();
-                    // Ensure that the desired FIFO format for pushing images to the screen is available
-                    present_modes = surface_loader
-                        .get_physical_device_surface_present_modes(*device, surface).unwrap();
-                }
-
-                let mut all_queues_found = false;
-                if required_physical_extensions_present(instance, *device, required_extensions) &&
-                    !present_modes.is_empty() && !surface_formats.is_empty() && dev_features.sampler_anisotropy ==
-                    vk::TRUE && rt_features.ray_tracing_pipeline == vk::TRUE &&
-                    buf_features.buffer_device_address == vk::TRUE {
-                    let queue_families: Vec<vk::QueueFamilyProperties>;
-                    unsafe {
-                        queue_families = instance
-                            .get_physical_device_queue_family_properties(*device);
-                    }
-
-                    let queue_fam_enumerator = queue_families.iter().enumerate();
-
-                    // For each Queue family associated with a given device
-                    for (idx, qf) in queue_fam_enumerator {
-                        if !graphics_family_found {
-                            // Check for graphics support
-                            let graphics_support =
-                                (qf.queue_flags & vk::QueueFlags::GRAPHICS) == vk::QueueFlags::GRAPHICS;
-                            if graphics_support {
-                                graphics_family_index = idx as u32;
-                                graphics_family_found = true;
-                            }
-                        }
-
-                        if !present_family_found {
-                            let surface_support =
-                                unsafe {
-                                    surface_loader.get_physical_device_surface_support(*device, idx as u32, surface)
-                                        .unwrap()
-                                };
-
-                            if surface_support {
-                                present_family_index = idx as u32;
-                                present_family_found = true;
-                            }
-                        }
-
-                        if present_family_found && graphics_family_found {
-                            all_queues_found = true;
-                            break;
-                        }
-                    }
-                }
-
-                // If the queue family and the device are suitable
-                if all_queues_found
-                    && dev_properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU
-                    && dev_features.geometry_shader != 0
-                {
-                    dev_found = true;
-                    dev_idx = p_idx;
-                    max_msaa_samples = get_max_usable_sample_count(&dev_properties);
-                    break; // Done
-                }
+            let dev_features: vk::PhysicalDeviceFeatures;
+            let mut rt_features: vk::PhysicalDeviceRayTracingPipelineFeaturesKHR =
+                vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+            let mut features2 = vk::PhysicalDeviceFeatures2::default()
+                .push_next(&mut rt_features);
+            unsafe {
+                dev_features = instance.get_physical_device_features(device);
+                instance.get_physical_device_features2(device, &mut features2);
             }
+            let capabilities = Capabilities::query(instance, device, instance_version);
 
-            if dev_found {
-                Some((physical_devices[dev_idx], present_family_index, graphics_family_index, surface_formats,
-                     present_modes, max_msaa_samples))
-            } else {
-                None
+            // Ensure that at least one kind of surface color/pixel format is supported
+            let surface_formats: Vec<vk::SurfaceFormatKHR>;
+            let present_modes: Vec<vk::PresentModeKHR>;
+            unsafe {
+                surface_formats = surface_loader
+                    .get_physical_device_surface_formats(device, surface).unwrap();
+                // Ensure that the desired FIFO format for pushing images to the screen is available
+                present_modes = surface_loader
+                    .get_physical_device_surface_present_modes(device, surface).unwrap();
             }
-        }
-
-        pub fn logical_init(instance: &Instance, physical_device: &vk::PhysicalDevice, graphics_family: u32,
-                            presentation_family: u32, required_extensions: &Vec<CString>)
-            -> (vk::Queue, // presentation queue
-                vk::Queue, // graphics queue
-                Device) // logical device
-         {
-            let extensions_cvec: Vec<*const c_char> = required_extensions
-                .iter()
-                .map(|e| e.as_ptr())
-                .collect();
 
-            let queue_priority: [f32; 1] = [1.0];
-            let graphics_queue_create_info = vk::DeviceQueueCreateInfo::default()
-                .queue_family_index(graphics_family)
-                .queue_priorities(&queue_priority);
-
-            let mut qci: Vec<vk::DeviceQueueCreateInfo> = Vec::new();
-            qci.push(graphics_queue_create_info);
-            if presentation_family != graphics_family {
-                qci.push(vk::DeviceQueueCreateInfo::default()
-                    .queue_family_index(presentation_family)
-                    .queue_priorities(&queue_priority));
+            if capabilities.api_version < MIN_API_VERSION {
+                return Err(String::from("Vulkan 1.1 is not supported"));
+            }
+            if !required_physical_extensions_present(instance, device, required_extensions) {
+                return Err(String::from("missing required extensions"));
+            }
+            if present_modes.is_empty() || surface_formats.is_empty() {
+                return Err(String::from("can't present to the window"));
+            }
+            if dev_features.sampler_anisotropy != vk::TRUE || dev_features.geometry_shader != vk::TRUE ||
+                rt_features.ray_tracing_pipeline != vk::TRUE || !capabilities.buffer_device_address.is_available() {
+                return Err(String::from("missing required features"));
             }
 
-            let mut rt_features = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
-            let mut accel_features = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
-            let mut buf_features = vk::PhysicalDeviceBufferDeviceAddressFeaturesEXT::default();
-            let mut features2 = vk::PhysicalDeviceFeatures2::default()
-                .push_next(&mut rt_features)
-                .push_next(&mut buf_features)
-                .push_next(&mut accel_features);
+            let queue_families: Vec<vk::QueueFamilyProperties>;
             unsafe {
-                instance.get_physical_device_features2(*physical_device, &mut features2)
+                queue_families = instance
+                    .get_physical_device_queue_family_properties(device);
             }
+            let graphics_family_index = queue_families.iter()
+                .position(|qf| qf.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+                .ok_or(String::from("no graphics queue"))? as u32;
+            let present_family_index = (0..queue_families.len() as u32)
+                .find(|&idx| unsafe {
+                    surface_loader.get_physical_device_surface_support(device, idx, surface).unwrap()
+                })
+                .ok_or(String::from("no queue can present to the window"))?;
+            // Transfer only families usually map to the copy engines, which can run alongside rendering
+            let transfer_family_index = match queue_families.iter().position(|qf|
+                qf.queue_flags.contains(vk::QueueFlags::TRANSFER) &&
+                    !qf.queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)) {
+                Some(idx) => idx as u32,
+                None => graphics_family_index
+            };
+            // Likewise for compute without graphics, see AsyncCompute
+            let compute_family_index = match queue_families.iter().position(|qf|
+                qf.queue_flags.contains(vk::QueueFlags::COMPUTE) &&
+                    !qf.queue_flags.contains(vk::QueueFlags::GRAPHICS)) {
+                Some(idx) => idx as u32,
+                None => graphics_family_index
+            };
 
-            let device_create_info = vk::DeviceCreateInfo::default()
-                .enabled_extension_names(&extensions_cvec)
-                .queue_create_infos(qci.as_slice())
-                .push_next(&mut features2);
+            let families = QueueFamilies {
+                graphics: graphics_family_index,
+                presentation: present_family_index,
+                transfer: transfer_family_index,
+                compute: compute_family_index
+            };
 
-            let logical_device = unsafe { instance.create_device(*physical_device, &device_create_info,
-                                                                      None).unwrap() };
+            Ok((families, surface_formats, present_modes))
+        }
 
-            let present_queue = unsafe {
-                logical_device
-                    .get_device_queue(presentation_family, 0)
-            };
-            let graphics_queue = unsafe {
-                logical_device
-                    .get_device_queue(graphics_family, 0)
-            };
+        fn physical_init(instance: &Instance, surface_loader: &khr::Surface, surface: vk::SurfaceKHR,
+                         required_extensions: &Vec<CString>, selection: &GpuSelection, instance_version: u32)
+                         -> Result<PhysicalSelection, String> {
+            let gpus = instance_gpus(instance);
+            for (_, gpu) in gpus.iter() {
+                info!(gpu = gpu.summary(), "Physical device");
+            }
+            // The first suitable device that matches, Auto tries the discrete GPUs before the others
+            let mut candidates: Vec<&(vk::PhysicalDevice, GpuInfo)> = gpus.iter()
+                .filter(|(_, gpu)| selection.matches(gpu))
+                .collect();
+            candidates.sort_by_key(|(_, gpu)| gpu.device_type != vk::PhysicalDeviceType::DISCRETE_GPU);
+            for (device, gpu) in candidates {
+                let (families, surface_formats, present_modes) =
+                    match check_physical_device(instance, surface_loader, surface, *device, required_extensions,
+                                                instance_version) {
+                        Ok(support) => support,
+                        Err(e) => {
+                            info!(gpu = gpu.name.as_str(), "Skipped physical device, {}", e);
+                            continue;
+                        }
+                    };
+                let dev_properties = unsafe { instance.get_physical_device_properties(*device) };
+                let max_msaa_samples = get_max_usable_sample_count(&dev_properties);
+                info!(device = gpu.name.as_str(), device_type = gpu.type_name(),
+                      api_version = format!("{}.{}.{}",
+                                            vk::api_version_major(dev_properties.api_version),
+                                            vk::api_version_minor(dev_properties.api_version),
+                                            vk::api_version_patch(dev_properties.api_version)),
+                      driver_version = dev_properties.driver_version, max_msaa_samples = ?max_msaa_samples,
+                      "Selected physical device");
+
+                return Ok(PhysicalSelection {
+                    device: *device,
+                    gpu: gpu.clone(),
+                    families,
+                    surface_formats,
+                    present_modes,
+                    max_msaa_samples
+                });
+            }
 
-            (present_queue, graphics_queue, logical_device)
+            match selection {
+                GpuSelection::Auto => Err(format!("none of the {} devices is suitable", gpus.len())),
+                _ => Err(format!("no suitable device matches {:?}, see --list-gpus", selection))
+            }
         }
 
+        let _init_span = info_span!("vk_core_init").entered();
         let entry = load_entry();
-        let window = init_window(&ev_loop);
-        let instance = instance_init(&entry, &window, &required_layers).unwrap();
+        let instance_version = instance_api_version(&entry);
+        let window = info_span!("window").in_scope(|| init_window(ev_loop, window_size, transparency));
+        // Full screen exclusive is a Windows only extension
+        let surface_capabilities2 = cfg!(windows) &&
+            instance_extension_present(&entry, vk::KhrGetSurfaceCapabilities2Fn::NAME);
+        let instance = info_span!("instance")
+            .in_scope(|| instance_init(&entry, &window, required_layers, surface_capabilities2, instance_version))
+            .unwrap();
         let surface: vk::SurfaceKHR;
         unsafe {
             surface = ash_window::create_surface(
@@ -435,27 +691,125 @@ impl VkCore {
             ).unwrap();
         }
         let surface_loader = khr::Surface::new(&entry, &instance);
-        let (physical_device, present_family_index, graphics_family_index, supported_surface_formats, present_modes,
-             max_msaa_samples) = physical_init(&instance, &surface_loader, surface, required_extensions).unwrap();
-        let (present_queue, graphics_queue, logical_device) = logical_init(&instance, &physical_device,
-                                                                           graphics_family_index,
-                                                                           present_family_index, required_extensions);
+        let PhysicalSelection {
+            device: physical_device, gpu, families, surface_formats: supported_surface_formats, present_modes,
+            max_msaa_samples
+        } = info_span!("physical_device")
+                .in_scope(|| physical_init(&instance, &surface_loader, surface, required_extensions, gpu,
+                                           instance_version))
+                .unwrap_or_else(|e| panic!("No suitable physical device, {}", e));
+        let capabilities = Capabilities::query(&instance, physical_device, instance_version);
+        capabilities.log();
+        #[cfg(feature = "device-group")]
+        let group_devices = find_group(&instance, physical_device).unwrap_or_default();
+        #[cfg(not(feature = "device-group"))]
+        let group_devices: Vec<vk::PhysicalDevice> = Vec::new();
+        let QueueFamilies {
+            graphics: graphics_family_index, presentation: present_family_index, transfer: transfer_family_index,
+            compute: compute_family_index
+        } = families;
+        let LogicalDevice {
+            present_queue, graphics_queue, transfer_queue, compute_queue, device: logical_device,
+            multiview_supported, index_type_uint8_supported, draw_indirect_count_supported,
+            full_screen_exclusive_supported, present_wait_supported, memory_budget_supported,
+            sparse_residency_supported, descriptor_indexing_supported, external_share_supported,
+            display_timing_supported
+        } = info_span!("logical_device")
+            .in_scope(|| logical_init(&instance, &physical_device, families, required_extensions,
+                                      surface_capabilities2, group_devices.as_slice(), &capabilities));
+        #[cfg(feature = "device-group")]
+        let device_group = (!group_devices.is_empty())
+            .then(|| DeviceGroup::new(&instance, &logical_device, surface, group_devices.clone()));
+        debug!(graphics_family_index, present_family_index, transfer_family_index, compute_family_index,
+               "Queue families");
+        debug!(multiview_supported, index_type_uint8_supported, draw_indirect_count_supported,
+               full_screen_exclusive_supported, present_wait_supported, memory_budget_supported,
+               sparse_residency_supported, descriptor_indexing_supported, external_share_supported,
+               display_timing_supported, "Device features");
 
         VkCore {
             _entry: entry,
             window,
+            transparency,
             instance,
             surface,
             surface_loader,
             physical_device,
+            gpu,
             present_family_index,
             graphics_family_index,
+            transfer_family_index,
+            compute_family_index,
             supported_surface_formats,
             present_modes,
             max_msaa_samples,
             present_queue,
             graphics_queue,
-            logical_device
+            transfer_queue,
+            compute_queue,
+            logical_device,
+            multiview_supported,
+            index_type_uint8_supported,
+            draw_indirect_count_supported,
+            full_screen_exclusive_supported,
+            present_wait_supported,
+            memory_budget_supported,
+            sparse_residency_supported,
+            descriptor_indexing_supported,
+            external_share_supported,
+            display_timing_supported,
+            capabilities,
+            rebar_policy: RebarPolicy::default(),
+            transient_policy: TransientPolicy::default(),
+            upload_counters: UploadCounters::default(),
+            #[cfg(feature = "device-group")]
+            device_group,
+            group_devices,
+            required_extensions: required_extensions.clone(),
+            surface_capabilities2
+        }
+    }
+
+    // After VK_ERROR_DEVICE_LOST. Destroys the lost logical device and creates a new one on the same physical
+    // device, with the same queues and extensions. The window, instance and surface stay, but everything created
+    // from the old device must have been destroyed already.
+    pub fn recreate_device(&mut self) {
+        let _span = info_span!("logical_device_recreate").entered();
+        unsafe { self.logical_device.destroy_device(None) };
+        let families = QueueFamilies {
+            graphics: self.graphics_family_index,
+            presentation: self.present_family_index,
+            transfer: self.transfer_family_index,
+            compute: self.compute_family_index
+        };
+        let logical = logical_init(&self.instance, &self.physical_device, families, &self.required_extensions,
+                                   self.surface_capabilities2, self.group_devices.as_slice(), &self.capabilities);
+        self.present_queue = logical.present_queue;
+        self.graphics_queue = logical.graphics_queue;
+        self.transfer_queue = logical.transfer_queue;
+        self.compute_queue = logical.compute_queue;
+        self.logical_device = logical.device;
+        self.multiview_supported = logical.multiview_supported;
+        self.index_type_uint8_supported = logical.index_type_uint8_supported;
+        self.draw_indirect_count_supported = logical.draw_indirect_count_supported;
+        self.full_screen_exclusive_supported = logical.full_screen_exclusive_supported;
+        self.present_wait_supported = logical.present_wait_supported;
+        self.memory_budget_supported = logical.memory_budget_supported;
+        self.sparse_residency_supported = logical.sparse_residency_supported;
+        self.descriptor_indexing_supported = logical.descriptor_indexing_supported;
+        self.external_share_supported = logical.external_share_supported;
+        self.display_timing_supported = logical.display_timing_supported;
+    }
+
+    // Every physical device of the instance, including the ones the renderers can't use
+    pub fn gpus(&self) -> Vec<GpuInfo> {
+        instance_gpus(&self.instance).into_iter().map(|(_, gpu)| gpu).collect()
+    }
+
+    // Lowers max_msaa_samples, which every multisampled attachment uses, to samples if the device supports more
+    pub fn limit_msaa_samples(&mut self, samples: vk::SampleCountFlags) {
+        if samples.as_raw() < self.max_msaa_samples.as_raw() {
+            self.max_msaa_samples = samples;
         }
     }
 
@@ -0,0 +1,604 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::path::Path;
+
+use ash::vk;
+use cgmath::{Point3, Vector3};
+use tracing::debug;
+
+use crate::clustered_lights::{ForwardDraw, ForwardPass};
+use crate::collision::Aabb;
+use crate::deletion_queue::{Deletion, DeletionQueue};
+use crate::descriptor::{create_object_descriptor_set_layout, Descriptor};
+use crate::dynamic_ubo::DynamicUniformBuffer;
+use crate::gpu_buffer::GpuBuffer;
+use crate::index::IndexBuffer;
+use crate::objects::{material_texture, Mesh, ViewTransforms};
+use crate::raster_pipeline::RasterPipeline;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_sampler, destroy_sampler};
+use crate::scene::Material;
+use crate::texture::Texture;
+use crate::vertex::Vertex;
+use crate::vkcore::VkCore;
+use crate::voxel_mesh::{palette_tiles, GreedyMesher, MeshedChunk};
+use crate::voxel_visibility::FaceConnections;
+
+// Blocks along each side of a chunk, like a chunk of the rt renderer's voxel grid
+pub const CHUNK_SIZE: usize = 16;
+const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+// 0 is air, any other block is drawn with the palette mesh at block - 1
+pub type Block = u16;
+pub const AIR: Block = 0;
+
+// Of a chunk, in chunks. It holds the blocks from coord * CHUNK_SIZE up to (coord + 1) * CHUNK_SIZE on every axis.
+pub type ChunkCoord = Vector3<i32>;
+
+pub(crate) const FACE_NEIGHBORS: [[i32; 3]; 6] = [[-1, 0, 0], [1, 0, 0], [0, -1, 0], [0, 1, 0], [0, 0, -1], [0, 0, 1]];
+
+// World space corner of the chunk with the lowest coordinates
+pub fn chunk_origin(coord: ChunkCoord) -> Point3<f32> {
+    Point3::new(coord.x as f32, coord.y as f32, coord.z as f32) * CHUNK_SIZE as f32
+}
+
+pub fn chunk_bounds(coord: ChunkCoord) -> Aabb {
+    let min = chunk_origin(coord);
+    Aabb {
+        min,
+        max: min + Vector3::new(1.0, 1.0, 1.0) * CHUNK_SIZE as f32
+    }
+}
+
+// The chunk a block is in and its position inside it
+pub fn chunk_of(block: Vector3<i32>) -> (ChunkCoord, [usize; 3]) {
+    let size = CHUNK_SIZE as i32;
+    let coord = block.map(|b| b.div_euclid(size));
+    let local = block.map(|b| b.rem_euclid(size) as usize);
+
+    (coord, [local.x, local.y, local.z])
+}
+
+// A unit cube from the origin to (1, 1, 1) that maps tex_min..tex_max of the texture onto every face, for palettes
+// whose blocks are tiles of one atlas
+pub fn cube_mesh(tex_min: [f32; 2], tex_max: [f32; 2]) -> Mesh {
+    // Normal and the two edges of each face, counterclockwise seen from outside
+    let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+        ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+        ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+        ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+        ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+        ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+        ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0])
+    ];
+    let mut vertices = Vec::with_capacity(24);
+    let mut indices = Vec::with_capacity(36);
+    for (normal, u, v) in faces.iter() {
+        let first = vertices.len() as u32;
+        for (s, t) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+            let pos = [0, 1, 2].map(|a| 0.5 + 0.5 * normal[a] + (s - 0.5) * u[a] + (t - 0.5) * v[a]);
+            vertices.push(Vertex {
+                pos,
+                color: [1.0, 1.0, 1.0],
+                tex_coord: [tex_min[0] + s * (tex_max[0] - tex_min[0]), tex_max[1] - t * (tex_max[1] - tex_min[1])]
+            });
+        }
+        indices.extend([first, first + 1, first + 2, first + 2, first + 3, first]);
+    }
+
+    Mesh::new(vertices, indices)
+}
+
+// A block drawn at the world space corner with the lowest coordinates, vec4 for the std430 layout in voxel.vert
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockInstance {
+    pub position: [f32; 4]
+}
+
+// The instances of a chunk sorted by palette entry. Each range is one indirect draw, of the palette entry, the first
+// instance and the number of instances.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkInstances {
+    pub instances: Vec<BlockInstance>,
+    pub ranges: Vec<(usize, u32, u32)>
+}
+
+// The blocks of a chunk, x first, then y, then z, which is up
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxelChunk {
+    blocks: Vec<Block>
+}
+
+impl Default for VoxelChunk {
+    fn default() -> VoxelChunk {
+        VoxelChunk::filled(AIR)
+    }
+}
+
+impl VoxelChunk {
+    pub fn filled(block: Block) -> VoxelChunk {
+        VoxelChunk {
+            blocks: vec![block; CHUNK_VOLUME]
+        }
+    }
+
+    fn index(x: usize, y: usize, z: usize) -> usize {
+        assert!(x < CHUNK_SIZE && y < CHUNK_SIZE && z < CHUNK_SIZE);
+        x + y * CHUNK_SIZE + z * CHUNK_SIZE * CHUNK_SIZE
+    }
+
+    pub fn get(&self, x: usize, y: usize, z: usize) -> Block {
+        self.blocks[VoxelChunk::index(x, y, z)]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, z: usize, block: Block) {
+        self.blocks[VoxelChunk::index(x, y, z)] = block;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.iter().all(|b| *b == AIR)
+    }
+
+    // In the order of get's index, x first, then y, then z
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    // The highest block in the chunk, AIR if it is empty
+    pub fn max_block(&self) -> Block {
+        self.blocks.iter().copied().max().unwrap_or(AIR)
+    }
+
+    // Whether the block has a face towards air. Faces on the sides of the chunk count as exposed, the neighbouring
+    // chunks aren't looked at.
+    fn exposed(&self, x: usize, y: usize, z: usize) -> bool {
+        FACE_NEIGHBORS.iter().any(|[dx, dy, dz]| {
+            let (nx, ny, nz) = (x as i32 + dx, y as i32 + dy, z as i32 + dz);
+            let inside = |n: i32| (0..CHUNK_SIZE as i32).contains(&n);
+            !(inside(nx) && inside(ny) && inside(nz)) || self.get(nx as usize, ny as usize, nz as usize) == AIR
+        })
+    }
+
+    // An instance per block with a face towards air, hidden blocks aren't drawn. Blocks without a palette entry are
+    // left out.
+    pub fn instances(&self, coord: ChunkCoord, palette_len: usize) -> ChunkInstances {
+        let origin = chunk_origin(coord);
+        let mut by_palette: Vec<Vec<BlockInstance>> = vec![Vec::new(); palette_len];
+        for (i, &block) in self.blocks.iter().enumerate() {
+            if block == AIR || block as usize > palette_len {
+                continue;
+            }
+            let (x, y, z) = (i % CHUNK_SIZE, (i / CHUNK_SIZE) % CHUNK_SIZE, i / (CHUNK_SIZE * CHUNK_SIZE));
+            if self.exposed(x, y, z) {
+                by_palette[block as usize - 1].push(BlockInstance {
+                    position: [origin.x + x as f32, origin.y + y as f32, origin.z + z as f32, 0.0]
+                });
+            }
+        }
+
+        let mut chunk_instances = ChunkInstances::default();
+        for (palette, instances) in by_palette.into_iter().enumerate().filter(|(_, i)| !i.is_empty()) {
+            chunk_instances.ranges.push((palette, chunk_instances.instances.len() as u32, instances.len() as u32));
+            chunk_instances.instances.extend(instances);
+        }
+
+        chunk_instances
+    }
+}
+
+// A world of blocks on the CPU, chunks that aren't stored are air. The raster renderer draws it with VoxelDraws, the
+// rt renderer instances a cube BLAS per block.
+#[derive(Clone, Debug)]
+pub struct VoxelWorld {
+    pub palette: Vec<Mesh>, // Block b is drawn with palette[b - 1]
+    pub material: Material, // Of every block, the palette meshes' texture coordinates are into its texture
+    chunks: HashMap<ChunkCoord, VoxelChunk>,
+    connections: HashMap<ChunkCoord, FaceConnections>, // Of every stored chunk, for visible_chunks
+    generation: u32
+}
+
+impl VoxelWorld {
+    pub fn new(palette: Vec<Mesh>, material: Material) -> VoxelWorld {
+        VoxelWorld {
+            palette,
+            material,
+            chunks: HashMap::new(),
+            connections: HashMap::new(),
+            generation: 0
+        }
+    }
+
+    // Replaces the chunk, an empty one is removed
+    pub fn set_chunk(&mut self, coord: ChunkCoord, chunk: VoxelChunk) -> Result<(), String> {
+        if chunk.max_block() as usize > self.palette.len() {
+            return Err(format!("Block {} has no palette entry, there are {}", chunk.max_block(),
+                               self.palette.len()));
+        }
+        if chunk.is_empty() {
+            self.remove_chunk(coord);
+            return Ok(());
+        }
+        self.connections.insert(coord, FaceConnections::of_chunk(&chunk));
+        self.chunks.insert(coord, chunk);
+        self.generation = self.generation.wrapping_add(1);
+
+        Ok(())
+    }
+
+    pub fn remove_chunk(&mut self, coord: ChunkCoord) -> Option<VoxelChunk> {
+        self.generation = self.generation.wrapping_add(1);
+        self.connections.remove(&coord);
+        self.chunks.remove(&coord)
+    }
+
+    // Which of the chunk's faces its air connects, all of them for chunks of air
+    pub fn connections(&self, coord: ChunkCoord) -> FaceConnections {
+        self.connections.get(&coord).copied().unwrap_or(FaceConnections::ALL)
+    }
+
+    pub fn chunk(&self, coord: ChunkCoord) -> Option<&VoxelChunk> {
+        self.chunks.get(&coord)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (&ChunkCoord, &VoxelChunk)> {
+        self.chunks.iter()
+    }
+
+    // The block at block..block + 1 on every axis, like VoxelHit::block
+    pub fn block(&self, block: Vector3<i32>) -> Block {
+        let (coord, [x, y, z]) = chunk_of(block);
+        self.chunks.get(&coord).map_or(AIR, |c| c.get(x, y, z))
+    }
+
+    // Changes with every set_chunk and remove_chunk
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+// How VoxelDraws turns chunks into triangles
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VoxelMeshing {
+    // An instance of the block's palette mesh per exposed block, found on the CPU. Palette meshes can be any shape,
+    // so there is no ambient occlusion.
+    #[default]
+    Instanced,
+    // Quads over the faces of equal blocks merged on the GPU, see GreedyMesher. Only for palettes of cubes whose
+    // texture coordinates cover a tile of the material's texture, like cube_mesh. Corners of faces next to other
+    // blocks are darkened by ambient occlusion baked into the vertices.
+    Greedy
+}
+
+// A palette mesh's triangles in VoxelDraws' shared buffers
+#[derive(Clone, Copy)]
+struct PaletteRange {
+    first_index: u32,
+    index_count: u32,
+    vertex_offset: i32
+}
+
+// A chunk's instances and an indirect draw per palette entry it uses
+struct InstancedChunk {
+    instances: GpuBuffer,
+    commands: Vec<vk::DrawIndexedIndirectCommand>, // Drawn one by one without multi_draw_indirect
+    indirect: GpuBuffer,
+    descriptor: Descriptor // One set, the view's transforms are bound at a dynamic offset
+}
+
+enum ChunkDraw {
+    Instanced(InstancedChunk),
+    Meshed(MeshedChunk)
+}
+
+impl ChunkDraw {
+    fn destroy(&self, core: &VkCore) {
+        match self {
+            ChunkDraw::Instanced(chunk) => {
+                chunk.descriptor.destroy(core);
+                chunk.indirect.destroy(core);
+                chunk.instances.destroy(core);
+            },
+            ChunkDraw::Meshed(chunk) => chunk.destroy(core)
+        }
+    }
+}
+
+// What VoxelMeshing::Greedy draws with, the quads are in world space and share a descriptor
+struct GreedyDraws {
+    mesher: GreedyMesher,
+    pipeline: RasterPipeline,
+    tiles: GpuBuffer, // palette_tiles
+    descriptor: Descriptor
+}
+
+impl GreedyDraws {
+    fn destroy(&self, core: &VkCore) {
+        self.descriptor.destroy(core);
+        self.tiles.destroy(core);
+        self.pipeline.destroy(core);
+        self.mesher.destroy(core);
+    }
+}
+
+// Draws a VoxelWorld with the clustered forward pipeline, see VoxelMeshing. The palette meshes share a vertex and
+// index buffer. With instancing, every chunk has a storage buffer with a BlockInstance per exposed block, sorted by
+// palette entry, and an indirect draw per entry whose first instance is where its blocks start, so the vertex shader
+// finds the block at gl_InstanceIndex. A chunk is one cmd_draw_indexed_indirect with multi_draw_indirect and
+// drawIndirectFirstInstance, devices without them get its draws one by one. Greedy meshed chunks are meshed by the
+// cmd_mesh after they were set and drawn with the indirect draw the compute shader wrote. Replaced and removed chunks
+// may still be drawn by frames in flight, so their buffers go to the renderer's deletion queue.
+pub struct VoxelDraws {
+    palette: Vec<PaletteRange>,
+    vertex_buffer: GpuBuffer,
+    index_buffer: IndexBuffer,
+    texture: Texture,
+    sampler: vk::Sampler,
+    chunks: HashMap<ChunkCoord, ChunkDraw>,
+    set_layout: vk::DescriptorSetLayout, // Of the pipelines, every chunk's descriptor has its own
+    pipeline: RasterPipeline,
+    greedy: Option<GreedyDraws>, // With VoxelMeshing::Greedy
+    unmeshed: Vec<ChunkCoord>, // Set since the last frame, meshed by cmd_mesh
+    uniforms: DynamicUniformBuffer, // Every view's transforms
+    view_offsets: Vec<u32>, // Of each view's transforms in the frame being recorded, see set_transforms
+    multi_draw_indirect: bool
+}
+
+impl VoxelDraws {
+    // Uploads the palette and draws every chunk of world
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, pass: &ForwardPass, world: &VoxelWorld,
+               meshing: VoxelMeshing) -> Result<VoxelDraws, String> {
+        let ForwardPass { render_pass, lights, msaa_samples, max_frames, views } = *pass;
+        if world.palette.is_empty() || world.palette.iter().any(|m| m.indices.is_empty()) {
+            return Err(String::from("Every palette entry needs triangles"));
+        }
+        if let Some(path) = world.material.texture.as_ref().filter(|p| !Path::new(p).is_file()) {
+            return Err(format!("{} not found", path));
+        }
+
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        let palette = world.palette.iter().map(|mesh| {
+            let range = PaletteRange {
+                first_index: indices.len() as u32,
+                index_count: mesh.indices.len() as u32,
+                vertex_offset: vertices.len() as i32
+            };
+            vertices.extend_from_slice(mesh.vertices.as_slice());
+            indices.extend_from_slice(mesh.indices.as_slice());
+            range
+        }).collect();
+        let features = unsafe { core.instance.get_physical_device_features(core.physical_device) };
+        let multi_draw_indirect = features.multi_draw_indirect == vk::TRUE &&
+            features.draw_indirect_first_instance == vk::TRUE;
+        let set_layout = create_object_descriptor_set_layout(core);
+        let texture = material_texture(core, command_pool, &world.material);
+        let sampler = create_sampler(core, texture.mip_levels);
+        let block_size = mem::size_of::<ViewTransforms>() as vk::DeviceSize;
+        let uniforms = DynamicUniformBuffer::new(core, block_size, views, max_frames);
+        let greedy = (meshing == VoxelMeshing::Greedy).then(|| {
+            let tiles = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                   palette_tiles(&world.palette).as_slice(),
+                                                   vk::MemoryPropertyFlags::DEVICE_LOCAL);
+            let tiles_layout = create_object_descriptor_set_layout(core); // Destroyed with the descriptor
+            GreedyDraws {
+                mesher: GreedyMesher::new(core),
+                pipeline: RasterPipeline::new_voxel_mesh(core, render_pass, set_layout, lights.set_layout(),
+                                                         msaa_samples),
+                descriptor: Descriptor::new_instances(core, &uniforms, tiles.buf, tiles.size, sampler, &texture,
+                                                      tiles_layout),
+                tiles
+            }
+        });
+
+        let mut draws = VoxelDraws {
+            palette,
+            vertex_buffer: GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::VERTEX_BUFFER,
+                                                      vertices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL),
+            index_buffer: IndexBuffer::new(core, command_pool, vk::BufferUsageFlags::INDEX_BUFFER, indices.as_slice(),
+                                           vk::MemoryPropertyFlags::DEVICE_LOCAL),
+            sampler,
+            texture,
+            chunks: HashMap::new(),
+            set_layout,
+            pipeline: RasterPipeline::new_voxels(core, render_pass, set_layout, lights.set_layout(), msaa_samples),
+            greedy,
+            unmeshed: Vec::new(),
+            uniforms,
+            view_offsets: vec![0; views],
+            multi_draw_indirect
+        };
+        for (coord, chunk) in world.chunks() {
+            draws.insert_chunk(core, command_pool, *coord, chunk);
+        }
+        debug!(palette = world.palette.len(), chunks = draws.chunks.len(), ?meshing, multi_draw_indirect,
+               "Created voxel draws");
+
+        Ok(draws)
+    }
+
+    pub fn meshing(&self) -> VoxelMeshing {
+        match self.greedy {
+            Some(_) => VoxelMeshing::Greedy,
+            None => VoxelMeshing::Instanced
+        }
+    }
+
+    // Draws the chunk at coord from the next frame, after VoxelWorld::set_chunk. Greedy meshing only uploads the
+    // blocks here. The chunk's previous draw is destroyed once frame_index finished.
+    pub fn set_chunk(&mut self, core: &VkCore, command_pool: vk::CommandPool, deletions: &mut DeletionQueue,
+                     frame_index: u64, coord: ChunkCoord, chunk: &VoxelChunk) {
+        self.remove_chunk(deletions, frame_index, coord);
+        self.insert_chunk(core, command_pool, coord, chunk);
+    }
+
+    // Frames up to frame_index may still draw it, the deletion queue destroys it once they finished
+    pub fn remove_chunk(&mut self, deletions: &mut DeletionQueue, frame_index: u64, coord: ChunkCoord) {
+        if let Some(draw) = self.chunks.remove(&coord) {
+            deletions.push(frame_index, Deletion::Custom(Box::new(move |core| draw.destroy(core))));
+        }
+        self.unmeshed.retain(|c| *c != coord);
+    }
+
+    // Where coord has no draw
+    fn insert_chunk(&mut self, core: &VkCore, command_pool: vk::CommandPool, coord: ChunkCoord, chunk: &VoxelChunk) {
+        if chunk.is_empty() {
+            return;
+        }
+        if let Some(greedy) = self.greedy.as_ref() {
+            let meshed = greedy.mesher.chunk(core, command_pool, coord, chunk);
+            self.chunks.insert(coord, ChunkDraw::Meshed(meshed));
+            self.unmeshed.push(coord);
+            return;
+        }
+
+        let chunk_instances = chunk.instances(coord, self.palette.len());
+        if chunk_instances.instances.is_empty() {
+            return;
+        }
+        let commands: Vec<vk::DrawIndexedIndirectCommand> = chunk_instances.ranges.iter()
+            .map(|&(palette, first_instance, instance_count)| {
+                let range = self.palette[palette];
+                vk::DrawIndexedIndirectCommand {
+                    index_count: range.index_count,
+                    instance_count,
+                    first_index: range.first_index,
+                    vertex_offset: range.vertex_offset,
+                    first_instance
+                }
+            })
+            .collect();
+        let instances = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                   chunk_instances.instances.as_slice(),
+                                                   vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let indirect = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::INDIRECT_BUFFER,
+                                                  commands.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let set_layout = create_object_descriptor_set_layout(core); // Destroyed with the descriptor
+        let descriptor = Descriptor::new_instances(core, &self.uniforms, instances.buf, instances.size,
+                                                   self.sampler, &self.texture, set_layout);
+        self.chunks.insert(coord, ChunkDraw::Instanced(InstancedChunk {
+            instances,
+            commands,
+            indirect,
+            descriptor
+        }));
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    // Of all instanced chunks, the exposed blocks
+    pub fn instance_count(&self) -> u32 {
+        self.chunks.values()
+            .filter_map(|c| match c {
+                ChunkDraw::Instanced(chunk) => Some(chunk),
+                ChunkDraw::Meshed(_) => None
+            })
+            .flat_map(|c| c.commands.iter())
+            .map(|c| c.instance_count)
+            .sum()
+    }
+
+    // Before the frame's first set_transforms
+    pub fn begin_frame(&mut self, current_frame: usize) {
+        self.uniforms.reset(current_frame);
+    }
+
+    // Once per frame and view, after begin_frame
+    pub fn set_transforms(&mut self, current_frame: usize, view_index: usize, view: cgmath::Matrix4<f32>,
+                          proj: cgmath::Matrix4<f32>) {
+        let transforms = ViewTransforms {
+            view,
+            proj
+        };
+        // Fits, there is a block per view
+        self.view_offsets[view_index] = self.uniforms.push(current_frame, &transforms).unwrap();
+    }
+
+    // Outside of render passes and before any cmd_draw of the frame, greedy meshes the chunks set since the last
+    // frame. end_frame forgets them.
+    pub fn cmd_mesh(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        if let Some(greedy) = self.greedy.as_ref() {
+            let chunks: Vec<&MeshedChunk> = self.unmeshed.iter()
+                .filter_map(|c| match self.chunks.get(c) {
+                    Some(ChunkDraw::Meshed(chunk)) => Some(chunk),
+                    _ => None
+                })
+                .collect();
+            greedy.mesher.cmd_mesh(core, command_buffer, chunks.as_slice());
+        }
+    }
+
+    // Inside the forward render pass, after the viewport and scissor are set. Only the visible chunks are drawn, see
+    // visible_chunks.
+    // Leaves the voxels' pipeline bound.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, draw: &ForwardDraw,
+                    visible: &HashSet<ChunkCoord>) {
+        if self.chunks.is_empty() {
+            return;
+        }
+        let ForwardDraw { lights, color_constants, current_frame, view_index } = *draw;
+        let (pipeline, layout) = match self.greedy.as_ref() {
+            Some(greedy) => (greedy.pipeline.pipelines[0], greedy.pipeline.pipeline_layout),
+            None => (self.pipeline.pipelines[0], self.pipeline.pipeline_layout)
+        };
+        let view_offset = [self.view_offsets[view_index]];
+        let stride = mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32;
+        unsafe {
+            core.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+            lights.cmd_bind(core, command_buffer, layout, current_frame);
+            core.logical_device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                                   cast_to_u8_slice(color_constants));
+            // Meshed chunks bind their own buffers, instanced ones their own descriptor
+            if let Some(greedy) = self.greedy.as_ref() {
+                core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout,
+                                                             0, &[greedy.descriptor.sets[0]], &view_offset);
+            } else {
+                core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buf], &[0]);
+                self.index_buffer.cmd_bind(core, command_buffer);
+            }
+            for draw in visible.iter().filter_map(|c| self.chunks.get(c)) {
+                let chunk = match draw {
+                    ChunkDraw::Meshed(chunk) => {
+                        chunk.cmd_draw(core, command_buffer);
+                        continue;
+                    },
+                    ChunkDraw::Instanced(chunk) => chunk
+                };
+                core.logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                                             layout, 0, &[chunk.descriptor.sets[0]], &view_offset);
+                if self.multi_draw_indirect {
+                    core.logical_device.cmd_draw_indexed_indirect(command_buffer, chunk.indirect.buf, 0,
+                                                                  chunk.commands.len() as u32, stride);
+                    continue;
+                }
+                for c in chunk.commands.iter() {
+                    core.logical_device.cmd_draw_indexed(command_buffer, c.index_count, c.instance_count,
+                                                         c.first_index, c.vertex_offset, c.first_instance);
+                }
+            }
+        }
+    }
+
+    // Call once per submitted frame
+    pub fn end_frame(&mut self) {
+        self.unmeshed.clear();
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for d in self.chunks.values() {
+            d.destroy(core);
+        }
+        if let Some(greedy) = self.greedy.as_ref() {
+            greedy.destroy(core);
+        }
+        self.pipeline.destroy(core);
+        self.uniforms.destroy(core);
+        destroy_sampler(core, self.sampler);
+        self.texture.destroy(core);
+        self.index_buffer.destroy(core);
+        self.vertex_buffer.destroy(core);
+        unsafe { core.logical_device.destroy_descriptor_set_layout(self.set_layout, None) };
+    }
+}
@@ -0,0 +1,117 @@
+use std::mem;
+
+use cgmath::Vector3;
+use winit::event::{MouseButton, VirtualKeyCode};
+
+use crate::collision::{cast_voxels, Ray, VoxelHit};
+use crate::input_replay::InputEvent;
+use crate::voxel::{chunk_of, Block, ChunkCoord, VoxelChunk, VoxelWorld, AIR};
+
+pub const REMOVE_BLOCK_BUTTON: MouseButton = MouseButton::Left;
+pub const PLACE_BLOCK_BUTTON: MouseButton = MouseButton::Right;
+// Key1 to Key9 pick the placed block, 1 to 9
+const BLOCK_KEYS: [VirtualKeyCode; 9] = [
+    VirtualKeyCode::Key1, VirtualKeyCode::Key2, VirtualKeyCode::Key3, VirtualKeyCode::Key4, VirtualKeyCode::Key5,
+    VirtualKeyCode::Key6, VirtualKeyCode::Key7, VirtualKeyCode::Key8, VirtualKeyCode::Key9
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockEdit {
+    Remove, // The targeted block
+    Place(Block) // Against the face of the targeted block the ray entered through
+}
+
+// The first solid block along ray, within reach of its origin
+pub fn target_block(world: &VoxelWorld, ray: &Ray, reach: f32) -> Option<VoxelHit> {
+    cast_voxels(ray, reach, |block| world.block(block) != AIR)
+}
+
+impl BlockEdit {
+    // The chunk the edit changes with its new blocks, for set_voxel_chunk. None if the ray hits nothing within reach,
+    // the targeted block is the one the ray starts in, or there is a block where one would be placed. Blocks only
+    // look at their own chunk to find their hidden faces, so neighbouring chunks never need to be drawn again.
+    pub fn apply(self, world: &VoxelWorld, ray: &Ray, reach: f32) -> Option<(ChunkCoord, VoxelChunk)> {
+        let hit = target_block(world, ray, reach)?;
+        let (block, value) = match self {
+            BlockEdit::Remove => (hit.block, AIR),
+            BlockEdit::Place(_) if hit.normal == Vector3::new(0, 0, 0) => return None,
+            BlockEdit::Place(value) => (hit.block + hit.normal, value)
+        };
+        if value != AIR && world.block(block) != AIR {
+            return None;
+        }
+        let (coord, [x, y, z]) = chunk_of(block);
+        let mut chunk = world.chunk(coord).cloned().unwrap_or_default();
+        chunk.set(x, y, z, value);
+
+        Some((coord, chunk))
+    }
+}
+
+// A click that edits a block, and where the cursor was, in physical pixels from the window's top left corner
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockClick {
+    pub edit: BlockEdit,
+    pub cursor: (f32, f32)
+}
+
+// Turns mouse clicks into block edits: REMOVE_BLOCK_BUTTON removes the targeted block, PLACE_BLOCK_BUTTON places the
+// block picked with keys 1 to 9 against the targeted face. The renderers take the clicks once per frame and apply
+// them with BlockEdit::apply, the edited chunks are drawn again from that frame.
+pub struct BlockEditor {
+    pub enabled: bool,
+    pub reach: f32, // In blocks from the camera
+    block: Block, // Placed by PLACE_BLOCK_BUTTON
+    cursor: (f32, f32),
+    clicks: Vec<BlockClick>
+}
+
+impl Default for BlockEditor {
+    fn default() -> BlockEditor {
+        BlockEditor {
+            enabled: true,
+            reach: 8.0,
+            block: 1,
+            cursor: (0.0, 0.0),
+            clicks: Vec::new()
+        }
+    }
+}
+
+impl BlockEditor {
+    pub fn block(&self) -> Block {
+        self.block
+    }
+
+    pub fn set_block(&mut self, block: Block) {
+        self.block = block;
+    }
+
+    pub fn handle_input(&mut self, input: &InputEvent) {
+        match *input {
+            InputEvent::CursorMoved { x, y } => self.cursor = (x as f32, y as f32),
+            InputEvent::MouseButton { button, pressed: true } if self.enabled => {
+                let edit = match button {
+                    REMOVE_BLOCK_BUTTON => BlockEdit::Remove,
+                    PLACE_BLOCK_BUTTON => BlockEdit::Place(self.block),
+                    _ => return
+                };
+                self.clicks.push(BlockClick {
+                    edit,
+                    cursor: self.cursor
+                });
+            },
+            InputEvent::Key { key: Some(key), pressed: true, .. } => {
+                if let Some(i) = BLOCK_KEYS.iter().position(|k| *k == key) {
+                    self.block = i as Block + 1;
+                }
+            },
+            _ => ()
+        }
+    }
+
+    // The clicks since the last call, in order
+    pub fn take_clicks(&mut self) -> Vec<BlockClick> {
+        mem::take(&mut self.clicks)
+    }
+}
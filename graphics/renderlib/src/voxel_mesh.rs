@@ -0,0 +1,226 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::compute::ComputePipeline;
+use crate::gpu_buffer::GpuBuffer;
+use crate::objects::Mesh;
+use crate::renderutils::cast_to_u8_slice;
+use crate::vertex::VoxelVertex;
+use crate::vkcore::VkCore;
+use crate::voxel::{chunk_origin, ChunkCoord, VoxelChunk};
+
+const VOXEL_MESH_SHADER_PATH: &str = "graphics/shaders/spv/voxel_mesh.spv";
+// A work group per face direction, voxel_mesh.comp has an invocation per slice of the chunk
+const FACE_DIRECTIONS: u32 = 6;
+
+// Quads a chunk's buffers hold. A chunk whose blocks alternate with air like a checkerboard has 12288, real terrain
+// has a few hundred after merging. Quads past this are dropped and leave holes.
+pub const MAX_CHUNK_QUADS: usize = 4096;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MeshConstants {
+    origin: [f32; 4],
+    max_quads: u32
+}
+
+// Mirrors the Draw block of voxel_mesh.comp
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct MeshDraw {
+    command: vk::DrawIndexedIndirectCommand,
+    quad_count: u32
+}
+
+// The part of the texture each palette entry's texture coordinates cover, corner and size, for palettes of
+// cube_mesh blocks that are tiles of one atlas. Greedy meshed quads repeat it once per block.
+pub fn palette_tiles(palette: &[Mesh]) -> Vec<[f32; 4]> {
+    palette.iter().map(|mesh| {
+        let (mut min, mut max) = ([f32::MAX; 2], [f32::MIN; 2]);
+        for v in mesh.vertices.iter() {
+            for a in 0..2 {
+                min[a] = min[a].min(v.tex_coord[a]);
+                max[a] = max[a].max(v.tex_coord[a]);
+            }
+        }
+        [min[0], min[1], max[0] - min[0], max[1] - min[1]]
+    }).collect()
+}
+
+// A chunk's blocks and the quads voxel_mesh.comp emits from them, with the indirect draw of the quads it wrote
+pub struct MeshedChunk {
+    origin: [f32; 4],
+    blocks: GpuBuffer, // Host visible, a u32 per block
+    vertices: GpuBuffer,
+    indices: GpuBuffer,
+    draw: GpuBuffer, // A MeshDraw
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet
+}
+
+impl MeshedChunk {
+    // The quads voxel_mesh.comp wrote, after cmd_mesh
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertices.buf], &[0]);
+            core.logical_device.cmd_bind_index_buffer(command_buffer, self.indices.buf, 0, vk::IndexType::UINT32);
+            core.logical_device.cmd_draw_indexed_indirect(command_buffer, self.draw.buf, 0, 1,
+                                                          mem::size_of::<MeshDraw>() as u32);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe { core.logical_device.destroy_descriptor_pool(self.pool, None) };
+        self.draw.destroy(core);
+        self.indices.destroy(core);
+        self.vertices.destroy(core);
+        self.blocks.destroy(core);
+    }
+}
+
+// Greedy meshes chunks on the GPU, so that changing the world costs an upload of the chunk's blocks rather than
+// meshing on the CPU and waiting for a staging copy. Every dispatch resets the chunk's indirect draw, voxel_mesh.comp
+// appends quads to the vertex and index buffers through an atomic counter and grows the draw's index count with them.
+// Each corner gets the classic voxel ambient occlusion from the two blocks beside it and the one diagonal to it on the
+// face's side, see VoxelVertex. Faces only merge with faces whose corners are occluded alike, so the baked occlusion
+// interpolates across quads the same as across single faces.
+pub struct GreedyMesher {
+    pipeline: ComputePipeline,
+    set_layout: vk::DescriptorSetLayout
+}
+
+impl GreedyMesher {
+    pub fn new(core: &VkCore) -> GreedyMesher {
+        let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..4).map(|b| {
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(b) // 0 blocks, 1 vertices, 2 indices, 3 indirect draw
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        }).collect();
+        let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .bindings(bindings.as_slice());
+        let set_layout = unsafe {
+            core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap()
+        };
+        let pipeline = ComputePipeline::new(core, VOXEL_MESH_SHADER_PATH, &[set_layout],
+                                            mem::size_of::<MeshConstants>() as u32);
+
+        GreedyMesher {
+            pipeline,
+            set_layout
+        }
+    }
+
+    // Uploads the blocks, the quads are emitted by the next cmd_mesh that gets the chunk
+    pub fn chunk(&self, core: &VkCore, command_pool: vk::CommandPool, coord: ChunkCoord, chunk: &VoxelChunk)
+        -> MeshedChunk {
+        let blocks: Vec<u32> = chunk.blocks().iter().map(|&b| b as u32).collect();
+        let blocks = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                blocks.as_slice(), vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                    vk::MemoryPropertyFlags::HOST_COHERENT);
+        let vertices = GpuBuffer::new(core, (mem::size_of::<VoxelVertex>() * 4 * MAX_CHUNK_QUADS) as vk::DeviceSize,
+                                      vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER,
+                                      vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let indices = GpuBuffer::new(core, (mem::size_of::<u32>() * 6 * MAX_CHUNK_QUADS) as vk::DeviceSize,
+                                     vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER,
+                                     vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let draw = GpuBuffer::new(core, mem::size_of::<MeshDraw>() as vk::DeviceSize,
+                                  vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER |
+                                      vk::BufferUsageFlags::TRANSFER_DST,
+                                  vk::MemoryPropertyFlags::DEVICE_LOCAL);
+
+        let pool_size = [
+            vk::DescriptorPoolSize::default()
+                .descriptor_count(4)
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(1)
+            .pool_sizes(&pool_size);
+        let pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = [self.set_layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let set = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap()[0] };
+        let buffer_infos = [blocks.buf, vertices.buf, indices.buf, draw.buf]
+            .map(|buf| [vk::DescriptorBufferInfo::default().buffer(buf).offset(0).range(vk::WHOLE_SIZE)]);
+        let writes: Vec<vk::WriteDescriptorSet> = buffer_infos.iter().enumerate().map(|(b, info)| {
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(b as u32)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(info)
+        }).collect();
+        unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+
+        let origin = chunk_origin(coord);
+        MeshedChunk {
+            origin: [origin.x, origin.y, origin.z, 1.0],
+            blocks,
+            vertices,
+            indices,
+            draw,
+            pool,
+            set
+        }
+    }
+
+    // Outside of render passes. The chunks' quads are ready for vertex input and indirect draws recorded after this
+    // call.
+    pub fn cmd_mesh(&self, core: &VkCore, command_buffer: vk::CommandBuffer, chunks: &[&MeshedChunk]) {
+        if chunks.is_empty() {
+            return;
+        }
+        let reset = MeshDraw {
+            command: vk::DrawIndexedIndirectCommand {
+                index_count: 0,
+                instance_count: 1,
+                first_index: 0,
+                vertex_offset: 0,
+                first_instance: 0
+            },
+            quad_count: 0
+        };
+        unsafe {
+            for chunk in chunks.iter() {
+                core.logical_device.cmd_update_buffer(command_buffer, chunk.draw.buf, 0, cast_to_u8_slice(&reset));
+            }
+            let reset_barrier = [vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)];
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &reset_barrier, &[], &[]);
+
+            for chunk in chunks.iter() {
+                let constants = MeshConstants {
+                    origin: chunk.origin,
+                    max_quads: MAX_CHUNK_QUADS as u32
+                };
+                self.pipeline.cmd_bind(core, command_buffer, chunk.set);
+                core.logical_device.cmd_push_constants(command_buffer, self.pipeline.layout,
+                                                       vk::ShaderStageFlags::COMPUTE, 0,
+                                                       cast_to_u8_slice(&constants));
+                core.logical_device.cmd_dispatch(command_buffer, FACE_DIRECTIONS, 1, 1);
+            }
+
+            let mesh_barrier = [vk::MemoryBarrier::default()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::VERTEX_ATTRIBUTE_READ |
+                    vk::AccessFlags::INDEX_READ)];
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::DRAW_INDIRECT |
+                                                         vk::PipelineStageFlags::VERTEX_INPUT,
+                                                     vk::DependencyFlags::empty(), &mesh_barrier, &[], &[]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.pipeline.destroy(core);
+        unsafe { core.logical_device.destroy_descriptor_set_layout(self.set_layout, None) };
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use cgmath::{EuclideanSpace, Point3, Vector3};
+use tracing::debug;
+
+use crate::voxel::{chunk_of, ChunkCoord, VoxelChunk};
+
+// Where streamed chunks come from, like a generator or a save file. Called on the streamer's worker threads, several
+// chunks at once.
+pub trait ChunkSource: Send + Sync {
+    // None for a chunk that is all air
+    fn load(&self, coord: ChunkCoord) -> Option<VoxelChunk>;
+}
+
+impl<F: Fn(ChunkCoord) -> Option<VoxelChunk> + Send + Sync> ChunkSource for F {
+    fn load(&self, coord: ChunkCoord) -> Option<VoxelChunk> {
+        self(coord)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkStreamingSettings {
+    pub radius: i32, // In chunks around the camera's chunk, horizontally
+    pub vertical_radius: i32, // Chunks above and below the camera's
+    // Chunks are unloaded this much farther out than they are loaded, so that moving back and forth across a chunk
+    // border doesn't load the same chunks again
+    pub unload_margin: i32,
+    pub max_loads: usize, // Chunks requested from the source at once
+    pub max_uploads: usize, // Loaded chunks handed out per update, each is an upload
+    pub workers: usize // Threads loading chunks, only read by ChunkStreamer::new
+}
+
+impl Default for ChunkStreamingSettings {
+    fn default() -> ChunkStreamingSettings {
+        ChunkStreamingSettings {
+            radius: 8,
+            vertical_radius: 4,
+            unload_margin: 2,
+            max_loads: 16,
+            max_uploads: 4,
+            // Leaves cores for recording and the other workers, like texture decoding
+            workers: thread::available_parallelism().map_or(1, |n| n.get() / 2).max(1)
+        }
+    }
+}
+
+impl ChunkStreamingSettings {
+    // A cylinder around center, margin chunks wider and higher
+    fn in_range(&self, center: ChunkCoord, coord: ChunkCoord, margin: i32) -> bool {
+        let d = coord - center;
+        let radius = self.radius + margin;
+        d.x * d.x + d.y * d.y <= radius * radius && d.z.abs() <= self.vertical_radius + margin
+    }
+}
+
+fn distance2(a: ChunkCoord, b: ChunkCoord) -> i32 {
+    let d = a - b;
+    d.x * d.x + d.y * d.y + d.z * d.z
+}
+
+// What a renderer applies to its VoxelWorld, its draws and its TLAS after ChunkStreamer::update
+#[derive(Debug, Default)]
+pub struct ChunkChanges {
+    pub loaded: Vec<(ChunkCoord, VoxelChunk)>,
+    pub unloaded: Vec<ChunkCoord>
+}
+
+impl ChunkChanges {
+    pub fn is_empty(&self) -> bool {
+        self.loaded.is_empty() && self.unloaded.is_empty()
+    }
+}
+
+// Keeps the chunks around the camera loaded. Worker threads ask the ChunkSource for the missing chunks, closest first,
+// while frames are rendered, and update hands out what they returned a few chunks per frame, so that uploads don't
+// pile up in one frame. Chunks that fell out of range are unloaded, the renderers destroy their buffers through the
+// deletion queue. Chunks of air are resident too, only nothing is drawn for them.
+pub struct ChunkStreamer {
+    settings: ChunkStreamingSettings,
+    resident: HashSet<ChunkCoord>, // Handed out by update and not unloaded since
+    requested: HashSet<ChunkCoord>, // Sent to the workers
+    ready: HashMap<ChunkCoord, Option<VoxelChunk>>, // Returned by the workers, not handed out yet
+    jobs: Sender<ChunkCoord>,
+    loaded: Receiver<(ChunkCoord, Option<VoxelChunk>)>
+}
+
+impl ChunkStreamer {
+    pub fn new(source: Arc<dyn ChunkSource>, settings: ChunkStreamingSettings) -> ChunkStreamer {
+        let (jobs, job_receiver) = mpsc::channel::<ChunkCoord>();
+        let (loaded_sender, loaded) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for i in 0..settings.workers.max(1) {
+            let (job_receiver, loaded_sender, source) = (job_receiver.clone(), loaded_sender.clone(), source.clone());
+            // Ends once the streamer, and with it jobs, is dropped
+            thread::Builder::new()
+                .name(format!("chunk-load-{}", i))
+                .spawn(move || loop {
+                    // The lock is only held while waiting for a job, the workers load in parallel
+                    let coord = match job_receiver.lock().unwrap().recv() {
+                        Ok(coord) => coord,
+                        Err(_) => break
+                    };
+                    if loaded_sender.send((coord, source.load(coord))).is_err() {
+                        break;
+                    }
+                })
+                .unwrap();
+        }
+
+        ChunkStreamer {
+            settings,
+            resident: HashSet::new(),
+            requested: HashSet::new(),
+            ready: HashMap::new(),
+            jobs,
+            loaded
+        }
+    }
+
+    pub fn settings(&self) -> ChunkStreamingSettings {
+        self.settings
+    }
+
+    // Takes effect with the next update, a smaller radius unloads the chunks outside it
+    pub fn set_settings(&mut self, settings: ChunkStreamingSettings) {
+        self.settings = settings;
+    }
+
+    pub fn resident_count(&self) -> usize {
+        self.resident.len()
+    }
+
+    // Requested or returned by the source, not handed out yet
+    pub fn loading_count(&self) -> usize {
+        self.requested.len() + self.ready.len()
+    }
+
+    // Once per frame with the camera's world space position
+    pub fn update(&mut self, eye: Point3<f32>) -> ChunkChanges {
+        let (center, _) = chunk_of(eye.to_vec().map(|c| c.floor() as i32));
+        let settings = self.settings;
+        for (coord, chunk) in self.loaded.try_iter() {
+            self.requested.remove(&coord);
+            self.ready.insert(coord, chunk);
+        }
+        // Chunks that left the range while they were loaded aren't needed anymore
+        self.ready.retain(|c, _| settings.in_range(center, *c, settings.unload_margin));
+
+        let mut changes = ChunkChanges {
+            loaded: Vec::new(),
+            unloaded: self.resident.iter()
+                .filter(|c| !settings.in_range(center, **c, settings.unload_margin))
+                .copied()
+                .collect()
+        };
+        for coord in changes.unloaded.iter() {
+            self.resident.remove(coord);
+        }
+
+        let (radius, height) = (settings.radius, settings.vertical_radius);
+        let mut missing: Vec<ChunkCoord> = Vec::new();
+        for z in -height..=height {
+            for y in -radius..=radius {
+                for x in -radius..=radius {
+                    let coord = center + Vector3::new(x, y, z);
+                    if settings.in_range(center, coord, 0) && !self.resident.contains(&coord) &&
+                        !self.requested.contains(&coord) && !self.ready.contains_key(&coord) {
+                        missing.push(coord);
+                    }
+                }
+            }
+        }
+        missing.sort_by_key(|c| distance2(*c, center));
+        for coord in missing.into_iter().take(settings.max_loads.saturating_sub(self.requested.len())) {
+            // Only fails if every worker panicked, the chunk stays missing then
+            if self.jobs.send(coord).is_ok() {
+                self.requested.insert(coord);
+            }
+        }
+
+        let mut ready: Vec<ChunkCoord> = self.ready.keys().copied().collect();
+        ready.sort_by_key(|c| distance2(*c, center));
+        for coord in ready.into_iter().take(settings.max_uploads) {
+            if let Some(chunk) = self.ready.remove(&coord).unwrap() {
+                changes.loaded.push((coord, chunk));
+            }
+            self.resident.insert(coord);
+        }
+        if !changes.is_empty() {
+            debug!(?center, loaded = changes.loaded.len(), unloaded = changes.unloaded.len(),
+                   resident = self.resident.len(), loading = self.loading_count(), "Streamed chunks");
+        }
+
+        changes
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{HashSet, VecDeque};
+
+use cgmath::{EuclideanSpace, Point3, Vector3};
+
+use crate::collision::Frustum;
+use crate::voxel::{chunk_bounds, chunk_of, ChunkCoord, VoxelChunk, VoxelWorld, AIR, CHUNK_SIZE, FACE_NEIGHBORS};
+
+// How far visible_chunks looks from the camera's chunk along each axis, 512 blocks
+pub const VISIBILITY_DISTANCE: i32 = 32;
+
+// Which faces of a chunk are connected through air inside it, a bit per ordered pair of faces. Faces are in the
+// order of FACE_NEIGHBORS, -x, +x, -y, +y, -z and +z, so opposite faces differ in the lowest bit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaceConnections(u64);
+
+impl FaceConnections {
+    // Of air chunks, which aren't stored
+    pub const ALL: FaceConnections = FaceConnections((1 << 36) - 1);
+
+    // Flood fills the chunk's air, each region connects all the faces it touches
+    pub fn of_chunk(chunk: &VoxelChunk) -> FaceConnections {
+        let size = CHUNK_SIZE as i32;
+        let blocks = chunk.blocks();
+        let mut visited = vec![false; blocks.len()];
+        let mut stack: Vec<usize> = Vec::new();
+        let mut connections = 0u64;
+        for start in 0..blocks.len() {
+            if visited[start] || blocks[start] != AIR {
+                continue;
+            }
+            visited[start] = true;
+            stack.push(start);
+            let mut faces = 0u64;
+            while let Some(i) = stack.pop() {
+                let p = Vector3::new(i % CHUNK_SIZE, (i / CHUNK_SIZE) % CHUNK_SIZE, i / (CHUNK_SIZE * CHUNK_SIZE))
+                    .map(|c| c as i32);
+                for (face, d) in FACE_NEIGHBORS.iter().enumerate() {
+                    let n = p + Vector3::from(*d);
+                    if n.x < 0 || n.y < 0 || n.z < 0 || n.x >= size || n.y >= size || n.z >= size {
+                        faces |= 1 << face;
+                        continue;
+                    }
+                    let j = (n.x + n.y * size + n.z * size * size) as usize;
+                    if !visited[j] && blocks[j] == AIR {
+                        visited[j] = true;
+                        stack.push(j);
+                    }
+                }
+            }
+            for a in (0..6).filter(|a| faces & (1 << a) != 0) {
+                connections |= faces << (a * 6);
+            }
+        }
+
+        FaceConnections(connections)
+    }
+
+    pub fn connects(self, a: usize, b: usize) -> bool {
+        (self.0 >> (a * 6 + b)) & 1 == 1
+    }
+}
+
+// The chunks a camera at eye may see, cave culling after Tommaso Checchi's "Advanced Cave Culling Algorithm". A
+// breadth first search from the camera's chunk only leaves a chunk through faces that its air connects to the face it
+// was entered through, never steps back towards the camera along an axis it already went along, and skips chunks
+// outside frustum. Chunks behind solid ground, like caves under the camera, are never reached. Stays within the
+// stored chunks and the camera's chunk, and within max_distance chunks of the camera's, like VISIBILITY_DISTANCE.
+pub fn visible_chunks(world: &VoxelWorld, eye: Point3<f32>, frustum: Option<&Frustum>, max_distance: i32)
+    -> HashSet<ChunkCoord> {
+    let (start, _) = chunk_of(eye.to_vec().map(|c| c.floor() as i32));
+    let (mut min, mut max) = (start, start);
+    for coord in world.chunks().map(|(c, _)| *c) {
+        for a in 0..3 {
+            min[a] = min[a].min(coord[a]).max(start[a] - max_distance);
+            max[a] = max[a].max(coord[a]).min(start[a] + max_distance);
+        }
+    }
+
+    let mut visible = HashSet::from([start]);
+    // The chunk, the face it was entered through and the directions taken to get there, a bit per face
+    let mut queue: VecDeque<(ChunkCoord, Option<usize>, u32)> = VecDeque::from([(start, None, 0)]);
+    while let Some((coord, entry, directions)) = queue.pop_front() {
+        let connections = world.connections(coord);
+        for (face, d) in FACE_NEIGHBORS.iter().enumerate() {
+            if directions & (1 << (face ^ 1)) != 0 || entry.is_some_and(|e| !connections.connects(e, face)) {
+                continue;
+            }
+            let next = coord + Vector3::from(*d);
+            if (0..3).any(|a| next[a] < min[a] || next[a] > max[a]) || visible.contains(&next) ||
+                frustum.is_some_and(|f| !f.intersects(&chunk_bounds(next))) {
+                continue;
+            }
+            visible.insert(next);
+            queue.push_back((next, Some(face ^ 1), directions | (1 << face)));
+        }
+    }
+
+    visible
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tunnel_connects_its_ends() {
+        let mut chunk = VoxelChunk::filled(1);
+        assert_eq!(FaceConnections::of_chunk(&chunk), FaceConnections(0));
+        // Along x through the middle
+        for x in 0..CHUNK_SIZE {
+            chunk.set(x, 8, 8, AIR);
+        }
+        let connections = FaceConnections::of_chunk(&chunk);
+        assert!(connections.connects(0, 1) && connections.connects(1, 0));
+        assert!(!connections.connects(0, 5) && !connections.connects(4, 5));
+        assert!(FaceConnections::of_chunk(&VoxelChunk::default()).connects(2, 5));
+    }
+}
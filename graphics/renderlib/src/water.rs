@@ -0,0 +1,495 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::vk;
+use cgmath::{InnerSpace, Matrix, Matrix4, SquareMatrix, Vector2, Vector3, Vector4};
+use tracing::debug;
+
+use crate::clock::Clock;
+use crate::color_pipeline::ColorConstants;
+use crate::depth::find_depth_format;
+use crate::frame_buffers::destroy_frame_buffers;
+use crate::gpu_buffer::GpuBuffer;
+use crate::image::transition_image_layout;
+use crate::index::IndexBuffer;
+use crate::offscreen::{Attachment, create_offscreen_render_pass, OffscreenTarget};
+use crate::raster_pipeline::RasterPipeline;
+use crate::render_target::RenderTarget;
+use crate::renderutils::cast_to_u8_slice;
+use crate::sampler::{create_linear_sampler, create_nearest_sampler, destroy_sampler};
+use crate::scene::{Light, SceneCamera, SceneWater};
+use crate::ssao::{create_apply_frame_buffers, create_apply_render_pass, create_set_layout};
+use crate::vertex::Vertex;
+use crate::vkcore::VkCore;
+
+const MAX_WAVES: usize = 4; // MAX_WAVES in watercommon.glsl
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WaterSettings {
+    pub resolution: u32, // Quads across the grid
+    pub reflection_scale: f32, // Size of the reflection target relative to the swap chain
+    pub distortion: f32, // How far ripples offset the reflection and refraction, in texture coordinates
+    pub clip_bias: f32 // The reflection keeps what is this far below the surface, which hides seams at the shore
+}
+
+impl Default for WaterSettings {
+    fn default() -> WaterSettings {
+        WaterSettings {
+            resolution: 128,
+            reflection_scale: 0.5,
+            distortion: 0.02,
+            clip_bias: 0.05
+        }
+    }
+}
+
+// Matches WaterUniforms in watercommon.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct WaterUniforms {
+    view_proj: Matrix4<f32>,
+    reflection_view_proj: Matrix4<f32>,
+    eye_time: [f32; 4],
+    depth: [f32; 4],
+    color: [f32; 4],
+    sun_direction: [f32; 4],
+    sun_color: [f32; 4],
+    waves: [[f32; 4]; MAX_WAVES],
+    steepness: [f32; 4]
+}
+
+fn create_scene_color(core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget) -> Attachment {
+    let scene_color = Attachment::new(core, render_target.extent, render_target.surface_format,
+                                      vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+                                      vk::SampleCountFlags::TYPE_1, vk::ImageAspectFlags::COLOR);
+    // cmd_draw expects it readable
+    transition_image_layout(core, command_pool, scene_color.image, render_target.surface_format,
+                            vk::ImageLayout::UNDEFINED, vk::ImageLayout::TRANSFER_DST_OPTIMAL, 1);
+    transition_image_layout(core, command_pool, scene_color.image, render_target.surface_format,
+                            vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, 1);
+
+    scene_color
+}
+
+fn reflection_extent(render_target: &RenderTarget, scale: f32) -> vk::Extent2D {
+    vk::Extent2D {
+        width: ((render_target.extent.width as f32 * scale) as u32).max(1),
+        height: ((render_target.extent.height as f32 * scale) as u32).max(1)
+    }
+}
+
+// Flat grid at the rest height, the vertex shader adds the waves
+fn grid(surface: &SceneWater, resolution: u32) -> (Vec<Vertex>, Vec<u32>) {
+    let quads = resolution.max(1);
+    let (size, height) = (surface.size, surface.height);
+    let vertices: Vec<Vertex> = (0..=quads)
+        .flat_map(|j| (0..=quads).map(move |i| {
+            let (u, v) = (i as f32 / quads as f32, j as f32 / quads as f32);
+            Vertex {
+                pos: [(u - 0.5) * size, (v - 0.5) * size, height],
+                color: [1.0, 1.0, 1.0],
+                tex_coord: [u, v]
+            }
+        }))
+        .collect();
+    let at = |i: u32, j: u32| j * (quads + 1) + i;
+    let mut indices: Vec<u32> = Vec::with_capacity((quads * quads * 6) as usize);
+    for j in 0..quads {
+        for i in 0..quads {
+            // Counter-clockwise seen from above
+            indices.extend([at(i, j), at(i + 1, j), at(i + 1, j + 1), at(i, j), at(i + 1, j + 1), at(i, j + 1)]);
+        }
+    }
+
+    (vertices, indices)
+}
+
+// Unused waves are flat
+fn wave_uniforms(surface: &SceneWater) -> ([[f32; 4]; MAX_WAVES], [f32; 4]) {
+    let mut waves = [[1.0, 0.0, 1.0, 0.0]; MAX_WAVES];
+    let mut steepness = [0.0; MAX_WAVES];
+    for (i, wave) in surface.waves.iter().take(MAX_WAVES).enumerate() {
+        let direction = Vector2::from(wave.direction).normalize();
+        waves[i] = [direction.x, direction.y, wave.wavelength, wave.amplitude];
+        steepness[i] = wave.steepness;
+    }
+
+    (waves, steepness)
+}
+
+// Direction and color times intensity of the first directional light, which makes the glint
+fn find_sun(lights: &[Light]) -> ([f32; 4], [f32; 4]) {
+    lights.iter()
+        .find_map(|l| match *l {
+            Light::Directional { direction, color, intensity } => {
+                let direction = Vector3::from(direction).normalize();
+                Some(([direction.x, direction.y, direction.z, 0.0],
+                      [color[0] * intensity, color[1] * intensity, color[2] * intensity, 0.0]))
+            },
+            Light::Point { .. } => None
+        })
+        .unwrap_or(([0.0, 0.0, -1.0, 0.0], [0.0; 4]))
+}
+
+// The water that Water::new draws and how
+#[derive(Clone, Copy)]
+pub struct WaterInfo<'a> {
+    pub surface: &'a SceneWater,
+    pub settings: WaterSettings,
+    pub lights: &'a [Light] // The first directional one is the sun, see Water::set_lights
+}
+
+// Animated water plane for the raster renderer, drawn over the finished frame:
+// 1. The scene is drawn again by the renderer from the camera mirrored below the surface, see cmd_draw_reflection.
+//    The projection's near plane lies on the water so that nothing below it is reflected.
+// 2. cmd_draw copies the frame into the scene color image, then draws the grid displaced by a sum of Gerstner waves.
+//    Fragments behind the depth buffer are discarded. The scene behind the surface is refracted, fading into the
+//    water's color with the distance the view ray travels underwater, and blended with the reflection by the
+//    Fresnel term. Both are offset along the ripples.
+// The depth buffer must come from Depth::new_sampled and the render pass from setup_render_pass_stored_depth. The
+// water pass is single sampled, its edges against the scene aren't antialiased.
+pub struct Water {
+    surface: SceneWater,
+    settings: WaterSettings,
+    waves: [[f32; 4]; MAX_WAVES],
+    steepness: [f32; 4],
+    sun: ([f32; 4], [f32; 4]),
+    vertex_buffer: GpuBuffer,
+    index_buffer: IndexBuffer,
+    uniform_buffers: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>,
+    render_pass: vk::RenderPass, // Draws over the swap chain image
+    frame_buffers: Vec<vk::Framebuffer>,
+    pipeline: RasterPipeline,
+    reflection_pass: vk::RenderPass,
+    reflection: OffscreenTarget, // Where the mirrored scene is drawn, at a fraction of the swap chain's size
+    scene_color: Attachment, // The frame before the water, copied from the swap chain image
+    sampler: vk::Sampler,
+    depth_sampler: vk::Sampler,
+    surface_format: vk::Format,
+    extent: vk::Extent2D
+}
+
+impl Water {
+    // depth_samples is the sample count of the depth buffer
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget, depth_view: vk::ImageView,
+               depth_samples: vk::SampleCountFlags, info: &WaterInfo, max_frames: usize) -> Water {
+        let WaterInfo { surface, settings, lights } = *info;
+        let (vertices, indices) = grid(surface, settings.resolution);
+        let vertex_buffer = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::VERTEX_BUFFER,
+                                                       vertices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let index_buffer = IndexBuffer::new(core, command_pool, vk::BufferUsageFlags::INDEX_BUFFER,
+                                            indices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let uniform_size = mem::size_of::<WaterUniforms>() as vk::DeviceSize;
+        let uniform_buffers: Vec<GpuBuffer> = (0..max_frames)
+            .map(|_| GpuBuffer::new_mapped(core, uniform_size, vk::BufferUsageFlags::UNIFORM_BUFFER))
+            .collect();
+        let mapped: Vec<*mut c_void> = uniform_buffers.iter()
+            .map(|b| unsafe {
+                core.logical_device.map_memory(b.mem, 0, uniform_size, vk::MemoryMapFlags::empty()).unwrap()
+            })
+            .collect();
+
+        let set_layout = create_set_layout(core, &[
+            (vk::DescriptorType::UNIFORM_BUFFER, vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT),
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT), // Depth buffer
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT), // Scene color
+            (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, vk::ShaderStageFlags::FRAGMENT) // Reflection
+        ]);
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(max_frames as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(3 * max_frames as u32)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+
+        let render_pass = create_apply_render_pass(core, render_target);
+        let reflection_pass = create_offscreen_render_pass(core, render_target.surface_format, find_depth_format(core),
+                                                           core.max_msaa_samples);
+        let (waves, steepness) = wave_uniforms(surface);
+        debug!(vertices = vertices.len(), waves = surface.waves.len(), "Created water");
+
+        let water = Water {
+            surface: surface.clone(),
+            settings,
+            waves,
+            steepness,
+            sun: find_sun(lights),
+            vertex_buffer,
+            index_buffer,
+            uniform_buffers,
+            mapped,
+            set_layout,
+            descriptor_pool,
+            sets,
+            render_pass,
+            frame_buffers: create_apply_frame_buffers(core, render_pass, render_target),
+            pipeline: RasterPipeline::new_water(core, render_pass, set_layout, depth_samples),
+            reflection_pass,
+            reflection: OffscreenTarget::new(core, reflection_pass, render_target.surface_format,
+                                              reflection_extent(render_target, settings.reflection_scale)),
+            scene_color: create_scene_color(core, command_pool, render_target),
+            sampler: create_linear_sampler(core),
+            depth_sampler: create_nearest_sampler(core),
+            surface_format: render_target.surface_format,
+            extent: render_target.extent
+        };
+        water.write_descriptor_sets(core, depth_view);
+
+        water
+    }
+
+    fn write_descriptor_sets(&self, core: &VkCore, depth_view: vk::ImageView) {
+        let depth_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.depth_sampler)
+            .image_view(depth_view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+        let scene_color_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(self.scene_color.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let reflection_info = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(self.reflection.resolved.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        for (set, buffer) in self.sets.iter().zip(self.uniform_buffers.iter()) {
+            let uniform_info = [vk::DescriptorBufferInfo::default()
+                .buffer(buffer.buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let mut writes = vec![vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&uniform_info)];
+            for (binding, info) in [(1, &depth_info), (2, &scene_color_info), (3, &reflection_info)] {
+                writes.push(vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(binding)
+                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                    .image_info(info));
+            }
+            unsafe { core.logical_device.update_descriptor_sets(writes.as_slice(), &[]) };
+        }
+    }
+
+    // Call after the swap chain and depth buffer were recreated
+    pub fn resize(&mut self, core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget,
+                  depth_view: vk::ImageView) {
+        destroy_frame_buffers(core, &self.frame_buffers);
+        self.reflection.destroy(core);
+        self.scene_color.destroy(core);
+        self.frame_buffers = create_apply_frame_buffers(core, self.render_pass, render_target);
+        self.reflection = OffscreenTarget::new(core, self.reflection_pass, self.surface_format,
+                                                reflection_extent(render_target, self.settings.reflection_scale));
+        self.scene_color = create_scene_color(core, command_pool, render_target);
+        self.extent = render_target.extent;
+        self.write_descriptor_sets(core, depth_view);
+    }
+
+    // Picks the light of the glint from the next update
+    pub fn set_lights(&mut self, lights: &[Light]) {
+        self.sun = find_sun(lights);
+    }
+
+    pub fn surface(&self) -> &SceneWater {
+        &self.surface
+    }
+
+    // The camera mirrored below the surface at rest, which draws the reflection
+    pub fn reflected_camera(&self, camera: &SceneCamera) -> SceneCamera {
+        let mirror = |p: [f32; 3]| [p[0], p[1], 2.0 * self.surface.height - p[2]];
+        SceneCamera {
+            eye: mirror(camera.eye),
+            target: mirror(camera.target),
+            up: [camera.up[0], camera.up[1], -camera.up[2]],
+            ..camera.clone()
+        }
+    }
+
+    // reflected's projection with Lengyel's oblique near plane on the water, so that nothing below the surface shows
+    // up in the reflection. The far plane tilts to pass through the far corner of the frustum. extent is the main
+    // view's, only its aspect ratio is used.
+    pub fn reflection_projection(&self, reflected: &SceneCamera, extent: vk::Extent2D) -> Matrix4<f32> {
+        let mut projection = reflected.projection(extent);
+        let clip_height = self.surface.height - self.settings.clip_bias;
+        // In view space, positive above the water
+        let plane = reflected.view().invert().unwrap().transpose() * Vector4::new(0.0, 0.0, 1.0, -clip_height);
+        // The mirror of a camera below the water is above it, there is nothing to clip
+        if plane.w >= 0.0 {
+            return projection;
+        }
+        let corner = projection.invert().unwrap() * Vector4::new(plane.x.signum() * projection.x.x.signum(),
+                                                                 plane.y.signum() * projection.y.y.signum(), 1.0,
+                                                                 1.0);
+        let near = plane * (1.0 / plane.dot(corner));
+        projection.x.z = near.x;
+        projection.y.z = near.y;
+        projection.z.z = near.z;
+        projection.w.z = near.w;
+
+        projection
+    }
+
+    // Once the frame's fence has been waited on. camera and extent are the main view's, the waves move with the clock.
+    pub fn update(&self, frame: usize, camera: &SceneCamera, extent: vk::Extent2D, clock: &Clock) {
+        let projection = camera.projection(extent);
+        let reflected = self.reflected_camera(camera);
+        let eye = camera.eye();
+        let color = self.surface.color;
+        let uniforms = WaterUniforms {
+            view_proj: projection * camera.view(),
+            reflection_view_proj: self.reflection_projection(&reflected, extent) * reflected.view(),
+            eye_time: [eye.x, eye.y, eye.z, clock.seconds()],
+            depth: [projection.z.z, projection.w.z, self.surface.clarity.max(0.001), self.settings.distortion],
+            color: [color[0], color[1], color[2], 1.0],
+            sun_direction: self.sun.0,
+            sun_color: self.sun.1,
+            waves: self.waves,
+            steepness: self.steepness
+        };
+        unsafe { (self.mapped[frame] as *mut WaterUniforms).write(uniforms) };
+    }
+
+    // Records the reflection pass, before the main render pass. draw records the scene with the pipelines of the main
+    // render pass and the transforms of reflected_camera and reflection_projection, the viewport and scissor are set.
+    pub fn cmd_draw_reflection<F: FnOnce()>(&self, core: &VkCore, command_buffer: vk::CommandBuffer, draw: F) {
+        self.reflection.cmd_draw(core, command_buffer, self.reflection_pass, draw);
+    }
+
+    // After the main render pass and everything else that draws over the swap chain image, which must be in
+    // PRESENT_SRC_KHR and stays there. color_constants are the main pass' push constants.
+    pub fn cmd_draw(&self, core: &VkCore, command_buffer: vk::CommandBuffer, image_index: u32,
+                    present_image: vk::Image, frame: usize, color_constants: &ColorConstants) {
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let layout_barrier = |image, old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier::default()
+                .image(image)
+                .subresource_range(subresource_range)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        };
+        // The depth writes of the main pass are read by the water's fragment shader
+        let depth_barrier = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        let to_copy = [
+            layout_barrier(present_image, vk::ImageLayout::PRESENT_SRC_KHR, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                           vk::AccessFlags::COLOR_ATTACHMENT_WRITE, vk::AccessFlags::TRANSFER_READ),
+            layout_barrier(self.scene_color.image, vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                           vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::empty(),
+                           vk::AccessFlags::TRANSFER_WRITE)
+        ];
+        let from_copy = [
+            layout_barrier(present_image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::PRESENT_SRC_KHR,
+                           vk::AccessFlags::empty(),
+                           vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE),
+            layout_barrier(self.scene_color.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                           vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL, vk::AccessFlags::TRANSFER_WRITE,
+                           vk::AccessFlags::SHADER_READ)
+        ];
+        let layers = vk::ImageSubresourceLayers::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(0)
+            .base_array_layer(0)
+            .layer_count(1);
+        let copy = [vk::ImageCopy::default()
+            .src_subresource(layers)
+            .dst_subresource(layers)
+            .extent(vk::Extent3D {
+                width: self.extent.width,
+                height: self.extent.height,
+                depth: 1
+            })];
+        let render_area = vk::Rect2D::default()
+            .offset(vk::Offset2D::default())
+            .extent(self.extent);
+        let viewports = [vk::Viewport::default()
+            .x(0.0)
+            .y(0.0)
+            .width(self.extent.width as f32)
+            .height(self.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.render_pass)
+            .framebuffer(self.frame_buffers[image_index as usize])
+            .render_area(render_area);
+        let layout = self.pipeline.pipeline_layout;
+        let logical_device = &core.logical_device;
+
+        unsafe {
+            logical_device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT |
+                                                    vk::PipelineStageFlags::LATE_FRAGMENT_TESTS |
+                                                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                vk::PipelineStageFlags::TRANSFER |
+                                                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                vk::DependencyFlags::empty(), &[depth_barrier], &[], &to_copy);
+            logical_device.cmd_copy_image(command_buffer, present_image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                          self.scene_color.image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &copy);
+            logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT |
+                                                    vk::PipelineStageFlags::FRAGMENT_SHADER,
+                                                vk::DependencyFlags::empty(), &[], &[], &from_copy);
+
+            logical_device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS,
+                                             self.pipeline.pipelines[0]);
+            logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            logical_device.cmd_set_scissor(command_buffer, 0, &[render_area]);
+            logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buf], &[0]);
+            self.index_buffer.cmd_bind(core, command_buffer);
+            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                    &[self.sets[frame]], &[]);
+            logical_device.cmd_push_constants(command_buffer, layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                              cast_to_u8_slice(color_constants));
+            logical_device.cmd_draw_indexed(command_buffer, self.index_buffer.index_count(), 1, 0, 0, 0);
+            logical_device.cmd_end_render_pass(command_buffer);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            core.logical_device.destroy_render_pass(self.render_pass, None);
+            core.logical_device.destroy_render_pass(self.reflection_pass, None);
+        }
+        destroy_frame_buffers(core, &self.frame_buffers);
+        self.pipeline.destroy(core);
+        self.reflection.destroy(core);
+        self.scene_color.destroy(core);
+        destroy_sampler(core, self.sampler);
+        destroy_sampler(core, self.depth_sampler);
+        for b in self.uniform_buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+        self.index_buffer.destroy(core);
+        self.vertex_buffer.destroy(core);
+    }
+}
@@ -0,0 +1,302 @@
+use cgmath::Vector3;
+
+use crate::voxel::{chunk_origin, Block, ChunkCoord, VoxelChunk, AIR, CHUNK_SIZE};
+use crate::voxel_streaming::ChunkSource;
+
+// Gradients of perlin, the edges of a cube
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0]
+];
+
+// Seeds of the noises, mixed into WorldGenSettings::seed so that they differ from each other
+const TEMPERATURE_SEED: u32 = 0x68e3_1da4;
+const HUMIDITY_SEED: u32 = 0xb529_7a4d;
+const OVERHANG_SEED: u32 = 0x1b56_c4e9;
+const CAVE_SEED: u32 = 0x7f4a_7c15;
+const RELIEF_SEED: u32 = 0x2545_f491; // Plus the biome's index
+
+// Of a lattice point, the same for the same seed on every run and platform
+fn hash(seed: u32, x: i32, y: i32, z: i32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x8da6_b343) ^ (y as u32).wrapping_mul(0xd816_3841) ^
+        (z as u32).wrapping_mul(0xcb1a_b31f);
+    h = (h ^ (h >> 16)).wrapping_mul(0x85eb_ca6b);
+    h = (h ^ (h >> 13)).wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+// Smoothstep with zero first and second derivatives at 0 and 1, so that lattice cells join without creases
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+// Interpolates the values at the 8 corners of the lattice cell around p, corner i is offset by its bits x, y and z
+fn interpolate_cell(p: [f32; 3], corner: impl Fn([i32; 3], [f32; 3]) -> f32) -> f32 {
+    let cell = p.map(|c| c.floor());
+    let f = [p[0] - cell[0], p[1] - cell[1], p[2] - cell[2]];
+    let values: Vec<f32> = (0..8).map(|i| {
+        let d = [i & 1, (i >> 1) & 1, (i >> 2) & 1];
+        corner([cell[0] as i32 + d[0], cell[1] as i32 + d[1], cell[2] as i32 + d[2]],
+               [f[0] - d[0] as f32, f[1] - d[1] as f32, f[2] - d[2] as f32])
+    }).collect();
+    let t = f.map(fade);
+    let x: Vec<f32> = values.chunks(2).map(|v| lerp(v[0], v[1], t[0])).collect();
+
+    lerp(lerp(x[0], x[1], t[1]), lerp(x[2], x[3], t[1]), t[2])
+}
+
+// Gradient noise in about [-1, 1], 0 at every lattice point. Two dimensional with p[2] at 0.
+pub fn perlin(seed: u32, p: [f32; 3]) -> f32 {
+    interpolate_cell(p, |lattice, offset| {
+        let g = GRADIENTS[(hash(seed, lattice[0], lattice[1], lattice[2]) % 12) as usize];
+        g[0] * offset[0] + g[1] * offset[1] + g[2] * offset[2]
+    })
+}
+
+// Random values in [0, 1) at the lattice points, interpolated. Blobbier than perlin, good for regions like biomes.
+pub fn value_noise(seed: u32, p: [f32; 3]) -> f32 {
+    interpolate_cell(p, |lattice, _| hash(seed, lattice[0], lattice[1], lattice[2]) as f32 / 4_294_967_296.0)
+}
+
+// Octaves of perlin, each at twice the frequency and half the amplitude of the one before
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseLayer {
+    pub frequency: f32, // Of the first octave, per block
+    pub amplitude: f32, // Of the sum, in blocks
+    pub octaves: u32
+}
+
+impl NoiseLayer {
+    // In about [-amplitude, amplitude]
+    pub fn sample(&self, seed: u32, p: [f32; 3]) -> f32 {
+        let (mut sum, mut weights, mut weight, mut frequency) = (0.0, 0.0, 1.0, self.frequency);
+        for octave in 0..self.octaves {
+            sum += weight * perlin(seed.wrapping_add(octave), p.map(|c| c * frequency));
+            weights += weight;
+            weight *= 0.5;
+            frequency *= 2.0;
+        }
+        match weights > 0.0 {
+            true => sum / weights * self.amplitude,
+            false => 0.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Biome {
+    pub climate: [f32; 2], // Temperature and humidity in [0, 1] it is chosen at, the closest biome's wins
+    pub height: f32, // Of the surface, in blocks above z = 0
+    pub relief: NoiseLayer, // Hills, added to height
+    pub surface: Block, // The top block of a column
+    pub filler: Block, // Below the surface block, stone below it
+    pub filler_depth: f32
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorldGenSettings {
+    pub seed: u32,
+    pub biomes: Vec<Biome>,
+    pub climate_frequency: f32, // Per block, biomes are about its inverse in blocks wide
+    // Heights of biomes whose climate is this close to a column's are blended into its height, so that biome borders
+    // are slopes rather than cliffs
+    pub biome_blend: f32,
+    pub stone: Block,
+    pub overhangs: NoiseLayer, // Three dimensional, added to the depth below the surface
+    pub caves: NoiseLayer,
+    pub cave_threshold: f32, // Blocks where caves' noise is within this of 0 are carved out, the noise's 0s are tunnels
+    pub cave_depth: f32 // Caves are carved this many blocks below the surface and deeper, they don't riddle it
+}
+
+impl Default for WorldGenSettings {
+    // For a palette of stone, dirt, grass and sand as blocks 1 to 4
+    fn default() -> WorldGenSettings {
+        let (stone, dirt, grass, sand) = (1, 2, 3, 4);
+        WorldGenSettings {
+            seed: 0,
+            biomes: vec![
+                Biome { // Plains
+                    climate: [0.5, 0.5],
+                    height: 8.0,
+                    relief: NoiseLayer { frequency: 1.0 / 64.0, amplitude: 6.0, octaves: 4 },
+                    surface: grass,
+                    filler: dirt,
+                    filler_depth: 3.0
+                },
+                Biome { // Desert
+                    climate: [0.9, 0.1],
+                    height: 4.0,
+                    relief: NoiseLayer { frequency: 1.0 / 48.0, amplitude: 3.0, octaves: 3 },
+                    surface: sand,
+                    filler: sand,
+                    filler_depth: 4.0
+                },
+                Biome { // Mountains
+                    climate: [0.1, 0.6],
+                    height: 28.0,
+                    relief: NoiseLayer { frequency: 1.0 / 96.0, amplitude: 32.0, octaves: 5 },
+                    surface: stone,
+                    filler: stone,
+                    filler_depth: 0.0
+                }
+            ],
+            climate_frequency: 1.0 / 256.0,
+            biome_blend: 0.25,
+            stone,
+            overhangs: NoiseLayer { frequency: 1.0 / 24.0, amplitude: 4.0, octaves: 2 },
+            caves: NoiseLayer { frequency: 1.0 / 32.0, amplitude: 1.0, octaves: 2 },
+            cave_threshold: 0.08,
+            cave_depth: 6.0
+        }
+    }
+}
+
+// Generates a VoxelWorld's chunks from noise, the same chunks for the same settings regardless of the order they are
+// generated in. A column's height is its biome's height plus relief, the biomes are picked by a temperature and a
+// humidity noise. Below the height, a density with three dimensional noise makes overhangs and carves caves. Meant
+// as the ChunkSource of a ChunkStreamer, whose threads generate chunks while frames are rendered.
+pub struct WorldGen {
+    settings: WorldGenSettings
+}
+
+impl WorldGen {
+    pub fn new(settings: WorldGenSettings) -> Result<WorldGen, String> {
+        if settings.biomes.is_empty() {
+            return Err(String::from("World generation needs a biome"));
+        }
+
+        Ok(WorldGen {
+            settings
+        })
+    }
+
+    pub fn settings(&self) -> &WorldGenSettings {
+        &self.settings
+    }
+
+    fn climate(&self, x: f32, y: f32) -> [f32; 2] {
+        let p = [x * self.settings.climate_frequency, y * self.settings.climate_frequency, 0.0];
+        [value_noise(self.settings.seed ^ TEMPERATURE_SEED, p), value_noise(self.settings.seed ^ HUMIDITY_SEED, p)]
+    }
+
+    fn climate_distance(biome: &Biome, climate: [f32; 2]) -> f32 {
+        let (t, h) = (biome.climate[0] - climate[0], biome.climate[1] - climate[1]);
+        (t * t + h * h).sqrt()
+    }
+
+    // The biome of the column at block x, y, whose surface blocks it gets
+    pub fn biome(&self, x: i32, y: i32) -> &Biome {
+        let climate = self.climate(x as f32, y as f32);
+        self.settings.biomes.iter()
+            .min_by(|a, b| WorldGen::climate_distance(a, climate).total_cmp(&WorldGen::climate_distance(b, climate)))
+            .unwrap()
+    }
+
+    // Of the surface of the column at block x, y, the heights of the biomes around its climate weighted by how close
+    // they are
+    pub fn height(&self, x: i32, y: i32) -> f32 {
+        let climate = self.climate(x as f32, y as f32);
+        let p = [x as f32, y as f32, 0.0];
+        let (mut sum, mut weights) = (0.0, 0.0);
+        for (i, biome) in self.settings.biomes.iter().enumerate() {
+            let d = WorldGen::climate_distance(biome, climate) / self.settings.biome_blend.max(f32::EPSILON);
+            // Never 0, so that a column whose climate is far from every biome's still gets a height
+            let weight = (-d * d).exp().max(f32::MIN_POSITIVE);
+            let relief = biome.relief.sample(self.settings.seed ^ RELIEF_SEED.wrapping_add(i as u32), p);
+            sum += weight * (biome.height + relief);
+            weights += weight;
+        }
+
+        sum / weights
+    }
+
+    // Roughly how deep below the surface the block is, positive blocks are solid
+    fn density(&self, block: Vector3<i32>, height: f32) -> f32 {
+        let p = [block.x as f32 + 0.5, block.y as f32 + 0.5, block.z as f32 + 0.5];
+        height - p[2] + self.settings.overhangs.sample(self.settings.seed ^ OVERHANG_SEED, p)
+    }
+
+    // The block at a world space block position, height is its column's
+    fn block(&self, block: Vector3<i32>, height: f32, biome: &Biome) -> Block {
+        let depth = self.density(block, height);
+        if depth <= 0.0 {
+            return AIR;
+        }
+        let p = [block.x as f32 + 0.5, block.y as f32 + 0.5, block.z as f32 + 0.5];
+        if height - p[2] > self.settings.cave_depth &&
+            self.settings.caves.sample(self.settings.seed ^ CAVE_SEED, p).abs() < self.settings.cave_threshold {
+            return AIR;
+        }
+        match depth {
+            d if d < 1.0 => biome.surface,
+            d if d < 1.0 + biome.filler_depth => biome.filler,
+            _ => self.settings.stone
+        }
+    }
+
+    // None for chunks of air, which are most chunks above the surface
+    pub fn generate(&self, coord: ChunkCoord) -> Option<VoxelChunk> {
+        let size = CHUNK_SIZE as i32;
+        let base = coord * size;
+        let columns: Vec<(f32, &Biome)> = (0..size * size)
+            .map(|i| (base.x + i % size, base.y + i / size))
+            .map(|(x, y)| (self.height(x, y), self.biome(x, y)))
+            .collect();
+        let highest = columns.iter().map(|(h, _)| *h).fold(f32::MIN, f32::max);
+        if chunk_origin(coord).z > highest + self.settings.overhangs.amplitude {
+            return None;
+        }
+
+        let mut chunk = VoxelChunk::default();
+        for z in 0..CHUNK_SIZE {
+            for y in 0..CHUNK_SIZE {
+                for x in 0..CHUNK_SIZE {
+                    let (height, biome) = columns[x + y * CHUNK_SIZE];
+                    let block = self.block(base + Vector3::new(x as i32, y as i32, z as i32), height, biome);
+                    chunk.set(x, y, z, block);
+                }
+            }
+        }
+
+        (!chunk.is_empty()).then_some(chunk)
+    }
+}
+
+impl ChunkSource for WorldGen {
+    fn load(&self, coord: ChunkCoord) -> Option<VoxelChunk> {
+        self.generate(coord)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noise_is_deterministic_and_bounded() {
+        for i in 0..1000 {
+            let p = [i as f32 * 0.37, i as f32 * -0.21, i as f32 * 0.13];
+            assert_eq!(perlin(7, p), perlin(7, p));
+            assert!(perlin(7, p).abs() <= 1.5);
+            assert!((0.0..1.0).contains(&value_noise(7, p)));
+        }
+        // Perlin is 0 on the lattice
+        assert_eq!(perlin(7, [3.0, -2.0, 5.0]), 0.0);
+        assert_ne!(value_noise(1, [0.5, 0.5, 0.5]), value_noise(2, [0.5, 0.5, 0.5]));
+    }
+
+    #[test]
+    fn chunks_follow_the_surface() {
+        let generator = WorldGen::new(WorldGenSettings::default()).unwrap();
+        // Far above the highest biome there is only air, far below only stone and caves
+        assert_eq!(generator.generate(Vector3::new(0, 0, 16)), None);
+        let deep = generator.generate(Vector3::new(0, 0, -8)).unwrap();
+        assert!(deep.blocks().iter().all(|b| *b == AIR || *b == generator.settings().stone));
+        assert_eq!(generator.generate(Vector3::new(3, -2, 0)), generator.generate(Vector3::new(3, -2, 0)));
+    }
+}
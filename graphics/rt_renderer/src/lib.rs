@@ -3,9 +3,17 @@ pub mod rt_pipeline;
 pub mod rt_accel;
 pub mod rt_canvas;
 pub mod rt_descriptor;
+pub mod rt_environment;
+pub mod rt_reflection;
+pub mod rt_path;
+pub mod rt_sampling;
+pub mod rt_light;
+pub mod rt_emissive;
 pub mod rt_ubo;
+pub mod rt_hybrid;
+pub mod rt_object;
+pub mod rt_analysis;
 mod rt_frame;
-mod rt_object;
 mod rt_constants;
 mod rt_types;
 
@@ -1,12 +1,16 @@
+use std::collections::HashSet;
 use std::mem;
-use std::time::Instant;
 use ash::extensions::khr;
 use ash::extensions::khr::AccelerationStructure;
 use ash::vk;
-use cgmath::{Point3, Vector3};
+use cgmath::{Matrix4, Point3, Vector3};
+use renderlib::collision::Aabb;
 use renderlib::gpu_buffer::GpuBuffer;
-use renderlib::single_time::{begin_single_time_commands, end_single_time_commands};
+use renderlib::index::{IndexBuffer, IndexElement};
+use renderlib::single_time::{begin_single_time_commands, end_single_time_commands, SingleTimeContext};
 use renderlib::vkcore::VkCore;
+use renderlib::voxel::{cube_mesh, ChunkCoord, VoxelWorld};
+use tracing::info_span;
 use crate::rt_types::{RtIndex, RtVertex};
 
 // pub const TRIANGLE_FACING_CULL_DISABLE: Self = Self(0b1);
@@ -535,9 +539,11 @@ const OUTWARD_SHELL: [u32; 4096] = [
 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
 ];
 
+#[derive(Clone, Copy, Debug)]
 pub struct RtPerInstanceData {
-    pub offset: Vector3<f32>,
-    pub blas_index: usize
+    pub transform: Matrix4<f32>, // Only the upper 3x4 is used
+    pub blas_index: usize,
+    pub custom_index: u32 // gl_InstanceCustomIndexEXT, selects the BLAS geometry in the ShaderAddressTable
 }
 
 pub struct RtAccel {
@@ -545,11 +551,45 @@ pub struct RtAccel {
     accel_buf: GpuBuffer,
     scratch_buf: GpuBuffer,
     pub acceleration_structure: vk::AccelerationStructureKHR,
+    // Geometry of triangle BLASes, kept for hit shaders that read it through a ShaderAddressTable
+    pub index_buffer: Option<IndexBuffer>,
+    pub vertex_buffer: Option<GpuBuffer>
 }
 
 pub type RtBlas = RtAccel;
 pub type RtTlas = RtAccel;
 
+// BLAS over vertices that change every frame, such as ComputeSkinner::positions. Built once with ALLOW_UPDATE and then
+// refit in place, which keeps the topology and only moves the bounding volumes.
+pub struct RtDynamicBlas {
+    pub blas: RtBlas,
+    index_buffer: IndexBuffer,
+    vertex_address: vk::DeviceAddress,
+    max_vertex: u32,
+    primitive_count: u32
+}
+
+// Updates must use the same flags as the initial build
+const DYNAMIC_BLAS_FLAGS: vk::BuildAccelerationStructureFlagsKHR = vk::BuildAccelerationStructureFlagsKHR::from_raw(
+    vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE.as_raw() |
+        vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE.as_raw());
+
+fn dynamic_geometry(core: &VkCore, index_buffer: &IndexBuffer, vertex_address: vk::DeviceAddress, max_vertex: u32)
+    -> vk::AccelerationStructureGeometryKHR<'static> {
+    let triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::default()
+        .index_type(index_buffer.index_type)
+        .index_data(vk::DeviceOrHostAddressConstKHR { device_address: index_buffer.get_device_address(core) })
+        .max_vertex(max_vertex)
+        .vertex_format(vk::Format::R32G32B32_SFLOAT)
+        .vertex_data(vk::DeviceOrHostAddressConstKHR { device_address: vertex_address })
+        .vertex_stride((mem::size_of::<f32>() * 3) as vk::DeviceSize);
+
+    vk::AccelerationStructureGeometryKHR::default()
+        .flags(vk::GeometryFlagsKHR::OPAQUE)
+        .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+        .geometry(vk::AccelerationStructureGeometryDataKHR { triangles })
+}
+
 impl RtAccel {
     // pub fn new_blas_aabbs(core: &VkCore, acceleration_instance: &AccelerationStructure, command_pool:
     // vk::CommandPool, corners: &[vk::AabbPositionsKHR]) -> RtBlas {
@@ -623,12 +663,35 @@ impl RtAccel {
     //     end_single_time_commands(core, command_pool, command_buffer);
     // }
 
-    pub fn new_blas_triangles<T>(core: &VkCore, acceleration_instance: &AccelerationStructure, command_pool: vk::CommandPool,
-                                 indices: &[T], vertices: &[f32]) -> RtBlas {
-        let index_buffer = GpuBuffer::new_initialized(core, command_pool,
-                                                      vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR |
-                                                          vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS, &indices,
-                                                      vk::MemoryPropertyFlags::DEVICE_LOCAL);
+    pub fn new_blas_triangles<T: IndexElement>(core: &VkCore, acceleration_instance: &AccelerationStructure,
+                                               command_pool: vk::CommandPool, indices: &[T], vertices: &[f32])
+        -> RtBlas {
+        let mut context = SingleTimeContext::new(command_pool);
+        let blas = RtAccel::record_blas_triangles(core, acceleration_instance, &mut context, indices, vertices);
+        info_span!("blas_build", triangles = indices.len() / 3).in_scope(|| context.flush(core));
+        context.destroy(core);
+
+        blas
+    }
+
+    // Like new_blas_triangles, the build is only recorded into context. The index and vertex buffers are uploaded
+    // right away.
+    pub fn record_blas_triangles<T: IndexElement>(core: &VkCore, acceleration_instance: &AccelerationStructure,
+                                                  context: &mut SingleTimeContext, indices: &[T], vertices: &[f32])
+        -> RtBlas {
+        let command_pool = context.command_pool();
+        let index_usage = vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR |
+            vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        // Acceleration structure builds only accept 16 and 32 bit indices, even with VK_EXT_index_type_uint8
+        let index_buffer = match T::INDEX_TYPE {
+            vk::IndexType::UINT8_EXT => {
+                let widened: Vec<u16> = indices.iter().map(|&i| i.to_u32() as u16).collect();
+                IndexBuffer::new_typed(core, command_pool, index_usage, widened.as_slice(),
+                                       vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            },
+            _ => IndexBuffer::new_typed(core, command_pool, index_usage, indices,
+                                        vk::MemoryPropertyFlags::DEVICE_LOCAL)
+        };
         let index_dev_addr = vk::DeviceOrHostAddressConstKHR {
             device_address: index_buffer.get_device_address(core)
         };
@@ -642,14 +705,8 @@ impl RtAccel {
         };
 
         assert_eq!(vertices.len() % 3, 0);
-        let index_type = match mem::size_of::<T>() {
-            1 => { vk::IndexType::UINT8_EXT },
-            2 => { vk::IndexType::UINT16 },
-            4 => { vk::IndexType::UINT32 },
-            _ => { panic!("Invalid index type") }
-        };
         let geometry_data_triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::default()
-            .index_type(index_type)
+            .index_type(index_buffer.index_type)
             .index_data(index_dev_addr)
             .max_vertex((vertices.len() / 3 - 1) as u32)
             .vertex_format(vk::Format::R32G32B32_SFLOAT)
@@ -683,9 +740,7 @@ impl RtAccel {
                                                        // STORAGE_BUFFER property is here, but the Nvidia tutorial
                                                        // uses it
 
-        let addr_info = vk::BufferDeviceAddressInfo::default()
-            .buffer(scratch_buf.buf);
-        let scratch_ptr = unsafe { core.logical_device.get_buffer_device_address(&addr_info) };
+        let scratch_ptr = scratch_buf.get_device_address(core);
 
         blas_build_info = blas_build_info.scratch_data(vk::DeviceOrHostAddressKHR { device_address: scratch_ptr });
 
@@ -713,30 +768,37 @@ impl RtAccel {
             build_range_info_l1.as_slice()
         ];
 
-        let start_time = Instant::now();
-        let command_buffer = begin_single_time_commands(core, command_pool);
+        let command_buffer = context.command_buffer(core);
         unsafe {
             acceleration_instance.cmd_build_acceleration_structures(command_buffer, &[blas_build_info],
                                                                     build_range_info.as_slice())
         }
-        end_single_time_commands(core, command_pool, command_buffer);
-        let end_time = Instant::now();
-        let build_time = end_time.duration_since(start_time).as_nanos();
-        println!("BLAS build time: {build_time}");
-
-        index_buffer.destroy(core);
-        vertex_buffer.destroy(core);
 
         RtBlas {
             scratch_size,
             accel_buf,
             scratch_buf,
             acceleration_structure,
+            index_buffer: Some(index_buffer),
+            vertex_buffer: Some(vertex_buffer)
         }
     }
 
     pub fn new_tlas(core: &VkCore, acceleration_instance: &AccelerationStructure, command_pool: vk::CommandPool,
                     blas: &[&RtBlas], per_blas_data: &[RtPerInstanceData]) -> RtTlas {
+        let mut context = SingleTimeContext::new(command_pool);
+        let tlas = RtAccel::record_tlas(core, acceleration_instance, &mut context, blas, per_blas_data);
+        info_span!("tlas_build", instances = per_blas_data.len()).in_scope(|| context.flush(core));
+        context.destroy(core);
+
+        tlas
+    }
+
+    // Like new_tlas, the build is only recorded into context. It waits for the builds recorded before it, so the
+    // BLASes can be built in the same submission.
+    pub fn record_tlas(core: &VkCore, acceleration_instance: &AccelerationStructure, context: &mut SingleTimeContext,
+                       blas: &[&RtBlas], per_blas_data: &[RtPerInstanceData]) -> RtTlas {
+        let command_pool = context.command_pool();
         // TODO Use a compute shader to construct BLAS instance arrays with different transforms
         let mut instance_vec: Vec<vk::AccelerationStructureInstanceKHR> = Vec::with_capacity(per_blas_data.len());
         for d in per_blas_data.iter() { // Iterate through each instance
@@ -746,10 +808,12 @@ impl RtAccel {
             let blas_ref = vk::AccelerationStructureReferenceKHR {
                 device_handle: blas_addr
             };
-            let index_and_mask = vk::Packed24_8::new(0, 0xFF); // No index data for now, assert all cull mask bits
+            // Assert all cull mask bits
+            let index_and_mask = vk::Packed24_8::new(d.custom_index, 0xFF);
             let offset_and_flags = vk::Packed24_8::new(0, MANUAL_CULL_DISABLE);
-            let transform_data = vk::TransformMatrixKHR { // Identity, no translation and no transform
-                matrix: [1.0, 0.0, 0.0, d.offset.x, 0.0, 1.0, 0.0, d.offset.y, 0.0, 0.0, 1.0, d.offset.z]
+            let m = d.transform;
+            let transform_data = vk::TransformMatrixKHR { // Row major, cgmath matrices are column major
+                matrix: [m.x.x, m.y.x, m.z.x, m.w.x, m.x.y, m.y.y, m.z.y, m.w.y, m.x.z, m.y.z, m.z.z, m.w.z]
             };
 
             instance_vec.push(vk::AccelerationStructureInstanceKHR {
@@ -768,10 +832,9 @@ impl RtAccel {
             //     }
             // ];
 
-        let instance_buf = GpuBuffer::new_initialized(core, command_pool,
-                                                               vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
-                                                                   | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS, instance_vec.as_slice(),
-                                                               vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let instance_usage = vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR |
+            vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+        let instance_buf = GpuBuffer::new_uploaded(core, command_pool, instance_usage, instance_vec.as_slice());
 
 
         let instance_addr = vk::DeviceOrHostAddressConstKHR {
@@ -804,9 +867,7 @@ impl RtAccel {
                                          vk::MemoryPropertyFlags::DEVICE_LOCAL); // Not sure why
                                                        // STORAGE_BUFFER property is here, but the Nvidia tutorial
                                                        // uses it
-        let addr_info = vk::BufferDeviceAddressInfo::default()
-            .buffer(scratch_buf.buf);
-        let scratch_ptr = unsafe { core.logical_device.get_buffer_device_address(&addr_info) };
+        let scratch_ptr = scratch_buf.get_device_address(core);
         tlas_build_info = tlas_build_info.scratch_data(vk::DeviceOrHostAddressKHR { device_address: scratch_ptr });
 
         let tlas_buf = GpuBuffer::new(core, tlas_build_size.acceleration_structure_size,
@@ -833,23 +894,27 @@ impl RtAccel {
         let build_range_info = [
             build_range_info_l1.as_slice()
         ];
-        let start_time = Instant::now();
-        let command_buffer = begin_single_time_commands(core, command_pool);
+        let command_buffer = context.command_buffer(core);
+        let barrier = [vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+            .dst_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR)];
         unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer,
+                                                     vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                                                     vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                                                     vk::DependencyFlags::empty(), &barrier, &[], &[]);
             acceleration_instance.cmd_build_acceleration_structures(command_buffer, &[tlas_build_info],
                                                                     build_range_info.as_slice());
         }
-        end_single_time_commands(core, command_pool, command_buffer);
-        let end_time = Instant::now();
-        let build_time = end_time.duration_since(start_time).as_nanos();
-        println!("TLAS build time: {build_time}");
-        instance_buf.destroy(core);
+        context.retire(instance_buf);
 
         RtTlas {
             scratch_size: tlas_scratch_size,
             accel_buf: tlas_buf,
             scratch_buf,
             acceleration_structure: tlas,
+            index_buffer: None,
+            vertex_buffer: None
         }
     }
 
@@ -857,11 +922,172 @@ impl RtAccel {
         unsafe { acceleration_instance.destroy_acceleration_structure(self.acceleration_structure, None) }
         self.accel_buf.destroy(core);
         self.scratch_buf.destroy(core);
+        if let Some(index_buffer) = &self.index_buffer {
+            index_buffer.destroy(core);
+        }
+        if let Some(vertex_buffer) = &self.vertex_buffer {
+            vertex_buffer.destroy(core);
+        }
+    }
+}
+
+impl RtDynamicBlas {
+    // positions holds packed vec3s and must outlive the BLAS, it is read again on every refit
+    pub fn new(core: &VkCore, acceleration_instance: &AccelerationStructure, command_pool: vk::CommandPool,
+               indices: &[u32], positions: &GpuBuffer, vertex_count: u32) -> RtDynamicBlas {
+        let index_buffer = IndexBuffer::new_typed(core, command_pool,
+                                                  vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR |
+                                                      vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                                                  indices, vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let vertex_address = positions.get_device_address(core);
+        let max_vertex = vertex_count - 1;
+        let primitive_count = (indices.len() / 3) as u32;
+
+        let geometry = [dynamic_geometry(core, &index_buffer, vertex_address, max_vertex)];
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .flags(DYNAMIC_BLAS_FLAGS)
+            .geometries(&geometry)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL);
+        let build_size = unsafe {
+            acceleration_instance.get_acceleration_structure_build_sizes(vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                                                                         &build_info, &[primitive_count])
+        };
+
+        // Shared by the initial build and every refit
+        let scratch_size = build_size.build_scratch_size.max(build_size.update_scratch_size);
+        let scratch_buf = GpuBuffer::new(core, scratch_size,
+                                         vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS |
+                                             vk::BufferUsageFlags::STORAGE_BUFFER,
+                                         vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let accel_buf = GpuBuffer::new(core, build_size.acceleration_structure_size,
+                                       vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR |
+                                           vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                                       vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let blas_create_info = vk::AccelerationStructureCreateInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .buffer(accel_buf.buf)
+            .offset(0)
+            .size(build_size.acceleration_structure_size);
+        let acceleration_structure = unsafe {
+            acceleration_instance.create_acceleration_structure(&blas_create_info, None).unwrap()
+        };
+
+        let dynamic_blas = RtDynamicBlas {
+            blas: RtAccel {
+                scratch_size,
+                accel_buf,
+                scratch_buf,
+                acceleration_structure,
+                index_buffer: None, // Owned by RtDynamicBlas
+                vertex_buffer: None
+            },
+            index_buffer,
+            vertex_address,
+            max_vertex,
+            primitive_count
+        };
+        info_span!("dynamic_blas_build", triangles = primitive_count).in_scope(|| {
+            let command_buffer = begin_single_time_commands(core, command_pool);
+            dynamic_blas.cmd_build(core, acceleration_instance, command_buffer,
+                                   vk::BuildAccelerationStructureModeKHR::BUILD);
+            end_single_time_commands(core, command_pool, command_buffer);
+        });
+
+        dynamic_blas
+    }
+
+    fn cmd_build(&self, core: &VkCore, acceleration_instance: &AccelerationStructure, command_buffer: vk::CommandBuffer,
+                 mode: vk::BuildAccelerationStructureModeKHR) {
+        let geometry = [dynamic_geometry(core, &self.index_buffer, self.vertex_address, self.max_vertex)];
+        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .flags(DYNAMIC_BLAS_FLAGS)
+            .geometries(&geometry)
+            .mode(mode)
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .dst_acceleration_structure(self.blas.acceleration_structure)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: self.blas.scratch_buf.get_device_address(core)
+            });
+        if mode == vk::BuildAccelerationStructureModeKHR::UPDATE {
+            build_info = build_info.src_acceleration_structure(self.blas.acceleration_structure);
+        }
+        let build_range_info = [
+            vk::AccelerationStructureBuildRangeInfoKHR::default()
+                .first_vertex(0)
+                .primitive_count(self.primitive_count)
+                .primitive_offset(0)
+                .transform_offset(0)
+        ];
+
+        unsafe {
+            acceleration_instance.cmd_build_acceleration_structures(command_buffer, &[build_info],
+                                                                    &[build_range_info.as_slice()])
+        }
+    }
+
+    // Record after ComputeSkinner::cmd_skin in the same command buffer. The TLAS referencing this BLAS has to be
+    // rebuilt or refit afterwards for traces to see the new positions.
+    pub fn cmd_refit(&self, core: &VkCore, acceleration_instance: &AccelerationStructure,
+                     command_buffer: vk::CommandBuffer) {
+        self.cmd_build(core, acceleration_instance, command_buffer, vk::BuildAccelerationStructureModeKHR::UPDATE);
+
+        let barrier = [vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR)
+            .dst_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR)];
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer,
+                                                     vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+                                                     vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR |
+                                                         vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+                                                     vk::DependencyFlags::empty(), &barrier, &[], &[]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore, acceleration_instance: &AccelerationStructure) {
+        self.blas.destroy(core, acceleration_instance);
+        self.index_buffer.destroy(core);
+    }
+}
+
+// Of the voxel grid's BLAS in object space, a chunk of 16 by 16 by 16 blocks. grid_instances places copies of it.
+pub fn grid_chunk_bounds() -> Aabb {
+    Aabb {
+        min: Point3::new(0.0, 0.0, 0.0),
+        max: Point3::new(16.0, 16.0, 16.0)
     }
 }
 
+// Copies of the voxel grid's BLAS, which comes first in the TLAS' BLAS list and the ShaderAddressTable
+pub fn grid_instances() -> Vec<RtPerInstanceData> {
+    (0..8000)
+        .map(|n| RtPerInstanceData {
+            transform: Matrix4::from_translation(Vector3::new(((n % 8) * 34) as f32, ((n / 8) * 34) as f32, 0.0)),
+            blas_index: 0,
+            custom_index: 0
+        })
+        .collect()
+}
+
+// Copies of the unit cube BLAS, one per block of the world with a face towards air. The palette's meshes aren't
+// traced, every block is a cube. With visible, only the blocks of those chunks, see visible_chunks.
+pub fn voxel_instances(world: &VoxelWorld, visible: Option<&HashSet<ChunkCoord>>, blas_index: usize,
+                       custom_index: u32) -> Vec<RtPerInstanceData> {
+    world.chunks()
+        .filter(|(coord, _)| visible.is_none_or(|v| v.contains(*coord)))
+        .flat_map(|(coord, chunk)| chunk.instances(*coord, world.palette.len()).instances)
+        .map(|block| RtPerInstanceData {
+            transform: Matrix4::from_translation(Vector3::new(block.position[0], block.position[1],
+                                                              block.position[2])),
+            blas_index,
+            custom_index
+        })
+        .collect()
+}
+
+// Also returns the BLAS of a unit cube, which voxel_instances places once per block
 pub fn create_acceleration_structures(core: &VkCore, command_pool: vk::CommandPool, max_frames: usize)
-    -> (AccelerationStructure, Vec<RtTlas>, RtBlas) {
+    -> (AccelerationStructure, Vec<RtTlas>, RtBlas, RtBlas) {
     // Clockwise, top to bottom, back to front
     // 0    1 - back    4   5
     // 2    3           6   7
@@ -971,32 +1197,26 @@ pub fn create_acceleration_structures(core: &VkCore, command_pool: vk::CommandPo
             indices.push(corners[6]); // +x face, -x side
             indices.push(corners[7]);
             indices.push(corners[5]);
-
-            // println!("cube {n}");
-            // for ii in corners.iter() {
-            //     let v1 = vertices[(*ii * 3) as usize];
-            //     let v2 = vertices[((*ii * 3) + 1) as usize];
-            //     let v3 = vertices[((*ii * 3) + 2) as usize];
-            //     print!("({v1}, {v2}, {v3}) ");
-            // }
-            // println!();
         }
     }
 
-    let blas = RtAccel::new_blas_triangles(core, &acceleration_instance, command_pool, &indices,
-                                           &vertices);
-    let mut instances: Vec<RtPerInstanceData> = Vec::new();
-    for n in 0..8000 {
-        instances.push(RtPerInstanceData {
-            blas_index: 0,
-            offset: Vector3::new(((n % 8) * 34) as f32, ((n / 8) * 34) as f32, 0.0)
-        });
-    }
+    // The BLASes and the TLASes are built in one submission
+    let mut context = SingleTimeContext::new(command_pool);
+    let blas = RtAccel::record_blas_triangles(core, &acceleration_instance, &mut context, &indices,
+                                              &vertices);
+    let cube = cube_mesh([0.0, 0.0], [1.0, 1.0]);
+    let cube_indices: Vec<RtIndex> = cube.indices.iter().map(|&i| i as RtIndex).collect();
+    let cube_vertices: Vec<RtVertex> = cube.vertices.iter().flat_map(|v| v.pos).collect();
+    let cube_blas = RtAccel::record_blas_triangles(core, &acceleration_instance, &mut context, &cube_indices,
+                                                   &cube_vertices);
+    let instances = grid_instances();
     let tlas: Vec<RtTlas> = Vec::from(
         [
-            RtAccel::new_tlas(core, &acceleration_instance, command_pool, &[&blas], instances.as_slice()),
-            RtAccel::new_tlas(core, &acceleration_instance, command_pool, &[&blas], instances.as_slice())
+            RtAccel::record_tlas(core, &acceleration_instance, &mut context, &[&blas], instances.as_slice()),
+            RtAccel::record_tlas(core, &acceleration_instance, &mut context, &[&blas], instances.as_slice())
         ]);
+    info_span!("acceleration_structures_build", triangles = indices.len() / 3).in_scope(|| context.flush(core));
+    context.destroy(core);
 
-    (acceleration_instance, tlas, blas)
+    (acceleration_instance, tlas, blas, cube_blas)
 }
\ No newline at end of file
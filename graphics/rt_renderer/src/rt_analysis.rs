@@ -0,0 +1,268 @@
+use std::mem;
+
+use ash::vk;
+use renderlib::color_pipeline::{ColorPipeline, LINEAR_INTERMEDIATE_FORMAT};
+use renderlib::compute::{ComputePipeline, group_count};
+use renderlib::exposure::{HISTOGRAM_BINS, LOG_LUMINANCE_RANGE, MIN_LOG_LUMINANCE};
+use renderlib::gpu_buffer::GpuBuffer;
+use renderlib::renderutils::cast_to_u8_slice;
+use renderlib::vkcore::VkCore;
+use tracing::warn;
+use winit::event::VirtualKeyCode;
+use crate::rt_canvas::RtCanvas;
+
+// Counts into the histogram buffer like for AutoExposure, the overlay shader draws it
+const HISTOGRAM_SHADER_PATH: &str = "graphics/shaders/spv/luminance_histogram.spv";
+const OVERLAY_SHADER_PATH: &str = "graphics/shaders/spv/rt_analysis.spv";
+const ANALYSIS_GROUP_SIZE: u32 = 8; // Matches local_size_x/y in both shaders
+pub const HISTOGRAM_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::H;
+pub const VARIANCE_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::V;
+
+// Debug overlays over the ray traced image, for judging how far path tracing converged and for spotting fireflies.
+// Post-processing is skipped while one is shown, so that the overlays show the traced pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnalysisSettings {
+    pub histogram: bool, // Of the frame's log2 scene luminance, in the bottom left corner
+    pub variance: bool, // Heatmap of each pixel's relative standard error, replaces the image while path tracing
+    pub max_error: f32 // Relative standard error at the hot end of the heatmap
+}
+
+impl Default for AnalysisSettings {
+    fn default() -> AnalysisSettings {
+        AnalysisSettings {
+            histogram: false,
+            variance: false,
+            max_error: 0.1
+        }
+    }
+}
+
+impl AnalysisSettings {
+    pub fn validated(&self) -> AnalysisSettings {
+        AnalysisSettings {
+            max_error: self.max_error.max(0.001),
+            ..*self
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.histogram || self.variance
+    }
+
+    // Applies HISTOGRAM_TOGGLE_KEY and VARIANCE_TOGGLE_KEY, returns false for other keys
+    pub fn handle_key(&mut self, key: VirtualKeyCode) -> bool {
+        match key {
+            HISTOGRAM_TOGGLE_KEY => self.histogram = !self.histogram,
+            VARIANCE_TOGGLE_KEY => self.variance = !self.variance,
+            _ => return false
+        }
+        true
+    }
+
+    // For the stats overlay
+    pub fn status(&self) -> String {
+        match (self.histogram, self.variance) {
+            (false, false) => String::from("no overlays"),
+            (true, false) => String::from("histogram"),
+            (false, true) => format!("variance up to {}%", self.max_error * 100.0),
+            (true, true) => format!("histogram, variance up to {}%", self.max_error * 100.0)
+        }
+    }
+}
+
+// Matches the push constants in rt_analysis.comp, luminance_histogram.comp only reads range
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct AnalysisConstants {
+    range: [f32; 4], // Min log2 luminance, log2 luminance range, exposure and gamma of the frame
+    params: [f32; 4] // Histogram and variance enabled, accumulated frames and max_error
+}
+
+// The analysis passes of the rt renderer. After the rays were traced, cmd_apply counts the canvas' luminance into a
+// histogram and draws the enabled overlays into the canvas. The variance comes from the second moment the ray
+// generation shader keeps in the alpha of the accumulation image.
+pub struct RtAnalysis {
+    settings: AnalysisSettings,
+    histogram_supported: bool, // luminance_histogram.comp reads the canvas as rgba16f
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>, // One per frame in flight, like the canvas images
+    histograms: Vec<GpuBuffer>,
+    histogram: ComputePipeline,
+    overlay: ComputePipeline
+}
+
+fn create_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    let types = [
+        vk::DescriptorType::STORAGE_IMAGE, // The frame's canvas image
+        vk::DescriptorType::STORAGE_BUFFER, // Histogram
+        vk::DescriptorType::STORAGE_IMAGE // Accumulation
+    ];
+    let bindings: Vec<vk::DescriptorSetLayoutBinding> = types.iter().enumerate()
+        .map(|(b, &ty)| vk::DescriptorSetLayoutBinding::default()
+            .binding(b as u32)
+            .descriptor_type(ty)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE))
+        .collect();
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(bindings.as_slice());
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+impl RtAnalysis {
+    pub fn new(core: &VkCore, canvas: &RtCanvas, settings: AnalysisSettings, max_frames: usize) -> RtAnalysis {
+        let set_layout = create_set_layout(core);
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(2 * max_frames as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(max_frames as u32)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+        let histogram_size = (HISTOGRAM_BINS * mem::size_of::<u32>()) as vk::DeviceSize;
+        let push_constant_size = mem::size_of::<AnalysisConstants>() as u32;
+        let histogram_supported = canvas.format.storage == LINEAR_INTERMEDIATE_FORMAT;
+        if !histogram_supported {
+            warn!(format = ?canvas.format.storage, "The histogram overlay is unavailable with this canvas format");
+        }
+
+        let analysis = RtAnalysis {
+            settings: settings.validated(),
+            histogram_supported,
+            set_layout,
+            descriptor_pool,
+            sets,
+            histograms: (0..max_frames)
+                .map(|_| GpuBuffer::new(core, histogram_size,
+                                        vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+                                        vk::MemoryPropertyFlags::DEVICE_LOCAL))
+                .collect(),
+            histogram: ComputePipeline::new(core, HISTOGRAM_SHADER_PATH, &[set_layout], push_constant_size),
+            overlay: ComputePipeline::new(core, OVERLAY_SHADER_PATH, &[set_layout], push_constant_size)
+        };
+        analysis.write_descriptor_sets(core, canvas);
+
+        analysis
+    }
+
+    // Again after the canvas was recreated, while no frame uses the sets
+    pub fn write_descriptor_sets(&self, core: &VkCore, canvas: &RtCanvas) {
+        let accumulation_info = [vk::DescriptorImageInfo::default()
+            .image_view(canvas.accumulation_view)
+            .image_layout(vk::ImageLayout::GENERAL)];
+        for (frame, &set) in self.sets.iter().enumerate() {
+            let canvas_info = [vk::DescriptorImageInfo::default()
+                .image_view(canvas.views[frame])
+                .image_layout(vk::ImageLayout::GENERAL)];
+            let histogram_info = [vk::DescriptorBufferInfo::default()
+                .buffer(self.histograms[frame].buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let writes = [
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&canvas_info),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(&histogram_info),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(2)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&accumulation_info)
+            ];
+            unsafe { core.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+    }
+
+    // Applied from the next recorded frame
+    pub fn set_settings(&mut self, settings: AnalysisSettings) {
+        self.settings = settings.validated();
+    }
+
+    pub fn settings(&self) -> AnalysisSettings {
+        self.settings
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.settings.is_active()
+    }
+
+    // After the rays were traced into the frame's canvas image, which stays in GENERAL. color is the frame's, with the
+    // adapted exposure. accumulated_frames are the frames in the accumulation image including this one, 0 while not
+    // path tracing, which leaves out the variance. Nothing is recorded without overlays.
+    pub fn cmd_apply(&self, core: &VkCore, command_buffer: vk::CommandBuffer, frame: usize, extent: vk::Extent2D,
+                     color: &ColorPipeline, accumulated_frames: u32) {
+        if !self.is_active() {
+            return;
+        }
+        let histogram = self.settings.histogram && self.histogram_supported;
+        let constants = [AnalysisConstants {
+            range: [MIN_LOG_LUMINANCE, LOG_LUMINANCE_RANGE, color.exposure, color.gamma],
+            params: [histogram as u32 as f32, self.settings.variance as u32 as f32,
+                accumulated_frames as f32, self.settings.max_error]
+        }];
+        // The canvas and the accumulation image, and the histogram once it was cleared
+        let traced = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE | vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let counted = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
+        let (group_x, group_y) = (group_count(extent.width, ANALYSIS_GROUP_SIZE),
+                                  group_count(extent.height, ANALYSIS_GROUP_SIZE));
+        let set = self.sets[frame];
+        let logical_device = &core.logical_device;
+        // The overlay draws the histogram counted before it
+        let pipelines = match histogram {
+            true => vec![&self.histogram, &self.overlay],
+            false => vec![&self.overlay]
+        };
+
+        unsafe {
+            logical_device.cmd_fill_buffer(command_buffer, self.histograms[frame].buf, 0, vk::WHOLE_SIZE, 0);
+            logical_device.cmd_pipeline_barrier(command_buffer,
+                                                vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR |
+                                                    vk::PipelineStageFlags::TRANSFER,
+                                                vk::PipelineStageFlags::COMPUTE_SHADER, vk::DependencyFlags::empty(),
+                                                &[traced], &[], &[]);
+            for pipeline in pipelines {
+                pipeline.cmd_bind(core, command_buffer, set);
+                logical_device.cmd_push_constants(command_buffer, pipeline.layout, vk::ShaderStageFlags::COMPUTE, 0,
+                                                  cast_to_u8_slice(&constants));
+                logical_device.cmd_dispatch(command_buffer, group_x, group_y, 1);
+                logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                    vk::DependencyFlags::empty(), &[counted], &[], &[]);
+            }
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.histogram.destroy(core);
+        self.overlay.destroy(core);
+        for b in self.histograms.iter() {
+            b.destroy(core);
+        }
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
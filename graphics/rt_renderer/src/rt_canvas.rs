@@ -1,34 +1,119 @@
 use ash::vk;
-use renderlib::image::{create_image, create_image_view};
-use renderlib::render_target::RenderTarget;
+use renderlib::color_pipeline::{is_srgb_format, LINEAR_INTERMEDIATE_FORMAT};
+use renderlib::image::{create_image, create_image_view, create_mutable_image};
 use renderlib::vkcore::VkCore;
 
+// Canvas image formats best first, with the format of the views the shaders write through. Floats hold linear color
+// without banding, the UNORM formats band in the dark. The SRGB image is written through a UNORM view, so the shaders
+// store encoded values and the blit decodes them again, instead of 8 bits of linear color. It only works for SRGB
+// swap chains, the others are blitted to as is and get encoded values from any format.
+const CANVAS_FORMATS: [(vk::Format, vk::Format); 5] = [
+    (LINEAR_INTERMEDIATE_FORMAT, LINEAR_INTERMEDIATE_FORMAT),
+    (vk::Format::R32G32B32A32_SFLOAT, vk::Format::R32G32B32A32_SFLOAT),
+    (vk::Format::A2B10G10R10_UNORM_PACK32, vk::Format::A2B10G10R10_UNORM_PACK32),
+    (vk::Format::R8G8B8A8_SRGB, vk::Format::R8G8B8A8_UNORM),
+    (vk::Format::R8G8B8A8_UNORM, vk::Format::R8G8B8A8_UNORM)
+];
+// Full precision first, so that averaging many frames doesn't band
+const ACCUMULATION_FORMATS: [vk::Format; 2] = [vk::Format::R32G32B32A32_SFLOAT, vk::Format::R16G16B16A16_SFLOAT];
+
+fn optimal_features(core: &VkCore, format: vk::Format) -> vk::FormatFeatureFlags {
+    unsafe { core.instance.get_physical_device_format_properties(core.physical_device, format) }
+        .optimal_tiling_features
+}
+
+// The formats of the canvas' images. The shaders declare them without a format qualifier, so any of them can be
+// bound.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CanvasFormat {
+    pub image: vk::Format,
+    pub storage: vk::Format, // Of the views the shaders write, the image's unless it is SRGB
+    pub accumulation: vk::Format
+}
+
+impl CanvasFormat {
+    // The first candidates the device can store to and blit from, linearly filtered for scaled internal resolutions
+    pub fn select(core: &VkCore, swapchain_format: vk::Format) -> Result<CanvasFormat, String> {
+        let features = unsafe { core.instance.get_physical_device_features(core.physical_device) };
+        if features.shader_storage_image_read_without_format != vk::TRUE ||
+            features.shader_storage_image_write_without_format != vk::TRUE {
+            return Err(String::from("The canvas needs shaderStorageImageReadWithoutFormat and \
+                                     shaderStorageImageWriteWithoutFormat"));
+        }
+        let blit_features = vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR;
+        let (image, storage) = CANVAS_FORMATS.into_iter()
+            .filter(|(image, storage)| image == storage || is_srgb_format(swapchain_format))
+            .find(|&(image, storage)| optimal_features(core, image).contains(blit_features) &&
+                optimal_features(core, storage).contains(vk::FormatFeatureFlags::STORAGE_IMAGE))
+            .ok_or(String::from("No canvas format supports storage and blits"))?;
+        let accumulation = ACCUMULATION_FORMATS.into_iter()
+            .find(|&f| optimal_features(core, f).contains(vk::FormatFeatureFlags::STORAGE_IMAGE))
+            .ok_or(String::from("No accumulation format supports storage"))?;
+
+        Ok(CanvasFormat {
+            image,
+            storage,
+            accumulation
+        })
+    }
+
+    // The format to pass ColorPipeline::constants for the shaders to write what the blit to swapchain_format expects
+    pub fn output_format(&self, swapchain_format: vk::Format) -> vk::Format {
+        match self.image == self.storage {
+            true => swapchain_format,
+            false => self.storage // Encoded, the blit decodes the image
+        }
+    }
+}
+
+// Storage images the ray generation shader writes to, blitted to the swap chain each frame. The blit performs the
+// sRGB encode when the swap chain format is SRGB, see CanvasFormat for what they hold. Their extent is the internal
+// resolution, which is the swap chain's unless the renderer scales to the window, see InternalResolution.
 pub struct RtCanvas {
+    pub format: CanvasFormat,
     pub images: Vec<vk::Image>,
-    pub views: Vec<vk::ImageView>,
-    mem: Vec<vk::DeviceMemory>
+    pub views: Vec<vk::ImageView>, // Of format.storage
+    mem: Vec<vk::DeviceMemory>,
+    // Running average of the path traced frames. Shared by all frames in flight, each frame reads what the previous
+    // one wrote, so its contents are lost whenever the canvas is recreated.
+    pub accumulation: vk::Image,
+    pub accumulation_view: vk::ImageView,
+    accumulation_mem: vk::DeviceMemory
 }
 
 impl RtCanvas {
-    pub fn new(core: &VkCore, render_target: &RenderTarget,  max_frames: usize) -> RtCanvas {
+    pub fn new(core: &VkCore, extent: vk::Extent2D, format: CanvasFormat, max_frames: usize) -> RtCanvas {
         let mut images: Vec<vk::Image> = Vec::new();
         let mut mem: Vec<vk::DeviceMemory> = Vec::new();
         let mut views: Vec<vk::ImageView> = Vec::new();
+        let usage = vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC;
         for _ in 0..max_frames {
-            let (i, m) = create_image(core, render_target.extent.width, render_target
-                .extent.height, 1, render_target.surface_format, vk::ImageTiling::OPTIMAL,
-                                      vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
-                                      vk::MemoryPropertyFlags::DEVICE_LOCAL, vk::SampleCountFlags::TYPE_1);
-            let v = create_image_view(core, i, render_target.surface_format, vk::ImageAspectFlags::COLOR, 1);
+            let (i, m) = match format.image == format.storage {
+                true => create_image(core, extent.width, extent.height, 1, format.image, vk::ImageTiling::OPTIMAL,
+                                     usage, vk::MemoryPropertyFlags::DEVICE_LOCAL, vk::SampleCountFlags::TYPE_1),
+                false => create_mutable_image(core, extent.width, extent.height, format.image,
+                                              vk::ImageTiling::OPTIMAL, usage, vk::MemoryPropertyFlags::DEVICE_LOCAL)
+            };
+            let v = create_image_view(core, i, format.storage, vk::ImageAspectFlags::COLOR, 1);
             images.push(i);
             mem.push(m);
             views.push(v);
         }
+        let (accumulation, accumulation_mem) = create_image(core, extent.width, extent.height, 1, format.accumulation,
+                                                            vk::ImageTiling::OPTIMAL, vk::ImageUsageFlags::STORAGE,
+                                                            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                            vk::SampleCountFlags::TYPE_1);
+        let accumulation_view = create_image_view(core, accumulation, format.accumulation,
+                                                  vk::ImageAspectFlags::COLOR, 1);
 
         RtCanvas {
+            format,
             images,
             views,
-            mem
+            mem,
+            accumulation,
+            accumulation_view,
+            accumulation_mem
         }
     }
 
@@ -40,5 +125,10 @@ impl RtCanvas {
                 core.logical_device.free_memory(m, None);
             }
         }
+        unsafe {
+            core.logical_device.destroy_image_view(self.accumulation_view, None);
+            core.logical_device.destroy_image(self.accumulation, None);
+            core.logical_device.free_memory(self.accumulation_mem, None);
+        }
     }
-}
\ No newline at end of file
+}
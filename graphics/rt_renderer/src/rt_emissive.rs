@@ -0,0 +1,119 @@
+use ash::vk;
+use cgmath::{InnerSpace, Point3, Transform, Vector3, Vector4};
+use renderlib::gpu_buffer::GpuBuffer;
+use renderlib::model::load_model;
+use renderlib::scene::Scene;
+use renderlib::vkcore::VkCore;
+use tracing::info;
+
+// Matches EmissiveTriangle in lights.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct RtEmissiveTriangle {
+    v0: Vector4<f32>, // w is the area
+    edge_a: Vector4<f32>, // v1 - v0, w is the cumulative probability
+    edge_b: Vector4<f32>, // v2 - v0, w is the probability of picking this triangle
+    emission: Vector4<f32>
+}
+
+// World space triangles of the scene models with emissive materials, sampled by the path tracer's next event
+// estimation in proportion to their power. Like quad lights they light the scene without being ray traced
+// themselves, the models aren't in the acceleration structures yet.
+pub struct EmissiveTriangles {
+    buffer: GpuBuffer, // Holds a single unused triangle when there are none, a descriptor needs a buffer
+    triangles: Vec<RtEmissiveTriangle>, // The buffer's contents, uploaded again by recreate
+    count: u32
+}
+
+// Luminance times area
+fn triangle_power(emission: [f32; 3], area: f32) -> f32 {
+    (0.2126 * emission[0] + 0.7152 * emission[1] + 0.0722 * emission[2]) * area
+}
+
+impl EmissiveTriangles {
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool) -> EmissiveTriangles {
+        EmissiveTriangles::from_triangles(core, command_pool, Vec::new())
+    }
+
+    pub fn from_scene(core: &VkCore, command_pool: vk::CommandPool, scene: &Scene) -> EmissiveTriangles {
+        let mut triangles: Vec<RtEmissiveTriangle> = Vec::new();
+        for model in scene.models.iter() {
+            let emission = match model.material.as_ref().and_then(|m| scene.material(m)) {
+                Some(material) if material.is_emissive() => material.emission(),
+                _ => continue
+            };
+            let transform = model.transform.matrix();
+            let (vertices, indices) = load_model(model.path.as_str());
+            for triangle in indices.chunks_exact(3) {
+                let [v0, v1, v2] = [0, 1, 2].map(|i| {
+                    transform.transform_point(Point3::from(vertices[triangle[i] as usize].pos))
+                });
+                let (edge_a, edge_b): (Vector3<f32>, Vector3<f32>) = (v1 - v0, v2 - v0);
+                let area = 0.5 * edge_a.cross(edge_b).magnitude();
+                if area <= 0.0 {
+                    continue;
+                }
+                triangles.push(RtEmissiveTriangle {
+                    v0: v0.to_homogeneous().truncate().extend(area),
+                    edge_a: edge_a.extend(0.0),
+                    edge_b: edge_b.extend(0.0),
+                    emission: Vector3::from(emission).extend(0.0)
+                });
+            }
+        }
+        info!(triangles = triangles.len(), "Built emissive triangle table");
+
+        EmissiveTriangles::from_triangles(core, command_pool, triangles)
+    }
+
+    fn from_triangles(core: &VkCore, command_pool: vk::CommandPool, mut triangles: Vec<RtEmissiveTriangle>)
+        -> EmissiveTriangles {
+        let count = triangles.len() as u32;
+        let total: f32 = triangles.iter().map(|t| triangle_power(t.emission.truncate().into(), t.v0.w)).sum();
+        let mut cumulative = 0.0;
+        for t in triangles.iter_mut() {
+            let probability = triangle_power(t.emission.truncate().into(), t.v0.w) / total;
+            cumulative += probability;
+            t.edge_a.w = cumulative;
+            t.edge_b.w = probability;
+        }
+        if triangles.is_empty() {
+            let zero = Vector4::new(0.0, 0.0, 0.0, 0.0);
+            triangles.push(RtEmissiveTriangle { v0: zero, edge_a: zero, edge_b: zero, emission: zero });
+        }
+        let buffer = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                triangles.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+
+        EmissiveTriangles {
+            buffer,
+            triangles,
+            count
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    // Bind as a STORAGE_BUFFER descriptor
+    pub fn buffer(&self) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo::default()
+            .buffer(self.buffer.buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+    }
+
+    // On a new device after the old one was lost
+    pub fn recreate(&mut self, core: &VkCore, command_pool: vk::CommandPool) {
+        self.buffer = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                                 self.triangles.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.buffer.destroy(core);
+    }
+}
@@ -0,0 +1,128 @@
+use cgmath::{Vector3, Vector4};
+use renderlib::sky::SkySettings;
+
+pub const MAX_ENVIRONMENT_MAPS: usize = 4;
+// Counted in frames rather than time, so that captures and replays stay deterministic
+const TRANSITION_FRAMES: u32 = 30;
+
+// What rays that miss all geometry see
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Environment {
+    Solid(Vector3<f32>),
+    // Blends from horizon to zenith with the elevation of the ray, z is up
+    Gradient { horizon: Vector3<f32>, zenith: Vector3<f32> },
+    // Equirectangular map loaded with RtRenderer::load_environment_map, scaled by intensity
+    Map { index: u32, intensity: f32 },
+    // Preetham's daylight model in sky.glsl, sun_direction points towards the sun. See Environment::from_sky.
+    Sky { sun_direction: Vector3<f32>, turbidity: f32, intensity: f32 }
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::Solid(Vector3::new(0.7, 0.7, 0.7))
+    }
+}
+
+// One environment in RtPerFrameUbo
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RtEnvironmentUbo {
+    // w is the environment map index, -1 without a map and -2 for a sky whose sun direction is in xyz
+    pub zenith: Vector4<f32>,
+    pub horizon: Vector4<f32> // w is the environment map or sky intensity, x the sky's turbidity
+}
+
+impl Environment {
+    // The same sky as the raster renderer's for settings
+    pub fn from_sky(settings: &SkySettings) -> Environment {
+        Environment::Sky {
+            sun_direction: settings.sun_direction(),
+            turbidity: settings.turbidity,
+            intensity: settings.sky_intensity
+        }
+    }
+
+    fn to_ubo(self) -> RtEnvironmentUbo {
+        match self {
+            Environment::Solid(color) => RtEnvironmentUbo {
+                zenith: color.extend(-1.0),
+                horizon: color.extend(0.0)
+            },
+            Environment::Gradient { horizon, zenith } => RtEnvironmentUbo {
+                zenith: zenith.extend(-1.0),
+                horizon: horizon.extend(0.0)
+            },
+            Environment::Map { index, intensity } => RtEnvironmentUbo {
+                zenith: Vector4::new(0.0, 0.0, 0.0, index as f32),
+                horizon: Vector4::new(0.0, 0.0, 0.0, intensity)
+            },
+            Environment::Sky { sun_direction, turbidity, intensity } => RtEnvironmentUbo {
+                zenith: sun_direction.extend(-2.0),
+                horizon: Vector4::new(turbidity, 0.0, 0.0, intensity)
+            }
+        }
+    }
+}
+
+// Fades from the previous environment to the current one over TRANSITION_FRAMES. The miss shader evaluates both
+// and blends them, so any two kinds of environment can be faded between.
+pub struct EnvironmentTransition {
+    from: Environment,
+    to: Environment,
+    frame: u32
+}
+
+impl EnvironmentTransition {
+    pub fn new(environment: Environment) -> EnvironmentTransition {
+        EnvironmentTransition {
+            from: environment,
+            to: environment,
+            frame: TRANSITION_FRAMES
+        }
+    }
+
+    // The environment being faded to
+    pub fn current(&self) -> Environment {
+        self.to
+    }
+
+    // A fade that is still running starts over from whichever environment was more visible
+    pub fn set(&mut self, environment: Environment) {
+        if environment == self.to {
+            return;
+        }
+        // A sky follows its sun without fading, the time of day may change every frame
+        if let (Environment::Sky { .. }, Environment::Sky { .. }) = (self.to, environment) {
+            if self.is_finished() {
+                self.from = environment;
+            }
+            self.to = environment;
+            return;
+        }
+        if self.blend() >= 0.5 {
+            self.from = self.to;
+        }
+        self.to = environment;
+        self.frame = 0;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= TRANSITION_FRAMES
+    }
+
+    // Call once per rendered frame
+    pub fn advance(&mut self) {
+        self.frame = (self.frame + 1).min(TRANSITION_FRAMES);
+    }
+
+    // Weight of the current environment, eased with smoothstep
+    fn blend(&self) -> f32 {
+        let t = self.frame as f32 / TRANSITION_FRAMES as f32;
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    // Previous and current environment, and the weight of the current one
+    pub fn ubo(&self) -> ([RtEnvironmentUbo; 2], Vector4<f32>) {
+        ([self.from.to_ubo(), self.to.to_ubo()], Vector4::new(self.blend(), 0.0, 0.0, 0.0))
+    }
+}
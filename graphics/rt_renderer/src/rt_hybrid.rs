@@ -0,0 +1,384 @@
+use std::ffi::c_void;
+use std::mem;
+
+use ash::extensions::khr::AccelerationStructure;
+use ash::vk;
+use cgmath::{Matrix4, Point3, SquareMatrix, Transform};
+use renderlib::color_pipeline::ColorConstants;
+use renderlib::compute::{ComputePipeline, group_count};
+use renderlib::gbuffer::GBuffer;
+use renderlib::gpu_buffer::GpuBuffer;
+use renderlib::image::StorageImage;
+use renderlib::render_target::RenderTarget;
+use renderlib::renderutils::cast_to_u8_slice;
+use renderlib::sky::SkySettings;
+use renderlib::vertex::Vertex;
+use renderlib::vkcore::VkCore;
+use crate::rt_accel::{RtAccel, RtBlas, RtPerInstanceData, RtTlas};
+use crate::rt_canvas::{CanvasFormat, RtCanvas};
+
+const TRACE_SHADER_PATH: &str = "graphics/shaders/spv/hybrid_trace.spv";
+const COMPOSITE_SHADER_PATH: &str = "graphics/shaders/spv/hybrid_composite.spv";
+const HYBRID_GROUP_SIZE: u32 = 8; // Matches local_size_x/y in hybrid.glsl
+const RESULT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+pub const MAX_AO_RAYS: u32 = 16; // Matches AO_MAX_RAYS in hybrid_trace.comp
+
+// What the hybrid renderer traces rays for, everything else comes from the G-buffer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HybridSettings {
+    pub shadows: bool, // Of the sun
+    pub reflections: bool, // A single mirror bounce
+    pub ao_rays: u32, // Ambient occlusion rays per pixel, 0 disables it
+    pub ao_radius: f32, // Occluders further away than this don't count
+    pub reflectivity: f32, // How much of the reflection replaces the shaded surface
+    pub ambient: f32 // Scales the sky's zenith radiance into the light from everywhere
+}
+
+impl Default for HybridSettings {
+    fn default() -> HybridSettings {
+        HybridSettings {
+            shadows: true,
+            reflections: true,
+            ao_rays: 4,
+            ao_radius: 2.0,
+            reflectivity: 0.2,
+            ambient: 0.5
+        }
+    }
+}
+
+// Matches HybridUniforms in hybrid.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct HybridUniforms {
+    view_proj: Matrix4<f32>,
+    inverse_view_proj: Matrix4<f32>,
+    eye: [f32; 4],
+    sun: [f32; 4],
+    sun_color: [f32; 4],
+    settings: [f32; 4],
+    material: [f32; 4]
+}
+
+fn create_set_layout(core: &VkCore) -> vk::DescriptorSetLayout {
+    let types = [
+        vk::DescriptorType::UNIFORM_BUFFER,
+        vk::DescriptorType::STORAGE_IMAGE, // Albedo
+        vk::DescriptorType::STORAGE_IMAGE, // Normal
+        vk::DescriptorType::STORAGE_IMAGE, // Position
+        vk::DescriptorType::STORAGE_IMAGE, // Visibility
+        vk::DescriptorType::STORAGE_IMAGE, // Reflection
+        vk::DescriptorType::STORAGE_IMAGE, // The frame's canvas image
+        vk::DescriptorType::ACCELERATION_STRUCTURE_KHR
+    ];
+    let bindings: Vec<vk::DescriptorSetLayoutBinding> = types.iter().enumerate()
+        .map(|(b, &ty)| vk::DescriptorSetLayoutBinding::default()
+            .binding(b as u32)
+            .descriptor_type(ty)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::COMPUTE))
+        .collect();
+    let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(bindings.as_slice());
+
+    unsafe { core.logical_device.create_descriptor_set_layout(&layout_create_info, None).unwrap() }
+}
+
+fn create_result_image(core: &VkCore, command_pool: vk::CommandPool, extent: vk::Extent2D) -> StorageImage {
+    StorageImage::new(core, command_pool, extent, RESULT_FORMAT, vk::ImageUsageFlags::empty())
+}
+
+// What is drawn into the G-buffer, which HybridTracer traces rays against
+#[derive(Clone, Copy)]
+pub struct HybridGeometry<'a> {
+    pub vertices: &'a [Vertex],
+    pub indices: &'a [u32],
+    pub model: Matrix4<f32> // Places the vertices in the world
+}
+
+// The ray traced half of the hybrid renderer. Rays start from the surfaces of a GBuffer instead of the camera and
+// are traced with ray queries from compute shaders: one towards the sun, a few for ambient occlusion and a
+// reflection. A second pass shades the G-buffer with their results into the frame's canvas, which is then blitted
+// to the swap chain like the rt renderer's. The model is placed in the world once, so the acceleration structures
+// are built when the tracer is created and never again.
+pub struct HybridTracer {
+    settings: HybridSettings,
+    accel_instance: AccelerationStructure,
+    blas: RtBlas,
+    tlas: RtTlas,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>, // One per frame in flight
+    uniform_buffers: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>,
+    trace: ComputePipeline,
+    composite: ComputePipeline,
+    visibility: StorageImage, // Shared by the frames in flight like the G-buffer
+    reflection: StorageImage,
+    canvas: RtCanvas,
+    extent: vk::Extent2D
+}
+
+impl HybridTracer {
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget, gbuffer: &GBuffer,
+               geometry: &HybridGeometry, settings: HybridSettings, max_frames: usize) -> HybridTracer {
+        let HybridGeometry { vertices, indices, model } = *geometry;
+        let accel_instance = AccelerationStructure::new(&core.instance, &core.logical_device);
+        let positions: Vec<f32> = vertices.iter()
+            .flat_map(|v| {
+                let p = model.transform_point(Point3::new(v.pos[0], v.pos[1], v.pos[2]));
+                [p.x, p.y, p.z]
+            })
+            .collect();
+        let blas = RtAccel::new_blas_triangles(core, &accel_instance, command_pool, indices, positions.as_slice());
+        let instance = RtPerInstanceData {
+            transform: Matrix4::identity(), // The positions are in world space already
+            blas_index: 0,
+            custom_index: 0
+        };
+        let tlas = RtAccel::new_tlas(core, &accel_instance, command_pool, &[&blas], &[instance]);
+
+        let uniform_size = mem::size_of::<HybridUniforms>() as vk::DeviceSize;
+        let uniform_buffers: Vec<GpuBuffer> = (0..max_frames)
+            .map(|_| GpuBuffer::new_mapped(core, uniform_size, vk::BufferUsageFlags::UNIFORM_BUFFER))
+            .collect();
+        let mapped: Vec<*mut c_void> = uniform_buffers.iter()
+            .map(|b| unsafe {
+                core.logical_device.map_memory(b.mem, 0, uniform_size, vk::MemoryMapFlags::empty()).unwrap()
+            })
+            .collect();
+
+        let set_layout = create_set_layout(core);
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(max_frames as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(6 * max_frames as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                .descriptor_count(max_frames as u32)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .max_sets(max_frames as u32)
+            .pool_sizes(&pool_sizes);
+        let descriptor_pool = unsafe { core.logical_device.create_descriptor_pool(&pool_create_info, None).unwrap() };
+        let layouts = vec![set_layout; max_frames];
+        let allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(layouts.as_slice());
+        let sets = unsafe { core.logical_device.allocate_descriptor_sets(&allocate_info).unwrap() };
+
+        let tracer = HybridTracer {
+            settings,
+            accel_instance,
+            blas,
+            tlas,
+            set_layout,
+            descriptor_pool,
+            sets,
+            uniform_buffers,
+            mapped,
+            trace: ComputePipeline::new(core, TRACE_SHADER_PATH, &[set_layout], 0),
+            composite: ComputePipeline::new(core, COMPOSITE_SHADER_PATH, &[set_layout],
+                                            mem::size_of::<ColorConstants>() as u32),
+            visibility: create_result_image(core, command_pool, render_target.extent),
+            reflection: create_result_image(core, command_pool, render_target.extent),
+            canvas: RtCanvas::new(core, render_target.extent,
+                                  CanvasFormat::select(core, render_target.surface_format).unwrap(), max_frames),
+            extent: render_target.extent
+        };
+        tracer.write_descriptor_sets(core, gbuffer);
+
+        tracer
+    }
+
+    fn write_descriptor_sets(&self, core: &VkCore, gbuffer: &GBuffer) {
+        let structures = [self.tlas.acceleration_structure];
+        let albedo_info = gbuffer.albedo_info();
+        let normal_info = gbuffer.normal_info();
+        let position_info = gbuffer.position_info();
+        let visibility_info = self.visibility.info();
+        let reflection_info = self.reflection.info();
+        for (frame, &set) in self.sets.iter().enumerate() {
+            let uniform_info = [vk::DescriptorBufferInfo::default()
+                .buffer(self.uniform_buffers[frame].buf)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let canvas_info = [vk::DescriptorImageInfo::default()
+                .image_view(self.canvas.views[frame])
+                .image_layout(vk::ImageLayout::GENERAL)];
+            let mut accel_write = vk::WriteDescriptorSetAccelerationStructureKHR::default()
+                .acceleration_structures(&structures);
+            let storage_write = |binding: u32, info| vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(binding)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(info);
+            let writes = [
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .buffer_info(&uniform_info),
+                storage_write(1, &albedo_info),
+                storage_write(2, &normal_info),
+                storage_write(3, &position_info),
+                storage_write(4, &visibility_info),
+                storage_write(5, &reflection_info),
+                storage_write(6, &canvas_info),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(7)
+                    .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                    .descriptor_count(1) // Not set by push_next
+                    .push_next(&mut accel_write)
+            ];
+            unsafe { core.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+    }
+
+    // After the G-buffer was resized, nothing may still be using the images
+    pub fn resize(&mut self, core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget,
+                  gbuffer: &GBuffer) {
+        self.visibility.destroy(core);
+        self.reflection.destroy(core);
+        self.canvas.destroy(core);
+        self.visibility = create_result_image(core, command_pool, render_target.extent);
+        self.reflection = create_result_image(core, command_pool, render_target.extent);
+        self.canvas = RtCanvas::new(core, render_target.extent, self.canvas.format, self.sets.len());
+        self.extent = render_target.extent;
+        self.write_descriptor_sets(core, gbuffer);
+    }
+
+    // What ColorPipeline::constants needs for cmd_trace's color_constants, see CanvasFormat::output_format
+    pub fn output_format(&self, swapchain_format: vk::Format) -> vk::Format {
+        self.canvas.format.output_format(swapchain_format)
+    }
+
+    // Applied from the next update
+    pub fn set_settings(&mut self, settings: HybridSettings) {
+        self.settings = settings;
+    }
+
+    pub fn settings(&self) -> HybridSettings {
+        self.settings
+    }
+
+    // Once the frame's fence has been waited on. frame_index changes every frame and reseeds the sampling.
+    pub fn update(&self, current_frame: usize, view: Matrix4<f32>, proj: Matrix4<f32>, eye: Point3<f32>,
+                  sky: &SkySettings, frame_index: u32) {
+        let view_proj = proj * view;
+        let sun = sky.sun_direction();
+        let sun_color = sky.sun_color() * sky.sun_intensity;
+        let uniforms = HybridUniforms {
+            view_proj,
+            inverse_view_proj: view_proj.invert().unwrap(),
+            eye: [eye.x, eye.y, eye.z, frame_index as f32],
+            sun: [sun.x, sun.y, sun.z, sky.turbidity],
+            sun_color: [sun_color.x, sun_color.y, sun_color.z, sky.sky_intensity],
+            settings: [self.settings.shadows as u32 as f32, self.settings.reflections as u32 as f32,
+                self.settings.ao_radius, self.settings.ao_rays.min(MAX_AO_RAYS) as f32],
+            material: [self.settings.reflectivity, self.settings.ambient, 0.0, 0.0]
+        };
+        unsafe { (self.mapped[current_frame] as *mut HybridUniforms).write(uniforms) };
+    }
+
+    // After the G-buffer's render pass. Traces, composites into the frame's canvas and blits it to present_image,
+    // which is left in PRESENT_SRC_KHR.
+    pub fn cmd_trace(&self, core: &VkCore, command_buffer: vk::CommandBuffer, current_frame: usize,
+                     color_constants: &ColorConstants, present_image: vk::Image) {
+        let canvas_image = self.canvas.images[current_frame];
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let layout_barrier = |image, old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier::default()
+                .image(image)
+                .subresource_range(subresource_range)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        };
+        let canvas_to_general = layout_barrier(canvas_image, vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL,
+                                               vk::AccessFlags::empty(), vk::AccessFlags::SHADER_WRITE);
+        // The visibility and reflection images
+        let traced = vk::MemoryBarrier::default()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        let canvas_to_src = layout_barrier(canvas_image, vk::ImageLayout::GENERAL,
+                                           vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::AccessFlags::SHADER_WRITE,
+                                           vk::AccessFlags::TRANSFER_READ);
+        let present_to_dst = layout_barrier(present_image, vk::ImageLayout::UNDEFINED,
+                                            vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::AccessFlags::empty(),
+                                            vk::AccessFlags::TRANSFER_WRITE);
+        let present_to_present = layout_barrier(present_image, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                                vk::ImageLayout::PRESENT_SRC_KHR, vk::AccessFlags::TRANSFER_WRITE,
+                                                vk::AccessFlags::empty());
+        let blit_subresource = vk::ImageSubresourceLayers::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_array_layer(0)
+            .mip_level(0)
+            .layer_count(1);
+        let blit_offsets = [vk::Offset3D::default(),
+            vk::Offset3D::default().x(self.extent.width as i32).y(self.extent.height as i32).z(1)];
+        let blit_region = vk::ImageBlit::default()
+            .src_subresource(blit_subresource)
+            .dst_subresource(blit_subresource)
+            .src_offsets(blit_offsets)
+            .dst_offsets(blit_offsets);
+        let group_x = group_count(self.extent.width, HYBRID_GROUP_SIZE);
+        let group_y = group_count(self.extent.height, HYBRID_GROUP_SIZE);
+        let set = self.sets[current_frame];
+
+        unsafe {
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[], &[], &[canvas_to_general]);
+            self.trace.cmd_bind(core, command_buffer, set);
+            core.logical_device.cmd_dispatch(command_buffer, group_x, group_y, 1);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::DependencyFlags::empty(), &[traced], &[], &[]);
+            self.composite.cmd_bind(core, command_buffer, set);
+            core.logical_device.cmd_push_constants(command_buffer, self.composite.layout,
+                                                   vk::ShaderStageFlags::COMPUTE, 0,
+                                                   cast_to_u8_slice(color_constants));
+            core.logical_device.cmd_dispatch(command_buffer, group_x, group_y, 1);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COMPUTE_SHADER,
+                                                     vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                     &[], &[], &[canvas_to_src, present_to_dst]);
+            core.logical_device.cmd_blit_image(command_buffer, canvas_image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                               present_image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[blit_region],
+                                               vk::Filter::NEAREST);
+            core.logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                     vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                     vk::DependencyFlags::empty(), &[], &[], &[present_to_present]);
+        }
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.trace.destroy(core);
+        self.composite.destroy(core);
+        self.visibility.destroy(core);
+        self.reflection.destroy(core);
+        self.canvas.destroy(core);
+        unsafe {
+            core.logical_device.destroy_descriptor_pool(self.descriptor_pool, None);
+            core.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+        for b in self.uniform_buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+        self.tlas.destroy(core, &self.accel_instance);
+        self.blas.destroy(core, &self.accel_instance);
+    }
+}
@@ -0,0 +1,274 @@
+use std::ffi::c_void;
+use std::mem;
+use ash::vk;
+use cgmath::{InnerSpace, Point3, Vector3, Vector4};
+use renderlib::gpu_buffer::GpuBuffer;
+use renderlib::scene;
+use renderlib::vkcore::VkCore;
+
+pub const MAX_LIGHTS: usize = 64;
+pub const MAX_LIGHT_SAMPLES: u32 = 16;
+
+// Lights sampled by the path tracer's next event estimation. Spheres and quads aren't part of the acceleration
+// structures, so they only light the scene and are never seen directly. Their shadows get penumbrae since the shadow
+// rays go to points spread over their surfaces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Light {
+    // Parallel rays travelling along direction, color is the irradiance
+    Directional { direction: Vector3<f32>, color: Vector3<f32> },
+    // color is the radiant intensity, falling off with the squared distance
+    Point { position: Point3<f32>, color: Vector3<f32> },
+    // Parallelogram spanned by edge_a and edge_b, emitting color as radiance towards edge_a.cross(edge_b)
+    Quad { corner: Point3<f32>, edge_a: Vector3<f32>, edge_b: Vector3<f32>, color: Vector3<f32> },
+    // Emits color as radiance in all directions from its surface
+    Sphere { center: Point3<f32>, radius: f32, color: Vector3<f32> }
+}
+
+// Stays the same until the light is removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LightId(u32);
+
+// Matches Light in lights.glsl
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct RtLightGpu {
+    color: Vector4<f32>, // w is the kind, 0 directional, 1 point, 2 quad and 3 sphere
+    position: Vector4<f32>, // The direction towards the light for directional lights, w is the cumulative probability
+    edge_a: Vector4<f32>, // w is the probability of picking this light
+    edge_b: Vector4<f32>, // w is the area of a quad
+    params: [f32; 4] // x is the radius of a sphere, y the shadow rays per sample
+}
+
+impl Light {
+    // The path traced equivalent of a scene light. Point lights with a radius become spheres of the same power.
+    pub fn from_scene(light: &scene::Light) -> Light {
+        match *light {
+            scene::Light::Directional { direction, color, intensity } => Light::Directional {
+                direction: Vector3::from(direction),
+                color: Vector3::from(color) * intensity
+            },
+            scene::Light::Point { position, color, intensity, radius, .. } if radius > 0.0 => Light::Sphere {
+                center: Point3::from(position),
+                radius,
+                color: Vector3::from(color) * intensity / (std::f32::consts::PI * radius * radius)
+            },
+            scene::Light::Point { position, color, intensity, .. } => Light::Point {
+                position: Point3::from(position),
+                color: Vector3::from(color) * intensity
+            }
+        }
+    }
+
+    // What importance sampling picks lights by
+    fn power(&self) -> f32 {
+        let (color, area) = match *self {
+            Light::Directional { color, .. } | Light::Point { color, .. } => (color, 1.0),
+            Light::Quad { edge_a, edge_b, color, .. } => (color, edge_a.cross(edge_b).magnitude()),
+            // Seen from afar it is a point light of the radiance times its cross section
+            Light::Sphere { radius, color, .. } => (color, std::f32::consts::PI * radius * radius)
+        };
+        (0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z).max(0.0) * area
+    }
+
+    fn to_gpu(self, probability: f32, cumulative: f32, samples: u32) -> RtLightGpu {
+        let samples = samples as f32;
+        match self {
+            Light::Directional { direction, color } => RtLightGpu {
+                color: color.extend(0.0),
+                position: (-direction.normalize()).extend(cumulative),
+                edge_a: Vector4::new(0.0, 0.0, 0.0, probability),
+                edge_b: Vector4::new(0.0, 0.0, 0.0, 0.0),
+                params: [0.0, samples, 0.0, 0.0]
+            },
+            Light::Point { position, color } => RtLightGpu {
+                color: color.extend(1.0),
+                position: position.to_homogeneous().truncate().extend(cumulative),
+                edge_a: Vector4::new(0.0, 0.0, 0.0, probability),
+                edge_b: Vector4::new(0.0, 0.0, 0.0, 0.0),
+                params: [0.0, samples, 0.0, 0.0]
+            },
+            Light::Quad { corner, edge_a, edge_b, color } => RtLightGpu {
+                color: color.extend(2.0),
+                position: corner.to_homogeneous().truncate().extend(cumulative),
+                edge_a: edge_a.extend(probability),
+                edge_b: edge_b.extend(edge_a.cross(edge_b).magnitude()),
+                params: [0.0, samples, 0.0, 0.0]
+            },
+            Light::Sphere { center, radius, color } => RtLightGpu {
+                color: color.extend(3.0),
+                position: center.to_homogeneous().truncate().extend(cumulative),
+                edge_a: Vector4::new(0.0, 0.0, 0.0, probability),
+                edge_b: Vector4::new(0.0, 0.0, 0.0, 0.0),
+                params: [radius, samples, 0.0, 0.0]
+            }
+        }
+    }
+}
+
+fn create_buffers(core: &VkCore, max_frames: usize) -> (Vec<GpuBuffer>, Vec<*mut c_void>) {
+    let size = (mem::size_of::<RtLightGpu>() * MAX_LIGHTS) as vk::DeviceSize;
+    let mut buffers: Vec<GpuBuffer> = Vec::with_capacity(max_frames);
+    let mut mapped: Vec<*mut c_void> = Vec::with_capacity(max_frames);
+    for _ in 0..max_frames {
+        let buffer = GpuBuffer::new(core, size, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT);
+        mapped.push(unsafe {
+            core.logical_device.map_memory(buffer.mem, 0, size, vk::MemoryMapFlags::empty()).unwrap()
+        });
+        buffers.push(buffer);
+    }
+
+    (buffers, mapped)
+}
+
+// The lights, and a storage buffer of them per frame in flight. Like ShaderAddressTable, changes are written to a
+// frame's buffer in update, once its previous submission has finished. Every light has a number of shadow rays it
+// casts when it is picked, averaged over points spread across its surface.
+pub struct LightList {
+    lights: Vec<(LightId, Light)>,
+    samples: Vec<u32>, // Per light, in the same order
+    next_id: u32,
+    generation: u32,
+    buffers: Vec<GpuBuffer>,
+    mapped: Vec<*mut c_void>,
+    dirty: Vec<bool>
+}
+
+impl LightList {
+    pub fn new(core: &VkCore, max_frames: usize) -> LightList {
+        let (buffers, mapped) = create_buffers(core, max_frames);
+
+        LightList {
+            lights: Vec::new(),
+            samples: Vec::new(),
+            next_id: 0,
+            generation: 0,
+            buffers,
+            mapped,
+            dirty: vec![false; max_frames]
+        }
+    }
+
+    pub fn add(&mut self, light: Light) -> Result<LightId, String> {
+        if self.lights.len() >= MAX_LIGHTS {
+            return Err(format!("At most {} lights are supported", MAX_LIGHTS));
+        }
+        let id = LightId(self.next_id);
+        self.next_id += 1;
+        self.lights.push((id, light));
+        self.samples.push(1);
+        self.changed();
+
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: LightId) -> Result<(), String> {
+        let index = match self.lights.iter().position(|(i, _)| *i == id) {
+            Some(index) => index,
+            None => return Err(format!("{:?} doesn't exist", id))
+        };
+        self.lights.remove(index);
+        self.samples.remove(index);
+        self.changed();
+
+        Ok(())
+    }
+
+    // Replaces the light in place, it keeps its id
+    pub fn set(&mut self, id: LightId, light: Light) -> Result<(), String> {
+        match self.lights.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, l)) => *l = light,
+            None => return Err(format!("{:?} doesn't exist", id))
+        }
+        self.changed();
+
+        Ok(())
+    }
+
+    pub fn get(&self, id: LightId) -> Option<Light> {
+        self.lights.iter().find(|(i, _)| *i == id).map(|(_, l)| *l)
+    }
+
+    // Shadow rays per picked sample of the light, 1 to MAX_LIGHT_SAMPLES. More only lower the noise of its penumbrae,
+    // the image converges to the same, so the accumulated frames are kept.
+    pub fn set_samples(&mut self, id: LightId, samples: u32) -> Result<(), String> {
+        if !(1..=MAX_LIGHT_SAMPLES).contains(&samples) {
+            return Err(format!("Lights take 1 to {} samples, not {}", MAX_LIGHT_SAMPLES, samples));
+        }
+        match self.lights.iter().position(|(i, _)| *i == id) {
+            Some(index) => self.samples[index] = samples,
+            None => return Err(format!("{:?} doesn't exist", id))
+        }
+        self.dirty.iter_mut().for_each(|d| *d = true);
+
+        Ok(())
+    }
+
+    pub fn samples(&self, id: LightId) -> Option<u32> {
+        self.lights.iter().position(|(i, _)| *i == id).map(|index| self.samples[index])
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    // Changes with every add, remove and set, so that accumulated frames can be discarded. set_samples keeps it.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    fn changed(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        self.dirty.iter_mut().for_each(|d| *d = true);
+    }
+
+    // Call once the frame's fence has been waited on, before recording commands that read the lights
+    pub fn update(&mut self, frame: usize) {
+        if !self.dirty[frame] {
+            return;
+        }
+        // Picked in proportion to their power, uniformly when none has any
+        let powers: Vec<f32> = self.lights.iter().map(|(_, l)| l.power()).collect();
+        let total: f32 = powers.iter().sum();
+        let mut cumulative = 0.0;
+        let gpu_lights: Vec<RtLightGpu> = self.lights.iter().zip(powers.iter()).zip(self.samples.iter())
+            .map(|(((_, light), power), samples)| {
+                let probability = match total > 0.0 {
+                    true => power / total,
+                    false => 1.0 / self.lights.len() as f32
+                };
+                cumulative += probability;
+                light.to_gpu(probability, cumulative, *samples)
+            })
+            .collect();
+        unsafe {
+            (self.mapped[frame] as *mut RtLightGpu).copy_from_nonoverlapping(gpu_lights.as_ptr(), gpu_lights.len());
+        }
+        self.dirty[frame] = false;
+    }
+
+    // Bind as a STORAGE_BUFFER descriptor
+    pub fn buffer(&self, frame: usize) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo::default()
+            .buffer(self.buffers[frame].buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+    }
+
+    // On a new device after the old one was lost, the lights and their ids stay
+    pub fn recreate(&mut self, core: &VkCore) {
+        (self.buffers, self.mapped) = create_buffers(core, self.dirty.len());
+        self.dirty.iter_mut().for_each(|d| *d = true);
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        for b in self.buffers.iter() {
+            unsafe { core.logical_device.unmap_memory(b.mem) };
+            b.destroy(core);
+        }
+    }
+}
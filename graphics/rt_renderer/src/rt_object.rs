@@ -1,6 +1,152 @@
+use std::collections::HashMap;
+
+use ash::extensions::khr::AccelerationStructure;
+use ash::vk;
+use renderlib::address_table::{AddressIndex, ShaderAddressTable};
+use renderlib::collision::Aabb;
+use renderlib::objects::{MeshHash, ObjectId, ObjectList, SceneObject};
+use renderlib::vkcore::VkCore;
+use tracing::debug;
+
+use crate::rt_accel::{RtAccel, RtBlas, RtPerInstanceData};
 use crate::rt_types::{RtIndex, RtVertex};
 
-pub struct RtObject {
-    indices: Vec<RtIndex>,
-    vertices: Vec<RtVertex>
-}
\ No newline at end of file
+// A BLAS shared by the objects with the same mesh, with its geometry registered for the hit shaders
+struct MeshBlas {
+    blas: RtBlas,
+    vertices: AddressIndex,
+    indices: AddressIndex,
+    users: usize
+}
+
+impl MeshBlas {
+    // Indices first, so that the next register hands the pair out again in the same order
+    fn unregister(&self, address_table: &mut ShaderAddressTable) {
+        address_table.unregister(self.indices);
+        address_table.unregister(self.vertices);
+    }
+}
+
+// The ray traced side of an ObjectList. Objects with the same mesh share a BLAS of it in object space, built the first
+// time the mesh is added, and their instances place it with the object's transform from the list. The hit shaders
+// find a BLAS' geometry through the instance custom index, see shader.rchit, and read 16 bit indices. BLASes no object
+// uses anymore may still be traced by frames in flight, so they are destroyed by end_frame once max_frames frames have
+// passed.
+pub struct RtObjects {
+    blases: HashMap<MeshHash, MeshBlas>,
+    instances: HashMap<ObjectId, MeshHash>,
+    retired: Vec<(MeshBlas, usize)>, // And the number of frames left until they can be destroyed
+    max_frames: usize
+}
+
+impl RtObjects {
+    pub fn new(max_frames: usize) -> RtObjects {
+        RtObjects {
+            blases: HashMap::new(),
+            instances: HashMap::new(),
+            retired: Vec::new(),
+            max_frames
+        }
+    }
+
+    // id is the object's in the renderer's ObjectList. The material isn't ray traced, the hit shaders shade every
+    // instance alike.
+    pub fn add(&mut self, core: &VkCore, acceleration_instance: &AccelerationStructure, command_pool: vk::CommandPool,
+               address_table: &mut ShaderAddressTable, id: ObjectId, object: &SceneObject) -> Result<(), String> {
+        if !self.blases.contains_key(&object.mesh_hash) {
+            let mesh = object.mesh.as_ref();
+            if mesh.indices.is_empty() {
+                return Err(format!("The mesh of {:?} has no triangles", id));
+            }
+            if mesh.vertices.len() > RtIndex::MAX as usize + 1 {
+                return Err(format!("Ray traced meshes have at most {} vertices, not {}", RtIndex::MAX as usize + 1,
+                                   mesh.vertices.len()));
+            }
+            // The voxel grid's and the voxel cube's pairs come first
+            let max_meshes = address_table.capacity() / 2 - 2;
+            if self.blases.len() >= max_meshes {
+                return Err(format!("At most {} different meshes can be ray traced", max_meshes));
+            }
+            let indices: Vec<RtIndex> = mesh.indices.iter().map(|&i| i as RtIndex).collect();
+            let vertices: Vec<RtVertex> = mesh.vertices.iter().flat_map(|v| v.pos).collect();
+            let blas = RtAccel::new_blas_triangles(core, acceleration_instance, command_pool, indices.as_slice(),
+                                                   vertices.as_slice());
+            let vertex_index = address_table.register(core, blas.vertex_buffer.as_ref().unwrap());
+            let index_index = address_table.register_address(blas.index_buffer.as_ref().unwrap()
+                .get_device_address(core));
+            assert!(vertex_index.0.is_multiple_of(2) && index_index.0 == vertex_index.0 + 1,
+                    "The geometry of a BLAS must be at 2 * i and 2 * i + 1");
+            debug!(vertices = mesh.vertices.len(), indices = mesh.indices.len(), "Built object BLAS");
+            self.blases.insert(object.mesh_hash, MeshBlas {
+                blas,
+                vertices: vertex_index,
+                indices: index_index,
+                users: 0
+            });
+        }
+        self.blases.get_mut(&object.mesh_hash).unwrap().users += 1;
+        self.instances.insert(id, object.mesh_hash);
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, address_table: &mut ShaderAddressTable, id: ObjectId) -> Result<(), String> {
+        let hash = match self.instances.remove(&id) {
+            Some(hash) => hash,
+            None => return Err(format!("{:?} isn't ray traced", id))
+        };
+        let mesh = self.blases.get_mut(&hash).unwrap();
+        mesh.users -= 1;
+        if mesh.users == 0 {
+            let mesh = self.blases.remove(&hash).unwrap();
+            mesh.unregister(address_table);
+            self.retired.push((mesh, self.max_frames));
+        }
+
+        Ok(())
+    }
+
+    // What to build a TLAS from, after the first_blas BLASes the caller passes before these. objects is the list the
+    // objects were added from, the instances follow its SceneBvh so that neighbours in space are neighbours in the
+    // instance buffer.
+    pub fn tlas_inputs(&self, first_blas: usize, objects: &ObjectList) -> (Vec<&RtBlas>, Vec<RtPerInstanceData>) {
+        let hashes: Vec<MeshHash> = self.blases.keys().copied().collect();
+        let blas = hashes.iter().map(|h| &self.blases[h].blas).collect();
+        let instances = objects.scene_bvh().ids()
+            .filter_map(|id| Some((objects.get(id)?, self.instances.get(&id)?)))
+            .map(|(object, hash)| RtPerInstanceData {
+                transform: object.transform,
+                blas_index: first_blas + hashes.iter().position(|h| h == hash).unwrap(),
+                custom_index: self.blases[hash].vertices.0 / 2
+            })
+            .collect();
+
+        (blas, instances)
+    }
+
+    // World space bounds of the instances tlas_inputs returns, in the same order, for checking where the TLAS places
+    // them
+    pub fn instance_bounds(&self, objects: &ObjectList) -> Vec<(ObjectId, Aabb)> {
+        objects.scene_bvh().ids()
+            .filter(|id| self.instances.contains_key(id))
+            .filter_map(|id| Some((id, objects.get(id)?.world_bounds())))
+            .collect()
+    }
+
+    // Call once per submitted frame
+    pub fn end_frame(&mut self, core: &VkCore, acceleration_instance: &AccelerationStructure) {
+        for (m, frames_left) in self.retired.iter_mut() {
+            *frames_left = frames_left.saturating_sub(1);
+            if *frames_left == 0 {
+                m.blas.destroy(core, acceleration_instance);
+            }
+        }
+        self.retired.retain(|r| r.1 > 0);
+    }
+
+    pub fn destroy(&self, core: &VkCore, acceleration_instance: &AccelerationStructure) {
+        for m in self.blases.values().chain(self.retired.iter().map(|(m, _)| m)) {
+            m.blas.destroy(core, acceleration_instance);
+        }
+    }
+}
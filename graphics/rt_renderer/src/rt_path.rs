@@ -0,0 +1,62 @@
+use winit::event::VirtualKeyCode;
+
+pub const MAX_SAMPLES_PER_PIXEL: u32 = 64;
+pub const PATH_TRACING_TOGGLE_KEY: VirtualKeyCode = VirtualKeyCode::P;
+pub const FEWER_SAMPLES_KEY: VirtualKeyCode = VirtualKeyCode::LBracket;
+pub const MORE_SAMPLES_KEY: VirtualKeyCode = VirtualKeyCode::RBracket;
+
+// Quality controls of the path tracing mode. Without it the renderer shows a preview, shaded surfaces with
+// ReflectionSettings reflections. With it every frame traces samples_per_pixel paths per pixel, and frames are
+// averaged as long as the camera and the settings stay the same.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathTracingSettings {
+    pub enabled: bool,
+    pub samples_per_pixel: u32,
+    pub max_bounces: u32,
+    pub firefly_clamp: f32, // Highest luminance a single sample may have, 0 disables clamping
+    pub rr_start_depth: u32 // Paths are terminated with russian roulette from this bounce on
+}
+
+impl Default for PathTracingSettings {
+    fn default() -> PathTracingSettings {
+        PathTracingSettings {
+            enabled: false,
+            samples_per_pixel: 1,
+            max_bounces: 4,
+            firefly_clamp: 10.0,
+            rr_start_depth: 2
+        }
+    }
+}
+
+impl PathTracingSettings {
+    // max_bounces is what the pipeline supports, see RtPipeline::max_bounces
+    pub fn validated(&self, max_bounces: u32) -> PathTracingSettings {
+        PathTracingSettings {
+            enabled: self.enabled,
+            samples_per_pixel: self.samples_per_pixel.clamp(1, MAX_SAMPLES_PER_PIXEL),
+            max_bounces: self.max_bounces.min(max_bounces),
+            firefly_clamp: self.firefly_clamp.max(0.0),
+            rr_start_depth: self.rr_start_depth
+        }
+    }
+
+    // Applies PATH_TRACING_TOGGLE_KEY, FEWER_SAMPLES_KEY and MORE_SAMPLES_KEY, returns false for other keys
+    pub fn handle_key(&mut self, key: VirtualKeyCode) -> bool {
+        match key {
+            PATH_TRACING_TOGGLE_KEY => self.enabled = !self.enabled,
+            FEWER_SAMPLES_KEY => self.samples_per_pixel = (self.samples_per_pixel / 2).max(1),
+            MORE_SAMPLES_KEY => self.samples_per_pixel = (self.samples_per_pixel * 2).min(MAX_SAMPLES_PER_PIXEL),
+            _ => return false
+        }
+        true
+    }
+
+    // For the stats overlay
+    pub fn status(&self, accumulated_frames: u32) -> String {
+        match self.enabled {
+            true => format!("path tracing {} spp, {} frames", self.samples_per_pixel, accumulated_frames),
+            false => String::from("preview")
+        }
+    }
+}
@@ -6,10 +6,15 @@ use std::mem;
 use ash::vk;
 use ash::extensions::khr;
 use ash::vk::Pipeline;
-use cgmath::Vector4;
+use tracing::info_span;
 use vk::PhysicalDeviceRayTracingPipelineFeaturesKHR;
+use renderlib::capabilities::buffer_device_address;
+use renderlib::color_pipeline::ColorConstants;
 use renderlib::gpu_buffer::{create_buffer, GpuBuffer};
+use renderlib::memory::mapped_memory_flags;
+use renderlib::specialization::SpecConstants;
 use renderlib::vkcore::VkCore;
+use crate::rt_reflection::MAX_REFLECTION_BOUNCES;
 
 const RAYGEN_IDX: usize = 0;
 const RAYHIT_IDX: usize = 2;
@@ -19,11 +24,8 @@ const RAYHIT_COUNT: usize = 1;
 const RAYMISS_COUNT: usize = 1;
 const RAYCALL_COUNT: usize = 0;
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug)]
-pub struct RtMissConstants {
-    pub clear_color: Vector4<f32>
-}
+// The miss shader reads the environment from RtPerFrameUbo, only raygen has push constants
+pub const RT_COLOR_CONSTANTS_OFFSET: u32 = 0;
 
 pub struct RtPipeline {
     instance: khr::RayTracingPipeline,
@@ -34,7 +36,8 @@ pub struct RtPipeline {
     pub raygen_addr_region: vk::StridedDeviceAddressRegionKHR,
     pub raymiss_addr_region: vk::StridedDeviceAddressRegionKHR,
     pub rayhit_addr_region: vk::StridedDeviceAddressRegionKHR,
-    pub raycallable_addr_region: vk::StridedDeviceAddressRegionKHR
+    pub raycallable_addr_region: vk::StridedDeviceAddressRegionKHR,
+    pub max_bounces: u32 // Reflection bounces the recursion depth allows, limited by the device
 }
 
 fn align_u32(val: u32, align: u32) -> u32 {
@@ -79,12 +82,19 @@ fn load_all_shaders(core: &VkCore) -> Vec<vk::ShaderModule> {
 
 impl RtPipeline {
     pub fn new(core: &VkCore, layouts: &Vec<vk::DescriptorSetLayout>) -> RtPipeline {
+        RtPipeline::new_specialized(core, layouts, &SpecConstants::new())
+    }
+
+    // Like new, with values for the specialization constants of all three shaders
+    pub fn new_specialized(core: &VkCore, layouts: &Vec<vk::DescriptorSetLayout>,
+                           spec_constants: &SpecConstants) -> RtPipeline {
+        let _span = info_span!("rt_pipeline_create").entered();
         let instance = khr::RayTracingPipeline::new(&core.instance, &core.logical_device);
         let push_constant_ranges = [
             vk::PushConstantRange::default()
-                .offset(0)
-                .size(mem::size_of::<RtMissConstants>() as u32)
-                .stage_flags(vk::ShaderStageFlags::MISS_KHR)
+                .offset(RT_COLOR_CONSTANTS_OFFSET)
+                .size(mem::size_of::<ColorConstants>() as u32)
+                .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
         ];
         let layout_create_info = vk::PipelineLayoutCreateInfo::default()
             .flags(vk::PipelineLayoutCreateFlags::empty())
@@ -112,8 +122,18 @@ impl RtPipeline {
                 .closest_hit_shader(RAYHIT_IDX as u32)
                 .intersection_shader(vk::SHADER_UNUSED_KHR),
         ];
+        // let rt_properties = unsafe { khr::RayTracingPipeline::get_properties(&core.instance, core.physical_device) };
+        let mut rt_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+        let mut dev_properties2 = vk::PhysicalDeviceProperties2::default()
+            .push_next(&mut rt_properties);
+        unsafe { core.instance.get_physical_device_properties2(core.physical_device, &mut dev_properties2) };
+        // The primary ray is the first level, every reflection bounce adds one
+        let recursion_depth = (MAX_REFLECTION_BOUNCES + 1).min(rt_properties.max_ray_recursion_depth).max(1);
+
         let shader_modules = load_all_shaders(core);
-        let stage_create_info = [
+        let spec_data = spec_constants.data();
+        let spec_info = spec_data.info();
+        let mut stage_create_info = [
             vk::PipelineShaderStageCreateInfo::default()
                 .name(CStr::from_bytes_with_nul(b"main\0").unwrap())
                 .stage(vk::ShaderStageFlags::RAYGEN_KHR)
@@ -127,6 +147,9 @@ impl RtPipeline {
                 .stage(vk::ShaderStageFlags::CLOSEST_HIT_KHR)
                 .module(shader_modules[RAYHIT_IDX]),
             ];
+        if let Some(info) = spec_info.as_ref() {
+            stage_create_info = stage_create_info.map(|stage| stage.specialization_info(info));
+        }
         let create_info = [
             vk::RayTracingPipelineCreateInfoKHR::default()
                 .layout(pipeline_layout)
@@ -134,7 +157,7 @@ impl RtPipeline {
                 // .base_pipeline_index(0)
                 // .dynamic_state()
                 .groups(&shader_groups)
-                .max_pipeline_ray_recursion_depth(1)
+                .max_pipeline_ray_recursion_depth(recursion_depth)
                 .stages(&stage_create_info)
         ];
         let pipelines = unsafe {
@@ -142,12 +165,6 @@ impl RtPipeline {
                                                   &create_info, None).unwrap()
         };
 
-        // let rt_properties = unsafe { khr::RayTracingPipeline::get_properties(&core.instance, core.physical_device) };
-        let mut rt_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
-        let mut dev_properties2 = vk::PhysicalDeviceProperties2::default()
-            .push_next(&mut rt_properties);
-        unsafe { core.instance.get_physical_device_properties2(core.physical_device, &mut dev_properties2) };
-
         // Note that each shader table group is made up of one handle for each shader within the group
         // Handles have alignment requirements
         let handle_size = align_u32(rt_properties.shader_group_handle_size, rt_properties
@@ -161,16 +178,12 @@ impl RtPipeline {
             .shader_group_base_alignment) as vk::DeviceSize;
         let sbt_size = raygen_group_size + rmiss_group_size + rhit_group_size + rcall_group_size;
 
-        // Should probably replace with a device local buffer later for draw indirect calls
+        // Read by every ray, device local where rebar_policy allows mapping it
         let (sbt_mem, sbt_buf) = create_buffer(core, sbt_size,
                                                vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR |
             vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS | vk::BufferUsageFlags::TRANSFER_SRC,
-                                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT);
-        let addr_info = vk::BufferDeviceAddressInfo::default()
-            .buffer(sbt_buf);
-        let sbt_buf_addr = unsafe {
-            core.logical_device.get_buffer_device_address(&addr_info)
-        };
+                                    mapped_memory_flags(core));
+        let sbt_buf_addr = buffer_device_address(core, sbt_buf);
 
         // The Vulkan spec states that the raygen handle stride must be equal to its group size
         let raygen_handle_stride = raygen_group_size;
@@ -241,6 +254,7 @@ impl RtPipeline {
             raymiss_addr_region,
             rayhit_addr_region,
             raycallable_addr_region,
+            max_bounces: recursion_depth - 1
         }
     }
 
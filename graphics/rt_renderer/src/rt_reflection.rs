@@ -0,0 +1,32 @@
+// Upper bound on the bounces requested from the pipeline, a reflection ray recurses once per bounce
+pub const MAX_REFLECTION_BOUNCES: u32 = 8;
+
+// Reflections traced by the closest hit shader. Each bounce samples one reflection direction from a GGX lobe, so
+// rough surfaces are noisy until there is some form of accumulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReflectionSettings {
+    pub max_bounces: u32, // 0 disables reflections
+    pub reflectivity: f32, // Share of the surface color that comes from the reflection
+    pub roughness: f32 // 0 is a mirror, 1 is close to diffuse
+}
+
+impl Default for ReflectionSettings {
+    fn default() -> ReflectionSettings {
+        ReflectionSettings {
+            max_bounces: 2,
+            reflectivity: 0.3,
+            roughness: 0.2
+        }
+    }
+}
+
+impl ReflectionSettings {
+    // max_bounces is what the pipeline supports, see RtPipeline::max_bounces
+    pub fn validated(&self, max_bounces: u32) -> ReflectionSettings {
+        ReflectionSettings {
+            max_bounces: self.max_bounces.min(max_bounces),
+            reflectivity: self.reflectivity.clamp(0.0, 1.0),
+            roughness: self.roughness.clamp(0.0, 1.0)
+        }
+    }
+}
@@ -1,122 +1,1073 @@
+use std::collections::{HashSet, VecDeque};
 use std::ffi::CString;
 use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use ash::vk;
 use ash::extensions::khr;
-use cgmath::{Deg, Matrix4, perspective, Point3, Transform, Vector3, Vector4};
-use winit::event::{Event, WindowEvent};
+use cgmath::{InnerSpace, Matrix4, Point3, Transform, Vector3, Vector4};
+use winit::event::{Event, VirtualKeyCode, WindowEvent};
+use winit::dpi::PhysicalSize;
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowId;
-use renderlib::render_target::RenderTarget;
+use renderlib::address_table::ShaderAddressTable;
+use renderlib::benchmark::{Benchmark, FrameStats};
+use renderlib::capture::{CaptureOutput, compare_hash_files, FrameCapture};
+use renderlib::clock::Clock;
+use renderlib::collision::{BoundingSphere, Ray};
+use renderlib::config::LaunchConfig;
+use renderlib::crash::{CRASH_HISTORY_FRAMES, CrashHandler};
+use renderlib::cursor::CursorCapture;
+use renderlib::descriptor_allocator::{DescriptorAllocator, LayoutCache};
+use renderlib::device_lost::{DeviceLost, DeviceLostCallback};
+use renderlib::display_mode::DisplayMode;
+use renderlib::color_pipeline::{ColorPipeline, SRGB_SWAPCHAIN_COLOR_SPACE, SRGB_SWAPCHAIN_FORMAT};
+use renderlib::exposure::{AutoExposureSettings, ExposureMetering};
+use renderlib::frame_limiter::FrameLimiter;
+use renderlib::idle::IdleTracker;
+use renderlib::input_replay::{InputEvent, InputRecorder, InputReplay};
+use renderlib::internal_resolution::{BlitImage, InternalResolution};
+use renderlib::latency::LatencyGovernor;
+use renderlib::motion_blur::MotionBlurSettings;
+use renderlib::objects::{Mesh, ObjectId, ObjectList, SceneObject};
+#[cfg(feature = "physics")]
+use renderlib::physics::{ColliderShape, PhysicsWorld, rapier3d::prelude::RigidBodyType};
+use renderlib::post_process::{PostFrame, PostProcessChain};
+use renderlib::profiler::GpuTimer;
+use renderlib::proxy::{check_screenshot_path, CommandQueue, RendererCommand, RendererProxy, RendererSetting};
+use renderlib::sampler::{create_sampler, destroy_sampler};
+use renderlib::render_target::{RenderTarget, SwapchainCallback, SwapchainRecreate};
+use renderlib::scene::{CameraProjection, Light as SceneLight, Material, Scene, SceneCamera};
+use renderlib::settings::RendererSettings;
+use renderlib::sky::SkySettings;
+use renderlib::stats_overlay::StatsOverlay;
+use renderlib::texture::Texture;
+use renderlib::upscale::Upscaler;
 
 use renderlib::renderutils::{cast_to_u8_slice, setup_sync_objects};
 use renderlib::vkcore::VkCore;
-use crate::rt_accel::{create_acceleration_structures, RtBlas, RtTlas};
-use crate::rt_canvas::RtCanvas;
-use crate::rt_descriptor::{create_per_frame_descriptor_sets, create_per_frame_descriptor_set_layout, destroy_descriptor_sets, create_singleton_descriptor_set_layout};
-use crate::rt_pipeline::{RtMissConstants, RtPipeline};
+use renderlib::voxel::{ChunkCoord, VoxelChunk, VoxelWorld};
+use renderlib::voxel_edit::BlockEditor;
+use renderlib::voxel_streaming::ChunkStreamer;
+use renderlib::voxel_visibility::{visible_chunks, VISIBILITY_DISTANCE};
+use tracing::{debug, error, info, info_span, trace, warn};
+use crate::rt_analysis::{AnalysisSettings, RtAnalysis};
+use crate::rt_accel::{create_acceleration_structures, grid_chunk_bounds, grid_instances, voxel_instances, RtAccel,
+                      RtBlas, RtPerInstanceData, RtTlas};
+use crate::rt_canvas::{CanvasFormat, RtCanvas};
+use crate::rt_descriptor::{create_per_frame_descriptor_set_layout, PER_FRAME_POOL_RATIOS, PerFrameBindings,
+                            write_per_frame_descriptor_set};
+use crate::rt_emissive::EmissiveTriangles;
+use crate::rt_environment::{Environment, EnvironmentTransition, MAX_ENVIRONMENT_MAPS};
+use crate::rt_light::{Light, LightId, LightList};
+use crate::rt_object::RtObjects;
+use crate::rt_path::PathTracingSettings;
+use crate::rt_pipeline::{RT_COLOR_CONSTANTS_OFFSET, RtPipeline};
+use crate::rt_reflection::ReflectionSettings;
+use crate::rt_sampling::RtSampling;
 use crate::rt_ubo::{RtUniformBuffer, RtPerFrameUbo};
 
 const MAX_FRAMES_IN_FLIGHT: usize = 2;
-const CLEAR_COLOR: [RtMissConstants; 1] = [RtMissConstants {
-    clear_color: Vector4 {
-        x: 0.7,
-        y: 0.7,
-        z: 0.7,
-        w: 0.7,
-    } // RGBA
-}];
+const ADDRESS_TABLE_CAPACITY: usize = 64;
+// TRANSFER_SRC lets frame capture read back presented images
+const SWAPCHAIN_USAGE: vk::ImageUsageFlags = vk::ImageUsageFlags::from_raw(
+    vk::ImageUsageFlags::TRANSFER_DST.as_raw() | vk::ImageUsageFlags::TRANSFER_SRC.as_raw() |
+        vk::ImageUsageFlags::COLOR_ATTACHMENT.as_raw());
+const CAMERA_STEP: f32 = 0.5; // Distance moved per frame while a movement key is held
+const MOUSE_LOOK_RADIANS_PER_PIXEL: f32 = 0.002;
+const MAX_LOOK_PITCH: f32 = 1.55; // Radians, short of straight up and down where the view matrix degenerates
+const FRAME_SCENE_KEY: VirtualKeyCode = VirtualKeyCode::F;
+const FRAME_SCENE_MARGIN: f32 = 0.1;
+const REPLAY_TIMESTEP: Duration = Duration::from_nanos(16_666_667);
 
-pub struct RtRenderer {
-    core: VkCore,
+// Until a scene or camera sets one, far enough for the whole voxel grid from the default camera
+const DEFAULT_PROJECTION: CameraProjection = CameraProjection::Perspective { fov_y_degrees: 45.0, near: 0.1,
+                                                                             far: 1000.0 };
+
+fn camera_view(eye: Point3<f32>, target: Point3<f32>) -> Matrix4<f32> {
+    Matrix4::look_at_rh(eye, target, Vector3::new(0.0, 0.0, 1.0))
+}
+
+// Everything that changes the path traced image, the accumulation starts over when any of it changes. The u32s are
+// LightList::generation and ObjectList::generation.
+type AccumulationKey = (Point3<f32>, Point3<f32>, CameraProjection, Environment, ReflectionSettings,
+                       PathTracingSettings, u32, u32, u32);
+
+// Everything created from the logical device. The acceleration structures and environment maps are created again
+// from the voxel grid, the objects and the maps' files when the device is lost, see RtRenderer::recover_device.
+struct DeviceResources {
     image_available_sems: Vec<vk::Semaphore>,
     render_finished_sems: Vec<vk::Semaphore>,
+    in_flight_fences: Vec<vk::Fence>,
     render_target: RenderTarget,
     command_pool: vk::CommandPool,
     command_buffers: Vec<vk::CommandBuffer>,
-    in_flight_fences: Vec<vk::Fence>,
-    current_frame: usize,
+    layout_cache: LayoutCache,
     descriptor_layouts: Vec<vk::DescriptorSetLayout>,
     rt_pipeline: RtPipeline,
-    descriptor_sets: Vec<vk::DescriptorSet>,
-    descriptor_pool: vk::DescriptorPool,
+    descriptor_allocator: DescriptorAllocator,
+    descriptor_sets: Vec<vk::DescriptorSet>, // Reallocated every frame
     canvas: RtCanvas,
+    analysis: RtAnalysis, // Debug overlays drawn into the canvas
     accel_instance: khr::AccelerationStructure,
     tlas: Vec<RtTlas>,
+    // The ObjectList::generation and voxel_generation each frame's TLAS was built with
+    tlas_generations: Vec<(u32, u32)>,
     blas: RtBlas,
+    cube_blas: RtBlas, // Instanced per block of the voxel world
+    rt_objects: RtObjects,
     per_frame_data: RtUniformBuffer<RtPerFrameUbo>,
+    address_table: ShaderAddressTable,
+    environment_maps: Vec<Texture>,
+    environment_placeholder: Texture, // Bound to the unused environment map slots
+    environment_sampler: vk::Sampler,
+    sampling: RtSampling,
+    upscaler: Option<Upscaler>, // Only when internal_resolution's filter is EdgeAdaptive and scales up
+    gpu_timer: GpuTimer,
+    post_process: PostProcessChain, // The ray generation shader writes its view depth
+    latency: LatencyGovernor
 }
 
-impl RtRenderer {
-    pub fn new(ev_loop: &EventLoop<()>) -> RtRenderer {
-        let required_extensions: Vec<CString> = Vec::from([
-            CString::from(vk::KhrSwapchainFn::NAME), // Equivalent to the Vulkan VK_KHR_SWAPCHAIN_EXTENSION_NAME
-            CString::from(vk::KhrRayTracingPipelineFn::NAME),
-            CString::from(vk::KhrAccelerationStructureFn::NAME),
-            CString::from(vk::KhrDeferredHostOperationsFn::NAME), // Required by VK_KHR_acceleration_structure
-            CString::from(vk::ExtBufferDeviceAddressFn::NAME)
-        ]);
-        let required_layers: Vec<String> = Vec::from([String::from("VK_LAYER_KHRONOS_validation")]);
-        let core = VkCore::new(ev_loop, &required_layers, &required_extensions);
-        let render_target = RenderTarget::new(&core,
-                                              // Apparently, B8G8R8A8_SRGB is incompatible with ImageUsageFlags::STORAGE
-                                              // Another special note: Even though the swap chain images are not used
-                                              // as render pass attachments, the COLOR_ATTACHMENT flag is needed for
-                                              // some reason.
-                                              vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::COLOR_ATTACHMENT,
-                                              vk::Format::B8G8R8A8_UNORM,
-                                              None);
-        let pool_create_info = vk::CommandPoolCreateInfo::default().flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+// The runtime settings DeviceResources are created with, they carry over a device loss
+struct DeviceOptions {
+    present_mode: vk::PresentModeKHR,
+    display_mode: DisplayMode,
+    internal_resolution: InternalResolution,
+    low_latency: bool,
+    motion_blur: MotionBlurSettings,
+    analysis: AnalysisSettings,
+    capturing: bool // The swap chain is shared with the capture's transfer queue, see start_capture
+}
+
+impl DeviceResources {
+    // Traces objects and environment_map_paths from the start, like add_object and load_environment_map
+    fn new(core: &VkCore, options: DeviceOptions, objects: &ObjectList, environment_map_paths: &[String])
+        -> DeviceResources {
+        // The swap chain is only ever a blit destination, so it can use an SRGB format even though those don't support
+        // STORAGE. The blit encodes the canvas' linear values, see CanvasFormat.
+        // Another special note: Even though the swap chain images are not used as render pass attachments, the
+        // COLOR_ATTACHMENT flag is needed for some reason.
+        let render_target = RenderTarget::new_with_display_mode(core, SWAPCHAIN_USAGE, SRGB_SWAPCHAIN_FORMAT,
+                                                                Some(SRGB_SWAPCHAIN_COLOR_SPACE),
+                                                                options.present_mode, options.display_mode,
+                                                                options.capturing);
+        let pool_create_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
             .queue_family_index(core.graphics_family_index);
         let command_pool = unsafe { core.logical_device.create_command_pool(&pool_create_info, None).unwrap() };
         let buf_create_info = vk::CommandBufferAllocateInfo::default()
             .command_pool(command_pool)
             .level(vk::CommandBufferLevel::PRIMARY)
             .command_buffer_count(MAX_FRAMES_IN_FLIGHT as u32);
-        let (image_available_sems, render_finished_sems, in_flight_fences) = setup_sync_objects(&core,
+        let (image_available_sems, render_finished_sems, in_flight_fences) = setup_sync_objects(core,
                                                                                                 MAX_FRAMES_IN_FLIGHT);
         let command_buffers = unsafe { core.logical_device.allocate_command_buffers(&buf_create_info).unwrap() };
-        let current_frame: usize = 0;
-        let descriptor_layouts = Vec::from([create_per_frame_descriptor_set_layout(&core)]);
+        let mut layout_cache = LayoutCache::new();
+        let descriptor_layouts = Vec::from([create_per_frame_descriptor_set_layout(core, &mut layout_cache)]);
             // create_singleton_descriptor_set_layout(&core)]);
-        let rt_pipeline = RtPipeline::new(&core, &descriptor_layouts);
-        let canvas = RtCanvas::new(&core, &render_target, MAX_FRAMES_IN_FLIGHT);
-        let (accel_instance, tlas, blas) = create_acceleration_structures(&core,
-                                                                         command_pool, MAX_FRAMES_IN_FLIGHT);
-        let per_frame_data = RtUniformBuffer::new(&core, MAX_FRAMES_IN_FLIGHT);
-        let (descriptor_sets, descriptor_pool) = create_per_frame_descriptor_sets(&core, &canvas, &tlas,
-                                                                                  //descriptor_layouts[0],
-                                                     &per_frame_data, descriptor_layouts[0],
-                                                                                  MAX_FRAMES_IN_FLIGHT);
+        let rt_pipeline = RtPipeline::new(core, &descriptor_layouts);
+        let render_extent = options.internal_resolution.extent(render_target.extent);
+        let canvas_format = CanvasFormat::select(core, render_target.surface_format).unwrap();
+        debug!(?canvas_format, "Canvas");
+        let canvas = RtCanvas::new(core, render_extent, canvas_format, MAX_FRAMES_IN_FLIGHT);
+        let upscaler = options.internal_resolution.needs_upscaler(render_target.extent)
+            .then(|| Upscaler::new(core, command_pool, &options.internal_resolution, render_target.extent));
+        let analysis = RtAnalysis::new(core, &canvas, options.analysis, MAX_FRAMES_IN_FLIGHT);
+        let (accel_instance, tlas, blas, cube_blas) = create_acceleration_structures(core, command_pool,
+                                                                                    MAX_FRAMES_IN_FLIGHT);
+        let per_frame_data = RtUniformBuffer::new(core, MAX_FRAMES_IN_FLIGHT);
+        // Hit shaders find the vertices of BLAS i at 2 * i and its indices at 2 * i + 1, see shader.rchit
+        let mut address_table = ShaderAddressTable::new(core, MAX_FRAMES_IN_FLIGHT, ADDRESS_TABLE_CAPACITY);
+        address_table.register(core, blas.vertex_buffer.as_ref().unwrap());
+        address_table.register_address(blas.index_buffer.as_ref().unwrap().get_device_address(core));
+        address_table.register(core, cube_blas.vertex_buffer.as_ref().unwrap());
+        address_table.register_address(cube_blas.index_buffer.as_ref().unwrap().get_device_address(core));
+        let mut rt_objects = RtObjects::new(MAX_FRAMES_IN_FLIGHT);
+        for (id, object) in objects.iter() {
+            if let Err(e) = rt_objects.add(core, &accel_instance, command_pool, &mut address_table, *id, object) {
+                warn!("{:?} is not traced: {}", id, e);
+            }
+        }
+        let gpu_timer = GpuTimer::new(core, MAX_FRAMES_IN_FLIGHT);
+        let latency = LatencyGovernor::new(core, options.low_latency);
+        let environment_maps = environment_map_paths.iter()
+            .map(|path| Texture::new_hdr(core, command_pool, path))
+            .collect();
+        let environment_placeholder = Texture::from_rgba32f(core, command_pool, 1, 1, &[0.0, 0.0, 0.0, 1.0]);
+        let environment_sampler = create_sampler(core, 1);
+        let sampling = RtSampling::new(core, command_pool);
+        let descriptor_allocator = DescriptorAllocator::new(MAX_FRAMES_IN_FLIGHT, &PER_FRAME_POOL_RATIOS);
+        let mut post_process = PostProcessChain::new(core, command_pool, render_extent, None, MAX_FRAMES_IN_FLIGHT);
+        post_process.set_motion_blur_settings(options.motion_blur);
 
-        RtRenderer {
-            core,
+        DeviceResources {
             image_available_sems,
             render_finished_sems,
+            in_flight_fences,
             render_target,
             command_pool,
             command_buffers,
-            in_flight_fences,
-            current_frame,
+            layout_cache,
             descriptor_layouts,
             rt_pipeline,
-            descriptor_sets,
-            descriptor_pool,
+            descriptor_allocator,
+            descriptor_sets: vec![vk::DescriptorSet::null(); MAX_FRAMES_IN_FLIGHT],
             canvas,
+            analysis,
             accel_instance,
             tlas,
+            tlas_generations: vec![(0, 0); MAX_FRAMES_IN_FLIGHT],
             blas,
-            per_frame_data
+            cube_blas,
+            rt_objects,
+            per_frame_data,
+            address_table,
+            environment_maps,
+            environment_placeholder,
+            environment_sampler,
+            sampling,
+            upscaler,
+            gpu_timer,
+            post_process,
+            latency
+        }
+    }
+
+    fn destroy_swap_chain(&self, core: &VkCore) {
+        self.render_target.destroy(core);
+        self.canvas.destroy(core);
+        if let Some(upscaler) = self.upscaler.as_ref() {
+            upscaler.destroy(core);
+        }
+    }
+
+    // Doesn't wait for the device, which may be lost
+    fn destroy(&self, core: &VkCore) {
+        self.destroy_swap_chain(core);
+        self.descriptor_allocator.destroy(core);
+        self.layout_cache.destroy(core);
+        for t in &self.tlas {
+            t.destroy(core, &self.accel_instance);
+        };
+        self.blas.destroy(core, &self.accel_instance);
+        self.cube_blas.destroy(core, &self.accel_instance);
+        self.rt_objects.destroy(core, &self.accel_instance);
+        unsafe {
+            for i in self.image_available_sems.iter() {
+                core.logical_device.destroy_semaphore(*i, None);
+            }
+            for r in self.render_finished_sems.iter() {
+                core.logical_device.destroy_semaphore(*r, None);
+            }
+            for f in self.in_flight_fences.iter() {
+                core.logical_device.destroy_fence(*f, None);
+            }
+            core.logical_device.destroy_command_pool(self.command_pool, None);
+        }
+        self.rt_pipeline.destroy(core);
+        self.per_frame_data.destroy(core);
+        self.address_table.destroy(core);
+        for m in self.environment_maps.iter() {
+            m.destroy(core);
+        }
+        self.environment_placeholder.destroy(core);
+        destroy_sampler(core, self.environment_sampler);
+        self.sampling.destroy(core);
+        self.post_process.destroy(core);
+        self.analysis.destroy(core);
+        self.gpu_timer.destroy(core);
+    }
+}
+
+pub struct RtRenderer {
+    core: VkCore, // Window, instance, devices and queues
+    device: DeviceResources,
+    current_frame: usize,
+    voxels: Option<VoxelWorld>, // Traced instead of the voxel grid, see set_voxel_world
+    voxel_generation: u32, // Changes with every change to voxels
+    chunk_streamer: Option<ChunkStreamer>, // Loads the voxel world around the camera, see set_chunk_streaming
+    block_editor: BlockEditor, // Clicks while mouse look is on edit the voxel world's blocks
+    visible_chunks: Option<HashSet<ChunkCoord>>, // The voxel chunks in the TLAS, see cull_voxel_chunks
+    objects: ObjectList, // Added with add_object
+    environment: EnvironmentTransition,
+    environment_map_paths: Vec<String>, // Loaded with load_environment_map, in the order of their indices
+    sky: SkySettings, // Last passed to set_sky
+    sun: Option<LightId>, // Added by set_sky
+    scene_lights: Vec<LightId>, // Added by load_scene
+    reflections: ReflectionSettings,
+    frame_index: u32, // Frames drawn, seeds the reflection sampling
+    // Like the objects, lights and emissive triangles outlive the device. Only their buffers are created again.
+    lights: LightList,
+    emissive: EmissiveTriangles, // Of the loaded scene
+    path_tracing: PathTracingSettings,
+    accumulated_frames: u32, // Frames averaged in the canvas' accumulation image
+    accumulation_key: Option<AccumulationKey>, // Of the last frame, None after the canvas was recreated
+    capture: Option<FrameCapture>,
+    present_mode: vk::PresentModeKHR,
+    display_mode: DisplayMode,
+    internal_resolution: InternalResolution, // The canvas' size, see render_extent
+    swapchain_recreate: SwapchainRecreate,
+    device_lost: DeviceLost,
+    camera_eye: Point3<f32>,
+    camera_target: Point3<f32>,
+    camera_projection: CameraProjection,
+    camera_focus_distance: f32, // Depth of field, see SceneCamera
+    camera_aperture: f32,
+    previous_view_projection: Option<Matrix4<f32>>, // Of the last drawn frame, for motion blur
+    held_keys: Vec<VirtualKeyCode>,
+    cursor: CursorCapture, // Mouse look while captured
+    last_cpu_ms: f64,
+    last_gpu_ms: Option<f64>,
+    last_frame_start: Instant,
+    clock: Clock, // Ticked at the start of every frame, by the capture's timestep while capturing
+    color_pipeline: ColorPipeline,
+    settings: RendererSettings,
+    commands: CommandQueue,
+    screenshots: VecDeque<(PathBuf, Sender<Result<PathBuf, String>>)>, // Taken one per frame
+    crash: CrashHandler,
+    limiter: FrameLimiter, // Waited on before every interactive and benchmark frame
+    idle: IdleTracker, // Skips interactive frames while idle, see RendererSettings::idle_behavior
+    stats_overlay: StatsOverlay,
+    #[cfg(feature = "physics")]
+    physics: Option<PhysicsWorld> // Stepped every frame, see set_physics
+}
+
+impl RtRenderer {
+    pub fn new(ev_loop: &EventLoop<()>) -> RtRenderer {
+        RtRenderer::new_with_config(ev_loop, &LaunchConfig::default())
+    }
+
+    // Uses the window size, validation and vsync settings of config, the scene and run mode are up to the caller
+    pub fn new_with_config(ev_loop: &EventLoop<()>, config: &LaunchConfig) -> RtRenderer {
+        let _span = info_span!("rt_renderer_init").entered();
+        let required_extensions: Vec<CString> = Vec::from([
+            CString::from(vk::KhrSwapchainFn::NAME), // Equivalent to the Vulkan VK_KHR_SWAPCHAIN_EXTENSION_NAME
+            CString::from(vk::KhrRayTracingPipelineFn::NAME),
+            CString::from(vk::KhrAccelerationStructureFn::NAME),
+            CString::from(vk::KhrDeferredHostOperationsFn::NAME) // Required by VK_KHR_acceleration_structure
+        ]);
+        let core = config.create_core(ev_loop, &required_extensions);
+        let crash = CrashHandler::install(&core, config.crash_report.clone(), CRASH_HISTORY_FRAMES);
+        let present_mode = config.present_mode();
+        let display_mode = config.display_mode.apply(&core);
+        let internal_resolution = config.internal_resolution();
+        let options = DeviceOptions {
+            present_mode,
+            display_mode,
+            internal_resolution,
+            low_latency: config.low_latency,
+            motion_blur: MotionBlurSettings {
+                enabled: config.motion_blur,
+                ..MotionBlurSettings::default()
+            },
+            analysis: AnalysisSettings::default(),
+            capturing: false
+        };
+        let device = DeviceResources::new(&core, options, &ObjectList::default(), &[]);
+        let lights = LightList::new(&core, MAX_FRAMES_IN_FLIGHT);
+        let emissive = EmissiveTriangles::new(&core, device.command_pool);
+        let max_bounces = device.rt_pipeline.max_bounces;
+        let reflections = ReflectionSettings {
+            max_bounces: config.reflection_bounces,
+            ..ReflectionSettings::default()
+        }.validated(max_bounces);
+        debug!(?reflections, device_max_bounces = max_bounces, "Reflections");
+        let path_tracing = PathTracingSettings {
+            enabled: config.path_tracing,
+            ..PathTracingSettings::default()
+        }.validated(max_bounces);
+        let (camera_eye, camera_target) = (Point3::new(-32.0, -32.0, 64.0), Point3::new(8.0, 8.0, 8.0));
+
+        RtRenderer {
+            core,
+            device,
+            current_frame: 0,
+            voxels: None,
+            voxel_generation: 0,
+            chunk_streamer: None,
+            block_editor: BlockEditor::default(),
+            visible_chunks: None,
+            objects: ObjectList::default(),
+            environment: EnvironmentTransition::new(Environment::default()),
+            environment_map_paths: Vec::new(),
+            sky: SkySettings::default(),
+            sun: None,
+            scene_lights: Vec::new(),
+            reflections,
+            frame_index: 0,
+            lights,
+            emissive,
+            path_tracing,
+            accumulated_frames: 0,
+            accumulation_key: None,
+            capture: None,
+            present_mode,
+            display_mode,
+            internal_resolution,
+            swapchain_recreate: SwapchainRecreate::default(),
+            device_lost: DeviceLost::default(),
+            camera_eye,
+            camera_target,
+            camera_projection: DEFAULT_PROJECTION,
+            camera_focus_distance: (camera_target - camera_eye).magnitude(),
+            camera_aperture: 0.0,
+            previous_view_projection: None,
+            held_keys: Vec::new(),
+            cursor: CursorCapture::default(),
+            last_cpu_ms: 0.0,
+            last_gpu_ms: None,
+            last_frame_start: Instant::now(),
+            clock: Clock::new(),
+            color_pipeline: ColorPipeline::default(),
+            settings: RendererSettings::from_config(config),
+            commands: CommandQueue::default(),
+            screenshots: VecDeque::new(),
+            crash,
+            limiter: FrameLimiter::new(config.max_fps),
+            idle: IdleTracker::default(),
+            stats_overlay: StatsOverlay::new("Cubulous (ray traced)"),
+            #[cfg(feature = "physics")]
+            physics: None
+        }
+    }
+
+    // Records every presented frame until stop_capture is called. Each recorded frame advances the simulation by
+    // exactly timestep, regardless of how long it took to render. The swap chain is shared with the transfer queue
+    // while capturing, so that frames don't need ownership transfers.
+    pub fn start_capture(&mut self, output: CaptureOutput, timestep: Duration) {
+        self.stop_capture();
+        self.capture = Some(FrameCapture::new(&self.core, self.device.render_target.extent,
+                                              self.device.render_target.surface_format, output, timestep));
+        self.swapchain_recreate.request();
+    }
+
+    // Takes the camera, the lights and the emissive models' triangles from the scene, the acceleration structures
+    // always hold the voxel grid. The lights replace the previous scene's, lights added otherwise stay.
+    pub fn load_scene(&mut self, scene: &Scene) {
+        if !scene.models.is_empty() {
+            warn!("Scene models are not ray traced yet, emissive ones only light the voxel grid when path tracing");
+        }
+        self.camera_eye = scene.camera.eye();
+        self.camera_target = scene.camera.target();
+        self.camera_projection = scene.camera.projection;
+        self.camera_focus_distance = scene.camera.focal_distance();
+        self.camera_aperture = scene.camera.aperture;
+        // Frames in flight may still read the old table
+        unsafe { self.core.logical_device.device_wait_idle().unwrap() };
+        self.emissive.destroy(&self.core);
+        self.emissive = EmissiveTriangles::from_scene(&self.core, self.device.command_pool, scene);
+        for id in self.scene_lights.drain(..) {
+            let _ = self.lights.remove(id); // Unless the caller removed it already
+        }
+        for light in scene.lights.iter() {
+            let samples = match *light {
+                SceneLight::Point { shadow_samples, .. } => shadow_samples,
+                SceneLight::Directional { .. } => 1
+            };
+            let added = self.lights.add(Light::from_scene(light))
+                .and_then(|id| {
+                    self.scene_lights.push(id);
+                    self.lights.set_samples(id, samples)
+                });
+            if let Err(e) = added {
+                warn!("A scene light is ignored: {}", e);
+            }
+        }
+        self.accumulation_key = None;
+    }
+
+    // Ray traced from the next frame until remove_object, with the voxel grid's albedo for now. Objects with the same
+    // mesh share its BLAS, each frame's TLAS is built again once the objects changed.
+    pub fn add_object(&mut self, mesh: Arc<Mesh>, transform: Matrix4<f32>, material: Material)
+        -> Result<ObjectId, String> {
+        let object = SceneObject::new(mesh, transform, material);
+        let id = self.objects.add(object.clone());
+        let device = &mut self.device;
+        if let Err(e) = device.rt_objects.add(&self.core, &device.accel_instance, device.command_pool,
+                                              &mut device.address_table, id, &object) {
+            let _ = self.objects.remove(id);
+            return Err(e);
+        }
+
+        Ok(id)
+    }
+
+    // The TLAS is built again for the next frame, restarting the accumulation like any change to the objects
+    pub fn set_object_transform(&mut self, id: ObjectId, transform: Matrix4<f32>) -> Result<(), String> {
+        self.objects.set_transform(id, transform)
+    }
+
+    // Only kept with the objects, the hit shaders don't shade with their materials
+    pub fn set_material(&mut self, material: Material) -> Result<(), String> {
+        if let Some(path) = material.texture.as_ref().filter(|p| !Path::new(p).is_file()) {
+            return Err(format!("{} not found", path));
+        }
+        self.objects.set_material(&material);
+
+        Ok(())
+    }
+
+    // Frames in flight may still trace it, its BLAS is destroyed once they finished
+    pub fn remove_object(&mut self, id: ObjectId) -> Result<(), String> {
+        self.objects.remove(id)?;
+        #[cfg(feature = "physics")]
+        if let Some(physics) = self.physics.as_mut() {
+            let _ = physics.remove_body(id); // Unless it had none
+        }
+        self.device.rt_objects.remove(&mut self.device.address_table, id)
+    }
+
+    // Traced instead of the voxel grid from the next frame, None brings the grid back. Every exposed block is an
+    // instance of the unit cube BLAS, whatever its palette mesh, so the TLAS is built again on every change.
+    pub fn set_voxel_world(&mut self, world: Option<VoxelWorld>) {
+        self.voxels = world;
+        self.voxel_generation = self.voxel_generation.wrapping_add(1);
+    }
+
+    pub fn voxel_world(&self) -> Option<&VoxelWorld> {
+        self.voxels.as_ref()
+    }
+
+    // Replaces a chunk of the voxel world, an empty chunk removes it
+    pub fn set_voxel_chunk(&mut self, coord: ChunkCoord, chunk: VoxelChunk) -> Result<(), String> {
+        let world = self.voxels.as_mut().ok_or_else(|| String::from("There is no voxel world"))?;
+        world.set_chunk(coord, chunk)?;
+        self.voxel_generation = self.voxel_generation.wrapping_add(1);
+
+        Ok(())
+    }
+
+    pub fn remove_voxel_chunk(&mut self, coord: ChunkCoord) -> Result<(), String> {
+        let world = self.voxels.as_mut().ok_or_else(|| String::from("There is no voxel world"))?;
+        world.remove_chunk(coord);
+        self.voxel_generation = self.voxel_generation.wrapping_add(1);
+
+        Ok(())
+    }
+
+    // Loads and unloads the voxel world's chunks around the camera every frame, None stops. The streamer assumes the
+    // chunks it loaded stay in the world, give set_voxel_world's new world a new streamer.
+    pub fn set_chunk_streaming(&mut self, streamer: Option<ChunkStreamer>) {
+        self.chunk_streamer = streamer;
+    }
+
+    pub fn chunk_streamer(&self) -> Option<&ChunkStreamer> {
+        self.chunk_streamer.as_ref()
+    }
+
+    // Any change bumps voxel_generation, every frame's TLAS is built again when its fence was waited on
+    fn stream_voxel_chunks(&mut self) {
+        if self.voxels.is_none() {
+            return;
+        }
+        let changes = match self.chunk_streamer.as_mut() {
+            Some(streamer) => streamer.update(self.camera_eye),
+            None => return
+        };
+        for coord in changes.unloaded {
+            let _ = self.remove_voxel_chunk(coord); // There is a world
+        }
+        for (coord, chunk) in changes.loaded {
+            if let Err(e) = self.set_voxel_chunk(coord, chunk) {
+                warn!(?coord, "Dropped a streamed chunk: {}", e);
+            }
+        }
+    }
+
+    // Clicks edit the block at the center of the image, where mouse look aims, see BlockEditor
+    pub fn block_editor_mut(&mut self) -> &mut BlockEditor {
+        &mut self.block_editor
+    }
+
+    // The TLAS of the frame about to be drawn is built again with the edited chunk's blocks
+    fn edit_blocks(&mut self) {
+        let ray = Ray::new(self.camera_eye, (self.camera_target - self.camera_eye).normalize());
+        for click in self.block_editor.take_clicks() {
+            let world = match self.voxels.as_ref() {
+                Some(world) => world,
+                None => continue
+            };
+            let edit = click.edit;
+            if let Some((coord, chunk)) = edit.apply(world, &ray, self.block_editor.reach) {
+                if let Err(e) = self.set_voxel_chunk(coord, chunk) {
+                    warn!(?edit, "The block isn't edited: {}", e);
+                }
+            }
+        }
+    }
+
+    // Chunks the camera can't see into, like caves under the ground, are left out of the TLAS. There is no frustum
+    // test, reflections and shadows trace rays out of the view. The TLAS is built again when the set changes.
+    fn cull_voxel_chunks(&mut self) {
+        let visible = self.voxels.as_ref().map(|world| {
+            visible_chunks(world, self.camera_eye, None, VISIBILITY_DISTANCE)
+        });
+        if visible != self.visible_chunks {
+            self.visible_chunks = visible;
+            self.voxel_generation = self.voxel_generation.wrapping_add(1);
+        }
+    }
+
+    // Stepped before every frame by the clock, bodies that moved set their object's transform. None stops the
+    // simulation.
+    #[cfg(feature = "physics")]
+    pub fn set_physics(&mut self, physics: Option<PhysicsWorld>) {
+        self.physics = physics;
+    }
+
+    #[cfg(feature = "physics")]
+    pub fn physics_mut(&mut self) -> Option<&mut PhysicsWorld> {
+        self.physics.as_mut()
+    }
+
+    // A body for an object at its current transform, see PhysicsWorld::add_body. Errors without set_physics.
+    #[cfg(feature = "physics")]
+    pub fn add_physics_body(&mut self, id: ObjectId, body_type: RigidBodyType, shape: ColliderShape)
+        -> Result<(), String> {
+        let object = self.objects.get(id).ok_or_else(|| format!("{:?} isn't an object", id))?;
+        self.physics.as_mut()
+            .ok_or_else(|| String::from("There is no physics world"))?
+            .add_body(id, object, body_type, shape)
+    }
+
+    #[cfg(feature = "physics")]
+    fn step_physics(&mut self) {
+        let physics = match self.physics.as_mut() {
+            Some(physics) => physics,
+            None => return
+        };
+        if physics.update(self.clock.delta()) == 0 {
+            return;
+        }
+        for (id, transform) in physics.moving_transforms() {
+            if let Err(e) = self.objects.set_transform(id, transform) {
+                warn!("Can't move {:?} with its body: {}", id, e);
+            }
+        }
+    }
+
+    // Applied from the next recorded frame
+    pub fn set_settings(&mut self, settings: RendererSettings) {
+        self.settings = settings;
+    }
+
+    pub fn settings(&self) -> RendererSettings {
+        self.settings
+    }
+
+    // Recreates the canvas at the new size, scaled to the window from the next frame
+    pub fn set_internal_resolution(&mut self, internal_resolution: InternalResolution) -> Result<(), String> {
+        self.internal_resolution = internal_resolution.validated()?;
+        self.recreate_swap_chain();
+
+        Ok(())
+    }
+
+    pub fn internal_resolution(&self) -> InternalResolution {
+        self.internal_resolution
+    }
+
+    // The size rays are traced at, the window's unless an internal resolution scales it
+    fn render_extent(&self) -> vk::Extent2D {
+        self.internal_resolution.extent(self.device.render_target.extent)
+    }
+
+    // Captured frames must not depend on the frames before them, so they keep the exposure manual
+    fn auto_exposure(&self) -> Option<AutoExposureSettings> {
+        self.settings.auto_exposure().filter(|_| self.capture.is_none())
+    }
+
+    // ColorPipeline::exposure, times the adapted exposure unless the exposure is manual
+    fn exposure(&self) -> f32 {
+        match self.auto_exposure() {
+            Some(_) => self.color_pipeline.exposure * self.device.post_process.adapted_exposure(self.current_frame),
+            None => self.color_pipeline.exposure
+        }
+    }
+
+    pub fn set_motion_blur_settings(&mut self, settings: MotionBlurSettings) {
+        self.device.post_process.set_motion_blur_settings(settings);
+    }
+
+    pub fn motion_blur_settings(&self) -> MotionBlurSettings {
+        self.device.post_process.motion_blur_settings()
+    }
+
+    // The histogram and variance overlays, see AnalysisSettings
+    pub fn set_analysis_settings(&mut self, settings: AnalysisSettings) {
+        self.device.analysis.set_settings(settings);
+    }
+
+    pub fn analysis_settings(&self) -> AnalysisSettings {
+        self.device.analysis.settings()
+    }
+
+    // Depth of field, aperture 0 turns it off. See SceneCamera.
+    pub fn set_camera_lens(&mut self, focus_distance: f32, aperture: f32) {
+        self.camera_focus_distance = focus_distance;
+        self.camera_aperture = aperture;
+    }
+
+    // Takes effect from the next frame, restarting the accumulation
+    pub fn set_projection(&mut self, projection: CameraProjection) -> Result<(), String> {
+        projection.validate()?;
+        self.camera_projection = projection;
+        self.previous_view_projection = None;
+
+        Ok(())
+    }
+
+    pub fn projection(&self) -> CameraProjection {
+        self.camera_projection
+    }
+
+    // Exposure, gamma and audit mode, applied from the next recorded frame
+    pub fn set_color_pipeline(&mut self, color_pipeline: ColorPipeline) {
+        self.color_pipeline = color_pipeline;
+    }
+
+    pub fn color_pipeline(&self) -> ColorPipeline {
+        self.color_pipeline
+    }
+
+    // Returns the settings actually used, the bounces are limited by the device's ray recursion depth
+    pub fn set_reflections(&mut self, reflections: ReflectionSettings) -> ReflectionSettings {
+        self.reflections = reflections.validated(self.device.rt_pipeline.max_bounces);
+        if self.reflections.max_bounces < reflections.max_bounces {
+            info!(requested = reflections.max_bounces, used = self.reflections.max_bounces,
+                  "Reflection bounces limited by the device");
+        }
+        self.reflections
+    }
+
+    pub fn reflections(&self) -> ReflectionSettings {
+        self.reflections
+    }
+
+    // Returns the settings actually used, like set_reflections. Any change restarts the accumulation.
+    pub fn set_path_tracing(&mut self, path_tracing: PathTracingSettings) -> PathTracingSettings {
+        self.path_tracing = path_tracing.validated(self.device.rt_pipeline.max_bounces);
+        if self.path_tracing.max_bounces < path_tracing.max_bounces {
+            info!(requested = path_tracing.max_bounces, used = self.path_tracing.max_bounces,
+                  "Path tracing bounces limited by the device");
+        }
+        self.path_tracing
+    }
+
+    pub fn path_tracing(&self) -> PathTracingSettings {
+        self.path_tracing
+    }
+
+    // Lights are only sampled when path tracing, the preview ignores them
+    pub fn add_light(&mut self, light: Light) -> Result<LightId, String> {
+        self.lights.add(light)
+    }
+
+    pub fn remove_light(&mut self, id: LightId) -> Result<(), String> {
+        self.lights.remove(id)
+    }
+
+    pub fn light(&self, id: LightId) -> Option<Light> {
+        self.lights.get(id)
+    }
+
+    // Shadow rays towards the light per sample, see LightList::set_samples
+    pub fn set_light_samples(&mut self, id: LightId, samples: u32) -> Result<(), String> {
+        self.lights.set_samples(id, samples)
+    }
+
+    // Fades to environment over the next frames
+    pub fn set_environment(&mut self, environment: Environment) -> Result<(), String> {
+        if let Environment::Map { index, .. } = environment {
+            if index as usize >= self.device.environment_maps.len() {
+                return Err(format!("Environment map {} isn't loaded, {} maps are", index,
+                                   self.device.environment_maps.len()));
+            }
+        }
+        self.environment.set(environment);
+        Ok(())
+    }
+
+    pub fn environment(&self) -> Environment {
+        self.environment.current()
+    }
+
+    // Switches to the sky's environment and moves its sun light, which the first call adds. Changing only the time of
+    // day doesn't fade, see EnvironmentTransition::set.
+    pub fn set_sky(&mut self, sky: SkySettings) -> Result<(), String> {
+        self.environment.set(Environment::from_sky(&sky));
+        self.sky = sky;
+        let light = Light::Directional {
+            direction: -sky.sun_direction(),
+            color: sky.sun_color() * sky.sun_intensity
+        };
+        match self.sun.filter(|id| self.lights.get(*id).is_some()) {
+            Some(id) => self.lights.set(id, light),
+            None => {
+                self.sun = Some(self.lights.add(light)?);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn sky(&self) -> SkySettings {
+        self.sky
+    }
+
+    // hours is the local solar time, see SkySettings
+    pub fn set_time_of_day(&mut self, hours: f32) -> Result<(), String> {
+        self.set_sky(SkySettings { time_of_day: hours, ..self.sky })
+    }
+
+    // Loads an equirectangular HDR image for Environment::Map and returns its index
+    pub fn load_environment_map(&mut self, path: &str) -> Result<u32, String> {
+        if self.device.environment_maps.len() >= MAX_ENVIRONMENT_MAPS {
+            return Err(format!("At most {} environment maps can be loaded", MAX_ENVIRONMENT_MAPS));
+        }
+        if !Path::new(path).exists() {
+            return Err(format!("Environment map {} not found", path));
+        }
+        self.device.environment_maps.push(Texture::new_hdr(&self.core, self.device.command_pool, path));
+        self.environment_map_paths.push(String::from(path));
+        info!(path, index = self.device.environment_maps.len() - 1, "Loaded environment map");
+
+        Ok((self.device.environment_maps.len() - 1) as u32)
+    }
+
+    fn environment_map_infos(&self) -> [vk::DescriptorImageInfo; MAX_ENVIRONMENT_MAPS] {
+        let mut infos = [vk::DescriptorImageInfo::default(); MAX_ENVIRONMENT_MAPS];
+        for (i, info) in infos.iter_mut().enumerate() {
+            *info = info
+                .sampler(self.device.environment_sampler)
+                .image_view(self.device.environment_maps.get(i).unwrap_or(&self.device.environment_placeholder).view)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL);
+        }
+
+        infos
+    }
+
+    // Takes effect from the next frame, returns the mode actually used
+    pub fn set_display_mode(&mut self, display_mode: DisplayMode) -> DisplayMode {
+        self.display_mode = display_mode.apply(&self.core);
+        self.swapchain_recreate.request();
+        self.display_mode
+    }
+
+    // Throttles frame submission to the display, see LatencyGovernor. Only the interactive loop throttles, benchmarks
+    // and replays run as fast as possible.
+    pub fn set_low_latency(&mut self, enabled: bool) {
+        self.device.latency.set_enabled(enabled);
+    }
+
+    pub fn low_latency(&self) -> bool {
+        self.device.latency.is_enabled()
+    }
+
+    // Caps the frame rate on the CPU, whatever the present mode, see FrameLimiter
+    pub fn set_max_fps(&mut self, max_fps: Option<f32>) {
+        self.limiter.set_max_fps(max_fps);
+    }
+
+    pub fn max_fps(&self) -> Option<f32> {
+        self.limiter.max_fps()
+    }
+
+    // Animation time, pause it to look at a single frame and step it one frame at a time
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    // Called after every swap chain recreate, for anything that has to follow the swap chain's size
+    pub fn add_swapchain_callback(&mut self, callback: SwapchainCallback) {
+        self.swapchain_recreate.add_callback(callback);
+    }
+
+    // Called after the renderer recovered from a lost device, see DeviceLost
+    pub fn add_device_lost_callback(&mut self, callback: DeviceLostCallback) {
+        self.device_lost.add_callback(callback);
+    }
+
+    // For other threads, commands sent through it are processed at the start of every frame
+    pub fn proxy(&self) -> RendererProxy {
+        self.commands.proxy()
+    }
+
+    // Like load_scene, without touching the emissive triangles
+    pub fn set_camera(&mut self, camera: SceneCamera) {
+        self.camera_eye = camera.eye();
+        self.camera_target = camera.target();
+        self.camera_projection = camera.projection;
+        self.camera_focus_distance = camera.focal_distance();
+        self.camera_aperture = camera.aperture;
+        self.previous_view_projection = None; // Motion blur would smear the jump
+    }
+
+    pub fn camera(&self) -> SceneCamera {
+        SceneCamera {
+            eye: self.camera_eye.into(),
+            target: self.camera_target.into(),
+            up: [0.0, 0.0, 1.0], // See camera_view
+            projection: self.camera_projection,
+            focus_distance: Some(self.camera_focus_distance),
+            aperture: self.camera_aperture
+        }
+    }
+
+    // Moves the camera so that the object fills the view, keeping the view direction. See SceneCamera::framing for
+    // margin.
+    pub fn frame_object(&mut self, id: ObjectId, margin: f32) -> Result<(), String> {
+        let sphere = self.objects.get(id)
+            .ok_or_else(|| format!("{:?} doesn't exist", id))?
+            .world_bounding_sphere();
+        self.frame_sphere(&sphere, margin)
+    }
+
+    // Like frame_object, for all objects. Without any, frames the voxel grid's first chunk instead of the whole grid,
+    // which stretches far along y.
+    pub fn frame_scene(&mut self, margin: f32) -> Result<(), String> {
+        let sphere = match self.objects.is_empty() {
+            true => BoundingSphere::from_aabb(&grid_chunk_bounds()),
+            false => self.objects.bounding_sphere()
+        };
+        self.frame_sphere(&sphere, margin)
+    }
+
+    fn frame_sphere(&mut self, sphere: &BoundingSphere, margin: f32) -> Result<(), String> {
+        if sphere.is_empty() {
+            return Err(String::from("There is nothing to frame"));
+        }
+        let camera = self.camera().framing(sphere, self.render_extent(), margin);
+        self.set_camera(camera);
+
+        Ok(())
+    }
+
+    pub fn set_setting(&mut self, setting: RendererSetting) {
+        match setting {
+            RendererSetting::Renderer(settings) => self.set_settings(settings),
+            RendererSetting::ColorPipeline(color_pipeline) => self.set_color_pipeline(color_pipeline),
+            RendererSetting::MotionBlur(settings) => self.set_motion_blur_settings(settings),
+            RendererSetting::LowLatency(enabled) => self.set_low_latency(enabled),
+            RendererSetting::MaxFps(max_fps) => self.set_max_fps(max_fps),
+            RendererSetting::DisplayMode(display_mode) => {
+                self.set_display_mode(display_mode);
+            },
+            RendererSetting::Sky(sky) => {
+                if let Err(e) = self.set_sky(sky) {
+                    warn!("The sun light is ignored: {}", e);
+                }
+            },
+            RendererSetting::Grid(_) => warn!("The ray traced renderer draws no ground grid"),
+            RendererSetting::Outline(_) => warn!("The ray traced renderer draws no selection outline"),
+            RendererSetting::Projection(projection) => {
+                if let Err(e) = self.set_projection(projection) {
+                    warn!("The projection is ignored: {}", e);
+                }
+            },
+            RendererSetting::Paused(paused) => self.clock.set_paused(paused),
+            RendererSetting::TimeScale(time_scale) => self.clock.set_time_scale(time_scale)
+        }
+    }
+
+    // Returns how many commands were processed
+    fn process_commands(&mut self) -> usize {
+        let commands = self.commands.drain();
+        let count = commands.len();
+        for command in commands {
+            match command {
+                // The acceleration structures only hold the voxel grid, see load_scene
+                RendererCommand::LoadModel { path, reply, .. } => {
+                    let _ = reply.send(Err(format!("Can't load {}, models are not ray traced yet", path)));
+                },
+                RendererCommand::SetCamera(camera) => self.set_camera(camera),
+                RendererCommand::FrameScene { margin } => {
+                    if let Err(e) = self.frame_scene(margin) {
+                        warn!("The camera is not moved: {}", e);
+                    }
+                },
+                RendererCommand::Screenshot { path, reply } => match check_screenshot_path(&path) {
+                    Ok(_) => self.screenshots.push_back((path, reply)),
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                },
+                RendererCommand::SetSetting(setting) => self.set_setting(setting),
+                RendererCommand::StepFrame => self.clock.step(),
+                RendererCommand::SetViewports(_) => warn!("The ray traced renderer only draws a single viewport"),
+                RendererCommand::SetMaterial { material, reply } => {
+                    let _ = reply.send(self.set_material(material));
+                }
+            }
+        }
+
+        count
+    }
+
+    // Waits for the copy, screenshots are rare enough for the stall not to matter
+    fn finish_screenshot(&self, mut capture: FrameCapture, path: PathBuf, reply: Sender<Result<PathBuf, String>>) {
+        let result = match unsafe { self.core.logical_device.device_wait_idle() } {
+            Ok(_) => {
+                capture.destroy(&self.core);
+                Ok(path)
+            },
+            Err(e) => Err(format!("Screenshot failed: {:?}", e)) // The capture goes with the lost device
+        };
+        let _ = reply.send(result);
+    }
+
+    // On a lost device the frames still being copied are dropped
+    pub fn stop_capture(&mut self) {
+        if let Some(mut capture) = self.capture.take() {
+            self.swapchain_recreate.request();
+            match unsafe { self.core.logical_device.device_wait_idle() } {
+                Ok(_) => capture.destroy(&self.core),
+                Err(e) => {
+                    warn!("The capture stopped without its last frames: {:?}", e);
+                    capture.release(&self.core);
+                }
+            }
         }
     }
 
     fn record_command_buffer(&self, image_index: u32) {
         let logical_device = &self.core.logical_device;
         let begin_info = vk::CommandBufferBeginInfo::default();
-        let command_buffer = *self.command_buffers.get(self.current_frame).unwrap();
+        let command_buffer = *self.device.command_buffers.get(self.current_frame).unwrap();
         let ray_instances = khr::RayTracingPipeline::new(&self.core.instance, logical_device);
-        let present_image = unsafe { *self.render_target.swap_loader.get_swapchain_images(self.render_target
-            .swap_chain).unwrap().get(image_index as usize).unwrap() };
-        let canvas_image = *self.canvas.images.get(self.current_frame).unwrap();
+        let render_target = &self.device.render_target;
+        let present_image = unsafe { *render_target.swap_loader.get_swapchain_images(render_target.swap_chain)
+            .unwrap().get(image_index as usize).unwrap() };
+        let canvas_image = *self.device.canvas.images.get(self.current_frame).unwrap();
+        let post_frame = PostFrame {
+            projection: self.camera_projection.matrix(self.render_extent()),
+            view: camera_view(self.camera_eye, self.camera_target),
+            // Captured frames must not depend on how fast the camera moved before them
+            previous_view_projection: self.previous_view_projection.filter(|_| self.capture.is_none()),
+            focus_distance: self.camera_focus_distance,
+            aperture: self.camera_aperture,
+            exposure: self.auto_exposure().map(|settings| ExposureMetering {
+                settings,
+                exposure: self.exposure(),
+                gamma: self.color_pipeline.gamma,
+                delta_time: self.last_frame_start.elapsed().as_secs_f32()
+            }),
+            frame: self.current_frame
+        };
+        // The blit to an SRGB swap chain encodes, otherwise the shader has to
+        let color_pipeline = ColorPipeline { exposure: self.exposure(), ..self.color_pipeline };
+        let color_constants = [color_pipeline.constants(self.device.canvas.format
+            .output_format(render_target.surface_format))];
+        // The accumulation image holds this frame too once the rays were traced
+        let analysed_frames = match self.path_tracing.enabled {
+            true => self.accumulated_frames + 1,
+            false => 0
+        };
 
         let subresource_range = vk::ImageSubresourceRange::default()
             .base_mip_level(0)
@@ -144,6 +1095,19 @@ impl RtRenderer {
             .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
             .src_queue_family_index(self.core.graphics_family_index)
             .dst_queue_family_index(self.core.graphics_family_index);
+        // Contents only matter while frames are being averaged
+        let accumulation_barrier = vk::ImageMemoryBarrier::default()
+            .image(self.device.canvas.accumulation)
+            .subresource_range(subresource_range)
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE)
+            .old_layout(match self.accumulated_frames {
+                0 => vk::ImageLayout::UNDEFINED,
+                _ => vk::ImageLayout::GENERAL
+            })
+            .new_layout(vk::ImageLayout::GENERAL)
+            .src_queue_family_index(self.core.graphics_family_index)
+            .dst_queue_family_index(self.core.graphics_family_index);
         let canvas_image_to_src_barrier = vk::ImageMemoryBarrier::default()
             .image(canvas_image)
             .subresource_range(subresource_range)
@@ -162,209 +1126,693 @@ impl RtRenderer {
             .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
             .src_queue_family_index(self.core.graphics_family_index)
             .dst_queue_family_index(self.core.graphics_family_index);
-        let blit_subresource = vk::ImageSubresourceLayers::default()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
-            .base_array_layer(0)
-            .mip_level(0)
-            .layer_count(1);
-        let blit_offsets = [vk::Offset3D::default().x(0).y(0).z(0), vk::Offset3D::default().x(self.render_target
-            .extent.width as i32).y(self.render_target.extent.height as i32).z(1)];
-        let blit_region = vk::ImageBlit::default()
-            .src_subresource(blit_subresource)
-            .dst_subresource(blit_subresource)
-            .src_offsets(blit_offsets)
-            .dst_offsets(blit_offsets);
+        let render_extent = self.render_extent();
 
         unsafe {
             logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
-            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR, self.rt_pipeline
-                .pipelines[0]);
-            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR, self
-                .rt_pipeline.pipeline_layout, 0, &[self.descriptor_sets[self.current_frame]], &[]);
-            logical_device.cmd_push_constants(command_buffer, self.rt_pipeline.pipeline_layout,
-                                              vk::ShaderStageFlags::MISS_KHR,
-                                              0, cast_to_u8_slice(&CLEAR_COLOR));
+            self.device.gpu_timer.cmd_begin(&self.core, command_buffer, self.current_frame);
+            logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR,
+                                             self.device.rt_pipeline.pipelines[0]);
+            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR,
+                                                    self.device.rt_pipeline.pipeline_layout, 0,
+                                                    &[self.device.descriptor_sets[self.current_frame]], &[]);
+            logical_device.cmd_push_constants(command_buffer, self.device.rt_pipeline.pipeline_layout,
+                                              vk::ShaderStageFlags::RAYGEN_KHR,
+                                              RT_COLOR_CONSTANTS_OFFSET, cast_to_u8_slice(&color_constants));
             logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
                                                 vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(),
-                                                &[], &[], &[canvas_image_to_dst_barrier]);
-            ray_instances.cmd_trace_rays(command_buffer, &self.rt_pipeline.raygen_addr_region,
-                                         &self.rt_pipeline.raymiss_addr_region,
-                                         &self.rt_pipeline.rayhit_addr_region,
-                                         &self.rt_pipeline.raycallable_addr_region,
-                                         self.render_target.extent.width, self.render_target.extent.height, 1);
+                                                &[], &[], &[canvas_image_to_dst_barrier, accumulation_barrier]);
+            ray_instances.cmd_trace_rays(command_buffer, &self.device.rt_pipeline.raygen_addr_region,
+                                         &self.device.rt_pipeline.raymiss_addr_region,
+                                         &self.device.rt_pipeline.rayhit_addr_region,
+                                         &self.device.rt_pipeline.raycallable_addr_region,
+                                         render_extent.width, render_extent.height, 1);
+            self.device.analysis.cmd_apply(&self.core, command_buffer, self.current_frame, render_extent,
+                                           &color_pipeline, analysed_frames);
             logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
                                                 vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(),
                                                 &[], &[], &[canvas_image_to_src_barrier]);
             logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
                                                 vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(),
                                                 &[], &[], &[present_to_dst_barrier]);
-            logical_device.cmd_blit_image(command_buffer, canvas_image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                                          present_image, vk::ImageLayout::TRANSFER_DST_OPTIMAL, &[blit_region],
-                                          vk::Filter::NEAREST);
+            // The post-process chain ends in the same blit to the swap chain image, scaled to the window unless the
+            // internal resolution is the window's. Overlays show the traced pixels. The upscaler takes the place of
+            // the blit, reading the chain's output or the canvas.
+            let post_process = self.device.post_process.is_active(&post_frame) && !self.device.analysis.is_active();
+            if post_process {
+                self.device.post_process.cmd_copy_in(&self.core, command_buffer, canvas_image,
+                                                     vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+                self.device.post_process.cmd_apply(&self.core, command_buffer, &post_frame);
+            }
+            if let Some(upscaler) = self.device.upscaler.as_ref() {
+                match post_process {
+                    true => self.device.post_process.cmd_copy_out(&self.core, command_buffer,
+                                                                  upscaler.input_image(), vk::ImageLayout::GENERAL),
+                    false => upscaler.cmd_copy_in(&self.core, command_buffer, canvas_image,
+                                                  vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                }
+                upscaler.cmd_apply(&self.core, command_buffer);
+                upscaler.cmd_copy_out(&self.core, command_buffer, present_image,
+                                      vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+            } else if post_process {
+                self.device.post_process.cmd_scale_out(&self.core, command_buffer, present_image,
+                                                       vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                                       &self.internal_resolution, render_target.extent);
+            } else {
+                let target = BlitImage {
+                    image: present_image,
+                    layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    extent: render_target.extent
+                };
+                self.internal_resolution.cmd_blit(&self.core, command_buffer, canvas_image,
+                                                  vk::ImageLayout::TRANSFER_SRC_OPTIMAL, &target);
+            }
             logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::ALL_COMMANDS,
                                                 vk::PipelineStageFlags::ALL_COMMANDS, vk::DependencyFlags::empty(),
                                                 &[], &[], &[present_to_present_barrier]);
+            self.device.gpu_timer.cmd_end(&self.core, command_buffer, self.current_frame);
             logical_device.end_command_buffer(command_buffer).unwrap();
         }
     }
 
     fn recreate_swap_chain(&mut self) {
+        debug!(present_mode = ?self.present_mode, "Recreating swap chain");
         self.cleanup_swap_chain();
-        self.render_target = RenderTarget::new(&self.core, vk::ImageUsageFlags::TRANSFER_DST,
-                                               vk::Format::B8G8R8A8_UNORM, None);
-        self.canvas = RtCanvas::new(&self.core, &self.render_target, MAX_FRAMES_IN_FLIGHT);
+        self.device.render_target = RenderTarget::new_with_display_mode(&self.core, SWAPCHAIN_USAGE,
+                                                                        SRGB_SWAPCHAIN_FORMAT,
+                                                                        Some(SRGB_SWAPCHAIN_COLOR_SPACE),
+                                                                        self.present_mode, self.display_mode,
+                                                                        self.capture.is_some());
+        self.device.canvas = RtCanvas::new(&self.core, self.render_extent(), self.device.canvas.format,
+                                           MAX_FRAMES_IN_FLIGHT);
+        self.device.analysis.write_descriptor_sets(&self.core, &self.device.canvas);
+        self.device.post_process.resize(&self.core, self.device.command_pool, self.render_extent(), None);
+        self.device.upscaler = self.internal_resolution.needs_upscaler(self.device.render_target.extent)
+            .then(|| Upscaler::new(&self.core, self.device.command_pool, &self.internal_resolution,
+                                   self.device.render_target.extent));
+        self.previous_view_projection = None; // The aspect ratio may have changed
+        self.accumulation_key = None;
+        if let Some(capture) = self.capture.as_mut() {
+            capture.resize(&self.core, self.device.render_target.extent);
+        }
+        self.device.latency.reset();
+        self.swapchain_recreate.finish(&self.core, &self.device.render_target);
     }
 
     fn cleanup_swap_chain(&self) {
         unsafe { self.core.logical_device.device_wait_idle().unwrap() };
-        self.render_target.destroy(&self.core);
-        self.canvas.destroy(&self.core);
+        self.device.destroy_swap_chain(&self.core);
+    }
+
+    // Nothing on the lost device can be waited on, so everything is destroyed as is. Only the window, instance and
+    // surface of the core stay. The objects, lights, environment maps and runtime settings carry over, a running
+    // capture loses the frames still being copied.
+    fn recover_device(&mut self) {
+        let _span = info_span!("device_recovery").entered();
+        self.device_lost.begin_recovery();
+        let options = DeviceOptions {
+            present_mode: self.present_mode,
+            display_mode: self.display_mode,
+            internal_resolution: self.internal_resolution,
+            low_latency: self.device.latency.is_enabled(),
+            motion_blur: self.device.post_process.motion_blur_settings(),
+            analysis: self.device.analysis.settings(),
+            capturing: self.capture.is_some()
+        };
+        if let Some(capture) = self.capture.as_mut() {
+            capture.release(&self.core);
+        }
+        self.lights.destroy(&self.core);
+        self.emissive.destroy(&self.core);
+        self.device.destroy(&self.core);
+        self.core.recreate_device();
+        self.crash.rearm(&self.core);
+        self.device = DeviceResources::new(&self.core, options, &self.objects, &self.environment_map_paths);
+        self.lights.recreate(&self.core);
+        self.emissive.recreate(&self.core, self.device.command_pool);
+        if let Some(capture) = self.capture.as_mut() {
+            capture.recreate(&self.core, self.device.render_target.extent);
+        }
+        self.current_frame = 0;
+        self.previous_view_projection = None;
+        self.accumulation_key = None;
+        self.device_lost.finish(&self.core);
+    }
+
+    // The per frame UBO of the frame about to be drawn
+    fn per_frame_ubo(&self) -> [RtPerFrameUbo; 1] {
+        let inverse_proj = self.camera_projection.matrix(self.render_extent()).inverse_transform().unwrap();
+        let (environment, mut environment_blend) = self.environment.ubo();
+        environment_blend.y = self.clock.seconds();
+        let path_tracing = &self.path_tracing;
+        let max_bounces = match path_tracing.enabled {
+            true => path_tracing.max_bounces,
+            false => self.reflections.max_bounces
+        };
+        // Accumulated frames continue the Sobol sequence where the previous frame stopped
+        let first_sample = match path_tracing.enabled {
+            true => self.accumulated_frames.wrapping_mul(path_tracing.samples_per_pixel),
+            false => self.frame_index
+        };
+        [RtPerFrameUbo {
+            inverse_view: camera_view(self.camera_eye, self.camera_target).inverse_transform().unwrap(),
+            inverse_proj,
+            environment,
+            environment_blend,
+            reflection: Vector4::new(self.reflections.reflectivity, self.reflections.roughness, 0.0, 0.0),
+            ray_settings: Vector4::new(max_bounces, self.frame_index, 0, 0),
+            path_settings: Vector4::new(path_tracing.enabled as u32, path_tracing.samples_per_pixel,
+                                        path_tracing.rr_start_depth, self.accumulated_frames),
+            firefly_clamp: Vector4::new(path_tracing.firefly_clamp, 0.0, 0.0, 0.0),
+            sampling: Vector4::new(RtSampling::frame_seed(self.frame_index), first_sample, 0, 0),
+            lights: Vector4::new(self.lights.len() as u32, self.emissive.len(), 0, 0)
+        }]
     }
 
     fn draw_frame(&mut self) {
-        fn build_transforms(render_target: &RenderTarget) -> [RtPerFrameUbo; 1] {
-            // let current_time = Instant::now();
-            // let time = current_time.duration_since(self.start_time).as_millis() as f32 / 1000.0;
-            // let time = 0.0;
-
-            let mut perspective = perspective(Deg(45.0),
-                                              (render_target.extent.width as f32) /
-                                                  (render_target.extent.height as f32),
-                                              0.1, 10.0).inverse_transform().unwrap();
-            perspective.y.y *= -1.0;
-            [RtPerFrameUbo {
-                inverse_view: Matrix4::look_at_rh(Point3::new(-32.0, -32.0, 64.0),
-                                                  Point3::new(8.0, 8.0, 8.0),
-                                                  Vector3::new(0.0, 0.0, 1.0)).inverse_transform().unwrap(),
-                inverse_proj: perspective
-            }]
+        self.process_commands();
+        match self.capture.as_ref() {
+            Some(capture) => self.clock.tick_by(capture.timestep),
+            None => self.clock.tick()
+        };
+        #[cfg(feature = "physics")]
+        self.step_physics();
+        // A minimized window has a zero sized surface, nothing can be presented until it is restored
+        let size = self.window_size();
+        if size.width == 0 || size.height == 0 {
+            return;
         }
+        if self.device_lost.is_lost() {
+            self.recover_device();
+        }
+        if self.swapchain_recreate.is_pending() {
+            self.recreate_swap_chain();
+        }
+        self.stream_voxel_chunks();
+        self.edit_blocks();
+        self.cull_voxel_chunks();
 
+        let frame_start = Instant::now();
         let logical_device = &self.core.logical_device;
         let graphics_queue = self.core.graphics_queue;
         let present_queue = self.core.present_queue;
         let current_frame = self.current_frame;
 
-        let fences = [*self.in_flight_fences.get(current_frame)
+        let fences = [*self.device.in_flight_fences.get(current_frame)
             .unwrap()];
         let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let wait_sems = [*self.image_available_sems.get(current_frame).unwrap()];
-        let command_buffers = [*self.command_buffers.get(current_frame).unwrap()];
-        let sig_sems = [*self.render_finished_sems.get(current_frame).unwrap()];
+        let wait_sems = [*self.device.image_available_sems.get(current_frame).unwrap()];
+        let command_buffers = [*self.device.command_buffers.get(current_frame).unwrap()];
+        let sig_sems = [*self.device.render_finished_sems.get(current_frame).unwrap()];
         let submit_info = vk::SubmitInfo::default()
             .wait_semaphores(&wait_sems)
             .wait_dst_stage_mask(&wait_stages)
             .command_buffers(&command_buffers)
             .signal_semaphores(&sig_sems);
         let submit_array = [submit_info];
-        let swap_chains = [self.render_target.swap_chain];
+        let swap_chains = [self.device.render_target.swap_chain];
 
-        let transform_matrix = build_transforms(&self.render_target);
-        self.per_frame_data.set_mapped(&transform_matrix, self.current_frame);
+        // A running environment fade changes the image every frame
+        let accumulation_key = Some((self.camera_eye, self.camera_target, self.camera_projection,
+                                     self.environment.current(), self.reflections, self.path_tracing,
+                                     self.lights.generation(), self.objects.generation(), self.voxel_generation));
+        if !self.path_tracing.enabled || accumulation_key != self.accumulation_key || !self.environment.is_finished() {
+            self.accumulated_frames = 0;
+        }
+        self.accumulation_key = accumulation_key;
+        let transform_matrix = self.per_frame_ubo();
+        self.device.per_frame_data.set_mapped(&transform_matrix, self.current_frame);
+        self.environment.advance();
+        self.frame_index = self.frame_index.wrapping_add(1);
 
+        let wait_time: Duration;
         unsafe {
-            logical_device.wait_for_fences(&fences, true, u64::MAX).unwrap();
-
-            let (next_image_idx, _) = match self.render_target.swap_loader.acquire_next_image(self.render_target.swap_chain,
-                                                                                              u64::MAX, *self.image_available_sems
-                    .get(current_frame).unwrap(), vk::Fence::null()) {
-                Ok(img_idx) => img_idx,
-                Err(result) => match result {
-                    vk::Result::ERROR_OUT_OF_DATE_KHR => { self.recreate_swap_chain(); return },
-                    _ => panic!("Unknown error at acquire_next_image")
+            let wait_start = Instant::now();
+            let wait_result = logical_device.wait_for_fences(&fences, true, u64::MAX);
+            if self.device_lost.check(wait_result, "wait_for_fences").is_none() {
+                return;
+            }
+            wait_time = wait_start.elapsed();
+            // The fence covers the last submission that used this frame's queries
+            self.last_gpu_ms = self.device.gpu_timer.frame_time_ms(&self.core, current_frame);
+            // The voxel grid's BLAS comes first, then the cube's, then the objects'
+            let generations = (self.objects.generation(), self.voxel_generation);
+            if self.device.tlas_generations[current_frame] != generations {
+                let (object_blas, object_instances) = self.device.rt_objects.tlas_inputs(2, &self.objects);
+                let blas: Vec<&RtBlas> = [&self.device.blas, &self.device.cube_blas].into_iter().chain(object_blas)
+                    .collect();
+                let blocks = match self.voxels.as_ref() {
+                    Some(world) => voxel_instances(world, self.visible_chunks.as_ref(), 1, 1),
+                    None => grid_instances()
+                };
+                let instances: Vec<RtPerInstanceData> = blocks.into_iter().chain(object_instances).collect();
+                let tlas = RtAccel::new_tlas(&self.core, &self.device.accel_instance, self.device.command_pool,
+                                             blas.as_slice(), instances.as_slice());
+                self.device.tlas[current_frame].destroy(&self.core, &self.device.accel_instance);
+                self.device.tlas[current_frame] = tlas;
+                self.device.tlas_generations[current_frame] = generations;
+                for (id, bounds) in self.device.rt_objects.instance_bounds(&self.objects) {
+                    trace!(?id, min = ?bounds.min, max = ?bounds.max, "TLAS instance bounds");
                 }
+            }
+            self.device.address_table.update(current_frame);
+            self.lights.update(current_frame);
+            self.device.descriptor_allocator.reset_frame(&self.core, current_frame);
+            self.device.descriptor_sets[current_frame] = self.device.descriptor_allocator
+                .allocate(&self.core, current_frame, self.device.descriptor_layouts[0]);
+            write_per_frame_descriptor_set(&self.core, self.device.descriptor_sets[current_frame],
+                                           &PerFrameBindings {
+                                               canvas: &self.device.canvas,
+                                               tlas: &self.device.tlas,
+                                               per_frame_data: &self.device.per_frame_data,
+                                               address_table: &self.device.address_table,
+                                               environment_maps: &self.environment_map_infos(),
+                                               sampling: &self.device.sampling,
+                                               lights: &self.lights,
+                                               emissive: &self.emissive,
+                                               view_depth: self.device.post_process.view_depth_info()
+                                           }, current_frame);
+
+            let render_target = &self.device.render_target;
+            let acquire_result = render_target.swap_loader.acquire_next_image(render_target.swap_chain, u64::MAX,
+                                                                              wait_sems[0], vk::Fence::null());
+            if self.device_lost.detect(&acquire_result, "acquire_next_image") {
+                return;
+            }
+            // An out of date swap chain is recreated at the start of the next frame, the fence is still signaled
+            let next_image_idx = match self.swapchain_recreate.check_acquire(acquire_result) {
+                Some(idx) => idx,
+                None => return
             };
 
             logical_device.reset_fences(&fences).unwrap();
 
-            let image_indices = [next_image_idx];
-            let present_info = vk::PresentInfoKHR::default()
-                .wait_semaphores(&sig_sems)
-                .swapchains(&swap_chains)
-                .image_indices(&image_indices);
-            logical_device.reset_command_buffer(*self.command_buffers
+            logical_device.reset_command_buffer(*self.device.command_buffers
                 .get(self.current_frame)
                 .unwrap(), vk::CommandBufferResetFlags::empty()).unwrap();
             self.record_command_buffer(next_image_idx);
-            logical_device.queue_submit(graphics_queue, &submit_array,
-                                        *self.in_flight_fences
-                                            .get(self.current_frame).unwrap()).unwrap();
+            let submit_result = logical_device.queue_submit(graphics_queue, &submit_array,
+                                                            *self.device.in_flight_fences
+                                                                .get(self.current_frame).unwrap());
+            if self.device_lost.check(submit_result, "queue_submit").is_none() {
+                return;
+            }
+            self.device.rt_objects.end_frame(&self.core, &self.device.accel_instance);
+            self.previous_view_projection = Some(self.camera_projection.matrix(self.render_extent()) *
+                camera_view(self.camera_eye, self.camera_target));
+            if self.path_tracing.enabled {
+                self.accumulated_frames = self.accumulated_frames.saturating_add(1);
+            }
+            trace!(frame = current_frame, image = next_image_idx, fence_wait_us = wait_time.as_micros() as u64,
+                   gpu_ms = ?self.last_gpu_ms, "Submitted frame");
 
+            // When capturing, presentation waits on the readback copy instead of the render. A screenshot copies
+            // after a running capture's copy.
+            let present_image = self.device.render_target.swap_loader
+                .get_swapchain_images(self.device.render_target.swap_chain).unwrap()[next_image_idx as usize];
+            let mut present_wait = sig_sems[0];
+            if let Some(capture) = self.capture.as_mut() {
+                present_wait = capture.submit(&self.core, &self.device.render_target, present_image, present_wait);
+            }
+            let mut screenshot = self.screenshots.pop_front().map(|(path, reply)| {
+                let capture = FrameCapture::new(&self.core, self.device.render_target.extent,
+                                                self.device.render_target.surface_format,
+                                                CaptureOutput::Png(path.clone()), Duration::ZERO);
+                (capture, path, reply)
+            });
+            if let Some((capture, _, _)) = screenshot.as_mut() {
+                present_wait = capture.submit(&self.core, &self.device.render_target, present_image, present_wait);
+            }
+            let present_wait_sems = [present_wait];
+            let image_indices = [next_image_idx];
+            let mut present_info = vk::PresentInfoKHR::default()
+                .wait_semaphores(&present_wait_sems)
+                .swapchains(&swap_chains)
+                .image_indices(&image_indices);
+            // Lets the latency governor wait for this frame to reach the display
+            let present_ids = self.device.latency.next_present_id().map(|id| [id]);
+            let mut present_id_info = vk::PresentIdKHR::default();
+            if let Some(ids) = present_ids.as_ref() {
+                present_id_info = present_id_info.present_ids(ids);
+                present_info = present_info.push_next(&mut present_id_info);
+            }
 
-            match self.render_target.swap_loader.queue_present(present_queue, &present_info)
-            {
-                Err(r) => match r {
-                    vk::Result::ERROR_OUT_OF_DATE_KHR | vk::Result::SUBOPTIMAL_KHR => { self.recreate_swap_chain() },
-                    _ => panic!("Unknown error")
-                }
-                Ok(_) => { }
+            let present_result = self.device.render_target.swap_loader.queue_present(present_queue, &present_info);
+            if let Some((capture, path, reply)) = screenshot {
+                self.finish_screenshot(capture, path, reply);
+            }
+            if self.device_lost.detect(&present_result, "queue_present") {
+                return;
             }
+            self.swapchain_recreate.check_present(present_result);
+            self.device_lost.frame_presented();
         }
 
         self.current_frame = (current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.last_cpu_ms = (frame_start.elapsed() - wait_time).as_secs_f64() * 1000.0;
+        let stats = FrameStats {
+            frame_ms: frame_start.duration_since(self.last_frame_start).as_secs_f64() * 1000.0,
+            cpu_ms: self.last_cpu_ms,
+            gpu_ms: self.last_gpu_ms,
+            passes: Vec::new(),
+            presents: Vec::new()
+        };
+        let status = format!("{} | {} | {} | {} | {} | {}", self.device.latency.status(), self.limiter.status(),
+                             self.path_tracing.status(self.accumulated_frames),
+                             self.device.post_process.motion_blur_settings().status(),
+                             self.device.analysis.settings().status(), self.clock.status());
+        self.stats_overlay.record(&self.core.window, &stats, status.as_str());
+        self.crash.record(stats);
+        self.last_frame_start = frame_start;
+    }
+
+    // Waits for the frame's start time, see FrameLimiter, then for the frame before the current one, so that camera
+    // input is applied as late as possible
+    fn throttle(&mut self) {
+        self.limiter.wait();
+        let previous_frame = (self.current_frame + MAX_FRAMES_IN_FLIGHT - 1) % MAX_FRAMES_IN_FLIGHT;
+        self.device.latency.throttle(&self.core, &self.device.render_target,
+                                     self.device.in_flight_fences[previous_frame]);
     }
 
     fn window_id(&self) -> WindowId {
         self.core.window.id()
     }
 
-    pub fn run_blocking(mut self, event_loop: EventLoop<()>) {
-        event_loop.run(move |event, _, control_flow| {
-            control_flow.set_poll();
+    pub fn window_size(&self) -> PhysicalSize<u32> {
+        self.core.window.inner_size()
+    }
 
+    // Live and replayed input both go through here so that replays reproduce the session
+    fn handle_input(&mut self, input: &InputEvent) {
+        if let InputEvent::MouseMotion { x, y } = *input {
+            self.look(x, y);
+        }
+        self.block_editor.handle_input(input);
+        if let InputEvent::Key { key: Some(key), pressed, .. } = *input {
+            // Key repeat sends more presses while a key is held
+            let repeat = self.held_keys.contains(&key);
+            self.held_keys.retain(|k| *k != key);
+            if pressed {
+                self.held_keys.push(key);
+                let mut path_tracing = self.path_tracing;
+                if !repeat && path_tracing.handle_key(key) {
+                    self.set_path_tracing(path_tracing);
+                    info!(status = self.path_tracing.status(0), "Path tracing settings changed");
+                }
+                let mut motion_blur = self.device.post_process.motion_blur_settings();
+                if !repeat && motion_blur.handle_key(key) {
+                    self.device.post_process.set_motion_blur_settings(motion_blur);
+                    info!(status = motion_blur.status(), "Motion blur settings changed");
+                }
+                let mut analysis = self.device.analysis.settings();
+                if !repeat && analysis.handle_key(key) {
+                    self.device.analysis.set_settings(analysis);
+                    info!(status = analysis.status(), "Analysis overlays changed");
+                }
+                if !repeat && key == FRAME_SCENE_KEY {
+                    if let Err(e) = self.frame_scene(FRAME_SCENE_MARGIN) {
+                        warn!("The camera is not moved: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    // WASD moves along the view direction and sideways, Q and E move down and up. Movement is per frame rather than
+    // per second so that replays don't depend on timing.
+    fn update_camera(&mut self) {
+        let up = Vector3::new(0.0, 0.0, 1.0);
+        let forward = (self.camera_target - self.camera_eye).normalize();
+        let right = forward.cross(up).normalize();
+        let mut offset = Vector3::new(0.0, 0.0, 0.0);
+        for key in self.held_keys.iter() {
+            offset += match key {
+                VirtualKeyCode::W => forward,
+                VirtualKeyCode::S => -forward,
+                VirtualKeyCode::D => right,
+                VirtualKeyCode::A => -right,
+                VirtualKeyCode::E => up,
+                VirtualKeyCode::Q => -up,
+                _ => Vector3::new(0.0, 0.0, 0.0)
+            };
+        }
+        self.camera_eye += offset * CAMERA_STEP;
+        self.camera_target += offset * CAMERA_STEP;
+    }
+
+    // Turns the camera around its eye by mouse motion in pixels, keeping the distance to the target. Moving right
+    // turns right and moving down looks down.
+    fn look(&mut self, x: f64, y: f64) {
+        let offset = self.camera_target - self.camera_eye;
+        let distance = offset.magnitude();
+        let direction = offset / distance;
+        let yaw = direction.y.atan2(direction.x) - x as f32 * MOUSE_LOOK_RADIANS_PER_PIXEL;
+        let pitch = (direction.z.clamp(-1.0, 1.0).asin() - y as f32 * MOUSE_LOOK_RADIANS_PER_PIXEL)
+            .clamp(-MAX_LOOK_PITCH, MAX_LOOK_PITCH);
+        let direction = Vector3::new(yaw.cos() * pitch.cos(), yaw.sin() * pitch.cos(), pitch.sin());
+        self.camera_target = self.camera_eye + direction * distance;
+    }
+
+    pub fn run_blocking(self, event_loop: EventLoop<()>) {
+        self.run_interactive(event_loop, None);
+    }
+
+    // Same as run_blocking, but writes every input event to recorder for run_replay
+    pub fn run_recording(self, event_loop: EventLoop<()>, recorder: InputRecorder) {
+        self.run_interactive(event_loop, Some(recorder));
+    }
+
+    // A panic while drawing idles the device and aborts, see CrashHandler
+    fn run_interactive(mut self, event_loop: EventLoop<()>, mut recorder: Option<InputRecorder>) {
+        let mut frames_drawn: u64 = 0;
+        let crash = self.crash.clone();
+        // The control flow is set once per iteration, at MainEventsCleared, see IdleTracker::next_frame
+        event_loop.run(move |event, _, control_flow| {
             match event {
                 Event::WindowEvent {
                     // If event has Event::WindowEvent type and event: WindowEvent::CloseRequested member and if window_id == window.id()
                     event: WindowEvent::CloseRequested,
                     window_id,
                 } if window_id == self.window_id() => *control_flow = ControlFlow::Exit,
-               Event::MainEventsCleared => self.core.window.request_redraw(), // Emits a RedrawRequested event
-                // after input events end
+                Event::WindowEvent { event, window_id } if window_id == self.window_id() => {
+                    self.idle.handle_window_event(&event);
+                    let captured = self.cursor.is_captured();
+                    self.cursor.handle_window_event(&self.core.window, &event);
+                    // While a UI is shown it gets the input instead of the camera
+                    if !self.cursor.ui_gets_events() {
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.record(frames_drawn, &event);
+                        }
+                        // Clicks edit blocks, except the one that captures the cursor for mouse look
+                        match InputEvent::from_window_event(&event) {
+                            Some(InputEvent::MouseButton { .. }) if !captured => (),
+                            Some(input) => self.handle_input(&input),
+                            None => ()
+                        }
+                    }
+                    self.device.latency.handle_window_event(&event);
+                    self.clock.handle_window_event(&event);
+                    self.swapchain_recreate.handle_window_event(&event);
+                },
+                Event::DeviceEvent { event, .. } => {
+                    if self.cursor.is_captured() {
+                        self.idle.mark_active();
+                    }
+                    self.cursor.handle_device_event(&event);
+                },
+                // Before this iteration's input events are handled
+                Event::NewEvents(_) => crash.run_guarded(|| self.throttle()),
+                Event::MainEventsCleared => crash.run_guarded(|| {
+                    // Commands are processed while idle too, they count as activity. So do held camera keys.
+                    if self.process_commands() > 0 || !self.held_keys.is_empty() {
+                        self.idle.mark_active();
+                    }
+                    if self.idle.next_frame(self.settings.idle_behavior, control_flow) {
+                        self.core.window.request_redraw(); // Emits a RedrawRequested event after input events end
+                    }
+                }),
                 // Needed when a redraw is needed after the user resizes for example
-                Event::RedrawRequested(window_id) if window_id == self.window_id() => self.draw_frame(),
-                Event::LoopDestroyed => unsafe { self.core.logical_device.device_wait_idle().unwrap() },
+                Event::RedrawRequested(window_id) if window_id == self.window_id() => {
+                    // A frame's mouse motion is recorded as one event, before the frame it turns
+                    let (x, y) = self.cursor.take_motion();
+                    if x != 0.0 || y != 0.0 {
+                        let input = InputEvent::MouseMotion { x, y };
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.record_input(frames_drawn, input);
+                        }
+                        self.handle_input(&input);
+                    }
+                    crash.run_guarded(|| {
+                        self.update_camera();
+                        self.draw_frame();
+                    });
+                    frames_drawn += 1;
+                },
+                Event::LoopDestroyed => {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.finish(frames_drawn);
+                    }
+                    if !self.device_lost.is_lost() {
+                        unsafe { self.core.logical_device.device_wait_idle().unwrap() }
+                    }
+                },
                 _ => (), // Similar to the "default" case of a switch statement: return void which is essentially () in Rust
             }
         });
     }
 
-    fn destroy_sync_objects(&self) {
-        unsafe {
-            for i in self.image_available_sems.iter() {
-                self.core.logical_device.destroy_semaphore(*i, None);
-            }
-            for r in self.render_finished_sems.iter() {
-                self.core.logical_device.destroy_semaphore(*r, None);
-            }
-            for f in self.in_flight_fences.iter() {
-                self.core.logical_device.destroy_fence(*f, None);
-            }
+    // Renders frames from a fixed camera with the window hidden and returns the PNG of the last one, for golden
+    // image tests. Several frames are rendered so that every frame in flight has been used once.
+    pub fn render_still(&mut self, eye: Point3<f32>, target: Point3<f32>, frames: usize, dir: PathBuf) -> PathBuf {
+        self.core.window.set_visible(false);
+        self.camera_eye = eye;
+        self.camera_target = target;
+        self.held_keys.clear();
+        self.frame_index = 0; // Same sampling pattern regardless of what was rendered before
+        self.accumulation_key = None;
+        self.start_capture(CaptureOutput::PngSequence(dir.clone()), REPLAY_TIMESTEP);
+        for _ in 0..frames {
+            self.draw_frame();
         }
+        self.stop_capture();
+
+        dir.join(format!("frame_{:06}.png", frames - 1))
     }
 
-    fn destroy_command_pool(&self) {
-        unsafe { self.core.logical_device.destroy_command_pool(self.command_pool, None) };
+    // Renders a recorded session with the window hidden and writes a hash of every frame to hash_path. When expected
+    // is given the hashes are compared against it and the process exits with 1 if any frame differs.
+    pub fn run_replay(mut self, event_loop: EventLoop<()>, mut replay: InputReplay, hash_path: PathBuf,
+                      expected: Option<PathBuf>) {
+        let size = self.window_size();
+        if let Some(recorded) = replay.size.filter(|s| *s != size) {
+            warn!("Replay was recorded at {}x{} but the window is {}x{}, hashes will not match",
+                  recorded.width, recorded.height, size.width, size.height);
+        }
+        // The swap chain still exists, frames just aren't shown. Hidden windows may not get RedrawRequested, so
+        // frames are drawn as soon as the event queue is empty.
+        self.core.window.set_visible(false);
+        self.start_capture(CaptureOutput::Hashes(hash_path.clone()), REPLAY_TIMESTEP);
+        let mut frames_drawn: u64 = 0;
+        let crash = self.crash.clone();
+
+        event_loop.run(move |event, _, control_flow| {
+            control_flow.set_poll();
+
+            match event {
+                Event::MainEventsCleared => {
+                    if replay.is_finished(frames_drawn) {
+                        return;
+                    }
+
+                    for input in replay.events_for_frame(frames_drawn) {
+                        self.handle_input(&input);
+                    }
+                    crash.run_guarded(|| {
+                        self.update_camera();
+                        self.draw_frame();
+                    });
+                    frames_drawn += 1;
+
+                    if replay.is_finished(frames_drawn) {
+                        self.stop_capture(); // Writes out the hashes still in flight
+                        let exit_code = match &expected {
+                            Some(expected) => match compare_hash_files(expected, &hash_path) {
+                                Ok(mismatches) if mismatches.is_empty() => {
+                                    info!("Replay: {} frames match {}", frames_drawn, expected.display());
+                                    0
+                                },
+                                Ok(mismatches) => {
+                                    error!("Replay: {} frames differ from {}, first at frame {}", mismatches.len(),
+                                           expected.display(), mismatches[0]);
+                                    1
+                                },
+                                Err(e) => {
+                                    error!("Replay: {}", e);
+                                    1
+                                }
+                            },
+                            None => 0
+                        };
+                        *control_flow = ControlFlow::ExitWithCode(exit_code);
+                    }
+                },
+                Event::LoopDestroyed if !self.device_lost.is_lost() =>
+                    unsafe { self.core.logical_device.device_wait_idle().unwrap() },
+                _ => (),
+            }
+        });
     }
-}
 
+    // Flies the camera along the benchmark spline with vsync off and at most at --max-fps, then writes the report and
+    // exits
+    pub fn run_benchmark(mut self, event_loop: EventLoop<()>, mut benchmark: Benchmark) {
+        self.present_mode = vk::PresentModeKHR::IMMEDIATE;
+        // Runs with the same effects regardless of how it was launched
+        self.set_motion_blur_settings(MotionBlurSettings { enabled: false, ..self.motion_blur_settings() });
+        self.recreate_swap_chain();
+        let mut last_frame_end = Instant::now();
+        let crash = self.crash.clone();
+
+        event_loop.run(move |event, _, control_flow| {
+            control_flow.set_poll();
+
+            match event {
+                Event::WindowEvent {
+                    event: WindowEvent::CloseRequested,
+                    window_id,
+                } if window_id == self.window_id() => *control_flow = ControlFlow::Exit,
+                Event::WindowEvent { event, window_id } if window_id == self.window_id() =>
+                    self.swapchain_recreate.handle_window_event(&event),
+                Event::MainEventsCleared => self.core.window.request_redraw(),
+                Event::RedrawRequested(window_id) if window_id == self.window_id() => {
+                    if benchmark.is_finished() {
+                        return;
+                    }
+
+                    (self.camera_eye, self.camera_target) = benchmark.camera();
+                    // Without a limit the benchmark measures the highest rate the renderer reaches
+                    self.limiter.wait();
+                    crash.run_guarded(|| self.draw_frame());
+                    let frame_end = Instant::now();
+                    benchmark.record(FrameStats {
+                        frame_ms: frame_end.duration_since(last_frame_end).as_secs_f64() * 1000.0,
+                        cpu_ms: self.last_cpu_ms,
+                        gpu_ms: self.last_gpu_ms,
+                        passes: Vec::new(),
+                        presents: Vec::new()
+                    });
+                    last_frame_end = frame_end;
+
+                    if benchmark.is_finished() {
+                        *control_flow = match benchmark.write_report() {
+                            Ok(()) => ControlFlow::Exit,
+                            Err(e) => {
+                                error!("Benchmark: {}", e);
+                                ControlFlow::ExitWithCode(1)
+                            }
+                        };
+                    }
+                },
+                Event::LoopDestroyed if !self.device_lost.is_lost() =>
+                    unsafe { self.core.logical_device.device_wait_idle().unwrap() },
+                _ => (),
+            }
+        });
+    }
+}
 
 impl Drop for RtRenderer {
     fn drop(&mut self) {
-        self.cleanup_swap_chain();
-       // destroy_sampler(&self.logical_layer, self.sampler);
-       //  self.texture.destroy(logical_layer);
-        destroy_descriptor_sets(&self.core, &self.descriptor_layouts, self.descriptor_pool);
-        for t in &self.tlas {
-            t.destroy(&self.core, &self.accel_instance);
-        };
-        self.blas.destroy(&self.core, &self.accel_instance);
-       //  self.index_buffer.destroy(logical_layer);
-       //  self.vertex_buffer.destroy(logical_layer);
-        self.destroy_sync_objects();
-        self.destroy_command_pool();
-        self.rt_pipeline.destroy(&self.core);
-        self.per_frame_data.destroy(&self.core);
-        // destroy_render_pass(logical_layer, self.render_pass);
+        self.stop_capture();
+        // A lost device has nothing left to wait for
+        if let Err(e) = unsafe { self.core.logical_device.device_wait_idle() } {
+            warn!("device_wait_idle before teardown failed: {:?}", e);
+        }
+        self.lights.destroy(&self.core);
+        self.emissive.destroy(&self.core);
+        self.device.destroy(&self.core);
+        self.crash.disarm();
         self.core.destroy();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,203 @@
+use ash::vk;
+use renderlib::gpu_buffer::GpuBuffer;
+use renderlib::sampler::{create_sampler, destroy_sampler};
+use renderlib::texture::Texture;
+use renderlib::vkcore::VkCore;
+use tracing::info_span;
+
+pub const BLUE_NOISE_SIZE: u32 = 64;
+pub const SOBOL_DIMENSIONS: usize = 8; // Must match sampling.glsl
+const SOBOL_BITS: usize = 32;
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+
+// (degree, coefficients, initial direction numbers) of the primitive polynomials for dimensions 1 onwards, from Joe
+// and Kuo's new-joe-kuo-6.21201. Dimension 0 is the van der Corput sequence.
+const SOBOL_POLYNOMIALS: [(usize, u32, &[u32]); SOBOL_DIMENSIONS - 1] = [
+    (1, 0, &[1]),
+    (2, 1, &[1, 3]),
+    (3, 1, &[1, 3, 1]),
+    (3, 2, &[1, 1, 1]),
+    (4, 1, &[1, 1, 3, 3]),
+    (4, 4, &[1, 3, 5, 13]),
+    (5, 2, &[1, 1, 5, 5, 17])
+];
+
+// SOBOL_BITS direction numbers per dimension, the sample at index i XORs those of the set bits of i
+fn sobol_directions() -> Vec<u32> {
+    let mut directions: Vec<u32> = (0..SOBOL_BITS).map(|i| 1 << (31 - i)).collect();
+    for &(degree, coefficients, initial) in SOBOL_POLYNOMIALS.iter() {
+        let mut v = [0u32; SOBOL_BITS];
+        for i in 0..SOBOL_BITS {
+            if i < degree {
+                v[i] = initial[i] << (31 - i);
+                continue;
+            }
+            v[i] = v[i - degree] ^ (v[i - degree] >> degree);
+            for k in 1..degree {
+                if (coefficients >> (degree - 1 - k)) & 1 == 1 {
+                    v[i] ^= v[i - k];
+                }
+            }
+        }
+        directions.extend_from_slice(&v);
+    }
+
+    directions
+}
+
+// Energy of a point pattern on a tileable size x size grid, every point adds a Gaussian around itself
+#[derive(Clone)]
+struct NoiseEnergy {
+    size: usize,
+    kernel: Vec<f32>, // Energy a point adds at each offset, wrapping around the tile
+    energy: Vec<f32>,
+    set: Vec<bool>
+}
+
+impl NoiseEnergy {
+    fn new(size: usize) -> NoiseEnergy {
+        let mut kernel = vec![0.0f32; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let dx = x.min(size - x) as f32;
+                let dy = y.min(size - y) as f32;
+                kernel[y * size + x] = (-(dx * dx + dy * dy) / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp();
+            }
+        }
+
+        NoiseEnergy {
+            size,
+            kernel,
+            energy: vec![0.0; size * size],
+            set: vec![false; size * size]
+        }
+    }
+
+    fn toggle(&mut self, point: usize) {
+        let size = self.size;
+        let sign = match self.set[point] {
+            true => -1.0,
+            false => 1.0
+        };
+        self.set[point] = !self.set[point];
+        let (px, py) = (point % size, point / size);
+        for y in 0..size {
+            for x in 0..size {
+                let offset = ((y + size - py) % size) * size + (x + size - px) % size;
+                self.energy[y * size + x] += sign * self.kernel[offset];
+            }
+        }
+    }
+
+    // The set point with the highest energy
+    fn tightest_cluster(&self) -> usize {
+        (0..self.energy.len()).filter(|i| self.set[*i]).max_by(|a, b| self.energy[*a].total_cmp(&self.energy[*b]))
+            .unwrap()
+    }
+
+    // The free point with the lowest energy
+    fn largest_void(&self) -> usize {
+        (0..self.energy.len()).filter(|i| !self.set[*i]).min_by(|a, b| self.energy[*a].total_cmp(&self.energy[*b]))
+            .unwrap()
+    }
+}
+
+// Ranks every pixel of a tileable size x size mask with Ulichney's void and cluster method. Thresholding the
+// normalized ranks at any level gives an evenly spread pattern.
+fn blue_noise_ranks(size: usize) -> Vec<f32> {
+    let count = size * size;
+    let initial_count = count / 10;
+    let mut pattern = NoiseEnergy::new(size);
+    // A deterministic scatter, relaxed by moving the tightest cluster into the largest void until that is a no-op
+    for i in 0..initial_count as u32 {
+        let mut point = (i.wrapping_mul(2654435761) >> 8) as usize % count;
+        while pattern.set[point] {
+            point = (point + 1) % count;
+        }
+        pattern.toggle(point);
+    }
+    for _ in 0..count {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0.0f32; count];
+    // The initial points get the lowest ranks, removing the tightest cluster first
+    let mut removing = pattern.clone();
+    for rank in (0..initial_count).rev() {
+        let cluster = removing.tightest_cluster();
+        removing.toggle(cluster);
+        ranks[cluster] = rank as f32 / count as f32;
+    }
+    // The rest fill the largest void
+    for rank in initial_count..count {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        ranks[void] = rank as f32 / count as f32;
+    }
+
+    ranks
+}
+
+// Low discrepancy sampling resources for the ray tracing shaders, see sampling.glsl. Samples come from a Sobol
+// sequence, and each pixel shifts them by its blue noise value so that the error left after a few samples is high
+// frequency noise rather than visible structure.
+pub struct RtSampling {
+    blue_noise: Texture, // BLUE_NOISE_SIZE squared ranks, each channel offset by the golden ratio
+    sampler: vk::Sampler, // Unused by texelFetch, but a combined image sampler needs one
+    sobol: GpuBuffer
+}
+
+impl RtSampling {
+    pub fn new(core: &VkCore, command_pool: vk::CommandPool) -> RtSampling {
+        let _span = info_span!("rt_sampling_init").entered();
+        let ranks = blue_noise_ranks(BLUE_NOISE_SIZE as usize);
+        let pixels: Vec<f32> = ranks.iter()
+            .flat_map(|r| (0..4).map(move |c| (r + c as f32 * 0.618_034).fract()))
+            .collect();
+        let blue_noise = Texture::from_rgba32f(core, command_pool, BLUE_NOISE_SIZE, BLUE_NOISE_SIZE, &pixels);
+        let sobol = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                               &sobol_directions(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+
+        RtSampling {
+            blue_noise,
+            sampler: create_sampler(core, 1),
+            sobol
+        }
+    }
+
+    // Scrambles the frame index, so that hash based sampling in consecutive frames isn't correlated
+    pub fn frame_seed(frame_index: u32) -> u32 {
+        let mut x = frame_index.wrapping_add(0x9e37_79b9);
+        x = (x ^ (x >> 16)).wrapping_mul(0x85eb_ca6b);
+        x = (x ^ (x >> 13)).wrapping_mul(0xc2b2_ae35);
+        x ^ (x >> 16)
+    }
+
+    // Bind as a COMBINED_IMAGE_SAMPLER descriptor
+    pub fn blue_noise_info(&self) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)
+            .image_view(self.blue_noise.view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+    }
+
+    // Bind as a STORAGE_BUFFER descriptor
+    pub fn sobol_info(&self) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo::default()
+            .buffer(self.sobol.buf)
+            .offset(0)
+            .range(vk::WHOLE_SIZE)
+    }
+
+    pub fn destroy(&self, core: &VkCore) {
+        self.blue_noise.destroy(core);
+        destroy_sampler(core, self.sampler);
+        self.sobol.destroy(core);
+    }
+}
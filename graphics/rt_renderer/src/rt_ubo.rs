@@ -1,10 +1,11 @@
 use std::mem;
 use ash::vk;
-use cgmath::{Deg, Matrix4, perspective, Point3, Transform, Vector3};
+use cgmath::{Deg, Matrix4, perspective, Point3, Transform, Vector3, Vector4};
 use renderlib::gpu_buffer::create_buffer;
+use renderlib::memory::mapped_memory_flags;
 use renderlib::render_target::RenderTarget;
 use renderlib::vkcore::VkCore;
-use crate::rt_pipeline::RtMissConstants;
+use crate::rt_environment::RtEnvironmentUbo;
 
 // Remember to align fields according to the Vulkan specification 15.7.4
 #[repr(C)]
@@ -12,32 +13,39 @@ use crate::rt_pipeline::RtMissConstants;
 pub struct RtPerFrameUbo {
     // model: Matrix4<f32>,
     pub inverse_view: Matrix4<f32>,
-    pub inverse_proj: Matrix4<f32>
+    pub inverse_proj: Matrix4<f32>,
+    pub environment: [RtEnvironmentUbo; 2], // See EnvironmentTransition::ubo
+    pub environment_blend: Vector4<f32>, // x is the weight of environment[1], y the clock's time in seconds
+    pub reflection: Vector4<f32>, // x is the reflectivity and y the roughness, see ReflectionSettings
+    pub ray_settings: Vector4<u32>, // x is the bounce limit and y the frame index, which seeds the sampling
+    // x is 1 when path tracing, y the samples per pixel, z the russian roulette start depth and w the number of
+    // frames already in the accumulation image, 0 starts over. See PathTracingSettings.
+    pub path_settings: Vector4<u32>,
+    pub firefly_clamp: Vector4<f32>, // x is the highest luminance of a single path sample, 0 disables clamping
+    // x is the frame's seed for hash based sampling and y the index of its first Sobol sample, see RtSampling
+    pub sampling: Vector4<u32>,
+    pub lights: Vector4<u32> // x is the number of lights in the light buffer, y the number of emissive triangles
 }
 
 pub struct  RtUniformBuffer<T> {
     pub data: Vec<vk::Buffer>,
     mem: Vec<vk::DeviceMemory>,
     mapped: Vec<*mut T>
-    // start_time: Instant
 }
 
 impl<T> RtUniformBuffer<T> {
     pub fn new(core: &VkCore, num_entries: usize) ->
                                                                                                              RtUniformBuffer<T> {
         let buffer_size: vk::DeviceSize = mem::size_of::<T>() as vk::DeviceSize;
-        // let start_time = Instant::now();
         let mut uniform_buffer: RtUniformBuffer<T> = RtUniformBuffer {
             data: vec![],
             mem: vec![],
             mapped: vec![]
-            // start_time
         };
 
         for _ in 0..num_entries {
             let (buf_mem, buffer) = create_buffer(core, buffer_size, vk::BufferUsageFlags::UNIFORM_BUFFER,
-                                                  vk::MemoryPropertyFlags::HOST_COHERENT |
-                                                      vk::MemoryPropertyFlags::HOST_VISIBLE);
+                                                  mapped_memory_flags(core));
             uniform_buffer.mem.push(buf_mem);
             uniform_buffer.data.push(buffer);
 
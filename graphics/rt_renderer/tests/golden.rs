@@ -0,0 +1,63 @@
+// Golden image tests for the ray traced renderer, these need a GPU with ray tracing support. Run with
+//     cargo test -p rt_renderer --features gpu-tests
+// and set UPDATE_GOLDEN=1 to regenerate the references in graphics/tests/golden after an intended change.
+// Uses its own main (harness = false) since the window and event loop have to live on the main thread.
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+use cgmath::Point3;
+use winit::event_loop::EventLoop;
+use renderlib::golden::{check_golden, GoldenTolerance};
+use renderlib::logging::init_logging;
+use rt_renderer::rt_renderer::RtRenderer;
+
+const STILL_FRAMES: usize = 3;
+
+struct GoldenCase {
+    name: &'static str,
+    eye: Point3<f32>,
+    target: Point3<f32>
+}
+
+fn main() {
+    init_logging();
+    // Shader and asset paths are relative to the repository root
+    env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../..")).unwrap();
+    let cases = [
+        GoldenCase {
+            name: "rt_default_camera",
+            eye: Point3::new(-32.0, -32.0, 64.0),
+            target: Point3::new(8.0, 8.0, 8.0)
+        },
+        GoldenCase {
+            name: "rt_side_view",
+            eye: Point3::new(48.0, 8.0, 8.0),
+            target: Point3::new(8.0, 8.0, 8.0)
+        }
+    ];
+
+    let event_loop = EventLoop::new();
+    let mut renderer = RtRenderer::new(&event_loop);
+    let out_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    let tolerance = GoldenTolerance::default();
+
+    let mut failures = 0;
+    for case in cases.iter() {
+        let frame = renderer.render_still(case.eye, case.target, STILL_FRAMES, out_dir.join(case.name));
+        match check_golden(case.name, &frame, &tolerance) {
+            Ok(report) => println!("{} ... ok (max delta {}, SSIM {:.4})", case.name, report.max_channel_delta,
+                                   report.ssim),
+            Err(e) => {
+                println!("{} ... FAILED: {}", case.name, e);
+                failures += 1;
+            }
+        }
+    }
+
+    drop(renderer);
+    if failures > 0 {
+        println!("{} of {} golden images failed", failures, cases.len());
+        process::exit(1);
+    }
+}
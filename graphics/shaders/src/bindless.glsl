@@ -0,0 +1,50 @@
+// Global resource heap of renderlib::bindless::ResourceHeap, bound as set 1. Define BINDLESS_SET before including
+// to bind it elsewhere. Images, samplers and buffers are picked by the indices their handles hold, which reach the
+// shader through the HeapIndices push constants or any buffer.
+#extension GL_EXT_nonuniform_qualifier : require
+
+#ifndef BINDLESS_SET
+#define BINDLESS_SET 1
+#endif
+
+layout(set = BINDLESS_SET, binding = 0) uniform texture2D heapImages[];
+layout(set = BINDLESS_SET, binding = 1) uniform sampler heapSamplers[];
+layout(std430, set = BINDLESS_SET, binding = 2) readonly buffer HeapBuffer {
+    uint words[];
+} heapBuffers[];
+
+// HeapIndices in bindless.rs. Shaders with push constants of their own define BINDLESS_CUSTOM_PUSH_CONSTANTS and
+// put a HeapIndices member first in their block.
+struct HeapIndices {
+    uint imageIndex;
+    uint samplerIndex;
+    uint bufferIndex;
+    uint user; // Free for the pipeline, e.g. an element of the buffer
+};
+
+#ifndef BINDLESS_CUSTOM_PUSH_CONSTANTS
+layout(push_constant) uniform HeapPushConstants {
+    HeapIndices indices;
+} heap;
+#endif
+
+// The indices may differ between invocations, e.g. when they come from a buffer
+vec4 heapSample(uint image, uint samp, vec2 uv)
+{
+    return texture(sampler2D(heapImages[nonuniformEXT(image)], heapSamplers[nonuniformEXT(samp)]), uv);
+}
+
+vec4 heapSampleLod(uint image, uint samp, vec2 uv, float lod)
+{
+    return textureLod(sampler2D(heapImages[nonuniformEXT(image)], heapSamplers[nonuniformEXT(samp)]), uv, lod);
+}
+
+uint heapLoad(uint buf, uint index)
+{
+    return heapBuffers[nonuniformEXT(buf)].words[index];
+}
+
+float heapLoadFloat(uint buf, uint index)
+{
+    return uintBitsToFloat(heapLoad(buf, index));
+}
@@ -0,0 +1,56 @@
+// Lights of renderlib::clustered_lights::ClusteredLights and the froxel grid they are sorted into. The grid splits
+// the view into screen tiles, and every tile into depth slices that grow exponentially between the near and far
+// planes. Bound as set 1 of the forward pass, define CLUSTER_SET before including to bind it elsewhere.
+#ifndef CLUSTER_SET
+#define CLUSTER_SET 1
+#endif
+
+const uvec3 CLUSTER_GRID = uvec3(16, 9, 24); // CLUSTER_GRID in clustered_lights.rs
+const uint MAX_LIGHTS_PER_CLUSTER = 128;
+const uint MAX_DIRECTIONAL_LIGHTS = 4;
+
+struct PointLight
+{
+    vec4 positionRange; // World space position, w is the range
+    vec4 color; // Times the intensity, w is the shadow slot in point_shadows.glsl or -1
+};
+
+struct DirectionalLight
+{
+    vec4 direction; // World space direction the light travels in
+    vec4 color; // Times the intensity
+};
+
+layout(std430, set = CLUSTER_SET, binding = 0) readonly buffer Lights {
+    mat4 view;
+    mat4 inverseProjection;
+    uvec4 counts; // Point lights and directional lights
+    vec4 depthRange; // Near plane, far plane, viewport width and height
+    DirectionalLight directional[MAX_DIRECTIONAL_LIGHTS];
+    PointLight points[];
+} lights;
+layout(std430, set = CLUSTER_SET, binding = 1) buffer ClusterCounts {
+    uint clusterCounts[];
+};
+layout(std430, set = CLUSTER_SET, binding = 2) buffer ClusterIndices {
+    uint clusterIndices[]; // MAX_LIGHTS_PER_CLUSTER point light indices per cluster
+};
+
+uint flatClusterIndex(uvec3 cluster)
+{
+    return (cluster.z * CLUSTER_GRID.y + cluster.y) * CLUSTER_GRID.x + cluster.x;
+}
+
+// Distance from the camera plane where slice starts
+float sliceDepth(float slice)
+{
+    return lights.depthRange.x * pow(lights.depthRange.y / lights.depthRange.x, slice / float(CLUSTER_GRID.z));
+}
+
+uvec3 clusterOf(vec2 fragCoord, float viewDepth)
+{
+    uvec2 tile = uvec2(fragCoord / lights.depthRange.zw * vec2(CLUSTER_GRID.xy));
+    float slice = log(max(viewDepth, lights.depthRange.x) / lights.depthRange.x) /
+        log(lights.depthRange.y / lights.depthRange.x) * float(CLUSTER_GRID.z);
+    return min(uvec3(tile, uint(slice)), CLUSTER_GRID - 1);
+}
@@ -0,0 +1,37 @@
+// Final color transform shared by the raster and ray traced paths, see renderlib::color_pipeline
+struct ColorConstants
+{
+    float exposure;
+    float inverseGamma;
+    uint encodeSrgb;
+    uint audit;
+};
+
+vec3 linearToSrgb(vec3 c)
+{
+    vec3 low = c * 12.92;
+    vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, lessThanEqual(c, vec3(0.0031308)));
+}
+
+// linearColor is the shaded scene value, pixel is in framebuffer pixels
+vec3 applyColorPipeline(ColorConstants cc, vec3 linearColor, vec2 pixel)
+{
+    vec3 c = linearColor * cc.exposure;
+
+    if (cc.audit != 0) {
+        if (pixel.y < 16.0) {
+            // 16 step linear reference ramp repeating every 256 pixels. Step 8 should read as ~0.53 linear (~0.76 in
+            // sRGB) in both renderers.
+            c = vec3(floor(mod(pixel.x, 256.0) / 16.0) / 15.0);
+        } else if (any(greaterThan(c, vec3(1.0)))) {
+            c = vec3(1.0, 0.0, 1.0); // Clipped
+        }
+    }
+
+    c = pow(clamp(c, 0.0, 1.0), vec3(cc.inverseGamma));
+    if (cc.encodeSrgb != 0) {
+        c = linearToSrgb(c);
+    }
+    return c;
+}
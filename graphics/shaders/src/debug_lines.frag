@@ -0,0 +1,17 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "colorcommon.glsl"
+
+layout(push_constant) uniform constants {
+    ColorConstants color;
+} pcs;
+
+layout(location = 0) in vec3 fragColor;
+
+layout(location = 0) out vec4 outColor;
+
+// Linear RGB, exposure doesn't dim the lines
+void main() {
+    outColor = vec4(applyColorPipeline(pcs.color, fragColor / pcs.color.exposure, gl_FragCoord.xy), 1.0);
+}
@@ -0,0 +1,16 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "frame_constants.glsl"
+
+// Line vertices of renderlib::debug_lines::DebugLines, two per line in world space
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+
+layout(location = 0) out vec3 fragColor;
+
+void main() {
+    gl_Position = frame.viewProj * vec4(inPosition, 1.0);
+    fragColor = inColor;
+}
@@ -0,0 +1,25 @@
+// Copies the depth buffer into a storage buffer for DepthImage, see depth_readback.rs. Included by depth_copy.comp and
+// depth_copy_ms.comp, the latter defines MULTISAMPLED and reads sample 0.
+layout(local_size_x = 8, local_size_y = 8) in;
+
+#ifdef MULTISAMPLED
+layout(binding = 0) uniform sampler2DMS depthBuffer;
+#else
+layout(binding = 0) uniform sampler2D depthBuffer;
+#endif
+layout(std430, binding = 1) writeonly buffer Depths {
+    float depths[]; // Rows from the top
+};
+layout(push_constant) uniform constants {
+    uvec2 extent;
+} pcs;
+
+void main()
+{
+    uvec2 pixel = gl_GlobalInvocationID.xy;
+    if (any(greaterThanEqual(pixel, pcs.extent))) {
+        return;
+    }
+
+    depths[pixel.y * pcs.extent.x + pixel.x] = texelFetch(depthBuffer, ivec2(pixel), 0).r;
+}
@@ -0,0 +1,29 @@
+// Copies the depth buffer into level 0 of the depth pyramid. Included by depth_pyramid_init.comp and
+// depth_pyramid_init_ms.comp, the latter defines MULTISAMPLED.
+layout(local_size_x = 8, local_size_y = 8) in;
+
+#ifdef MULTISAMPLED
+layout(binding = 0) uniform sampler2DMS depthBuffer;
+#else
+layout(binding = 0) uniform sampler2D depthBuffer;
+#endif
+layout(binding = 2, r32f) uniform writeonly image2D dstLevel;
+
+void main()
+{
+    ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+    if (any(greaterThanEqual(pixel, imageSize(dstLevel)))) {
+        return;
+    }
+
+#ifdef MULTISAMPLED
+    // Farthest sample, keeps the pyramid conservative
+    float depth = 0.0;
+    for (int s = 0; s < textureSamples(depthBuffer); s++) {
+        depth = max(depth, texelFetch(depthBuffer, pixel, s).r);
+    }
+#else
+    float depth = texelFetch(depthBuffer, pixel, 0).r;
+#endif
+    imageStore(dstLevel, pixel, vec4(depth));
+}
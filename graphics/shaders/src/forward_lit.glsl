@@ -0,0 +1,92 @@
+// Same as shader.frag, lit by the directional lights and the point lights of the fragment's cluster. Included by
+// forward_lit.frag and forward_virtual.frag, the latter defines VIRTUAL_TEXTURE and samples a sparse texture instead.
+// terrain.frag defines SPLAT_MAP and blends the terrain's layers by the splat map at the texture binding.
+// voxel_mesh.frag defines ATLAS_TILES and repeats a tile of the texture across greedy meshed quads, and
+// BAKED_OCCLUSION to darken them by the ambient occlusion of their vertices.
+#include "colorcommon.glsl"
+#include "clustered.glsl"
+#include "point_shadows.glsl"
+
+#ifdef VIRTUAL_TEXTURE
+#include "virtual_texture.glsl"
+#else
+layout(binding = 1) uniform sampler2D texSampler;
+#endif
+#ifdef SPLAT_MAP
+#include "splat_map.glsl"
+#endif
+layout(push_constant) uniform constants {
+    ColorConstants color;
+} pcs;
+
+layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragTexCoord;
+layout(location = 2) in vec3 fragWorldPosition;
+layout(location = 3) in float fragViewDepth;
+#ifdef ATLAS_TILES
+layout(location = 4) flat in vec4 fragTile; // Corner and size of the tile in the texture
+#endif
+#ifdef BAKED_OCCLUSION
+layout(location = 5) in float fragOcclusion; // 1 is unoccluded
+#endif
+
+layout(location = 0) out vec4 outColor;
+
+const float AMBIENT = 0.05;
+
+// Inverse square falloff that reaches 0 at the light's range
+float attenuation(float distance, float range)
+{
+    float ratio = distance / range;
+    float window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    return window * window / (distance * distance + 1.0);
+}
+
+void main() {
+    // Sampled from an SRGB view, so already linear
+#if defined(VIRTUAL_TEXTURE)
+    vec4 texel = sampleVirtual(fragTexCoord, gl_FragCoord.xy);
+#elif defined(SPLAT_MAP)
+    vec4 texel = sampleSplat(fragTexCoord);
+#elif defined(ATLAS_TILES)
+    // Texture coordinates count blocks, the gradients are the unwrapped ones so that fract doesn't pick a tiny mip
+    vec2 tileCoord = fragTile.xy + fract(fragTexCoord) * fragTile.zw;
+    vec4 texel = textureGrad(texSampler, tileCoord, dFdx(fragTexCoord) * fragTile.zw, dFdy(fragTexCoord) * fragTile.zw);
+#else
+    vec4 texel = texture(texSampler, fragTexCoord);
+#endif
+#ifdef BAKED_OCCLUSION
+    // All light, lit and unlit, so that the depth cues stay without SSAO or lights
+    texel.rgb *= fragOcclusion;
+#endif
+    // Scenes without lights are shown unlit
+    if (lights.counts.x == 0 && lights.counts.y == 0) {
+        outColor = vec4(applyColorPipeline(pcs.color, texel.rgb, gl_FragCoord.xy), texel.a);
+        return;
+    }
+
+    // Vertices have no normals, so surfaces are shaded flat
+    vec3 normal = normalize(cross(dFdx(fragWorldPosition), dFdy(fragWorldPosition)));
+    vec3 eye = -transpose(mat3(lights.view)) * lights.view[3].xyz;
+    if (dot(normal, eye - fragWorldPosition) < 0.0) {
+        normal = -normal;
+    }
+    vec3 irradiance = vec3(AMBIENT);
+    for (uint i = 0; i < lights.counts.y; i++) {
+        DirectionalLight light = lights.directional[i];
+        irradiance += light.color.rgb * max(dot(normal, -light.direction.xyz), 0.0);
+    }
+
+    uint cluster = flatClusterIndex(clusterOf(gl_FragCoord.xy, fragViewDepth));
+    uint count = clusterCounts[cluster];
+    for (uint i = 0; i < count; i++) {
+        PointLight light = lights.points[clusterIndices[cluster * MAX_LIGHTS_PER_CLUSTER + i]];
+        vec3 toLight = light.positionRange.xyz - fragWorldPosition;
+        float distance = length(toLight);
+        float shadow = light.color.w >= 0.0 ? pointShadow(uint(light.color.w), fragWorldPosition) : 1.0;
+        irradiance += light.color.rgb * max(dot(normal, toLight / distance), 0.0) *
+            attenuation(distance, light.positionRange.w) * shadow;
+    }
+
+    outColor = vec4(applyColorPipeline(pcs.color, texel.rgb * irradiance, gl_FragCoord.xy), texel.a);
+}
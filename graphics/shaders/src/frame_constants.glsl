@@ -0,0 +1,18 @@
+// Values almost every shader needs, filled once a frame from renderlib::frame_constants::FrameConstants. Bound as
+// set 0, define FRAME_SET before including to bind it elsewhere. Camera values are the main view's.
+#ifndef FRAME_SET
+#define FRAME_SET 0
+#endif
+
+layout(set = FRAME_SET, binding = 0) uniform FrameConstants {
+    mat4 view;
+    mat4 proj;
+    mat4 viewProj;
+    mat4 inverseView;
+    mat4 inverseProj;
+    mat4 inverseViewProj;
+    vec4 eye; // w is unused
+    vec4 resolution; // Width and height of the main view in pixels, then their reciprocals
+    vec4 time; // x is the clock's time, y its delta and z the real time since startup, in seconds
+    uvec4 info; // x is the frame index, y the debug flags
+} frame;
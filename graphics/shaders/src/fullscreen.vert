@@ -0,0 +1,8 @@
+#version 460
+
+// A triangle covering the viewport, counter clockwise so that back face culling keeps it. Draw 3 vertices without
+// vertex buffers.
+void main() {
+    vec2 uv = vec2(gl_VertexIndex & 2, (gl_VertexIndex << 1) & 2);
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+}
@@ -0,0 +1,18 @@
+#version 460
+
+// Writes what the pixel sees to the attachments of a GBuffer, see renderlib::gbuffer
+layout(binding = 1) uniform sampler2D texSampler;
+
+layout(location = 1) in vec2 fragTexCoord;
+layout(location = 2) in vec3 fragWorldPosition;
+
+layout(location = 0) out vec4 outAlbedo;
+layout(location = 1) out vec4 outNormal;
+layout(location = 2) out vec4 outPosition;
+
+void main() {
+    outAlbedo = vec4(texture(texSampler, fragTexCoord).rgb, 1.0); // Sampled from an SRGB view, so already linear
+    // Flat, the vertices have no normals. Its sign is left to the passes reading it, which know where the camera is.
+    outNormal = vec4(normalize(cross(dFdx(fragWorldPosition), dFdy(fragWorldPosition))), 0.0);
+    outPosition = vec4(fragWorldPosition, 1.0); // w = 0 where nothing was drawn
+}
@@ -0,0 +1,73 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "colorcommon.glsl"
+#include "frame_constants.glsl"
+
+layout(set = 1, binding = 0) uniform GridUniforms {
+    vec4 lineColor; // Linear RGB and opacity
+    // x is the cell size, y the cells between major lines, z 1 to highlight the axes and w the distance from the
+    // camera the grid has faded out at
+    vec4 params;
+} grid;
+
+layout(push_constant) uniform constants {
+    ColorConstants color;
+} pcs;
+
+layout(location = 0) in vec3 nearPoint;
+layout(location = 1) in vec3 farPoint;
+
+layout(location = 0) out vec4 outColor;
+
+const vec3 X_AXIS_COLOR = vec3(0.8, 0.1, 0.1);
+const vec3 Y_AXIS_COLOR = vec3(0.1, 0.8, 0.1);
+
+// Coverage of lines every spacing units along both axes, a pixel wide at any distance
+float lines(vec2 p, float spacing) {
+    vec2 coord = p / spacing;
+    vec2 distance = abs(fract(coord - 0.5) - 0.5) / fwidth(coord);
+    return 1.0 - min(min(distance.x, distance.y), 1.0);
+}
+
+// Coverage of the line where c is 0
+float axis(float c) {
+    return 1.0 - min(abs(c) / fwidth(c), 1.0);
+}
+
+void main() {
+    // The ground plane is z = 0, rays that cross it behind the camera or past the far plane miss it
+    float dz = farPoint.z - nearPoint.z;
+    float t = dz != 0.0 ? -nearPoint.z / dz : -1.0;
+    vec3 p = nearPoint + clamp(t, 0.0, 1.0) * (farPoint - nearPoint);
+
+    // Derivatives first, they are undefined after a discard
+    float cellSize = grid.params.x;
+    float minor = lines(p.xy, cellSize);
+    float major = lines(p.xy, cellSize * grid.params.y);
+    float xAxis = axis(p.y);
+    float yAxis = axis(p.x);
+    // Minor lines fade out as their cells shrink towards a pixel, before they turn into moiré
+    float minorFade = 1.0 - smoothstep(0.25, 0.5, length(fwidth(p.xy)) / cellSize);
+    if (t <= 0.0 || t > 1.0) {
+        discard;
+    }
+
+    vec4 clip = frame.viewProj * vec4(p, 1.0);
+    gl_FragDepth = clip.z / clip.w;
+
+    float coverage = max(minor * minorFade * 0.5, major);
+    vec3 color = grid.lineColor.rgb;
+    if (grid.params.z != 0.0) {
+        color = mix(color, X_AXIS_COLOR, xAxis);
+        color = mix(color, Y_AXIS_COLOR, yAxis);
+        coverage = max(coverage, max(xAxis, yAxis));
+    }
+    float fade = 1.0 - smoothstep(0.5 * grid.params.w, grid.params.w, distance(p, frame.eye.xyz));
+    float alpha = coverage * fade * grid.lineColor.a;
+    if (alpha <= 0.0) {
+        discard;
+    }
+    // A helper rather than part of the scene, so it keeps its color whatever the exposure
+    outColor = vec4(applyColorPipeline(pcs.color, color / pcs.color.exposure, gl_FragCoord.xy), alpha);
+}
@@ -0,0 +1,23 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "frame_constants.glsl"
+
+// A triangle covering the viewport, see renderlib::grid::Grid. Draw 3 vertices without vertex buffers.
+
+// Where the pixel's view ray crosses the near and far planes, in world space
+layout(location = 0) out vec3 nearPoint;
+layout(location = 1) out vec3 farPoint;
+
+vec3 unproject(vec2 xy, float depth) {
+    vec4 p = frame.inverseViewProj * vec4(xy, depth, 1.0);
+    return p.xyz / p.w;
+}
+
+void main() {
+    vec2 uv = vec2(gl_VertexIndex & 2, (gl_VertexIndex << 1) & 2);
+    vec2 xy = uv * 2.0 - 1.0;
+    gl_Position = vec4(xy, 0.0, 1.0);
+    nearPoint = unproject(xy, 0.0);
+    farPoint = unproject(xy, 1.0);
+}
@@ -0,0 +1,17 @@
+#version 460
+
+layout(binding = 0) uniform sampler2D view;
+
+// Matches HudConstants in aux_camera.rs
+layout(push_constant) uniform constants {
+    vec4 rect; // Offset and size in pixels of the window
+    vec4 params; // Opacity
+} pcs;
+
+layout(location = 0) out vec4 outColor;
+
+// The viewport is set to the rect, so the texture is stretched over it
+void main() {
+    vec2 uv = (gl_FragCoord.xy - pcs.rect.xy) / pcs.rect.zw;
+    outColor = vec4(texture(view, uv).rgb, pcs.params.x);
+}
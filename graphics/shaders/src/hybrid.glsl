@@ -0,0 +1,55 @@
+// Shared by the compute passes of the hybrid renderer, see rt_renderer::rt_hybrid. hybrid_trace.comp traces the
+// G-buffer's surfaces into the visibility and reflection images, which hybrid_composite.comp shades with.
+#include "sky.glsl"
+
+layout(local_size_x = 8, local_size_y = 8) in; // Matches HYBRID_GROUP_SIZE
+
+const float HYBRID_PI = 3.14159265359;
+
+layout(binding = 0) uniform HybridUniforms {
+    mat4 viewProj;
+    mat4 inverseViewProj;
+    vec4 eye; // w is the frame index, which seeds the sampling
+    vec4 sun; // Towards the sun, w is the turbidity
+    vec4 sunColor; // w is the sky intensity
+    vec4 settings; // Shadows and reflections (0 or 1), AO radius and AO rays
+    vec4 material; // Reflectivity and ambient intensity
+} hybrid;
+layout(binding = 1, rgba16f) uniform readonly image2D albedoImage;
+layout(binding = 2, rgba16f) uniform readonly image2D normalImage;
+layout(binding = 3, rgba32f) uniform readonly image2D positionImage;
+layout(binding = 4, rgba16f) uniform image2D visibilityImage; // Sun visibility and ambient occlusion
+layout(binding = 5, rgba16f) uniform image2D reflectionImage; // Reflected radiance, a is its weight
+
+vec3 skyRadiance(vec3 direction)
+{
+    return preethamSky(direction, normalize(hybrid.sun.xyz), hybrid.sun.w) * hybrid.sunColor.w;
+}
+
+// Light from everywhere but the sun, with nothing in the way
+vec3 ambientRadiance()
+{
+    return skyRadiance(vec3(0.0, 0.0, 1.0)) * hybrid.material.y;
+}
+
+// Lit by the sun and the sky, sunVisibility and occlusion are 1 where no rays were traced
+vec3 shadeSurface(vec3 albedo, vec3 normal, float sunVisibility, float occlusion)
+{
+    vec3 sunLight = hybrid.sunColor.rgb * max(dot(normal, normalize(hybrid.sun.xyz)), 0.0) * sunVisibility;
+    return albedo * (sunLight + ambientRadiance() * occlusion);
+}
+
+// Facing the camera, the G-buffer's normals are flat and their sign is arbitrary
+vec3 loadNormal(ivec2 pixel, vec3 position)
+{
+    vec3 normal = imageLoad(normalImage, pixel).xyz;
+    return dot(normal, hybrid.eye.xyz - position) < 0.0 ? -normal : normal;
+}
+
+// From the eye through the center of pixel
+vec3 viewDirection(ivec2 pixel, ivec2 size)
+{
+    vec2 ndc = (vec2(pixel) + 0.5) / vec2(size) * 2.0 - 1.0;
+    vec4 far = hybrid.inverseViewProj * vec4(ndc, 1.0, 1.0);
+    return normalize(far.xyz / far.w - hybrid.eye.xyz);
+}
@@ -0,0 +1,156 @@
+// Next event estimation over the LightList and the EmissiveTriangles, see rt_light.rs and rt_emissive.rs. Needs
+// rtuniforms.glsl.
+struct Light {
+    vec4 color; // w is the kind, LIGHT_DIRECTIONAL, LIGHT_POINT, LIGHT_QUAD or LIGHT_SPHERE
+    vec4 position; // The direction towards the light for directional lights, w is the cumulative probability
+    vec4 edgeA; // w is the probability of picking this light
+    vec4 edgeB; // w is the area of a quad
+    vec4 params; // x is the radius of a sphere, y the shadow rays per sample
+};
+
+layout(binding = 8, set = 0) readonly buffer Lights { Light l[]; } lights;
+
+// Matches RtEmissiveTriangle
+struct EmissiveTriangle {
+    vec4 v0; // w is the area
+    vec4 edgeA; // v1 - v0, w is the cumulative probability
+    vec4 edgeB; // v2 - v0, w is the probability of picking this triangle
+    vec4 emission;
+};
+
+layout(binding = 9, set = 0) readonly buffer EmissiveTriangles { EmissiveTriangle t[]; } emissive;
+
+const float LIGHT_DIRECTIONAL = 0.0;
+const float LIGHT_POINT = 1.0;
+const float LIGHT_QUAD = 2.0;
+const float LIGHT_SPHERE = 3.0;
+const float LIGHTS_PI = 3.14159265359;
+
+// A sample towards a light, radiance is already divided by the probability of the sample
+struct LightSample {
+    vec3 direction;
+    float distance; // To the light, how far the shadow ray has to go
+    vec3 radiance;
+};
+
+// Picks a light by power with u, its index in the LightList. Returns false without lights.
+bool pickLight(float u, out uint i)
+{
+    uint count = ubo.lights.x;
+    if (count == 0) {
+        return false;
+    }
+    // The last light also takes what rounding leaves above its cumulative probability
+    i = 0;
+    while (i < count - 1 && u >= lights.l[i].position.w) {
+        i++;
+    }
+    return lights.l[i].edgeA.w > 0.0;
+}
+
+// Shadow rays the light wants per sample, see LightList::set_samples. Lights without an area only need one.
+uint lightSamples(uint i)
+{
+    Light light = lights.l[i];
+    if (light.color.w == LIGHT_DIRECTIONAL || light.color.w == LIGHT_POINT) {
+        return 1;
+    }
+    return max(uint(light.params.y), 1u);
+}
+
+// Uniform over the part of the sphere seen from p, by solid angle. Returns false from inside the sphere.
+bool sampleSphere(vec3 p, Light light, vec2 u, out LightSample s)
+{
+    vec3 toCenter = light.position.xyz - p;
+    float radius = light.params.x;
+    float distanceSquared = dot(toCenter, toCenter);
+    if (distanceSquared <= radius * radius) {
+        return false;
+    }
+    // 1 - cos of the cone's half angle, expanded for far away spheres where it would round to 0
+    float sinSquared = radius * radius / distanceSquared;
+    float oneMinusCos = sinSquared < 1e-4 ? 0.5 * sinSquared : 1.0 - sqrt(1.0 - sinSquared);
+    float cosTheta = 1.0 - u.x * oneMinusCos;
+    float sinTheta = sqrt(max(1.0 - cosTheta * cosTheta, 0.0));
+    float phi = 2.0 * LIGHTS_PI * u.y;
+    vec3 axis = toCenter / sqrt(distanceSquared);
+    vec3 t = normalize(cross(axis, abs(axis.z) < 0.999 ? vec3(0.0, 0.0, 1.0) : vec3(1.0, 0.0, 0.0)));
+    vec3 b = cross(axis, t);
+    s.direction = normalize((t * cos(phi) + b * sin(phi)) * sinTheta + axis * cosTheta);
+    // The nearer intersection with the sphere
+    float along = dot(s.direction, toCenter);
+    s.distance = along - sqrt(max(radius * radius - (distanceSquared - along * along), 0.0));
+    s.radiance = light.color.rgb * 2.0 * LIGHTS_PI * oneMinusCos / light.edgeA.w;
+    return true;
+}
+
+// A point on light i with u, see pickLight. Returns false when the point faces away from p.
+bool sampleLightAt(uint i, vec3 p, vec2 u, out LightSample s)
+{
+    Light light = lights.l[i];
+    float probability = light.edgeA.w;
+    if (light.color.w == LIGHT_DIRECTIONAL) {
+        s.direction = light.position.xyz;
+        s.distance = 10000.0;
+        s.radiance = light.color.rgb / probability;
+        return true;
+    }
+    if (light.color.w == LIGHT_SPHERE) {
+        return sampleSphere(p, light, u, s);
+    }
+    vec3 target = light.position.xyz;
+    if (light.color.w == LIGHT_QUAD) {
+        target += light.edgeA.xyz * u.x + light.edgeB.xyz * u.y;
+    }
+    vec3 toLight = target - p;
+    float distanceSquared = max(dot(toLight, toLight), 1e-6);
+    s.distance = sqrt(distanceSquared);
+    s.direction = toLight / s.distance;
+    if (light.color.w == LIGHT_POINT) {
+        s.radiance = light.color.rgb / (distanceSquared * probability);
+        return true;
+    }
+    // Uniform over the quad's area, converted to solid angle
+    float cosLight = dot(normalize(cross(light.edgeA.xyz, light.edgeB.xyz)), -s.direction);
+    if (cosLight <= 0.0) {
+        return false;
+    }
+    s.radiance = light.color.rgb * cosLight * light.edgeB.w / (distanceSquared * probability);
+    return true;
+}
+
+// Picks an emissive triangle by power with u.x and a uniformly distributed point on it with u.yz. Triangles emit on
+// both sides, since the winding of model faces isn't reliable.
+bool sampleEmissive(vec3 p, vec3 u, out LightSample s)
+{
+    uint count = ubo.lights.y;
+    if (count == 0) {
+        return false;
+    }
+    // Binary search for the first cumulative probability above u.x, the last triangle takes any rounding remainder
+    uint low = 0;
+    uint high = count - 1;
+    while (low < high) {
+        uint middle = (low + high) / 2;
+        if (u.x < emissive.t[middle].edgeA.w) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    EmissiveTriangle triangle = emissive.t[low];
+    float probability = triangle.edgeB.w;
+    if (probability <= 0.0) {
+        return false;
+    }
+
+    float r = sqrt(u.y);
+    vec3 target = triangle.v0.xyz + triangle.edgeA.xyz * (r * (1.0 - u.z)) + triangle.edgeB.xyz * (r * u.z);
+    vec3 toLight = target - p;
+    float distanceSquared = max(dot(toLight, toLight), 1e-6);
+    s.distance = sqrt(distanceSquared);
+    s.direction = toLight / s.distance;
+    float cosLight = abs(dot(normalize(cross(triangle.edgeA.xyz, triangle.edgeB.xyz)), s.direction));
+    s.radiance = triangle.emission.rgb * cosLight * triangle.v0.w / (distanceSquared * probability);
+    return true;
+}
@@ -0,0 +1,24 @@
+// Converts the depth buffer into the view depth of the post-process chain. Included by linear_depth.comp and
+// linear_depth_ms.comp, the latter defines MULTISAMPLED and reads sample 0.
+layout(local_size_x = 8, local_size_y = 8) in;
+
+#ifdef MULTISAMPLED
+layout(binding = 0) uniform sampler2DMS depthBuffer;
+#else
+layout(binding = 0) uniform sampler2D depthBuffer;
+#endif
+layout(binding = 1, r32f) uniform writeonly image2D viewDepth;
+layout(push_constant) uniform constants {
+    vec4 projection; // proj[0][0], proj[1][1], proj[2][2] and proj[3][2]
+} pcs;
+
+void main()
+{
+    ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+    if (any(greaterThanEqual(pixel, imageSize(viewDepth)))) {
+        return;
+    }
+
+    float depth = texelFetch(depthBuffer, pixel, 0).r;
+    imageStore(viewDepth, pixel, vec4(pcs.projection.w / (depth + pcs.projection.z)));
+}
@@ -0,0 +1,22 @@
+#version 460
+#extension GL_EXT_multiview : require
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+
+layout(location = 0) out vec3 fragColor;
+layout(location = 1) out vec2 fragTexCoord;
+
+// One view/projection pair per multiview layer, 0 is the left eye and 1 is the right eye
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 model;
+    mat4 view[2];
+    mat4 proj[2];
+} ubo;
+
+void main() {
+    gl_Position = ubo.proj[gl_ViewIndex] * ubo.view[gl_ViewIndex] * ubo.model * vec4(inPosition, 1.0);
+    fragColor = inColor;
+    fragTexCoord = inTexCoord;
+}
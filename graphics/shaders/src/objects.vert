@@ -0,0 +1,32 @@
+#version 460
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+
+layout(location = 0) out vec3 fragColor;
+layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) out vec3 fragWorldPosition; // For forward_lit.frag
+layout(location = 3) out float fragViewDepth;
+
+// The view's matrices, bound at a dynamic offset per view
+layout(binding = 0) uniform ViewTransforms {
+    mat4 view;
+    mat4 proj;
+} ubo;
+
+// Every object's model matrix for the frame, each object is drawn with its slot as first instance. See
+// renderlib::objects::ObjectTransforms
+layout(std430, binding = 2) readonly buffer Objects {
+    mat4 models[];
+};
+
+void main() {
+    vec4 worldPosition = models[gl_InstanceIndex] * vec4(inPosition, 1.0);
+    vec4 viewPosition = ubo.view * worldPosition;
+    gl_Position = ubo.proj * viewPosition;
+    fragColor = inColor;
+    fragTexCoord = inTexCoord;
+    fragWorldPosition = worldPosition.xyz;
+    fragViewDepth = -viewPosition.z;
+}
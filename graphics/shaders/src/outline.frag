@@ -0,0 +1,38 @@
+#version 460
+
+// Must match MAX_OUTLINE_WIDTH in outline.rs
+#define MAX_WIDTH 8
+
+layout(binding = 0) uniform sampler2D mask;
+
+// Matches OutlineConstants in outline.rs
+layout(push_constant) uniform constants {
+    vec4 color; // Linear RGB and opacity
+    ivec4 params; // Width in pixels
+} pcs;
+
+layout(location = 0) out vec4 outColor;
+
+// Dilates the mask by the width, the outline is what the dilation adds. The outer pixel is faded by how far the
+// nearest masked pixel is, which takes the stairs off diagonal edges.
+void main() {
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    if (texelFetch(mask, pixel, 0).r > 0.5) {
+        discard;
+    }
+    ivec2 last = textureSize(mask, 0) - 1;
+    int width = clamp(pcs.params.x, 1, MAX_WIDTH);
+    float nearest = float(MAX_WIDTH * 2);
+    for (int y = -width; y <= width; y++) {
+        for (int x = -width; x <= width; x++) {
+            if (texelFetch(mask, clamp(pixel + ivec2(x, y), ivec2(0), last), 0).r > 0.5) {
+                nearest = min(nearest, length(vec2(x, y)));
+            }
+        }
+    }
+    float coverage = clamp(float(width) + 0.5 - nearest, 0.0, 1.0);
+    if (coverage <= 0.0) {
+        discard;
+    }
+    outColor = vec4(pcs.color.rgb, pcs.color.a * coverage);
+}
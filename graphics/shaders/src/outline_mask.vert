@@ -0,0 +1,15 @@
+#version 460
+
+// Selected objects into the mask of renderlib::outline::Outline, without depth testing so that the whole silhouette
+// is outlined even where something is in front of it
+layout(push_constant) uniform constants {
+    mat4 modelViewProj;
+} pcs;
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+
+void main() {
+    gl_Position = pcs.modelViewProj * vec4(inPosition, 1.0);
+}
@@ -0,0 +1,43 @@
+// Shadows of the point lights in clustered.glsl with a slot in renderlib::shadow::PointShadows, bound with the
+// lights. Every slot has six layers in the atlas holding the distance to the light over its range per cube face.
+#define SHADOW_SET CLUSTER_SET
+#define SHADOW_BINDING 4
+#include "shadowcommon.glsl"
+
+layout(set = CLUSTER_SET, binding = 3) uniform sampler2DArray shadowAtlas;
+
+// The face the direction from the light leaves the cube through, in the order of ShadowSlot::faces
+uint cubeFace(vec3 direction)
+{
+    vec3 magnitude = abs(direction);
+    if (magnitude.x >= magnitude.y && magnitude.x >= magnitude.z) {
+        return direction.x >= 0.0 ? 0u : 1u;
+    }
+    if (magnitude.y >= magnitude.z) {
+        return direction.y >= 0.0 ? 2u : 3u;
+    }
+    return direction.z >= 0.0 ? 4u : 5u;
+}
+
+// 1 where worldPosition is lit by the slot's light, filtered over 3x3 texels of the face. Near the cube's edges the
+// filter stays on the face and is clamped to it.
+float pointShadow(uint slot, vec3 worldPosition)
+{
+    ShadowSlot shadow = shadows.slots[slot];
+    vec3 fromLight = worldPosition - shadow.light.xyz;
+    uint face = cubeFace(fromLight);
+    vec4 clip = shadow.faces[face] * vec4(worldPosition, 1.0);
+    vec2 uv = clip.xy / clip.w * 0.5 + 0.5;
+    float layer = float(slot * CUBE_FACES + face);
+    float depth = (length(fromLight) - shadow.params.x) / shadow.light.w;
+
+    vec2 texel = 1.0 / vec2(textureSize(shadowAtlas, 0).xy);
+    float lit = 0.0;
+    for (int y = -1; y <= 1; y++) {
+        for (int x = -1; x <= 1; x++) {
+            vec2 offsetUv = clamp(uv + vec2(x, y) * texel, 0.5 * texel, 1.0 - 0.5 * texel);
+            lit += depth <= texture(shadowAtlas, vec3(offsetUv, layer)).r ? 1.0 : 0.0;
+        }
+    }
+    return lit / 9.0;
+}
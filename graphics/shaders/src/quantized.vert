@@ -0,0 +1,35 @@
+#version 460
+
+// Like shader.vert, for the QuantizedVertex attributes of renderlib::vertex. The normal at location 5 isn't read.
+layout(location = 0) in vec4 inPosition; // 0 to 1 across the mesh's bounds
+layout(location = 1) in vec4 inColor;
+layout(location = 2) in vec2 inTexCoord; // 0 to 1 across the mesh's texture coordinate range
+
+layout(location = 0) out vec3 fragColor;
+layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) out vec3 fragWorldPosition;
+layout(location = 3) out float fragViewDepth;
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 model;
+    mat4 view;
+    mat4 proj;
+} ubo;
+
+// After the fragment shader's ColorConstants, see DEQUANTIZATION_OFFSET
+layout(push_constant) uniform constants {
+    layout(offset = 16) vec4 positionOffset;
+    vec4 positionScale;
+    vec4 texCoord; // Offset in xy, scale in zw
+} dequantization;
+
+void main() {
+    vec3 position = dequantization.positionOffset.xyz + dequantization.positionScale.xyz * inPosition.xyz;
+    vec4 worldPosition = ubo.model * vec4(position, 1.0);
+    vec4 viewPosition = ubo.view * worldPosition;
+    gl_Position = ubo.proj * viewPosition;
+    fragColor = inColor.rgb;
+    fragTexCoord = dequantization.texCoord.xy + dequantization.texCoord.zw * inTexCoord;
+    fragWorldPosition = worldPosition.xyz;
+    fragViewDepth = -viewPosition.z;
+}
@@ -1,4 +1,21 @@
 struct hitPayload
 {
     vec3 hitValue;
-};
\ No newline at end of file
+    uint depth; // Bounces so far, 0 for primary rays, SHADOW_RAY for visibility tests
+    uint seed; // Random state, see nextRandom
+    uint sampleIndex; // Of the path in the pixel's Sobol sequence, see sampling.glsl
+    float hitDistance; // Along the ray, NO_HIT when it missed
+};
+
+const float NO_HIT = 1.0e20;
+
+// Shadow rays skip the closest hit shader, the miss shader sets their hitValue to 1 when nothing is in the way
+const uint SHADOW_RAY = 0xFFFFFFFFu;
+
+// PCG hash, advances state and returns a value in [0, 1)
+float nextRandom(inout uint state)
+{
+    state = state * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return float((word >> 22u) ^ word) / 4294967296.0;
+}
@@ -0,0 +1,21 @@
+// Matches RtEnvironmentUbo
+struct Environment {
+    vec4 zenith; // w is the environment map index, negative without a map
+    vec4 horizon; // w is the environment map intensity
+};
+
+// Matches RtPerFrameUbo
+layout(binding = 2, set = 0) uniform UniformBufferObject {
+    mat4 viewInverse;
+    mat4 projInverse;
+    Environment environment[2];
+    vec4 environmentBlend; // x is the weight of environment[1], y the clock's time in seconds
+    vec4 reflection; // x is the reflectivity and y the roughness
+    uvec4 raySettings; // x is the bounce limit and y the frame index
+    // x is 1 when path tracing, y the samples per pixel, z the russian roulette start depth and w the accumulated
+    // frames, 0 starts over
+    uvec4 pathSettings;
+    vec4 fireflyClamp; // x is the highest luminance of a path sample, 0 disables clamping
+    uvec4 sampling; // x is the frame's seed and y the index of its first Sobol sample
+    uvec4 lights; // x is the number of lights and y the number of emissive triangles
+} ubo;
@@ -0,0 +1,29 @@
+// Low discrepancy sampling, see RtSampling. Needs raycommon.glsl and rtuniforms.glsl.
+layout(binding = 6, set = 0) uniform sampler2D blueNoise; // Ranks, each channel offset by the golden ratio
+layout(binding = 7, set = 0) readonly buffer SobolDirections { uint directions[]; } sobol; // 32 per dimension
+
+const uint SOBOL_DIMENSIONS = 8; // Matches SOBOL_DIMENSIONS
+
+float sobolSample(uint index, uint dimension)
+{
+    uint result = 0;
+    for (uint bit = 0; index != 0; bit++, index >>= 1) {
+        if ((index & 1) != 0) {
+            result ^= sobol.directions[dimension * 32 + bit];
+        }
+    }
+    return float(result) / 4294967296.0;
+}
+
+// Sample index of the current pixel in [0, 1). Pixels shift the Sobol sequence by their blue noise value, which
+// spreads the remaining error as high frequency noise. Dimensions beyond the Sobol table fall back to nextRandom.
+float pixelSample(uint index, uint dimension, inout uint seed)
+{
+    if (dimension >= SOBOL_DIMENSIONS) {
+        return nextRandom(seed);
+    }
+    // Every four dimensions read the mask at a different offset, so that they aren't shifted alike
+    ivec2 texel = (ivec2(gl_LaunchIDEXT.xy) + ivec2(17, 29) * int(dimension / 4)) % textureSize(blueNoise, 0);
+    float shift = texelFetch(blueNoise, texel, 0)[dimension % 4];
+    return fract(sobolSample(index, dimension) + shift);
+}
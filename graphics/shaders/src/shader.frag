@@ -1,8 +1,13 @@
 #version 460
+#extension GL_GOOGLE_include_directive : enable
 
 // #extension GL_ARB_separate_shader_objects : enable
+#include "colorcommon.glsl"
 
 layout(binding = 1) uniform sampler2D texSampler;
+layout(push_constant) uniform constants {
+    ColorConstants color;
+} pcs;
 
 layout(location = 0) in vec3 fragColor;
 layout(location = 1) in vec2 fragTexCoord;
@@ -10,5 +15,6 @@ layout(location = 1) in vec2 fragTexCoord;
 layout(location = 0) out vec4 outColor;
 
 void main() {
-    outColor = texture(texSampler, fragTexCoord);
-}
\ No newline at end of file
+    vec4 texel = texture(texSampler, fragTexCoord); // Sampled from an SRGB view, so already linear
+    outColor = vec4(applyColorPipeline(pcs.color, texel.rgb, gl_FragCoord.xy), texel.a);
+}
@@ -7,6 +7,8 @@ layout(location = 2) in vec2 inTexCoord;
 
 layout(location = 0) out vec3 fragColor; // Note that these per vertex values are interpolated to produce the per fragment values
 layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) out vec3 fragWorldPosition; // For forward_lit.frag
+layout(location = 3) out float fragViewDepth;
 
 layout(binding = 0) uniform UniformBufferObject {
     mat4 model;
@@ -15,7 +17,11 @@ layout(binding = 0) uniform UniformBufferObject {
 } ubo;
 
 void main() {
-    gl_Position = ubo.proj * ubo.view * ubo.model * vec4(inPosition, 1.0);
+    vec4 worldPosition = ubo.model * vec4(inPosition, 1.0);
+    vec4 viewPosition = ubo.view * worldPosition;
+    gl_Position = ubo.proj * viewPosition;
     fragColor = inColor;
     fragTexCoord = inTexCoord;
+    fragWorldPosition = worldPosition.xyz;
+    fragViewDepth = -viewPosition.z;
 }
\ No newline at end of file
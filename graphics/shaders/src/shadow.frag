@@ -0,0 +1,17 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "shadowcommon.glsl"
+
+layout(push_constant) uniform constants {
+    mat4 model;
+    uvec4 slot;
+} pcs;
+
+layout(location = 0) in vec3 fragWorldPosition;
+
+// The distance to the light over its range instead of the projected depth, so that sampling needs no projection
+void main() {
+    vec4 light = shadows.slots[pcs.slot.x].light;
+    gl_FragDepth = clamp(distance(fragWorldPosition, light.xyz) / light.w, 0.0, 1.0);
+}
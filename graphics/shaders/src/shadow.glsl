@@ -0,0 +1,25 @@
+// Renders the faces of a point light's cube in renderlib::shadow::PointShadows. shadow_multiview.vert defines
+// MULTIVIEW and renders all six faces in one pass, one per view.
+#include "shadowcommon.glsl"
+
+layout(push_constant) uniform constants {
+    mat4 model;
+    uvec4 slot; // The slot and, without multiview, the face
+} pcs;
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+
+layout(location = 0) out vec3 fragWorldPosition;
+
+void main() {
+#ifdef MULTIVIEW
+    uint face = gl_ViewIndex;
+#else
+    uint face = pcs.slot.y;
+#endif
+    vec4 worldPosition = pcs.model * vec4(inPosition, 1.0);
+    gl_Position = shadows.slots[pcs.slot.x].faces[face] * worldPosition;
+    fragWorldPosition = worldPosition.xyz;
+}
@@ -0,0 +1,6 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+#extension GL_EXT_multiview : require
+
+#define MULTIVIEW
+#include "shadow.glsl"
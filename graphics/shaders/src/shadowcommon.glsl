@@ -0,0 +1,21 @@
+// Slots of renderlib::shadow::PointShadows, a cube of six layers in the shadow atlas each. Define SHADOW_SET and
+// SHADOW_BINDING before including to bind them elsewhere than the shadow pass.
+#ifndef SHADOW_SET
+#define SHADOW_SET 0
+#endif
+#ifndef SHADOW_BINDING
+#define SHADOW_BINDING 0
+#endif
+
+const uint CUBE_FACES = 6;
+
+struct ShadowSlot
+{
+    mat4 faces[CUBE_FACES]; // View projections of +x, -x, +y, -y, +z and -z, layer slot * 6 + face
+    vec4 light; // World space position, w is the range
+    vec4 params; // x is the bias in world units
+};
+
+layout(std430, set = SHADOW_SET, binding = SHADOW_BINDING) readonly buffer Shadows {
+    ShadowSlot slots[];
+} shadows;
@@ -0,0 +1,31 @@
+#version 460
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+layout(location = 3) in uvec4 inJoints;
+layout(location = 4) in vec4 inWeights;
+
+layout(location = 0) out vec3 fragColor;
+layout(location = 1) out vec2 fragTexCoord;
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 model;
+    mat4 view;
+    mat4 proj;
+} ubo;
+
+// One skinning matrix per joint, see renderlib::skinning::BoneBuffer
+layout(std430, binding = 2) readonly buffer Bones {
+    mat4 bones[];
+};
+
+void main() {
+    mat4 skin = inWeights.x * bones[inJoints.x] +
+                inWeights.y * bones[inJoints.y] +
+                inWeights.z * bones[inJoints.z] +
+                inWeights.w * bones[inJoints.w];
+    gl_Position = ubo.proj * ubo.view * ubo.model * skin * vec4(inPosition, 1.0);
+    fragColor = inColor;
+    fragTexCoord = inTexCoord;
+}
@@ -0,0 +1,25 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "colorcommon.glsl"
+#include "sky.glsl"
+
+layout(binding = 0) uniform SkyUniforms {
+    mat4 inverseViewProj;
+    vec4 sun;
+    vec4 settings;
+} sky;
+
+layout(push_constant) uniform constants {
+    ColorConstants color;
+} pcs;
+
+layout(location = 0) in vec4 fragDirection;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    vec3 direction = normalize(fragDirection.xyz / fragDirection.w);
+    vec3 radiance = preethamSky(direction, normalize(sky.sun.xyz), sky.sun.w) * sky.settings.x;
+    outColor = vec4(applyColorPipeline(pcs.color, radiance, gl_FragCoord.xy), 1.0);
+}
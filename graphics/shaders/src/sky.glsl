@@ -0,0 +1,55 @@
+// Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight". Included by sky.frag for the raster
+// renderer's sky and by shader.rmiss for Environment::Sky, z is up. The result is linear sRGB in kcd/m², the renderers
+// scale it by SkySettings::sky_intensity.
+
+// Perez et al.'s distribution of luminance over the sky, for Y, x and y at once. gamma is the angle to the sun.
+vec3 perez(float cosTheta, float gamma, float cosGamma, vec3 A, vec3 B, vec3 C, vec3 D, vec3 E)
+{
+    return (1.0 + A * exp(B / cosTheta)) * (1.0 + C * exp(D * gamma) + E * cosGamma * cosGamma);
+}
+
+// Polynomial fit in the turbidity and the sun's zenith angle, the rows are for T², T and 1
+float zenithChromaticity(vec4 t2, vec4 t1, vec4 t0, float turbidity, float thetaS)
+{
+    vec4 powers = vec4(thetaS * thetaS * thetaS, thetaS * thetaS, thetaS, 1.0);
+    return turbidity * turbidity * dot(t2, powers) + turbidity * dot(t1, powers) + dot(t0, powers);
+}
+
+vec3 xyYToLinearSrgb(vec3 xyY)
+{
+    vec3 XYZ = vec3(xyY.x / xyY.y * xyY.z, xyY.z, (1.0 - xyY.x - xyY.y) / xyY.y * xyY.z);
+    mat3 toSrgb = mat3(3.2406, -0.9689, 0.0557, -1.5372, 1.8758, -0.2040, -0.4986, 0.0415, 1.0570);
+    return max(toSrgb * XYZ, vec3(0.0));
+}
+
+// sunDirection points towards the sun. turbidity is clamped to the 1.7 to 10 the model was fitted for.
+vec3 preethamSky(vec3 direction, vec3 sunDirection, float turbidity)
+{
+    float T = clamp(turbidity, 1.7, 10.0);
+    // The model only covers a sun above the horizon, the sky darkens through twilight instead
+    float daylight = smoothstep(-0.1, 0.02, sunDirection.z);
+    vec3 sun = normalize(vec3(sunDirection.xy, max(sunDirection.z, 0.02)));
+    // The horizon continues below it, the ground is drawn over the sky
+    vec3 view = normalize(vec3(direction.xy, max(direction.z, 0.01)));
+    float thetaS = acos(sun.z);
+    float cosGamma = clamp(dot(view, sun), -1.0, 1.0);
+
+    vec3 A = vec3(0.1787, -0.0193, -0.0167) * T + vec3(-1.4630, -0.2592, -0.2608);
+    vec3 B = vec3(-0.3554, -0.0665, -0.0950) * T + vec3(0.4275, 0.0008, 0.0092);
+    vec3 C = vec3(-0.0227, -0.0004, -0.0079) * T + vec3(5.3251, 0.2125, 0.2102);
+    vec3 D = vec3(0.1206, -0.0641, -0.0441) * T + vec3(-2.5771, -0.8989, -1.6537);
+    vec3 E = vec3(-0.0670, -0.0033, -0.0109) * T + vec3(0.3703, 0.0452, 0.0529);
+
+    float chi = (4.0 / 9.0 - T / 120.0) * (3.14159265 - 2.0 * thetaS);
+    vec3 zenith = vec3(
+        (4.0453 * T - 4.9710) * tan(chi) - 0.2155 * T + 2.4192,
+        zenithChromaticity(vec4(0.00166, -0.00375, 0.00209, 0.0), vec4(-0.02903, 0.06377, -0.03202, 0.00394),
+                           vec4(0.11693, -0.21196, 0.06052, 0.25886), T, thetaS),
+        zenithChromaticity(vec4(0.00275, -0.00610, 0.00317, 0.0), vec4(-0.04214, 0.08970, -0.04153, 0.00516),
+                           vec4(0.15346, -0.26756, 0.06670, 0.26688), T, thetaS));
+    // Relative to the zenith, where the model's luminance and chromaticity are known
+    vec3 xyY = zenith * perez(view.z, acos(cosGamma), cosGamma, A, B, C, D, E) /
+        perez(1.0, thetaS, sun.z, A, B, C, D, E);
+
+    return xyYToLinearSrgb(xyY) * daylight;
+}
@@ -0,0 +1,16 @@
+#version 460
+
+// A triangle covering the viewport on the far plane, see renderlib::sky::Sky. Draw 3 vertices without vertex buffers.
+layout(binding = 0) uniform SkyUniforms {
+    mat4 inverseViewProj; // Without the view's translation, so that it maps the far plane to view directions
+    vec4 sun; // xyz is the direction towards the sun, w the turbidity
+    vec4 settings; // x is the sky intensity
+} sky;
+
+layout(location = 0) out vec4 fragDirection; // Homogeneous, divided by w per fragment
+
+void main() {
+    vec2 uv = vec2(gl_VertexIndex & 2, (gl_VertexIndex << 1) & 2);
+    gl_Position = vec4(uv * 2.0 - 1.0, 1.0, 1.0);
+    fragDirection = sky.inverseViewProj * gl_Position;
+}
@@ -0,0 +1,22 @@
+// Material of renderlib::terrain::TerrainMesh, bound as set 2 of the forward pass. The texture binding of set 0
+// holds the splat map, whose channels weigh the four layers. Layers repeat across the terrain, the splat map and the
+// heightmap span it once.
+const uint SPLAT_LAYERS = 4;
+
+layout(set = 2, binding = 0) uniform sampler2D layers[SPLAT_LAYERS];
+layout(set = 2, binding = 1) uniform SplatParams {
+    vec4 tiling; // x: times the layers repeat across the terrain
+} splat;
+
+vec4 sampleSplat(vec2 uv)
+{
+    // Linear weights, normalized so that painted maps don't have to sum to 1
+    vec4 weights = texture(texSampler, uv);
+    weights /= max(dot(weights, vec4(1.0)), 0.0001);
+    vec2 layerUV = uv * splat.tiling.x;
+    vec4 color = vec4(0.0);
+    for (uint i = 0; i < SPLAT_LAYERS; i++) {
+        color += texture(layers[i], layerUV) * weights[i];
+    }
+    return color;
+}
@@ -0,0 +1,86 @@
+// Screen-space ambient occlusion from the depth buffer. Included by ssao.comp and ssao_ms.comp, the latter defines
+// MULTISAMPLED and reads sample 0.
+layout(local_size_x = 8, local_size_y = 8) in;
+
+#define KERNEL_SIZE 16 // Must match KERNEL_SIZE in ssao.rs
+#define NOISE_SIZE 4
+
+#ifdef MULTISAMPLED
+layout(binding = 0) uniform sampler2DMS depthBuffer;
+#else
+layout(binding = 0) uniform sampler2D depthBuffer;
+#endif
+layout(binding = 1, r32f) uniform writeonly image2D occlusion;
+layout(binding = 2) readonly buffer Kernel {
+    vec4 samples[KERNEL_SIZE]; // Points in the unit hemisphere around +z
+} kernel;
+layout(binding = 3) uniform sampler2D rotationNoise;
+layout(push_constant) uniform constants {
+    vec4 projection; // proj[0][0], proj[1][1], proj[2][2] and proj[3][2]
+    vec4 settings; // Radius, bias and intensity
+} pcs;
+
+float loadDepth(ivec2 pixel)
+{
+    return texelFetch(depthBuffer, pixel, 0).r;
+}
+
+// Inverts the perspective projection for the center of pixel
+vec3 viewPosition(ivec2 pixel, ivec2 size)
+{
+    vec2 ndc = (vec2(pixel) + 0.5) / vec2(size) * 2.0 - 1.0;
+    float z = -pcs.projection.w / (loadDepth(pixel) + pcs.projection.z);
+    return vec3(ndc * -z / pcs.projection.xy, z);
+}
+
+// The neighbour closer in depth on each axis, so that normals at silhouettes don't bend towards the background
+vec3 viewNormal(ivec2 pixel, ivec2 size, vec3 p)
+{
+    ivec2 maxPixel = size - 1;
+    vec3 right = viewPosition(min(pixel + ivec2(1, 0), maxPixel), size) - p;
+    vec3 left = p - viewPosition(max(pixel - ivec2(1, 0), ivec2(0)), size);
+    vec3 down = viewPosition(min(pixel + ivec2(0, 1), maxPixel), size) - p;
+    vec3 up = p - viewPosition(max(pixel - ivec2(0, 1), ivec2(0)), size);
+    vec3 dx = abs(right.z) < abs(left.z) ? right : left;
+    vec3 dy = abs(down.z) < abs(up.z) ? down : up;
+    vec3 n = normalize(cross(dx, dy));
+    return dot(n, p) > 0.0 ? -n : n; // Facing the camera
+}
+
+void main()
+{
+    ivec2 pixel = ivec2(gl_GlobalInvocationID.xy);
+    ivec2 size = imageSize(occlusion);
+    if (any(greaterThanEqual(pixel, size))) {
+        return;
+    }
+    if (loadDepth(pixel) >= 1.0) { // Background
+        imageStore(occlusion, pixel, vec4(1.0));
+        return;
+    }
+
+    float radius = pcs.settings.x;
+    float bias = pcs.settings.y;
+    vec3 p = viewPosition(pixel, size);
+    vec3 n = viewNormal(pixel, size, p);
+    // Gram-Schmidt turns the noise vector into a tangent, rotating the kernel around the normal
+    vec3 random = texelFetch(rotationNoise, pixel % NOISE_SIZE, 0).xyz;
+    vec3 tangent = normalize(random - n * dot(random, n));
+    mat3 tbn = mat3(tangent, cross(n, tangent), n);
+
+    float occluded = 0.0;
+    for (int i = 0; i < KERNEL_SIZE; i++) {
+        vec3 s = p + tbn * kernel.samples[i].xyz * radius;
+        vec2 uv = pcs.projection.xy * s.xy / -s.z * 0.5 + 0.5;
+        ivec2 samplePixel = ivec2(uv * vec2(size));
+        if (any(lessThan(samplePixel, ivec2(0))) || any(greaterThanEqual(samplePixel, size))) {
+            continue;
+        }
+        float sceneZ = viewPosition(samplePixel, size).z;
+        // Geometry far in front of p is a different object, it shouldn't darken p
+        float range = smoothstep(0.0, 1.0, radius / abs(p.z - sceneZ));
+        occluded += (sceneZ >= s.z + bias ? 1.0 : 0.0) * range;
+    }
+    float ao = pow(1.0 - occluded / float(KERNEL_SIZE), pcs.settings.z);
+    imageStore(occlusion, pixel, vec4(ao));
+}
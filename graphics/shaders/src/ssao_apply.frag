@@ -0,0 +1,11 @@
+#version 460
+
+layout(binding = 0, r32f) uniform readonly image2D occlusion;
+
+layout(location = 0) out vec4 outColor;
+
+// Alpha blending with ONE_MINUS_SRC_ALPHA multiplies what is already in the attachment by the occlusion
+void main() {
+    float ao = imageLoad(occlusion, ivec2(gl_FragCoord.xy)).r;
+    outColor = vec4(0.0, 0.0, 0.0, 1.0 - ao);
+}
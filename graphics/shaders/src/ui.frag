@@ -0,0 +1,21 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "colorcommon.glsl"
+#include "ui.glsl"
+
+layout(binding = 0) uniform sampler2D image; // sRGB, so that it is sampled as linear
+
+layout(location = 0) in vec4 fragColor;
+layout(location = 1) in vec2 fragTexCoord;
+
+layout(location = 0) out vec4 outColor;
+
+// Premultiplied alpha, blended with BlendMode::Premultiplied. Neither exposure nor gamma apply to the panels.
+void main() {
+    vec4 color = fragColor * texture(image, fragTexCoord);
+    if (pcs.encodeSrgb != 0) {
+        color.rgb = linearToSrgb(color.rgb);
+    }
+    outColor = color;
+}
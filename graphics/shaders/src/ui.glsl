@@ -0,0 +1,5 @@
+// Matches UiConstants in renderlib::debug_ui
+layout(push_constant) uniform constants {
+    vec2 screenSize; // In points, which the vertices are in
+    uint encodeSrgb; // Set when the swap chain image is UNORM, see ColorConstants
+} pcs;
@@ -0,0 +1,26 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#include "ui.glsl"
+
+// Vertices of the meshes egui tessellates, see renderlib::debug_ui::DebugUiDraws
+
+layout(location = 0) in vec2 inPosition; // In points from the top left corner
+layout(location = 1) in vec4 inColor; // sRGB with premultiplied alpha
+layout(location = 2) in vec2 inTexCoord;
+
+layout(location = 0) out vec4 fragColor;
+layout(location = 1) out vec2 fragTexCoord;
+
+vec3 srgbToLinear(vec3 c)
+{
+    vec3 low = c / 12.92;
+    vec3 high = pow((c + 0.055) / 1.055, vec3(2.4));
+    return mix(high, low, lessThanEqual(c, vec3(0.04045)));
+}
+
+void main() {
+    gl_Position = vec4(inPosition / pcs.screenSize * 2.0 - 1.0, 0.0, 1.0);
+    fragColor = vec4(srgbToLinear(inColor.rgb), inColor.a);
+    fragTexCoord = inTexCoord;
+}
@@ -0,0 +1,37 @@
+// Sparse texture of renderlib::sparse_texture::SparseTexture, bound as set 2 of the forward pass. Only levels the
+// page table marks resident are sampled, coarser ones stand in for the rest. Fragments report the level they would
+// like in the feedback buffer, which SparseTexture reads back to pick the pages it binds.
+#ifndef VIRTUAL_TEXTURE_SET
+#define VIRTUAL_TEXTURE_SET 2
+#endif
+
+const uint PAGE_TABLE_SIZE = 64; // PAGE_TABLE_SIZE in sparse_texture.rs
+const uint FEEDBACK_SPACING = 4; // One fragment in FEEDBACK_SPACING x FEEDBACK_SPACING reports
+
+// Kept in the GENERAL layout, pages are copied in while it is sampled
+layout(set = VIRTUAL_TEXTURE_SET, binding = 0) uniform sampler2D virtualTexture;
+layout(std140, set = VIRTUAL_TEXTURE_SET, binding = 1) uniform PageTable {
+    uvec4 size; // Cells across and down
+    uvec4 levels[PAGE_TABLE_SIZE * PAGE_TABLE_SIZE / 16]; // Finest resident level of every cell, a byte each
+} pageTable;
+layout(std430, set = VIRTUAL_TEXTURE_SET, binding = 2) buffer Feedback {
+    uint wanted[]; // Finest level wanted in every cell, reset to 0xFFFFFFFF once read back
+} feedback;
+
+uint residentLevel(uint cell)
+{
+    uint word = pageTable.levels[cell / 16][(cell / 4) % 4];
+    return (word >> ((cell % 4) * 8)) & 0xFF;
+}
+
+vec4 sampleVirtual(vec2 uv, vec2 fragCoord)
+{
+    uvec2 cellXY = min(uvec2(fract(uv) * vec2(pageTable.size.xy)), pageTable.size.xy - 1); // The sampler repeats
+    uint cell = cellXY.y * pageTable.size.x + cellXY.x;
+    float lod = max(textureQueryLod(virtualTexture, uv).y, 0.0);
+    if (all(equal(uvec2(fragCoord) % FEEDBACK_SPACING, uvec2(0)))) {
+        atomicMin(feedback.wanted[cell], uint(lod));
+    }
+    // Explicit levels lose anisotropic filtering, but never touch a page that isn't bound
+    return textureLod(virtualTexture, uv, max(lod, float(residentLevel(cell))));
+}
@@ -0,0 +1,32 @@
+#version 460
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+
+layout(location = 0) out vec3 fragColor;
+layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) out vec3 fragWorldPosition; // For forward_lit.frag
+layout(location = 3) out float fragViewDepth;
+
+// The view's matrices, bound at a dynamic offset per view
+layout(binding = 0) uniform ViewTransforms {
+    mat4 view;
+    mat4 proj;
+} ubo;
+
+// A chunk's exposed blocks by palette entry, each entry's indirect draw starts at its first block. See
+// renderlib::voxel::VoxelDraws
+layout(std430, binding = 2) readonly buffer Instances {
+    vec4 blocks[];
+};
+
+void main() {
+    vec4 worldPosition = vec4(inPosition + blocks[gl_InstanceIndex].xyz, 1.0);
+    vec4 viewPosition = ubo.view * worldPosition;
+    gl_Position = ubo.proj * viewPosition;
+    fragColor = inColor;
+    fragTexCoord = inTexCoord;
+    fragWorldPosition = worldPosition.xyz;
+    fragViewDepth = -viewPosition.z;
+}
@@ -0,0 +1,6 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+#define ATLAS_TILES
+#define BAKED_OCCLUSION
+#include "forward_lit.glsl"
@@ -0,0 +1,39 @@
+#version 460
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+layout(location = 7) in float inOcclusion; // Air blocks around the corner, 0 to 3
+
+layout(location = 0) out vec3 fragColor;
+layout(location = 1) out vec2 fragTexCoord;
+layout(location = 2) out vec3 fragWorldPosition; // For forward_lit.frag
+layout(location = 3) out float fragViewDepth;
+layout(location = 4) flat out vec4 fragTile;
+layout(location = 5) out float fragOcclusion;
+
+// The view's matrices, bound at a dynamic offset per view
+layout(binding = 0) uniform ViewTransforms {
+    mat4 view;
+    mat4 proj;
+} ubo;
+
+// The part of the texture each palette entry covers, corner in xy and size in zw. See renderlib::voxel_mesh
+layout(std430, binding = 2) readonly buffer Tiles {
+    vec4 tiles[];
+};
+
+// Light reaching a corner by its occlusion, a fully occluded corner still gets some so that it isn't black
+const float OCCLUSION_CURVE[4] = float[4](0.4, 0.6, 0.8, 1.0);
+
+// Vertices of voxel_mesh.comp are in world space, with the block in color.r
+void main() {
+    vec4 viewPosition = ubo.view * vec4(inPosition, 1.0);
+    gl_Position = ubo.proj * viewPosition;
+    fragColor = vec3(1.0);
+    fragTexCoord = inTexCoord;
+    fragWorldPosition = inPosition;
+    fragViewDepth = -viewPosition.z;
+    fragTile = tiles[uint(inColor.r) - 1];
+    fragOcclusion = OCCLUSION_CURVE[clamp(int(inOcclusion), 0, 3)];
+}
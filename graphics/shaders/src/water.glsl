@@ -0,0 +1,82 @@
+// Shades the water surface over the finished frame. Included by water.frag and water_ms.frag, the latter defines
+// MULTISAMPLED and reads sample 0 of the depth buffer. The pass has no depth attachment, fragments behind the scene
+// are discarded against the sampled depth buffer instead. sceneColor and reflection hold colors that already went
+// through the color pipeline, only the water's own color is put through it here.
+#include "colorcommon.glsl"
+#include "watercommon.glsl"
+
+#ifdef MULTISAMPLED
+layout(binding = 1) uniform sampler2DMS depthBuffer;
+#else
+layout(binding = 1) uniform sampler2D depthBuffer;
+#endif
+layout(binding = 2) uniform sampler2D sceneColor; // The frame without the water
+layout(binding = 3) uniform sampler2D reflection; // Drawn from the mirrored camera
+layout(push_constant) uniform constants {
+    ColorConstants color;
+} pcs;
+
+layout(location = 0) in vec3 fragWorldPosition;
+layout(location = 1) in vec3 fragRestPosition;
+layout(location = 2) in vec3 fragNormal;
+
+layout(location = 0) out vec4 outColor;
+
+const float WATER_F0 = 0.02; // Reflectance of water looking straight down
+const float AMBIENT = 0.05;
+
+float loadDepth(ivec2 pixel)
+{
+    return texelFetch(depthBuffer, pixel, 0).r;
+}
+
+// Distance from the camera along the view direction
+float viewDepth(float depth)
+{
+    return water.depth.y / (depth + water.depth.x);
+}
+
+void main() {
+    ivec2 size = textureSize(sceneColor, 0);
+    ivec2 pixel = ivec2(gl_FragCoord.xy);
+    float sceneDepth = loadDepth(pixel);
+    if (gl_FragCoord.z >= sceneDepth) {
+        discard;
+    }
+
+    vec3 normal = normalize(fragNormal);
+    vec3 toEye = normalize(water.eyeTime.xyz - fragWorldPosition);
+    vec2 ripple = normal.xy * water.depth.w;
+
+    // Refraction, the ripple offset is dropped where it would pick up something in front of the water
+    vec2 refractedUV = gl_FragCoord.xy / vec2(size) + ripple;
+    float refractedDepth = loadDepth(clamp(ivec2(refractedUV * vec2(size)), ivec2(0), size - 1));
+    if (refractedDepth <= gl_FragCoord.z) {
+        refractedUV = gl_FragCoord.xy / vec2(size);
+        refractedDepth = sceneDepth;
+    }
+    // Light from below fades to the water's color over the distance the view ray travels underwater, to about 5% at
+    // the clarity
+    float underwater = max(viewDepth(refractedDepth) - viewDepth(gl_FragCoord.z), 0.0);
+    float transmittance = exp(-3.0 * underwater / water.depth.z);
+    float lambert = max(dot(normal, -water.sunDirection.xyz), 0.0);
+    vec3 deep = applyColorPipeline(pcs.color, water.color.rgb * (AMBIENT + water.sunColor.rgb * lambert),
+                                   gl_FragCoord.xy);
+    vec3 refracted = mix(deep, texture(sceneColor, refractedUV).rgb, transmittance);
+
+    // The reflection target was drawn with reflectionViewProj, the rest position keeps the lookup on the mirror plane
+    vec4 reflectionClip = water.reflectionViewProj * vec4(fragRestPosition, 1.0);
+    vec2 reflectedUV = reflectionClip.xy / reflectionClip.w * 0.5 + 0.5 + ripple;
+    vec3 reflected = texture(reflection, reflectedUV).rgb;
+
+    // Schlick's approximation of the Fresnel term
+    float fresnel = WATER_F0 + (1.0 - WATER_F0) * pow(1.0 - max(dot(normal, toEye), 0.0), 5.0);
+    vec3 color = mix(refracted, reflected, fresnel);
+
+    // Sun glint
+    vec3 halfway = normalize(toEye - water.sunDirection.xyz);
+    vec3 glint = water.sunColor.rgb * pow(max(dot(normal, halfway), 0.0), 512.0) * fresnel;
+    color += applyColorPipeline(pcs.color, glint, gl_FragCoord.xy);
+
+    outColor = vec4(color, 1.0);
+}
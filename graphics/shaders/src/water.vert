@@ -0,0 +1,22 @@
+#version 460
+#extension GL_GOOGLE_include_directive : enable
+
+// Displaces the flat grid of renderlib::water::Water by its waves
+#include "watercommon.glsl"
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inColor;
+layout(location = 2) in vec2 inTexCoord;
+
+layout(location = 0) out vec3 fragWorldPosition;
+layout(location = 1) out vec3 fragRestPosition; // Before displacement, where the reflection is looked up
+layout(location = 2) out vec3 fragNormal;
+
+void main() {
+    vec3 normal;
+    vec3 position = inPosition + gerstner(inPosition.xy, normal);
+    gl_Position = water.viewProj * vec4(position, 1.0);
+    fragWorldPosition = position;
+    fragRestPosition = inPosition;
+    fragNormal = normal;
+}
@@ -0,0 +1,39 @@
+// Uniforms of renderlib::water::Water, shared by water.vert and water.glsl
+const uint MAX_WAVES = 4; // MAX_WAVES in water.rs
+const float GRAVITY = 9.81;
+
+layout(binding = 0) uniform WaterUniforms {
+    mat4 viewProj;
+    mat4 reflectionViewProj;
+    vec4 eyeTime; // Camera position, w is the time in seconds
+    vec4 depth; // proj[2][2] and proj[3][2] of the main view, the water's clarity and the ripple distortion
+    vec4 color; // Linear color of deep water
+    vec4 sunDirection; // Direction the first directional light travels in
+    vec4 sunColor; // Times its intensity, 0 without a directional light
+    vec4 waves[MAX_WAVES]; // Normalized direction, wavelength and amplitude
+    vec4 steepness; // Of each wave, unused waves have an amplitude and steepness of 0
+} water;
+
+// Sum of Gerstner waves at a point of the plane at rest. Crests move towards the wave's direction at the deep water
+// speed of their wavelength, and points on the surface circle around their rest position, sharpening the crests
+// as the steepness grows. The steepness of all waves should sum to 1 at most, beyond that the crests loop.
+vec3 gerstner(vec2 position, out vec3 normal)
+{
+    vec3 offset = vec3(0.0);
+    vec3 tangent = vec3(1.0, 0.0, 0.0);
+    vec3 binormal = vec3(0.0, 1.0, 0.0);
+    for (uint i = 0; i < MAX_WAVES; i++) {
+        vec2 direction = water.waves[i].xy;
+        float k = 2.0 * 3.14159265 / water.waves[i].z;
+        float amplitude = water.waves[i].w;
+        float steepness = water.steepness[i];
+        float f = k * (dot(direction, position) - sqrt(GRAVITY / k) * water.eyeTime.w);
+        float s = sin(f);
+        float c = cos(f);
+        offset += vec3(direction * (steepness / k * c), amplitude * s);
+        tangent += vec3(-direction.x * direction * steepness * s, direction.x * amplitude * k * c);
+        binormal += vec3(-direction.y * direction * steepness * s, direction.y * amplitude * k * c);
+    }
+    normal = normalize(cross(tangent, binormal));
+    return offset;
+}
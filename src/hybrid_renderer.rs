@@ -0,0 +1,343 @@
+use std::ffi::CString;
+use std::time::Instant;
+
+use winit::{
+    event::{Event, WindowEvent},
+    event_loop::{ControlFlow, EventLoop},
+    window::WindowId,
+};
+
+use renderlib::crash::CRASH_HISTORY_FRAMES;
+use renderlib::prelude::*;
+use rt_renderer::rt_hybrid::{HybridGeometry, HybridSettings, HybridTracer};
+use tracing::{debug, info_span, trace, warn};
+
+const MAX_FRAMES_IN_FLIGHT: usize = 2;
+// Like the rt renderer's, the swap chain is only a blit destination
+const SWAPCHAIN_USAGE: vk::ImageUsageFlags = vk::ImageUsageFlags::from_raw(
+    vk::ImageUsageFlags::TRANSFER_DST.as_raw() | vk::ImageUsageFlags::COLOR_ATTACHMENT.as_raw());
+
+// Rasterizes the first model of the scene into a GBuffer, which replaces the primary rays of the rt renderer. Ray
+// queries from the G-buffer's surfaces then only add the sun's shadows, ambient occlusion and reflections, see
+// HybridTracer. The light comes from the sky, the scene's lights, terrain and water are raster renderer features.
+// The exposure is manual, there is no post-process chain to meter it.
+pub struct HybridRenderer {
+    core: VkCore,
+    image_available_sems: Vec<vk::Semaphore>,
+    render_finished_sems: Vec<vk::Semaphore>,
+    in_flight_fences: Vec<vk::Fence>,
+    backend_resources: VkResources, // The swap chain's, created and recreated through a VkBackend
+    swapchain: SwapchainResources,
+    command_pool: vk::CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    gpu_timer: GpuTimer,
+    vertex_buffer: GpuBuffer,
+    index_buffer: IndexBuffer,
+    uniform_buffer: UniformBuffer,
+    descriptor: Descriptor,
+    texture: Texture,
+    sampler: vk::Sampler,
+    gbuffer: GBuffer,
+    tracer: HybridTracer,
+    current_frame: usize,
+    frame_index: u32, // Frames drawn, seeds the sampling of the traced rays
+    camera: SceneCamera,
+    model_matrix: cgmath::Matrix4<f32>,
+    sky: SkySettings,
+    color_pipeline: ColorPipeline,
+    swapchain_recreate: SwapchainRecreate,
+    crash: CrashHandler,
+    last_frame_start: Instant,
+    stats_overlay: StatsOverlay
+}
+
+impl HybridRenderer {
+    // Draws the first model of the scene with its material's texture
+    pub fn new(ev_loop: &EventLoop<()>, scene: &Scene, config: &LaunchConfig) -> HybridRenderer {
+        let _span = info_span!("hybrid_renderer_init").entered();
+        let model = scene.models.first().expect("The scene has no models");
+        if scene.models.len() > 1 {
+            warn!("Only the first of {} models is drawn", scene.models.len());
+        }
+        let texture_path = model.material.as_ref()
+            .and_then(|m| scene.material(m))
+            .and_then(|m| m.texture.clone())
+            .expect("The first model needs a textured material");
+        let required_extensions: Vec<CString> = Vec::from([
+            CString::from(vk::KhrSwapchainFn::NAME),
+            CString::from(vk::KhrAccelerationStructureFn::NAME),
+            CString::from(vk::KhrDeferredHostOperationsFn::NAME), // Required by VK_KHR_acceleration_structure
+            CString::from(vk::KhrRayQueryFn::NAME)
+        ]);
+        let core = config.create_core(ev_loop, &required_extensions);
+        let crash = CrashHandler::install(&core, config.crash_report.clone(), CRASH_HISTORY_FRAMES);
+        let mut backend_resources = VkResources::new(SWAPCHAIN_USAGE, config.present_mode(),
+                                                     config.display_mode.apply(&core));
+        // The G-buffer has its own depth
+        let swapchain = SwapchainResources::new(&mut VkBackend::new(&core, &mut backend_resources), None);
+        let render_target = backend_resources.render_target(swapchain.swapchain);
+        let pool_create_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(core.graphics_family_index);
+        let command_pool = unsafe { core.logical_device.create_command_pool(&pool_create_info, None).unwrap() };
+        let buf_create_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(MAX_FRAMES_IN_FLIGHT as u32);
+        let command_buffers = unsafe { core.logical_device.allocate_command_buffers(&buf_create_info).unwrap() };
+        let (image_available_sems, render_finished_sems, in_flight_fences) = setup_sync_objects(&core,
+                                                                                                MAX_FRAMES_IN_FLIGHT);
+        let gpu_timer = GpuTimer::new(&core, MAX_FRAMES_IN_FLIGHT);
+
+        let (vertices, indices) = load_model(model.path.as_str());
+        let vertex_buffer = GpuBuffer::new_initialized(&core, command_pool, vk::BufferUsageFlags::VERTEX_BUFFER,
+                                                       vertices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let index_buffer = IndexBuffer::new(&core, command_pool, vk::BufferUsageFlags::INDEX_BUFFER,
+                                            indices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let texture = Texture::new(&core, command_pool, texture_path.as_str());
+        let sampler = create_sampler(&core, texture.mip_levels);
+        let uniform_buffer = UniformBuffer::new(&core, MAX_FRAMES_IN_FLIGHT);
+        let descriptor_layout = create_descriptor_set_layout(&core);
+        let descriptor = Descriptor::new(&core, &uniform_buffer, sampler, &texture, descriptor_layout,
+                                         MAX_FRAMES_IN_FLIGHT);
+        let gbuffer = GBuffer::new(&core, command_pool, render_target.extent, descriptor_layout);
+        let model_matrix = model.transform.matrix();
+        let geometry = HybridGeometry {
+            vertices: vertices.as_slice(),
+            indices: indices.as_slice(),
+            model: model_matrix
+        };
+        let tracer = HybridTracer::new(&core, command_pool, render_target, &gbuffer, &geometry,
+                                       HybridSettings::default(), MAX_FRAMES_IN_FLIGHT);
+
+        HybridRenderer {
+            core,
+            image_available_sems,
+            render_finished_sems,
+            in_flight_fences,
+            backend_resources,
+            swapchain,
+            command_pool,
+            command_buffers,
+            gpu_timer,
+            vertex_buffer,
+            index_buffer,
+            uniform_buffer,
+            descriptor,
+            texture,
+            sampler,
+            gbuffer,
+            tracer,
+            current_frame: 0,
+            frame_index: 0,
+            camera: scene.camera.clone(),
+            model_matrix,
+            sky: SkySettings::default(),
+            color_pipeline: ColorPipeline::default(),
+            swapchain_recreate: SwapchainRecreate::default(),
+            crash,
+            last_frame_start: Instant::now(),
+            stats_overlay: StatsOverlay::new("Cubulous (hybrid)")
+        }
+    }
+
+    // Applied from the next frame
+    pub fn set_hybrid_settings(&mut self, settings: HybridSettings) {
+        self.tracer.set_settings(settings);
+    }
+
+    pub fn hybrid_settings(&self) -> HybridSettings {
+        self.tracer.settings()
+    }
+
+    pub fn set_sky(&mut self, sky: SkySettings) {
+        self.sky = sky;
+    }
+
+    pub fn sky(&self) -> SkySettings {
+        self.sky
+    }
+
+    pub fn set_camera(&mut self, camera: SceneCamera) {
+        self.camera = camera;
+    }
+
+    pub fn set_color_pipeline(&mut self, color_pipeline: ColorPipeline) {
+        self.color_pipeline = color_pipeline;
+    }
+
+    fn render_target(&self) -> &RenderTarget {
+        self.backend_resources.render_target(self.swapchain.swapchain)
+    }
+
+    fn record_command_buffer(&self, image_index: u32) {
+        let logical_device = &self.core.logical_device;
+        let command_buffer = self.command_buffers[self.current_frame];
+        let render_target = self.render_target();
+        let present_image = unsafe {
+            render_target.swap_loader.get_swapchain_images(render_target.swap_chain).unwrap()[image_index as usize]
+        };
+        // The blit to an SRGB swap chain encodes, otherwise the shader has to
+        let color_constants = self.color_pipeline.constants(self.tracer.output_format(render_target.surface_format));
+        let layout = self.gbuffer.pipeline_layout();
+
+        unsafe {
+            logical_device.begin_command_buffer(command_buffer, &vk::CommandBufferBeginInfo::default()).unwrap();
+            self.gpu_timer.cmd_begin(&self.core, command_buffer, self.current_frame);
+            self.gbuffer.cmd_begin(&self.core, command_buffer);
+            logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.vertex_buffer.buf], &[0]);
+            self.index_buffer.cmd_bind(&self.core, command_buffer);
+            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, layout, 0,
+                                                    &[self.descriptor.sets[self.current_frame]], &[]);
+            logical_device.cmd_draw_indexed(command_buffer, self.index_buffer.index_count(), 1, 0, 0, 0);
+            self.gbuffer.cmd_end(&self.core, command_buffer);
+            self.tracer.cmd_trace(&self.core, command_buffer, self.current_frame, &color_constants, present_image);
+            self.gpu_timer.cmd_end(&self.core, command_buffer, self.current_frame);
+            logical_device.end_command_buffer(command_buffer).unwrap();
+        }
+    }
+
+    // Waits for the device before the old swap chain goes, see SwapchainResources::recreate
+    fn recreate_swap_chain(&mut self) {
+        debug!(present_mode = ?self.backend_resources.present_mode, "Recreating swap chain");
+        self.swapchain.recreate(&mut VkBackend::new(&self.core, &mut self.backend_resources));
+        let render_target = self.backend_resources.render_target(self.swapchain.swapchain);
+        self.gbuffer.resize(&self.core, self.command_pool, render_target.extent);
+        self.tracer.resize(&self.core, self.command_pool, render_target, &self.gbuffer);
+        self.swapchain_recreate.finish(&self.core, render_target);
+    }
+
+    // A panic while drawing idles the device and aborts, see CrashHandler
+    pub fn run_blocking(mut self, event_loop: EventLoop<()>) {
+        let crash = self.crash.clone();
+        event_loop.run(move |event, _, control_flow| {
+            control_flow.set_poll();
+
+            match event {
+                Event::WindowEvent {
+                    event: WindowEvent::CloseRequested,
+                    window_id,
+                } if window_id == self.window_id() => *control_flow = ControlFlow::Exit,
+                Event::WindowEvent { event, window_id } if window_id == self.window_id() =>
+                    self.swapchain_recreate.handle_window_event(&event),
+                Event::MainEventsCleared => self.core.window.request_redraw(),
+                Event::RedrawRequested(window_id) if window_id == self.window_id() =>
+                    crash.run_guarded(|| self.draw_frame()),
+                Event::LoopDestroyed => unsafe { self.core.logical_device.device_wait_idle().unwrap() },
+                _ => ()
+            }
+        });
+    }
+
+    fn window_id(&self) -> WindowId {
+        self.core.window.id()
+    }
+
+    fn draw_frame(&mut self) {
+        // A minimized window has a zero sized surface, nothing can be presented until it is restored
+        let size = self.core.window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            return;
+        }
+        if self.swapchain_recreate.is_pending() {
+            self.recreate_swap_chain();
+        }
+
+        let frame_start = Instant::now();
+        let current_frame = self.current_frame;
+        let fences = [self.in_flight_fences[current_frame]];
+        let wait_sems = [self.image_available_sems[current_frame]];
+        let sig_sems = [self.render_finished_sems[current_frame]];
+        let command_buffers = [self.command_buffers[current_frame]];
+        // The G-buffer pass waits on the image too, its attachments are shared with the previous frame
+        let wait_stages = [vk::PipelineStageFlags::TOP_OF_PIPE];
+        let extent = self.swapchain.extent;
+
+        let wait_time = unsafe {
+            let wait_start = Instant::now();
+            self.core.logical_device.wait_for_fences(&fences, true, u64::MAX).unwrap();
+            wait_start.elapsed()
+        };
+        // Of the frame that last used this slot
+        let gpu_ms = self.gpu_timer.frame_time_ms(&self.core, current_frame);
+        self.uniform_buffer.set_transforms(current_frame, self.model_matrix, self.camera.view(),
+                                           self.camera.projection(extent));
+        self.tracer.update(current_frame, self.camera.view(), self.camera.projection(extent), self.camera.eye(),
+                           &self.sky, self.frame_index);
+
+        unsafe {
+            let render_target = self.backend_resources.render_target(self.swapchain.swapchain);
+            let acquire_result = render_target.swap_loader.acquire_next_image(render_target.swap_chain, u64::MAX,
+                                                                              wait_sems[0], vk::Fence::null());
+            // An out of date swap chain is recreated at the start of the next frame, the fence is still signaled
+            let image_index = match self.swapchain_recreate.check_acquire(acquire_result) {
+                Some(idx) => idx,
+                None => return
+            };
+            self.core.logical_device.reset_fences(&fences).unwrap();
+            self.core.logical_device.reset_command_buffer(command_buffers[0], vk::CommandBufferResetFlags::empty())
+                .unwrap();
+            self.record_command_buffer(image_index);
+            let submit_info = vk::SubmitInfo::default()
+                .wait_semaphores(&wait_sems)
+                .wait_dst_stage_mask(&wait_stages)
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&sig_sems);
+            self.core.logical_device.queue_submit(self.core.graphics_queue, &[submit_info], fences[0]).unwrap();
+            trace!(frame = current_frame, image = image_index, "Submitted frame");
+
+            let render_target = self.render_target();
+            let swap_chains = [render_target.swap_chain];
+            let image_indices = [image_index];
+            let present_info = vk::PresentInfoKHR::default()
+                .wait_semaphores(&sig_sems)
+                .swapchains(&swap_chains)
+                .image_indices(&image_indices);
+            let present_result = render_target.swap_loader.queue_present(self.core.present_queue, &present_info);
+            self.swapchain_recreate.check_present(present_result);
+        }
+
+        self.current_frame = (current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.frame_index = self.frame_index.wrapping_add(1);
+        let stats = FrameStats {
+            frame_ms: frame_start.duration_since(self.last_frame_start).as_secs_f64() * 1000.0,
+            cpu_ms: (frame_start.elapsed() - wait_time).as_secs_f64() * 1000.0,
+            gpu_ms,
+            passes: Vec::new(),
+            presents: Vec::new()
+        };
+        let settings = self.tracer.settings();
+        let extra = format!("shadows {} | reflections {} | AO rays {}", settings.shadows, settings.reflections,
+                            settings.ao_rays);
+        self.stats_overlay.record(&self.core.window, &stats, extra.as_str());
+        self.crash.record(stats);
+        self.last_frame_start = frame_start;
+    }
+}
+
+impl Drop for HybridRenderer {
+    fn drop(&mut self) {
+        unsafe { self.core.logical_device.device_wait_idle().unwrap() };
+        self.tracer.destroy(&self.core);
+        self.gbuffer.destroy(&self.core);
+        self.gpu_timer.destroy(&self.core);
+        self.descriptor.destroy(&self.core);
+        self.uniform_buffer.destroy(&self.core);
+        destroy_sampler(&self.core, self.sampler);
+        self.texture.destroy(&self.core);
+        self.index_buffer.destroy(&self.core);
+        self.vertex_buffer.destroy(&self.core);
+        unsafe {
+            for ((&i, &r), &f) in self.image_available_sems.iter().zip(self.render_finished_sems.iter())
+                .zip(self.in_flight_fences.iter()) {
+                self.core.logical_device.destroy_semaphore(i, None);
+                self.core.logical_device.destroy_semaphore(r, None);
+                self.core.logical_device.destroy_fence(f, None);
+            }
+            self.core.logical_device.destroy_command_pool(self.command_pool, None);
+        }
+        self.swapchain.destroy(&mut VkBackend::new(&self.core, &mut self.backend_resources));
+        self.crash.disarm();
+        self.core.destroy();
+    }
+}
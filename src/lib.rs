@@ -0,0 +1,81 @@
+use std::path::PathBuf;
+
+use winit::event_loop::EventLoop;
+
+use renderlib::config::DEFAULT_SCENE_PATH;
+use renderlib::logging::init_logging;
+use renderlib::prelude::*;
+use rt_renderer::rt_renderer::RtRenderer;
+
+use crate::hybrid_renderer::HybridRenderer;
+use crate::raster_renderer::RasterRenderer;
+
+pub mod hybrid_renderer;
+pub mod raster_renderer;
+
+// Starts the renderer picked by config, shared by the main binary and the examples
+pub fn run(config: LaunchConfig) {
+    init_logging();
+    if config.list_gpus {
+        for gpu in enumerate_gpus().unwrap() {
+            println!("{}", gpu.summary());
+        }
+        return;
+    }
+    let event_loop = EventLoop::new();
+
+    match config.renderer {
+        RendererKind::Raster => {
+            if config.record.is_some() || config.replay.is_some() {
+                config.reject("--record and --replay are only supported by the rt renderer");
+            }
+            if config.internal_size.is_some() || config.aspect.is_some() {
+                config.reject("--internal-size and --aspect are only supported by the rt renderer");
+            }
+            let scene = config.load_scene(DEFAULT_SCENE_PATH).unwrap();
+            let renderer = RasterRenderer::new(&event_loop, &scene, &config);
+            if let Some(spline_path) = &config.benchmark {
+                let spline = CameraSpline::load(spline_path).unwrap();
+                let benchmark = Benchmark::new(spline, config.benchmark_frames, PathBuf::from("raster_benchmark"));
+                renderer.run_benchmark(event_loop, benchmark);
+            } else {
+                renderer.run_blocking(event_loop);
+            }
+        },
+        RendererKind::Hybrid => {
+            if config.benchmark.is_some() {
+                config.reject("--benchmark is only supported by the raster and rt renderers");
+            }
+            if config.record.is_some() || config.replay.is_some() {
+                config.reject("--record and --replay are only supported by the rt renderer");
+            }
+            if config.internal_size.is_some() || config.aspect.is_some() {
+                config.reject("--internal-size and --aspect are only supported by the rt renderer");
+            }
+            let scene = config.load_scene(DEFAULT_SCENE_PATH).unwrap();
+            let renderer = HybridRenderer::new(&event_loop, &scene, &config);
+            renderer.run_blocking(event_loop);
+        },
+        RendererKind::Rt => {
+            let mut renderer = RtRenderer::new_with_config(&event_loop, &config);
+            // The rt renderer keeps its built in camera unless a scene is given
+            if config.scene.is_some() {
+                renderer.load_scene(&config.load_scene(DEFAULT_SCENE_PATH).unwrap());
+            }
+
+            if let Some(spline_path) = &config.benchmark {
+                let spline = CameraSpline::load(spline_path).unwrap();
+                let benchmark = Benchmark::new(spline, config.benchmark_frames, PathBuf::from("rt_benchmark"));
+                renderer.run_benchmark(event_loop, benchmark);
+            } else if let Some(input_path) = &config.record {
+                let recorder = InputRecorder::new(input_path, renderer.window_size()).unwrap();
+                renderer.run_recording(event_loop, recorder);
+            } else if let Some(input_path) = &config.replay {
+                let replay = InputReplay::load(input_path).unwrap();
+                renderer.run_replay(event_loop, replay, config.hashes.clone().unwrap(), config.expect_hashes.clone());
+            } else {
+                renderer.run_blocking(event_loop);
+            }
+        }
+    }
+}
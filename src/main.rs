@@ -0,0 +1,6 @@
+use renderlib::config::LaunchConfig;
+
+// cubulous_client [--renderer raster|rt] [options], see --help
+fn main() {
+    cubulous_client::run(LaunchConfig::from_args());
+}
@@ -0,0 +1,2202 @@
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use cgmath::MetricSpace;
+use winit::{
+    event::{Event, WindowEvent},
+    event_loop::{ControlFlow, EventLoop},
+    window::WindowId,
+};
+
+use renderlib::config::DEFAULT_SCENE_PATH;
+use renderlib::crash::CRASH_HISTORY_FRAMES;
+use renderlib::proxy::check_screenshot_path;
+use renderlib::prelude::*;
+#[cfg(feature = "physics")]
+use renderlib::physics::rapier3d::prelude::RigidBodyType;
+use tracing::{debug, error, info_span, trace, warn};
+
+pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+// Cameras the scene is drawn from every frame, each has its own transforms, see view_slot
+const MAIN_VIEW: usize = 0; // The first viewport's
+const REFLECTION_VIEW: usize = 1; // Of the water
+// The other viewports follow, see split_view, and then the auxiliary cameras, see aux_view
+const VIEWS: usize = MAX_VIEWPORTS + MAX_AUX_CAMERAS + 1;
+// Passes the pipeline statistics are counted in, see FrameStats::passes
+const SHADOW_PASS: usize = 0;
+const OFFSCREEN_PASS: usize = 1; // The water's reflection and the auxiliary cameras
+const FORWARD_PASS: usize = 2; // The window's viewports, before post-processing
+const STATISTICS_PASSES: [&str; 3] = ["shadows", "offscreen", "forward"];
+// TRANSFER_SRC and TRANSFER_DST let the post-process chain copy the image out and back
+const SWAPCHAIN_USAGE: vk::ImageUsageFlags = vk::ImageUsageFlags::from_raw(
+    vk::ImageUsageFlags::COLOR_ATTACHMENT.as_raw() | vk::ImageUsageFlags::TRANSFER_SRC.as_raw() |
+        vk::ImageUsageFlags::TRANSFER_DST.as_raw());
+
+// SSAO and occlusion culling read the depth buffer after the render pass, so it has to be sampled and stored
+fn create_depth(core: &VkCore, render_target: &RenderTarget, command_pool: vk::CommandPool) -> Depth {
+    Depth::new_sampled(core, render_target, command_pool)
+}
+
+fn create_render_pass(core: &VkCore, render_target: &RenderTarget) -> vk::RenderPass {
+    setup_render_pass_stored_depth(core, render_target, find_depth_format(core), core.max_msaa_samples)
+}
+
+// Index of the uniform buffer and descriptor set of a view in a frame
+fn view_slot(frame: usize, view: usize) -> usize {
+    view * MAX_FRAMES_IN_FLIGHT + frame
+}
+
+// View of a viewport after the first, see set_viewports
+fn split_view(viewport: usize) -> usize {
+    REFLECTION_VIEW + viewport
+}
+
+// View of an auxiliary camera, by its slot
+fn aux_view(slot: usize) -> usize {
+    MAX_VIEWPORTS + 1 + slot
+}
+
+// Whether the view is drawn to the window rather than to a texture
+fn window_view(view: usize) -> bool {
+    view == MAIN_VIEW || (view > REFLECTION_VIEW && view < aux_view(0))
+}
+
+// The whole model is a single draw
+#[cfg(feature = "indirect-draw")]
+fn create_culler(core: &VkCore, command_pool: vk::CommandPool, render_target: &RenderTarget, depth: &Depth,
+                 vertices: &[Vertex], index_count: u32) -> OcclusionCuller {
+    let mut min = cgmath::Point3::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = cgmath::Point3::new(f32::MIN, f32::MIN, f32::MIN);
+    for v in vertices.iter() {
+        min = cgmath::Point3::new(min.x.min(v.pos[0]), min.y.min(v.pos[1]), min.z.min(v.pos[2]));
+        max = cgmath::Point3::new(max.x.max(v.pos[0]), max.y.max(v.pos[1]), max.z.max(v.pos[2]));
+    }
+    let draws = [vk::DrawIndexedIndirectCommand {
+        index_count,
+        instance_count: 1,
+        first_index: 0,
+        vertex_offset: 0,
+        first_instance: 0
+    }];
+
+    OcclusionCuller::new(core, command_pool, render_target.extent, depth.view, core.max_msaa_samples, &draws,
+                         &[DrawBounds::new(min, max)])
+}
+
+// const VERTICES: [Vertex; 8] = [
+//     Vertex {
+//         pos: [-0.5, -0.5, 0.0],
+//         color: [1.0, 0.0, 0.0],
+//         tex_coord: [1.0, 0.0]
+//     },
+//     Vertex {
+//         pos: [0.5, -0.5, 0.0],
+//         color: [0.0, 1.0, 0.0],
+//         tex_coord: [0.0, 0.0]
+//     },
+//     Vertex {
+//         pos: [0.5, 0.5, 0.0],
+//         color: [0.0, 0.0, 1.0],
+//         tex_coord: [0.0, 1.0]
+//     },
+//     Vertex {
+//         pos: [-0.5, 0.5, 0.0],
+//         color: [1.0, 1.0, 1.0],
+//         tex_coord: [1.0, 1.0]
+//     },
+//
+//     Vertex {
+//         pos: [-0.5, -0.5, -0.5],
+//         color: [1.0, 0.0, 0.0],
+//         tex_coord: [1.0, 0.0]
+//     },
+//     Vertex {
+//         pos: [0.5, -0.5, -0.5],
+//         color: [0.0, 1.0, 0.0],
+//         tex_coord: [0.0, 0.0]
+//     },
+//     Vertex {
+//         pos: [0.5, 0.5, -0.5],
+//         color: [0.0, 0.0, 1.0],
+//         tex_coord: [0.0, 1.0]
+//     },
+//     Vertex {
+//         pos: [-0.5, 0.5, -0.5],
+//         color: [1.0, 1.0, 1.0],
+//         tex_coord: [1.0, 1.0]
+//     },
+// ];
+//
+// const INDICES: [u32; 12] =  [0, 1, 2, 2, 3, 0, 4, 5, 6, 6, 7, 4];
+
+// What the device resources are created from, kept so that they can be created again after a device loss
+struct RasterAssets {
+    model_path: String,
+    texture_path: String, // Of the model's material
+    model_material: Option<String>,
+    lights: Vec<Light>,
+    terrain: Option<Terrain>,
+    water: Option<SceneWater>,
+    sky: SkySettings,
+    objects: ObjectList, // Added with add_object
+    voxels: Option<VoxelWorld>, // See set_voxel_world
+    voxel_meshing: VoxelMeshing
+}
+
+// The model and its texture, decoded on worker threads while the instance and device are created. DeviceResources::new
+// only waits on them when it uploads, creating the resources again loads the files on the spot.
+struct StartupLoads {
+    model: Preload<(Vec<Vertex>, Vec<u32>)>,
+    texture: Preload<Result<DecodedTexture, String>>
+}
+
+impl StartupLoads {
+    fn spawn(assets: &RasterAssets) -> StartupLoads {
+        let model_path = assets.model_path.clone();
+        let texture_path = assets.texture_path.clone();
+        StartupLoads {
+            model: Preload::spawn("model-load", move || load_model(model_path.as_str())),
+            texture: Preload::spawn("texture-load", move || decode_texture(texture_path.as_str()))
+        }
+    }
+}
+
+// Renderer state the device resources are created with, carried over when they are created again
+#[derive(Clone)]
+struct DeviceOptions {
+    present_mode: vk::PresentModeKHR,
+    display_mode: DisplayMode,
+    low_latency: bool,
+    frame_pacing: u32, // See DisplayTiming
+    ssao: SsaoSettings,
+    motion_blur: MotionBlurSettings,
+    texture_streaming: StreamingSettings,
+    grid: GridSettings,
+    outline: OutlineSettings,
+    aux_cameras: Vec<(AuxCameraId, AuxCamera)>, // Added again with the same ids
+    stereo: bool, // Where the device supports multiview
+    frame_share: bool // The swap chain is shared with the frame share's transfer queue, see --share-frames
+}
+
+// The model's texture. Huge ones are virtual textures where the device has sparse residency, sampled through set 2
+// of a pipeline with the layout kept here, and set 0 gets the placeholder.
+#[derive(Clone, Copy)]
+enum ModelTexture {
+    Streamed(TextureId),
+    Virtual(VirtualTextureId, vk::DescriptorSetLayout)
+}
+
+impl ModelTexture {
+    // What set 0 samples, and its generation
+    fn bound<'a>(&self, textures: &'a TextureStreamer) -> (&'a Texture, u64) {
+        match *self {
+            ModelTexture::Streamed(id) => (textures.texture(id), textures.generation(id)),
+            ModelTexture::Virtual(..) => (textures.placeholder(), 0)
+        }
+    }
+}
+
+// Everything created from the logical device. Pipelines and assets are created again from the SPIR-V and the
+// model and texture files when the device is lost, see RasterRenderer::recover_device.
+struct DeviceResources {
+    image_available_sems: Vec<vk::Semaphore>,
+    render_finished_sems: Vec<vk::Semaphore>,
+    in_flight_fences: Vec<vk::Fence>,
+    render_target: RenderTarget,
+    frame_share: bool, // See DeviceOptions
+    raster_pipeline: RasterPipeline,
+    render_pass: vk::RenderPass,
+    frame_buffers: Vec<vk::Framebuffer>,
+    command_pool: vk::CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    vertex_buffer: GpuBuffer,
+    index_buffer: IndexBuffer,
+    model_bounds: BoundingSphere, // Of the model, in object space
+    uniform_buffer: UniformBuffer,
+    descriptor: Descriptor,
+    textures: TextureStreamer,
+    texture: ModelTexture,
+    texture_generations: [u64; MAX_FRAMES_IN_FLIGHT], // Each frame's descriptor sets were last written with
+    sampler: vk::Sampler,
+    depth: Depth,
+    color: Color,
+    latency: LatencyGovernor,
+    display_timing: Option<DisplayTiming>,
+    ssao: Ssao,
+    post_process: PostProcessChain,
+    lights: ClusteredLights,
+    shadows: PointShadows,
+    terrain: Option<TerrainMesh>,
+    water: Option<Water>,
+    sky: Sky,
+    frame_constants: FrameConstantsBuffer,
+    grid: Grid,
+    debug_lines: DebugLines,
+    deletions: DeletionQueue,
+    pipeline_stats: Option<PipelineStatsQueries>,
+    gpu_timer: GpuTimer,
+    objects: ObjectDraws,
+    voxels: Option<VoxelDraws>,
+    outline: Outline,
+    aux_cameras: AuxCameras,
+    stereo: Option<StereoView>, // Over everything else, see --stereo
+    ui_draws: DebugUiDraws, // The debug UI, over the stereo view too
+    #[cfg(feature = "indirect-draw")]
+    culler: OcclusionCuller,
+    #[cfg(feature = "indirect-draw")]
+    async_compute: AsyncCompute // Builds the culler's depth pyramid
+}
+
+impl DeviceResources {
+    fn new(core: &VkCore, assets: &RasterAssets, options: DeviceOptions, preloads: Option<StartupLoads>)
+        -> DeviceResources {
+        let (preloaded_model, preloaded_texture) = preloads.map_or((None, None), |p| (Some(p.model), Some(p.texture)));
+        let latency = LatencyGovernor::new(core, options.low_latency);
+        let (image_available_sems, render_finished_sems, in_flight_fences) = setup_sync_objects(core,
+                                                                                                MAX_FRAMES_IN_FLIGHT);
+        let render_target = RenderTarget::new_with_display_mode(core, SWAPCHAIN_USAGE,
+                                                                vk::Format::B8G8R8A8_SRGB,
+                                                                Some(vk::ColorSpaceKHR::SRGB_NONLINEAR),
+                                                                options.present_mode, options.display_mode,
+                                                                options.frame_share);
+        let mut display_timing = DisplayTiming::new(core, options.frame_pacing);
+        match display_timing.as_mut() {
+            Some(timing) => timing.reset(&render_target),
+            None if options.frame_pacing > 0 => warn!("Frame pacing needs VK_GOOGLE_display_timing"),
+            None => ()
+        }
+        let render_pass = create_render_pass(core, &render_target);
+        let descriptor_layout = create_descriptor_set_layout(core);
+        let pool_create_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(core.graphics_family_index);
+        let command_pool = unsafe {
+            core.logical_device.create_command_pool(&pool_create_info, None).unwrap()
+        };
+        let mut shadows = PointShadows::new(core, command_pool, ShadowSettings::default(), MAX_FRAMES_IN_FLIGHT);
+        let mut lights = ClusteredLights::new(core, MAX_FRAMES_IN_FLIGHT, &shadows);
+        match lights.set_lights(assets.lights.as_slice()) {
+            Ok(()) => shadows.set_lights(assets.lights.as_slice()),
+            Err(e) => warn!("The scene's lights are ignored: {}", e)
+        }
+        let virtual_layout = match TextureStreamer::wants_virtual(core, assets.texture_path.as_str()) {
+            true => Some(create_virtual_texture_set_layout(core)),
+            false => None
+        };
+        let raster_pipeline = match virtual_layout {
+            Some(virtual_layout) => RasterPipeline::new_clustered_virtual(core, render_pass, descriptor_layout,
+                                                                          lights.set_layout(), virtual_layout,
+                                                                          core.max_msaa_samples),
+            None => RasterPipeline::new_clustered(core, render_pass, descriptor_layout, lights.set_layout(),
+                                                  core.max_msaa_samples)
+        };
+
+        let forward = ForwardPass {
+            render_pass,
+            lights: &lights,
+            msaa_samples: core.max_msaa_samples,
+            max_frames: MAX_FRAMES_IN_FLIGHT,
+            views: VIEWS
+        };
+        let terrain = assets.terrain.as_ref().map(|t| TerrainMesh::new(core, command_pool, &forward, t));
+        let sky = Sky::new(core, render_pass, core.max_msaa_samples, assets.sky, MAX_FRAMES_IN_FLIGHT, VIEWS);
+        let frame_constants = FrameConstantsBuffer::new(core, MAX_FRAMES_IN_FLIGHT);
+        let grid = Grid::new(core, render_pass, core.max_msaa_samples, &frame_constants, options.grid,
+                             MAX_FRAMES_IN_FLIGHT);
+        let debug_lines = DebugLines::new(core, render_pass, core.max_msaa_samples, &frame_constants,
+                                          MAX_FRAMES_IN_FLIGHT);
+        let mut objects = ObjectDraws::new(core, render_pass, &lights, core.max_msaa_samples, MAX_FRAMES_IN_FLIGHT,
+                                           VIEWS);
+        for (id, object) in assets.objects.iter() {
+            if let Err(e) = objects.add(core, command_pool, *id, object) {
+                warn!("{:?} is not drawn: {}", id, e);
+            }
+        }
+        let voxels = assets.voxels.as_ref().and_then(|world| {
+            VoxelDraws::new(core, command_pool, &forward, world, assets.voxel_meshing)
+                .map_err(|e| warn!("The voxel world is not drawn: {}", e))
+                .ok()
+        });
+
+        let depth = create_depth(core, &render_target, command_pool);
+        let color = Color::new(core, &render_target);
+        let frame_buffers = setup_frame_buffers(core, render_pass,
+                                                &render_target, depth.view,
+                                                color.view);
+
+        let buf_create_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(MAX_FRAMES_IN_FLIGHT as u32);
+        let command_buffers = unsafe { core.logical_device.allocate_command_buffers(&buf_create_info).unwrap() };
+        let (vertices, indices) = match preloaded_model {
+            Some(model) => model.wait(),
+            None => load_model(assets.model_path.as_str())
+        };
+        // let (vertices, indices) = (Vec::from(VERTICES), Vec::from(INDICES));
+        let vertex_buffer = GpuBuffer::new_initialized(core, command_pool, vk::BufferUsageFlags::VERTEX_BUFFER,
+                                                       vertices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let index_buffer = IndexBuffer::new(core, command_pool, vk::BufferUsageFlags::INDEX_BUFFER,
+                                            indices.as_slice(), vk::MemoryPropertyFlags::DEVICE_LOCAL);
+        let positions: Vec<cgmath::Point3<f32>> = vertices.iter().map(|v| cgmath::Point3::from(v.pos)).collect();
+        let model_bounds = BoundingSphere::from_points(positions.as_slice());
+        let uniform_buffer = UniformBuffer::new(core, MAX_FRAMES_IN_FLIGHT * VIEWS);
+        let mut textures = TextureStreamer::new(core, command_pool, options.texture_streaming, MAX_FRAMES_IN_FLIGHT);
+        let texture = match (virtual_layout, preloaded_texture.map(|t| t.wait().unwrap())) {
+            (Some(layout), Some(decoded)) => {
+                let id = textures.load_virtual_decoded(core, command_pool, layout, decoded).unwrap();
+                ModelTexture::Virtual(id, layout)
+            },
+            (Some(layout), None) => ModelTexture::Virtual(textures.load_virtual(core, command_pool, layout,
+                                                                                assets.texture_path.as_str()).unwrap(),
+                                                          layout),
+            (None, Some(decoded)) => ModelTexture::Streamed(textures.load_decoded(decoded)),
+            (None, None) => ModelTexture::Streamed(textures.load(assets.texture_path.as_str()).unwrap())
+        };
+        // let texture = Texture::new(&core, command_pool, "textures/texture.jpg");
+
+        let sampler = match texture {
+            ModelTexture::Streamed(id) => create_sampler(core, textures.mip_levels(id)),
+            ModelTexture::Virtual(..) => create_sampler(core, 1)
+        };
+        let (bound_texture, generation) = texture.bound(&textures);
+        let descriptor = Descriptor::new(core, &uniform_buffer, sampler, bound_texture, descriptor_layout,
+                                         MAX_FRAMES_IN_FLIGHT * VIEWS);
+        let texture_generations = [generation; MAX_FRAMES_IN_FLIGHT];
+        let stereo = match (options.stereo, core.multiview_supported) {
+            (true, true) => Some(StereoView::new(core, &render_target, sampler, bound_texture, MAX_FRAMES_IN_FLIGHT)),
+            (true, false) => {
+                warn!("Stereo rendering needs multiview, which the device doesn't support");
+                None
+            },
+            (false, _) => None
+        };
+        let ssao = Ssao::new(core, command_pool, &render_target, depth.view, core.max_msaa_samples, options.ssao);
+        let outline = Outline::new(core, command_pool, &render_target, options.outline);
+        let ui_draws = DebugUiDraws::new(core, &render_target, MAX_FRAMES_IN_FLIGHT);
+        let mut aux_cameras = AuxCameras::new(core, &render_target);
+        for (id, camera) in options.aux_cameras {
+            aux_cameras.insert(core, id, camera);
+        }
+        let water = assets.water.as_ref()
+            .map(|w| Water::new(core, command_pool, &render_target, depth.view, core.max_msaa_samples, &WaterInfo {
+                surface: w,
+                settings: WaterSettings::default(),
+                lights: assets.lights.as_slice()
+            }, MAX_FRAMES_IN_FLIGHT));
+        let mut post_process = PostProcessChain::new(core, command_pool, render_target.extent,
+                                                     Some((depth.view, core.max_msaa_samples)), MAX_FRAMES_IN_FLIGHT);
+        post_process.set_motion_blur_settings(options.motion_blur);
+        #[cfg(feature = "indirect-draw")]
+        let culler = create_culler(core, command_pool, &render_target, &depth, vertices.as_slice(),
+                                   index_buffer.index_count());
+        #[cfg(feature = "indirect-draw")]
+        let async_compute = AsyncCompute::new(core, MAX_FRAMES_IN_FLIGHT);
+
+        DeviceResources {
+            image_available_sems,
+            render_finished_sems,
+            in_flight_fences,
+            render_target,
+            frame_share: options.frame_share,
+            raster_pipeline,
+            render_pass,
+            frame_buffers,
+            command_pool,
+            command_buffers,
+            vertex_buffer,
+            index_buffer,
+            model_bounds,
+            uniform_buffer,
+            descriptor,
+            textures,
+            texture,
+            texture_generations,
+            sampler,
+            depth,
+            color,
+            latency,
+            display_timing,
+            ssao,
+            post_process,
+            lights,
+            shadows,
+            terrain,
+            water,
+            sky,
+            frame_constants,
+            grid,
+            debug_lines,
+            deletions: DeletionQueue::new(MAX_FRAMES_IN_FLIGHT),
+            pipeline_stats: PipelineStatsQueries::new(core, &STATISTICS_PASSES, MAX_FRAMES_IN_FLIGHT),
+            gpu_timer: GpuTimer::new(core, MAX_FRAMES_IN_FLIGHT),
+            objects,
+            voxels,
+            outline,
+            aux_cameras,
+            stereo,
+            ui_draws,
+            #[cfg(feature = "indirect-draw")]
+            culler,
+            #[cfg(feature = "indirect-draw")]
+            async_compute
+        }
+    }
+
+    // After destroy_swap_chain, everything that follows the window size is resized to the new swap chain
+    fn create_swap_chain(&mut self, core: &VkCore, present_mode: vk::PresentModeKHR, display_mode: DisplayMode) {
+        self.render_target = RenderTarget::new_with_display_mode(core, SWAPCHAIN_USAGE,
+                                                                 vk::Format::B8G8R8A8_SRGB,
+                                                                 Some(vk::ColorSpaceKHR::SRGB_NONLINEAR),
+                                                                 present_mode, display_mode, self.frame_share);
+        self.color = Color::new(core, &self.render_target);
+        self.depth = create_depth(core, &self.render_target, self.command_pool);
+        self.frame_buffers = setup_frame_buffers(core, self.render_pass,
+                                                 &self.render_target,
+                                                 self.depth.view, self.color.view);
+        self.ssao.resize(core, self.command_pool, &self.render_target, self.depth.view);
+        self.outline.resize(core, self.command_pool, &self.render_target);
+        self.aux_cameras.resize(core, &self.render_target);
+        if let Some(water) = self.water.as_mut() {
+            water.resize(core, self.command_pool, &self.render_target, self.depth.view);
+        }
+        self.post_process.resize(core, self.command_pool, self.render_target.extent, Some(self.depth.view));
+        #[cfg(feature = "indirect-draw")]
+        self.culler.resize(core, self.command_pool, self.render_target.extent, self.depth.view);
+        if let Some(stereo) = self.stereo.as_mut() {
+            stereo.resize(core, &self.render_target);
+        }
+        self.ui_draws.resize(core, &self.render_target);
+        self.latency.reset();
+        if let Some(timing) = self.display_timing.as_mut() {
+            timing.reset(&self.render_target);
+        }
+    }
+
+    // The swap chain and the window sized images, nothing may still be using them
+    fn destroy_swap_chain(&self, core: &VkCore) {
+        self.color.destroy(core);
+        self.depth.destroy(core);
+        destroy_frame_buffers(core, &self.frame_buffers);
+        self.render_target.destroy(core);
+    }
+
+    // Doesn't wait for the device, which may be lost
+    fn destroy(&mut self, core: &VkCore) {
+        self.deletions.destroy(core);
+        self.destroy_swap_chain(core);
+        destroy_sampler(core, self.sampler);
+        self.textures.destroy(core);
+        if let Some(terrain) = self.terrain.as_ref() {
+            terrain.destroy(core);
+        }
+        if let Some(water) = self.water.as_ref() {
+            water.destroy(core);
+        }
+        self.sky.destroy(core);
+        self.grid.destroy(core);
+        self.debug_lines.destroy(core);
+        if let Some(queries) = self.pipeline_stats.as_ref() {
+            queries.destroy(core);
+        }
+        self.gpu_timer.destroy(core);
+        self.frame_constants.destroy(core);
+        self.objects.destroy(core);
+        if let Some(voxels) = self.voxels.as_ref() {
+            voxels.destroy(core);
+        }
+        self.outline.destroy(core);
+        self.aux_cameras.destroy(core);
+        if let ModelTexture::Virtual(_, layout) = self.texture {
+            unsafe { core.logical_device.destroy_descriptor_set_layout(layout, None) };
+        }
+        self.descriptor.destroy(core);
+        self.index_buffer.destroy(core);
+        self.vertex_buffer.destroy(core);
+        unsafe {
+            for i in self.image_available_sems.iter() {
+                core.logical_device.destroy_semaphore(*i, None);
+            }
+            for r in self.render_finished_sems.iter() {
+                core.logical_device.destroy_semaphore(*r, None);
+            }
+            for f in self.in_flight_fences.iter() {
+                core.logical_device.destroy_fence(*f, None);
+            }
+            core.logical_device.destroy_command_pool(self.command_pool, None);
+        }
+        self.raster_pipeline.destroy(core);
+        self.uniform_buffer.destroy(core);
+        self.ssao.destroy(core);
+        self.post_process.destroy(core);
+        self.lights.destroy(core);
+        self.shadows.destroy(core);
+        #[cfg(feature = "indirect-draw")]
+        self.culler.destroy(core);
+        if let Some(stereo) = self.stereo.as_ref() {
+            stereo.destroy(core);
+        }
+        self.ui_draws.destroy(core);
+        #[cfg(feature = "indirect-draw")]
+        self.async_compute.destroy(core);
+        destroy_render_pass(core, self.render_pass);
+    }
+}
+
+pub struct RasterRenderer {
+    core: VkCore, // Window, instance, devices and queues
+    device: DeviceResources,
+    assets: RasterAssets,
+    current_frame: usize,
+    color_pipeline: ColorPipeline,
+    settings: RendererSettings,
+    camera: SceneCamera,
+    main_viewport: ViewportRect, // The camera's part of the window
+    split_viewports: Vec<Viewport>, // Drawn after the main view with their own cameras
+    model_matrix: cgmath::Matrix4<f32>,
+    previous_view_projection: Option<cgmath::Matrix4<f32>>, // Of the last drawn frame, for motion blur
+    present_mode: vk::PresentModeKHR,
+    display_mode: DisplayMode,
+    swapchain_recreate: SwapchainRecreate,
+    device_lost: DeviceLost,
+    commands: CommandQueue,
+    screenshots: VecDeque<(PathBuf, Sender<Result<PathBuf, String>>)>, // Taken one per frame
+    crash: CrashHandler,
+    last_frame_start: Instant,
+    last_cpu_ms: f64, // Of the last drawn frame, for benchmarks
+    last_gpu_ms: Option<f64>, // From the GPU timer, of the frame that last used the current slot
+    clock: Clock, // Ticked at the start of every frame
+    limiter: FrameLimiter, // Waited on before every frame
+    idle: IdleTracker, // Skips frames while idle, see RendererSettings::idle_behavior
+    stats_overlay: StatsOverlay,
+    debug_ui: DebugUi, // Toggled with DEBUG_UI_TOGGLE_KEY
+    material_panel: Option<MaterialPanel>, // Edits the materials of the scene file
+    selection: Vec<ObjectId>, // Outlined, see set_selection
+    chunk_streamer: Option<ChunkStreamer>, // Loads the voxel world around the camera, see set_chunk_streaming
+    block_editor: BlockEditor, // Clicks edit the voxel world's blocks under the cursor
+    frame_index: u64, // Frames recorded so far, for the frame constants
+    debug_flags: u32, // See set_debug_flags
+    #[cfg(unix)]
+    frame_share: Option<FrameShare>, // Copies of the presented frames for other processes, see --share-frames
+    #[cfg(feature = "physics")]
+    physics: Option<PhysicsWorld> // Stepped every frame, see set_physics
+}
+
+impl RasterRenderer {
+    // Draws the first model of the scene with its material's texture
+    pub fn new(ev_loop: &EventLoop<()>, scene: &Scene, config: &LaunchConfig) -> RasterRenderer {
+        let _span = info_span!("raster_renderer_init").entered();
+        let model = scene.models.first().expect("The scene has no models");
+        if scene.models.len() > 1 {
+            warn!("Only the first of {} models is drawn", scene.models.len());
+        }
+        let texture_path = model.material.as_ref()
+            .and_then(|m| scene.material(m))
+            .and_then(|m| m.texture.clone())
+            .expect("The first model needs a textured material");
+        let required_extensions: Vec<CString> = Vec::from([
+            CString::from(vk::KhrSwapchainFn::NAME), // Equivalent to the Vulkan VK_KHR_SWAPCHAIN_EXTENSION_NAME
+        ]);
+        let mut assets = RasterAssets {
+            model_path: model.path.clone(),
+            texture_path,
+            model_material: model.material.clone(),
+            lights: scene.lights.clone(),
+            terrain: None,
+            water: scene.water.clone(),
+            sky: SkySettings::default(),
+            objects: ObjectList::default(),
+            voxels: None,
+            voxel_meshing: VoxelMeshing::default()
+        };
+        let preloads = StartupLoads::spawn(&assets);
+        let terrain = scene.terrain.clone()
+            .map(|t| Preload::spawn("terrain-load", move || Terrain::load(&t, TerrainSettings::default()).unwrap()));
+        let core = config.create_core(ev_loop, &required_extensions);
+        let crash = CrashHandler::install(&core, config.crash_report.clone(), CRASH_HISTORY_FRAMES);
+        let present_mode = config.present_mode();
+        let display_mode = config.display_mode.apply(&core);
+        assets.terrain = terrain.map(Preload::wait);
+        let debug_ui = DebugUi::new(&core.window);
+        let material_panel = MaterialEditor::load(config.scene.as_deref().unwrap_or(DEFAULT_SCENE_PATH))
+            .map(MaterialPanel::new)
+            .map_err(|e| warn!("The materials can't be edited: {}", e))
+            .ok();
+        let options = DeviceOptions {
+            present_mode,
+            display_mode,
+            low_latency: config.low_latency,
+            frame_pacing: config.frame_pacing,
+            ssao: SsaoSettings {
+                enabled: !config.no_ssao,
+                ..SsaoSettings::default()
+            },
+            motion_blur: MotionBlurSettings {
+                enabled: config.motion_blur,
+                ..MotionBlurSettings::default()
+            },
+            texture_streaming: StreamingSettings::default(),
+            grid: GridSettings::default(),
+            outline: OutlineSettings::default(),
+            aux_cameras: Vec::new(),
+            stereo: config.stereo,
+            frame_share: cfg!(unix) && config.share_frames.is_some()
+        };
+        let device = DeviceResources::new(&core, &assets, options, Some(preloads));
+        #[cfg(unix)]
+        let frame_share = config.share_frames.as_ref().and_then(|path| {
+            FrameShare::bind(&core, path, device.render_target.extent, device.render_target.surface_format)
+                .map_err(|e| warn!("Not sharing frames: {}", e))
+                .ok()
+        });
+        #[cfg(not(unix))]
+        if config.share_frames.is_some() {
+            warn!("Frames can only be shared on Unix");
+        }
+
+        RasterRenderer {
+            core,
+            device,
+            assets,
+            current_frame: 0,
+            color_pipeline: ColorPipeline::default(),
+            settings: RendererSettings::from_config(config),
+            camera: scene.camera.clone(),
+            main_viewport: ViewportRect::FULL,
+            split_viewports: Vec::new(),
+            model_matrix: model.transform.matrix(),
+            previous_view_projection: None,
+            present_mode,
+            display_mode,
+            swapchain_recreate: SwapchainRecreate::default(),
+            device_lost: DeviceLost::default(),
+            commands: CommandQueue::default(),
+            screenshots: VecDeque::new(),
+            crash,
+            last_frame_start: Instant::now(),
+            last_cpu_ms: 0.0,
+            last_gpu_ms: None,
+            clock: Clock::new(),
+            limiter: FrameLimiter::new(config.max_fps),
+            idle: IdleTracker::default(),
+            stats_overlay: StatsOverlay::new("Cubulous (raster)"),
+            debug_ui,
+            material_panel,
+            selection: Vec::new(),
+            chunk_streamer: None,
+            block_editor: BlockEditor::default(),
+            frame_index: 0,
+            debug_flags: 0,
+            #[cfg(unix)]
+            frame_share,
+            #[cfg(feature = "physics")]
+            physics: None
+        }
+    }
+
+    // Passed to the shaders in the frame constants from the next frame. The bits' meaning is up to the shaders that
+    // read them.
+    pub fn set_debug_flags(&mut self, debug_flags: u32) {
+        self.debug_flags = debug_flags;
+    }
+
+    pub fn debug_flags(&self) -> u32 {
+        self.debug_flags
+    }
+
+    // Takes effect from the next frame, returns the mode actually used
+    pub fn set_display_mode(&mut self, display_mode: DisplayMode) -> DisplayMode {
+        self.display_mode = display_mode.apply(&self.core);
+        self.swapchain_recreate.request();
+        self.display_mode
+    }
+
+    // Throttles frame submission to the display, see LatencyGovernor. Only the interactive loop throttles, benchmarks
+    // run as fast as possible.
+    pub fn set_low_latency(&mut self, enabled: bool) {
+        self.device.latency.set_enabled(enabled);
+    }
+
+    pub fn low_latency(&self) -> bool {
+        self.device.latency.is_enabled()
+    }
+
+    // Caps the frame rate on the CPU, whatever the present mode, see FrameLimiter
+    pub fn set_max_fps(&mut self, max_fps: Option<f32>) {
+        self.limiter.set_max_fps(max_fps);
+    }
+
+    pub fn max_fps(&self) -> Option<f32> {
+        self.limiter.max_fps()
+    }
+
+    // Takes effect from the next frame, also toggled with SSAO_TOGGLE_KEY
+    pub fn set_ssao_settings(&mut self, settings: SsaoSettings) {
+        self.device.ssao.set_settings(settings);
+    }
+
+    pub fn ssao_settings(&self) -> SsaoSettings {
+        self.device.ssao.settings()
+    }
+
+    // Takes effect from the next frame, also toggled with GRID_TOGGLE_KEY
+    pub fn set_grid_settings(&mut self, settings: GridSettings) {
+        self.device.grid.set_settings(settings);
+    }
+
+    pub fn grid_settings(&self) -> GridSettings {
+        self.device.grid.settings()
+    }
+
+    // Takes effect from the next frame
+    pub fn set_outline_settings(&mut self, settings: OutlineSettings) {
+        self.device.outline.set_settings(settings);
+    }
+
+    pub fn outline_settings(&self) -> OutlineSettings {
+        self.device.outline.settings()
+    }
+
+    // Applied from the next recorded frame
+    pub fn set_settings(&mut self, settings: RendererSettings) {
+        self.settings = settings;
+    }
+
+    pub fn settings(&self) -> RendererSettings {
+        self.settings
+    }
+
+    // ColorPipeline::exposure, times the adapted exposure unless the exposure is manual
+    fn exposure(&self) -> f32 {
+        let adapted = match self.settings.exposure {
+            ExposureMode::Auto(_) => self.device.post_process.adapted_exposure(self.current_frame),
+            ExposureMode::Manual => 1.0
+        };
+        self.color_pipeline.exposure * adapted
+    }
+
+    // Replaces the scene's lights from the next frame. Without any the model is drawn unlit.
+    pub fn set_lights(&mut self, lights: &[Light]) -> Result<(), String> {
+        self.device.lights.set_lights(lights)?;
+        self.device.shadows.set_lights(lights);
+        if let Some(water) = self.device.water.as_mut() {
+            water.set_lights(lights);
+        }
+        self.assets.lights = lights.to_vec();
+
+        Ok(())
+    }
+
+    // Moves the sun light along with the sky, it replaces the first directional light of the scene or is added to the
+    // lights. The sky is applied even if the lights can't be.
+    pub fn set_sky(&mut self, sky: SkySettings) -> Result<(), String> {
+        self.device.sky.set_settings(sky);
+        self.assets.sky = sky;
+        let mut lights = self.assets.lights.clone();
+        match lights.iter_mut().find(|l| matches!(l, Light::Directional { .. })) {
+            Some(sun) => *sun = sky.sun_light(),
+            None => lights.push(sky.sun_light())
+        }
+        self.set_lights(lights.as_slice())
+    }
+
+    pub fn sky(&self) -> SkySettings {
+        self.assets.sky
+    }
+
+    // hours is the local solar time, see SkySettings
+    pub fn set_time_of_day(&mut self, hours: f32) -> Result<(), String> {
+        self.set_sky(SkySettings { time_of_day: hours, ..self.assets.sky })
+    }
+
+    // For height queries, like keeping a camera or objects on the ground
+    pub fn terrain(&self) -> Option<&Terrain> {
+        self.assets.terrain.as_ref()
+    }
+
+    pub fn set_motion_blur_settings(&mut self, settings: MotionBlurSettings) {
+        self.device.post_process.set_motion_blur_settings(settings);
+    }
+
+    pub fn motion_blur_settings(&self) -> MotionBlurSettings {
+        self.device.post_process.motion_blur_settings()
+    }
+
+    // The budget is taken from the memory stats again, levels follow from the next frame
+    pub fn set_texture_streaming(&mut self, settings: StreamingSettings) {
+        self.device.textures.set_settings(&self.core, settings);
+    }
+
+    pub fn texture_streaming(&self) -> StreamingSettings {
+        self.device.textures.settings()
+    }
+
+    // Depth of field, aperture 0 turns it off. See SceneCamera.
+    pub fn set_camera_lens(&mut self, focus_distance: f32, aperture: f32) {
+        self.camera.focus_distance = Some(focus_distance);
+        self.camera.aperture = aperture;
+    }
+
+    // Takes effect from the next frame, see CameraProjection
+    pub fn set_projection(&mut self, projection: CameraProjection) -> Result<(), String> {
+        projection.validate()?;
+        self.camera.projection = projection;
+        self.previous_view_projection = None;
+
+        Ok(())
+    }
+
+    pub fn projection(&self) -> CameraProjection {
+        self.camera.projection
+    }
+
+    // The depth buffer of the last submitted frame's main view, waits for the device to become idle
+    pub fn read_depth(&self) -> DepthImage {
+        unsafe { self.core.logical_device.device_wait_idle().unwrap() };
+        read_depth(&self.core, self.device.command_pool, self.device.depth.view, self.core.max_msaa_samples,
+                   self.device.render_target.extent, self.camera.projection(self.main_extent()))
+    }
+
+    // Draws that survived occlusion culling in the last submitted frame, stalls until the GPU has finished it
+    #[cfg(feature = "indirect-draw")]
+    pub fn visible_draw_count(&self) -> u32 {
+        self.device.culler.visible_draw_count(&self.core, self.device.command_pool)
+    }
+
+    // Called after every swap chain recreate, for anything that has to follow the swap chain's size
+    pub fn add_swapchain_callback(&mut self, callback: SwapchainCallback) {
+        self.swapchain_recreate.add_callback(callback);
+    }
+
+    // For other threads, commands sent through it are processed at the start of every frame
+    pub fn proxy(&self) -> RendererProxy {
+        self.commands.proxy()
+    }
+
+    // Replaces the drawn model, and its texture when one is given. Everything on the device is created again.
+    pub fn load_model(&mut self, path: String, texture: Option<String>) -> Result<(), String> {
+        if let Some(missing) = iter::once(&path).chain(texture.as_ref()).find(|p| !Path::new(p).is_file()) {
+            return Err(format!("{} not found", missing));
+        }
+        self.assets.model_path = path;
+        if let Some(texture) = texture {
+            self.assets.texture_path = texture;
+        }
+        unsafe { self.core.logical_device.device_wait_idle().unwrap() };
+        let options = self.device_options();
+        self.device.destroy(&self.core);
+        self.device = DeviceResources::new(&self.core, &self.assets, options, None);
+        self.current_frame = 0;
+        self.previous_view_projection = None;
+
+        Ok(())
+    }
+
+    // Drawn from the next frame until remove_object, objects with the same mesh share its buffers. Without a texture,
+    // the material's base color is drawn flat.
+    pub fn add_object(&mut self, mesh: Arc<Mesh>, transform: cgmath::Matrix4<f32>, material: Material)
+        -> Result<ObjectId, String> {
+        let object = SceneObject::new(mesh, transform, material);
+        let id = self.assets.objects.add(object.clone());
+        if let Err(e) = self.device.objects.add(&self.core, self.device.command_pool, id, &object) {
+            let _ = self.assets.objects.remove(id);
+            return Err(e);
+        }
+        self.device.shadows.invalidate();
+
+        Ok(id)
+    }
+
+    // The objects with material's name are drawn with it from the next frame, see ObjectDraws::set_material. Only the
+    // texture of the model's material is drawn, a new one reloads the model like load_model.
+    pub fn set_material(&mut self, material: Material) -> Result<(), String> {
+        let objects = self.device.objects.set_material(&self.core, self.device.command_pool, &mut self.device.deletions,
+                                                       self.frame_index, &material)?;
+        self.assets.objects.set_material(&material);
+        let model_texture = match self.assets.model_material.as_deref() == Some(material.name.as_str()) {
+            true => material.texture.clone().filter(|t| *t != self.assets.texture_path),
+            false => None
+        };
+        debug!(material = material.name.as_str(), objects, model = model_texture.is_some(), "Set material");
+        match model_texture {
+            Some(texture) => self.load_model(self.assets.model_path.clone(), Some(texture)),
+            None => Ok(())
+        }
+    }
+
+    // Drawn with the new transform from the next frame, its shadows are rendered again
+    pub fn set_object_transform(&mut self, id: ObjectId, transform: cgmath::Matrix4<f32>) -> Result<(), String> {
+        self.assets.objects.set_transform(id, transform)?;
+        self.device.objects.set_transform(id, transform)?;
+        self.device.shadows.invalidate();
+
+        Ok(())
+    }
+
+    // Frames in flight may still draw it, its buffers are destroyed once they finished
+    pub fn remove_object(&mut self, id: ObjectId) -> Result<(), String> {
+        self.assets.objects.remove(id)?;
+        self.device.objects.remove(&mut self.device.deletions, self.frame_index, id)?;
+        self.selection.retain(|s| *s != id);
+        self.device.shadows.invalidate();
+        #[cfg(feature = "physics")]
+        if let Some(physics) = self.physics.as_mut() {
+            let _ = physics.remove_body(id); // Unless it had none
+        }
+
+        Ok(())
+    }
+
+    // Replaces the voxel world from the next frame, None removes it. Its chunks are drawn as set_voxel_meshing
+    // chose, see VoxelDraws.
+    pub fn set_voxel_world(&mut self, world: Option<VoxelWorld>) -> Result<(), String> {
+        self.replace_voxel_draws(world.as_ref(), self.assets.voxel_meshing)?;
+        self.assets.voxels = world;
+
+        Ok(())
+    }
+
+    // Draws the voxel world's chunks again from the next frame, either as instances of the palette's block meshes or
+    // greedy meshed on the GPU
+    pub fn set_voxel_meshing(&mut self, meshing: VoxelMeshing) -> Result<(), String> {
+        let world = self.assets.voxels.take();
+        let replaced = self.replace_voxel_draws(world.as_ref(), meshing);
+        self.assets.voxels = world;
+        replaced?;
+        self.assets.voxel_meshing = meshing;
+
+        Ok(())
+    }
+
+    pub fn voxel_meshing(&self) -> VoxelMeshing {
+        self.assets.voxel_meshing
+    }
+
+    // The draws being replaced may still be used by frames in flight
+    fn replace_voxel_draws(&mut self, world: Option<&VoxelWorld>, meshing: VoxelMeshing) -> Result<(), String> {
+        let voxels = match world {
+            Some(world) => {
+                let forward = ForwardPass {
+                    render_pass: self.device.render_pass,
+                    lights: &self.device.lights,
+                    msaa_samples: self.core.max_msaa_samples,
+                    max_frames: MAX_FRAMES_IN_FLIGHT,
+                    views: VIEWS
+                };
+                Some(VoxelDraws::new(&self.core, self.device.command_pool, &forward, world, meshing)?)
+            },
+            None => None
+        };
+        if let Some(old) = self.device.voxels.take() {
+            self.device.deletions.push(self.frame_index, Deletion::Custom(Box::new(move |core| old.destroy(core))));
+        }
+        self.device.voxels = voxels;
+
+        Ok(())
+    }
+
+    pub fn voxel_world(&self) -> Option<&VoxelWorld> {
+        self.assets.voxels.as_ref()
+    }
+
+    // Replaces a chunk of the voxel world, drawn from the next frame. An empty chunk removes it.
+    pub fn set_voxel_chunk(&mut self, coord: ChunkCoord, chunk: VoxelChunk) -> Result<(), String> {
+        let world = self.assets.voxels.as_mut().ok_or_else(|| String::from("There is no voxel world"))?;
+        world.set_chunk(coord, chunk)?;
+        if let Some(voxels) = self.device.voxels.as_mut() {
+            match world.chunk(coord) {
+                Some(chunk) => voxels.set_chunk(&self.core, self.device.command_pool, &mut self.device.deletions,
+                                                self.frame_index, coord, chunk),
+                None => voxels.remove_chunk(&mut self.device.deletions, self.frame_index, coord)
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_voxel_chunk(&mut self, coord: ChunkCoord) -> Result<(), String> {
+        let world = self.assets.voxels.as_mut().ok_or_else(|| String::from("There is no voxel world"))?;
+        world.remove_chunk(coord);
+        if let Some(voxels) = self.device.voxels.as_mut() {
+            voxels.remove_chunk(&mut self.device.deletions, self.frame_index, coord);
+        }
+
+        Ok(())
+    }
+
+    // Loads and unloads the voxel world's chunks around the camera every frame, None stops. The streamer assumes the
+    // chunks it loaded stay in the world, give set_voxel_world's new world a new streamer.
+    pub fn set_chunk_streaming(&mut self, streamer: Option<ChunkStreamer>) {
+        self.chunk_streamer = streamer;
+    }
+
+    pub fn chunk_streamer(&self) -> Option<&ChunkStreamer> {
+        self.chunk_streamer.as_ref()
+    }
+
+    // Before recording, the chunks that were unloaded are destroyed once the frames in flight finished
+    fn stream_voxel_chunks(&mut self) {
+        if self.assets.voxels.is_none() {
+            return;
+        }
+        let changes = match self.chunk_streamer.as_mut() {
+            Some(streamer) => streamer.update(self.camera.eye()),
+            None => return
+        };
+        for coord in changes.unloaded {
+            let _ = self.remove_voxel_chunk(coord); // There is a world
+        }
+        for (coord, chunk) in changes.loaded {
+            if let Err(e) = self.set_voxel_chunk(coord, chunk) {
+                warn!(?coord, "Dropped a streamed chunk: {}", e);
+            }
+        }
+    }
+
+    // The edited chunk is uploaded, and greedy meshed, in the frame about to be recorded
+    fn edit_blocks(&mut self) {
+        for click in self.block_editor.take_clicks() {
+            let (world, ray) = match (self.assets.voxels.as_ref(), self.ray_at(click.cursor.0, click.cursor.1)) {
+                (Some(world), Some(ray)) => (world, ray),
+                _ => continue
+            };
+            let edit = click.edit;
+            if let Some((coord, chunk)) = edit.apply(world, &ray, self.block_editor.reach) {
+                if let Err(e) = self.set_voxel_chunk(coord, chunk) {
+                    warn!(?edit, "The block isn't edited: {}", e);
+                }
+            }
+        }
+    }
+
+    // Stepped before every frame, bodies that moved set their object's transform. None stops the simulation.
+    #[cfg(feature = "physics")]
+    pub fn set_physics(&mut self, physics: Option<PhysicsWorld>) {
+        self.physics = physics;
+    }
+
+    #[cfg(feature = "physics")]
+    pub fn physics_mut(&mut self) -> Option<&mut PhysicsWorld> {
+        self.physics.as_mut()
+    }
+
+    // A body for an object at its current transform, see PhysicsWorld::add_body. Errors without set_physics.
+    #[cfg(feature = "physics")]
+    pub fn add_physics_body(&mut self, id: ObjectId, body_type: RigidBodyType, shape: ColliderShape)
+        -> Result<(), String> {
+        let object = self.assets.objects.get(id).ok_or_else(|| format!("{:?} isn't an object", id))?;
+        self.physics.as_mut()
+            .ok_or_else(|| String::from("There is no physics world"))?
+            .add_body(id, object, body_type, shape)
+    }
+
+    #[cfg(feature = "physics")]
+    fn step_physics(&mut self) {
+        let physics = match self.physics.as_mut() {
+            Some(physics) => physics,
+            None => return
+        };
+        if physics.update(self.clock.delta()) == 0 {
+            return;
+        }
+        for (id, transform) in physics.moving_transforms() {
+            if let Err(e) = self.set_object_transform(id, transform) {
+                warn!("Can't move {:?} with its body: {}", id, e);
+            }
+        }
+    }
+
+    // Replaces the selection, which is outlined from the next frame. Removed objects leave it, an empty selection
+    // outlines nothing.
+    pub fn set_selection(&mut self, ids: &[ObjectId]) -> Result<(), String> {
+        if let Some(id) = ids.iter().find(|id| self.assets.objects.get(**id).is_none()) {
+            return Err(format!("{:?} isn't an object", id));
+        }
+        self.selection = ids.to_vec();
+
+        Ok(())
+    }
+
+    pub fn selection(&self) -> &[ObjectId] {
+        self.selection.as_slice()
+    }
+
+    // Lines added here are drawn in the next frame only, add them again every frame they should stay
+    pub fn debug_lines(&mut self) -> &mut DebugLines {
+        &mut self.device.debug_lines
+    }
+
+    // Destroys a resource once the frames in flight that may use it finished, without stalling. It must not be used
+    // by frames recorded after this call.
+    pub fn defer_destroy(&mut self, deletion: Deletion) {
+        self.device.deletions.push(self.frame_index, deletion);
+    }
+
+    // The object under a point of the window, in pixels from its top left corner, as the camera of the viewport the
+    // point is in sees it. Tested against the meshes on the CPU, see ObjectList::pick.
+    pub fn pick(&self, x: f32, y: f32) -> Option<ObjectId> {
+        let ray = self.ray_at(x, y)?;
+        self.assets.objects.pick(&ray).map(|(id, _)| id)
+    }
+
+    // From the camera of the viewport the point is in, None outside of every viewport
+    fn ray_at(&self, x: f32, y: f32) -> Option<Ray> {
+        let extent = self.device.render_target.extent;
+        // Split viewports are drawn over the main one
+        let (camera, rect, (x, y)) = self.split_viewports.iter().rev()
+            .map(|v| (&v.camera, v.rect))
+            .chain(iter::once((&self.camera, self.main_viewport)))
+            .find_map(|(camera, rect)| rect.local_point(extent, x, y).map(|point| (camera, rect, point)))?;
+
+        Some(Ray::from_camera(camera, rect.extent(extent), x, y))
+    }
+
+    // Clicks edit blocks of the voxel world under the cursor, see BlockEditor
+    pub fn block_editor_mut(&mut self) -> &mut BlockEditor {
+        &mut self.block_editor
+    }
+
+    // Animation time, pause it to look at a single frame and step it one frame at a time
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    pub fn set_camera(&mut self, camera: SceneCamera) {
+        self.camera = camera;
+        self.previous_view_projection = None; // Motion blur would smear the jump
+    }
+
+    // Moves the current camera so that the object fills the main viewport, keeping the view direction. See
+    // SceneCamera::framing for margin.
+    pub fn frame_object(&mut self, id: ObjectId, margin: f32) -> Result<(), String> {
+        let sphere = self.assets.objects.get(id)
+            .ok_or_else(|| format!("{:?} doesn't exist", id))?
+            .world_bounding_sphere();
+        self.frame_sphere(&sphere, margin)
+    }
+
+    // Like frame_object, for the model and all objects. The terrain and water are left out, they would dwarf the rest.
+    pub fn frame_scene(&mut self, margin: f32) -> Result<(), String> {
+        let model = self.device.model_bounds.transformed(self.model_matrix);
+        let sphere = BoundingSphere::enclosing(&[model, self.assets.objects.bounding_sphere()]);
+        self.frame_sphere(&sphere, margin)
+    }
+
+    fn frame_sphere(&mut self, sphere: &BoundingSphere, margin: f32) -> Result<(), String> {
+        if sphere.is_empty() {
+            return Err(String::from("There is nothing to frame"));
+        }
+        let camera = self.camera.framing(sphere, self.main_extent(), margin);
+        self.set_camera(camera);
+
+        Ok(())
+    }
+
+    // Splits the window between one to MAX_VIEWPORTS cameras, see ViewportLayout and editor_quad. The first viewport's
+    // camera becomes the current one, which set_camera replaces and lights, shadows, culling and picking follow. SSAO,
+    // water, depth of field and motion blur work in screen space as if the current camera covered the window, they
+    // are only right inside its viewport.
+    pub fn set_viewports(&mut self, viewports: Vec<Viewport>) -> Result<(), String> {
+        if viewports.is_empty() || viewports.len() > MAX_VIEWPORTS {
+            return Err(format!("There can be 1 to {} viewports, not {}", MAX_VIEWPORTS, viewports.len()));
+        }
+        for viewport in viewports.iter() {
+            viewport.validate()?;
+        }
+        let mut viewports = viewports.into_iter();
+        let main = viewports.next().unwrap();
+        self.main_viewport = main.rect;
+        self.set_camera(main.camera);
+        self.split_viewports = viewports.collect();
+
+        Ok(())
+    }
+
+    // The main viewport first, with the current camera
+    pub fn viewports(&self) -> Vec<Viewport> {
+        iter::once(Viewport { rect: self.main_viewport, camera: self.camera.clone() })
+            .chain(self.split_viewports.iter().cloned())
+            .collect()
+    }
+
+    // Draws the scene from another camera into a texture every frame, which is blended over the finished frame at the
+    // camera's rect, see AuxCamera::minimap. The textures leave out the grid, the selection outline and the effects in
+    // screen space. At most MAX_AUX_CAMERAS.
+    pub fn add_aux_camera(&mut self, camera: AuxCamera) -> Result<AuxCameraId, String> {
+        self.device.aux_cameras.add(&self.core, camera)
+    }
+
+    // Moving the camera every frame is cheap, changing the size of its texture waits for the device
+    pub fn set_aux_camera(&mut self, id: AuxCameraId, camera: AuxCamera) -> Result<(), String> {
+        self.device.aux_cameras.set(&self.core, id, camera)
+    }
+
+    pub fn remove_aux_camera(&mut self, id: AuxCameraId) -> Result<(), String> {
+        self.device.aux_cameras.remove(&self.core, id)
+    }
+
+    pub fn aux_camera(&self, id: AuxCameraId) -> Option<&AuxCamera> {
+        self.device.aux_cameras.get(id)
+    }
+
+    // Of the main viewport, the camera's aspect ratio follows it
+    fn main_extent(&self) -> vk::Extent2D {
+        self.main_viewport.extent(self.device.render_target.extent)
+    }
+
+    pub fn set_color_pipeline(&mut self, color_pipeline: ColorPipeline) {
+        self.color_pipeline = color_pipeline;
+    }
+
+    pub fn set_setting(&mut self, setting: RendererSetting) {
+        match setting {
+            RendererSetting::Renderer(settings) => self.set_settings(settings),
+            RendererSetting::ColorPipeline(color_pipeline) => self.set_color_pipeline(color_pipeline),
+            RendererSetting::MotionBlur(settings) => self.set_motion_blur_settings(settings),
+            RendererSetting::LowLatency(enabled) => self.set_low_latency(enabled),
+            RendererSetting::MaxFps(max_fps) => self.set_max_fps(max_fps),
+            RendererSetting::DisplayMode(display_mode) => {
+                self.set_display_mode(display_mode);
+            },
+            RendererSetting::Sky(sky) => {
+                if let Err(e) = self.set_sky(sky) {
+                    warn!("The sun light is ignored: {}", e);
+                }
+            },
+            RendererSetting::Grid(grid) => self.set_grid_settings(grid),
+            RendererSetting::Outline(outline) => self.set_outline_settings(outline),
+            RendererSetting::Projection(projection) => {
+                if let Err(e) = self.set_projection(projection) {
+                    warn!("The projection is ignored: {}", e);
+                }
+            },
+            RendererSetting::Paused(paused) => self.clock.set_paused(paused),
+            RendererSetting::TimeScale(time_scale) => self.clock.set_time_scale(time_scale)
+        }
+    }
+
+    // Returns how many commands were processed
+    fn process_commands(&mut self) -> usize {
+        let commands = self.commands.drain();
+        let count = commands.len();
+        for command in commands {
+            match command {
+                RendererCommand::LoadModel { path, texture, reply } => {
+                    let _ = reply.send(self.load_model(path, texture));
+                },
+                RendererCommand::SetCamera(camera) => self.set_camera(camera),
+                RendererCommand::FrameScene { margin } => {
+                    if let Err(e) = self.frame_scene(margin) {
+                        warn!("The camera is not moved: {}", e);
+                    }
+                },
+                RendererCommand::Screenshot { path, reply } => match check_screenshot_path(&path) {
+                    Ok(_) => self.screenshots.push_back((path, reply)),
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                },
+                RendererCommand::SetSetting(setting) => self.set_setting(setting),
+                RendererCommand::StepFrame => self.clock.step(),
+                RendererCommand::SetViewports(viewports) => {
+                    if let Err(e) = self.set_viewports(viewports) {
+                        warn!("The viewports are ignored: {}", e);
+                    }
+                },
+                RendererCommand::SetMaterial { material, reply } => {
+                    let _ = reply.send(self.set_material(material));
+                }
+            }
+        }
+
+        count
+    }
+
+    // Waits for the copy, screenshots are rare enough for the stall not to matter
+    fn finish_screenshot(&self, mut capture: FrameCapture, path: PathBuf, reply: Sender<Result<PathBuf, String>>) {
+        let result = match unsafe { self.core.logical_device.device_wait_idle() } {
+            Ok(_) => {
+                capture.destroy(&self.core);
+                Ok(path)
+            },
+            Err(e) => Err(format!("Screenshot failed: {:?}", e)) // The capture goes with the lost device
+        };
+        let _ = reply.send(result);
+    }
+
+    // Called after the renderer recovered from a lost device, see DeviceLost
+    pub fn add_device_lost_callback(&mut self, callback: DeviceLostCallback) {
+        self.device_lost.add_callback(callback);
+    }
+
+    fn record_command_buffer(&self, image_index: u32) {
+        let render_target = &self.device.render_target;
+        let logical_device = &self.core.logical_device;
+        let main_extent = self.main_extent();
+
+        let begin_info = vk::CommandBufferBeginInfo::default();
+        // Only the GPU that renders this frame runs it, see DeviceGroup
+        #[cfg(feature = "device-group")]
+        let mut group_begin_info = self.core.device_group.as_ref()
+            .map(|g| g.command_buffer_begin_info(self.frame_index));
+        #[cfg(feature = "device-group")]
+        let begin_info = match group_begin_info.as_mut() {
+            Some(group_begin_info) => begin_info.push_next(group_begin_info),
+            None => begin_info
+        };
+
+        let render_offset = vk::Offset2D::default()
+            .x(0)
+            .y(0);
+        let render_extent = vk::Extent2D::default()
+            .height(render_target.extent.height)
+            .width(render_target.extent.width);
+        let render_area = vk::Rect2D::default() // Area where shader loads and stores occur
+            .offset(render_offset)
+            .extent(render_extent);
+
+        // The sky is drawn over what stays clear, except in a transparent window where the desktop shows through
+        let clear_alpha = if render_target.is_transparent() { 0.0 } else { 1.0 };
+        let clear_color_value = vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, clear_alpha]
+        };
+        let clear_depth_stencil = vk::ClearDepthStencilValue::default()
+            .depth(1.0)
+            .stencil(0);
+        let clear_values = [
+            vk::ClearValue {
+                color: clear_color_value
+            },
+            vk::ClearValue {
+                depth_stencil: clear_depth_stencil
+            }
+        ];
+
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.device.render_pass)
+            .framebuffer(self.device.frame_buffers[image_index as usize])
+            .render_area(render_area)
+            .clear_values(&clear_values);
+
+        // Defines a transformation from a VK image to the framebuffer
+        let viewports = [self.main_viewport.viewport(render_target.extent)];
+
+        let scissors = [self.main_viewport.pixels(render_target.extent)];
+
+        let command_buffer = *self.device.command_buffers.get(self.current_frame).unwrap();
+
+        let color_pipeline = ColorPipeline { exposure: self.exposure(), ..self.color_pipeline };
+        let color_constants = color_pipeline.constants(self.device.render_target.surface_format);
+
+        unsafe {
+            logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
+            self.device.gpu_timer.cmd_begin(&self.core, command_buffer, self.current_frame);
+            if let Some(queries) = self.device.pipeline_stats.as_ref() {
+                queries.cmd_reset(&self.core, command_buffer, self.current_frame);
+            }
+            #[cfg(feature = "indirect-draw")]
+            {
+                // The draw bounds are in model space, and tested where the main viewport is in the depth buffer
+                let model_view_proj = self.main_viewport.clip_transform() * self.camera.projection(main_extent) *
+                    self.camera.view() * self.model_matrix;
+                self.device.culler.cmd_cull(&self.core, command_buffer, model_view_proj);
+            }
+            self.device.lights.cmd_cull(&self.core, command_buffer, self.current_frame);
+            if let Some(voxels) = self.device.voxels.as_ref() {
+                voxels.cmd_mesh(&self.core, command_buffer);
+            }
+            self.cmd_begin_statistics(command_buffer, SHADOW_PASS);
+            self.cmd_render_shadows(command_buffer);
+            self.cmd_end_statistics(command_buffer, SHADOW_PASS);
+            self.cmd_begin_statistics(command_buffer, OFFSCREEN_PASS);
+            if let Some(water) = self.device.water.as_ref() {
+                let reflected_eye = water.reflected_camera(&self.camera).eye();
+                water.cmd_draw_reflection(&self.core, command_buffer,
+                                          || self.cmd_draw_scene(command_buffer, REFLECTION_VIEW, reflected_eye,
+                                                                 &color_constants));
+            }
+            self.device.aux_cameras.cmd_draw_views(&self.core, command_buffer, |id, camera| {
+                self.cmd_draw_scene(command_buffer, aux_view(id.slot()), camera.camera.eye(), &color_constants)
+            });
+            self.cmd_end_statistics(command_buffer, OFFSCREEN_PASS);
+            self.cmd_begin_statistics(command_buffer, FORWARD_PASS);
+            logical_device.cmd_begin_render_pass(command_buffer,
+                                                      &render_pass_info,
+                                                      vk::SubpassContents::INLINE); // Execute commands in primary buffer
+            logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
+            logical_device.cmd_set_scissor(command_buffer, 0, &scissors);
+            self.cmd_draw_scene(command_buffer, MAIN_VIEW, self.camera.eye(), &color_constants);
+            for (i, viewport) in self.split_viewports.iter().enumerate() {
+                logical_device.cmd_set_viewport(command_buffer, 0, &[viewport.rect.viewport(render_target.extent)]);
+                logical_device.cmd_set_scissor(command_buffer, 0, &[viewport.rect.pixels(render_target.extent)]);
+                self.cmd_draw_scene(command_buffer, split_view(i + 1), viewport.camera.eye(), &color_constants);
+            }
+            logical_device.cmd_end_render_pass(command_buffer);
+            self.cmd_end_statistics(command_buffer, FORWARD_PASS);
+            self.device.ssao.cmd_apply(&self.core, command_buffer, image_index, self.camera.projection(main_extent));
+            if let Some(water) = self.device.water.as_ref() {
+                let present_image = render_target.swap_loader.get_swapchain_images(render_target.swap_chain)
+                    .unwrap()[image_index as usize];
+                water.cmd_draw(&self.core, command_buffer, image_index, present_image, self.current_frame,
+                               &color_constants);
+            }
+            self.record_post_process(command_buffer, image_index);
+            // Over the post-processed image, so that the outline stays sharp
+            self.device.outline.cmd_draw(&self.core, command_buffer, image_index,
+                                         self.main_viewport.clip_transform() * self.view_projection(MAIN_VIEW),
+                                         &self.device.objects, self.selection.as_slice());
+            self.device.aux_cameras.cmd_composite(&self.core, command_buffer, image_index);
+            if let Some(stereo) = self.device.stereo.as_ref() {
+                let present_image = render_target.swap_loader.get_swapchain_images(render_target.swap_chain)
+                    .unwrap()[image_index as usize];
+                stereo.cmd_draw(&self.core, command_buffer, self.current_frame, &color_constants, present_image, || {
+                    logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.device.vertex_buffer.buf], &[0]);
+                    self.device.index_buffer.cmd_bind(&self.core, command_buffer);
+                    logical_device.cmd_draw_indexed(command_buffer, self.device.index_buffer.index_count(), 1, 0, 0,
+                                                    0);
+                });
+            }
+            self.device.ui_draws.cmd_draw(&self.core, command_buffer, image_index, self.current_frame);
+            self.device.gpu_timer.cmd_end(&self.core, command_buffer, self.current_frame);
+            logical_device.end_command_buffer(command_buffer).unwrap();
+        }
+    }
+
+    // The shadows of the model, the terrain and the objects, for the point lights whose cubes are out of date
+    // Outside of render passes, pass is one of STATISTICS_PASSES
+    fn cmd_begin_statistics(&self, command_buffer: vk::CommandBuffer, pass: usize) {
+        if let Some(queries) = self.device.pipeline_stats.as_ref() {
+            queries.cmd_begin(&self.core, command_buffer, self.current_frame, pass);
+        }
+    }
+
+    fn cmd_end_statistics(&self, command_buffer: vk::CommandBuffer, pass: usize) {
+        if let Some(queries) = self.device.pipeline_stats.as_ref() {
+            queries.cmd_end(&self.core, command_buffer, self.current_frame, pass);
+        }
+    }
+
+    fn cmd_render_shadows(&self, command_buffer: vk::CommandBuffer) {
+        let shadows = &self.device.shadows;
+        shadows.cmd_render(&self.core, command_buffer, self.current_frame, |light| unsafe {
+            shadows.cmd_set_model(&self.core, command_buffer, self.model_matrix);
+            self.core.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &[self.device.vertex_buffer.buf],
+                                                             &[0]);
+            self.device.index_buffer.cmd_bind(&self.core, command_buffer);
+            self.core.logical_device.cmd_draw_indexed(command_buffer, self.device.index_buffer.index_count(), 1, 0, 0,
+                                                      0);
+            if let Some(terrain) = self.device.terrain.as_ref() {
+                shadows.cmd_set_model(&self.core, command_buffer, cgmath::Matrix4::from_scale(1.0));
+                terrain.cmd_draw_geometry(&self.core, command_buffer, light);
+            }
+            self.device.objects.cmd_draw_geometry(&self.core, command_buffer,
+                                                  |model| shadows.cmd_set_model(&self.core, command_buffer, model));
+            // Voxel worlds cast none, the shadow pipeline has no block instances
+        });
+    }
+
+    // What the view's transforms are set to, see draw_frame
+    fn view_projection(&self, view: usize) -> cgmath::Matrix4<f32> {
+        let extent = self.device.render_target.extent;
+        match (view, self.device.water.as_ref()) {
+            (REFLECTION_VIEW, Some(water)) => {
+                let reflected = water.reflected_camera(&self.camera);
+                water.reflection_projection(&reflected, extent) * reflected.view()
+            },
+            (MAIN_VIEW, _) | (REFLECTION_VIEW, None) => self.camera.projection(self.main_extent()) * self.camera.view(),
+            (view, _) if view >= aux_view(0) => self.device.aux_cameras.iter()
+                .find(|(id, ..)| aux_view(id.slot()) == view)
+                .map(|(_, camera, projection)| projection * camera.camera.view())
+                .unwrap(),
+            _ => {
+                let viewport = &self.split_viewports[view - split_view(1)];
+                viewport.camera.projection(viewport.rect.extent(extent)) * viewport.camera.view()
+            }
+        }
+    }
+
+    // The model, the terrain, the objects and the sky from one of the views, inside a render pass compatible with the
+    // main one after the viewport and scissor are set. Objects outside the view's frustum are culled. The main view
+    // also gets the ground grid.
+    fn cmd_draw_scene(&self, command_buffer: vk::CommandBuffer, view: usize, eye: cgmath::Point3<f32>,
+                      color_constants: &ColorConstants) {
+        let logical_device = &self.core.logical_device;
+        let vertex_buffers = [self.device.vertex_buffer.buf];
+        let offsets: [vk::DeviceSize; 1] = [0];
+        let pipeline_layout = self.device.raster_pipeline.pipeline_layout;
+        let set = self.device.descriptor.sets[view_slot(self.current_frame, view)];
+        let forward = ForwardDraw {
+            lights: &self.device.lights,
+            color_constants,
+            current_frame: self.current_frame,
+            view_index: view
+        };
+
+        unsafe {
+            logical_device.cmd_bind_pipeline(command_buffer,
+                                                  vk::PipelineBindPoint::GRAPHICS,
+                                                  *self.device.raster_pipeline.pipelines.first().unwrap());
+            logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+            self.device.index_buffer.cmd_bind(&self.core, command_buffer);
+            // self.logical_layer.logical_device.cmd_draw(command_buffer,
+            //                              self.device.vertex_buffer.vertex_count,
+            //                              1,
+            //                              0, // Vertex buffer offset, lowest value of gl_VertexIndex
+            //                              0); // lowest value of gl_InstanceIndex
+            logical_device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline_layout,
+                                                    0, &[set], &[]);
+            // Point lights are picked from the main view's clusters in every view
+            self.device.lights.cmd_bind(&self.core, command_buffer, pipeline_layout, self.current_frame);
+            if let ModelTexture::Virtual(id, _) = self.device.texture {
+                self.device.textures.cmd_bind_virtual(&self.core, command_buffer, pipeline_layout, id,
+                                                      self.current_frame);
+            }
+            logical_device.cmd_push_constants(command_buffer, pipeline_layout, vk::ShaderStageFlags::FRAGMENT, 0,
+                                              cast_to_u8_slice(color_constants));
+            // The culler only knows what the main view sees
+            match view {
+                #[cfg(feature = "indirect-draw")]
+                MAIN_VIEW => self.device.culler.cmd_draw(&self.core, command_buffer),
+                _ => logical_device.cmd_draw_indexed(command_buffer, self.device.index_buffer.index_count(), 1, 0, 0,
+                                                     0)
+            }
+            if let Some(terrain) = self.device.terrain.as_ref() {
+                terrain.cmd_draw(&self.core, command_buffer, &forward, eye);
+            }
+            let visible = self.assets.objects.visible(&Frustum::from_view_proj(self.view_projection(view)));
+            self.device.objects.cmd_draw(&self.core, command_buffer, &forward, visible.as_slice());
+            if let (Some(voxels), Some(world)) = (self.device.voxels.as_ref(), self.assets.voxels.as_ref()) {
+                let frustum = Frustum::from_view_proj(self.view_projection(view));
+                let visible = visible_chunks(world, eye, Some(&frustum), VISIBILITY_DISTANCE);
+                voxels.cmd_draw(&self.core, command_buffer, &forward, &visible);
+            }
+            // Last, so that it is only shaded where nothing else was drawn
+            if !(window_view(view) && self.device.render_target.is_transparent()) {
+                self.device.sky.cmd_draw(&self.core, command_buffer, color_constants, self.current_frame, view);
+            }
+            if view == MAIN_VIEW {
+                self.device.grid.cmd_draw(&self.core, command_buffer, &self.device.frame_constants, color_constants,
+                                          self.current_frame);
+                self.device.debug_lines.cmd_draw(&self.core, command_buffer, &self.device.frame_constants,
+                                                 color_constants, self.current_frame);
+            }
+        }
+    }
+
+    // Runs the post-process chain over the swap chain image, which the render passes left in PRESENT_SRC_KHR
+    fn record_post_process(&self, command_buffer: vk::CommandBuffer, image_index: u32) {
+        let frame = PostFrame {
+            projection: self.camera.projection(self.main_extent()),
+            view: self.camera.view(),
+            previous_view_projection: self.previous_view_projection,
+            focus_distance: self.camera.focal_distance(),
+            aperture: self.camera.aperture,
+            exposure: self.settings.auto_exposure().map(|settings| ExposureMetering {
+                settings,
+                exposure: self.exposure(),
+                gamma: self.color_pipeline.gamma,
+                delta_time: self.last_frame_start.elapsed().as_secs_f32()
+            }),
+            frame: self.current_frame
+        };
+        if !self.device.post_process.is_active(&frame) {
+            return;
+        }
+        let logical_device = &self.core.logical_device;
+        let present_image = unsafe {
+            self.device.render_target.swap_loader.get_swapchain_images(self.device.render_target.swap_chain).unwrap()
+                [image_index as usize]
+        };
+        let subresource_range = vk::ImageSubresourceRange::default()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .base_mip_level(0)
+            .level_count(1)
+            .base_array_layer(0)
+            .layer_count(1);
+        let layout_barrier = |old_layout, new_layout, src_access_mask, dst_access_mask| {
+            vk::ImageMemoryBarrier::default()
+                .image(present_image)
+                .subresource_range(subresource_range)
+                .old_layout(old_layout)
+                .new_layout(new_layout)
+                .src_access_mask(src_access_mask)
+                .dst_access_mask(dst_access_mask)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        };
+        let to_src = layout_barrier(vk::ImageLayout::PRESENT_SRC_KHR, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                    vk::AccessFlags::COLOR_ATTACHMENT_WRITE, vk::AccessFlags::TRANSFER_READ);
+        let to_dst = layout_barrier(vk::ImageLayout::TRANSFER_SRC_OPTIMAL, vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                    vk::AccessFlags::empty(), vk::AccessFlags::TRANSFER_WRITE);
+        let to_present = layout_barrier(vk::ImageLayout::TRANSFER_DST_OPTIMAL, vk::ImageLayout::PRESENT_SRC_KHR,
+                                        vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::empty());
+
+        unsafe {
+            logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                                                vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                &[], &[], &[to_src]);
+            self.device.post_process.cmd_copy_in(&self.core, command_buffer, present_image,
+                                                 vk::ImageLayout::TRANSFER_SRC_OPTIMAL);
+            self.device.post_process.cmd_apply(&self.core, command_buffer, &frame);
+            logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                vk::PipelineStageFlags::TRANSFER, vk::DependencyFlags::empty(),
+                                                &[], &[], &[to_dst]);
+            self.device.post_process.cmd_copy_out(&self.core, command_buffer, present_image,
+                                                  vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+            logical_device.cmd_pipeline_barrier(command_buffer, vk::PipelineStageFlags::TRANSFER,
+                                                vk::PipelineStageFlags::BOTTOM_OF_PIPE, vk::DependencyFlags::empty(),
+                                                &[], &[], &[to_present]);
+        }
+    }
+
+    fn cleanup_swap_chain(&self) {
+        unsafe { self.core.logical_device.device_wait_idle().unwrap() };
+        self.device.destroy_swap_chain(&self.core);
+    }
+
+    // Nothing on the lost device can be waited on, so everything is destroyed as is. Only the window, instance and
+    // surface of the core stay. Runtime settings like SSAO and motion blur carry over.
+    fn device_options(&self) -> DeviceOptions {
+        DeviceOptions {
+            present_mode: self.present_mode,
+            display_mode: self.display_mode,
+            low_latency: self.device.latency.is_enabled(),
+            frame_pacing: self.device.display_timing.as_ref().map_or(0, |t| t.pacing()),
+            ssao: self.device.ssao.settings(),
+            motion_blur: self.device.post_process.motion_blur_settings(),
+            texture_streaming: self.device.textures.settings(),
+            grid: self.device.grid.settings(),
+            outline: self.device.outline.settings(),
+            aux_cameras: self.device.aux_cameras.cameras(),
+            stereo: self.device.stereo.is_some(),
+            frame_share: self.device.frame_share
+        }
+    }
+
+    fn recover_device(&mut self) {
+        let _span = info_span!("device_recovery").entered();
+        self.device_lost.begin_recovery();
+        let options = self.device_options();
+        #[cfg(unix)]
+        if let Some(share) = self.frame_share.as_mut() {
+            share.release(&self.core);
+        }
+        self.device.destroy(&self.core);
+        self.core.recreate_device();
+        self.crash.rearm(&self.core);
+        self.device = DeviceResources::new(&self.core, &self.assets, options, None);
+        #[cfg(unix)]
+        if let Some(share) = self.frame_share.as_mut() {
+            let render_target = &self.device.render_target;
+            share.recreate(&self.core, render_target.extent, render_target.surface_format);
+        }
+        self.current_frame = 0;
+        self.previous_view_projection = None;
+        self.device_lost.finish(&self.core);
+    }
+
+    fn recreate_swap_chain(&mut self) {
+        debug!(present_mode = ?self.present_mode, "Recreating swap chain");
+        self.cleanup_swap_chain();
+        self.device.create_swap_chain(&self.core, self.present_mode, self.display_mode);
+        #[cfg(unix)]
+        if let Some(share) = self.frame_share.as_mut() {
+            let render_target = &self.device.render_target;
+            share.recreate(&self.core, render_target.extent, render_target.surface_format);
+        }
+        self.previous_view_projection = None; // The aspect ratio may have changed
+        self.swapchain_recreate.finish(&self.core, &self.device.render_target);
+    }
+
+    // Lays out the debug UI's panels and draws the materials edited in them. Changes to the scene file are hot reloaded
+    // while the panels are hidden too.
+    fn update_debug_ui(&mut self) {
+        let mut materials = self.material_panel.as_mut().map_or(Vec::new(), MaterialPanel::poll);
+        if let Some(context) = self.debug_ui.begin_frame(&self.core.window) {
+            if let Some(panel) = self.material_panel.as_mut() {
+                materials.extend(panel.show(&context));
+            }
+            self.debug_ui.end_frame(&self.core.window);
+        }
+        for material in materials {
+            if let Err(e) = self.set_material(material) {
+                warn!("The edited material is not drawn: {}", e);
+                if let Some(panel) = self.material_panel.as_mut() {
+                    panel.set_error(e);
+                }
+            }
+        }
+        if self.debug_ui.wants_repaint() {
+            self.idle.mark_active();
+        }
+    }
+
+    // A panic while drawing idles the device and aborts, see CrashHandler
+    pub fn run_blocking(mut self, event_loop: EventLoop<()>) {
+        let crash = self.crash.clone();
+        // The control flow is set once per iteration, at MainEventsCleared, see IdleTracker::next_frame
+        event_loop.run(move |event, _, control_flow| {
+            match event {
+                Event::WindowEvent {
+                    // If event has Event::WindowEvent type and event: WindowEvent::CloseRequested member and if window_id == window.id()
+                    event: WindowEvent::CloseRequested,
+                    window_id,
+                } if window_id == self.window_id() => *control_flow = ControlFlow::Exit,
+                Event::WindowEvent { event, window_id } if window_id == self.window_id() => {
+                    self.idle.handle_window_event(&event);
+                    self.swapchain_recreate.handle_window_event(&event);
+                    // Typing into the debug UI doesn't toggle effects or edit blocks
+                    if self.debug_ui.handle_window_event(&event) {
+                        return;
+                    }
+                    self.device.latency.handle_window_event(&event);
+                    self.device.ssao.handle_window_event(&event);
+                    self.device.grid.handle_window_event(&event);
+                    self.device.post_process.handle_window_event(&event);
+                    self.clock.handle_window_event(&event);
+                    if let Some(input) = InputEvent::from_window_event(&event) {
+                        self.block_editor.handle_input(&input);
+                    }
+                },
+                // Before this iteration's input events are handled
+                Event::NewEvents(_) => crash.run_guarded(|| self.throttle()),
+                Event::MainEventsCleared => crash.run_guarded(|| {
+                    // Commands are processed while idle too, they count as activity
+                    if self.process_commands() > 0 {
+                        self.idle.mark_active();
+                    }
+                    if self.idle.next_frame(self.settings.idle_behavior, control_flow) {
+                        self.core.window.request_redraw(); // Emits a RedrawRequested event after input events end
+                    }
+                }),
+                // Needed when a redraw is needed after the user resizes for example
+                Event::RedrawRequested(window_id) if window_id == self.window_id() =>
+                    crash.run_guarded(|| self.draw_frame()),
+                Event::LoopDestroyed if !self.device_lost.is_lost() =>
+                    unsafe { self.core.logical_device.device_wait_idle().unwrap() },
+                _ => (), // Similar to the "default" case of a switch statement: return void which is essentially () in Rust
+            }
+        });
+    }
+
+    // Draws the benchmark's camera path without vsync or motion blur, then writes its report and exits. Input other
+    // than closing the window is ignored.
+    pub fn run_benchmark(mut self, event_loop: EventLoop<()>, mut benchmark: Benchmark) {
+        self.present_mode = vk::PresentModeKHR::IMMEDIATE;
+        // Runs with the same effects regardless of how it was launched
+        self.set_motion_blur_settings(MotionBlurSettings { enabled: false, ..self.motion_blur_settings() });
+        self.recreate_swap_chain();
+        let mut last_frame_end = Instant::now();
+        let crash = self.crash.clone();
+
+        event_loop.run(move |event, _, control_flow| {
+            control_flow.set_poll();
+
+            match event {
+                Event::WindowEvent {
+                    event: WindowEvent::CloseRequested,
+                    window_id,
+                } if window_id == self.window_id() => *control_flow = ControlFlow::Exit,
+                Event::WindowEvent { event, window_id } if window_id == self.window_id() =>
+                    self.swapchain_recreate.handle_window_event(&event),
+                Event::MainEventsCleared => self.core.window.request_redraw(),
+                Event::RedrawRequested(window_id) if window_id == self.window_id() => {
+                    if benchmark.is_finished() {
+                        return;
+                    }
+
+                    let (eye, target) = benchmark.camera();
+                    self.camera.eye = eye.into();
+                    self.camera.target = target.into();
+                    // Without a limit the benchmark measures the highest rate the renderer reaches
+                    self.limiter.wait();
+                    crash.run_guarded(|| self.draw_frame());
+                    let frame_end = Instant::now();
+                    benchmark.record(FrameStats {
+                        frame_ms: frame_end.duration_since(last_frame_end).as_secs_f64() * 1000.0,
+                        cpu_ms: self.last_cpu_ms,
+                        gpu_ms: self.last_gpu_ms,
+                        passes: Vec::new(),
+                        presents: Vec::new()
+                    });
+                    last_frame_end = frame_end;
+
+                    if benchmark.is_finished() {
+                        *control_flow = match benchmark.write_report() {
+                            Ok(()) => ControlFlow::Exit,
+                            Err(e) => {
+                                error!("Benchmark: {}", e);
+                                ControlFlow::ExitWithCode(1)
+                            }
+                        };
+                    }
+                },
+                Event::LoopDestroyed if !self.device_lost.is_lost() =>
+                    unsafe { self.core.logical_device.device_wait_idle().unwrap() },
+                _ => (),
+            }
+        });
+    }
+
+    // Waits for the frame's start time, see FrameLimiter, then for the frame before the current one, see
+    // LatencyGovernor::throttle
+    fn throttle(&mut self) {
+        self.limiter.wait();
+        let previous_frame = (self.current_frame + MAX_FRAMES_IN_FLIGHT - 1) % MAX_FRAMES_IN_FLIGHT;
+        self.device.latency.throttle(&self.core, &self.device.render_target,
+                                     self.device.in_flight_fences[previous_frame]);
+    }
+
+    fn window_id(&self) -> WindowId {
+        self.core.window.id()
+    }
+
+    fn draw_frame(&mut self) {
+        self.process_commands();
+        self.update_debug_ui();
+        self.clock.tick();
+        #[cfg(feature = "physics")]
+        self.step_physics();
+        // A minimized window has a zero sized surface, nothing can be presented until it is restored
+        let size = self.core.window.inner_size();
+        if size.width == 0 || size.height == 0 {
+            return;
+        }
+        if self.device_lost.is_lost() {
+            self.recover_device();
+        }
+        if self.swapchain_recreate.is_pending() {
+            self.recreate_swap_chain();
+        }
+        self.stream_voxel_chunks();
+        self.edit_blocks();
+
+        let frame_start = Instant::now();
+        let logical_device = &self.core.logical_device;
+        let render_target = &self.device.render_target;
+        let graphics_queue = self.core.graphics_queue;
+        let present_queue = self.core.present_queue;
+        let current_frame = self.current_frame;
+        let main_extent = self.main_extent();
+
+        let fences = [*self.device.in_flight_fences.get(current_frame)
+            .unwrap()];
+        let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let wait_sems = [*self.device.image_available_sems.get(current_frame).unwrap()];
+        let command_buffers = [*self.device.command_buffers.get(current_frame).unwrap()];
+        let sig_sems = [*self.device.render_finished_sems.get(current_frame).unwrap()];
+        let swap_chains = [render_target.swap_chain];
+
+        let wait_time: Duration;
+        let passes: Vec<(&'static str, PipelineStatistics)>;
+        unsafe {
+            let wait_start = Instant::now();
+            let wait_result = logical_device.wait_for_fences(&fences, true, u64::MAX);
+            if self.device_lost.check(wait_result, "wait_for_fences").is_none() {
+                return;
+            }
+            wait_time = wait_start.elapsed();
+            self.device.deletions.collect(&self.core, self.frame_index);
+            // Of the frame that last used this slot
+            self.last_gpu_ms = self.device.gpu_timer.frame_time_ms(&self.core, current_frame);
+            passes = self.device.pipeline_stats.as_ref()
+                .map_or(Vec::new(), |queries| queries.results(&self.core, current_frame));
+            // The frame that last used this slot has finished, its uniforms and transforms can be written again
+            self.device.uniform_buffer.set_transforms(view_slot(current_frame, MAIN_VIEW), self.model_matrix,
+                                                      self.camera.view(), self.camera.projection(main_extent));
+            if let Some(stereo) = self.device.stereo.as_ref() {
+                stereo.set_transforms(current_frame, self.model_matrix, self.camera.view(),
+                                      self.camera.projection(stereo.eye_extent()));
+            }
+            if let Some(terrain) = self.device.terrain.as_ref() {
+                terrain.set_transforms(current_frame, MAIN_VIEW, self.camera.view(),
+                                       self.camera.projection(main_extent));
+            }
+            self.device.objects.begin_frame(current_frame);
+            self.device.objects.set_transforms(current_frame, MAIN_VIEW, self.camera.view(),
+                                               self.camera.projection(main_extent));
+            if let Some(voxels) = self.device.voxels.as_mut() {
+                voxels.begin_frame(current_frame);
+                voxels.set_transforms(current_frame, MAIN_VIEW, self.camera.view(),
+                                      self.camera.projection(main_extent));
+            }
+            for (i, viewport) in self.split_viewports.iter().enumerate() {
+                let view = split_view(i + 1);
+                let projection = viewport.camera.projection(viewport.rect.extent(render_target.extent));
+                self.device.uniform_buffer.set_transforms(view_slot(current_frame, view), self.model_matrix,
+                                                          viewport.camera.view(), projection);
+                if let Some(terrain) = self.device.terrain.as_ref() {
+                    terrain.set_transforms(current_frame, view, viewport.camera.view(), projection);
+                }
+                self.device.objects.set_transforms(current_frame, view, viewport.camera.view(), projection);
+                if let Some(voxels) = self.device.voxels.as_mut() {
+                    voxels.set_transforms(current_frame, view, viewport.camera.view(), projection);
+                }
+            }
+            for (id, camera, projection) in self.device.aux_cameras.iter() {
+                let (view, camera_view) = (aux_view(id.slot()), camera.camera.view());
+                self.device.uniform_buffer.set_transforms(view_slot(current_frame, view), self.model_matrix,
+                                                          camera_view, projection);
+                if let Some(terrain) = self.device.terrain.as_ref() {
+                    terrain.set_transforms(current_frame, view, camera_view, projection);
+                }
+                self.device.objects.set_transforms(current_frame, view, camera_view, projection);
+                if let Some(voxels) = self.device.voxels.as_mut() {
+                    voxels.set_transforms(current_frame, view, camera_view, projection);
+                }
+            }
+            if let Some(water) = self.device.water.as_ref() {
+                let reflected = water.reflected_camera(&self.camera);
+                let reflected_projection = water.reflection_projection(&reflected, render_target.extent);
+                self.device.uniform_buffer.set_transforms(view_slot(current_frame, REFLECTION_VIEW),
+                                                          self.model_matrix, reflected.view(), reflected_projection);
+                if let Some(terrain) = self.device.terrain.as_ref() {
+                    terrain.set_transforms(current_frame, REFLECTION_VIEW, reflected.view(), reflected_projection);
+                }
+                self.device.objects.set_transforms(current_frame, REFLECTION_VIEW, reflected.view(),
+                                                   reflected_projection);
+                if let Some(voxels) = self.device.voxels.as_mut() {
+                    voxels.set_transforms(current_frame, REFLECTION_VIEW, reflected.view(), reflected_projection);
+                }
+            }
+            self.device.shadows.update(current_frame, self.camera.eye());
+            // Clusters tile the whole window, so that they line up with the main viewport's pixels
+            self.device.lights.update(current_frame, &ClusterCamera {
+                view: self.camera.view(),
+                projection: self.main_viewport.clip_transform() * self.camera.projection(main_extent),
+                near: self.camera.projection.near(),
+                far: self.camera.projection.far(),
+                extent: render_target.extent
+            }, self.device.shadows.shadow_slots());
+            self.device.sky.update(current_frame, MAIN_VIEW, self.camera.view(), self.camera.projection(main_extent));
+            for (i, viewport) in self.split_viewports.iter().enumerate() {
+                self.device.sky.update(current_frame, split_view(i + 1), viewport.camera.view(),
+                                       viewport.camera.projection(viewport.rect.extent(render_target.extent)));
+            }
+            for (id, camera, projection) in self.device.aux_cameras.iter() {
+                self.device.sky.update(current_frame, aux_view(id.slot()), camera.camera.view(), projection);
+            }
+            let frame_constants = FrameConstants::new(&self.camera, main_extent, &self.clock, self.frame_index,
+                                                      self.debug_flags);
+            self.device.frame_constants.update(current_frame, &frame_constants);
+            self.device.grid.update(current_frame);
+            self.device.debug_lines.update(current_frame);
+            self.device.ui_draws.update(&self.core, self.device.command_pool, &mut self.device.deletions,
+                                        self.frame_index, current_frame, &self.debug_ui);
+            if let Some(water) = self.device.water.as_ref() {
+                water.update(current_frame, &self.camera, render_target.extent, &self.clock);
+                let reflected = water.reflected_camera(&self.camera);
+                self.device.sky.update(current_frame, REFLECTION_VIEW, reflected.view(),
+                                       water.reflection_projection(&reflected, render_target.extent));
+            }
+            // The model's texture is streamed in at the resolution its distance to the camera needs, virtual textures
+            // by what the frame's feedback asked for
+            if let ModelTexture::Streamed(texture) = self.device.texture {
+                let model_position = cgmath::Point3::from_homogeneous(self.model_matrix.w);
+                self.device.textures.request(texture, self.camera.eye().distance(model_position));
+            }
+            self.device.textures.update(&self.core, current_frame);
+            self.device.objects.update(&self.core, current_frame);
+            let (texture, texture_generation) = self.device.texture.bound(&self.device.textures);
+            if self.device.texture_generations[current_frame] != texture_generation {
+                for view in 0..VIEWS {
+                    self.device.descriptor.write_texture(&self.core, view_slot(current_frame, view),
+                                                         self.device.sampler, texture);
+                }
+                if let Some(stereo) = self.device.stereo.as_ref() {
+                    stereo.write_texture(&self.core, current_frame, self.device.sampler, texture);
+                }
+                self.device.texture_generations[current_frame] = texture_generation;
+            }
+
+            // With a device group the image is acquired for the GPU that renders this frame
+            #[cfg(feature = "device-group")]
+            let acquire_result = match self.core.device_group.as_ref() {
+                Some(group) => group.acquire_next_image(&render_target.swap_loader, render_target.swap_chain,
+                                                        wait_sems[0], self.frame_index),
+                None => render_target.swap_loader.acquire_next_image(render_target.swap_chain, u64::MAX,
+                                                                     wait_sems[0], vk::Fence::null())
+            };
+            #[cfg(not(feature = "device-group"))]
+            let acquire_result = render_target.swap_loader.acquire_next_image(render_target.swap_chain, u64::MAX,
+                                                                              wait_sems[0], vk::Fence::null());
+            if self.device_lost.detect(&acquire_result, "acquire_next_image") {
+                return;
+            }
+            // An out of date swap chain is recreated at the start of the next frame, the fence is still signaled
+            let next_image_idx = match self.swapchain_recreate.check_acquire(acquire_result) {
+                Some(idx) => idx,
+                None => return
+            };
+
+            logical_device.reset_fences(&fences).unwrap();
+
+            // Culling waits on the previous frame's depth pyramid, which is built on the async compute queue
+            #[cfg(feature = "indirect-draw")]
+            let (compute_wait, compute_signal) = (self.device.async_compute.take_wait(),
+                                                  Some(self.device.async_compute.graphics_finished(current_frame)));
+            #[cfg(not(feature = "indirect-draw"))]
+            let (compute_wait, compute_signal): (Option<vk::Semaphore>, Option<vk::Semaphore>) = (None, None);
+            let texture_waits = self.device.textures.take_waits();
+            let submit_wait_sems: Vec<vk::Semaphore> = wait_sems.iter().copied().chain(compute_wait)
+                .chain(texture_waits.iter().copied())
+                .collect();
+            let submit_wait_stages: Vec<vk::PipelineStageFlags> = wait_stages.iter().copied()
+                .chain(compute_wait.map(|_| vk::PipelineStageFlags::COMPUTE_SHADER))
+                .chain(texture_waits.iter().map(|_| vk::PipelineStageFlags::FRAGMENT_SHADER))
+                .collect();
+            let submit_sig_sems: Vec<vk::Semaphore> = sig_sems.iter().copied().chain(compute_signal).collect();
+            let submit_info = vk::SubmitInfo::default()
+                .wait_semaphores(submit_wait_sems.as_slice())
+                .wait_dst_stage_mask(submit_wait_stages.as_slice())
+                .command_buffers(&command_buffers)
+                .signal_semaphores(submit_sig_sems.as_slice());
+            // Waits and signals on the frame's GPU. Semaphores signaled by the other GPUs, like the previous frame's
+            // async compute, synchronize the devices.
+            #[cfg(feature = "device-group")]
+            let group_submit = self.core.device_group.as_ref()
+                .map(|g| g.submit(self.frame_index, submit_wait_sems.len(), submit_sig_sems.len()));
+            #[cfg(feature = "device-group")]
+            let mut group_submit_info = group_submit.as_ref().map(|s| s.info());
+            #[cfg(feature = "device-group")]
+            let submit_info = match group_submit_info.as_mut() {
+                Some(group_submit_info) => submit_info.push_next(group_submit_info),
+                None => submit_info
+            };
+
+            logical_device.reset_command_buffer(*self.device.command_buffers.get(self.current_frame).unwrap(),
+                                                                   vk::CommandBufferResetFlags::empty())
+                .unwrap();
+            self.record_command_buffer(next_image_idx);
+            let submit_result = logical_device.queue_submit(graphics_queue, &[submit_info],
+                                                            *self.device.in_flight_fences
+                                                                .get(self.current_frame).unwrap());
+            if self.device_lost.check(submit_result, "queue_submit").is_none() {
+                return;
+            }
+            if let Some(voxels) = self.device.voxels.as_mut() {
+                voxels.end_frame();
+            }
+            #[cfg(feature = "indirect-draw")]
+            self.device.async_compute.submit(&self.core, current_frame,
+                                             |command_buffer| self.device.culler
+                                                 .cmd_build_pyramid_async(&self.core, command_buffer));
+            trace!(frame = current_frame, image = next_image_idx, "Submitted frame");
+
+            // For a screenshot, presentation waits on the readback copy instead of the render
+            let mut screenshot = self.screenshots.pop_front().map(|(path, reply)| {
+                let capture = FrameCapture::new(&self.core, render_target.extent, render_target.surface_format,
+                                                CaptureOutput::Png(path.clone()), Duration::ZERO);
+                (capture, path, reply)
+            });
+            let present_wait_sems = match screenshot.as_mut() {
+                Some((capture, _, _)) => {
+                    let present_image = render_target.swap_loader.get_swapchain_images(render_target.swap_chain)
+                        .unwrap()[next_image_idx as usize];
+                    [capture.submit(&self.core, render_target, present_image, sig_sems[0])]
+                },
+                None => sig_sems
+            };
+            // Then on the copy for other processes, after the screenshot's
+            #[cfg(unix)]
+            let present_wait_sems = match self.frame_share.as_mut() {
+                Some(share) => {
+                    share.poll(&self.core);
+                    let present_image = render_target.swap_loader.get_swapchain_images(render_target.swap_chain)
+                        .unwrap()[next_image_idx as usize];
+                    [share.submit(&self.core, present_image, present_wait_sems[0])]
+                },
+                None => present_wait_sems
+            };
+            let image_indices = [next_image_idx];
+            let mut present_info = vk::PresentInfoKHR::default()
+                .wait_semaphores(&present_wait_sems)
+                .swapchains(&swap_chains)
+                .image_indices(&image_indices);
+            // Lets the latency governor wait for this frame to reach the display
+            let present_ids = self.device.latency.next_present_id().map(|id| [id]);
+            let mut present_id_info = vk::PresentIdKHR::default();
+            if let Some(ids) = present_ids.as_ref() {
+                present_id_info = present_id_info.present_ids(ids);
+                present_info = present_info.push_next(&mut present_id_info);
+            }
+            // Lets the display report when the frame was shown, and paces it to the refresh rate
+            let present_times = self.device.display_timing.as_mut().map(|t| [t.next_present_time()]);
+            let mut present_times_info = vk::PresentTimesInfoGOOGLE::default();
+            if let Some(times) = present_times.as_ref() {
+                present_times_info = present_times_info.times(times);
+                present_info = present_info.push_next(&mut present_times_info);
+            }
+            // From the instance of the image on the GPU that rendered it
+            #[cfg(feature = "device-group")]
+            let mut group_present_info = self.core.device_group.as_ref()
+                .zip(group_submit.as_ref())
+                .and_then(|(g, s)| g.present_mode.map(|mode| s.present_info(mode)));
+            #[cfg(feature = "device-group")]
+            if let Some(group_present_info) = group_present_info.as_mut() {
+                present_info = present_info.push_next(group_present_info);
+            }
+
+            let present_result = render_target.swap_loader.queue_present(present_queue, &present_info);
+            if let Some((capture, path, reply)) = screenshot {
+                self.finish_screenshot(capture, path, reply);
+            }
+            if self.device_lost.detect(&present_result, "queue_present") {
+                return;
+            }
+            self.swapchain_recreate.check_present(present_result);
+            self.device_lost.frame_presented();
+            if let Some(timing) = self.device.display_timing.as_mut() {
+                timing.collect(&self.device.render_target);
+            }
+        }
+
+        self.current_frame = (current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.frame_index += 1;
+        self.previous_view_projection = Some(self.camera.projection(self.main_extent()) *
+            self.camera.view());
+        self.last_cpu_ms = (frame_start.elapsed() - wait_time).as_secs_f64() * 1000.0;
+        let stats = FrameStats {
+            frame_ms: frame_start.duration_since(self.last_frame_start).as_secs_f64() * 1000.0,
+            cpu_ms: self.last_cpu_ms,
+            gpu_ms: self.last_gpu_ms,
+            passes,
+            presents: self.device.display_timing.as_mut().map_or(Vec::new(), |t| t.take_collected())
+        };
+        let mut extra = format!("{} | {} | {} | {} | {} | {}", self.device.latency.status(),
+                                self.device.ssao.status(), self.device.post_process.motion_blur_settings().status(),
+                                self.device.textures.status(), self.device.grid.status(), self.clock.status());
+        extra = format!("{} | {}", extra, self.limiter.status());
+        if let Some(timing) = self.device.display_timing.as_ref() {
+            extra = format!("{} | {}", extra, timing.status());
+        }
+        self.stats_overlay.record(&self.core.window, &stats, extra.as_str());
+        self.crash.record(stats);
+        self.last_frame_start = frame_start;
+    }
+}
+
+impl Drop for RasterRenderer {
+    fn drop(&mut self) {
+        // A lost device has nothing left to wait for
+        if let Err(e) = unsafe { self.core.logical_device.device_wait_idle() } {
+            warn!("device_wait_idle before teardown failed: {:?}", e);
+        }
+        #[cfg(unix)]
+        if let Some(share) = self.frame_share.as_mut() {
+            share.destroy(&self.core);
+        }
+        self.device.destroy(&self.core);
+        self.crash.disarm();
+        self.core.destroy();
+    }
+}